@@ -0,0 +1,220 @@
+//! Running several source files at once, for the `run` command. Each file is
+//! compiled and run against the same specs, and a problem with one file
+//! doesn't stop the others from running (unless `--fail-fast` is given).
+
+use crate::json::{JsonError, TestCaseOutput};
+use anyhow::Context;
+use gdlk::{CompileOptions, Compiler, HardwareSpec, ProgramSpec};
+use serde::Serialize;
+use std::{
+    fmt::{self, Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Extension of GDLK source files. When a directory is given, only files with
+/// this extension are run.
+const SOURCE_EXTENSION: &str = "gdlk";
+
+/// Expand the given paths into a list of source files. Each directory is
+/// replaced by the source files directly inside it (subdirectories aren't
+/// searched), sorted by name. Files are kept as-is, regardless of extension.
+pub fn collect_sources(paths: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut sources = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            sources.push(path.clone());
+            continue;
+        }
+
+        let mut dir_sources = Vec::new();
+        let entries = fs::read_dir(path)
+            .with_context(|| format!("Failed to read directory {:?}", path))?;
+        for entry in entries {
+            let entry_path = entry
+                .with_context(|| {
+                    format!("Failed to read directory {:?}", path)
+                })?
+                .path();
+            if entry_path.is_file()
+                && entry_path.extension() == Some(SOURCE_EXTENSION.as_ref())
+            {
+                dir_sources.push(entry_path);
+            }
+        }
+        if dir_sources.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No .{} files in directory {:?}",
+                SOURCE_EXTENSION,
+                path
+            ));
+        }
+        dir_sources.sort();
+        sources.extend(dir_sources);
+    }
+    Ok(sources)
+}
+
+/// How running a single file turned out
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    /// Passed every test case
+    Passed,
+    /// Compiled, but failed at least one test case
+    Failed,
+    CompileError,
+    /// Compiled, but couldn't run against the program spec, e.g. because it
+    /// uses a constant that the spec doesn't define
+    SpecError,
+    ReadError,
+}
+
+/// The outcome of running a single source file
+#[derive(Debug, Serialize)]
+pub struct FileResult {
+    pub path: PathBuf,
+    pub status: FileStatus,
+    /// Compile errors, if the file didn't compile
+    pub errors: Vec<JsonError>,
+    pub warnings: Vec<JsonError>,
+    /// Why the file couldn't be read, if it couldn't
+    pub read_error: Option<String>,
+    /// Why the file couldn't run against the program spec, if it couldn't
+    pub spec_error: Option<String>,
+    /// The result of each test case. Empty if the file didn't compile.
+    pub test_cases: Vec<TestCaseOutput>,
+    /// Errors and warnings formatted with their source, for text output
+    #[serde(skip)]
+    pub details: Option<String>,
+}
+
+impl FileStatus {
+    /// Short, upper-case label for this status, for text output
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Passed => "PASS",
+            Self::Failed => "FAIL",
+            Self::CompileError => "COMPILE ERROR",
+            Self::SpecError => "SPEC ERROR",
+            Self::ReadError => "READ ERROR",
+        }
+    }
+}
+
+impl FileResult {
+    /// Did this file pass every test case?
+    pub fn successful(&self) -> bool {
+        self.status == FileStatus::Passed
+    }
+}
+
+/// Compile a single source file and run it against every test case. This
+/// never fails, since any problem with the file is part of its result.
+pub fn run_file(
+    path: &Path,
+    hardware_spec: &HardwareSpec,
+    program_spec: &ProgramSpec,
+    options: CompileOptions,
+) -> FileResult {
+    let mut result = FileResult {
+        path: path.to_owned(),
+        status: FileStatus::ReadError,
+        errors: Vec::new(),
+        warnings: Vec::new(),
+        read_error: None,
+        spec_error: None,
+        test_cases: Vec::new(),
+        details: None,
+    };
+
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            result.read_error = Some(error.to_string());
+            result.details = Some(format!("Failed to read file: {}", error));
+            return result;
+        }
+    };
+
+    let resolver = crate::include_resolver(path);
+    match Compiler::compile_with_options_and_resolver(
+        source,
+        hardware_spec.clone(),
+        options,
+        &resolver,
+    ) {
+        Ok(compiler) => {
+            let warnings = compiler.warnings();
+            if !warnings.errors().is_empty() {
+                result.warnings = JsonError::from_errors(&warnings);
+                result.details = Some(warnings.to_string());
+            }
+            let machines = match compiler.allocate_all(program_spec) {
+                Ok(machines) => machines,
+                Err(error) => {
+                    result.status = FileStatus::SpecError;
+                    result.spec_error = Some(error.to_string());
+                    result.details = Some(error.to_string());
+                    return result;
+                }
+            };
+            result.test_cases = machines
+                .into_iter()
+                .map(|mut machine| {
+                    // Any error gets stored in the machine, and included in
+                    // the output
+                    let _ = machine.execute_all();
+                    TestCaseOutput::from(&machine)
+                })
+                .collect();
+            result.status = if result
+                .test_cases
+                .iter()
+                .all(|test_case| test_case.success)
+            {
+                FileStatus::Passed
+            } else {
+                FileStatus::Failed
+            };
+        }
+        Err(errors) => {
+            result.status = FileStatus::CompileError;
+            result.errors = JsonError::from_errors(&errors);
+            result.details = Some(errors.to_string());
+        }
+    }
+    result
+}
+
+/// A table with one row per file, showing whether it passed and the cycle
+/// count for each test case
+pub struct Summary<'a>(pub &'a [FileResult]);
+
+impl Display for Summary<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let paths: Vec<String> = self
+            .0
+            .iter()
+            .map(|result| result.path.display().to_string())
+            .collect();
+        let width = paths.iter().map(String::len).max().unwrap_or(0);
+
+        writeln!(f, "{:<width$}  {:<13}  Cycles", "File", "Result")?;
+        for (path, result) in paths.iter().zip(self.0) {
+            let status = result.status.label();
+            let cycles = if result.test_cases.is_empty() {
+                "-".to_owned()
+            } else {
+                result
+                    .test_cases
+                    .iter()
+                    .map(|test_case| test_case.cycle_count.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            writeln!(f, "{:<width$}  {:<13}  {}", path, status, cycles)?;
+        }
+        Ok(())
+    }
+}
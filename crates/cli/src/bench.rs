@@ -0,0 +1,129 @@
+//! Benchmarking for the `bench` command. A program is compiled once, then run
+//! against every test case over and over. Execution is deterministic, so the
+//! cycle counts should be the same on every run. The wall-clock time measures
+//! the interpreter itself.
+
+use gdlk::{
+    ast::compiled::Program, error::AllocateError, Compiler, Machine,
+    ProgramSpec, Span,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    time::{Duration, Instant},
+};
+
+/// The results of running a program repeatedly
+#[derive(Debug, Serialize)]
+pub struct BenchResults {
+    /// The number of instructions in the compiled program
+    pub num_instructions: usize,
+    pub iterations: usize,
+    /// Cycle count for each test case, from the first run
+    pub cycle_counts: Vec<usize>,
+    /// Whether every run had the same cycle counts as the first. If not,
+    /// execution is nondeterministic, which is a bug in the interpreter.
+    pub deterministic: bool,
+    /// Wall-clock time to run every test case once, in nanoseconds
+    pub time_ns: TimeStats,
+    /// The most values that each stack held at once, across every run and
+    /// test case. Keyed by stack name.
+    pub max_stack_usage: BTreeMap<String, usize>,
+}
+
+/// Summary of the time taken by each run
+#[derive(Debug, Serialize)]
+pub struct TimeStats {
+    pub min: u128,
+    pub median: u128,
+    pub max: u128,
+}
+
+impl TimeStats {
+    fn new(mut times: Vec<Duration>) -> Self {
+        times.sort();
+        Self {
+            min: times[0].as_nanos(),
+            median: times[times.len() / 2].as_nanos(),
+            max: times[times.len() - 1].as_nanos(),
+        }
+    }
+}
+
+/// Run the compiled program against every test case in the program spec,
+/// `iterations` times. Each run allocates fresh machines, and that's included
+/// in the timing. `iterations` must be at least one. Fails if the machines
+/// can't be allocated for the program spec.
+pub fn bench(
+    compiler: &Compiler<Program<Span>>,
+    program_spec: &ProgramSpec,
+    iterations: usize,
+) -> Result<BenchResults, AllocateError> {
+    let mut cycle_counts: Option<Vec<usize>> = None;
+    let mut deterministic = true;
+    let mut times = Vec::with_capacity(iterations);
+    let mut max_stack_usage = BTreeMap::new();
+
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let mut machines = compiler.allocate_all(program_spec)?;
+        for machine in &mut machines {
+            // Failures don't matter here, we just want the stats
+            let _ = machine.execute_all();
+        }
+        times.push(start.elapsed());
+
+        let run_cycle_counts: Vec<usize> =
+            machines.iter().map(Machine::cycle_count).collect();
+        match &cycle_counts {
+            None => cycle_counts = Some(run_cycle_counts),
+            Some(first) => deterministic &= *first == run_cycle_counts,
+        }
+        for machine in &machines {
+            let hardware_spec = machine.hardware_spec();
+            for (stack_ref, length) in machine.max_stack_usage() {
+                let peak = max_stack_usage
+                    .entry(hardware_spec.stack_name(stack_ref))
+                    .or_insert(0);
+                *peak = length.max(*peak);
+            }
+        }
+    }
+
+    Ok(BenchResults {
+        num_instructions: compiler.program().num_instructions(),
+        iterations,
+        cycle_counts: cycle_counts.unwrap_or_default(),
+        deterministic,
+        time_ns: TimeStats::new(times),
+        max_stack_usage,
+    })
+}
+
+impl Display for BenchResults {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let cycle_counts: Vec<String> =
+            self.cycle_counts.iter().map(usize::to_string).collect();
+        let time = |nanos: u128| Duration::from_nanos(nanos as u64);
+        writeln!(f, "Instructions: {}", self.num_instructions)?;
+        writeln!(f, "Iterations: {}", self.iterations)?;
+        writeln!(f, "Cycles per test case: {}", cycle_counts.join(", "))?;
+        writeln!(
+            f,
+            "Time per run: min {:?}, median {:?}, max {:?}",
+            time(self.time_ns.min),
+            time(self.time_ns.median),
+            time(self.time_ns.max),
+        )?;
+        if !self.max_stack_usage.is_empty() {
+            let peaks: Vec<String> = self
+                .max_stack_usage
+                .iter()
+                .map(|(name, length)| format!("{}={}", name, length))
+                .collect();
+            writeln!(f, "Peak stack usage: {}", peaks.join(", "))?;
+        }
+        Ok(())
+    }
+}
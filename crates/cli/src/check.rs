@@ -0,0 +1,208 @@
+//! Validation for spec files, used by the `check` command. Loading a spec
+//! normally stops at the first problem, but this reports every problem it can
+//! find, each one located by the JSON pointer of the offending field.
+
+use gdlk::{
+    ast::LangValue, HardwareSpec, DEFAULT_MAX_CALL_DEPTH, MAX_TEST_CASE_LENGTH,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::{collections::BTreeMap, fmt::Display};
+
+/// Collects the problems found while checking one or more spec values
+#[derive(Debug, Default)]
+pub struct Checker {
+    violations: Vec<String>,
+}
+
+impl Checker {
+    /// Every problem found so far, formatted as `<pointer>: <message>`
+    pub fn violations(&self) -> &[String] {
+        &self.violations
+    }
+
+    /// Check a hardware spec. Each field is checked on its own first, then if
+    /// the required fields are all usable, the spec is checked against the
+    /// rules in [HardwareSpec::violations].
+    pub fn check_hardware_spec(&mut self, value: &Value) {
+        let object = match self.object(value, "") {
+            Some(object) => object,
+            None => return,
+        };
+
+        let num_registers = self.field(object, "", "num_registers", true);
+        let num_stacks = self.field(object, "", "num_stacks", true);
+        // The uniform length can be left out if per-stack lengths are given
+        let stack_lengths: Option<Vec<usize>> =
+            self.field(object, "", "stack_lengths", false);
+        let max_stack_length = self.field(
+            object,
+            "",
+            "max_stack_length",
+            !object.contains_key("stack_lengths"),
+        );
+        let max_stack_length = max_stack_length.or_else(|| {
+            stack_lengths.as_ref().map(|lengths| {
+                lengths.iter().copied().max().unwrap_or_default()
+            })
+        });
+        // Optional fields that are broken get replaced by their default, which
+        // is always valid, so they don't cause any more violations below
+        let dump_enabled = self.field(object, "", "dump_enabled", false);
+        let null_count_enabled =
+            self.field(object, "", "null_count_enabled", false);
+        let register_names = self.field(object, "", "register_names", false);
+        let stack_names = self.field(object, "", "stack_names", false);
+        let cost_model = self.field(object, "", "cost_model", false);
+        let overflow_behavior =
+            self.field(object, "", "overflow_behavior", false);
+        let allowed_instructions =
+            self.field(object, "", "allowed_instructions", false);
+        let max_call_depth = self.field(object, "", "max_call_depth", false);
+        let num_extern_ops = self.field(object, "", "num_extern_ops", false);
+        let initial_state = self.field(object, "", "initial_state", false);
+        let max_instructions =
+            self.field(object, "", "max_instructions", false);
+
+        // The rules compare fields against each other, so they can only be
+        // checked if all the required fields are there
+        if let (Some(num_registers), Some(num_stacks), Some(max_stack_length)) =
+            (num_registers, num_stacks, max_stack_length)
+        {
+            let spec = HardwareSpec {
+                num_registers,
+                num_stacks,
+                max_stack_length,
+                stack_lengths: stack_lengths.unwrap_or_default(),
+                dump_enabled: dump_enabled.unwrap_or_default(),
+                null_count_enabled: null_count_enabled.unwrap_or_default(),
+                register_names: register_names.unwrap_or_default(),
+                stack_names: stack_names.unwrap_or_default(),
+                cost_model: cost_model.unwrap_or_default(),
+                overflow_behavior: overflow_behavior.unwrap_or_default(),
+                allowed_instructions: allowed_instructions.flatten(),
+                max_call_depth: max_call_depth
+                    .unwrap_or(DEFAULT_MAX_CALL_DEPTH),
+                num_extern_ops: num_extern_ops.unwrap_or_default(),
+                initial_state: initial_state.unwrap_or_default(),
+                max_instructions: max_instructions.flatten(),
+            };
+            self.violations.extend(
+                spec.violations()
+                    .iter()
+                    .map(|violation| violation.to_string()),
+            );
+        }
+    }
+
+    /// Check a program spec, in either the multiple test case format or the
+    /// single test case format.
+    pub fn check_program_spec(&mut self, value: &Value) {
+        let object = match self.object(value, "") {
+            Some(object) => object,
+            None => return,
+        };
+
+        if object.contains_key("test_cases") {
+            self.field::<BTreeMap<String, LangValue>>(
+                object,
+                "",
+                "constants",
+                false,
+            );
+            let test_cases: Option<Vec<Value>> =
+                self.field(object, "", "test_cases", true);
+            match test_cases {
+                Some(test_cases) if test_cases.is_empty() => self.report(
+                    "/test_cases",
+                    "Program spec must have at least one test case",
+                ),
+                Some(test_cases) => {
+                    for (i, test_case) in test_cases.iter().enumerate() {
+                        self.check_test_case(
+                            test_case,
+                            &format!("/test_cases/{}", i),
+                        );
+                    }
+                }
+                None => {}
+            }
+        } else {
+            self.check_test_case(value, "");
+        }
+    }
+
+    fn check_test_case(&mut self, value: &Value, pointer: &str) {
+        if let Some(object) = self.object(value, pointer) {
+            for key in &["input", "expected_output"] {
+                let values: Option<Vec<LangValue>> =
+                    self.field(object, pointer, key, true);
+                match values {
+                    Some(values) if values.len() > MAX_TEST_CASE_LENGTH => self
+                        .report(
+                            &format!("{}/{}", pointer, key),
+                            format!(
+                                "Expected at most {} values, but got {}",
+                                MAX_TEST_CASE_LENGTH,
+                                values.len()
+                            ),
+                        ),
+                    _ => {}
+                }
+            }
+            self.field::<BTreeMap<String, LangValue>>(
+                object,
+                pointer,
+                "constants",
+                false,
+            );
+        }
+    }
+
+    fn report(&mut self, pointer: &str, message: impl Display) {
+        // An empty pointer refers to the whole document
+        let pointer = if pointer.is_empty() { "/" } else { pointer };
+        self.violations.push(format!("{}: {}", pointer, message));
+    }
+
+    /// Make sure a value is an object, reporting a violation if it isn't
+    fn object<'a>(
+        &mut self,
+        value: &'a Value,
+        pointer: &str,
+    ) -> Option<&'a Map<String, Value>> {
+        let object = value.as_object();
+        if object.is_none() {
+            self.report(pointer, "Expected an object");
+        }
+        object
+    }
+
+    /// Deserialize a single field of an object. If the field can't be
+    /// deserialized, or it's required but missing, a violation is reported
+    /// and `None` is returned.
+    fn field<T: DeserializeOwned>(
+        &mut self,
+        object: &Map<String, Value>,
+        pointer: &str,
+        key: &str,
+        required: bool,
+    ) -> Option<T> {
+        let pointer = format!("{}/{}", pointer, key);
+        match object.get(key) {
+            Some(value) => match T::deserialize(value) {
+                Ok(value) => Some(value),
+                Err(err) => {
+                    self.report(&pointer, err);
+                    None
+                }
+            },
+            None => {
+                if required {
+                    self.report(&pointer, "Missing required field");
+                }
+                None
+            }
+        }
+    }
+}
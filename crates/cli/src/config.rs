@@ -0,0 +1,78 @@
+//! Project-wide defaults for command line options, so they don't have to be
+//! typed out on every invocation. They're loaded from a `gdlk.toml` file:
+//!
+//! ```toml
+//! hardware = "specs/hw1.json"
+//! program = "specs/p3.json"
+//! format = "json"
+//! constant_folding = true
+//! ```
+//!
+//! Every key is optional. Paths are relative to the directory that the config
+//! file is in. Flags given on the command line always win over the config.
+
+use crate::Format;
+use anyhow::Context;
+use serde::Deserialize;
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+/// The name of the config file that gets picked up automatically
+pub const CONFIG_FILE_NAME: &str = "gdlk.toml";
+
+/// Default values for command line options
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Default for `--hardware`
+    pub hardware: Option<PathBuf>,
+    /// Default for `--program`
+    pub program: Option<PathBuf>,
+    /// Default for `--format`
+    pub format: Option<Format>,
+    /// Turns on `--constant-folding`
+    #[serde(default)]
+    pub constant_folding: bool,
+}
+
+impl Config {
+    /// Load a config from a file. Every path in the returned config has
+    /// already been joined onto the config file's directory.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let config: Self = toml::from_str(&crate::read_file(path)?)
+            .with_context(|| format!("Invalid config file {:?}", path))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Ok(Self {
+            hardware: config.hardware.map(|path| dir.join(path)),
+            program: config.program.map(|path| dir.join(path)),
+            ..config
+        })
+    }
+
+    /// Load the config for this invocation. If a path was given explicitly
+    /// (with `--config`), that file has to exist. Otherwise, the current
+    /// directory and each of its parents are searched for a [CONFIG_FILE_NAME],
+    /// and the closest one is used. If there isn't one, every option keeps its
+    /// built-in default.
+    pub fn find(explicit_path: Option<&Path>) -> anyhow::Result<Self> {
+        if let Some(path) = explicit_path {
+            return Self::load(path);
+        }
+        let cwd =
+            env::current_dir().context("Failed to get current directory")?;
+        match discover(&cwd) {
+            Some(path) => Self::load(&path),
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+/// Find the closest config file in the given directory or any of its parents
+fn discover(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(CONFIG_FILE_NAME))
+        .find(|path| path.is_file())
+}
@@ -0,0 +1,266 @@
+//! An interactive debugger for GDLK programs. This is a simple REPL that lets
+//! the user step through a program, set breakpoints, and inspect state.
+
+use gdlk::{Machine, MachineSnapshot, Span};
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, Write},
+};
+
+const HELP: &str = "Commands:
+  step [n]            Execute the next n instructions (default 1)
+  run                 Execute until a breakpoint or termination
+  break <line>        Toggle a breakpoint on a source line
+  break               List all breakpoints
+  print registers     Print the current value of all registers
+  print stacks        Print the current contents of all stacks
+  print input         Print the remaining input
+  print output        Print the output so far
+  reset               Restart the program from the beginning
+  help                Show this message
+  quit                Exit the debugger";
+
+/// The state of a debugging session
+struct Debugger {
+    machine: Machine,
+    /// The state of the machine before anything was executed, used to reset
+    initial_state: MachineSnapshot,
+    /// Breakpoints, keyed by source line number. Each value is the list of
+    /// instruction indices on that line.
+    breakpoints: BTreeMap<usize, Vec<usize>>,
+}
+
+impl Debugger {
+    fn new(machine: Machine) -> Self {
+        let initial_state = machine.snapshot();
+        Self {
+            machine,
+            initial_state,
+            breakpoints: BTreeMap::new(),
+        }
+    }
+
+    /// Get the span of the next instruction to be executed. Returns `None` if
+    /// the program has terminated.
+    fn current_span(&self) -> Option<Span> {
+        self.machine
+            .program()
+            .instructions
+            .get(self.machine.program_counter())
+            .map(|instr| *instr.metadata())
+    }
+
+    /// Get the indices of all instructions that start on the given source
+    /// line.
+    fn instructions_on_line(&self, line: usize) -> Vec<usize> {
+        self.machine
+            .program()
+            .instructions
+            .iter()
+            .enumerate()
+            .filter(|(_, instr)| instr.metadata().start_line == line)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Check if the next instruction to be executed has a breakpoint on it.
+    fn at_breakpoint(&self) -> bool {
+        let pc = self.machine.program_counter();
+        self.breakpoints
+            .values()
+            .any(|instr_indexes| instr_indexes.contains(&pc))
+    }
+
+    /// Execute the next instruction. Returns false if nothing was executed,
+    /// either because the program terminated or an error occurred.
+    fn step(&mut self) -> bool {
+        match self.machine.execute_next() {
+            Ok(executed) => executed,
+            Err(error) => {
+                println!("{}", error);
+                false
+            }
+        }
+    }
+
+    /// Execute until we hit a breakpoint or the program terminates. Always
+    /// executes at least one instruction, so we don't get stuck on the
+    /// current breakpoint.
+    fn run(&mut self) {
+        while self.step() {
+            if self.at_breakpoint() {
+                println!("Hit breakpoint");
+                break;
+            }
+        }
+    }
+
+    /// Print where the machine currently is in the program
+    fn print_location(&self) {
+        if self.machine.terminated() {
+            println!(
+                "Program terminated with {} after {} cycles",
+                if self.machine.successful() {
+                    "SUCCESS"
+                } else {
+                    "FAILURE"
+                },
+                self.machine.cycle_count()
+            );
+        } else if let Some(span) = self.current_span() {
+            // The instruction could be from an included file, so pull the line
+            // from whichever file it's in
+            let (file_name, source) =
+                match self.machine.includes().get_by_id(span.source_id) {
+                    Some(included) => {
+                        (Some(included.name.as_str()), included.code.as_str())
+                    }
+                    None => (None, self.machine.source_code()),
+                };
+            let source_line =
+                source.lines().nth(span.start_line - 1).unwrap_or_default();
+            match file_name {
+                Some(file_name) => println!(
+                    "{}:{} | {}",
+                    file_name,
+                    span.start_line,
+                    source_line.trim()
+                ),
+                None => {
+                    println!("{:>4} | {}", span.start_line, source_line.trim())
+                }
+            }
+        }
+    }
+
+    fn print_registers(&self) {
+        let hardware_spec = self.machine.hardware_spec();
+        // Keep the machine's order, so e.g. RX2 comes before RX10
+        let registers =
+            self.machine
+                .registers()
+                .into_iter()
+                .map(|(reg_ref, value)| {
+                    (hardware_spec.register_name(reg_ref), value)
+                });
+        for (name, value) in registers {
+            println!("{} = {}", name, value);
+        }
+    }
+
+    fn print_stacks(&self) {
+        let hardware_spec = self.machine.hardware_spec();
+        let stacks: Vec<_> = self
+            .machine
+            .stacks()
+            .into_iter()
+            .map(|(stack_ref, values)| {
+                (hardware_spec.stack_name(stack_ref), values)
+            })
+            .collect();
+        if stacks.is_empty() {
+            println!("No stacks");
+        }
+        for (name, values) in stacks {
+            println!("{} = {:?}", name, values);
+        }
+    }
+
+    /// Execute a single command from the user. Returns false if the user
+    /// wants to exit.
+    fn execute_command(&mut self, command: &str) -> bool {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["step"] | ["s"] => {
+                self.step();
+                self.print_location();
+            }
+            ["step", n] | ["s", n] => match n.parse::<usize>() {
+                Ok(n) => {
+                    for _ in 0..n {
+                        if !self.step() {
+                            break;
+                        }
+                    }
+                    self.print_location();
+                }
+                Err(_) => println!("Invalid step count: {}", n),
+            },
+            ["run"] | ["r"] => {
+                self.run();
+                self.print_location();
+            }
+            ["break"] | ["b"] => {
+                if self.breakpoints.is_empty() {
+                    println!("No breakpoints");
+                }
+                for line in self.breakpoints.keys() {
+                    println!("Breakpoint on line {}", line);
+                }
+            }
+            ["break", line] | ["b", line] => match line.parse::<usize>() {
+                Ok(line) => {
+                    let instr_indexes = self.instructions_on_line(line);
+                    if instr_indexes.is_empty() {
+                        println!("No instructions on line {}", line);
+                    } else if self.breakpoints.remove(&line).is_some() {
+                        println!("Removed breakpoint on line {}", line);
+                    } else {
+                        self.breakpoints.insert(line, instr_indexes);
+                        println!("Added breakpoint on line {}", line);
+                    }
+                }
+                Err(_) => println!("Invalid line number: {}", line),
+            },
+            ["print", "registers"] | ["p", "registers"] => {
+                self.print_registers()
+            }
+            ["print", "stacks"] | ["p", "stacks"] => self.print_stacks(),
+            ["print", "input"] | ["p", "input"] => {
+                println!("{:?}", self.machine.input())
+            }
+            ["print", "output"] | ["p", "output"] => {
+                println!("{:?}", self.machine.output())
+            }
+            ["reset"] => {
+                self.machine.restore(self.initial_state.clone());
+                self.print_location();
+            }
+            ["help"] | ["h"] => println!("{}", HELP),
+            ["quit"] | ["q"] | ["exit"] => return false,
+            _ => println!("Unknown command: {}\n{}", command, HELP),
+        }
+        true
+    }
+}
+
+/// Start an interactive debugging session for the given machine. Reads
+/// commands from stdin until the user quits or stdin closes. Returns whether
+/// the machine completed successfully by the end of the session.
+pub fn debug(machine: Machine) -> anyhow::Result<bool> {
+    let mut debugger = Debugger::new(machine);
+    println!("Type `help` for a list of commands");
+    debugger.print_location();
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("(gdlk) ");
+        io::stdout().flush()?;
+        match lines.next() {
+            Some(line) => {
+                if !debugger.execute_command(line?.trim()) {
+                    break;
+                }
+            }
+            // EOF
+            None => {
+                println!();
+                break;
+            }
+        }
+    }
+
+    Ok(debugger.machine.successful())
+}
@@ -0,0 +1,47 @@
+//! Terminal-attached I/O for `run --interactive`. Instead of running against a
+//! program spec, the program reads its input from stdin and writes its output
+//! to stdout as it executes.
+
+use gdlk::{ast::LangValue, InputSource, OutputSink};
+use std::io::{self, BufRead};
+
+/// Reads input values from stdin, one per line. Blank lines are skipped, and
+/// invalid values are reported and re-prompted. End of input (or a read
+/// error) means the input is exhausted.
+pub struct StdinSource;
+
+impl InputSource for StdinSource {
+    fn read(&mut self) -> Option<LangValue> {
+        let stdin = io::stdin();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => return None,
+                Ok(_) => {}
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            match line.parse() {
+                Ok(value) => return Some(value),
+                Err(_) => eprintln!("Invalid value: {:?}", line),
+            }
+        }
+    }
+
+    fn remaining(&self) -> Option<usize> {
+        // There's no telling how much the user will type
+        None
+    }
+}
+
+/// Prints output values to stdout, one per line
+pub struct StdoutSink;
+
+impl OutputSink for StdoutSink {
+    fn write(&mut self, value: LangValue) {
+        println!("{}", value);
+    }
+}
@@ -0,0 +1,124 @@
+//! Machine-readable output for the CLI. These types define the JSON that gets
+//! printed when `--format json` is used. Registers and stacks are keyed by
+//! their names (e.g. "RX0", or an alias from the hardware spec), and sorted so
+//! the output is stable.
+
+use gdlk::{
+    ast::LangValue,
+    error::{SourceError, WithSource},
+    FailureReason, Machine, OutputDiff, Span,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// A single error or warning, with the span of source code that caused it
+#[derive(Debug, Serialize)]
+pub struct JsonError {
+    /// Machine-readable code for the kind of error, e.g.
+    /// `"R003_STACK_OVERFLOW"`
+    pub code: &'static str,
+    pub message: String,
+    pub span: Span,
+    /// The included file that the span is in. Omitted for the main source
+    /// file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+impl JsonError {
+    /// Convert every error in the collection
+    pub fn from_errors<E: SourceError>(errors: &WithSource<E>) -> Vec<Self> {
+        errors
+            .errors()
+            .iter()
+            .map(|error| Self {
+                code: error.error_code(),
+                message: error.to_string(),
+                span: error.span(),
+                file: error.source_name().map(String::from),
+            })
+            .collect()
+    }
+}
+
+/// Output when compilation fails. This gets printed to stderr.
+#[derive(Debug, Serialize)]
+pub struct CompileOutput {
+    pub errors: Vec<JsonError>,
+}
+
+/// Output of the `run` command, covering every test case
+#[derive(Debug, Serialize)]
+pub struct RunOutput {
+    pub success: bool,
+    /// The program's name, from its metadata header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The seed that registers were randomized with, if they were. See
+    /// `InitialState::Random`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    pub warnings: Vec<JsonError>,
+    pub test_cases: Vec<TestCaseOutput>,
+}
+
+/// The final state of a machine after executing a single test case
+#[derive(Debug, Serialize)]
+pub struct TestCaseOutput {
+    pub success: bool,
+    pub failure_reason: Option<FailureReason>,
+    pub cycle_count: usize,
+    /// The number of values written to `RZR`
+    pub null_writes: usize,
+    pub registers: BTreeMap<String, LangValue>,
+    pub stacks: BTreeMap<String, Vec<LangValue>>,
+    /// The most values that each stack held at once during execution
+    pub max_stack_usage: BTreeMap<String, usize>,
+    pub input: Vec<LangValue>,
+    pub output: Vec<LangValue>,
+    /// Where the output differs from the expected output, if that's why the
+    /// test case failed
+    pub output_diff: Option<OutputDiff>,
+    /// The runtime error that halted execution, if any
+    pub error: Option<JsonError>,
+}
+
+impl From<&Machine> for TestCaseOutput {
+    fn from(machine: &Machine) -> Self {
+        let hardware_spec = machine.hardware_spec();
+        Self {
+            success: machine.successful(),
+            failure_reason: machine.failure_reason(),
+            cycle_count: machine.cycle_count(),
+            null_writes: machine.null_writes(),
+            registers: machine
+                .registers()
+                .into_iter()
+                .map(|(reg_ref, value)| {
+                    (hardware_spec.register_name(reg_ref), value)
+                })
+                .collect(),
+            stacks: machine
+                .stacks()
+                .into_iter()
+                .map(|(stack_ref, values)| {
+                    (hardware_spec.stack_name(stack_ref), values.to_vec())
+                })
+                .collect(),
+            max_stack_usage: machine
+                .max_stack_usage()
+                .into_iter()
+                .map(|(stack_ref, usage)| {
+                    (hardware_spec.stack_name(stack_ref), usage)
+                })
+                .collect(),
+            input: machine.input().to_vec(),
+            output: machine.output().to_vec(),
+            output_diff: machine.output_diff(),
+            // A machine can only ever hit one error, since it halts
+            error: machine.error().and_then(|error| {
+                JsonError::from_errors(error).into_iter().next()
+            }),
+        }
+    }
+}
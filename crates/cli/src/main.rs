@@ -1,35 +1,217 @@
 #![deny(clippy::all)]
 
-use anyhow::Context;
-use gdlk::{Compiler, HardwareSpec, ProgramSpec};
-use serde::de::DeserializeOwned;
+use anyhow::{anyhow, Context};
+use check::Checker;
+use config::Config;
+use gdlk::{
+    ast::{compiled::Program, RegisterRef, StackRef},
+    include::FileResolver,
+    CompileOptions, Compiler, HardwareSpec, InitialState, InstructionInfo,
+    Machine, ProgramSpec, Span, INSTRUCTION_CATALOG,
+};
+use interactive::{StdinSource, StdoutSink};
+use json::{CompileOutput, JsonError, RunOutput, TestCaseOutput};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
 use std::{
-    fs,
+    fmt::{self, Debug},
+    fs::{self, File},
+    io::{BufWriter, Write},
     path::{Path, PathBuf},
     process,
+    str::FromStr,
 };
 use structopt::StructOpt;
 
+mod batch;
+mod bench;
+mod check;
+mod config;
+mod debug;
+mod interactive;
+mod json;
+mod manifest;
+mod watch;
+
+/// Extra output that can be generated by the compile command
+#[derive(Copy, Clone, Debug)]
+enum Emit {
+    /// Compiled instructions, in canonical GDLK syntax
+    Asm,
+}
+
+impl FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asm" => Ok(Self::Asm),
+            _ => Err(format!("Unknown emit type: {}", s)),
+        }
+    }
+}
+
+/// Format for the output of the run command
+#[derive(Copy, Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    /// Human-readable output
+    #[default]
+    Text,
+    /// Machine-readable JSON, see the [json] module
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unknown format: {}", s)),
+        }
+    }
+}
+
 /// The sub-command to execute.
-#[derive(Debug, StructOpt)]
+#[derive(Clone, Debug, StructOpt)]
 enum Command {
     /// Compile source code.
     #[structopt(name = "compile")]
     Compile {
-        /// Path to the hardware spec file, in JSON format. If not provided, a
-        /// default hardware spec will be used.
+        /// Path to the hardware spec file, in JSON format. If not provided,
+        /// the minimal hardware spec (one register, no stacks) will be used.
         #[structopt(parse(from_os_str), long = "hardware")]
         hardware_spec_path: Option<PathBuf>,
         /// Path to the source code file
         #[structopt(parse(from_os_str), long = "source", short = "s")]
         source_path: PathBuf,
+        /// Print extra output from the compiled program. Supported values:
+        /// asm
+        #[structopt(long = "emit")]
+        emit: Option<Emit>,
+        /// Compile again every time the source or hardware spec file
+        /// changes. Compile errors are printed, and then watching continues.
+        #[structopt(long = "watch")]
+        watch: bool,
+        /// Fold arithmetic on constants at compile time. See
+        /// CompileOptions::constant_folding.
+        #[structopt(long = "constant-folding")]
+        constant_folding: bool,
     },
 
     /// Compile and execute source code.
     #[structopt(name = "run")]
     Run {
-        /// Path to the hardware spec file, in JSON format. If not provided, a
-        /// default hardware spec will be used.
+        /// Path to the hardware spec file, in JSON format. If not provided,
+        /// the minimal hardware spec (one register, no stacks) will be used.
+        #[structopt(parse(from_os_str), long = "hardware")]
+        hardware_spec_path: Option<PathBuf>,
+        /// Path to the program spec file, in JSON format. If not provided, a
+        /// default program spec will be used.
+        #[structopt(parse(from_os_str), long = "program", short = "p")]
+        program_spec_path: Option<PathBuf>,
+        /// Path to a source code file, or a directory of .gdlk files. Can be
+        /// given multiple times. With more than one file, each one is run in
+        /// turn, and a summary table is printed at the end.
+        #[structopt(
+            parse(from_os_str),
+            long = "source",
+            short = "s",
+            required = true,
+            number_of_values = 1
+        )]
+        source_paths: Vec<PathBuf>,
+        /// Print all snapshots generated by DUMP instructions after execution.
+        /// DUMP must be enabled in the hardware spec. Only applies to the text
+        /// format with a single source file.
+        #[structopt(long = "show-dumps")]
+        show_dumps: bool,
+        /// Output format, either text or json. In json mode, the results are
+        /// printed to stdout, and compile errors are printed to stderr. With
+        /// multiple source files, the output is an array with the results for
+        /// each file, including any compile errors. Defaults to text.
+        #[structopt(long = "format")]
+        format: Option<Format>,
+        /// With multiple source files, stop at the first one that doesn't
+        /// pass
+        #[structopt(long = "fail-fast")]
+        fail_fast: bool,
+        /// Write a trace of every executed instruction to this file, for each
+        /// test case. Only supports a single source file. See
+        /// Machine::execute_all_traced for the format.
+        #[structopt(
+            parse(from_os_str),
+            long = "trace",
+            conflicts_with = "interactive"
+        )]
+        trace_path: Option<PathBuf>,
+        /// Read input from stdin and print output to stdout as the program
+        /// runs, instead of using a program spec. Each input value goes on
+        /// its own line. RLI reads as -1, since the input length is unknown.
+        #[structopt(
+            long = "interactive",
+            conflicts_with = "program-spec-path"
+        )]
+        interactive: bool,
+        /// Run again every time a source file or spec file changes. Compile
+        /// errors and failures are printed, and then watching continues.
+        /// Only supports the text format.
+        #[structopt(long = "watch", conflicts_with = "interactive")]
+        watch: bool,
+        /// Start every register with pseudo-random garbage generated from
+        /// this seed, instead of zero, to catch programs that rely on
+        /// registers starting at zero. Overrides the initial state from the
+        /// hardware spec.
+        #[structopt(long = "random-init", value_name = "seed")]
+        random_init: Option<u64>,
+        /// Fold arithmetic on constants at compile time. See
+        /// CompileOptions::constant_folding.
+        #[structopt(long = "constant-folding")]
+        constant_folding: bool,
+    },
+
+    /// Run every solution for every puzzle listed in a manifest file, and
+    /// print a table of results for each puzzle. Fails if any solution
+    /// doesn't pass.
+    #[structopt(name = "test")]
+    Test {
+        /// Path to the manifest file, in TOML format. Each `[[puzzle]]` has a
+        /// `name`, optional `hardware` and `program` spec paths, and a list of
+        /// `solutions` globs. Solutions matched by a top-level `solutions`
+        /// list are paired with a puzzle by the `;! puzzle:` line in their
+        /// metadata. Paths are relative to the manifest.
+        #[structopt(parse(from_os_str))]
+        manifest_path: PathBuf,
+        /// Only run solutions whose puzzle name or path contains this
+        #[structopt(long = "filter")]
+        filter: Option<String>,
+        /// Also write the results to this file as a JUnit XML report
+        #[structopt(parse(from_os_str), long = "report")]
+        report_path: Option<PathBuf>,
+        /// Path to the hardware spec file for puzzles that don't list their
+        /// own, in JSON format. If not provided, the minimal hardware spec
+        /// (one register, no stacks) will be used.
+        #[structopt(parse(from_os_str), long = "hardware")]
+        hardware_spec_path: Option<PathBuf>,
+        /// Path to the program spec file for puzzles that don't list their
+        /// own, in JSON format. If not provided, a default program spec will
+        /// be used.
+        #[structopt(parse(from_os_str), long = "program", short = "p")]
+        program_spec_path: Option<PathBuf>,
+        /// Fold arithmetic on constants at compile time. See
+        /// CompileOptions::constant_folding.
+        #[structopt(long = "constant-folding")]
+        constant_folding: bool,
+    },
+
+    /// Compile source code once, then run it against the program spec over
+    /// and over, and report how it performed.
+    #[structopt(name = "bench")]
+    Bench {
+        /// Path to the hardware spec file, in JSON format. If not provided,
+        /// the minimal hardware spec (one register, no stacks) will be used.
         #[structopt(parse(from_os_str), long = "hardware")]
         hardware_spec_path: Option<PathBuf>,
         /// Path to the program spec file, in JSON format. If not provided, a
@@ -39,13 +221,205 @@ enum Command {
         /// Path to the source code file
         #[structopt(parse(from_os_str), long = "source", short = "s")]
         source_path: PathBuf,
+        /// Number of times to run every test case
+        #[structopt(long = "iterations", short = "n", default_value = "10")]
+        iterations: usize,
+        /// Output format, either text or json. In json mode, compile errors
+        /// are printed to stderr. Defaults to text.
+        #[structopt(long = "format")]
+        format: Option<Format>,
+        /// Fold arithmetic on constants at compile time. See
+        /// CompileOptions::constant_folding.
+        #[structopt(long = "constant-folding")]
+        constant_folding: bool,
+    },
+
+    /// Compile source code and step through its execution interactively.
+    #[structopt(name = "debug")]
+    Debug {
+        /// Path to the hardware spec file, in JSON format. If not provided,
+        /// the minimal hardware spec (one register, no stacks) will be used.
+        #[structopt(parse(from_os_str), long = "hardware")]
+        hardware_spec_path: Option<PathBuf>,
+        /// Path to the program spec file, in JSON format. If not provided, a
+        /// default program spec will be used.
+        #[structopt(parse(from_os_str), long = "program", short = "p")]
+        program_spec_path: Option<PathBuf>,
+        /// Path to the source code file
+        #[structopt(parse(from_os_str), long = "source", short = "s")]
+        source_path: PathBuf,
+    },
+
+    /// Format source code in the canonical style. The file is rewritten in
+    /// place.
+    #[structopt(name = "fmt")]
+    Fmt {
+        /// Don't modify the file, just check if it's already formatted. Exits
+        /// with an error if it isn't.
+        #[structopt(long = "check")]
+        check: bool,
+        /// Keep statements that share a line (e.g. `READ RX0 | WRITE RX0`)
+        /// on one line, instead of splitting them up
+        #[structopt(long = "keep-shared-lines")]
+        keep_shared_lines: bool,
+        /// Path to the source code file
+        #[structopt(parse(from_os_str))]
+        source_path: PathBuf,
+    },
+
+    /// Check spec files for problems, without compiling anything. Every
+    /// problem is printed, along with the JSON pointer of the offending field.
+    #[structopt(name = "check")]
+    Check {
+        /// Path to the hardware spec file, in JSON format
+        #[structopt(parse(from_os_str), long = "hardware")]
+        hardware_spec_path: PathBuf,
+        /// Path to the program spec file, in JSON format
+        #[structopt(parse(from_os_str), long = "program", short = "p")]
+        program_spec_path: Option<PathBuf>,
+    },
+
+    /// Print a human-readable reference card for a hardware spec, including
+    /// every register and stack that programs can use, and optionally for a
+    /// program spec too.
+    #[structopt(name = "spec")]
+    Spec {
+        /// Path to the hardware spec file, in JSON format. If not provided,
+        /// the minimal hardware spec (one register, no stacks) will be used.
+        #[structopt(parse(from_os_str), long = "hardware")]
+        hardware_spec_path: Option<PathBuf>,
+        /// Path to the program spec file, in JSON format
+        #[structopt(parse(from_os_str), long = "program", short = "p")]
+        program_spec_path: Option<PathBuf>,
+    },
+
+    /// Print a reference for every instruction, including its arguments and
+    /// the runtime errors it can cause.
+    #[structopt(name = "docs")]
+    Docs {
+        /// Path to the hardware spec file, in JSON format. If provided, only
+        /// the instructions that can be used on that hardware are included.
+        #[structopt(parse(from_os_str), long = "hardware")]
+        hardware_spec_path: Option<PathBuf>,
     },
 }
 
+impl Command {
+    /// Fill in every option that wasn't given on the command line with its
+    /// value from the config, if the config has one. Only commands that run
+    /// or compile programs use the config.
+    fn apply_config(&mut self, config: &Config) {
+        let (hardware_spec_path, program_spec_path, format, constant_folding) =
+            match self {
+                Self::Compile {
+                    hardware_spec_path,
+                    constant_folding,
+                    ..
+                } => (hardware_spec_path, None, None, constant_folding),
+                Self::Run {
+                    hardware_spec_path,
+                    program_spec_path,
+                    format,
+                    constant_folding,
+                    ..
+                }
+                | Self::Bench {
+                    hardware_spec_path,
+                    program_spec_path,
+                    format,
+                    constant_folding,
+                    ..
+                } => (
+                    hardware_spec_path,
+                    Some(program_spec_path),
+                    Some(format),
+                    constant_folding,
+                ),
+                Self::Test {
+                    hardware_spec_path,
+                    program_spec_path,
+                    constant_folding,
+                    ..
+                } => (
+                    hardware_spec_path,
+                    Some(program_spec_path),
+                    None,
+                    constant_folding,
+                ),
+                _ => return,
+            };
+        if hardware_spec_path.is_none() {
+            *hardware_spec_path = config.hardware.clone();
+        }
+        if let Some(program_spec_path) = program_spec_path {
+            if program_spec_path.is_none() {
+                *program_spec_path = config.program.clone();
+            }
+        }
+        if let Some(format) = format {
+            *format = format.or(config.format);
+        }
+        // There's no flag to turn this off, so the config can only turn it on
+        *constant_folding |= config.constant_folding;
+    }
+
+    /// If watch mode was requested, turn it off and get the files to watch.
+    /// The command can then be run over and over as normal. Returns `None` if
+    /// watch mode wasn't requested.
+    fn take_watch_paths(&mut self) -> anyhow::Result<Option<Vec<PathBuf>>> {
+        let (watch, spec_paths, source_paths) = match self {
+            Self::Compile {
+                hardware_spec_path,
+                source_path,
+                watch,
+                ..
+            } => (
+                watch,
+                vec![hardware_spec_path.clone()],
+                vec![source_path.clone()],
+            ),
+            Self::Run {
+                hardware_spec_path,
+                program_spec_path,
+                source_paths,
+                format,
+                watch,
+                ..
+            } => {
+                // JSON output is meant for other programs, which can't handle
+                // the screen being cleared
+                if *watch && matches!(format, Some(Format::Json)) {
+                    return Err(anyhow!(
+                        "Watch mode only supports the text format"
+                    ));
+                }
+                (
+                    watch,
+                    vec![hardware_spec_path.clone(), program_spec_path.clone()],
+                    source_paths.clone(),
+                )
+            }
+            _ => return Ok(None),
+        };
+        if !*watch {
+            return Ok(None);
+        }
+        *watch = false;
+        let mut paths = batch::collect_sources(&source_paths)?;
+        paths.extend(spec_paths.into_iter().flatten());
+        Ok(Some(paths))
+    }
+}
+
 /// GDLK executable, for compiling and executing GDLK programs
-#[derive(Debug, StructOpt)]
+#[derive(Clone, Debug, StructOpt)]
 #[structopt(name = "gdlk")]
 struct Opt {
+    /// Path to a config file with default options, in TOML format. If not
+    /// provided, the closest gdlk.toml in the current directory or any of its
+    /// parents is used, if there is one.
+    #[structopt(parse(from_os_str), long = "config", global = true)]
+    config_path: Option<PathBuf>,
     #[structopt(subcommand)]
     cmd: Command,
 }
@@ -56,7 +430,9 @@ fn read_file(path: &Path) -> anyhow::Result<String> {
 }
 
 /// Loads a hardware or program spec from a file. If the path is None, returns
-/// the default value instead.
+/// the default value instead. The spec is validated as it's loaded, so an
+/// invalid spec is an error here. Use the check command to see every problem
+/// with a spec, rather than just the first.
 fn load_spec<T: Default + DeserializeOwned>(
     path_opt: &Option<PathBuf>,
 ) -> anyhow::Result<T> {
@@ -64,9 +440,360 @@ fn load_spec<T: Default + DeserializeOwned>(
         None => Ok(T::default()),
         Some(path) => {
             let spec_str = read_file(path)?;
-            Ok(serde_json::from_str(&spec_str)?)
+            serde_json::from_str(&spec_str)
+                .with_context(|| format!("Invalid spec file {:?}", path))
+        }
+    }
+}
+
+/// Loads a hardware spec from a file. If the path is None, returns
+/// [HardwareSpec::minimal] instead.
+fn load_hardware_spec(
+    path_opt: &Option<PathBuf>,
+) -> anyhow::Result<HardwareSpec> {
+    match path_opt {
+        None => Ok(HardwareSpec::minimal()),
+        Some(_) => load_spec(path_opt),
+    }
+}
+
+/// Read a spec file as raw JSON, check it, and print every problem that was
+/// found. Returns the number of problems.
+fn check_spec(
+    path: &Path,
+    check: fn(&mut Checker, &Value),
+) -> anyhow::Result<usize> {
+    let value: Value = serde_json::from_str(&read_file(path)?)
+        .with_context(|| format!("Failed to parse JSON in {:?}", path))?;
+    let mut checker = Checker::default();
+    check(&mut checker, &value);
+    for violation in checker.violations() {
+        println!("{}: {}", path.display(), violation);
+    }
+    Ok(checker.violations().len())
+}
+
+/// Get the options to compile with, based on the command line flags
+fn compile_options(constant_folding: bool) -> CompileOptions {
+    CompileOptions {
+        constant_folding,
+        ..CompileOptions::default()
+    }
+}
+
+/// Get the resolver for include directives in the given source file. Included
+/// paths are relative to the directory that the source file is in.
+fn include_resolver(source_path: &Path) -> FileResolver {
+    match source_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => FileResolver::new(dir),
+        _ => FileResolver::new("."),
+    }
+}
+
+/// Compile the source code, and print the program's name (from its metadata)
+/// and any warnings that were generated. Warnings don't cause a failure, so
+/// they go to stderr alongside the normal output. Includes are resolved
+/// relative to the source file.
+fn compile(
+    source: String,
+    source_path: &Path,
+    hw_spec: HardwareSpec,
+    options: CompileOptions,
+) -> anyhow::Result<Compiler<Program<Span>>> {
+    let compiler = Compiler::compile_with_options_and_resolver(
+        source,
+        hw_spec,
+        options,
+        &include_resolver(source_path),
+    )?;
+    if let Some(name) = &compiler.metadata().name {
+        eprintln!("Solution: {}", name);
+    }
+    let warnings = compiler.warnings();
+    if !warnings.errors().is_empty() {
+        eprintln!("{}", warnings);
+    }
+    Ok(compiler)
+}
+
+/// Compile the source code for JSON output. If compilation fails, the errors
+/// are printed to stderr as JSON and the process exits, so that nothing else
+/// gets mixed into the output.
+fn compile_json(
+    source: String,
+    source_path: &Path,
+    hw_spec: HardwareSpec,
+    options: CompileOptions,
+) -> anyhow::Result<Compiler<Program<Span>>> {
+    match Compiler::compile_with_options_and_resolver(
+        source,
+        hw_spec,
+        options,
+        &include_resolver(source_path),
+    ) {
+        Ok(compiler) => Ok(compiler),
+        Err(errors) => {
+            let output = CompileOutput {
+                errors: JsonError::from_errors(&errors),
+            };
+            eprintln!("{}", serde_json::to_string(&output)?);
+            process::exit(1);
+        }
+    }
+}
+
+/// Execute each machine to completion, and print the outcome of each one in a
+/// human-readable format.
+fn print_results(machines: &mut [Machine], show_dumps: bool) {
+    // Every machine has the same hardware, so they all have the same seed
+    if let Some(seed) = machines.first().and_then(Machine::seed) {
+        println!("Registers randomized with seed {}", seed);
+    }
+    for (i, machine) in machines.iter_mut().enumerate() {
+        println!("===== Test case {} =====", i + 1);
+        // Any error is stored on the machine, so we can check it after
+        let _ = machine.execute_all();
+
+        // Print dumps before checking the result, since they're most useful
+        // when something went wrong
+        if show_dumps {
+            print_dumps(machine);
+        }
+
+        let hardware_spec = machine.hardware_spec();
+        match machine.error() {
+            None => println!(
+                "Registers: {:#?}
+Stacks: {:?}
+Input: {:?}
+Output: {:?}",
+                OrderedMap::registers(hardware_spec, machine.registers()),
+                OrderedMap::stacks(hardware_spec, machine.stacks()),
+                machine.input(),
+                machine.output(),
+            ),
+            Some(error) => eprintln!("{}", error),
+        }
+    }
+
+    // Summarize all test cases at the end, so failures are easy to spot
+    println!("===== Summary =====");
+    for (i, machine) in machines.iter().enumerate() {
+        let outcome = match machine.failure_reason() {
+            None => "SUCCESS".to_owned(),
+            Some(reason) => format!("FAILURE ({:?})", reason),
+        };
+        print!(
+            "Test case {}: {} in {} cycles",
+            i + 1,
+            outcome,
+            machine.cycle_count()
+        );
+        // Most programs never write to RZR, so only mention it if they do
+        if machine.null_writes() > 0 {
+            print!(", {} writes to RZR", machine.null_writes());
+        }
+        // Point out where the output went wrong, so it doesn't have to be
+        // lined up with the spec by hand
+        match machine.output_diff() {
+            Some(diff) => println!(", {}", diff),
+            None => println!(),
+        }
+    }
+}
+
+/// Execute each machine to completion, and print the outcome of all of them
+/// as a single JSON object.
+fn print_results_json(
+    compiler: &Compiler<Program<Span>>,
+    machines: &mut [Machine],
+) -> anyhow::Result<()> {
+    let test_cases = machines
+        .iter_mut()
+        .map(|machine| {
+            // Any error gets stored in the machine, and included below
+            let _ = machine.execute_all();
+            TestCaseOutput::from(&*machine)
+        })
+        .collect::<Vec<_>>();
+    let output = RunOutput {
+        success: test_cases.iter().all(|test_case| test_case.success),
+        name: compiler.metadata().name.clone(),
+        seed: machines.first().and_then(Machine::seed),
+        warnings: JsonError::from_errors(&compiler.warnings()),
+        test_cases,
+    };
+    println!("{}", serde_json::to_string_pretty(&output)?);
+    Ok(())
+}
+
+/// Execute each machine to completion, and write a trace of every test case
+/// to the given file. Each trace starts with a `# Test case <n>` line.
+fn write_trace(path: &Path, machines: &mut [Machine]) -> anyhow::Result<()> {
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create trace file {:?}", path))?;
+    let mut writer = BufWriter::new(file);
+    for (i, machine) in machines.iter_mut().enumerate() {
+        writeln!(writer, "# Test case {}", i + 1)?;
+        // Any runtime error is stored on the machine, and reported with the
+        // rest of the results
+        let _ = machine.execute_all_traced(&mut writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Print the compiled program as GDLK source, one instruction per line. Labels
+/// are included so that the output can be compiled again.
+fn print_asm(compiler: &Compiler<Program<Span>>) {
+    let instructions = compiler.instructions_pretty();
+    // Labels are sorted by index, so we can walk them alongside the
+    // instructions. A label can point one past the last instruction, so that's
+    // included too.
+    let mut labels = compiler.program().labels().peekable();
+    for index in 0..=instructions.len() {
+        while let Some((label, _)) =
+            labels.next_if(|(_, label_index)| *label_index == index)
+        {
+            println!("{}:", label);
+        }
+        if let Some((text, _)) = instructions.get(index) {
+            println!("    {}", text);
+        }
+    }
+}
+
+/// Registers or stacks, keyed by name. This formats like a map, but keeps
+/// the order that the machine gives (see [Machine::registers]), which reads
+/// better than sorting by name, where `RX10` comes before `RX2`.
+struct OrderedMap<T>(Vec<(String, T)>);
+
+impl<T> OrderedMap<T> {
+    fn registers(
+        hardware_spec: &HardwareSpec,
+        registers: impl IntoIterator<Item = (RegisterRef, T)>,
+    ) -> Self {
+        Self(
+            registers
+                .into_iter()
+                .map(|(reg_ref, value)| {
+                    (hardware_spec.register_name(reg_ref), value)
+                })
+                .collect(),
+        )
+    }
+
+    fn stacks(
+        hardware_spec: &HardwareSpec,
+        stacks: impl IntoIterator<Item = (StackRef, T)>,
+    ) -> Self {
+        Self(
+            stacks
+                .into_iter()
+                .map(|(stack_ref, values)| {
+                    (hardware_spec.stack_name(stack_ref), values)
+                })
+                .collect(),
+        )
+    }
+}
+
+impl<T: Debug> Debug for OrderedMap<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(name, value)| (name, value)))
+            .finish()
+    }
+}
+
+/// Print all debug snapshots that the machine has collected from `DUMP`
+/// instructions.
+fn print_dumps(machine: &Machine) {
+    let hardware_spec = machine.hardware_spec();
+    for snapshot in machine.debug_snapshots() {
+        let registers = OrderedMap::registers(
+            hardware_spec,
+            snapshot
+                .registers
+                .iter()
+                .map(|(reg_ref, value)| (*reg_ref, value)),
+        );
+        let stacks = OrderedMap::stacks(
+            hardware_spec,
+            snapshot
+                .stacks
+                .iter()
+                .map(|(stack_ref, values)| (*stack_ref, values)),
+        );
+        println!(
+            "DUMP at line {} (cycle {})
+  Registers: {:?}
+  Stacks: {:?}",
+            snapshot.span.start_line, snapshot.cycle, registers, stacks,
+        );
+    }
+}
+
+/// Print the reference for the given instructions, with a blank line between
+/// each one
+fn print_docs<'a>(infos: impl Iterator<Item = &'a InstructionInfo>) {
+    for (i, info) in infos.enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print!("{}", info.kind);
+        for arg in info.args {
+            print!(" <{}>", arg);
+        }
+        println!("\n    {}", info.description);
+        if !info.errors.is_empty() {
+            println!("    Errors: {}", info.errors.join(", "));
+        }
+    }
+}
+
+/// Run every source file under the given paths, and print a summary of how
+/// each one did. Fails if any file didn't pass.
+fn run_batch(
+    source_paths: &[PathBuf],
+    hw_spec: &HardwareSpec,
+    program_spec: &ProgramSpec,
+    options: CompileOptions,
+    format: Format,
+    fail_fast: bool,
+) -> anyhow::Result<()> {
+    let sources = batch::collect_sources(source_paths)?;
+    let mut results = Vec::with_capacity(sources.len());
+    for path in &sources {
+        let result = batch::run_file(path, hw_spec, program_spec, options);
+        // Print problems as we go, so they're next to the file they're for
+        if let (Format::Text, Some(details)) = (format, &result.details) {
+            eprintln!("===== {} =====\n{}", path.display(), details);
+        }
+        let successful = result.successful();
+        results.push(result);
+        if fail_fast && !successful {
+            break;
         }
     }
+
+    match format {
+        Format::Text => print!("{}", batch::Summary(&results)),
+        Format::Json => {
+            println!("{}", serde_json::to_string_pretty(&results)?)
+        }
+    }
+
+    let num_failed =
+        results.iter().filter(|result| !result.successful()).count();
+    if num_failed > 0 {
+        return Err(anyhow!(
+            "{} of {} files failed",
+            num_failed,
+            sources.len()
+        ));
+    }
+    Ok(())
 }
 
 fn run(opt: Opt) -> anyhow::Result<()> {
@@ -75,53 +802,319 @@ fn run(opt: Opt) -> anyhow::Result<()> {
         Command::Compile {
             hardware_spec_path,
             source_path,
+            emit,
+            watch: _,
+            constant_folding,
         } => {
-            let hw_spec: HardwareSpec = load_spec(&hardware_spec_path)?;
+            let hw_spec = load_hardware_spec(&hardware_spec_path)?;
+            let options = compile_options(constant_folding);
             // Read the source code from the file
             let source = read_file(&source_path)?;
             // Compile
-            Compiler::compile(source, hw_spec)?;
+            let compiler = compile(source, &source_path, hw_spec, options)?;
+            if let Some(Emit::Asm) = emit {
+                print_asm(&compiler);
+            }
         }
 
         // Compile and build the given program
         Command::Run {
             hardware_spec_path,
             program_spec_path,
-            source_path,
+            source_paths,
+            show_dumps,
+            format,
+            fail_fast,
+            trace_path,
+            interactive,
+            watch: _,
+            random_init,
+            constant_folding,
         } => {
+            let format = format.unwrap_or_default();
+            let options = compile_options(constant_folding);
             // Read and parse the hw spec and program spec from JSON files
-            let hw_spec: HardwareSpec = load_spec(&hardware_spec_path)?;
+            let mut hw_spec = load_hardware_spec(&hardware_spec_path)?;
+            if let Some(seed) = random_init {
+                hw_spec.initial_state = InitialState::Random { seed };
+            }
+            let source_path = match source_paths.as_slice() {
+                [source_path] if !source_path.is_dir() => source_path,
+                _ if interactive => {
+                    return Err(anyhow!(
+                        "Interactive mode only supports a single source file"
+                    ));
+                }
+                _ if trace_path.is_some() => {
+                    return Err(anyhow!(
+                        "Tracing only supports a single source file"
+                    ));
+                }
+                _ => {
+                    let program_spec: ProgramSpec =
+                        load_spec(&program_spec_path)?;
+                    return run_batch(
+                        &source_paths,
+                        &hw_spec,
+                        &program_spec,
+                        options,
+                        format,
+                        fail_fast,
+                    );
+                }
+            };
+            if interactive {
+                let source = read_file(source_path)?;
+                let mut machine =
+                    compile(source, source_path, hw_spec, options)?
+                        .allocate_with_io(
+                            Box::new(StdinSource),
+                            Box::new(StdoutSink),
+                        )?;
+                if let Err(error) = machine.execute_all() {
+                    return Err(anyhow!("{}", error));
+                }
+                if show_dumps {
+                    print_dumps(&machine);
+                }
+                return Ok(());
+            }
             let program_spec: ProgramSpec = load_spec(&program_spec_path)?;
 
             // Read the source code from the file
+            let source = read_file(source_path)?;
+
+            // Compile, then execute each test case
+            let compiler = match format {
+                Format::Text => compile(source, source_path, hw_spec, options)?,
+                Format::Json => {
+                    compile_json(source, source_path, hw_spec, options)?
+                }
+            };
+            let mut machines = compiler.allocate_all(&program_spec)?;
+            // Tracing runs the machines to completion, so the results below
+            // just report on them
+            if let Some(trace_path) = &trace_path {
+                write_trace(trace_path, &mut machines)?;
+            }
+            match format {
+                Format::Text => print_results(&mut machines, show_dumps),
+                Format::Json => print_results_json(&compiler, &mut machines)?,
+            }
+
+            let num_failed = machines
+                .iter()
+                .filter(|machine| !machine.successful())
+                .count();
+            if num_failed > 0 {
+                return Err(anyhow!(
+                    "{} of {} test cases failed",
+                    num_failed,
+                    machines.len()
+                ));
+            }
+        }
+
+        // Run the given program repeatedly, and report stats on the runs
+        Command::Bench {
+            hardware_spec_path,
+            program_spec_path,
+            source_path,
+            iterations,
+            format,
+            constant_folding,
+        } => {
+            let options = compile_options(constant_folding);
+            if iterations == 0 {
+                return Err(anyhow!("Iterations must be at least 1"));
+            }
+            let hw_spec = load_hardware_spec(&hardware_spec_path)?;
+            let program_spec: ProgramSpec = load_spec(&program_spec_path)?;
             let source = read_file(&source_path)?;
 
-            // Compile and execute
-            let mut machine =
-                Compiler::compile(source, hw_spec)?.allocate(&program_spec);
-            let success = machine.execute_all().map_err(Clone::clone)?;
+            let results = match format.unwrap_or_default() {
+                Format::Text => {
+                    let compiler =
+                        compile(source, &source_path, hw_spec, options)?;
+                    let results =
+                        bench::bench(&compiler, &program_spec, iterations)?;
+                    print!("{}", results);
+                    results
+                }
+                Format::Json => {
+                    let compiler =
+                        compile_json(source, &source_path, hw_spec, options)?;
+                    let results =
+                        bench::bench(&compiler, &program_spec, iterations)?;
+                    println!("{}", serde_json::to_string_pretty(&results)?);
+                    results
+                }
+            };
 
-            println!(
-                "Registers: {:#?}
-Stacks: {:?}
-Input: {:?}
-Output: {:?}
-Cycles: {}
-Program completed with {}",
-                machine.registers(),
-                machine.stacks(),
-                machine.input(),
-                machine.output(),
-                machine.cycle_count(),
-                if success { "SUCCESS" } else { "FAILURE" },
-            );
+            // Execution is supposed to be deterministic, so this is a bug
+            if !results.deterministic {
+                return Err(anyhow!(
+                    "Cycle counts changed between runs, which means \
+                    execution is nondeterministic. This is a bug in GDLK!"
+                ));
+            }
+        }
+
+        // Compile the given program and start a debugging session
+        Command::Debug {
+            hardware_spec_path,
+            program_spec_path,
+            source_path,
+        } => {
+            let hw_spec = load_hardware_spec(&hardware_spec_path)?;
+            let program_spec: ProgramSpec = load_spec(&program_spec_path)?;
+            let source = read_file(&source_path)?;
+
+            let machine = compile(
+                source,
+                &source_path,
+                hw_spec,
+                CompileOptions::default(),
+            )?
+            .allocate(&program_spec)?;
+            if !debug::debug(machine)? {
+                return Err(anyhow!("Program did not complete successfully"));
+            }
+        }
+
+        // Format the given program, or check that it's already formatted
+        Command::Fmt {
+            check,
+            keep_shared_lines,
+            source_path,
+        } => {
+            let source = read_file(&source_path)?;
+            let formatted = gdlk::fmt::format_program_with_options(
+                &source,
+                gdlk::fmt::FormatOptions { keep_shared_lines },
+            )?;
+            if formatted != source {
+                if check {
+                    return Err(anyhow!("{:?} is not formatted", source_path));
+                }
+                fs::write(&source_path, formatted).with_context(|| {
+                    format!("Failed to write file {:?}", source_path)
+                })?;
+            }
+        }
+
+        // Check the given spec files, and report every problem with them
+        Command::Check {
+            hardware_spec_path,
+            program_spec_path,
+        } => {
+            let mut num_violations =
+                check_spec(&hardware_spec_path, Checker::check_hardware_spec)?;
+            if let Some(program_spec_path) = program_spec_path {
+                num_violations += check_spec(
+                    &program_spec_path,
+                    Checker::check_program_spec,
+                )?;
+            }
+            if num_violations > 0 {
+                return Err(anyhow!(
+                    "Found {} problem(s) in spec files",
+                    num_violations
+                ));
+            }
+        }
+
+        // Print the reference card for the given spec files
+        Command::Spec {
+            hardware_spec_path,
+            program_spec_path,
+        } => {
+            let hw_spec = load_hardware_spec(&hardware_spec_path)?;
+            print!("{}", hw_spec);
+            if program_spec_path.is_some() {
+                let program_spec: ProgramSpec = load_spec(&program_spec_path)?;
+                print!("{}", program_spec);
+            }
+        }
+
+        // Run every puzzle's solutions, and report how each one did
+        Command::Test {
+            manifest_path,
+            filter,
+            report_path,
+            hardware_spec_path,
+            program_spec_path,
+            constant_folding,
+        } => {
+            let manifest = manifest::Manifest::load(&manifest_path)?;
+            let defaults = manifest::Defaults {
+                hardware_spec_path,
+                program_spec_path,
+                options: compile_options(constant_folding),
+            };
+            let results = manifest::run_manifest(
+                &manifest,
+                &defaults,
+                filter.as_deref(),
+            )?;
+            // Print problems first, so the tables all end up together
+            for result in results.iter().flat_map(|puzzle| &puzzle.results) {
+                if let Some(details) = &result.details {
+                    eprintln!(
+                        "===== {} =====\n{}",
+                        result.path.display(),
+                        details
+                    );
+                }
+            }
+            print!("{}", manifest::Matrix(&results));
+            if let Some(report_path) = report_path {
+                manifest::write_junit_report(&report_path, &results)?;
+            }
+
+            let all_results = results.iter().flat_map(|puzzle| &puzzle.results);
+            let num_failed = all_results
+                .clone()
+                .filter(|result| !result.successful())
+                .count();
+            if num_failed > 0 {
+                return Err(anyhow!(
+                    "{} of {} solutions failed",
+                    num_failed,
+                    all_results.count()
+                ));
+            }
+        }
+
+        // Print the instruction reference, for the given hardware if any
+        Command::Docs { hardware_spec_path } => {
+            let hw_spec = match hardware_spec_path {
+                Some(_) => Some(load_hardware_spec(&hardware_spec_path)?),
+                None => None,
+            };
+            print_docs(INSTRUCTION_CATALOG.iter().filter(
+                |info| match &hw_spec {
+                    Some(hw_spec) => info.is_available(hw_spec),
+                    None => true,
+                },
+            ));
         }
     }
     Ok(())
 }
 
+/// Load the config, and then run the command, in watch mode if requested
+fn start(mut opt: Opt) -> anyhow::Result<()> {
+    let config = Config::find(opt.config_path.as_deref())?;
+    opt.cmd.apply_config(&config);
+    match opt.cmd.take_watch_paths()? {
+        Some(paths) => watch::watch(paths, || run(opt.clone())),
+        None => run(opt),
+    }
+}
+
 fn main() {
-    let exit_code = match run(Opt::from_args()) {
+    let exit_code = match start(Opt::from_args()) {
         Ok(_) => 0,
         Err(err) => {
             eprintln!("{:#}", err);
@@ -0,0 +1,323 @@
+//! Running a whole folder of puzzles at once, for the `test` command. The
+//! puzzles are listed in a TOML manifest, along with the solutions for each
+//! one:
+//!
+//! ```toml
+//! [[puzzle]]
+//! name = "reverse"
+//! hardware = "reverse/hardware.json"
+//! program = "reverse/program.json"
+//! solutions = ["reverse/*.gdlk"]
+//! ```
+//!
+//! Paths and globs are relative to the directory that the manifest is in. The
+//! spec files are optional. Puzzles without them use the ones given to the
+//! `test` command, and if there aren't any, they default the same way as in
+//! the `run` command.
+//!
+//! Solutions can also be listed at the top of the manifest, before any
+//! puzzles, with `solutions = ["shared/*.gdlk"]`. Each of those files says
+//! which puzzle it's for in its metadata header, e.g. `;! puzzle: reverse`.
+
+use crate::batch::{self, FileResult, FileStatus};
+use anyhow::{anyhow, Context};
+use gdlk::{CompileOptions, HardwareSpec, ProgramMetadata, ProgramSpec};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// A list of puzzles, and the solutions to run for each one
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(rename = "puzzle", default)]
+    pub puzzles: Vec<Puzzle>,
+    /// Glob patterns for solution files that get paired with a puzzle by
+    /// their metadata, rather than being listed under it
+    #[serde(default)]
+    pub solutions: Vec<String>,
+}
+
+/// A single puzzle in a [Manifest]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Puzzle {
+    pub name: String,
+    /// Path to the hardware spec file, in JSON format
+    pub hardware: Option<PathBuf>,
+    /// Path to the program spec file, in JSON format
+    pub program: Option<PathBuf>,
+    /// Glob patterns for the solution files. Every pattern has to match at
+    /// least one file.
+    #[serde(default)]
+    pub solutions: Vec<String>,
+}
+
+impl Manifest {
+    /// Load a manifest from a file. Every path in the returned manifest has
+    /// already been joined onto the manifest's directory.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let manifest: Self = toml::from_str(&crate::read_file(path)?)
+            .with_context(|| format!("Invalid manifest file {:?}", path))?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        // The directory is part of every pattern, so it can't have any glob
+        // characters of its own
+        let dir_pattern = glob::Pattern::escape(&dir.to_string_lossy());
+        let join_patterns = |patterns: Vec<String>| -> Vec<String> {
+            patterns
+                .iter()
+                .map(|pattern| format!("{}/{}", dir_pattern, pattern))
+                .collect()
+        };
+        Ok(Self {
+            puzzles: manifest
+                .puzzles
+                .into_iter()
+                .map(|puzzle| Puzzle {
+                    name: puzzle.name,
+                    hardware: puzzle.hardware.map(|path| dir.join(path)),
+                    program: puzzle.program.map(|path| dir.join(path)),
+                    solutions: join_patterns(puzzle.solutions),
+                })
+                .collect(),
+            solutions: join_patterns(manifest.solutions),
+        })
+    }
+
+    /// Find the solutions listed at the top of the manifest, and group them
+    /// by the puzzle named in their metadata. Every one of them has to name a
+    /// puzzle in the manifest.
+    fn paired_solutions(&self) -> anyhow::Result<HashMap<&str, Vec<PathBuf>>> {
+        let mut paired: HashMap<&str, Vec<PathBuf>> = HashMap::new();
+        for path in expand_patterns(&self.solutions, "the manifest")? {
+            let metadata =
+                ProgramMetadata::from_source(&crate::read_file(&path)?);
+            let puzzle_name = metadata.puzzle.ok_or_else(|| {
+                anyhow!(
+                    "Solution {:?} doesn't say which puzzle it's for \
+                    (add a `;! puzzle: <name>` line at the top)",
+                    path
+                )
+            })?;
+            let puzzle = self
+                .puzzles
+                .iter()
+                .find(|puzzle| puzzle.name == puzzle_name)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Solution {:?} is for puzzle `{}`, which isn't in the \
+                        manifest",
+                        path,
+                        puzzle_name
+                    )
+                })?;
+            paired.entry(&puzzle.name).or_default().push(path);
+        }
+        Ok(paired)
+    }
+}
+
+/// Find every file that matches any of the glob patterns, sorted by path.
+/// Files that match more than one pattern are only included once. `owner`
+/// says where the patterns came from, for error messages.
+fn expand_patterns(
+    patterns: &[String],
+    owner: &str,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        let matches = glob::glob(pattern)
+            .with_context(|| format!("Invalid glob pattern {:?}", pattern))?
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Failed to expand {:?}", pattern))?;
+        if matches.is_empty() {
+            return Err(anyhow!(
+                "Pattern {:?} for {} didn't match any files",
+                pattern,
+                owner
+            ));
+        }
+        paths.extend(matches);
+    }
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
+
+/// Settings that apply to every puzzle, unless the puzzle has its own
+pub struct Defaults {
+    /// Hardware spec for puzzles that don't have one
+    pub hardware_spec_path: Option<PathBuf>,
+    /// Program spec for puzzles that don't have one
+    pub program_spec_path: Option<PathBuf>,
+    pub options: CompileOptions,
+}
+
+/// The results of every solution that was run for one puzzle
+pub struct PuzzleResults {
+    pub name: String,
+    pub results: Vec<FileResult>,
+}
+
+/// Run every solution for every puzzle in the manifest. If a filter is given,
+/// only solutions whose puzzle name or path contains it are run, and puzzles
+/// with no such solutions are left out entirely.
+pub fn run_manifest(
+    manifest: &Manifest,
+    defaults: &Defaults,
+    filter: Option<&str>,
+) -> anyhow::Result<Vec<PuzzleResults>> {
+    let mut paired = manifest.paired_solutions()?;
+    let mut all_results = Vec::new();
+    for puzzle in &manifest.puzzles {
+        let mut paths = expand_patterns(
+            &puzzle.solutions,
+            &format!("puzzle `{}`", puzzle.name),
+        )?;
+        paths.extend(paired.remove(puzzle.name.as_str()).unwrap_or_default());
+        paths.sort();
+        paths.dedup();
+        let paths: Vec<PathBuf> = paths
+            .into_iter()
+            .filter(|path| match filter {
+                Some(filter) => {
+                    puzzle.name.contains(filter)
+                        || path.to_string_lossy().contains(filter)
+                }
+                None => true,
+            })
+            .collect();
+        if paths.is_empty() {
+            continue;
+        }
+
+        let hardware_path = puzzle
+            .hardware
+            .as_ref()
+            .or(defaults.hardware_spec_path.as_ref());
+        let program_path = puzzle
+            .program
+            .as_ref()
+            .or(defaults.program_spec_path.as_ref());
+        let hw_spec: HardwareSpec =
+            crate::load_hardware_spec(&hardware_path.cloned())?;
+        let program_spec: ProgramSpec =
+            crate::load_spec(&program_path.cloned())?;
+        all_results.push(PuzzleResults {
+            name: puzzle.name.clone(),
+            results: paths
+                .iter()
+                .map(|path| {
+                    batch::run_file(
+                        path,
+                        &hw_spec,
+                        &program_spec,
+                        defaults.options,
+                    )
+                })
+                .collect(),
+        });
+    }
+    if all_results.is_empty() {
+        return Err(anyhow!("No solutions to run"));
+    }
+    Ok(all_results)
+}
+
+/// One results table per puzzle, see [batch::Summary]
+pub struct Matrix<'a>(pub &'a [PuzzleResults]);
+
+impl Display for Matrix<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, puzzle) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "===== {} =====", puzzle.name)?;
+            write!(f, "{}", batch::Summary(&puzzle.results))?;
+        }
+        Ok(())
+    }
+}
+
+/// Escape text so it can go in an XML attribute or element
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Format the results as a JUnit XML report. Each puzzle is a test suite, and
+/// each solution is a test case. Anything but a pass counts as a failure.
+pub fn junit_report(all_results: &[PuzzleResults]) -> String {
+    let num_failed = |results: &[FileResult]| {
+        results.iter().filter(|result| !result.successful()).count()
+    };
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites tests=\"{}\" failures=\"{}\">\n",
+        all_results
+            .iter()
+            .map(|puzzle| puzzle.results.len())
+            .sum::<usize>(),
+        all_results
+            .iter()
+            .map(|puzzle| num_failed(&puzzle.results))
+            .sum::<usize>(),
+    ));
+    for puzzle in all_results {
+        let name = escape_xml(&puzzle.name);
+        xml.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\">\n",
+            name,
+            puzzle.results.len(),
+            num_failed(&puzzle.results),
+        ));
+        for result in &puzzle.results {
+            xml.push_str(&format!(
+                "    <testcase classname=\"{}\" name=\"{}\"",
+                name,
+                escape_xml(&result.path.display().to_string()),
+            ));
+            if result.status == FileStatus::Passed {
+                xml.push_str("/>\n");
+                continue;
+            }
+            xml.push_str(">\n");
+            xml.push_str(&format!(
+                "      <failure message=\"{}\">{}</failure>\n",
+                result.status.label(),
+                escape_xml(result.details.as_deref().unwrap_or_default()),
+            ));
+            xml.push_str("    </testcase>\n");
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+/// Write the results to a file as a JUnit XML report, see [junit_report]
+pub fn write_junit_report(
+    path: &Path,
+    all_results: &[PuzzleResults],
+) -> anyhow::Result<()> {
+    fs::write(path, junit_report(all_results))
+        .with_context(|| format!("Failed to write file {:?}", path))
+}
@@ -0,0 +1,121 @@
+//! Watch mode for the `run` and `compile` commands. The command runs once,
+//! then again every time one of its input files changes. Files are polled for
+//! changes rather than using OS notifications, which keeps this simple and
+//! behaves the same on every platform.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How often to check the watched files for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long files have to go unchanged before a change counts. Some editors
+/// write a file in several steps, and this makes sure that only triggers one
+/// run.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Clears the terminal and moves the cursor to the top left
+const CLEAR_SCREEN: &str = "\x1B[2J\x1B[1;1H";
+
+/// The state of a file that can tell us if it's changed. Length is included
+/// because some filesystems only store modification times to the second.
+/// `None` means the file couldn't be read, e.g. because an editor has deleted
+/// it in order to replace it.
+type FileState = Option<(SystemTime, u64)>;
+
+fn file_state(path: &Path) -> FileState {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Watches a fixed set of files for changes
+pub struct Watcher {
+    files: Vec<(PathBuf, FileState)>,
+}
+
+impl Watcher {
+    pub fn new(paths: impl IntoIterator<Item = PathBuf>) -> Self {
+        Self {
+            files: paths
+                .into_iter()
+                .map(|path| {
+                    let state = file_state(&path);
+                    (path, state)
+                })
+                .collect(),
+        }
+    }
+
+    /// Check every file once. Returns true if any of them changed since the
+    /// last check.
+    pub fn poll(&mut self) -> bool {
+        let mut changed = false;
+        for (path, state) in &mut self.files {
+            let new_state = file_state(path);
+            if new_state != *state {
+                *state = new_state;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Block until a file changes, and then until all files have stopped
+    /// changing for a bit (see [DEBOUNCE])
+    pub fn wait(&mut self) {
+        while !self.poll() {
+            thread::sleep(POLL_INTERVAL);
+        }
+        let mut quiet = Duration::from_secs(0);
+        while quiet < DEBOUNCE {
+            thread::sleep(POLL_INTERVAL);
+            quiet = if self.poll() {
+                Duration::from_secs(0)
+            } else {
+                quiet + POLL_INTERVAL
+            };
+        }
+    }
+}
+
+/// Format the current time of day as `HH:MM:SS`, in UTC
+fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    format!(
+        "{:02}:{:02}:{:02} UTC",
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Call the given function now, and again every time one of the files
+/// changes. Before each call, the screen is cleared and a header with the
+/// time is printed. Errors are printed, but don't stop the watching, so this
+/// never returns. The set of files is fixed, so new files in a watched
+/// directory aren't picked up.
+pub fn watch(
+    paths: impl IntoIterator<Item = PathBuf>,
+    mut f: impl FnMut() -> anyhow::Result<()>,
+) -> ! {
+    let mut watcher = Watcher::new(paths);
+    loop {
+        print!("{}", CLEAR_SCREEN);
+        println!("===== Run at {} =====", timestamp());
+        if let Err(err) = f() {
+            eprintln!("{:#}", err);
+        }
+        // Make sure everything shows up before we go idle
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+        watcher.wait();
+    }
+}
@@ -0,0 +1,84 @@
+//! Helpers shared by the CLI integration tests. Each test file only uses some
+//! of these, so unused ones aren't dead code.
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+pub fn fixtures_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+/// Get a command for the CLI, to be run from the given directory. If there
+/// isn't one, it runs from the fixtures directory.
+pub fn command(dir: Option<&Path>) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_gdlk_cli"));
+    match dir {
+        Some(dir) => command.current_dir(dir),
+        None => command.current_dir(fixtures_dir()),
+    };
+    command
+}
+
+/// Run the CLI from the given directory (or the fixtures directory), and get
+/// the exit status, stdout, and stderr
+pub fn gdlk(dir: Option<&Path>, args: &[&str]) -> (bool, String, String) {
+    let output = command(dir).args(args).output().unwrap();
+    (
+        output.status.success(),
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+/// A temp directory for tests that need files outside of `tests/fixtures`.
+/// Each test builds the layout it needs on top of this. Deleted on drop.
+pub struct TempProject(PathBuf);
+
+impl TempProject {
+    /// Create an empty temp directory. `name` has to be unique across every
+    /// test, since tests run in parallel.
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "gdlk_test_{}_{}",
+            name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Get the full path of a file in the directory, as a CLI argument
+    pub fn file(&self, path: &str) -> String {
+        self.0.join(path).to_str().unwrap().to_owned()
+    }
+
+    /// Copy a file from `tests/fixtures` to the given path in the directory,
+    /// creating any missing parent directories
+    pub fn copy_fixture(&self, fixture: &str, path: &str) {
+        let dest = self.0.join(path);
+        fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        fs::copy(fixtures_dir().join(fixture), dest).unwrap();
+    }
+
+    /// Write a file at the given path in the directory, creating any missing
+    /// parent directories
+    pub fn write(&self, path: &str, contents: &str) {
+        let dest = self.0.join(path);
+        fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        fs::write(dest, contents).unwrap();
+    }
+}
+
+impl Drop for TempProject {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,65 @@
+//! Integration tests for the `bench` command. The spec and source files are
+//! all in `tests/fixtures`.
+
+mod common;
+
+use common::gdlk;
+use serde_json::{json, Value};
+
+/// Arguments to bench the reverse program, which is correct for the valid
+/// spec files
+const BENCH_REVERSE: [&str; 7] = [
+    "bench",
+    "--hardware",
+    "hardware_valid.json",
+    "--program",
+    "program_valid.json",
+    "-s",
+    "reverse.gdlk",
+];
+
+#[test]
+fn test_bench_text() {
+    let (success, stdout, stderr) =
+        gdlk(None, &[&BENCH_REVERSE[..], &["-n", "3"]].concat());
+    assert!(success, "{}", stderr);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 5, "{}", stdout);
+    assert_eq!(lines[0], "Instructions: 8");
+    assert_eq!(lines[1], "Iterations: 3");
+    // PUSH costs 2 cycles on this hardware
+    assert_eq!(lines[2], "Cycles per test case: 20, 2");
+    // Timing varies, so just check the layout
+    assert!(lines[3].starts_with("Time per run: min "), "{}", lines[3]);
+    assert_eq!(lines[4], "Peak stack usage: CARGO=2");
+}
+
+#[test]
+fn test_bench_json() {
+    let (success, stdout, stderr) =
+        gdlk(None, &[&BENCH_REVERSE[..], &["--format", "json"]].concat());
+    assert!(success, "{}", stderr);
+    let mut output: Value = serde_json::from_str(&stdout).unwrap();
+    let time = output.as_object_mut().unwrap().remove("time_ns").unwrap();
+    assert_eq!(
+        output,
+        json!({
+            "num_instructions": 8,
+            "iterations": 10,
+            "cycle_counts": [20, 2],
+            "deterministic": true,
+            "max_stack_usage": {"CARGO": 2},
+        })
+    );
+    assert!(time["min"].as_u64() <= time["median"].as_u64());
+    assert!(time["median"].as_u64() <= time["max"].as_u64());
+}
+
+#[test]
+fn test_bench_zero_iterations() {
+    let (success, stdout, stderr) =
+        gdlk(None, &[&BENCH_REVERSE[..], &["-n", "0"]].concat());
+    assert!(!success);
+    assert_eq!(stdout, "");
+    assert_eq!(stderr, "Iterations must be at least 1\n");
+}
@@ -0,0 +1,273 @@
+//! Integration tests for the `check` command, and for how the other commands
+//! handle invalid spec files. The spec files are all in `tests/fixtures`.
+
+mod common;
+
+use common::gdlk;
+
+/// Run the check command on a hardware spec, and optionally a program spec,
+/// and make sure it reports exactly the expected problems
+macro_rules! assert_check {
+    ($args:expr, $expected:expr $(,)?) => {
+        let (success, stdout, stderr) = gdlk(None, $args);
+        let expected: &[&str] = $expected;
+        assert_eq!(stdout.lines().collect::<Vec<_>>(), expected);
+        if expected.is_empty() {
+            assert!(success, "{}", stderr);
+        } else {
+            assert!(!success);
+            assert_eq!(
+                stderr.trim(),
+                format!("Found {} problem(s) in spec files", expected.len())
+            );
+        }
+    };
+}
+
+#[test]
+fn test_check_valid() {
+    assert_check!(&["check", "--hardware", "hardware_valid.json"], &[]);
+    assert_check!(
+        &[
+            "check",
+            "--hardware",
+            "hardware_valid.json",
+            "--program",
+            "program_valid.json"
+        ],
+        &[]
+    );
+    assert_check!(
+        &[
+            "check",
+            "--hardware",
+            "hardware_valid.json",
+            "-p",
+            "program_single.json"
+        ],
+        &[]
+    );
+}
+
+#[test]
+fn test_check_hardware_bounds() {
+    assert_check!(
+        &["check", "--hardware", "hardware_bounds.json"],
+        &[
+            "hardware_bounds.json: /num_registers: Expected num_registers to \
+                be between 1 and 16, but got 0",
+            "hardware_bounds.json: /num_stacks: Expected num_stacks to be \
+                between 0 and 16, but got 17",
+            "hardware_bounds.json: /max_stack_length: Expected \
+                max_stack_length to be between 0 and 256, but got 1000",
+        ]
+    );
+}
+
+#[test]
+fn test_check_hardware_types() {
+    assert_check!(
+        &["check", "--hardware", "hardware_types.json"],
+        &[
+            "hardware_types.json: /num_registers: invalid value: integer \
+                `-1`, expected usize",
+            "hardware_types.json: /num_stacks: Missing required field",
+            "hardware_types.json: /max_stack_length: invalid type: string \
+                \"10\", expected usize",
+            "hardware_types.json: /dump_enabled: invalid type: string \
+                \"yes\", expected a boolean",
+        ]
+    );
+}
+
+#[test]
+fn test_check_hardware_names() {
+    assert_check!(
+        &["check", "--hardware", "hardware_name_count.json"],
+        &[
+            "hardware_name_count.json: /register_names: Expected 2 register \
+                names, but got 1",
+            "hardware_name_count.json: /stack_names: Expected 1 stack names, \
+                but got 2",
+        ]
+    );
+    assert_check!(
+        &["check", "--hardware", "hardware_names.json"],
+        &[
+            "hardware_names.json: /register_names/1: Invalid name `2FAST`: \
+                names must start with a letter or underscore, and contain \
+                only ASCII letters, digits, and underscores",
+            "hardware_names.json: /register_names/2: Name `RZR` is reserved \
+                for a built-in register or stack",
+            "hardware_names.json: /stack_names/1: Name `fuel` is used more \
+                than once",
+        ]
+    );
+}
+
+#[test]
+fn test_check_hardware_stack_lengths() {
+    // Per-stack lengths can replace the uniform length
+    assert_check!(&["check", "--hardware", "hardware_stack_lengths.json"], &[]);
+    assert_check!(
+        &["check", "--hardware", "hardware_stack_lengths_invalid.json"],
+        &[
+            "hardware_stack_lengths_invalid.json: /stack_lengths: Expected 2 \
+                stack lengths, but got 3",
+            "hardware_stack_lengths_invalid.json: /stack_lengths/1: Expected \
+                stack_lengths to be between 0 and 8, but got 16",
+        ]
+    );
+}
+
+#[test]
+fn test_check_hardware_cost() {
+    assert_check!(
+        &["check", "--hardware", "hardware_cost.json"],
+        &[
+            "hardware_cost.json: /cost_model/READ: Cost of `READ` must be at \
+                least 1 cycle",
+            "hardware_cost.json: /cost_model/NOP: Cost of `NOP` must be at \
+                least 1 cycle",
+        ]
+    );
+}
+
+#[test]
+fn test_check_program() {
+    // The type in serde's error depends on the width of LangValue
+    let float_error = format!(
+        "program_types.json: /test_cases/0/input: invalid type: floating \
+            point `2.5`, expected {}",
+        std::any::type_name::<gdlk::ast::LangValue>()
+    );
+    assert_check!(
+        &[
+            "check",
+            "--hardware",
+            "hardware_valid.json",
+            "-p",
+            "program_empty.json"
+        ],
+        &[
+            "program_empty.json: /test_cases: Program spec must have at least \
+            one test case"
+        ]
+    );
+    assert_check!(
+        &[
+            "check",
+            "--hardware",
+            "hardware_valid.json",
+            "-p",
+            "program_types.json"
+        ],
+        &[
+            &float_error,
+            "program_types.json: /test_cases/1/expected_output: Missing \
+                required field",
+            "program_types.json: /test_cases/2: Expected an object",
+        ]
+    );
+    assert_check!(
+        &[
+            "check",
+            "--hardware",
+            "hardware_valid.json",
+            "-p",
+            "program_constants.json"
+        ],
+        &[
+            "program_constants.json: /constants: invalid type: sequence, \
+                expected a map",
+            "program_constants.json: /test_cases/0/constants: invalid type: \
+                integer `5`, expected a map",
+        ]
+    );
+    // The expected output is right at the limit, the input is just past it
+    assert_check!(
+        &[
+            "check",
+            "--hardware",
+            "hardware_valid.json",
+            "-p",
+            "program_long.json"
+        ],
+        &[
+            "program_long.json: /input: Expected at most 4096 values, but got \
+            4097"
+        ]
+    );
+    // Problems in both files are all reported
+    assert_check!(
+        &[
+            "check",
+            "--hardware",
+            "hardware_cost.json",
+            "-p",
+            "program_empty.json"
+        ],
+        &[
+            "hardware_cost.json: /cost_model/READ: Cost of `READ` must be at \
+                least 1 cycle",
+            "hardware_cost.json: /cost_model/NOP: Cost of `NOP` must be at \
+                least 1 cycle",
+            "program_empty.json: /test_cases: Program spec must have at least \
+                one test case",
+        ]
+    );
+}
+
+#[test]
+fn test_invalid_spec_rejected() {
+    // Other commands stop at the first problem, but still explain it
+    let (success, _, stderr) = gdlk(
+        None,
+        &[
+            "compile",
+            "--hardware",
+            "hardware_name_count.json",
+            "-s",
+            "echo.gdlk",
+        ],
+    );
+    assert!(!success);
+    assert_eq!(
+        stderr.trim(),
+        "Invalid spec file \"hardware_name_count.json\": Expected 2 register \
+            names, but got 1"
+    );
+
+    let (success, _, stderr) = gdlk(
+        None,
+        &[
+            "run",
+            "--hardware",
+            "hardware_valid.json",
+            "-p",
+            "program_empty.json",
+            "-s",
+            "echo.gdlk",
+        ],
+    );
+    assert!(!success);
+    assert!(
+        stderr.starts_with("Invalid spec file \"program_empty.json\""),
+        "{}",
+        stderr
+    );
+
+    let (success, _, stderr) = gdlk(
+        None,
+        &[
+            "run",
+            "--hardware",
+            "hardware_valid.json",
+            "-p",
+            "program_valid.json",
+            "-s",
+            "echo.gdlk",
+        ],
+    );
+    assert!(!success, "{}", stderr);
+}
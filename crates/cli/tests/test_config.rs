@@ -0,0 +1,197 @@
+//! Integration tests for `gdlk.toml` config files. Each test builds a small
+//! project in a temp directory, with the config at the root and the solution
+//! in a subdirectory, and runs the CLI from the subdirectory.
+
+mod common;
+
+use common::{gdlk, TempProject};
+use serde_json::Value;
+use std::{fs, path::PathBuf};
+
+/// A temp directory with the valid spec fixtures under `specs/`, plus a
+/// hardware spec that's too small for `reverse.gdlk`, which is under `src/`.
+/// The config points at the valid specs.
+struct Project(TempProject);
+
+impl Project {
+    fn new(name: &str) -> Self {
+        let project = TempProject::new(&format!("config_{}", name));
+        project.copy_fixture("hardware_valid.json", "specs/hardware.json");
+        project.copy_fixture("program_valid.json", "specs/program.json");
+        project.copy_fixture("reverse.gdlk", "src/reverse.gdlk");
+        project.write(
+            "specs/small.json",
+            r#"{ "num_registers": 1, "num_stacks": 0, "max_stack_length": 0 }"#,
+        );
+        let project = Self(project);
+        project.write_config(
+            "hardware = \"specs/hardware.json\"\n\
+            program = \"specs/program.json\"\n",
+        );
+        project
+    }
+
+    fn write_config(&self, config: &str) {
+        self.0.write("gdlk.toml", config);
+    }
+
+    /// Directory that the CLI gets run from
+    fn src(&self) -> PathBuf {
+        self.0.path().join("src")
+    }
+}
+
+#[test]
+fn test_config_discovery() {
+    let project = Project::new("discovery");
+    // The config is found in a parent directory, and its paths are relative
+    // to the config file rather than the current directory
+    let (success, stdout, stderr) =
+        gdlk(Some(&project.src()), &["run", "-s", "reverse.gdlk"]);
+    assert!(success, "{}", stderr);
+    assert!(stdout.contains("Test case 2: SUCCESS"), "{}", stdout);
+    let (success, _, stderr) = gdlk(
+        Some(&project.src()),
+        &["bench", "-s", "reverse.gdlk", "-n", "1"],
+    );
+    assert!(success, "{}", stderr);
+
+    // Without a config, the built-in defaults are used, and the minimal
+    // hardware doesn't have any stacks
+    fs::remove_file(project.0.path().join("gdlk.toml")).unwrap();
+    let (success, _, stderr) =
+        gdlk(Some(&project.src()), &["run", "-s", "reverse.gdlk"]);
+    assert!(!success);
+    assert!(stderr.contains("Invalid reference to stack"), "{}", stderr);
+}
+
+#[test]
+fn test_config_precedence() {
+    let project = Project::new("precedence");
+    project.write_config(
+        "hardware = \"specs/hardware.json\"\n\
+        program = \"specs/program.json\"\n\
+        format = \"json\"\n",
+    );
+
+    // Config beats the built-in default
+    let (success, stdout, stderr) =
+        gdlk(Some(&project.src()), &["run", "-s", "reverse.gdlk"]);
+    assert!(success, "{}", stderr);
+    let output: Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(output["test_cases"][0]["success"], true);
+
+    // Flags beat the config
+    let (success, stdout, stderr) = gdlk(
+        Some(&project.src()),
+        &["run", "-s", "reverse.gdlk", "--format", "text"],
+    );
+    assert!(success, "{}", stderr);
+    assert!(stdout.contains("===== Summary ====="), "{}", stdout);
+    let (success, _, stderr) = gdlk(
+        Some(&project.src()),
+        &[
+            "compile",
+            "-s",
+            "reverse.gdlk",
+            "--hardware",
+            "../specs/small.json",
+        ],
+    );
+    assert!(!success);
+    assert!(stderr.contains("Invalid reference to stack"), "{}", stderr);
+}
+
+#[test]
+fn test_config_explicit_path() {
+    let project = Project::new("explicit");
+    project
+        .0
+        .write("small.toml", "hardware = \"specs/small.json\"\n");
+
+    // An explicit config is used instead of the one that would be found
+    let (success, _, stderr) = gdlk(
+        Some(&project.src()),
+        &["--config", "../small.toml", "compile", "-s", "reverse.gdlk"],
+    );
+    assert!(!success);
+    assert!(stderr.contains("Invalid reference to stack"), "{}", stderr);
+    // Flags can go after the subcommand too
+    let (success, _, _) = gdlk(
+        Some(&project.src()),
+        &["compile", "-s", "reverse.gdlk", "--config", "../small.toml"],
+    );
+    assert!(!success);
+
+    // Unlike discovery, an explicit config has to exist
+    let (success, _, stderr) = gdlk(
+        Some(&project.src()),
+        &["compile", "-s", "reverse.gdlk", "--config", "../nope.toml"],
+    );
+    assert!(!success);
+    assert!(stderr.contains("Failed to read file"), "{}", stderr);
+}
+
+#[test]
+fn test_config_errors() {
+    let project = Project::new("errors");
+    let compile = ["compile", "-s", "reverse.gdlk"];
+
+    // Errors name the offending key
+    project.write_config("hardwre = \"specs/hardware.json\"\n");
+    let (success, _, stderr) = gdlk(Some(&project.src()), &compile);
+    assert!(!success);
+    assert!(stderr.contains("Invalid config file"), "{}", stderr);
+    assert!(stderr.contains("unknown field `hardwre`"), "{}", stderr);
+
+    project.write_config("format = \"yaml\"\n");
+    let (success, _, stderr) = gdlk(Some(&project.src()), &compile);
+    assert!(!success);
+    assert!(stderr.contains("for key `format`"), "{}", stderr);
+}
+
+#[test]
+fn test_config_constant_folding() {
+    let project = Project::new("constant_folding");
+    project
+        .0
+        .write("src/fold.gdlk", "SET RX0 1\nADD RX0 2\nWRITE RX0\n");
+    let compile = ["compile", "-s", "fold.gdlk", "--emit", "asm"];
+
+    let (success, unfolded, stderr) = gdlk(Some(&project.src()), &compile);
+    assert!(success, "{}", stderr);
+    assert!(unfolded.contains("ADD RX0 2"), "{}", unfolded);
+
+    // The config turns it on just like the flag does
+    let (_, flag, _) = gdlk(
+        Some(&project.src()),
+        &[&compile[..], &["--constant-folding"]].concat(),
+    );
+    assert!(!flag.contains("ADD RX0 2"), "{}", flag);
+    project.write_config("constant_folding = true\n");
+    let (success, folded, stderr) = gdlk(Some(&project.src()), &compile);
+    assert!(success, "{}", stderr);
+    assert_eq!(folded, flag);
+}
+
+#[test]
+fn test_config_test_command() {
+    let project = Project::new("test_command");
+    // This puzzle doesn't list any specs, so it gets the config's
+    project.0.write(
+        "src/manifest.toml",
+        "[[puzzle]]\nname = \"reverse\"\nsolutions = [\"*.gdlk\"]\n",
+    );
+    let (success, stdout, stderr) =
+        gdlk(Some(&project.src()), &["test", "manifest.toml"]);
+    assert!(success, "{}", stderr);
+    assert!(stdout.contains("PASS"), "{}", stdout);
+
+    // And flags still win over the config
+    let (success, stdout, _) = gdlk(
+        Some(&project.src()),
+        &["test", "manifest.toml", "--hardware", "../specs/small.json"],
+    );
+    assert!(!success);
+    assert!(stdout.contains("COMPILE ERROR"), "{}", stdout);
+}
@@ -0,0 +1,97 @@
+//! Integration tests for `debug`, which reads commands from stdin and prints
+//! the state of the program as it steps through it.
+
+mod common;
+
+use common::{command, TempProject};
+use std::{io::Write, process::Stdio};
+
+/// Build a project with a program that includes another file, so that some
+/// instructions come from outside the main source
+fn project(name: &str) -> TempProject {
+    let project = TempProject::new(&format!("debug_{}", name));
+    project.write("main.gdlk", "READ RX0\n%include \"lib.gdlk\"\nWRITE RX0\n");
+    project.write("lib.gdlk", "ADD RX0 1\n");
+    project.write(
+        "program.json",
+        r#"{"test_cases": [{"input": [4], "expected_output": [5]}]}"#,
+    );
+    project
+}
+
+/// Debug the project's program with the given commands on stdin, and get the
+/// exit status, the output of each command, and stderr. The first element of
+/// the output is everything printed before the first prompt.
+fn debug(project: &TempProject, stdin: &str) -> (bool, Vec<String>, String) {
+    let mut child = command(None)
+        .args([
+            "debug",
+            "-p",
+            &project.file("program.json"),
+            "-s",
+            &project.file("main.gdlk"),
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    (
+        output.status.success(),
+        stdout.split("(gdlk) ").map(String::from).collect(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+#[test]
+fn test_debug_session() {
+    let project = project("session");
+    let (success, outputs, stderr) = debug(
+        &project,
+        "step\nprint registers\nbreak 3\nreset\nrun\nprint output\nrun\n\
+        print output\nquit\n",
+    );
+    assert!(success, "{}", stderr);
+    assert_eq!(outputs.len(), 10, "{:?}", outputs);
+    assert_eq!(
+        outputs[0],
+        "Type `help` for a list of commands\n   1 | READ RX0\n"
+    );
+    // The next instruction is in the included file
+    assert_eq!(outputs[1], "lib.gdlk:1 | ADD RX0 1\n");
+    assert!(outputs[2].contains("RX0 = 4\n"), "{}", outputs[2]);
+    assert_eq!(outputs[3], "Added breakpoint on line 3\n");
+    assert_eq!(outputs[4], "   1 | READ RX0\n");
+    assert_eq!(outputs[5], "Hit breakpoint\n   3 | WRITE RX0\n");
+    assert_eq!(outputs[6], "[]\n");
+    assert_eq!(
+        outputs[7],
+        "Program terminated with SUCCESS after 3 cycles\n"
+    );
+    assert_eq!(outputs[8], "[5]\n");
+    assert_eq!(outputs[9], "");
+}
+
+#[test]
+fn test_debug_unfinished() {
+    // Closing stdin before the program finishes counts as a failure
+    let project = project("unfinished");
+    let (success, outputs, stderr) = debug(&project, "step\n");
+    assert!(!success);
+    assert_eq!(outputs.len(), 3, "{:?}", outputs);
+    assert_eq!(outputs[1], "lib.gdlk:1 | ADD RX0 1\n");
+    assert_eq!(outputs[2], "\n");
+    assert!(
+        stderr.contains("Program did not complete successfully"),
+        "{}",
+        stderr
+    );
+}
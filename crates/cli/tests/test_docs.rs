@@ -0,0 +1,54 @@
+//! Integration tests for the `docs` command. The spec files are all in
+//! `tests/fixtures`.
+
+mod common;
+
+use common::gdlk;
+
+/// Get the first line of each instruction's entry
+fn signatures(stdout: &str) -> Vec<&str> {
+    stdout
+        .split("\n\n")
+        .filter_map(|entry| entry.lines().next())
+        .collect()
+}
+
+#[test]
+fn test_docs_all() {
+    let (success, stdout, stderr) = gdlk(None, &["docs"]);
+    assert!(success, "{}", stderr);
+    assert!(
+        stdout.starts_with(
+            "READ <writable register>
+    Read the next value from the input into a register
+    Errors: R002_EMPTY_INPUT
+
+RDZ <writable register>
+    Read the next value from the input into a register, or zero if the input is empty
+
+WRITE <value>
+    Write a value to the output
+
+"
+        ),
+        "{}",
+        stdout
+    );
+    let signatures = signatures(&stdout);
+    assert_eq!(signatures.len(), 32);
+    assert_eq!(signatures[31], "EXT <extern op> <writable register>");
+}
+
+#[test]
+fn test_docs_hardware() {
+    // This hardware has no DUMP or extern ops
+    let (success, stdout, stderr) =
+        gdlk(None, &["docs", "--hardware", "hardware_valid.json"]);
+    assert!(success, "{}", stderr);
+    let signatures = signatures(&stdout);
+    assert_eq!(signatures.len(), 30);
+    assert!(signatures.contains(&"PUSH <value> <stack>"));
+    assert!(signatures.contains(&"SHL <writable register> <value>"));
+    assert!(signatures.contains(&"DUP <stack>"));
+    assert!(!signatures.contains(&"DUMP"));
+}
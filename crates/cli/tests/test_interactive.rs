@@ -0,0 +1,54 @@
+//! Integration tests for `run --interactive`, which reads input from stdin and
+//! writes output to stdout as the program runs.
+
+mod common;
+
+use common::command;
+use std::{io::Write, process::Stdio};
+
+/// Run a program interactively with the given stdin, and get the exit status,
+/// stdout, and stderr
+fn run_interactive(source_file: &str, stdin: &str) -> (bool, String, String) {
+    let mut child = command(None)
+        .args(["run", "--interactive", "-s", source_file])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    (
+        output.status.success(),
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+#[test]
+fn test_interactive() {
+    // Extra input is fine, since there's no expected output to check
+    let (success, stdout, stderr) =
+        run_interactive("echo.gdlk", "\nabc\n5\n6\n");
+    assert!(success, "{}", stderr);
+    assert_eq!(stdout, "5\n");
+    assert_eq!(stderr, "Invalid value: \"abc\"\n");
+}
+
+#[test]
+fn test_interactive_end_of_input() {
+    let (success, stdout, stderr) = run_interactive("echo.gdlk", "");
+    assert!(!success);
+    assert_eq!(stdout, "");
+    assert!(
+        stderr
+            .starts_with("Runtime error at 1:1: Read attempted on empty input"),
+        "{}",
+        stderr
+    );
+}
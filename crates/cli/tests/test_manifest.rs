@@ -0,0 +1,201 @@
+//! Integration tests for the `test` command. Each test builds its own puzzle
+//! folder in a temp directory, using the spec files and solutions from
+//! `tests/fixtures`.
+
+mod common;
+
+use common::{gdlk, TempProject};
+use std::fs;
+
+/// A temp directory with a manifest for two puzzles. `reverse` has one
+/// solution that passes, one that fails, and one that doesn't compile. `echo`
+/// has a single passing solution.
+fn puzzles(name: &str) -> TempProject {
+    let project = TempProject::new(&format!("manifest_{}", name));
+    project.copy_fixture("hardware_valid.json", "hardware.json");
+    project.copy_fixture("program_valid.json", "program.json");
+    project.copy_fixture("reverse.gdlk", "reverse/pass.gdlk");
+    project.copy_fixture("echo.gdlk", "echo/echo.gdlk");
+    project.write("reverse/fail.gdlk", "READ RX0\nWRITE RX0\n");
+    project.write("reverse/broken.gdlk", "READ RX9\n");
+    project.write("echo.json", r#"{ "input": [5], "expected_output": [5] }"#);
+    project.write(
+        "manifest.toml",
+        r#"
+[[puzzle]]
+name = "reverse"
+hardware = "hardware.json"
+program = "program.json"
+solutions = ["reverse/*.gdlk"]
+
+[[puzzle]]
+name = "echo"
+program = "echo.json"
+solutions = ["echo/*.gdlk"]
+"#,
+    );
+    project
+}
+
+/// Get the result column of the table row for the given file
+fn status<'a>(stdout: &'a str, file: &str) -> &'a str {
+    let row = stdout
+        .lines()
+        .find(|line| line.contains(file))
+        .unwrap_or_else(|| panic!("No row for {} in:\n{}", file, stdout));
+    row.split("  ")
+        .map(str::trim)
+        .filter(|column| !column.is_empty())
+        .nth(1)
+        .unwrap()
+}
+
+#[test]
+fn test_manifest() {
+    let puzzles = puzzles("all");
+    let manifest = puzzles.file("manifest.toml");
+    let (success, stdout, stderr) = gdlk(None, &["test", &manifest]);
+    assert!(!success);
+    assert!(stderr.contains("2 of 4 solutions failed"), "{}", stderr);
+    // Compile errors are shown in full
+    assert!(
+        stderr.contains("Invalid reference to register `RX9`"),
+        "{}",
+        stderr
+    );
+
+    // Puzzles are in manifest order, and solutions are sorted by path
+    let reverse = stdout.find("===== reverse =====").unwrap();
+    let echo = stdout.find("===== echo =====").unwrap();
+    assert!(reverse < echo);
+    assert_eq!(status(&stdout, "broken.gdlk"), "COMPILE ERROR");
+    assert_eq!(status(&stdout, "fail.gdlk"), "FAIL");
+    assert_eq!(status(&stdout, "pass.gdlk"), "PASS");
+    assert_eq!(status(&stdout, "echo.gdlk"), "PASS");
+    // Cycle counts are listed for each test case
+    assert!(stdout.contains("PASS           20, 2\n"), "{}", stdout);
+}
+
+#[test]
+fn test_manifest_filter() {
+    let puzzles = puzzles("filter");
+    let manifest = puzzles.file("manifest.toml");
+
+    // Filter on the puzzle name
+    let (success, stdout, stderr) =
+        gdlk(None, &["test", &manifest, "--filter", "echo"]);
+    assert!(success, "{}", stderr);
+    assert!(!stdout.contains("reverse"), "{}", stdout);
+    assert_eq!(status(&stdout, "echo.gdlk"), "PASS");
+
+    // Filter on the solution path
+    let (success, stdout, stderr) =
+        gdlk(None, &["test", &manifest, "--filter", "pass.gdlk"]);
+    assert!(success, "{}", stderr);
+    assert!(!stdout.contains("fail.gdlk"), "{}", stdout);
+    assert!(!stdout.contains("===== echo ====="), "{}", stdout);
+
+    let (success, _, stderr) =
+        gdlk(None, &["test", &manifest, "--filter", "nope"]);
+    assert!(!success);
+    assert!(stderr.contains("No solutions to run"), "{}", stderr);
+}
+
+#[test]
+fn test_manifest_report() {
+    let puzzles = puzzles("report");
+    let manifest = puzzles.file("manifest.toml");
+    let report = puzzles.file("junit.xml");
+    let (success, _, _) = gdlk(None, &["test", &manifest, "--report", &report]);
+    assert!(!success);
+
+    let report = fs::read_to_string(&report).unwrap();
+    assert!(report.starts_with(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<testsuites tests=\"4\" failures=\"2\">
+  <testsuite name=\"reverse\" tests=\"3\" failures=\"2\">
+"
+    ));
+    assert!(report.contains("<failure message=\"COMPILE ERROR\">"));
+    assert!(report.contains("<failure message=\"FAIL\">"));
+    // Failures include the same details as the text output
+    assert!(
+        report.contains("Invalid reference to register `RX9`"),
+        "{}",
+        report
+    );
+    assert!(report.contains(
+        "  <testsuite name=\"echo\" tests=\"1\" failures=\"0\">\n    \
+            <testcase classname=\"echo\" name=\""
+    ));
+    assert!(report.ends_with("</testsuite>\n</testsuites>\n"));
+}
+
+#[test]
+fn test_manifest_errors() {
+    let puzzles = puzzles("errors");
+    let manifest = puzzles.file("manifest.toml");
+    puzzles.write(
+        "manifest.toml",
+        "[[puzzle]]\nname = \"missing\"\nsolutions = [\"nope/*.gdlk\"]\n",
+    );
+    let (success, _, stderr) = gdlk(None, &["test", &manifest]);
+    assert!(!success);
+    assert!(
+        stderr.contains("for puzzle `missing` didn't match any files"),
+        "{}",
+        stderr
+    );
+
+    puzzles.write(
+        "manifest.toml",
+        "[[puzzle]]\nsolutions = [\"echo/*.gdlk\"]\n",
+    );
+    let (success, _, stderr) = gdlk(None, &["test", &manifest]);
+    assert!(!success);
+    assert!(stderr.contains("Invalid manifest file"), "{}", stderr);
+    assert!(stderr.contains("missing field `name`"), "{}", stderr);
+}
+
+#[test]
+fn test_manifest_paired_solutions() {
+    let puzzles = puzzles("paired");
+    let manifest = puzzles.file("manifest.toml");
+    puzzles.write(
+        "shared/a.gdlk",
+        ";! name: Echo again\n;! puzzle: echo\nREAD RX0\nWRITE RX0\n",
+    );
+    // Already listed under the puzzle, so it only runs once
+    puzzles.write("echo/echo.gdlk", ";! puzzle: echo\nREAD RX0\nWRITE RX0\n");
+    let contents = fs::read_to_string(&manifest).unwrap();
+    puzzles.write(
+        "manifest.toml",
+        &format!(
+            "solutions = [\"shared/*.gdlk\", \"echo/*.gdlk\"]\n{}",
+            contents
+        ),
+    );
+    let (success, stdout, stderr) =
+        gdlk(None, &["test", &manifest, "--filter", "echo"]);
+    assert!(success, "{}", stderr);
+    assert!(stdout.contains("shared/a.gdlk"), "{}", stdout);
+    assert_eq!(stdout.matches("echo/echo.gdlk").count(), 1, "{}", stdout);
+
+    // Every top-level solution has to name a puzzle in the manifest
+    puzzles.write("shared/b.gdlk", ";! puzzle: nope\n");
+    let (success, _, stderr) = gdlk(None, &["test", &manifest]);
+    assert!(!success);
+    assert!(
+        stderr.contains("is for puzzle `nope`, which isn't in the manifest"),
+        "{}",
+        stderr
+    );
+    puzzles.write("shared/b.gdlk", "READ RX0\n");
+    let (success, _, stderr) = gdlk(None, &["test", &manifest]);
+    assert!(!success);
+    assert!(
+        stderr.contains("doesn't say which puzzle it's for"),
+        "{}",
+        stderr
+    );
+}
@@ -0,0 +1,533 @@
+//! Integration tests for the `run` command. Tests that need extra files
+//! (solutions, traces) put them in a temp directory, and use the spec files
+//! from `tests/fixtures`.
+
+mod common;
+
+use common::{command, fixtures_dir, gdlk, TempProject};
+use serde_json::Value;
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::Path,
+    process::{Child, Stdio},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+/// A temp directory of solutions for the valid spec fixtures: one that
+/// passes, one that fails, one that doesn't compile, and a file that should
+/// be ignored
+fn solutions(name: &str) -> TempProject {
+    let project = TempProject::new(&format!("run_{}", name));
+    project.copy_fixture("reverse.gdlk", "pass.gdlk");
+    project.write("fail.gdlk", "READ RX0\nWRITE RX0\n");
+    project.write("broken.gdlk", "READ RX9\n");
+    project.write("notes.txt", "not a program");
+    project
+}
+
+/// Arguments to run against the valid spec files, minus the source
+const RUN_VALID: [&str; 5] = [
+    "run",
+    "--hardware",
+    "hardware_valid.json",
+    "--program",
+    "program_valid.json",
+];
+
+#[test]
+fn test_run_text() {
+    let (success, stdout, stderr) =
+        gdlk(None, &[&RUN_VALID[..], &["-s", "reverse.gdlk"]].concat());
+    assert!(success, "{}", stderr);
+    // Registers are in hardware order, not sorted by name
+    assert!(
+        stdout.starts_with(
+            "===== Test case 1 =====
+Registers: {
+    \"RLI\": 0,
+    \"RIH\": 0,
+    \"RS0\": 0,
+    \"FUEL\": 1,
+    \"TOTAL\": 0,
+}
+Stacks: {\"CARGO\": []}
+Input: []
+Output: [2, 1]
+"
+        ),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn test_run_directory_text() {
+    let solutions = solutions("directory_text");
+    let dir = solutions.path().to_str().unwrap();
+    let (success, stdout, stderr) =
+        gdlk(None, &[&RUN_VALID[..], &["-s", dir]].concat());
+    assert!(!success);
+
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 4, "{}", stdout);
+    assert!(lines[0].starts_with("File "), "{}", lines[0]);
+    let row = |line: &str| -> Vec<String> {
+        line.split("  ")
+            .map(str::trim)
+            .filter(|cell| !cell.is_empty())
+            .map(String::from)
+            .collect()
+    };
+    assert_eq!(
+        row(lines[1]),
+        [&solutions.file("broken.gdlk"), "COMPILE ERROR", "-"]
+    );
+    assert_eq!(
+        row(lines[2]),
+        [&solutions.file("fail.gdlk"), "FAIL", "2, 1"]
+    );
+    assert_eq!(
+        row(lines[3]),
+        [&solutions.file("pass.gdlk"), "PASS", "20, 2"]
+    );
+
+    // The compile error is shown with the file it came from
+    assert!(
+        stderr.contains(&format!(
+            "===== {} =====",
+            solutions.file("broken.gdlk")
+        )),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("Invalid reference to register `RX9`"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.ends_with("2 of 3 files failed\n"), "{}", stderr);
+}
+
+#[test]
+fn test_run_directory_json() {
+    let solutions = solutions("directory_json");
+    let dir = solutions.path().to_str().unwrap();
+    let (success, stdout, stderr) = gdlk(
+        None,
+        &[&RUN_VALID[..], &["-s", dir, "--format", "json"]].concat(),
+    );
+    assert!(!success);
+    assert!(stderr.ends_with("2 of 3 files failed\n"), "{}", stderr);
+
+    let output: Value = serde_json::from_str(&stdout).unwrap();
+    let results = output.as_array().unwrap();
+    let summary: Vec<(&str, &str)> = results
+        .iter()
+        .map(|result| {
+            let path = Path::new(result["path"].as_str().unwrap());
+            (
+                path.file_name().unwrap().to_str().unwrap(),
+                result["status"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("broken.gdlk", "compile_error"),
+            ("fail.gdlk", "failed"),
+            ("pass.gdlk", "passed"),
+        ]
+    );
+    assert_eq!(results[0]["errors"][0]["code"], "E010_INVALID_REGISTER");
+    assert_eq!(results[0]["test_cases"], Value::Array(vec![]));
+    assert_eq!(
+        results[1]["test_cases"][0]["failure_reason"],
+        "RemainingInput"
+    );
+    assert_eq!(results[2]["test_cases"][0]["cycle_count"], 20);
+}
+
+#[test]
+fn test_run_multiple_sources() {
+    let solutions = solutions("multiple_sources");
+    let (success, stdout, stderr) = gdlk(
+        None,
+        &[
+            &RUN_VALID[..],
+            &["-s", "reverse.gdlk", "-s", &solutions.file("pass.gdlk")],
+        ]
+        .concat(),
+    );
+    assert!(success, "{}", stderr);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 3, "{}", stdout);
+    assert!(lines[1].starts_with("reverse.gdlk "), "{}", lines[1]);
+    assert!(lines[1].ends_with("PASS           20, 2"), "{}", lines[1]);
+    assert!(lines[2].ends_with("PASS           20, 2"), "{}", lines[2]);
+}
+
+#[test]
+fn test_run_fail_fast() {
+    let solutions = solutions("fail_fast");
+    let dir = solutions.path().to_str().unwrap();
+    let (success, stdout, stderr) = gdlk(
+        None,
+        &[&RUN_VALID[..], &["-s", dir, "--fail-fast"]].concat(),
+    );
+    assert!(!success);
+    // Stops after the first file, which doesn't compile
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", stdout);
+    assert!(lines[1].contains("COMPILE ERROR"), "{}", lines[1]);
+    assert!(stderr.ends_with("1 of 3 files failed\n"), "{}", stderr);
+}
+
+#[test]
+fn test_run_trace() {
+    let solutions = solutions("trace");
+    let trace_path = solutions.file("reverse.trace");
+    let (success, _, stderr) = gdlk(
+        None,
+        &[
+            &RUN_VALID[..],
+            &["-s", "reverse.gdlk", "--trace", &trace_path],
+        ]
+        .concat(),
+    );
+    assert!(success, "{}", stderr);
+
+    // One section per test case, and aliases aren't used
+    let trace = fs::read_to_string(&trace_path).unwrap();
+    let lines: Vec<&str> = trace.lines().collect();
+    assert_eq!(lines[0], "# Test case 1");
+    assert_eq!(lines[1], "1 0 JEZ RLI POP_LOOP | RX0=0 RX1=0 RS0=0");
+    let headers: Vec<&str> = lines
+        .iter()
+        .copied()
+        .filter(|line| line.starts_with('#') || line.starts_with("END"))
+        .collect();
+    assert_eq!(
+        headers,
+        [
+            "# Test case 1",
+            "END success",
+            "# Test case 2",
+            "END success"
+        ]
+    );
+
+    // Tracing needs a single source file
+    let (success, _, stderr) = gdlk(
+        None,
+        &[
+            &RUN_VALID[..],
+            &[
+                "-s",
+                solutions.path().to_str().unwrap(),
+                "--trace",
+                &trace_path,
+            ],
+        ]
+        .concat(),
+    );
+    assert!(!success);
+    assert_eq!(stderr, "Tracing only supports a single source file\n");
+}
+
+#[test]
+fn test_run_include() {
+    let solutions = solutions("include");
+    solutions.copy_fixture("reverse.gdlk", "lib/reverse.gdlk");
+    solutions.write("lib/broken.gdlk", "READ RX9\n");
+    // Paths are relative to the main source file, not the working directory
+    solutions.write("main.gdlk", "%include \"lib/reverse.gdlk\"\n");
+    solutions.write("bad.gdlk", "%include \"./lib/broken.gdlk\"\n");
+
+    let (success, _, stderr) = gdlk(
+        None,
+        &[&RUN_VALID[..], &["-s", &solutions.file("main.gdlk")]].concat(),
+    );
+    assert!(success, "{}", stderr);
+
+    let (success, _, stderr) = gdlk(
+        None,
+        &[
+            &RUN_VALID[..],
+            &["--format", "json", "-s", &solutions.file("bad.gdlk")],
+        ]
+        .concat(),
+    );
+    assert!(!success);
+    let output: Value = serde_json::from_str(&stderr).unwrap();
+    let error = &output["errors"][0];
+    assert_eq!(error["code"], "E010_INVALID_REGISTER");
+    assert_eq!(error["file"], "lib/broken.gdlk");
+    assert_eq!(error["span"]["source_id"], 0);
+}
+
+#[test]
+fn test_run_output_diff() {
+    let solutions = solutions("output_diff");
+    // Writes the input in the original order, instead of reversed
+    solutions.write(
+        "in_order.gdlk",
+        "JEZ RLI END\nREAD RX0\nREAD RX1\nWRITE RX0\nWRITE RX1\nEND:\n",
+    );
+    let source = solutions.file("in_order.gdlk");
+
+    let (success, stdout, _) =
+        gdlk(None, &[&RUN_VALID[..], &["-s", &source]].concat());
+    assert!(!success);
+    let summary: Vec<&str> = stdout
+        .lines()
+        .skip_while(|line| *line != "===== Summary =====")
+        .collect();
+    assert_eq!(
+        summary,
+        &[
+            "===== Summary =====",
+            "Test case 1: FAILURE (IncorrectOutput) in 5 cycles, output \
+                differs at position 0: expected 2, got 1",
+            "Test case 2: SUCCESS in 1 cycles",
+        ]
+    );
+
+    let (_, stdout, _) = gdlk(
+        None,
+        &[&RUN_VALID[..], &["--format", "json", "-s", &source]].concat(),
+    );
+    let output: Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(
+        output["test_cases"][0]["output_diff"],
+        serde_json::json!({
+            "index": 0,
+            "expected": 2,
+            "actual": 1,
+            "missing": 0,
+            "extra": 0,
+        })
+    );
+    assert_eq!(output["test_cases"][1]["output_diff"], Value::Null);
+}
+
+#[test]
+fn test_run_null_writes() {
+    let solutions = solutions("null_writes");
+    solutions.write(
+        "discard.gdlk",
+        "READ RZR
+SET RZR 1
+",
+    );
+    solutions
+        .write("discard.json", r#"{ "input": [4], "expected_output": [] }"#);
+    let source = solutions.file("discard.gdlk");
+    let program_spec = solutions.file("discard.json");
+    let run = ["run", "-p", &program_spec, "-s", &source];
+
+    let (success, stdout, _) = gdlk(None, &run);
+    assert!(success);
+    assert!(
+        stdout.ends_with("Test case 1: SUCCESS in 2 cycles, 2 writes to RZR\n"),
+        "{}",
+        stdout
+    );
+
+    let (_, stdout, _) =
+        gdlk(None, &[&run[..], &["--format", "json"]].concat());
+    let output: Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(output["test_cases"][0]["null_writes"], 2);
+}
+
+#[test]
+fn test_run_metadata() {
+    let solutions = solutions("metadata");
+    solutions.write(
+        "named.gdlk",
+        ";! name: Echo\n;! puzzle: echo\nREAD RX0\nWRITE RX0\n",
+    );
+    solutions.write("echo.json", r#"{ "input": [5], "expected_output": [5] }"#);
+    let source = solutions.file("named.gdlk");
+    let program_spec = solutions.file("echo.json");
+    let run = ["run", "-p", &program_spec, "-s", &source];
+
+    let (success, _, stderr) = gdlk(None, &run);
+    assert!(success, "{}", stderr);
+    assert!(stderr.starts_with("Solution: Echo\n"), "{}", stderr);
+    let (success, _, stderr) = gdlk(None, &["compile", "-s", &source]);
+    assert!(success, "{}", stderr);
+    assert!(stderr.starts_with("Solution: Echo\n"), "{}", stderr);
+
+    let (_, stdout, _) =
+        gdlk(None, &[&run[..], &["--format", "json"]].concat());
+    let output: Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(output["name"], "Echo");
+
+    // Programs without a name don't print anything extra
+    let unnamed = solutions.file("fail.gdlk");
+    let (_, _, stderr) =
+        gdlk(None, &["run", "-p", &program_spec, "-s", &unnamed]);
+    assert!(!stderr.contains("Solution:"), "{}", stderr);
+    let (_, stdout, _) = gdlk(
+        None,
+        &[
+            "run",
+            "-p",
+            &program_spec,
+            "-s",
+            &unnamed,
+            "--format",
+            "json",
+        ],
+    );
+    let output: Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(output["name"], Value::Null);
+}
+
+#[test]
+fn test_run_random_init() {
+    // Only passes if RX0 starts at zero
+    let solutions = solutions("random_init");
+    solutions.write("zero.gdlk", "WRITE RX0\n");
+    solutions.write("zero.json", r#"{ "input": [], "expected_output": [0] }"#);
+    let source = solutions.file("zero.gdlk");
+    let program_spec = solutions.file("zero.json");
+    let run = ["run", "-p", &program_spec, "-s", &source];
+
+    let (success, stdout, _) =
+        gdlk(None, &[&run[..], &["--format", "json"]].concat());
+    assert!(success);
+    let output: Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(output["seed"], Value::Null);
+
+    let (success, stdout, _) = gdlk(
+        None,
+        &[&run[..], &["--random-init", "42", "--format", "json"]].concat(),
+    );
+    assert!(!success);
+    let output: Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(output["seed"], 42);
+    let registers = &output["test_cases"][0]["registers"];
+    // The same seed gives the same garbage every time
+    let (_, stdout, _) = gdlk(
+        None,
+        &[&run[..], &["--random-init", "42", "--format", "json"]].concat(),
+    );
+    let output: Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(&output["test_cases"][0]["registers"], registers);
+
+    let (_, stdout, _) =
+        gdlk(None, &[&run[..], &["--random-init", "42"]].concat());
+    assert!(stdout.starts_with("Registers randomized with seed 42\n"));
+}
+
+#[test]
+fn test_run_program_too_long() {
+    let solutions = solutions("too_long");
+    solutions.write(
+        "short.json",
+        r#"{
+            "num_registers": 1,
+            "num_stacks": 0,
+            "max_stack_length": 0,
+            "max_instructions": 1
+        }"#,
+    );
+    let hardware_spec = solutions.file("short.json");
+    let source = solutions.file("fail.gdlk");
+    let (success, _, stderr) = gdlk(
+        None,
+        &[
+            "run",
+            "--hardware",
+            &hardware_spec,
+            "--program",
+            "program_valid.json",
+            "--source",
+            &source,
+        ],
+    );
+    assert!(!success);
+    assert!(
+        stderr.contains(
+            "Validation error at 2:1: Program has 2 instructions, \
+                but this hardware allows at most 1"
+        ),
+        "{}",
+        stderr
+    );
+}
+
+/// A child process that gets killed when this is dropped, so a failed test
+/// doesn't leave it running
+struct KillOnDrop(Child);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn test_run_watch() {
+    let solutions = solutions("watch");
+    let source = solutions.file("watched.gdlk");
+    fs::write(&source, "READ RX9\n").unwrap();
+
+    let mut child = KillOnDrop(
+        command(None)
+            .args([&RUN_VALID[..], &["-s", &source, "--watch"]].concat())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    // Read stdout in the background, so we can time out on it
+    let stdout = BufReader::new(child.0.stdout.take().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in stdout.lines() {
+            if sender.send(line.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+    let wait_for = |text: &str| loop {
+        let line = receiver
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap_or_else(|_| panic!("Timed out waiting for {:?}", text));
+        if line.contains(text) {
+            break;
+        }
+    };
+
+    // The compile error doesn't stop the watching
+    wait_for("===== Run at ");
+    thread::sleep(Duration::from_millis(200));
+    assert!(child.0.try_wait().unwrap().is_none());
+
+    // Fixing the file triggers another run
+    fs::copy(fixtures_dir().join("reverse.gdlk"), &source).unwrap();
+    wait_for("===== Run at ");
+    wait_for("Test case 2: SUCCESS");
+    assert!(child.0.try_wait().unwrap().is_none());
+}
+
+#[test]
+fn test_run_watch_json() {
+    let (success, _, stderr) = gdlk(
+        None,
+        &[
+            &RUN_VALID[..],
+            &["-s", "reverse.gdlk", "--watch", "--format", "json"],
+        ]
+        .concat(),
+    );
+    assert!(!success);
+    assert_eq!(stderr, "Watch mode only supports the text format\n");
+}
@@ -0,0 +1,69 @@
+//! Integration tests for the `spec` command. The spec files are all in
+//! `tests/fixtures`.
+
+mod common;
+
+use common::gdlk;
+
+#[test]
+fn test_spec_default() {
+    let (success, stdout, stderr) = gdlk(None, &["spec"]);
+    assert!(success, "{}", stderr);
+    assert_eq!(
+        stdout,
+        "Registers: 1
+Stacks: 0
+Max stack length: 0
+Valid registers: RZR, RLI, RIH, RX0
+"
+    );
+}
+
+#[test]
+fn test_spec_files() {
+    let (success, stdout, stderr) = gdlk(
+        None,
+        &[
+            "spec",
+            "--hardware",
+            "hardware_valid.json",
+            "--program",
+            "program_valid.json",
+        ],
+    );
+    assert!(success, "{}", stderr);
+    assert_eq!(
+        stdout,
+        "Registers: 2
+Stacks: 1
+Max stack length: 10
+Valid registers: RZR, RLI, RIH, RS0, RX0 (FUEL), RX1 (TOTAL)
+Valid stacks: S0 (CARGO)
+Instruction costs: PUSH=2
+Test case 1:
+  Input: [1, 2]
+  Expected output: [2, 1]
+Test case 2:
+  Input: []
+  Expected output: []
+"
+    );
+}
+
+#[test]
+fn test_spec_invalid() {
+    let (success, stdout, stderr) =
+        gdlk(None, &["spec", "--hardware", "hardware_name_count.json"]);
+    assert!(!success);
+    assert_eq!(stdout, "");
+    assert!(
+        stderr.starts_with("Invalid spec file \"hardware_name_count.json\""),
+        "{}",
+        stderr
+    );
+
+    // Specs outside the allowed ranges still load, only check rejects them
+    let (success, _, stderr) =
+        gdlk(None, &["spec", "--hardware", "hardware_bounds.json"]);
+    assert!(success, "{}", stderr);
+}
@@ -0,0 +1,458 @@
+//! Static analysis of source code, for editor tooling such as a language
+//! server. Unlike [Compiler](crate::Compiler), analysis never fails. It works
+//! on incomplete programs, and reports everything it finds as structured data,
+//! so tools don't have to pick apart formatted error messages.
+//!
+//! ```
+//! use gdlk::{analysis, HardwareSpec};
+//!
+//! let source = "LOOP:\nPUSH 1 S3\nJMP LOOP";
+//! let analysis = analysis::analyze(source, &HardwareSpec::default());
+//! assert_eq!(analysis.diagnostics()[0].code, "E011_INVALID_STACK");
+//! assert_eq!(analysis.symbols()[0].name, "LOOP");
+//! ```
+
+use crate::{
+    ast::{
+        source::{LabelDecl, Statement},
+        Node,
+    },
+    consts::STATEMENT_SEPARATOR,
+    error::{SourceError, SourceErrorWrapper},
+    models::HardwareSpec,
+    parse::{self, ArgKind, INSTRUCTION_CATALOG},
+    util::Span,
+    Compiler,
+};
+use serde::Serialize;
+
+/// How serious a [Diagnostic] is
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum Severity {
+    /// Prevents the program from compiling
+    Error,
+    /// The program compiles, but likely has a mistake
+    Warning,
+}
+
+/// A single problem found in the source code
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Machine-readable code for the kind of problem, e.g.
+    /// `"E001_SYNTAX"`. See
+    /// [SourceError::error_code](crate::error::SourceError::error_code).
+    pub code: &'static str,
+    /// Human-readable message, without any position info
+    pub message: String,
+    pub span: Span,
+}
+
+/// A named element of the program that an editor can navigate to. Currently,
+/// that's just label declarations.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DocumentSymbol {
+    pub name: String,
+    /// The span of the declaration, including the trailing `:`
+    pub span: Span,
+}
+
+/// What a [CompletionItem] refers to
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum CompletionKind {
+    Instruction,
+    Register,
+    Stack,
+    Label,
+}
+
+/// A suggestion for text to insert at the cursor
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CompletionItem {
+    /// The text to insert
+    pub label: String,
+    pub kind: CompletionKind,
+}
+
+/// Everything known about a program from static analysis. See [analyze].
+#[derive(Clone, Debug)]
+pub struct Analysis {
+    source: String,
+    hardware_spec: HardwareSpec,
+    diagnostics: Vec<Diagnostic>,
+    symbols: Vec<DocumentSymbol>,
+}
+
+/// Analyze a program under the given hardware. If there are any syntax errors,
+/// those are the only diagnostics, since validating a partial program would
+/// give misleading errors. Otherwise, the diagnostics are the same errors or
+/// warnings that compilation gives. Symbols are collected from every line
+/// that parsed, even if other lines have syntax errors.
+pub fn analyze(source: &str, hardware_spec: &HardwareSpec) -> Analysis {
+    let (program, syntax_errors) = parse::parse_lenient(source, hardware_spec);
+    let symbols = program
+        .body
+        .iter()
+        .filter_map(|Node(statement, _)| match statement {
+            Statement::Label(Node(LabelDecl(label), span)) => {
+                Some(DocumentSymbol {
+                    name: label.clone(),
+                    span: *span,
+                })
+            }
+            Statement::Instruction(_) => None,
+        })
+        .collect();
+
+    let diagnostics = if syntax_errors.is_empty() {
+        match Compiler::compile(source, hardware_spec.clone()) {
+            Ok(compiler) => {
+                to_diagnostics(compiler.warnings().errors(), Severity::Warning)
+            }
+            Err(errors) => to_diagnostics(errors.errors(), Severity::Error),
+        }
+    } else {
+        to_diagnostics(&syntax_errors, Severity::Error)
+    };
+
+    Analysis {
+        source: source.to_owned(),
+        hardware_spec: hardware_spec.clone(),
+        diagnostics,
+        symbols,
+    }
+}
+
+/// Convert errors or warnings into diagnostics
+fn to_diagnostics<E: SourceError>(
+    errors: &[SourceErrorWrapper<E>],
+    severity: Severity,
+) -> Vec<Diagnostic> {
+    errors
+        .iter()
+        .map(|error| Diagnostic {
+            severity,
+            code: error.error_code(),
+            message: error.message(),
+            span: error.span(),
+        })
+        .collect()
+}
+
+impl Analysis {
+    /// Get every error or warning in the program. See [analyze] for which
+    /// ones get included.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Get every label declaration in the program, in source order.
+    pub fn symbols(&self) -> &[DocumentSymbol] {
+        &self.symbols
+    }
+
+    /// Get suggestions for the token being typed at the given byte offset
+    /// into the source. At the start of a statement, that's any instruction the
+    /// hardware allows. After an instruction, it's whatever is valid for the
+    /// next argument, e.g. only stacks after `PUSH RX0 `. Suggestions are
+    /// filtered by whatever has been typed of the token so far
+    /// (case-insensitive). Inside a comment, or anywhere nothing could be
+    /// typed, this returns nothing.
+    pub fn completions_at(&self, offset: usize) -> Vec<CompletionItem> {
+        let before_cursor = match self.source.get(..offset) {
+            Some(before_cursor) => before_cursor,
+            None => return Vec::new(),
+        };
+        let line = match before_cursor.rfind('\n') {
+            Some(index) => &before_cursor[index + 1..],
+            None => before_cursor,
+        };
+        if line.contains(';') {
+            return Vec::new();
+        }
+        // Only the last statement on the line matters
+        let line = match line.rfind(STATEMENT_SEPARATOR) {
+            Some(index) => &line[index + STATEMENT_SEPARATOR.len()..],
+            None => line,
+        };
+
+        // Everything before the last whitespace is complete, and whatever
+        // comes after is a partial token
+        let mut tokens: Vec<&str> = line.split_whitespace().collect();
+        let partial = if line.ends_with(char::is_whitespace) {
+            ""
+        } else {
+            tokens.pop().unwrap_or("")
+        };
+        let candidates = match tokens.split_first() {
+            None => self.instruction_completions(),
+            Some((mnemonic, args)) => {
+                let arg_kind = INSTRUCTION_CATALOG
+                    .iter()
+                    .find(|info| {
+                        info.kind.to_string().eq_ignore_ascii_case(mnemonic)
+                    })
+                    .and_then(|info| info.args.get(args.len()));
+                match arg_kind {
+                    Some(arg_kind) => self.arg_completions(*arg_kind),
+                    // Unknown instruction, or too many arguments
+                    None => Vec::new(),
+                }
+            }
+        };
+
+        let partial = partial.to_ascii_uppercase();
+        candidates
+            .into_iter()
+            .filter(|item| {
+                item.label.to_ascii_uppercase().starts_with(&partial)
+            })
+            .collect()
+    }
+
+    /// Get every instruction that the hardware allows
+    fn instruction_completions(&self) -> Vec<CompletionItem> {
+        INSTRUCTION_CATALOG
+            .iter()
+            .filter(|info| info.is_available(&self.hardware_spec))
+            .map(|info| CompletionItem {
+                label: info.kind.to_string(),
+                kind: CompletionKind::Instruction,
+            })
+            .collect()
+    }
+
+    /// Get everything that could be used as a certain kind of argument
+    fn arg_completions(&self, arg_kind: ArgKind) -> Vec<CompletionItem> {
+        let hardware_spec = &self.hardware_spec;
+        let registers = |writable_only: bool| {
+            hardware_spec
+                .register_set()
+                .iter()
+                .filter(|info| {
+                    info.readable && (info.writable || !writable_only)
+                })
+                .map(|info| CompletionItem {
+                    label: hardware_spec.register_name(info.register),
+                    kind: CompletionKind::Register,
+                })
+                .collect()
+        };
+        match arg_kind {
+            ArgKind::WritableRegister => registers(true),
+            ArgKind::Value | ArgKind::Register => registers(false),
+            ArgKind::Stack => hardware_spec
+                .all_stack_refs()
+                .into_iter()
+                .map(|stack_ref| CompletionItem {
+                    label: hardware_spec.stack_name(stack_ref),
+                    kind: CompletionKind::Stack,
+                })
+                .collect(),
+            ArgKind::Label => self
+                .symbols
+                .iter()
+                .map(|symbol| CompletionItem {
+                    label: symbol.name.clone(),
+                    kind: CompletionKind::Label,
+                })
+                .collect(),
+            ArgKind::ExternOp => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::InstructionKind;
+
+    /// Get the labels of the completions at the last `|` in the source. That
+    /// `|` is removed before analyzing, and any others are left as statement
+    /// separators.
+    fn complete(hardware_spec: &HardwareSpec, source: &str) -> Vec<String> {
+        let offset = source.rfind('|').unwrap();
+        let source = format!("{}{}", &source[..offset], &source[offset + 1..]);
+        analyze(&source, hardware_spec)
+            .completions_at(offset)
+            .into_iter()
+            .map(|item| item.label)
+            .collect()
+    }
+
+    /// Hardware with a little bit of everything
+    fn hardware_spec() -> HardwareSpec {
+        HardwareSpec {
+            num_registers: 2,
+            num_stacks: 2,
+            max_stack_length: 4,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_complete_instruction() {
+        let hardware_spec = hardware_spec();
+        let all = complete(&hardware_spec, "|");
+        assert_eq!(
+            all.len(),
+            INSTRUCTION_CATALOG.len() - 2,
+            "DUMP and EXT are disabled"
+        );
+        assert_eq!(all[0], "READ");
+        assert!(!all.contains(&"DUMP".to_owned()));
+        assert!(!all.contains(&"EXT".to_owned()));
+
+        assert_eq!(
+            complete(&hardware_spec, "READ RX0\n  p|"),
+            vec!["PUSH", "POP", "PEEK", "POPN"]
+        );
+        assert_eq!(complete(&hardware_spec, "POPN|"), vec!["POPN"]);
+        assert_eq!(
+            complete(&hardware_spec, "READ RX0 | p|"),
+            vec!["PUSH", "POP", "PEEK", "POPN"]
+        );
+        assert_eq!(
+            complete(&hardware_spec, "  j|\nREAD RX0"),
+            vec!["JMP", "JEZ", "JNZ", "JLZ", "JGZ", "JRI"]
+        );
+
+        // Only allowed instructions are suggested
+        let hardware_spec = HardwareSpec {
+            allowed_instructions: Some(
+                vec![InstructionKind::Read, InstructionKind::Write]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        assert_eq!(complete(&hardware_spec, "|"), vec!["READ", "WRITE"]);
+    }
+
+    #[test]
+    fn test_complete_registers() {
+        let hardware_spec = hardware_spec();
+        // Destinations have to be writable
+        assert_eq!(
+            complete(&hardware_spec, "SET |"),
+            vec!["RZR", "RX0", "RX1"]
+        );
+        assert_eq!(
+            complete(&hardware_spec, "set RX0 |"),
+            vec!["RZR", "RLI", "RIH", "RS0", "RS1", "RX0", "RX1"]
+        );
+        assert_eq!(
+            complete(&hardware_spec, "CMP RX0 RX1 rs|"),
+            vec!["RS0", "RS1"]
+        );
+
+        // Aliases are suggested instead of canonical names
+        let hardware_spec = HardwareSpec {
+            register_names: vec!["ACC".into()],
+            ..Default::default()
+        };
+        assert_eq!(complete(&hardware_spec, "READ |"), vec!["RZR", "ACC"]);
+    }
+
+    #[test]
+    fn test_complete_stacks() {
+        let hardware_spec = hardware_spec();
+        assert_eq!(complete(&hardware_spec, "PUSH RX0 |"), vec!["S0", "S1"]);
+        assert_eq!(complete(&hardware_spec, "POP |"), vec!["S0", "S1"]);
+        assert_eq!(
+            complete(&hardware_spec, "POP S1 |"),
+            vec!["RZR", "RX0", "RX1"]
+        );
+        assert_eq!(
+            complete(&HardwareSpec::default(), "PUSH RX0 |"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_complete_labels() {
+        let hardware_spec = hardware_spec();
+        let source = "START:\nJEZ RX0 |\nEND:";
+        assert_eq!(complete(&hardware_spec, source), vec!["START", "END"]);
+        // Labels are still found if other lines have syntax errors
+        let source = "START:\nJMP S|\nBAD BAD\nSTOP:";
+        assert_eq!(complete(&hardware_spec, source), vec!["START", "STOP"]);
+    }
+
+    #[test]
+    fn test_complete_nothing() {
+        let hardware_spec = hardware_spec();
+        // Too many arguments
+        assert!(complete(&hardware_spec, "READ RX0 |").is_empty());
+        assert!(complete(&hardware_spec, "NOP |").is_empty());
+        // Unknown instruction
+        assert!(complete(&hardware_spec, "FOO |").is_empty());
+        // Inside a comment
+        assert!(complete(&hardware_spec, "READ RX0 ; |").is_empty());
+        assert!(complete(&hardware_spec, "; |").is_empty());
+        // Offset out of bounds
+        assert!(analyze("", &hardware_spec).completions_at(1).is_empty());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let hardware_spec = hardware_spec();
+        // Syntax errors are reported alone, even if validation would fail
+        let analysis = analyze("READ RX9\nFOO\nBAR", &hardware_spec);
+        assert_eq!(
+            analysis
+                .diagnostics()
+                .iter()
+                .map(|d| (d.severity, d.code, d.span.start_line))
+                .collect::<Vec<_>>(),
+            vec![
+                (Severity::Error, "E004_UNKNOWN_INSTRUCTION", 2),
+                (Severity::Error, "E004_UNKNOWN_INSTRUCTION", 3)
+            ]
+        );
+
+        let analysis = analyze("READ RX9", &hardware_spec);
+        assert_eq!(
+            analysis.diagnostics(),
+            &[Diagnostic {
+                severity: Severity::Error,
+                code: "E010_INVALID_REGISTER",
+                message: "Invalid reference to register `RX9` \
+                    (this hardware has registers RX0-RX1)"
+                    .into(),
+                span: Span {
+                    source_id: None,
+                    offset: 5,
+                    length: 3,
+                    start_line: 1,
+                    start_col: 6,
+                    end_line: 1,
+                    end_col: 9,
+                },
+            }]
+        );
+
+        // Warnings only show up when the program is valid
+        let analysis = analyze("UNUSED:\nREAD RX0", &hardware_spec);
+        assert_eq!(analysis.diagnostics().len(), 1);
+        assert_eq!(analysis.diagnostics()[0].severity, Severity::Warning);
+        assert_eq!(analysis.diagnostics()[0].code, "W001_UNUSED_LABEL");
+        assert_eq!(
+            analysis.symbols(),
+            &[DocumentSymbol {
+                name: "UNUSED".into(),
+                span: Span {
+                    source_id: None,
+                    offset: 0,
+                    length: 7,
+                    start_line: 1,
+                    start_col: 1,
+                    end_line: 1,
+                    end_col: 8,
+                },
+            }]
+        );
+
+        assert!(analyze("READ RX0", &hardware_spec).diagnostics().is_empty());
+    }
+}
@@ -0,0 +1,66 @@
+//! Compile-time assertions on the auto traits of our public types. Consumers
+//! rely on these (e.g. running machines on worker threads, or sharing a
+//! compiled program between threads), so if a change breaks one of them, we
+//! want the error to show up here rather than in some far-off downstream
+//! crate. Nothing in here is ever called.
+//!
+//! The contract is:
+//! - Everything listed here is `Send + Sync`. Use `Arc` instead of `Rc` for
+//!   shared data, and `Send + Sync` bounds on any boxed closures.
+//! - Interior mutability is only allowed where state is shared between clones
+//!   of a [Machine], or between a machine and another thread. Every such field
+//!   has to be thread-safe on its own:
+//!   - The I/O streams and the extern handler sit behind `Arc<Mutex<_>>`. The
+//!     mutex makes them `Sync`, so the boxed trait objects inside
+//!     ([InputSource](crate::InputSource), [OutputSink](crate::OutputSink), and
+//!     [ExternHandler](crate::ExternHandler)) only need to be `Send`.
+//!   - [CancelToken] wraps an `Arc<AtomicBool>`, which is `Send + Sync` as-is.
+//!     It's only ever a flag, so relaxed ordering is enough.
+//! - Error types are also `'static`, so they can be boxed into
+//!   `std::error::Error` trait objects (e.g. with `anyhow`). This is already
+//!   enforced by the bounds on [SourceError](crate::error::SourceError).
+
+use crate::{
+    ast::compiled::Program,
+    error::{
+        CompileError, LintWarning, RuntimeError, RuntimeWarning,
+        SourceErrorWrapper, WithSource,
+    },
+    CancelToken, Compiler, DebugSnapshot, HardwareSpec, Machine,
+    MachineSnapshot, ProgramSpec, ProgramStats, RegisterSet, Span, StepTrace,
+    TestCase, TestResults,
+};
+
+fn _assert_auto_traits() {
+    // Helpers are defined in here so they don't count as dead code
+    fn is_send_sync<T: Send + Sync>() {}
+    fn is_static_error<T: 'static + Send + Sync + std::error::Error>() {}
+
+    // Specs and compiled programs
+    is_send_sync::<HardwareSpec>();
+    is_send_sync::<ProgramSpec>();
+    is_send_sync::<TestCase>();
+    is_send_sync::<ProgramStats>();
+    is_send_sync::<RegisterSet>();
+    is_send_sync::<Program<Span>>();
+    is_send_sync::<Compiler<Program<Span>>>();
+
+    // Execution
+    is_send_sync::<Machine>();
+    is_send_sync::<MachineSnapshot>();
+    is_send_sync::<StepTrace>();
+    is_send_sync::<DebugSnapshot>();
+    is_send_sync::<TestResults>();
+    is_send_sync::<CancelToken>();
+
+    // Errors
+    is_send_sync::<CompileError>();
+    is_send_sync::<RuntimeError>();
+    is_send_sync::<RuntimeWarning>();
+    is_send_sync::<LintWarning>();
+    is_static_error::<SourceErrorWrapper<CompileError>>();
+    is_static_error::<SourceErrorWrapper<RuntimeError>>();
+    is_static_error::<WithSource<CompileError>>();
+    is_static_error::<WithSource<RuntimeError>>();
+    is_static_error::<WithSource<LintWarning>>();
+}
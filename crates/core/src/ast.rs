@@ -5,15 +5,32 @@
 
 use crate::{
     consts::{
-        INPUT_LENGTH_REGISTER_REF, NULL_REGISTER_REF,
+        CONSTANT_REF_TAG, INPUT_HEAD_REGISTER_REF, INPUT_LENGTH_REGISTER_REF,
+        NULL_COUNT_REGISTER_REF, NULL_REGISTER_REF,
         STACK_LENGTH_REGISTER_REF_TAG, STACK_REF_TAG, USER_REGISTER_REF_TAG,
     },
     util::Span,
 };
+#[cfg(feature = "serde")]
+use serde::{
+    de::{self, Unexpected},
+    Deserializer,
+};
+use serde::{Deserialize, Serialize, Serializer};
 use std::fmt::{self, Display, Formatter};
 
-/// The type of every value in our language.
+/// The type of every value in our language. This is an `i32`, or an `i64`
+/// with the `i64-values` feature. Literals, input, and output all have to fit
+/// in it, and arithmetic overflows past its bounds (see
+/// [OverflowBehavior](crate::OverflowBehavior)).
+#[cfg(not(feature = "i64-values"))]
 pub type LangValue = i32;
+/// The type of every value in our language. This is an `i32`, or an `i64`
+/// with the `i64-values` feature. Literals, input, and output all have to fit
+/// in it, and arithmetic overflows past its bounds (see
+/// [OverflowBehavior](crate::OverflowBehavior)).
+#[cfg(feature = "i64-values")]
+pub type LangValue = i64;
 
 /// A symbol used to identify a certain user register.
 pub type UserRegisterId = usize;
@@ -21,12 +38,21 @@ pub type UserRegisterId = usize;
 /// A symbol used to identify a certain stack.
 pub type StackId = usize;
 
+/// A symbol used to identify a certain extern op, i.e. a function provided by
+/// the host. See [Instruction::Ext].
+pub type ExternOpId = usize;
+
 /// A label for a certain point in the code.
 pub type Label = String;
 
+/// The name of a constant, without the `$` prefix. The value comes from the
+/// program spec, see [ValueSource::Constant].
+pub type ConstantName = String;
+
 /// A generic AST node container. This holds the AST node data itself, as well
 /// as some metadata (e.g. source span).
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Node<T, M>(pub T, pub M);
 
 impl<T, M> Node<T, M> {
@@ -52,8 +78,8 @@ impl<T, M> Node<T, M> {
 pub(crate) type SpanNode<T> = Node<T, Span>;
 
 /// A reference to a stack, e.g. "S0". This should NOT be used for other uses
-/// of a stack ID, e.g. in the register "RS0".
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+/// of a stack ID, e.g. in the register "RS0". Stacks are ordered by ID.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct StackRef(pub StackId);
 
 impl Display for StackRef {
@@ -62,10 +88,38 @@ impl Display for StackRef {
     }
 }
 
+// Serialize as the canonical string form, e.g. "S0"
+impl Serialize for StackRef {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// Deserialize from the canonical string form, e.g. "S0". Aliases aren't
+// supported here, since they're never serialized.
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for StackRef {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        parse_id(&s, STACK_REF_TAG).map(StackRef).ok_or_else(|| {
+            de::Error::invalid_value(Unexpected::Str(&s), &"stack reference")
+        })
+    }
+}
+
 /// A reference to a register. Registers can be readonly (in which case the
 /// value is a reflection of some other part of state), or read-write, which
 /// means the user can read and write freely from/to it.
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+///
+/// Registers are ordered the same way that hardware lists them: the special
+/// registers (`RZR`, `RLI`, `RIH`, `RZC`) first, then stack lengths by stack
+/// ID, then user registers by ID.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Hash)]
 pub enum RegisterRef {
     /// This register is both readable and writable, but it also produces zero
     /// when read from, and anything written to it is thrown away.
@@ -73,6 +127,13 @@ pub enum RegisterRef {
     /// Read-only register that provides the number of elements remaining
     /// in the input buffer
     InputLength,
+    /// Read-only register that provides the next value in the input, without
+    /// consuming it. Reads as zero if the input is empty.
+    InputHead,
+    /// Read-only register that provides the number of writes to `RZR` so
+    /// far. Only available if the hardware has
+    /// [null_count_enabled](crate::HardwareSpec::null_count_enabled) set.
+    NullCount,
     /// Read-only register that provides the current length of (i.e. the number
     /// of elements stored in) the referenced stack
     StackLength(StackId),
@@ -85,6 +146,8 @@ impl Display for RegisterRef {
         match self {
             Self::Null => write!(f, "{}", NULL_REGISTER_REF),
             Self::InputLength => write!(f, "{}", INPUT_LENGTH_REGISTER_REF),
+            Self::InputHead => write!(f, "{}", INPUT_HEAD_REGISTER_REF),
+            Self::NullCount => write!(f, "{}", NULL_COUNT_REGISTER_REF),
             Self::StackLength(stack_id) => {
                 write!(f, "{}{}", STACK_LENGTH_REGISTER_REF_TAG, stack_id)
             }
@@ -95,31 +158,101 @@ impl Display for RegisterRef {
     }
 }
 
+// Serialize as the canonical string form, e.g. "RX0"
+impl Serialize for RegisterRef {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// Deserialize from the canonical string form, e.g. "RX0"
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for RegisterRef {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        match s.as_str() {
+            NULL_REGISTER_REF => Some(Self::Null),
+            INPUT_LENGTH_REGISTER_REF => Some(Self::InputLength),
+            INPUT_HEAD_REGISTER_REF => Some(Self::InputHead),
+            NULL_COUNT_REGISTER_REF => Some(Self::NullCount),
+            _ => parse_id(&s, STACK_LENGTH_REGISTER_REF_TAG)
+                .map(Self::StackLength)
+                .or_else(|| {
+                    parse_id(&s, USER_REGISTER_REF_TAG).map(Self::User)
+                }),
+        }
+        .ok_or_else(|| {
+            de::Error::invalid_value(Unexpected::Str(&s), &"register reference")
+        })
+    }
+}
+
+/// Parse the ID out of a canonical reference, e.g. `"RX3"` with the tag `"RX"`
+/// gives `3`. Returns `None` if the string doesn't have the tag, or the rest
+/// of it isn't a valid ID.
+#[cfg(feature = "serde")]
+fn parse_id(s: &str, tag: &str) -> Option<usize> {
+    s.strip_prefix(tag)?.parse().ok()
+}
+
 /// Something that can produce a [LangValue] idempotently. The value
 /// can be read (repeatedly if necessary), but cannot *necessarily* be written
 /// to.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ValueSource<T> {
     /// A static value, fixed at build time
     Const(Node<LangValue, T>),
     /// A register, which can be read from to get a value
     Register(Node<RegisterRef, T>),
+    /// A named constant, e.g. `$K`. The compiler doesn't know its value, so
+    /// it's filled in from the program spec when a machine is allocated (see
+    /// [ProgramSpec::constants](crate::ProgramSpec::constants)), after which
+    /// it becomes a [Self::Const]. If the spec doesn't define it, reading it
+    /// triggers a runtime error.
+    Constant(Node<ConstantName, T>),
+}
+
+impl<T> Display for ValueSource<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Const(value) => write!(f, "{}", value.value()),
+            Self::Register(reg_ref) => write!(f, "{}", reg_ref.value()),
+            Self::Constant(name) => {
+                write!(f, "{}{}", CONSTANT_REF_TAG, name.value())
+            }
+        }
+    }
 }
 
 /// An instruction is the basic functional unit of GDLK. Each instruction
 /// performs a single basic operation, and takes 0 or more arguments.
 ///
-/// NOTE: All arithmetic operations are wrapping (for overflow/underflow).
+/// NOTE: By default, all arithmetic operations are wrapping (for
+/// overflow/underflow). See [OverflowBehavior](crate::OverflowBehavior).
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Instruction<T> {
     /// Reads one value from the input buffer to a register. If the input is
     /// empty, triggers a runtime error.
     Read(Node<RegisterRef, T>),
+    /// Same as [Self::Read], but if the input is empty, the register is set
+    /// to zero instead. This never triggers an error.
+    Rdz(Node<RegisterRef, T>),
     /// Writes a value to the output buffer.
     Write(Node<ValueSource<T>, T>),
 
     /// Sets a register to a value.
     Set(Node<RegisterRef, T>, Node<ValueSource<T>, T>),
+    /// Exchanges the values in two registers. Both must be writable. Swapping
+    /// with `RZR` just zeroes the other register, the same as setting it from
+    /// `RZR`.
+    Swp(Node<RegisterRef, T>, Node<RegisterRef, T>),
 
     /// Adds two values. Puts the result in the first argument.
     Add(Node<RegisterRef, T>, Node<ValueSource<T>, T>),
@@ -133,6 +266,22 @@ pub enum Instruction<T> {
     /// result is floored. If the divisor is zero, triggers a runtime error.
     Div(Node<RegisterRef, T>, Node<ValueSource<T>, T>),
 
+    /// Bitwise AND of the two values. Puts the result in the first argument.
+    And(Node<RegisterRef, T>, Node<ValueSource<T>, T>),
+    /// Bitwise OR of the two values. Puts the result in the first argument.
+    Or(Node<RegisterRef, T>, Node<ValueSource<T>, T>),
+    /// Bitwise XOR of the two values. Puts the result in the first argument.
+    Xor(Node<RegisterRef, T>, Node<ValueSource<T>, T>),
+    /// Shifts the first value left by the second. Puts the result in the first
+    /// argument. Only the low bits of the shift amount are used (5 bits for
+    /// 32-bit values), so shifting by the width of a value or more wraps
+    /// around, and a negative amount shifts by its low bits, e.g. `-1` shifts
+    /// a 32-bit value by 31. This never triggers an error.
+    Shl(Node<RegisterRef, T>, Node<ValueSource<T>, T>),
+    /// Same as [Self::Shl], but shifts right. This is an arithmetic shift, so
+    /// the sign bit is copied in from the left.
+    Shr(Node<RegisterRef, T>, Node<ValueSource<T>, T>),
+
     /// Compares the last two arguments, and stores the comparison result in
     /// the first register. Result is -1 if the first value is less than the
     /// second, 0 if they are equal, and 1 if the first value is greater. The
@@ -149,6 +298,19 @@ pub enum Instruction<T> {
     /// Pops the top value off the given stack into a register. If the stack is
     /// empty, triggers a runtime error.
     Pop(Node<StackRef, T>, Node<RegisterRef, T>),
+    /// Copies the top value of the given stack into a register, without
+    /// popping it. If the stack is empty, triggers a runtime error.
+    Peek(Node<StackRef, T>, Node<RegisterRef, T>),
+    /// Pops the given number of values off the given stack, and throws them
+    /// away. A count of zero or less pops nothing. If the stack has fewer
+    /// values than that, triggers a runtime error and nothing is popped.
+    Popn(Node<StackRef, T>, Node<ValueSource<T>, T>),
+    /// Pushes a copy of the top value of the given stack onto the same stack.
+    /// If the stack is empty or already at capacity, triggers a runtime error.
+    Dup(Node<StackRef, T>),
+    /// Swaps the top two values of the given stack. If the stack has fewer
+    /// than two values, triggers a runtime error.
+    Sswp(Node<StackRef, T>),
 
     /// Jumps unconditionally to a label
     Jmp(Node<Label, T>),
@@ -160,6 +322,500 @@ pub enum Instruction<T> {
     Jlz(Node<ValueSource<T>, T>, Node<Label, T>),
     /// Jumps to a label if the value < 0
     Jgz(Node<ValueSource<T>, T>, Node<Label, T>),
+    /// Jumps to the instruction whose index is held in a register, e.g. for a
+    /// jump table. Indexes start at 0, and don't count labels. An index equal
+    /// to the number of instructions terminates the program. If the index is
+    /// negative or past that, triggers a runtime error.
+    Jri(Node<RegisterRef, T>),
+
+    /// Jumps to a label, and pushes the index of the next instruction onto the
+    /// machine's hidden call stack. If the call stack is already at its
+    /// maximum depth, triggers a runtime error.
+    Call(Node<Label, T>),
+    /// Pops an instruction index off the hidden call stack and jumps to it,
+    /// i.e. returns from the most recent `CALL`. If the call stack is empty,
+    /// triggers a runtime error.
+    Ret,
+
+    /// Does nothing, but still costs a cycle. Useful as a placeholder or as an
+    /// explicit jump target.
+    Nop,
+
+    /// Records the current value of all registers and stacks to the machine's
+    /// debug buffer. This has no effect on program state, but still costs a
+    /// cycle. Only available if enabled by the hardware spec.
+    Dump,
+
+    /// Calls a function provided by the host (an "extern op") with the value
+    /// of the register, then stores the result back in the register. Only
+    /// available if the hardware spec has extern ops. If the machine has no
+    /// handler for extern ops, or the handler fails, triggers a runtime error.
+    /// See [Machine::set_extern_handler](crate::Machine::set_extern_handler).
+    Ext(Node<ExternOpId, T>, Node<RegisterRef, T>),
+}
+
+// Formats as canonical source code, e.g. "ADD RX0 RX1". Parsing the output will
+// give back an equivalent instruction.
+impl<T> Display for Instruction<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(dst) => write!(f, "READ {}", dst.value()),
+            Self::Rdz(dst) => write!(f, "RDZ {}", dst.value()),
+            Self::Write(src) => write!(f, "WRITE {}", src.value()),
+            Self::Set(dst, src) => {
+                write!(f, "SET {} {}", dst.value(), src.value())
+            }
+            Self::Swp(reg_1, reg_2) => {
+                write!(f, "SWP {} {}", reg_1.value(), reg_2.value())
+            }
+            Self::Add(dst, src) => {
+                write!(f, "ADD {} {}", dst.value(), src.value())
+            }
+            Self::Sub(dst, src) => {
+                write!(f, "SUB {} {}", dst.value(), src.value())
+            }
+            Self::Mul(dst, src) => {
+                write!(f, "MUL {} {}", dst.value(), src.value())
+            }
+            Self::Div(dst, src) => {
+                write!(f, "DIV {} {}", dst.value(), src.value())
+            }
+            Self::And(dst, src) => {
+                write!(f, "AND {} {}", dst.value(), src.value())
+            }
+            Self::Or(dst, src) => {
+                write!(f, "OR {} {}", dst.value(), src.value())
+            }
+            Self::Xor(dst, src) => {
+                write!(f, "XOR {} {}", dst.value(), src.value())
+            }
+            Self::Shl(dst, src) => {
+                write!(f, "SHL {} {}", dst.value(), src.value())
+            }
+            Self::Shr(dst, src) => {
+                write!(f, "SHR {} {}", dst.value(), src.value())
+            }
+            Self::Cmp(dst, src_1, src_2) => write!(
+                f,
+                "CMP {} {} {}",
+                dst.value(),
+                src_1.value(),
+                src_2.value()
+            ),
+            Self::Push(src, stack) => {
+                write!(f, "PUSH {} {}", src.value(), stack.value())
+            }
+            Self::Pop(stack, dst) => {
+                write!(f, "POP {} {}", stack.value(), dst.value())
+            }
+            Self::Peek(stack, dst) => {
+                write!(f, "PEEK {} {}", stack.value(), dst.value())
+            }
+            Self::Popn(stack, count) => {
+                write!(f, "POPN {} {}", stack.value(), count.value())
+            }
+            Self::Dup(stack) => write!(f, "DUP {}", stack.value()),
+            Self::Sswp(stack) => write!(f, "SSWP {}", stack.value()),
+            Self::Jmp(label) => write!(f, "JMP {}", label.value()),
+            Self::Jez(src, label) => {
+                write!(f, "JEZ {} {}", src.value(), label.value())
+            }
+            Self::Jnz(src, label) => {
+                write!(f, "JNZ {} {}", src.value(), label.value())
+            }
+            Self::Jlz(src, label) => {
+                write!(f, "JLZ {} {}", src.value(), label.value())
+            }
+            Self::Jgz(src, label) => {
+                write!(f, "JGZ {} {}", src.value(), label.value())
+            }
+            Self::Jri(src) => write!(f, "JRI {}", src.value()),
+            Self::Call(label) => write!(f, "CALL {}", label.value()),
+            Self::Ret => write!(f, "RET"),
+            Self::Nop => write!(f, "NOP"),
+            Self::Dump => write!(f, "DUMP"),
+            Self::Ext(id, reg) => {
+                write!(f, "EXT {} {}", id.value(), reg.value())
+            }
+        }
+    }
+}
+
+impl<T> Instruction<T> {
+    /// Get mutable access to every value argument of this instruction, in
+    /// order.
+    pub(crate) fn value_sources_mut(
+        &mut self,
+    ) -> Vec<&mut Node<ValueSource<T>, T>> {
+        match self {
+            Self::Write(src)
+            | Self::Set(_, src)
+            | Self::Add(_, src)
+            | Self::Sub(_, src)
+            | Self::Mul(_, src)
+            | Self::Div(_, src)
+            | Self::And(_, src)
+            | Self::Or(_, src)
+            | Self::Xor(_, src)
+            | Self::Shl(_, src)
+            | Self::Shr(_, src)
+            | Self::Push(src, _)
+            | Self::Popn(_, src)
+            | Self::Jez(src, _)
+            | Self::Jnz(src, _)
+            | Self::Jlz(src, _)
+            | Self::Jgz(src, _) => vec![src],
+            Self::Cmp(_, src_1, src_2) => vec![src_1, src_2],
+            Self::Read(_)
+            | Self::Rdz(_)
+            | Self::Swp(_, _)
+            | Self::Pop(_, _)
+            | Self::Peek(_, _)
+            | Self::Dup(_)
+            | Self::Sswp(_)
+            | Self::Jmp(_)
+            | Self::Jri(_)
+            | Self::Call(_)
+            | Self::Ret
+            | Self::Nop
+            | Self::Dump
+            | Self::Ext(_, _) => vec![],
+        }
+    }
+
+    /// Get the metadata (usually the span) of every argument of this
+    /// instruction, including the values inside value arguments, in order.
+    /// The instruction's own metadata isn't included. Only needed for
+    /// loading cached programs.
+    #[cfg(feature = "serde")]
+    pub(crate) fn arg_metadata(&self) -> Vec<&T> {
+        fn value_metadata<T>(src: &Node<ValueSource<T>, T>) -> [&T; 2] {
+            let inner = match src.value() {
+                ValueSource::Const(value) => value.metadata(),
+                ValueSource::Register(reg) => reg.metadata(),
+                ValueSource::Constant(name) => name.metadata(),
+            };
+            [src.metadata(), inner]
+        }
+
+        let mut metadata = Vec::new();
+        match self {
+            Self::Read(reg) | Self::Rdz(reg) | Self::Jri(reg) => {
+                metadata.push(reg.metadata())
+            }
+            Self::Write(src) => metadata.extend(value_metadata(src)),
+            Self::Set(dst, src)
+            | Self::Add(dst, src)
+            | Self::Sub(dst, src)
+            | Self::Mul(dst, src)
+            | Self::Div(dst, src)
+            | Self::And(dst, src)
+            | Self::Or(dst, src)
+            | Self::Xor(dst, src)
+            | Self::Shl(dst, src)
+            | Self::Shr(dst, src) => {
+                metadata.push(dst.metadata());
+                metadata.extend(value_metadata(src));
+            }
+            Self::Swp(reg_1, reg_2) => {
+                metadata.extend([reg_1.metadata(), reg_2.metadata()])
+            }
+            Self::Cmp(dst, src_1, src_2) => {
+                metadata.push(dst.metadata());
+                metadata.extend(value_metadata(src_1));
+                metadata.extend(value_metadata(src_2));
+            }
+            Self::Push(src, stack) => {
+                metadata.extend(value_metadata(src));
+                metadata.push(stack.metadata());
+            }
+            Self::Popn(stack, src) => {
+                metadata.push(stack.metadata());
+                metadata.extend(value_metadata(src));
+            }
+            Self::Pop(stack, reg) | Self::Peek(stack, reg) => {
+                metadata.extend([stack.metadata(), reg.metadata()])
+            }
+            Self::Dup(stack) | Self::Sswp(stack) => {
+                metadata.push(stack.metadata())
+            }
+            Self::Jmp(label) | Self::Call(label) => {
+                metadata.push(label.metadata())
+            }
+            Self::Jez(src, label)
+            | Self::Jnz(src, label)
+            | Self::Jlz(src, label)
+            | Self::Jgz(src, label) => {
+                metadata.extend(value_metadata(src));
+                metadata.push(label.metadata());
+            }
+            Self::Ext(op, reg) => {
+                metadata.extend([op.metadata(), reg.metadata()])
+            }
+            Self::Ret | Self::Nop | Self::Dump => {}
+        }
+        metadata
+    }
+
+    /// Get every register that this instruction references, including ones
+    /// read through value arguments, in order. This comes straight from the
+    /// arguments, so unlike [ProgramStats](crate::ProgramStats), it can't be
+    /// out of date.
+    pub(crate) fn register_refs(&self) -> Vec<RegisterRef> {
+        let (registers, sources): (Vec<&Node<RegisterRef, T>>, Vec<_>) =
+            match self {
+                Self::Read(dst)
+                | Self::Rdz(dst)
+                | Self::Pop(_, dst)
+                | Self::Peek(_, dst)
+                | Self::Jri(dst)
+                | Self::Ext(_, dst) => (vec![dst], vec![]),
+                Self::Write(src)
+                | Self::Push(src, _)
+                | Self::Popn(_, src)
+                | Self::Jez(src, _)
+                | Self::Jnz(src, _)
+                | Self::Jlz(src, _)
+                | Self::Jgz(src, _) => (vec![], vec![src]),
+                Self::Set(dst, src)
+                | Self::Add(dst, src)
+                | Self::Sub(dst, src)
+                | Self::Mul(dst, src)
+                | Self::Div(dst, src)
+                | Self::And(dst, src)
+                | Self::Or(dst, src)
+                | Self::Xor(dst, src)
+                | Self::Shl(dst, src)
+                | Self::Shr(dst, src) => (vec![dst], vec![src]),
+                Self::Swp(reg_1, reg_2) => (vec![reg_1, reg_2], vec![]),
+                Self::Cmp(dst, src_1, src_2) => (vec![dst], vec![src_1, src_2]),
+                Self::Dup(_)
+                | Self::Sswp(_)
+                | Self::Jmp(_)
+                | Self::Call(_)
+                | Self::Ret
+                | Self::Nop
+                | Self::Dump => (vec![], vec![]),
+            };
+        registers
+            .into_iter()
+            .map(|reg| *reg.value())
+            .chain(sources.into_iter().filter_map(|src| match src.value() {
+                ValueSource::Register(reg) => Some(*reg.value()),
+                ValueSource::Const(_) | ValueSource::Constant(_) => None,
+            }))
+            .collect()
+    }
+
+    /// Get the stack that this instruction references, if any. No
+    /// instruction references more than one.
+    pub(crate) fn stack_ref(&self) -> Option<StackRef> {
+        match self {
+            Self::Push(_, stack)
+            | Self::Pop(stack, _)
+            | Self::Peek(stack, _)
+            | Self::Popn(stack, _)
+            | Self::Dup(stack)
+            | Self::Sswp(stack) => Some(*stack.value()),
+            Self::Read(_)
+            | Self::Rdz(_)
+            | Self::Write(_)
+            | Self::Set(_, _)
+            | Self::Swp(_, _)
+            | Self::Add(_, _)
+            | Self::Sub(_, _)
+            | Self::Mul(_, _)
+            | Self::Div(_, _)
+            | Self::And(_, _)
+            | Self::Or(_, _)
+            | Self::Xor(_, _)
+            | Self::Shl(_, _)
+            | Self::Shr(_, _)
+            | Self::Cmp(_, _, _)
+            | Self::Jmp(_)
+            | Self::Jez(_, _)
+            | Self::Jnz(_, _)
+            | Self::Jlz(_, _)
+            | Self::Jgz(_, _)
+            | Self::Jri(_)
+            | Self::Call(_)
+            | Self::Ret
+            | Self::Nop
+            | Self::Dump
+            | Self::Ext(_, _) => None,
+        }
+    }
+
+    /// Get the kind of this instruction, without any of its arguments.
+    pub fn kind(&self) -> InstructionKind {
+        match self {
+            Self::Read(_) => InstructionKind::Read,
+            Self::Rdz(_) => InstructionKind::Rdz,
+            Self::Write(_) => InstructionKind::Write,
+            Self::Set(_, _) => InstructionKind::Set,
+            Self::Swp(_, _) => InstructionKind::Swp,
+            Self::Add(_, _) => InstructionKind::Add,
+            Self::Sub(_, _) => InstructionKind::Sub,
+            Self::Mul(_, _) => InstructionKind::Mul,
+            Self::Div(_, _) => InstructionKind::Div,
+            Self::And(_, _) => InstructionKind::And,
+            Self::Or(_, _) => InstructionKind::Or,
+            Self::Xor(_, _) => InstructionKind::Xor,
+            Self::Shl(_, _) => InstructionKind::Shl,
+            Self::Shr(_, _) => InstructionKind::Shr,
+            Self::Cmp(_, _, _) => InstructionKind::Cmp,
+            Self::Push(_, _) => InstructionKind::Push,
+            Self::Pop(_, _) => InstructionKind::Pop,
+            Self::Peek(_, _) => InstructionKind::Peek,
+            Self::Popn(_, _) => InstructionKind::Popn,
+            Self::Dup(_) => InstructionKind::Dup,
+            Self::Sswp(_) => InstructionKind::Sswp,
+            Self::Jmp(_) => InstructionKind::Jmp,
+            Self::Jez(_, _) => InstructionKind::Jez,
+            Self::Jnz(_, _) => InstructionKind::Jnz,
+            Self::Jlz(_, _) => InstructionKind::Jlz,
+            Self::Jgz(_, _) => InstructionKind::Jgz,
+            Self::Jri(_) => InstructionKind::Jri,
+            Self::Call(_) => InstructionKind::Call,
+            Self::Ret => InstructionKind::Ret,
+            Self::Nop => InstructionKind::Nop,
+            Self::Dump => InstructionKind::Dump,
+            Self::Ext(_, _) => InstructionKind::Ext,
+        }
+    }
+}
+
+/// The different kinds of [Instruction], without any arguments attached.
+/// (De)serializes as the instruction's keyword, e.g. `"PUSH"`.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum InstructionKind {
+    Read,
+    Rdz,
+    Write,
+    Set,
+    Swp,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Cmp,
+    Push,
+    Pop,
+    Peek,
+    Popn,
+    Dup,
+    Sswp,
+    Jmp,
+    Jez,
+    Jnz,
+    Jlz,
+    Jgz,
+    Jri,
+    Call,
+    Ret,
+    Nop,
+    Dump,
+    Ext,
+}
+
+impl InstructionKind {
+    /// Every kind of instruction, in the order they're defined
+    pub const ALL: &'static [Self] = &[
+        Self::Read,
+        Self::Rdz,
+        Self::Write,
+        Self::Set,
+        Self::Swp,
+        Self::Add,
+        Self::Sub,
+        Self::Mul,
+        Self::Div,
+        Self::And,
+        Self::Or,
+        Self::Xor,
+        Self::Shl,
+        Self::Shr,
+        Self::Cmp,
+        Self::Push,
+        Self::Pop,
+        Self::Peek,
+        Self::Popn,
+        Self::Dup,
+        Self::Sswp,
+        Self::Jmp,
+        Self::Jez,
+        Self::Jnz,
+        Self::Jlz,
+        Self::Jgz,
+        Self::Jri,
+        Self::Call,
+        Self::Ret,
+        Self::Nop,
+        Self::Dump,
+        Self::Ext,
+    ];
+
+    /// The keyword for this instruction, as it's written in source, e.g.
+    /// `"PUSH"`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Read => "READ",
+            Self::Rdz => "RDZ",
+            Self::Write => "WRITE",
+            Self::Set => "SET",
+            Self::Swp => "SWP",
+            Self::Add => "ADD",
+            Self::Sub => "SUB",
+            Self::Mul => "MUL",
+            Self::Div => "DIV",
+            Self::And => "AND",
+            Self::Or => "OR",
+            Self::Xor => "XOR",
+            Self::Shl => "SHL",
+            Self::Shr => "SHR",
+            Self::Cmp => "CMP",
+            Self::Push => "PUSH",
+            Self::Pop => "POP",
+            Self::Peek => "PEEK",
+            Self::Popn => "POPN",
+            Self::Dup => "DUP",
+            Self::Sswp => "SSWP",
+            Self::Jmp => "JMP",
+            Self::Jez => "JEZ",
+            Self::Jnz => "JNZ",
+            Self::Jlz => "JLZ",
+            Self::Jgz => "JGZ",
+            Self::Jri => "JRI",
+            Self::Call => "CALL",
+            Self::Ret => "RET",
+            Self::Nop => "NOP",
+            Self::Dump => "DUMP",
+            Self::Ext => "EXT",
+        }
+    }
+}
+
+impl Display for InstructionKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
 }
 
 /// All types unique to the source AST live here.
@@ -180,10 +836,112 @@ pub mod source {
         Instruction(Node<Instruction<T>, T>),
     }
 
+    /// A line comment, e.g. "; hello". Comments have no effect on the
+    /// program, but they're kept around for tooling like the formatter.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct Comment {
+        /// Everything after the leading `;`, up to the end of the line
+        pub text: String,
+        /// Whether the comment follows a statement on the same line, rather
+        /// than being on a line of its own
+        pub trailing: bool,
+    }
+
+    impl Comment {
+        /// Doc comments start with `;;`, and describe the statement right
+        /// below them. See [Program::doc_comment].
+        pub fn is_doc(&self) -> bool {
+            self.text.starts_with(';')
+        }
+
+        /// Metadata comments start with `;!`, and describe the whole program.
+        /// See [ProgramMetadata](crate::ProgramMetadata).
+        pub fn is_metadata(&self) -> bool {
+            self.text.starts_with('!')
+        }
+    }
+
     /// A parsed and untransformed program.
     #[derive(Clone, Debug, PartialEq, Eq)]
     pub struct Program<T> {
+        /// Every statement in the program, in source order
         pub body: Vec<Node<Statement<T>, T>>,
+        /// Every comment in the program, in source order. These are kept
+        /// apart from the statements, so that compiling can ignore them.
+        pub(crate) comments: Vec<Node<Comment, T>>,
+    }
+
+    impl<T> Program<T> {
+        /// Get every comment in the program, in the order they appear in the
+        /// source. This includes both comments on their own lines and
+        /// comments that trail a statement.
+        pub fn comments(&self) -> &[Node<Comment, T>] {
+            &self.comments
+        }
+    }
+
+    impl Program<Span> {
+        /// Get the comments directly above a statement, i.e. the unbroken
+        /// run of comment-only lines that ends on the line before it. A blank
+        /// line or another statement ends the run.
+        pub fn leading_comments(
+            &self,
+            statement: &SpanNode<Statement<Span>>,
+        ) -> &[SpanNode<Comment>] {
+            let span = statement.metadata();
+            let end = self
+                .comments
+                .partition_point(|comment| comment.1.offset < span.offset);
+            let mut start = end;
+            let mut line = span.start_line;
+            while start > 0 {
+                let Node(comment, comment_span) = &self.comments[start - 1];
+                if comment.trailing || comment_span.start_line + 1 != line {
+                    break;
+                }
+                start -= 1;
+                line -= 1;
+            }
+            &self.comments[start..end]
+        }
+
+        /// Get the doc comment for a statement, which is made of the `;;`
+        /// lines directly above it. The `;;` (and one space after it, if
+        /// any) is stripped from each line, and the lines are joined with
+        /// newlines. Returns `None` if there is no doc comment.
+        pub fn doc_comment(
+            &self,
+            statement: &SpanNode<Statement<Span>>,
+        ) -> Option<String> {
+            let leading = self.leading_comments(statement);
+            // Regular comments above the doc comment aren't part of it
+            let start = leading
+                .iter()
+                .rposition(|Node(comment, _)| !comment.is_doc())
+                .map_or(0, |index| index + 1);
+            let lines: Vec<&str> = leading[start..]
+                .iter()
+                .map(|Node(comment, _)| {
+                    let text = &comment.text[1..];
+                    text.strip_prefix(' ').unwrap_or(text)
+                })
+                .collect();
+            if lines.is_empty() {
+                None
+            } else {
+                Some(lines.join("\n"))
+            }
+        }
+    }
+
+    /// A parsed program that has been validated against a hardware spec.
+    /// Validation doesn't change the program, it just collects the static
+    /// stats along the way. See
+    /// [Compiler::validate](crate::Compiler::validate).
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct ValidatedProgram<T> {
+        pub(crate) program: Program<T>,
+        pub(crate) stats: crate::ProgramStats,
     }
 }
 
@@ -196,6 +954,7 @@ pub mod compiled {
 
     /// A compiled program, ready to be executed.
     #[derive(Clone, Debug, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
     pub struct Program<T> {
         pub instructions: Vec<Node<Instruction<T>, T>>,
         /// A mapping of label:instruction index. These indexes are _after_ the
@@ -203,9 +962,63 @@ pub mod compiled {
         /// `instructions` field of this struct.
         pub symbol_table: HashMap<Label, usize>,
         pub stats: ProgramStats,
+        /// Every label and the index it points to, in the order they were
+        /// declared. This has the same contents as `symbol_table`, but keeps
+        /// the ordering from the source.
+        pub(crate) labels: Vec<(Label, usize)>,
+        /// Spans of other instructions that contributed to an instruction,
+        /// keyed by instruction index. Only set by optimization passes, and
+        /// most instructions don't have any. See [Self::contributing_spans].
+        #[cfg_attr(feature = "serde", serde(default))]
+        pub(crate) contributing_spans: HashMap<usize, Vec<T>>,
     }
 
     impl<T> Program<T> {
+        /// Get every label in the program, along with the index of the
+        /// instruction it points to. Labels are in the order they were
+        /// declared, which means they're also sorted by index. A label at the
+        /// very end of the program points one past the last instruction.
+        pub fn labels(&self) -> impl Iterator<Item = (&Label, usize)> {
+            self.labels.iter().map(|(label, index)| (label, *index))
+        }
+
+        /// Get the label that points at the instruction with the given index,
+        /// if any. If there are multiple, the first one declared is returned.
+        /// Pass the number of instructions to get a label at the end of the
+        /// program.
+        pub fn label_at(&self, instruction_index: usize) -> Option<&Label> {
+            // Labels are sorted by index, so we can binary search. There may
+            // be multiple at the same index, so find the first one.
+            let position = self
+                .labels
+                .partition_point(|(_, index)| *index < instruction_index);
+            match self.labels.get(position) {
+                Some((label, index)) if *index == instruction_index => {
+                    Some(label)
+                }
+                _ => None,
+            }
+        }
+
+        /// Get the spans of the other source instructions that contributed
+        /// to the instruction at the given index, sorted by position. The
+        /// instruction's own span is its primary span, and isn't included.
+        /// These come from optimization passes: a folded instruction lists
+        /// everything that was folded into it, and an instruction that's
+        /// certain to fail lists wherever its operands came from. Empty for
+        /// most instructions.
+        pub fn contributing_spans(&self, instruction_index: usize) -> &[T] {
+            self.contributing_spans
+                .get(&instruction_index)
+                .map(Vec::as_slice)
+                .unwrap_or(&[])
+        }
+
+        /// Get the static statistics for this program. See [ProgramStats].
+        pub fn stats(&self) -> &ProgramStats {
+            &self.stats
+        }
+
         /// Get the number of compiled instructions in this program (does not
         /// include comments, whitespace, etc.).
         pub fn num_instructions(&self) -> usize {
@@ -228,10 +1041,36 @@ pub mod compiled {
             self.stats.referenced_stacks.len()
         }
     }
+
+    impl Program<Span> {
+        /// Get the source span of the instruction at the given index, or
+        /// `None` if the index is past the end of the program.
+        pub fn span_at(&self, instruction_index: usize) -> Option<Span> {
+            self.instructions
+                .get(instruction_index)
+                .map(|instruction| *instruction.metadata())
+        }
+
+        /// Get the index of every instruction that starts on the given line
+        /// (starting at 1) of the main source, in order. Labels aren't
+        /// instructions, so a line with just a label has nothing on it, and
+        /// neither do lines in included files.
+        pub fn instructions_on_line(&self, line: usize) -> Vec<usize> {
+            self.instructions
+                .iter()
+                .enumerate()
+                .filter(|(_, instruction)| {
+                    let span = instruction.metadata();
+                    span.source_id.is_none() && span.start_line == line
+                })
+                .map(|(index, _)| index)
+                .collect()
+        }
+    }
 }
 
 // Types that are only needed in wasm.
-#[cfg(target_arch = "wasm32")]
+#[cfg(feature = "wasm")]
 pub mod wasm {
     use crate::Span;
     use serde::{Deserialize, Serialize};
@@ -246,6 +1085,16 @@ pub mod wasm {
         pub text: String,
         #[wasm_bindgen(readonly)]
         pub span: Span,
+        /// For a compiled instruction, the label that points at it, if any.
+        /// This is always `None` for errors.
+        #[wasm_bindgen(skip)]
+        #[serde(default)]
+        pub label: Option<String>,
+        /// For an error, its machine-readable code, e.g.
+        /// `"R003_STACK_OVERFLOW"`. This is always `None` for instructions.
+        #[wasm_bindgen(skip)]
+        #[serde(default)]
+        pub code: Option<String>,
     }
 
     #[wasm_bindgen]
@@ -254,6 +1103,16 @@ pub mod wasm {
         pub fn text(&self) -> String {
             self.text.clone()
         }
+
+        #[wasm_bindgen(getter)]
+        pub fn label(&self) -> Option<String> {
+            self.label.clone()
+        }
+
+        #[wasm_bindgen(getter)]
+        pub fn code(&self) -> Option<String> {
+            self.code.clone()
+        }
     }
 
     // Types that we can't natively return. These are assigned TS types, but
@@ -272,5 +1131,114 @@ pub mod wasm {
 
         #[wasm_bindgen(typescript_type = "SourceElement[]")]
         pub type SourceElementArray;
+
+        #[wasm_bindgen(typescript_type = "StepTrace | null")]
+        pub type OptionalStepTrace;
+
+        #[wasm_bindgen(typescript_type = "OutputDiff | null")]
+        pub type OptionalOutputDiff;
+
+        #[wasm_bindgen(typescript_type = "StopReason")]
+        pub type StopReasonObject;
+
+        #[wasm_bindgen(typescript_type = "DebugSnapshot[]")]
+        pub type DebugSnapshotArray;
+
+        #[wasm_bindgen(typescript_type = "ProfileEntry[] | null")]
+        pub type OptionalProfileEntryArray;
+
+        #[wasm_bindgen(typescript_type = "IoEvent[] | null")]
+        pub type OptionalIoEventArray;
+
+        #[wasm_bindgen(typescript_type = "RegisterInfo[]")]
+        pub type RegisterInfoArray;
+
+        #[wasm_bindgen(typescript_type = "InstructionInfo[]")]
+        pub type InstructionInfoArray;
+
+        #[wasm_bindgen(typescript_type = "ProgramStats")]
+        pub type ProgramStatsObject;
     }
+
+    // TS definitions for types that get serialized to plain JS objects
+    #[wasm_bindgen(typescript_custom_section)]
+    const TS_STEP_TRACE: &'static str = r#"
+export type StackOp =
+  | { kind: "Push"; stack: string; value: number }
+  | { kind: "Pop"; stack: string; value: number };
+
+export interface StepTrace {
+  span: Span;
+  program_counter_before: number;
+  program_counter_after: number;
+  register_writes: { register: string; old_value: number; new_value: number }[];
+  stack_ops: StackOp[];
+  input_read: number | null;
+  output_written: number | null;
+}
+
+export type StopReason =
+  | {
+      kind: "Event";
+      event: "OutputWritten" | "InputRead" | "StackChanged" | "JumpTaken";
+      span: Span;
+    }
+  | { kind: "Terminated" };
+
+export interface OutputDiff {
+  index: number;
+  expected: number | null;
+  actual: number | null;
+  missing: number;
+  extra: number;
+}
+
+export interface RegisterInfo {
+  register: string;
+  readable: boolean;
+  writable: boolean;
+  reason: "Base" | "NullCount" | "UserRegister" | { Stack: number };
+}
+
+export interface DebugSnapshot {
+  span: Span;
+  cycle: number;
+  registers: Record<string, number>;
+  stacks: Record<string, number[]>;
+}
+
+export interface ProfileEntry {
+  span: Span;
+  count: number;
+}
+
+export interface IoEvent {
+  cycle: number;
+  kind: "Read" | "Write";
+  value: number;
+  span: Span;
+}
+
+export interface InstructionInfo {
+  kind: string;
+  args: (
+    | "WritableRegister"
+    | "Register"
+    | "Value"
+    | "Stack"
+    | "Label"
+    | "ExternOp"
+  )[];
+  description: string;
+  errors: string[];
+}
+
+export interface ProgramStats {
+  num_instructions: number;
+  instruction_counts: Record<string, number>;
+  referenced_registers: string[];
+  referenced_stacks: string[];
+  max_jump_depth: number;
+}
+"#;
 }
@@ -0,0 +1,204 @@
+//! Serialization of compiled programs, so they can be cached and loaded later
+//! without compiling them again. The format is a short header, followed by
+//! the program as JSON:
+//!
+//! - 4 bytes: [MAGIC]
+//! - 4 bytes: format version, as a little-endian `u32` (see
+//!   [CACHE_FORMAT_VERSION])
+//! - The rest: the program, hardware spec, source, warnings, and metadata, as
+//!   JSON
+
+use crate::{
+    ast::{compiled, Node},
+    consts::CACHE_FORMAT_VERSION,
+    error::{CacheError, LintWarning, SourceErrorWrapper},
+    include::Includes,
+    metadata::ProgramMetadata,
+    models::HardwareSpec,
+    util::Span,
+    Compiler,
+};
+use serde::{Deserialize, Serialize};
+use std::{convert::TryInto, iter, mem};
+
+/// The first bytes of every cached program
+const MAGIC: &[u8; 4] = b"GDLK";
+
+/// Length of the header that comes before the JSON
+const HEADER_LENGTH: usize = MAGIC.len() + mem::size_of::<u32>();
+
+/// Everything needed to rebuild a compiler. This borrows from the compiler
+/// for serialization, see [CachedProgram] for deserialization.
+#[derive(Serialize)]
+struct CachedProgramRef<'a> {
+    hardware_spec: &'a HardwareSpec,
+    source: &'a str,
+    includes: &'a Includes,
+    program: &'a compiled::Program<Span>,
+    warnings: &'a [SourceErrorWrapper<LintWarning>],
+    metadata: &'a ProgramMetadata,
+}
+
+/// Owned version of [CachedProgramRef]
+#[derive(Deserialize)]
+struct CachedProgram {
+    hardware_spec: HardwareSpec,
+    source: String,
+    includes: Includes,
+    program: compiled::Program<Span>,
+    warnings: Vec<SourceErrorWrapper<LintWarning>>,
+    metadata: ProgramMetadata,
+}
+
+impl CachedProgram {
+    /// Make sure every span in the program and its warnings fits the source
+    /// that it points into. See [check_span].
+    fn check_spans(&self) -> Result<(), CacheError> {
+        let program = &self.program;
+        let instruction_spans =
+            program
+                .instructions
+                .iter()
+                .flat_map(|Node(instruction, span)| {
+                    iter::once(span).chain(instruction.arg_metadata())
+                });
+        let contributing_spans = program.contributing_spans.values().flatten();
+        let warning_spans = self.warnings.iter().flat_map(|warning| {
+            iter::once(warning.span())
+                .chain(warning.contributing_spans().iter().copied())
+        });
+        instruction_spans
+            .chain(contributing_spans)
+            .copied()
+            .chain(warning_spans)
+            .try_for_each(|span| {
+                check_span(&span, &self.source, &self.includes)
+            })
+    }
+}
+
+/// Make sure a span points into the file that it says it does: the file has
+/// to exist, the byte range has to be within it (and on character
+/// boundaries), and the lines and columns have to match the byte offsets.
+/// Rendering an error with a span that doesn't fit would panic.
+fn check_span(
+    span: &Span,
+    source: &str,
+    includes: &Includes,
+) -> Result<(), CacheError> {
+    let source = match span.source_id {
+        Some(id) => match includes.get(span) {
+            Some(included) => included.code.as_str(),
+            None => {
+                return Err(CacheError::Corrupt(format!(
+                    "Span points into unknown file {}",
+                    id
+                )))
+            }
+        },
+        None => source,
+    };
+    // Get the line and column of a byte offset, if it's in bounds
+    let position = |offset: usize| {
+        let before = source.get(..offset)?;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Some((
+            before.matches('\n').count() + 1,
+            before[line_start..].chars().count() + 1,
+        ))
+    };
+
+    let start = position(span.offset);
+    let end = span.offset.checked_add(span.length).and_then(position);
+    let fits = match (start, end) {
+        (Some(start), Some(end)) => {
+            // Empty spans can still cover a column, see Span::from_position
+            start == (span.start_line, span.start_col)
+                && (end == (span.end_line, span.end_col)
+                    || (span.length == 0
+                        && (span.end_line, span.end_col)
+                            == (span.start_line, span.start_col + 1)))
+        }
+        _ => false,
+    };
+    if fits {
+        Ok(())
+    } else {
+        Err(CacheError::Corrupt(format!(
+            "Span at offset {} doesn't fit the source",
+            span.offset
+        )))
+    }
+}
+
+impl Compiler<compiled::Program<Span>> {
+    /// Serialize the compiled program, along with the hardware spec it was
+    /// compiled for, so it can be cached. Use [Self::from_bytes] to load it
+    /// again. The format is versioned (see [CACHE_FORMAT_VERSION]), but
+    /// only the current version can be loaded.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let cached = CachedProgramRef {
+            hardware_spec: &self.hardware_spec,
+            source: &self.source,
+            includes: &self.includes,
+            program: &self.ast,
+            warnings: &self.warnings,
+            metadata: &self.metadata,
+        };
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&CACHE_FORMAT_VERSION.to_le_bytes());
+        // All of these types serialize to plain JSON values, so this can't
+        // fail
+        serde_json::to_writer(&mut bytes, &cached)
+            .expect("Failed to serialize compiled program");
+        bytes
+    }
+
+    /// Load a compiled program that was serialized with [Self::to_bytes].
+    /// The program must have been compiled for the given hardware spec, and
+    /// it gets validated against the spec again, so a program built for
+    /// different hardware (or a tampered one) can't be run. Every span has to
+    /// point into the stored source, since errors get rendered from it.
+    /// Malformed bytes give an error, never a panic.
+    pub fn from_bytes(
+        bytes: &[u8],
+        hardware_spec: &HardwareSpec,
+    ) -> Result<Self, CacheError> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(CacheError::Truncated);
+        }
+        let (magic, rest) = bytes.split_at(MAGIC.len());
+        if magic != MAGIC {
+            return Err(CacheError::InvalidHeader);
+        }
+        let (version, payload) = rest.split_at(mem::size_of::<u32>());
+        // The length was checked above, so this can't fail
+        let version = u32::from_le_bytes(version.try_into().unwrap());
+        if version != CACHE_FORMAT_VERSION {
+            return Err(CacheError::VersionMismatch {
+                expected: CACHE_FORMAT_VERSION,
+                actual: version,
+            });
+        }
+
+        let cached: CachedProgram = serde_json::from_slice(payload)
+            .map_err(|error| CacheError::Corrupt(error.to_string()))?;
+        if &cached.hardware_spec != hardware_spec {
+            return Err(CacheError::HardwareMismatch);
+        }
+
+        cached.check_spans()?;
+
+        let mut compiler = Self {
+            source: cached.source.into(),
+            hardware_spec: cached.hardware_spec,
+            includes: cached.includes,
+            ast: cached.program,
+            warnings: cached.warnings,
+            metadata: cached.metadata,
+        };
+        compiler.revalidate().map_err(CacheError::InvalidProgram)?;
+        Ok(compiler)
+    }
+}
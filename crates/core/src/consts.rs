@@ -1,17 +1,85 @@
+use crate::ast::LangValue;
+use std::ops::RangeInclusive;
+
 /// The maximum number of cycles that a program can run for before being killed.
-/// Programs that take this number of cycles *will* terminate normally, but the
-/// next instruction *after* hitting this threshold will trigger a runtime
-/// error. This isn't meant to be a strategic restriction on users, just a
-/// mechanism to prevent programs from running forever.
+/// Programs that take this number of cycles *will* terminate normally, but any
+/// instruction whose cost would push the count *past* this threshold will
+/// trigger a runtime error. This isn't meant to be a strategic restriction on
+/// users, just a mechanism to prevent programs from running forever.
 pub const MAX_CYCLE_COUNT: usize = 1_000_000;
 
+/// The default number of debug snapshots (generated by `DUMP`) that a machine
+/// will hold onto. Once the buffer is full, the oldest snapshot is dropped for
+/// each new one.
+pub const DEFAULT_MAX_DEBUG_SNAPSHOTS: usize = 100;
+
+/// The default maximum depth of the call stack used by `CALL` and `RET`. See
+/// [HardwareSpec::max_call_depth](crate::HardwareSpec::max_call_depth).
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 16;
+
+/// The value of the `RLI` register when the number of remaining inputs can't
+/// be known, e.g. when input comes from an interactive stream. See
+/// [InputSource::remaining](crate::InputSource::remaining).
+pub const UNKNOWN_INPUT_LENGTH: LangValue = -1;
+
+/// The range of user registers that [HardwareSpec::try_new] accepts.
+///
+/// [HardwareSpec::try_new]: crate::HardwareSpec::try_new
+pub const NUM_REGISTERS_RANGE: RangeInclusive<usize> = 1..=16;
+/// The range of stack counts that [HardwareSpec::try_new] accepts.
+///
+/// [HardwareSpec::try_new]: crate::HardwareSpec::try_new
+pub const NUM_STACKS_RANGE: RangeInclusive<usize> = 0..=16;
+/// The range of maximum stack lengths that [HardwareSpec::try_new] accepts.
+///
+/// [HardwareSpec::try_new]: crate::HardwareSpec::try_new
+pub const MAX_STACK_LENGTH_RANGE: RangeInclusive<usize> = 0..=256;
+
+/// The most values that a test case's input or expected output can hold. `RLI`
+/// holds the input length, so this has to fit in a [LangValue], but it's kept
+/// far lower so that a program spec can't create an enormous machine. See
+/// [ProgramSpec::validate](crate::ProgramSpec::validate).
+pub const MAX_TEST_CASE_LENGTH: usize = 4096;
+
+/// The largest ID that a register, stack, or extern op reference can have,
+/// e.g. `RX65535`.
+/// This is far past what any hardware has, so a reference that's in range can
+/// still be invalid, but bigger IDs are rejected while parsing. Keeping this
+/// fixed means the limit doesn't depend on the platform's `usize`.
+pub const MAX_REF_ID: usize = u16::MAX as usize;
+
+/// The token that separates statements that share a line, e.g.
+/// `READ RX0 | WRITE RX0`. `;` already starts a comment, so it can't be used.
+pub const STATEMENT_SEPARATOR: &str = "|";
+
 /// The prefix that indicates a stack reference.
 pub const STACK_REF_TAG: &str = "S";
 /// The string that refers to the null register.
 pub const NULL_REGISTER_REF: &str = "RZR";
 /// The string that refers to the input length register.
 pub const INPUT_LENGTH_REGISTER_REF: &str = "RLI";
+/// The string that refers to the input head register.
+pub const INPUT_HEAD_REGISTER_REF: &str = "RIH";
+/// The string that refers to the null write count register.
+pub const NULL_COUNT_REGISTER_REF: &str = "RZC";
 /// The prefix that indicates a reference to a stack length register.
 pub const STACK_LENGTH_REGISTER_REF_TAG: &str = "RS";
 /// The prefix that indicates a reference to a user register.
 pub const USER_REGISTER_REF_TAG: &str = "RX";
+/// The prefix that indicates a named constant, e.g. `$K`. See
+/// [ProgramSpec::constants](crate::ProgramSpec::constants).
+pub const CONSTANT_REF_TAG: &str = "$";
+/// The prefix that indicates a hexadecimal literal, e.g. `0x1F`.
+pub const HEX_PREFIX: &str = "0x";
+/// The prefix of a metadata line, e.g. `;! name: reverse`. See
+/// [ProgramMetadata](crate::ProgramMetadata).
+pub const METADATA_PREFIX: &str = ";!";
+/// The directive that pulls another file into a program, e.g.
+/// `%include "lib.gdlk"`. See [crate::include].
+pub const INCLUDE_DIRECTIVE: &str = "%include";
+
+/// The version of the format used by
+/// [Compiler::to_bytes](crate::Compiler::to_bytes). This gets bumped whenever
+/// the format changes, so that stale cached programs are rejected rather than
+/// misread.
+pub const CACHE_FORMAT_VERSION: u32 = 3;
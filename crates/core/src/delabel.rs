@@ -1,34 +1,139 @@
 use crate::{
     ast::{
         compiled::{self},
-        source::{self, LabelDecl, Statement},
-        Instruction, Node,
+        source::{LabelDecl, Statement, ValidatedProgram},
+        Instruction, Label, Node,
     },
+    error::LintWarning,
     util::Span,
-    Compiler, ProgramStats,
+    Compiler,
 };
 use std::collections::HashMap;
 
-impl Compiler<(source::Program<Span>, ProgramStats)> {
+/// Find all instructions that can never be executed, by walking every possible
+/// path through the jump graph from the first instruction. Returns one span per
+/// contiguous block of unreachable instructions.
+fn find_unreachable(
+    instructions: &[Node<Instruction<Span>, Span>],
+    symbol_table: &HashMap<Label, usize>,
+) -> Vec<Span> {
+    let mut reachable = vec![false; instructions.len()];
+    let mut to_visit = vec![0];
+    while let Some(index) = to_visit.pop() {
+        // Running off the end just terminates the program
+        if index >= instructions.len() || reachable[index] {
+            continue;
+        }
+        reachable[index] = true;
+
+        let target = |Node(label, _): &Node<Label, Span>| symbol_table[label];
+        match instructions[index].value() {
+            // Unconditional jumps are the only way to skip the next instruction
+            Instruction::Jmp(label) => to_visit.push(target(label)),
+            Instruction::Jez(_, label)
+            | Instruction::Jnz(_, label)
+            | Instruction::Jlz(_, label)
+            | Instruction::Jgz(_, label)
+            // A call eventually returns to the next instruction
+            | Instruction::Call(label) => {
+                to_visit.push(target(label));
+                to_visit.push(index + 1);
+            }
+            // Wherever this returns to is already covered by its CALL
+            Instruction::Ret => {}
+            // A computed jump could go anywhere, so everything is reachable
+            Instruction::Jri(_) => to_visit.extend(0..instructions.len()),
+            _ => to_visit.push(index + 1),
+        }
+    }
+
+    // Merge adjacent unreachable instructions into a single span. A run of
+    // instructions can cross into or out of an included file, in which case
+    // each file gets its own span.
+    let mut blocks: Vec<Span> = Vec::new();
+    let mut prev_reachable = true;
+    for (Node(_, span), reachable) in instructions.iter().zip(reachable) {
+        if !reachable {
+            match blocks.last_mut() {
+                Some(block)
+                    if !prev_reachable
+                        && block.source_id == span.source_id
+                        && block.offset <= span.offset =>
+                {
+                    *block = block.merge(span)
+                }
+                _ => blocks.push(*span),
+            }
+        }
+        prev_reachable = reachable;
+    }
+    blocks
+}
+
+/// Find how deeply jumps are nested. See
+/// [ProgramStats::max_jump_depth](crate::ProgramStats::max_jump_depth).
+pub(crate) fn max_jump_depth(
+    instructions: &[Node<Instruction<Span>, Span>],
+    symbol_table: &HashMap<Label, usize>,
+) -> usize {
+    // Mark where each jump's span starts and ends, then sweep over the
+    // instructions keeping a running count of open spans. A label can point
+    // one past the last instruction, hence the extra slot.
+    let mut deltas = vec![0isize; instructions.len() + 1];
+    for (index, Node(instruction, _)) in instructions.iter().enumerate() {
+        if let Instruction::Jmp(label)
+        | Instruction::Jez(_, label)
+        | Instruction::Jnz(_, label)
+        | Instruction::Jlz(_, label)
+        | Instruction::Jgz(_, label)
+        | Instruction::Call(label) = instruction
+        {
+            // A forward jump covers itself and everything it skips over. A
+            // backward jump covers everything from its target up to itself.
+            // Either way, a jump that lands right where another one starts
+            // doesn't overlap it.
+            let target = symbol_table[label.value()];
+            let (start, end) = if target > index {
+                (index, target)
+            } else {
+                (target, index + 1)
+            };
+            deltas[start] += 1;
+            deltas[end] -= 1;
+        }
+    }
+
+    let mut depth = 0;
+    deltas
+        .into_iter()
+        .map(|delta| {
+            depth += delta;
+            depth as usize
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+impl Compiler<ValidatedProgram<Span>> {
     /// Removes labels from the source, and pull them into a separate symbol
     /// table. The symbol table will map each label to its location in the
     /// program. The location will be an index into the vector of instructions
     /// that this function generates for the new program.
-    pub(crate) fn delabel(self) -> Compiler<compiled::Program<Span>> {
-        let body = self.ast.0.body;
-        let stats = self.ast.1;
+    pub fn delabel(self) -> Compiler<compiled::Program<Span>> {
+        let body = self.ast.program.body;
+        let mut stats = self.ast.stats;
 
         // Do a pass over the instructions and collect two things:
         // 1. A mapping of label:index, showing where a label exists in code
         // 2. All instructions (i.e. all statements *except* labels)
         // The label indexes will refer to the resulting list of *instructions*,
         // NOT the input list of *statements*
-        let mut symbol_table = HashMap::new();
+        let mut labels = Vec::new();
         let mut instructions: Vec<Node<Instruction<_>, _>> = Vec::new();
         for statement in body {
             match statement.0 {
                 Statement::Label(Node(LabelDecl(label), _)) => {
-                    symbol_table.insert(label, instructions.len());
+                    labels.push((label, instructions.len()));
                 }
                 Statement::Instruction(instruction_node) => {
                     instructions.push(instruction_node);
@@ -36,15 +141,55 @@ impl Compiler<(source::Program<Span>, ProgramStats)> {
             }
         }
 
+        // Labels were already checked for duplicates, so nothing gets lost
+        // when building the lookup table
+        let symbol_table: HashMap<Label, usize> =
+            labels.iter().cloned().collect();
+
+        // Collect the stats that depend on the final instruction list
+        stats.num_instructions = instructions.len();
+        for Node(instruction, _) in &instructions {
+            *stats
+                .instruction_counts
+                .entry(instruction.kind())
+                .or_insert(0) += 1;
+        }
+        stats.max_jump_depth = max_jump_depth(&instructions, &symbol_table);
+
+        // Now that we have the jump graph, look for dead code
+        let source = &self.source;
+        let includes = &self.includes;
+        let mut warnings = self.warnings;
+        warnings.extend(
+            find_unreachable(&instructions, &symbol_table)
+                .into_iter()
+                .map(|span| {
+                    includes.wrap_error(
+                        LintWarning::UnreachableCode,
+                        span,
+                        source,
+                    )
+                }),
+        );
+        // Keep warnings from all stages in source order, grouped by file
+        warnings.sort_by_key(|warning| {
+            let span = warning.span();
+            (span.source_id, span.offset)
+        });
+
         Compiler {
             source: self.source,
             hardware_spec: self.hardware_spec,
-            // Stats won't change at this point, just forward them down
+            includes: self.includes,
             ast: compiled::Program {
                 instructions,
                 symbol_table,
                 stats,
+                labels,
+                contributing_spans: HashMap::new(),
             },
+            warnings,
+            metadata: self.metadata,
         }
     }
 }
@@ -53,15 +198,17 @@ impl Compiler<(source::Program<Span>, ProgramStats)> {
 mod tests {
     use super::*;
     use crate::{
-        ast::{Instruction, RegisterRef},
+        ast::{source, Instruction, RegisterRef},
+        include::Includes,
         models::HardwareSpec,
+        ProgramMetadata, ProgramStats,
     };
-    use std::collections::HashSet;
 
     #[test]
     fn test_delabel() {
         // Dummy span for comparisons
         let span = Span {
+            source_id: None,
             offset: 0,
             length: 0,
             start_line: 0,
@@ -111,14 +258,19 @@ mod tests {
             ),
             Node(Statement::Label(Node(LabelDecl("END".into()), span)), span),
         ];
-        let empty_stats = ProgramStats {
-            referenced_registers: HashSet::new(),
-            referenced_stacks: HashSet::new(),
-        };
         let compiler = Compiler {
             source: "".into(),
             hardware_spec: HardwareSpec::default(),
-            ast: (source::Program { body }, empty_stats),
+            includes: Includes::default(),
+            ast: ValidatedProgram {
+                program: source::Program {
+                    body,
+                    comments: Vec::new(),
+                },
+                stats: ProgramStats::default(),
+            },
+            warnings: Vec::new(),
+            metadata: ProgramMetadata::default(),
         };
         assert_eq!(
             compiler.delabel().ast.instructions,
@@ -134,4 +286,49 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_delabel_trailing_labels() {
+        let span = Span {
+            source_id: None,
+            offset: 0,
+            length: 0,
+            start_line: 0,
+            start_col: 0,
+            end_line: 0,
+            end_col: 0,
+        };
+        // One label on a trailing NOP, and one pointing past the end
+        let body = vec![
+            Node(Statement::Instruction(Node(Instruction::Nop, span)), span),
+            Node(Statement::Label(Node(LabelDecl("LAST".into()), span)), span),
+            Node(Statement::Instruction(Node(Instruction::Nop, span)), span),
+            Node(Statement::Label(Node(LabelDecl("END".into()), span)), span),
+        ];
+        let compiler = Compiler {
+            source: "".into(),
+            hardware_spec: HardwareSpec::default(),
+            includes: Includes::default(),
+            ast: ValidatedProgram {
+                program: source::Program {
+                    body,
+                    comments: Vec::new(),
+                },
+                stats: ProgramStats::default(),
+            },
+            warnings: Vec::new(),
+            metadata: ProgramMetadata::default(),
+        };
+        let program = compiler.delabel().ast;
+        assert_eq!(
+            program.instructions,
+            vec![Node(Instruction::Nop, span), Node(Instruction::Nop, span)]
+        );
+        assert_eq!(
+            program.symbol_table,
+            vec![("LAST".into(), 1), ("END".into(), 2)]
+                .into_iter()
+                .collect()
+        );
+    }
 }
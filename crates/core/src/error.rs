@@ -1,10 +1,23 @@
 //! All error-related GDLK types.
 
-#[cfg(target_arch = "wasm32")]
+#[cfg(feature = "wasm")]
 use crate::ast::wasm::SourceElement;
-use crate::util::{self, Span};
+use crate::{
+    ast::{ConstantName, InstructionKind, LangValue, RegisterRef, StackRef},
+    consts::{
+        STACK_LENGTH_REGISTER_REF_TAG, STACK_REF_TAG, USER_REGISTER_REF_TAG,
+    },
+    include::{Includes, ResolveError},
+    metadata::parse_entry,
+    util::{self, Span},
+};
+#[cfg(feature = "serde")]
+use serde::Deserialize;
 use serde::Serialize;
-use std::fmt::{self, Debug, Display, Formatter};
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    sync::Arc,
+};
 use thiserror::Error;
 
 /// A trait for any error that originates in source code. [SourceError]s rely on
@@ -13,6 +26,19 @@ pub trait SourceError: 'static + Send + Sync + Debug + Serialize {
     /// A simple type label for this error, e.g. `"syntax"` or `"runtime"`.
     fn type_label(&self) -> &'static str;
 
+    /// How severe this is, e.g. `"error"` or `"warning"`. Fatal errors should
+    /// just use the default.
+    fn severity(&self) -> &'static str {
+        "error"
+    }
+
+    /// A stable, machine-readable code for this kind of error, e.g.
+    /// `"R003_STACK_OVERFLOW"`. Unlike the message, this will never change,
+    /// so it can be used for localization or analytics. The prefix indicates
+    /// the category: `E` for compile errors, `R` for runtime errors, and `W`
+    /// for warnings.
+    fn error_code(&self) -> &'static str;
+
     /// Format this error into a simple message. `spanned_src` is the slice of
     /// the source code that corresponds to this error's [Span]. This needs to
     /// be provided by the caller in order to create a proper error message.
@@ -22,59 +48,260 @@ pub trait SourceError: 'static + Send + Sync + Debug + Serialize {
 /// An error that occurs during compilation of a program. The error will be
 /// due to a flaw in the program. This indicates a user error, _not_ an internal
 /// compiler error. Compiler bugs will always cause a panic.
-#[derive(Copy, Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 pub enum CompileError {
     /// Failed to parse the program because of a syntax error. `expected` is
     /// the name of the type of element that was expected where the error
     /// occured.
     Syntax { expected: &'static str },
-    /// Referenced a user register with an invalid identifier
-    InvalidRegisterRef,
-    /// Referenced a stack with an invalid identifier
-    InvalidStackRef,
-    /// Tried to write to a read-only register
-    UnwritableRegister,
-    /// Defined the same label more than once
+    /// An instruction was given more arguments than it takes. The span covers
+    /// just the extra arguments.
+    TooManyArguments { instruction: InstructionKind },
+    /// A register, stack, or extern op reference has an ID bigger than `max`,
+    /// e.g. `RX99999999`. The span covers just the ID.
+    RefIdTooLarge { max: usize },
+    /// A statement started with a word that isn't an instruction, e.g.
+    /// `REED RX0`. `suggestion` is the closest instruction by edit distance,
+    /// if any is close enough. The span covers just the word.
+    UnknownInstruction {
+        found: String,
+        suggestion: Option<&'static str>,
+    },
+    /// Referenced a user register with an invalid identifier. `available`
+    /// is the registers of the same kind that do exist.
+    InvalidRegisterRef { available: AvailableRefs },
+    /// Referenced a stack with an invalid identifier. `available` is the
+    /// stacks that do exist.
+    InvalidStackRef { available: AvailableRefs },
+    /// Tried to write to a read-only register. `available` is the user
+    /// registers, which can be written to.
+    UnwritableRegister { available: AvailableRefs },
+    /// Referenced an extern op that the hardware doesn't have. `available`
+    /// is the extern ops that do exist.
+    InvalidExternOp { available: AvailableRefs },
+    /// Defined the same label more than once. The span is the repeated
+    /// declaration, and `original` is the first one.
     DuplicateLabel { original: Span },
+    /// Defined a label with the same name as an instruction, e.g. `ADD:`
+    ReservedLabel,
     /// Referenced a label that wasn't defined
     InvalidLabel,
+    /// Used an instruction that isn't enabled by the hardware spec
+    DisabledInstruction,
+    /// Used an instruction that the hardware spec doesn't allow. See
+    /// [HardwareSpec::allowed_instructions](crate::HardwareSpec::allowed_instructions).
+    ForbiddenInstruction { kind: InstructionKind },
+    /// The program has more instructions than the hardware allows. Labels
+    /// don't count. The span is the first instruction past the limit. See
+    /// [HardwareSpec::max_instructions](crate::HardwareSpec::max_instructions).
+    ProgramTooLong { actual: usize, max: usize },
+    /// An included file couldn't be loaded. See [crate::include].
+    Include { error: ResolveError },
+    /// A file included itself, directly or through other includes
+    IncludeCycle,
 }
 
 impl SourceError for CompileError {
     fn type_label(&self) -> &'static str {
         match self {
-            Self::Syntax { .. } => "Syntax",
+            Self::Syntax { .. }
+            | Self::TooManyArguments { .. }
+            | Self::RefIdTooLarge { .. }
+            | Self::UnknownInstruction { .. } => "Syntax",
+            Self::Include { .. } | Self::IncludeCycle => "Include",
             _ => "Validation",
         }
     }
 
+    fn error_code(&self) -> &'static str {
+        match self {
+            Self::Syntax { .. } => "E001_SYNTAX",
+            Self::TooManyArguments { .. } => "E002_TOO_MANY_ARGUMENTS",
+            Self::RefIdTooLarge { .. } => "E003_REF_ID_TOO_LARGE",
+            Self::UnknownInstruction { .. } => "E004_UNKNOWN_INSTRUCTION",
+            Self::InvalidRegisterRef { .. } => "E010_INVALID_REGISTER",
+            Self::InvalidStackRef { .. } => "E011_INVALID_STACK",
+            Self::UnwritableRegister { .. } => "E012_UNWRITABLE_REGISTER",
+            Self::InvalidExternOp { .. } => "E013_INVALID_EXTERN_OP",
+            Self::DuplicateLabel { .. } => "E020_DUPLICATE_LABEL",
+            Self::ReservedLabel => "E022_RESERVED_LABEL",
+            Self::InvalidLabel => "E021_INVALID_LABEL",
+            Self::DisabledInstruction => "E030_DISABLED_INSTRUCTION",
+            Self::ForbiddenInstruction { .. } => "E031_FORBIDDEN_INSTRUCTION",
+            Self::ProgramTooLong { .. } => "E032_PROGRAM_TOO_LONG",
+            Self::Include { .. } => "E040_INCLUDE_FAILED",
+            Self::IncludeCycle => "E041_INCLUDE_CYCLE",
+        }
+    }
+
     fn fmt_msg(&self, f: &mut Formatter<'_>, spanned_src: &str) -> fmt::Result {
         match self {
             // the source span for syntax errors is just the remaining source,
             // so not very helpful
             Self::Syntax { expected } => write!(f, "Expected {}", expected,),
-            Self::InvalidRegisterRef => {
-                write!(f, "Invalid reference to register `{}`", spanned_src)
-            }
-            Self::InvalidStackRef => {
-                write!(f, "Invalid reference to stack `{}`", spanned_src)
+            Self::TooManyArguments { instruction } => write!(
+                f,
+                "Too many arguments for `{}`, unexpected `{}`",
+                instruction, spanned_src
+            ),
+            Self::RefIdTooLarge { max } => write!(
+                f,
+                "ID `{}` is too large, the maximum is {}",
+                spanned_src, max
+            ),
+            Self::UnknownInstruction { found, suggestion } => {
+                write!(f, "Unknown instruction `{}`", found)?;
+                match suggestion {
+                    Some(suggestion) => {
+                        write!(f, ", did you mean `{}`?", suggestion)
+                    }
+                    None => Ok(()),
+                }
             }
-            Self::UnwritableRegister => write!(
+            Self::InvalidRegisterRef { available } => write!(
                 f,
-                "Cannot write to read-only register `{}`",
-                spanned_src
+                "Invalid reference to register `{}` ({})",
+                spanned_src, available
+            ),
+            Self::InvalidStackRef { available } => write!(
+                f,
+                "Invalid reference to stack `{}` ({})",
+                spanned_src, available
+            ),
+            Self::InvalidExternOp { available } => write!(
+                f,
+                "Invalid reference to extern op `{}` ({})",
+                spanned_src, available
+            ),
+            Self::UnwritableRegister { available } => write!(
+                f,
+                "Cannot write to read-only register `{}` ({})",
+                spanned_src, available
             ),
+            // The span is the whole declaration, so strip off the colon
             Self::DuplicateLabel {
                 original: original_span,
             } => write!(
                 f,
-                "Duplicate decalaration of label `{}`, \
+                "Duplicate declaration of label `{}`, \
                     originally defined on line {}",
-                spanned_src, original_span.start_line,
+                spanned_src.trim_end_matches(':'),
+                original_span.start_line,
+            ),
+            Self::ReservedLabel => write!(
+                f,
+                "Label `{}` has the same name as an instruction",
+                spanned_src.trim_end_matches(':')
             ),
             Self::InvalidLabel => {
                 write!(f, "Invalid reference to label `{}`", spanned_src)
             }
+            Self::DisabledInstruction => write!(
+                f,
+                "Instruction `{}` is not enabled on this hardware",
+                spanned_src
+            ),
+            Self::ForbiddenInstruction { kind } => write!(
+                f,
+                "Instruction `{}` is not allowed on this hardware",
+                kind
+            ),
+            Self::ProgramTooLong { actual, max } => write!(
+                f,
+                "Program has {} instructions, but this hardware allows at \
+                    most {}",
+                actual, max
+            ),
+            Self::Include { error } => {
+                write!(f, "Cannot include `{}`, ", spanned_src)?;
+                match error {
+                    ResolveError::Disabled => {
+                        write!(f, "includes are not supported here")
+                    }
+                    ResolveError::NotFound => write!(f, "file not found"),
+                    ResolveError::Unreadable => {
+                        write!(f, "file could not be read")
+                    }
+                }
+            }
+            Self::IncludeCycle => {
+                write!(f, "Include cycle, `{}` includes itself", spanned_src)
+            }
+        }
+    }
+}
+
+/// The registers or stacks of one kind that exist on some hardware. This is
+/// included in reference errors, so the message can tell the user what they
+/// can actually use. It displays as a phrase like
+/// `this hardware has registers RX0-RX1`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum AvailableRefs {
+    /// `RX0` up to the given number of user registers
+    UserRegisters(usize),
+    /// `RS0` up to the given number of stacks, since there's one stack length
+    /// register per stack
+    StackLengthRegisters(usize),
+    /// `S0` up to the given number of stacks
+    Stacks(usize),
+    /// `0` up to the given number of extern ops
+    ExternOps(usize),
+    /// `RZC`, which only exists if the hardware counts writes to `RZR`
+    NullCount,
+}
+
+impl Display for AvailableRefs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // Format a range of references, e.g. "RX0-RX3", or just "RX0" if
+        // there's only one
+        let range = |tag: &str, count: usize| {
+            if count == 1 {
+                format!("{}0", tag)
+            } else {
+                format!("{}0-{}{}", tag, tag, count - 1)
+            }
+        };
+        let plural = |count: usize| if count == 1 { "" } else { "s" };
+
+        match *self {
+            Self::UserRegisters(0) => {
+                write!(f, "this hardware has no registers")
+            }
+            Self::UserRegisters(count) => write!(
+                f,
+                "this hardware has register{} {}",
+                plural(count),
+                range(USER_REGISTER_REF_TAG, count)
+            ),
+            Self::StackLengthRegisters(0) | Self::Stacks(0) => {
+                write!(f, "this hardware has no stacks")
+            }
+            Self::StackLengthRegisters(count) => write!(
+                f,
+                "this hardware has {} stack{}, with length register{} {}",
+                count,
+                plural(count),
+                plural(count),
+                range(STACK_LENGTH_REGISTER_REF_TAG, count)
+            ),
+            Self::Stacks(count) => write!(
+                f,
+                "this hardware has stack{} {}",
+                plural(count),
+                range(STACK_REF_TAG, count)
+            ),
+            Self::ExternOps(0) => {
+                write!(f, "this hardware has no extern ops")
+            }
+            Self::ExternOps(count) => write!(
+                f,
+                "this hardware has extern op{} {}",
+                plural(count),
+                range("", count)
+            ),
+            Self::NullCount => {
+                write!(f, "this hardware doesn't count writes to RZR")
+            }
         }
     }
 }
@@ -82,18 +309,54 @@ impl SourceError for CompileError {
 /// An error that occurs during execution of a program. The error will be
 /// due to a flaw in the program. This indicates a user error, _not_ a bug in
 /// the interpreter. Interpreter bugs will always panic.
-#[derive(Copy, Clone, Debug, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
 pub enum RuntimeError {
     /// DIV attempted with a zero divisor
     DivideByZero,
     /// READ attempted while input is empty
     EmptyInput,
-    /// PUSH attemped onto a stack that is at capacity
+    /// PUSH or DUP attemped onto a stack that is at capacity
     StackOverflow,
-    /// POP attempted from an empty stack
+    /// POP, PEEK, or DUP attempted from an empty stack, POPN attempted with
+    /// fewer values on the stack than requested, or SSWP attempted with fewer
+    /// than two values on the stack
     EmptyStack,
     /// Execution attempted after the program has hit the CPU cycle limit
     TooManyCycles,
+    /// ADD/SUB/MUL/DIV produced a result that doesn't fit in a value. Only
+    /// possible under
+    /// [OverflowBehavior::Error](crate::OverflowBehavior::Error).
+    ArithmeticOverflow,
+    /// CALL attempted while the call stack is at its maximum depth. See
+    /// [HardwareSpec::max_call_depth](crate::HardwareSpec::max_call_depth).
+    CallStackOverflow,
+    /// RET attempted while the call stack is empty
+    ReturnWithoutCall,
+    /// JRI attempted with an index that doesn't point to an instruction, or
+    /// just past the last one
+    InvalidJumpTarget { target: LangValue },
+    /// EXT attempted on a machine with no extern handler. See
+    /// [Machine::set_extern_handler](crate::Machine::set_extern_handler).
+    NoExternHandler,
+    /// The extern handler returned an error for EXT. `message` is the error
+    /// from the handler.
+    ExternFailed { message: String },
+    /// The machine returned to a state it was already in, so it would never
+    /// terminate. Only possible with loop detection enabled, see
+    /// [Machine::set_loop_detection](crate::Machine::set_loop_detection).
+    InfiniteLoop,
+    /// An instruction referenced a register, stack, or label that doesn't
+    /// exist, or wrote to a register that can't be written. Compiling for
+    /// the hardware rules this out, so this only happens if the program was
+    /// put together or deserialized by hand.
+    InvalidReference,
+    /// A named constant (e.g. `$K`) was read, but neither the program spec
+    /// nor the test case defines it. See
+    /// [ProgramSpec::constants](crate::ProgramSpec::constants). Allocating
+    /// a machine rules this out (see [AllocateError::UndefinedConstants]), so
+    /// this only happens if the machine was deserialized by hand.
+    UndefinedConstant,
 }
 
 impl SourceError for RuntimeError {
@@ -101,6 +364,25 @@ impl SourceError for RuntimeError {
         "Runtime"
     }
 
+    fn error_code(&self) -> &'static str {
+        match self {
+            Self::DivideByZero => "R001_DIVIDE_BY_ZERO",
+            Self::EmptyInput => "R002_EMPTY_INPUT",
+            Self::StackOverflow => "R003_STACK_OVERFLOW",
+            Self::EmptyStack => "R004_EMPTY_STACK",
+            Self::TooManyCycles => "R005_TOO_MANY_CYCLES",
+            Self::ArithmeticOverflow => "R006_ARITHMETIC_OVERFLOW",
+            Self::CallStackOverflow => "R007_CALL_STACK_OVERFLOW",
+            Self::ReturnWithoutCall => "R008_RETURN_WITHOUT_CALL",
+            Self::InfiniteLoop => "R009_INFINITE_LOOP",
+            Self::InvalidJumpTarget { .. } => "R010_INVALID_JUMP_TARGET",
+            Self::NoExternHandler => "R011_NO_EXTERN_HANDLER",
+            Self::ExternFailed { .. } => "R012_EXTERN_FAILED",
+            Self::InvalidReference => "R013_INVALID_REFERENCE",
+            Self::UndefinedConstant => "R014_UNDEFINED_CONSTANT",
+        }
+    }
+
     fn fmt_msg(&self, f: &mut Formatter<'_>, spanned_src: &str) -> fmt::Result {
         match self {
             Self::DivideByZero => write!(f, "Divide by zero"),
@@ -119,20 +401,334 @@ impl SourceError for RuntimeError {
                 cannot execute instruction `{}`",
                 spanned_src
             ),
+            Self::ArithmeticOverflow => {
+                write!(f, "Arithmetic overflow in `{}`", spanned_src)
+            }
+            Self::CallStackOverflow => {
+                write!(f, "Call stack overflow in `{}`", spanned_src)
+            }
+            Self::ReturnWithoutCall => {
+                write!(f, "Cannot return without a matching `CALL`")
+            }
+            Self::InfiniteLoop => write!(
+                f,
+                "Infinite loop detected, cannot execute instruction `{}`",
+                spanned_src
+            ),
+            Self::InvalidJumpTarget { target } => write!(
+                f,
+                "Jump target {} is out of range in `{}`",
+                target, spanned_src
+            ),
+            Self::NoExternHandler => write!(
+                f,
+                "Cannot execute `{}`, extern ops aren't available here",
+                spanned_src
+            ),
+            Self::ExternFailed { message } => {
+                write!(f, "Extern op failed in `{}`: {}", spanned_src, message)
+            }
+            Self::InvalidReference => write!(
+                f,
+                "Invalid reference to `{}`, it doesn't exist or can't be used here",
+                spanned_src
+            ),
+            Self::UndefinedConstant => write!(
+                f,
+                "Constant `{}` isn't defined by the program spec",
+                spanned_src
+            ),
+        }
+    }
+}
+
+/// An error in the definition of a [HardwareSpec](crate::HardwareSpec). These
+/// are caught when the spec is deserialized, or by calling
+/// [HardwareSpec::validate](crate::HardwareSpec::validate).
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum SpecError {
+    /// A list of names was given, but its length doesn't match the number of
+    /// registers/stacks that it names
+    #[error("Expected {expected} {kind} names, but got {actual}")]
+    NameCountMismatch {
+        kind: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+    /// A list of stack lengths was given, but its length doesn't match the
+    /// number of stacks
+    #[error("Expected {expected} stack lengths, but got {actual}")]
+    StackLengthCountMismatch { expected: usize, actual: usize },
+    /// Neither `max_stack_length` nor `stack_lengths` was given
+    #[error("Expected either max_stack_length or stack_lengths")]
+    MissingStackLength,
+    /// A name contains invalid characters
+    #[error(
+        "Invalid name `{0}`: names must start with a letter or underscore, and \
+        contain only ASCII letters, digits, and underscores"
+    )]
+    InvalidName(String),
+    /// A name collides with a built-in register or stack, e.g. `RX0`
+    #[error("Name `{0}` is reserved for a built-in register or stack")]
+    ReservedName(String),
+    /// The same name was given to more than one register/stack
+    #[error("Name `{0}` is used more than once")]
+    DuplicateName(String),
+    /// A hardware parameter is outside the range that it's allowed to be in
+    #[error(
+        "Expected {field} to be between {min} and {max}, but got {actual}"
+    )]
+    OutOfBounds {
+        field: &'static str,
+        min: usize,
+        max: usize,
+        actual: usize,
+    },
+    /// An instruction was given a cost of zero cycles
+    #[error("Cost of `{0}` must be at least 1 cycle")]
+    ZeroCost(InstructionKind),
+    /// An instruction costs more than a whole program is allowed to take
+    /// (see [MAX_CYCLE_COUNT](crate::MAX_CYCLE_COUNT)), so it could never run
+    #[error("Cost of `{kind}` must be at most {max} cycles, but got {actual}")]
+    CostTooHigh {
+        kind: InstructionKind,
+        max: usize,
+        actual: usize,
+    },
+}
+
+/// An error in the definition of a [ProgramSpec](crate::ProgramSpec). These
+/// are caught when the spec is deserialized, or by calling
+/// [ProgramSpec::validate](crate::ProgramSpec::validate).
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum ProgramSpecError {
+    /// The spec has no test cases
+    #[error("Program spec must have at least one test case")]
+    NoTestCases,
+    /// A test case's input or expected output has more than
+    /// [MAX_TEST_CASE_LENGTH](crate::MAX_TEST_CASE_LENGTH) values.
+    /// `test_case` is the index of the test case.
+    #[error(
+        "Expected {field} of test case {} to have at most {max} values, but \
+        got {actual}",
+        .test_case + 1
+    )]
+    TooManyValues {
+        test_case: usize,
+        field: &'static str,
+        max: usize,
+        actual: usize,
+    },
+}
+
+/// An error loading a compiled program from bytes. See
+/// [Compiler::from_bytes](crate::Compiler::from_bytes).
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, Error)]
+pub enum CacheError {
+    /// The bytes end before the header does
+    #[error("Cached program is truncated")]
+    Truncated,
+    /// The bytes don't start with the expected header, so they probably
+    /// aren't a cached program at all
+    #[error("Bytes are not a cached GDLK program")]
+    InvalidHeader,
+    /// The program was cached by a different version of GDLK, with a
+    /// different format
+    #[error(
+        "Cached program has format version {actual}, but only version \
+        {expected} is supported"
+    )]
+    VersionMismatch { expected: u32, actual: u32 },
+    /// The header is fine, but the program itself couldn't be decoded
+    #[error("Cached program is corrupt: {0}")]
+    Corrupt(String),
+    /// The program was compiled for a different hardware spec than the one
+    /// it's being loaded for
+    #[error("Cached program was compiled for different hardware")]
+    HardwareMismatch,
+    /// The program references something that the hardware doesn't have
+    #[error("Cached program is invalid for this hardware:\n{0}")]
+    InvalidProgram(WithSource<CompileError>),
+}
+
+/// An error pairing a compiled program with hardware that it doesn't fit,
+/// e.g. because it was compiled for a different hardware spec. Running it
+/// anyway would panic. See [Machine::new](crate::Machine::new).
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum SpecMismatchError {
+    /// The program references a register that the hardware doesn't have
+    #[error("Program references register `{reference}`, but {available}")]
+    InvalidRegisterRef {
+        reference: RegisterRef,
+        available: AvailableRefs,
+    },
+    /// The program references a stack that the hardware doesn't have
+    #[error("Program references stack `{reference}`, but {available}")]
+    InvalidStackRef {
+        reference: StackRef,
+        available: AvailableRefs,
+    },
+    /// A saved machine state doesn't have one slot per register or stack on
+    /// the hardware, e.g. because it was truncated or edited by hand
+    #[error(
+        "Machine state has {actual} {kind}, but the hardware has {expected}"
+    )]
+    InvalidState {
+        kind: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+    /// The hardware has more registers or stacks than a machine can be built
+    /// with (see [NUM_REGISTERS_RANGE](crate::NUM_REGISTERS_RANGE)
+    /// and [NUM_STACKS_RANGE](crate::NUM_STACKS_RANGE)). Specs aren't
+    /// range-checked when they're deserialized, so this can still get here.
+    #[error(
+        "Hardware has {actual} {kind}, but a machine can have at most {max}"
+    )]
+    TooLarge {
+        kind: &'static str,
+        max: usize,
+        actual: usize,
+    },
+}
+
+/// An error allocating a [Machine](crate::Machine) to run a program. See
+/// [Machine::new_with_constants](crate::Machine::new_with_constants).
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum AllocateError {
+    /// The program doesn't fit the hardware. This can't happen when
+    /// allocating through the [Compiler](crate::Compiler) that compiled the
+    /// program.
+    #[error(transparent)]
+    SpecMismatch(#[from] SpecMismatchError),
+    /// The program uses named constants (e.g. `$K`) that neither the test
+    /// case nor the program spec defines. Each name is listed once, sorted.
+    #[error(
+        "Program uses constants that aren't defined by the program spec: {}",
+        names
+            .iter()
+            .map(|name| format!("`${}`", name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )]
+    UndefinedConstants { names: Vec<ConstantName> },
+}
+
+/// An error from [Compiler::fuzz](crate::Compiler::fuzz)
+#[cfg(feature = "fuzz")]
+#[derive(Clone, Debug, Error)]
+pub enum FuzzError {
+    /// The program failed on one of the generated inputs. The failure is
+    /// boxed since it holds the whole test case result.
+    #[error("Program failed on the input generated from seed {}", .0.seed)]
+    Failed(Box<crate::FuzzFailure>),
+    /// The program couldn't be allocated, e.g. because it uses named
+    /// constants, which generated specs never define
+    #[error(transparent)]
+    Allocate(#[from] AllocateError),
+}
+
+/// A non-fatal issue found during compilation. These don't prevent the
+/// program from compiling, but probably indicate a mistake in the program.
+/// Lint warnings are accessible via
+/// [Compiler::warnings](crate::Compiler::warnings).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub enum LintWarning {
+    /// Declared a label that is never referenced by any jump
+    UnusedLabel,
+    /// One or more instructions that can never be executed, e.g. because they
+    /// follow an unconditional jump
+    UnreachableCode,
+    /// A metadata key that was already set earlier in the header. The first
+    /// value is kept. See [ProgramMetadata](crate::ProgramMetadata).
+    DuplicateMetadata,
+    /// A metadata line after the first statement, which is ignored
+    MisplacedMetadata,
+    /// A metadata line that isn't in the `;! key: value` format, which is
+    /// ignored
+    InvalidMetadata,
+}
+
+impl SourceError for LintWarning {
+    fn type_label(&self) -> &'static str {
+        "Lint"
+    }
+
+    fn severity(&self) -> &'static str {
+        "warning"
+    }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            Self::UnusedLabel => "W001_UNUSED_LABEL",
+            Self::UnreachableCode => "W002_UNREACHABLE_CODE",
+            Self::DuplicateMetadata => "W003_DUPLICATE_METADATA",
+            Self::MisplacedMetadata => "W004_MISPLACED_METADATA",
+            Self::InvalidMetadata => "W005_INVALID_METADATA",
+        }
+    }
+
+    fn fmt_msg(&self, f: &mut Formatter<'_>, spanned_src: &str) -> fmt::Result {
+        match self {
+            // The span is the whole declaration, so strip off the colon
+            Self::UnusedLabel => write!(
+                f,
+                "Label `{}` is never referenced",
+                spanned_src.trim_end_matches(':')
+            ),
+            Self::UnreachableCode => write!(f, "Unreachable code"),
+            Self::DuplicateMetadata => write!(
+                f,
+                "Metadata key `{}` is already set, so this line is ignored",
+                parse_entry(spanned_src).map_or(spanned_src, |(key, _)| key)
+            ),
+            Self::MisplacedMetadata => write!(
+                f,
+                "Metadata has to come before the first statement, so this \
+                    line is ignored"
+            ),
+            Self::InvalidMetadata => write!(
+                f,
+                "Metadata should look like `;! key: value`, so this line is \
+                    ignored"
+            ),
         }
     }
 }
 
+/// A runtime error that was recovered from, rather than halting execution.
+/// This only happens when a machine is running under
+/// [ErrorPolicy::Lenient](crate::ErrorPolicy::Lenient).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct RuntimeWarning {
+    /// The error that would have halted execution under the strict policy
+    pub error: RuntimeError,
+    /// The source span of the offending instruction or argument
+    pub span: Span,
+}
+
 /// A wrapper around a [SourceError], that holds some extra data:
 /// - The [Span] of the source code that caused the error
 /// - The offending chunk of source code itself
+/// - The name of the file it's in, if it's in an included file (see
+///   [crate::include])
 ///
 /// This type on its own can be formatted, without any external data.
 #[derive(Clone, Debug, Error, Serialize)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
 pub struct SourceErrorWrapper<E: SourceError> {
     error: E,
     span: Span,
     spanned_source: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_name: Option<String>,
+    /// Spans of other instructions that were folded into the one that caused
+    /// this error, rendered as notes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    contributing_spans: Vec<Span>,
 }
 
 impl<E: SourceError> SourceErrorWrapper<E> {
@@ -141,81 +737,372 @@ impl<E: SourceError> SourceErrorWrapper<E> {
             error,
             span,
             spanned_source: span.get_source_slice(src).into(),
+            source_name: None,
+            contributing_spans: Vec::new(),
         }
     }
 
+    /// Attach the spans of instructions that contributed to the one that
+    /// caused this error. See
+    /// [compiled::Program::contributing_spans](crate::ast::compiled::Program::contributing_spans).
+    pub(crate) fn with_contributing_spans(mut self, spans: &[Span]) -> Self {
+        self.contributing_spans = spans.to_vec();
+        self
+    }
+
+    /// Mark this error as coming from an included file with the given name
+    pub(crate) fn in_file(mut self, name: &str) -> Self {
+        self.source_name = Some(name.to_owned());
+        self
+    }
+
     pub fn span(&self) -> Span {
         self.span
     }
+
+    /// Get the spans of instructions that contributed to the one that caused
+    /// this error, besides its own [Self::span]. Only set for runtime errors
+    /// in optimized programs.
+    pub fn contributing_spans(&self) -> &[Span] {
+        &self.contributing_spans
+    }
+
+    /// Get the name of the included file that this error is in, or `None` if
+    /// it's in the main source
+    pub fn source_name(&self) -> Option<&str> {
+        self.source_name.as_deref()
+    }
+
+    /// Get the machine-readable code of the wrapped error. See
+    /// [SourceError::error_code].
+    pub fn error_code(&self) -> &'static str {
+        self.error.error_code()
+    }
+
+    /// Get just the message for the wrapped error, without the type label and
+    /// position that get included when formatting the whole wrapper.
+    pub fn message(&self) -> String {
+        /// Formats the message via [SourceError::fmt_msg]
+        struct Message<'a, E: SourceError>(&'a SourceErrorWrapper<E>);
+
+        impl<E: SourceError> Display for Message<'_, E> {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                self.0.error.fmt_msg(f, &self.0.spanned_source)
+            }
+        }
+
+        Message(self).to_string()
+    }
 }
 
 impl<E: SourceError> Display for SourceErrorWrapper<E> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{} error at {}:{}: ",
+            "{} {} at ",
             self.error.type_label(),
-            self.span.start_line,
-            self.span.start_col,
+            self.error.severity()
         )?;
+        if let Some(source_name) = &self.source_name {
+            write!(f, "{}:", source_name)?;
+        }
+        write!(f, "{}:{}: ", self.span.start_line, self.span.start_col)?;
         self.error.fmt_msg(f, &self.spanned_source)?;
+        for span in &self.contributing_spans {
+            write!(f, "\nnote: folded from line {}", span.start_line)?;
+        }
         Ok(())
     }
 }
 
 // This makes it a bit easier to send errors out to wasm
-#[cfg(target_arch = "wasm32")]
+#[cfg(feature = "wasm")]
 impl<E: SourceError> From<&SourceErrorWrapper<E>> for SourceElement {
     fn from(error: &SourceErrorWrapper<E>) -> Self {
         SourceElement {
             text: error.to_string(),
             span: error.span(),
+            label: None,
+            code: Some(error.error_code().into()),
         }
     }
 }
 
 /// A wrapper around of a collection of errors. This holds the errors as well as
-/// the source code, and can be used to render associated source code with each
-/// error.
+/// the source code (including any included files), and can be used to render
+/// associated source code with each error. The source code is *not* included
+/// in (de)serialization, so a deserialized value renders without any source.
+///
+/// The collection can be capped (see [Self::errors_limited]), in which case
+/// only the first errors are kept, and the rest are just counted.
 #[derive(Clone, Debug, Error, Serialize)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
 pub struct WithSource<E: SourceError> {
     errors: Vec<SourceErrorWrapper<E>>,
+    /// The number of errors that were dropped from the end of the list
+    #[serde(default)]
+    truncated: usize,
+    /// Shared with the compiler or machine that produced the errors, so
+    /// wrapping them doesn't copy the whole program
+    #[serde(skip, default = "empty_source")]
+    source_code: Arc<str>,
     #[serde(skip)]
-    source_code: String,
+    includes: Includes,
+}
+
+/// Source code for a deserialized [WithSource], which doesn't have any
+#[cfg(feature = "serde")]
+fn empty_source() -> Arc<str> {
+    Arc::from("")
 }
 
 impl<E: SourceError> WithSource<E> {
     /// Wrap a collection of errors with its source code.
     pub(crate) fn new(
         errors: impl IntoIterator<Item = SourceErrorWrapper<E>>,
-        source: String,
+        source: impl Into<Arc<str>>,
     ) -> Self {
         Self {
             errors: errors.into_iter().collect(),
-            source_code: source,
+            truncated: 0,
+            source_code: source.into(),
+            includes: Includes::default(),
+        }
+    }
+
+    /// Keep only the first `max` errors, and count the rest as truncated
+    pub(crate) fn truncate(mut self, max: usize) -> Self {
+        if self.errors.len() > max {
+            self.truncated += self.errors.len() - max;
+            self.errors.truncate(max);
         }
+        self
+    }
+
+    /// Attach the included files that the errors' spans may point into
+    pub(crate) fn with_includes(mut self, includes: Includes) -> Self {
+        self.includes = includes;
+        self
     }
 
     /// Get a reference to the errors wrapped by this type.
     pub fn errors(&self) -> &[SourceErrorWrapper<E>] {
         &self.errors
     }
+
+    /// Get a copy of these errors with at most `max` of them. The rest are
+    /// dropped, but still counted by [Self::truncated_count], and mentioned
+    /// at the end of the rendered output.
+    pub fn errors_limited(&self, max: usize) -> Self
+    where
+        E: Clone,
+    {
+        self.clone().truncate(max)
+    }
+
+    /// Get the number of errors that were dropped because of a limit, either
+    /// from [Self::errors_limited] or
+    /// [CompileOptions::max_errors](crate::CompileOptions::max_errors).
+    pub fn truncated_count(&self) -> usize {
+        self.truncated
+    }
+
+    /// Get the full source code of the file with the given source ID (see
+    /// [Span::source_id]), so an error's span can be rendered against the
+    /// right file. `None` is the main source. Returns an empty string if the
+    /// source isn't available, e.g. after deserialization.
+    pub fn source(&self, source_id: Option<u32>) -> &str {
+        self.includes.source_for(&self.source_code, source_id)
+    }
 }
 
 impl<E: SourceError> Display for WithSource<E> {
+    /// Render each error with an annotated snippet of the source that caused
+    /// it, separated by blank lines. If any errors were truncated, that gets
+    /// noted at the end. To get just the error messages, format the individual
+    /// errors from [Self::errors] instead.
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        // Write each error, separated by a newline
         for (i, error) in self.errors.iter().enumerate() {
-            // Prefix with a newline for all errors but the first
+            // Separate each error from the previous one with a blank line
             if i > 0 {
-                writeln!(f)?; // just a newline
+                write!(f, "\n\n")?;
             }
 
             write!(f, "{}", error)?;
-            if f.alternate() {
-                util::fmt_src_highlights(f, &error.span, &self.source_code)?;
+            util::fmt_src_highlights(
+                f,
+                &error.span,
+                self.source(error.span.source_id),
+            )?;
+        }
+
+        if self.truncated > 0 {
+            if !self.errors.is_empty() {
+                write!(f, "\n\n")?;
             }
+            write!(
+                f,
+                "...and {} more {}",
+                self.truncated,
+                if self.truncated == 1 {
+                    "error"
+                } else {
+                    "errors"
+                }
+            )?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_available_refs_display() {
+        let cases = [
+            (
+                AvailableRefs::UserRegisters(0),
+                "this hardware has no registers",
+            ),
+            (
+                AvailableRefs::UserRegisters(1),
+                "this hardware has register RX0",
+            ),
+            (
+                AvailableRefs::UserRegisters(4),
+                "this hardware has registers RX0-RX3",
+            ),
+            (
+                AvailableRefs::StackLengthRegisters(0),
+                "this hardware has no stacks",
+            ),
+            (
+                AvailableRefs::StackLengthRegisters(1),
+                "this hardware has 1 stack, with length register RS0",
+            ),
+            (
+                AvailableRefs::StackLengthRegisters(2),
+                "this hardware has 2 stacks, with length registers RS0-RS1",
+            ),
+            (AvailableRefs::Stacks(0), "this hardware has no stacks"),
+            (AvailableRefs::Stacks(1), "this hardware has stack S0"),
+            (AvailableRefs::Stacks(3), "this hardware has stacks S0-S2"),
+            (
+                AvailableRefs::ExternOps(0),
+                "this hardware has no extern ops",
+            ),
+            (AvailableRefs::ExternOps(1), "this hardware has extern op 0"),
+            (
+                AvailableRefs::ExternOps(3),
+                "this hardware has extern ops 0-2",
+            ),
+            (
+                AvailableRefs::NullCount,
+                "this hardware doesn't count writes to RZR",
+            ),
+        ];
+        for (available, expected) in &cases {
+            assert_eq!(available.to_string(), *expected);
+        }
+    }
+
+    #[test]
+    fn test_error_codes_unique() {
+        // Every variant of every error type. The error_code implementations
+        // don't have wildcards, so new variants always need a new code, but
+        // they also need to be added here to be checked for uniqueness.
+        let compile_errors = [
+            CompileError::Syntax { expected: "" },
+            CompileError::TooManyArguments {
+                instruction: InstructionKind::Nop,
+            },
+            CompileError::RefIdTooLarge { max: 1 },
+            CompileError::UnknownInstruction {
+                found: String::new(),
+                suggestion: None,
+            },
+            CompileError::InvalidRegisterRef {
+                available: AvailableRefs::UserRegisters(1),
+            },
+            CompileError::InvalidStackRef {
+                available: AvailableRefs::Stacks(1),
+            },
+            CompileError::UnwritableRegister {
+                available: AvailableRefs::UserRegisters(1),
+            },
+            CompileError::InvalidExternOp {
+                available: AvailableRefs::ExternOps(1),
+            },
+            CompileError::DuplicateLabel {
+                original: Span {
+                    source_id: None,
+                    offset: 0,
+                    length: 0,
+                    start_line: 1,
+                    start_col: 1,
+                    end_line: 1,
+                    end_col: 1,
+                },
+            },
+            CompileError::ReservedLabel,
+            CompileError::InvalidLabel,
+            CompileError::DisabledInstruction,
+            CompileError::ForbiddenInstruction {
+                kind: InstructionKind::Nop,
+            },
+            CompileError::ProgramTooLong { actual: 2, max: 1 },
+            CompileError::Include {
+                error: ResolveError::Disabled,
+            },
+            CompileError::IncludeCycle,
+        ];
+        let runtime_errors = [
+            RuntimeError::DivideByZero,
+            RuntimeError::EmptyInput,
+            RuntimeError::StackOverflow,
+            RuntimeError::EmptyStack,
+            RuntimeError::TooManyCycles,
+            RuntimeError::ArithmeticOverflow,
+            RuntimeError::CallStackOverflow,
+            RuntimeError::ReturnWithoutCall,
+            RuntimeError::InfiniteLoop,
+            RuntimeError::InvalidJumpTarget { target: -1 },
+            RuntimeError::NoExternHandler,
+            RuntimeError::ExternFailed {
+                message: String::new(),
+            },
+            RuntimeError::InvalidReference,
+            RuntimeError::UndefinedConstant,
+        ];
+        let lint_warnings = [
+            LintWarning::UnusedLabel,
+            LintWarning::UnreachableCode,
+            LintWarning::DuplicateMetadata,
+            LintWarning::MisplacedMetadata,
+            LintWarning::InvalidMetadata,
+        ];
+
+        let codes: Vec<&str> = compile_errors
+            .iter()
+            .map(SourceError::error_code)
+            .chain(runtime_errors.iter().map(SourceError::error_code))
+            .chain(lint_warnings.iter().map(SourceError::error_code))
+            .collect();
+        let unique: HashSet<&str> = codes.iter().copied().collect();
+        assert_eq!(unique.len(), codes.len(), "Duplicate codes in {:?}", codes);
+
+        // The prefix matches the category
+        assert!(compile_errors
+            .iter()
+            .all(|error| error.error_code().starts_with('E')));
+        assert!(runtime_errors
+            .iter()
+            .all(|error| error.error_code().starts_with('R')));
+        assert!(lint_warnings
+            .iter()
+            .all(|error| error.error_code().starts_with('W')));
+    }
+}
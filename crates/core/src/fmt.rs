@@ -0,0 +1,449 @@
+//! Source code formatting. The formatter parses a program, then re-emits it in
+//! a canonical style: one statement per line, labels flush-left, instructions
+//! indented, and keywords/references upper-case. Comments stay on the line
+//! they were written on, and blank lines are kept (but runs of them are
+//! collapsed to one). Metadata lines (see
+//! [ProgramMetadata](crate::ProgramMetadata)) are never indented. Statements
+//! that share a line are split onto their own lines, unless
+//! [FormatOptions::keep_shared_lines] is set.
+
+use crate::{
+    ast::{source::Statement, Instruction, Node, SpanNode, ValueSource},
+    consts::{HEX_PREFIX, METADATA_PREFIX, STATEMENT_SEPARATOR},
+    error::{CompileError, WithSource},
+    models::HardwareSpec,
+    parse,
+    util::Span,
+};
+use std::collections::HashMap;
+
+/// Indentation that goes before every instruction
+const INDENT: &str = "    ";
+
+/// Options that change how a program is formatted
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// Keep statements that share a line (e.g. `READ RX0 | WRITE RX0`) on
+    /// one line, instead of giving each its own line
+    pub keep_shared_lines: bool,
+}
+
+/// One line of source, broken down into the pieces that get formatted
+enum Line<'a> {
+    Blank,
+    /// A line with nothing but a comment (including the leading `;`)
+    Comment(&'a str),
+    Statement {
+        /// The statement in canonical form, without any indentation
+        code: String,
+        is_label: bool,
+        /// Comment that follows the statement on the same line
+        comment: Option<&'a str>,
+    },
+}
+
+/// Format a program in the canonical GDLK style. Formatting is idempotent, so
+/// formatting the output again will give back the same output. The program
+/// must be free of syntax errors, but it doesn't have to be valid for any
+/// particular hardware. Register/stack aliases aren't supported, since there's
+/// no hardware spec to define them.
+pub fn format_program(
+    source: &str,
+) -> Result<String, WithSource<CompileError>> {
+    format_program_with_options(source, FormatOptions::default())
+}
+
+/// Same as [format_program], but with non-default options
+pub fn format_program_with_options(
+    source: &str,
+    options: FormatOptions,
+) -> Result<String, WithSource<CompileError>> {
+    let program = parse::parse(source, &HardwareSpec::default())
+        .map_err(|errors| WithSource::new(errors, source))?;
+    // Group statements by the line they're on, keeping their order
+    let mut statements: HashMap<usize, Vec<&Statement<Span>>> = HashMap::new();
+    for Node(statement, span) in &program.body {
+        statements
+            .entry(span.start_line)
+            .or_default()
+            .push(statement);
+    }
+    // There's at most one comment per line
+    let comments: HashMap<usize, &str> = program
+        .comments()
+        .iter()
+        .map(|Node(_, span)| {
+            let text = &source[span.offset..span.offset + span.length];
+            (span.start_line, text.trim_end())
+        })
+        .collect();
+
+    let mut lines = Vec::new();
+    for i in 0..source.split('\n').count() {
+        let comment = comments.get(&(i + 1)).copied();
+        match (statements.get(&(i + 1)), comment) {
+            (Some(line_statements), comment) => {
+                let is_label = |statement: &Statement<Span>| {
+                    matches!(statement, Statement::Label(_))
+                };
+                if options.keep_shared_lines {
+                    let codes: Vec<String> = line_statements
+                        .iter()
+                        .map(|statement| format_statement(statement, source))
+                        .collect();
+                    lines.push(Line::Statement {
+                        code: codes.join(&format!(" {} ", STATEMENT_SEPARATOR)),
+                        is_label: is_label(line_statements[0]),
+                        comment,
+                    });
+                } else {
+                    // The comment goes with the last statement, since that's
+                    // where it was written
+                    let last = line_statements.len() - 1;
+                    for (j, statement) in line_statements.iter().enumerate() {
+                        lines.push(Line::Statement {
+                            code: format_statement(statement, source),
+                            is_label: is_label(statement),
+                            comment: if j == last { comment } else { None },
+                        });
+                    }
+                }
+            }
+            (None, Some(comment)) => lines.push(Line::Comment(comment)),
+            (None, None) => lines.push(Line::Blank),
+        }
+    }
+
+    let mut output: Vec<String> = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        match line {
+            // Drop leading blank lines, and collapse runs of them
+            Line::Blank => {
+                if matches!(output.last(), Some(line) if !line.is_empty()) {
+                    output.push(String::new());
+                }
+            }
+            // Metadata describes the whole program, so it's never indented
+            Line::Comment(comment) if comment.starts_with(METADATA_PREFIX) => {
+                output.push(comment.to_string());
+            }
+            // Comments get the same indentation as the statement below them,
+            // so they stay visually attached to it
+            Line::Comment(comment) => {
+                let next_is_label =
+                    lines[i..].iter().find_map(|line| match line {
+                        Line::Statement { is_label, .. } => Some(*is_label),
+                        _ => None,
+                    });
+                let indent = match next_is_label {
+                    Some(false) => INDENT,
+                    Some(true) | None => "",
+                };
+                output.push(format!("{}{}", indent, comment));
+            }
+            Line::Statement {
+                code,
+                is_label,
+                comment,
+            } => {
+                let indent = if *is_label { "" } else { INDENT };
+                output.push(match comment {
+                    Some(comment) => format!("{}{} {}", indent, code, comment),
+                    None => format!("{}{}", indent, code),
+                });
+            }
+        }
+    }
+    // Drop trailing blank lines, then end with exactly one newline
+    while matches!(output.last(), Some(line) if line.is_empty()) {
+        output.pop();
+    }
+    let mut formatted = output.join("\n");
+    formatted.push('\n');
+    Ok(formatted)
+}
+
+/// Format a single statement in canonical form, without indentation
+fn format_statement(statement: &Statement<Span>, source: &str) -> String {
+    match statement {
+        Statement::Label(Node(label_decl, _)) => format!("{}:", label_decl.0),
+        Statement::Instruction(Node(instruction, _)) => {
+            format_instruction(instruction, source)
+        }
+    }
+}
+
+/// Format an instruction in canonical form. This is the same as the
+/// instruction's [Display](std::fmt::Display) implementation, except constant
+/// values are written the same way as in the source (e.g. `'A'` stays as
+/// `'A'` instead of becoming `65`).
+fn format_instruction(instruction: &Instruction<Span>, source: &str) -> String {
+    let value = |src: &SpanNode<ValueSource<Span>>| match src.value() {
+        ValueSource::Const(Node(_, span)) => format_const(span, source),
+        ValueSource::Register(reg_ref) => reg_ref.value().to_string(),
+        ValueSource::Constant(_) => src.value().to_string(),
+    };
+    let args = match instruction {
+        Instruction::Read(dst) | Instruction::Rdz(dst) => {
+            vec![dst.value().to_string()]
+        }
+        Instruction::Write(src) => vec![value(src)],
+        Instruction::Set(dst, src)
+        | Instruction::Add(dst, src)
+        | Instruction::Sub(dst, src)
+        | Instruction::Mul(dst, src)
+        | Instruction::Div(dst, src)
+        | Instruction::And(dst, src)
+        | Instruction::Or(dst, src)
+        | Instruction::Xor(dst, src)
+        | Instruction::Shl(dst, src)
+        | Instruction::Shr(dst, src) => {
+            vec![dst.value().to_string(), value(src)]
+        }
+        Instruction::Swp(reg_1, reg_2) => {
+            vec![reg_1.value().to_string(), reg_2.value().to_string()]
+        }
+        Instruction::Cmp(dst, src_1, src_2) => {
+            vec![dst.value().to_string(), value(src_1), value(src_2)]
+        }
+        Instruction::Push(src, stack) => {
+            vec![value(src), stack.value().to_string()]
+        }
+        Instruction::Pop(stack, dst) | Instruction::Peek(stack, dst) => {
+            vec![stack.value().to_string(), dst.value().to_string()]
+        }
+        Instruction::Popn(stack, count) => {
+            vec![stack.value().to_string(), value(count)]
+        }
+        Instruction::Dup(stack) | Instruction::Sswp(stack) => {
+            vec![stack.value().to_string()]
+        }
+        Instruction::Jmp(label) | Instruction::Call(label) => {
+            vec![label.value().clone()]
+        }
+        Instruction::Jez(src, label)
+        | Instruction::Jnz(src, label)
+        | Instruction::Jlz(src, label)
+        | Instruction::Jgz(src, label) => {
+            vec![value(src), label.value().clone()]
+        }
+        Instruction::Jri(src) => vec![src.value().to_string()],
+        Instruction::Ext(id, reg) => {
+            vec![id.value().to_string(), reg.value().to_string()]
+        }
+        Instruction::Ret | Instruction::Nop | Instruction::Dump => vec![],
+    };
+
+    let mut formatted = instruction.kind().to_string();
+    for arg in args {
+        formatted.push(' ');
+        formatted.push_str(&arg);
+    }
+    formatted
+}
+
+/// Format a constant value, keeping the literal style from the source. Hex
+/// literals are normalized to a lower-case prefix and upper-case digits.
+fn format_const(span: &Span, source: &str) -> String {
+    let literal = span.get_source_slice(source);
+    let prefix_len = HEX_PREFIX.len();
+    match literal.get(..prefix_len) {
+        Some(prefix) if prefix.eq_ignore_ascii_case(HEX_PREFIX) => format!(
+            "{}{}",
+            HEX_PREFIX,
+            literal[prefix_len..].to_ascii_uppercase()
+        ),
+        _ => literal.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compiler;
+
+    /// Programs with a mix of formatting styles. These come from the parser
+    /// tests, plus a few extra cases for comments and literals.
+    const CORPUS: &[&str] = &[
+        "LBL:\n\n\n",
+        "\n\nLBL:\n\n",
+        "  LBL:",
+        "  \n  LBL:",
+        "
+                LBL:
+                LBL1:
+                LBL_WITH_UNDERSCORE:
+                1LBL:
+                ",
+        "
+                ReAd RX0
+                WrItE RX0
+                ",
+        "
+        Set RX0 -10
+        add rx0 rx1
+        SUB RX0 RLI
+        MUL RX0 RS0
+        DIV RX0 rzr
+        CMP RX0 RX1 5
+        push RX0 s0
+        POP S0 RX0
+        ",
+        "
+        LOOP:
+        jez rli END
+        jnz RX0 LOOP
+        JGZ 1 LOOP
+        jlz -1 LOOP
+        JMP LOOP
+        nop
+        dump
+        END:
+        ",
+        "; header\n\n\n\nREAD RX0   ;   trailing  \r\n  ; about the label\nLBL:\n\
+            \t; about the instruction\n\tWRITE RX0\n; the end",
+        "WRITE 0x1f\nWRITE 0X0\nWRITE 'A'\nWRITE ' '\nWRITE ';' ; semicolon",
+        "LOOP: | JEZ RLI END|READ RX0 |  WRITE '|' ; c\nJMP LOOP\nEND:",
+        ";! name: echo\n  ;!puzzle:p1\n; about READ\nREAD RX0 ;! late\nWRITE RX0",
+    ];
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            format_program(
+                "
+  ; header
+loop:  ; start
+jez  rli   end
+
+
+    READ rx0 ;read
+  Write 0x1f
+  add   RX0   $K
+      jmp loop
+  end:
+; done
+"
+            )
+            .unwrap(),
+            "; header
+loop: ; start
+    JEZ RLI end
+
+    READ RX0 ;read
+    WRITE 0x1F
+    ADD RX0 $K
+    JMP loop
+end:
+; done
+"
+        );
+    }
+
+    #[test]
+    fn test_format_comments() {
+        // Comments take the indentation of the next statement
+        assert_eq!(
+            format_program(
+                ";a\nREAD RX0\n  ;b\nLBL:\n;c\n\n; d ;;\nWRITE ';' ; e\n;f"
+            )
+            .unwrap(),
+            "    ;a
+    READ RX0
+;b
+LBL:
+    ;c
+
+    ; d ;;
+    WRITE ';' ; e
+;f
+"
+        );
+    }
+
+    #[test]
+    fn test_format_metadata() {
+        // Metadata stays flush-left, even above an instruction
+        let source = "  ;! name: echo\n\t;!puzzle:p1\n;c\nREAD RX0\n;! late\n";
+        let formatted = format_program(source).unwrap();
+        assert_eq!(
+            formatted,
+            ";! name: echo\n;!puzzle:p1\n    ;c\n    READ RX0\n;! late\n"
+        );
+        // And it means the same thing after formatting
+        let metadata = |source: &str| {
+            Compiler::compile(source, HardwareSpec::default())
+                .unwrap()
+                .metadata()
+                .clone()
+        };
+        assert_eq!(metadata(&formatted), metadata(source));
+        assert_eq!(metadata(&formatted).puzzle.as_deref(), Some("p1"));
+    }
+
+    #[test]
+    fn test_format_shared_lines() {
+        let source = "LBL: |read rx0|WRITE RX0 ; c\n  JMP LBL | NOP\n";
+        assert_eq!(
+            format_program(source).unwrap(),
+            "LBL:\n    READ RX0\n    WRITE RX0 ; c\n    JMP LBL\n    NOP\n"
+        );
+        assert_eq!(
+            format_program_with_options(
+                source,
+                FormatOptions {
+                    keep_shared_lines: true
+                }
+            )
+            .unwrap(),
+            "LBL: | READ RX0 | WRITE RX0 ; c\n    JMP LBL | NOP\n"
+        );
+    }
+
+    #[test]
+    fn test_format_syntax_error() {
+        let errors = format_program("READ RX0\nREAD RW0\n").unwrap_err();
+        assert_eq!(
+            errors.errors()[0].to_string(),
+            "Syntax error at 2:6: Expected register reference"
+        );
+    }
+
+    #[test]
+    fn test_format_idempotent() {
+        for source in CORPUS {
+            let formatted = format_program(source).unwrap();
+            assert_eq!(
+                format_program(&formatted).unwrap(),
+                formatted,
+                "{:?}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_format_preserves_program() {
+        // Formatting should never change what the program does
+        let hardware_spec = HardwareSpec {
+            num_registers: 2,
+            num_stacks: 1,
+            max_stack_length: 5,
+            dump_enabled: true,
+            ..Default::default()
+        };
+        let instructions = |source: &str| {
+            Compiler::compile(source, hardware_spec.clone())
+                .unwrap()
+                .program()
+                .instructions
+                .iter()
+                .map(|instr| instr.value().to_string())
+                .collect::<Vec<_>>()
+        };
+        for source in &CORPUS[5..] {
+            let formatted = format_program(source).unwrap();
+            assert_eq!(instructions(&formatted), instructions(source));
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! Splitting programs across multiple files. A program can pull in another
+//! file with an include directive, on its own line:
+//!
+//! ```text
+//! %include "lib/math.gdlk"
+//! ```
+//!
+//! The included file's statements are spliced into the program in place of
+//! the directive, as if they had been written there. Included files can
+//! include other files, but an include cycle is an error.
+//!
+//! How paths get turned into source code is up to a [SourceResolver]. The
+//! default one, used by [Compiler::compile](crate::Compiler::compile), doesn't
+//! allow includes at all, so sandboxed environments (e.g. the browser) are
+//! unaffected. To enable them, use
+//! [Compiler::compile_with_resolver](crate::Compiler::compile_with_resolver).
+//!
+//! Spans in an included file are relative to that file, and their
+//! [source_id](crate::Span::source_id) says which file that is. See
+//! [Includes].
+
+use crate::{
+    error::{SourceError, SourceErrorWrapper},
+    util::Span,
+};
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Loads the source code for include directives. Implement this to control
+/// where included files come from.
+pub trait SourceResolver {
+    /// Load the file at the given path, exactly as written in the include
+    /// directive.
+    fn resolve(&self, path: &str) -> Result<IncludedSource, ResolveError>;
+}
+
+/// Why a [SourceResolver] couldn't load an included file
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum ResolveError {
+    /// The resolver doesn't allow includes at all
+    Disabled,
+    /// There's no file at the given path
+    NotFound,
+    /// The file exists, but couldn't be read
+    Unreadable,
+}
+
+/// A file that was pulled into a program by an include directive
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct IncludedSource {
+    /// A unique name for the file, which is shown in error messages. Two
+    /// paths that lead to the same file must give the same name, since this
+    /// is also used to detect include cycles.
+    pub name: String,
+    pub code: String,
+}
+
+/// A [SourceResolver] that rejects every include. This is what
+/// [Compiler::compile](crate::Compiler::compile) uses.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NoIncludes;
+
+impl SourceResolver for NoIncludes {
+    fn resolve(&self, _: &str) -> Result<IncludedSource, ResolveError> {
+        Err(ResolveError::Disabled)
+    }
+}
+
+/// A [SourceResolver] that reads from the filesystem. Paths are relative to
+/// the root directory (usually the directory of the main source file), no
+/// matter which file the include is in.
+#[derive(Clone, Debug)]
+pub struct FileResolver {
+    root: PathBuf,
+}
+
+impl FileResolver {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl SourceResolver for FileResolver {
+    fn resolve(&self, path: &str) -> Result<IncludedSource, ResolveError> {
+        let full_path = self.root.join(path);
+        let code =
+            fs::read_to_string(&full_path).map_err(|error| {
+                match error.kind() {
+                    io::ErrorKind::NotFound => ResolveError::NotFound,
+                    _ => ResolveError::Unreadable,
+                }
+            })?;
+
+        // Canonicalize so that different spellings of the same path (e.g.
+        // "a.gdlk" and "./a.gdlk") get the same name. Show it relative to the
+        // root when possible, since that's shorter.
+        let canonical = full_path.canonicalize().unwrap_or(full_path);
+        let name = match self.root.canonicalize() {
+            Ok(root) => canonical.strip_prefix(root).unwrap_or(&canonical),
+            Err(_) => canonical.as_path(),
+        };
+        Ok(IncludedSource {
+            name: display_path(name),
+            code,
+        })
+    }
+}
+
+/// Format a path the same way on every platform, so error messages are
+/// stable
+fn display_path(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Resolves includes from an in-memory map of path to source code. The path
+/// is used as the name.
+impl SourceResolver for HashMap<String, String> {
+    fn resolve(&self, path: &str) -> Result<IncludedSource, ResolveError> {
+        let code = self.get(path).ok_or(ResolveError::NotFound)?;
+        Ok(IncludedSource {
+            name: path.to_owned(),
+            code: code.clone(),
+        })
+    }
+}
+
+/// Every file that was included into a program, in the order they were
+/// first included. A [Span] with a `source_id` of `Some(n)` points into the
+/// file at index `n`, while `None` means the main source. This is cheap to
+/// clone, so it can be shared between a compiler and all its machines.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Includes(Arc<Vec<IncludedSource>>);
+
+impl Includes {
+    pub(crate) fn new(includes: Vec<IncludedSource>) -> Self {
+        Self(Arc::new(includes))
+    }
+
+    /// Get the file that a span points into, or `None` if it's in the main
+    /// source
+    pub fn get(&self, span: &Span) -> Option<&IncludedSource> {
+        self.get_by_id(span.source_id)
+    }
+
+    /// Get the file with the given source ID, or `None` for the main source
+    /// (or an ID that doesn't exist)
+    pub fn get_by_id(&self, source_id: Option<u32>) -> Option<&IncludedSource> {
+        source_id.and_then(|id| self.0.get(id as usize))
+    }
+
+    /// Get every included file, in order of source ID
+    pub fn files(&self) -> &[IncludedSource] {
+        &self.0
+    }
+
+    /// Get the source code that a span points into. `main` is the source of
+    /// the main file.
+    pub(crate) fn source_for<'a>(
+        &'a self,
+        main: &'a str,
+        source_id: Option<u32>,
+    ) -> &'a str {
+        match self.get_by_id(source_id) {
+            Some(included) => &included.code,
+            None => main,
+        }
+    }
+
+    /// Wrap an error with the spanned source code, from whichever file its
+    /// span points into. `main` is the source of the main file.
+    pub(crate) fn wrap_error<E: SourceError>(
+        &self,
+        error: E,
+        span: Span,
+        main: &str,
+    ) -> SourceErrorWrapper<E> {
+        match self.get(&span) {
+            Some(included) => {
+                SourceErrorWrapper::new(error, span, &included.code)
+                    .in_file(&included.name)
+            }
+            None => SourceErrorWrapper::new(error, span, main),
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Abstractions over where a [Machine](crate::Machine) reads its input from
+//! and writes its output to. Normally these are plain buffers, defined by a
+//! [TestCase](crate::TestCase), but they can be swapped out for streams (e.g.
+//! stdin and stdout) to run a program interactively. See
+//! [Machine::new_with_io](crate::Machine::new_with_io).
+
+use crate::ast::LangValue;
+
+/// Somewhere that a program's input comes from. `READ` pulls values from
+/// here, `RLI` reports how many are left, and `RIH` shows the next one.
+pub trait InputSource: Send {
+    /// Take the next value from the input. Returns `None` if the input is
+    /// exhausted, which is treated the same as reading from an empty input
+    /// buffer.
+    fn read(&mut self) -> Option<LangValue>;
+
+    /// Get the number of values left in the input. Returns `None` if that
+    /// can't be known ahead of time, e.g. for an interactive stream. In that
+    /// case, `RLI` reads as
+    /// [UNKNOWN_INPUT_LENGTH](crate::UNKNOWN_INPUT_LENGTH).
+    fn remaining(&self) -> Option<usize>;
+
+    /// Get the next value in the input, without taking it. Returns `None` if
+    /// the input is exhausted, or if the next value can't be known without
+    /// waiting for it, e.g. for an interactive stream. In either case, `RIH`
+    /// reads as zero. The default implementation always returns `None`.
+    fn peek(&self) -> Option<LangValue> {
+        None
+    }
+}
+
+/// Somewhere that a program's output goes. `WRITE` pushes values into here.
+pub trait OutputSink: Send {
+    /// Write a single value to the output.
+    fn write(&mut self, value: LangValue);
+}
+
+/// A plain input buffer. Values are read from the front.
+impl InputSource for Vec<LangValue> {
+    fn read(&mut self) -> Option<LangValue> {
+        if self.is_empty() {
+            None
+        } else {
+            // These buffers are small enough that shifting is fine
+            Some(self.remove(0))
+        }
+    }
+
+    fn remaining(&self) -> Option<usize> {
+        Some(self.len())
+    }
+
+    fn peek(&self) -> Option<LangValue> {
+        self.first().copied()
+    }
+}
+
+/// A plain output buffer. Values are pushed onto the back.
+impl OutputSink for Vec<LangValue> {
+    fn write(&mut self, value: LangValue) {
+        self.push(value);
+    }
+}
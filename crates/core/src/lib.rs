@@ -8,63 +8,119 @@
 //! use gdlk::{HardwareSpec, ProgramSpec, Compiler};
 //!
 //! // Create the specs
-//! let hardware_spec = HardwareSpec {
-//!     num_registers: 1,
-//!     num_stacks: 0,
-//!     max_stack_length: 0,
-//! };
+//! let hardware_spec = HardwareSpec::builder()
+//!     .registers(1)
+//!     .stacks(0)
+//!     .build()
+//!     .unwrap();
 //! let program_spec = ProgramSpec::new(vec![1], vec![2]);
 //!
 //! // Write your program
-//! let source: String = "
+//! let source = "
 //! READ RX0
 //! ADD RX0 1
 //! WRITE RX0
-//! ".into();
+//! ";
 //!
 //! // Compile
-//! let compiled = Compiler::compile(
-//!     source,
-//!     hardware_spec,
-//! ).unwrap();
+//! let compiled = Compiler::compile(source, hardware_spec).unwrap();
 //!
 //! // Execute
-//! let mut machine = compiled.allocate(&program_spec);
+//! let mut machine = compiled.allocate(&program_spec).unwrap();
 //! machine.execute_all().unwrap();
 //! assert!(machine.successful());
 //! ```
 
 #![deny(clippy::all)]
+#![cfg_attr(feature = "wasm", feature(cfg_eval))]
 
+// The wasm bindings pass values to JS as plain numbers, which can't hold
+// every i64
+#[cfg(all(feature = "wasm", feature = "i64-values"))]
+compile_error!("The `wasm` and `i64-values` features can't be used together");
+
+pub mod analysis;
+mod assertions;
 pub mod ast;
+#[cfg(feature = "serde")]
+mod cache;
 mod consts;
 mod delabel;
 pub mod error;
+pub mod fmt;
+pub mod include;
+mod io;
 mod machine;
+mod metadata;
 mod models;
+mod optimize;
 mod parse;
+mod registers;
 mod util;
 mod validate;
 
-pub use consts::MAX_CYCLE_COUNT;
+pub use consts::{
+    CACHE_FORMAT_VERSION, DEFAULT_MAX_CALL_DEPTH, DEFAULT_MAX_DEBUG_SNAPSHOTS,
+    MAX_CYCLE_COUNT, MAX_REF_ID, MAX_TEST_CASE_LENGTH, UNKNOWN_INPUT_LENGTH,
+};
+pub use io::{InputSource, OutputSink};
 pub use machine::*;
+pub use metadata::ProgramMetadata;
 pub use models::*;
+pub use parse::{ArgKind, InstructionInfo, INSTRUCTION_CATALOG};
+pub use registers::*;
 pub use util::Span;
 
-use crate::ast::compiled;
-use error::{CompileError, WithSource};
-use std::fmt::Debug;
+use crate::ast::{compiled, source, Node};
+#[cfg(feature = "fuzz")]
+use error::FuzzError;
+use error::{
+    AllocateError, CompileError, LintWarning, SourceErrorWrapper, WithSource,
+};
+use include::{Includes, NoIncludes, SourceResolver};
+use std::{fmt::Debug, sync::Arc};
 
 /// Struct used to compile a program. `T` represents the current type of the
 /// program. It starts as a [String], and as the compiler executes, the program
 /// gets transformed. See the library-level documentation for examples on how to
 /// compile and execute a program.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Compiler<T: Debug> {
     // These are deliberately private, to prevent direct construction
-    source: String,
+    // Shared with every machine that gets allocated, so the source doesn't
+    // need to be copied for each one
+    source: Arc<str>,
     hardware_spec: HardwareSpec,
+    /// Files pulled in by include directives, which spans may point into
+    includes: Includes,
     ast: T,
+    /// Non-fatal issues found so far. Each stage can add to this, and it gets
+    /// carried along to the next stage.
+    warnings: Vec<SourceErrorWrapper<LintWarning>>,
+    /// Read from the source's header during parsing, see [ProgramMetadata]
+    metadata: ProgramMetadata,
+}
+
+/// Compile a solution and run it against every test case in the program spec.
+/// This never panics, so it's safe to use on untrusted source. Compile and
+/// allocation errors are returned as part of the result rather than as an
+/// `Err`, since they're just another outcome for a solution.
+pub fn run_solution(
+    hardware_spec: &HardwareSpec,
+    program_spec: &ProgramSpec,
+    source: &str,
+) -> SolutionResult {
+    let compiler = match Compiler::compile(source, hardware_spec.clone()) {
+        Ok(compiler) => compiler,
+        Err(errors) => return SolutionResult::CompileError(errors),
+    };
+    match compiler.test_all(program_spec) {
+        Ok(results) => SolutionResult::Executed {
+            num_instructions: compiler.program().num_instructions(),
+            results,
+        },
+        Err(error) => SolutionResult::AllocateError(error),
+    }
 }
 
 impl Compiler<()> {
@@ -74,22 +130,186 @@ impl Compiler<()> {
     /// syntax) or used to allocate a [Machine] that can be executed. See
     /// library-level documentation for more info.
     pub fn compile(
-        source: String,
+        source: impl Into<String>,
         hardware_spec: HardwareSpec,
     ) -> Result<Compiler<compiled::Program<Span>>, WithSource<CompileError>>
     {
-        Ok(Self {
+        Self::compile_with_options(
             source,
             hardware_spec,
+            CompileOptions::default(),
+        )
+    }
+
+    /// Same as [Self::compile], but with custom [CompileOptions], which
+    /// enable extra passes over the compiled program.
+    pub fn compile_with_options(
+        source: impl Into<String>,
+        hardware_spec: HardwareSpec,
+        options: CompileOptions,
+    ) -> Result<Compiler<compiled::Program<Span>>, WithSource<CompileError>>
+    {
+        Self::compile_inner(source.into(), hardware_spec, options, &NoIncludes)
+    }
+
+    /// Same as [Self::compile], but include directives are allowed, and
+    /// loaded with the given resolver. See [include] for more info.
+    pub fn compile_with_resolver(
+        source: impl Into<String>,
+        hardware_spec: HardwareSpec,
+        resolver: &dyn SourceResolver,
+    ) -> Result<Compiler<compiled::Program<Span>>, WithSource<CompileError>>
+    {
+        Self::compile_inner(
+            source.into(),
+            hardware_spec,
+            CompileOptions::default(),
+            resolver,
+        )
+    }
+
+    /// Parse a source program, without compiling it any further. This is the
+    /// first stage of [Self::compile], and gives access to the source AST,
+    /// which still has its labels, for tooling that wants to inspect or
+    /// transform the program. To finish compiling, call [Compiler::validate]
+    /// with the hardware spec, and then [Compiler::delabel]. Optimization
+    /// passes (see [CompileOptions]) are only available through
+    /// [Self::compile_with_options].
+    ///
+    /// Register and stack aliases (see [HardwareSpec::register_names]) come
+    /// from the hardware, so they aren't recognized here. Programs that use
+    /// them have to be compiled with [Self::compile]. Include directives are
+    /// rejected, see [Self::parse_only_with_resolver].
+    ///
+    /// ```
+    /// use gdlk::{ast::source::Statement, Compiler, HardwareSpec};
+    ///
+    /// let source = "
+    /// LOOP:
+    /// JEZ RLI END
+    /// READ RX0
+    /// WRITE RX0
+    /// JMP LOOP
+    /// END:
+    /// ";
+    /// let compiler = Compiler::parse_only(source).unwrap();
+    ///
+    /// // Walk the source AST to count the labels
+    /// let num_labels = compiler
+    ///     .source_ast()
+    ///     .body
+    ///     .iter()
+    ///     .filter(|statement| matches!(statement.value(), Statement::Label(_)))
+    ///     .count();
+    /// assert_eq!(num_labels, 2);
+    ///
+    /// // Then finish compiling, same as Compiler::compile would
+    /// let compiler = compiler
+    ///     .validate(HardwareSpec::default())
+    ///     .unwrap()
+    ///     .delabel();
+    /// assert_eq!(compiler.compiled_ast().num_instructions(), 4);
+    /// ```
+    pub fn parse_only(
+        source: impl Into<String>,
+    ) -> Result<Compiler<source::Program<Span>>, WithSource<CompileError>> {
+        Self::parse_only_with_resolver(source, &NoIncludes)
+    }
+
+    /// Same as [Self::parse_only], but include directives are allowed, and
+    /// loaded with the given resolver. See [include] for more info.
+    pub fn parse_only_with_resolver(
+        source: impl Into<String>,
+        resolver: &dyn SourceResolver,
+    ) -> Result<Compiler<source::Program<Span>>, WithSource<CompileError>> {
+        Self {
+            source: source.into().into(),
+            // Parsing only needs the hardware for its aliases, and this one
+            // has none. It gets replaced during validation.
+            hardware_spec: HardwareSpec::default(),
+            includes: Includes::default(),
+            ast: (),
+            warnings: Vec::new(),
+            metadata: ProgramMetadata::default(),
+        }
+        .debug()
+        .parse(resolver)
+    }
+
+    /// Same as [Self::compile], but with both custom [CompileOptions] (see
+    /// [Self::compile_with_options]) and an include resolver (see
+    /// [Self::compile_with_resolver]).
+    pub fn compile_with_options_and_resolver(
+        source: impl Into<String>,
+        hardware_spec: HardwareSpec,
+        options: CompileOptions,
+        resolver: &dyn SourceResolver,
+    ) -> Result<Compiler<compiled::Program<Span>>, WithSource<CompileError>>
+    {
+        Self::compile_inner(source.into(), hardware_spec, options, resolver)
+    }
+
+    fn compile_inner(
+        source: String,
+        hardware_spec: HardwareSpec,
+        options: CompileOptions,
+        resolver: &dyn SourceResolver,
+    ) -> Result<Compiler<compiled::Program<Span>>, WithSource<CompileError>>
+    {
+        let limit_errors =
+            |errors: WithSource<CompileError>| match options.max_errors {
+                Some(max_errors) => errors.truncate(max_errors),
+                None => errors,
+            };
+        let compiler = Self {
+            source: source.into(),
+            // Aliases are resolved during parsing, so it needs the hardware
+            // too
+            hardware_spec: hardware_spec.clone(),
+            includes: Includes::default(),
             ast: (),
+            warnings: Vec::new(),
+            metadata: ProgramMetadata::default(),
         }
         .debug()
-        .parse()?
+        .parse(resolver)
+        .map_err(limit_errors)?
         .debug()
-        .validate()?
+        .validate(hardware_spec)
+        .map_err(limit_errors)?
         .debug()
         .delabel()
-        .debug())
+        .debug();
+
+        Ok(if options.constant_folding {
+            compiler.fold_constants().debug()
+        } else {
+            compiler
+        })
+    }
+}
+
+impl Compiler<source::Program<Span>> {
+    /// Get the AST for the parsed program, exactly as it appears in source.
+    /// See [Compiler::parse_only].
+    pub fn source_ast(&self) -> &source::Program<Span> {
+        &self.ast
+    }
+}
+
+impl Compiler<source::ValidatedProgram<Span>> {
+    /// Get the AST for the validated program. Validation doesn't change the
+    /// program, so this is the same as the parsed AST.
+    pub fn source_ast(&self) -> &source::Program<Span> {
+        &self.ast.program
+    }
+
+    /// Get the static statistics collected during validation. Only the
+    /// referenced registers and stacks are filled in so far. The rest depend
+    /// on the final instruction list, so they aren't counted until
+    /// [Compiler::delabel].
+    pub fn stats(&self) -> &ProgramStats {
+        &self.ast.stats
     }
 }
 
@@ -99,11 +319,182 @@ impl Compiler<compiled::Program<Span>> {
         &self.ast
     }
 
+    /// Get the AST for the compiled program, after labels have been removed.
+    /// This is the same as [Self::program], named to match
+    /// [Compiler::source_ast] for the earlier stages.
+    pub fn compiled_ast(&self) -> &compiled::Program<Span> {
+        &self.ast
+    }
+
+    /// Get the static statistics for the compiled program, e.g. how many
+    /// instructions it has. See [ProgramStats].
+    pub fn stats(&self) -> &ProgramStats {
+        self.ast.stats()
+    }
+
+    /// Get the text of each compiled instruction, in canonical GDLK syntax,
+    /// paired with the span of source code that it came from. Labels are not
+    /// included, since they aren't instructions.
+    pub fn instructions_pretty(&self) -> Vec<(String, Span)> {
+        self.ast
+            .instructions
+            .iter()
+            .map(|Node(instr, span)| (instr.to_string(), *span))
+            .collect()
+    }
+
+    /// Get all warnings that were generated during compilation. Warnings
+    /// don't prevent compilation, but likely indicate a mistake in the
+    /// program. The returned value can be formatted the same way as
+    /// compile errors.
+    pub fn warnings(&self) -> WithSource<LintWarning> {
+        WithSource::new(self.warnings.iter().cloned(), self.source.clone())
+            .with_includes(self.includes.clone())
+    }
+
+    /// Get every file that was pulled into the program by an include
+    /// directive. See [include].
+    pub fn includes(&self) -> &Includes {
+        &self.includes
+    }
+
+    /// Get the metadata from the header of the source, e.g. the solution's
+    /// name. Metadata has no effect on the compiled program. See
+    /// [ProgramMetadata].
+    pub fn metadata(&self) -> &ProgramMetadata {
+        &self.metadata
+    }
+
     /// Allocate a new [Machine] to execute a compiled program. The returned
     /// machine can then be executed. `program_spec` defines the parameters
-    /// under which the program will execute.
-    pub fn allocate(self, program_spec: &ProgramSpec) -> Machine {
-        Machine::new(self.hardware_spec, program_spec, self.ast, self.source)
+    /// under which the program will execute. If the spec has multiple test
+    /// cases, only the first one is used. To run every test case, see
+    /// [Self::allocate_all] or [Self::test_all].
+    ///
+    /// The program was validated against its hardware spec, either when it
+    /// was compiled or when it was loaded from the cache, so this can only
+    /// fail if the program uses named constants that the spec doesn't
+    /// define. See [AllocateError::UndefinedConstants].
+    pub fn allocate(
+        self,
+        program_spec: &ProgramSpec,
+    ) -> Result<Machine, AllocateError> {
+        self.allocate_with_policy(program_spec, ErrorPolicy::default())
+    }
+
+    /// Same as [Self::allocate], but with a custom [ErrorPolicy], which
+    /// determines which runtime errors will halt execution.
+    pub fn allocate_with_policy(
+        self,
+        program_spec: &ProgramSpec,
+        error_policy: ErrorPolicy,
+    ) -> Result<Machine, AllocateError> {
+        let machine = Machine::new_with_constants(
+            self.hardware_spec,
+            &program_spec.test_cases()[0],
+            program_spec.constants(),
+            self.ast,
+            self.source,
+            error_policy,
+        )?;
+        Ok(machine.with_includes(self.includes))
+    }
+
+    /// Build a [Machine] that reads its input from, and writes its output to,
+    /// the given streams, rather than running against a [ProgramSpec]. See
+    /// [Machine::new_with_io]. This fails if the program uses any named
+    /// constants, since there's no spec to define them.
+    pub fn allocate_with_io(
+        self,
+        input: Box<dyn InputSource>,
+        output: Box<dyn OutputSink>,
+    ) -> Result<Machine, AllocateError> {
+        let machine = Machine::new_with_io(
+            self.hardware_spec,
+            self.ast,
+            self.source,
+            ErrorPolicy::default(),
+            input,
+            output,
+        )?;
+        Ok(machine.with_includes(self.includes))
+    }
+
+    /// Allocate one [Machine] for each test case in the program spec, in the
+    /// same order as the test cases. Fails if any test case is missing a
+    /// named constant, see [Self::allocate].
+    pub fn allocate_all(
+        &self,
+        program_spec: &ProgramSpec,
+    ) -> Result<Vec<Machine>, AllocateError> {
+        program_spec
+            .test_cases()
+            .iter()
+            .map(|test_case| {
+                let machine = Machine::new_with_constants(
+                    self.hardware_spec.clone(),
+                    test_case,
+                    program_spec.constants(),
+                    self.ast.clone(),
+                    self.source.clone(),
+                    ErrorPolicy::default(),
+                )?;
+                Ok(machine.with_includes(self.includes.clone()))
+            })
+            .collect()
+    }
+
+    /// Execute the program against every test case in the program spec, and
+    /// collect the outcome of each one. Fails without running anything if
+    /// the machines can't be allocated, see [Self::allocate_all].
+    pub fn test_all(
+        &self,
+        program_spec: &ProgramSpec,
+    ) -> Result<TestResults, AllocateError> {
+        let cases = self
+            .allocate_all(program_spec)?
+            .into_iter()
+            .map(|mut machine| {
+                // Any error gets stored in the machine, we'll grab it below
+                let _ = machine.execute_all();
+                TestCaseResult {
+                    cycle_count: machine.cycle_count(),
+                    null_writes: machine.null_writes(),
+                    failure_reason: machine.failure_reason(),
+                    error: machine.error().cloned(),
+                    max_stack_usage: machine.max_stack_usage(),
+                }
+            })
+            .collect();
+        Ok(TestResults { cases })
+    }
+
+    /// Execute the program against `iterations` randomly generated inputs.
+    /// `expected_fn` computes the correct output for each input. Stops at the
+    /// first input that the program fails on, and returns the details needed
+    /// to reproduce it. Generated specs don't define any named constants, so
+    /// a program that uses any gives [FuzzError::Allocate].
+    #[cfg(feature = "fuzz")]
+    pub fn fuzz(
+        &self,
+        generator: &ProgramSpecGenerator,
+        expected_fn: impl Fn(&[ast::LangValue]) -> Vec<ast::LangValue>,
+        iterations: usize,
+    ) -> Result<(), FuzzError> {
+        for seed in generator.seeds(iterations) {
+            let program_spec = generator.generate(seed, &expected_fn);
+            // Generated specs always have exactly one test case
+            let result = self.test_all(&program_spec)?.cases.remove(0);
+            if !result.successful() {
+                return Err(FuzzError::Failed(Box::new(FuzzFailure {
+                    seed,
+                    input: program_spec.input().to_vec(),
+                    expected_output: program_spec.expected_output().to_vec(),
+                    result,
+                })));
+            }
+        }
+        Ok(())
     }
 }
 
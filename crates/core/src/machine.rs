@@ -1,39 +1,168 @@
-#[cfg(target_arch = "wasm32")]
-use crate::ast::wasm::{LangValueArrayMap, LangValueMap, SourceElement};
+#[cfg(feature = "wasm")]
+use crate::ast::wasm::{
+    DebugSnapshotArray, LangValueArrayMap, LangValueMap, OptionalIoEventArray,
+    OptionalOutputDiff, OptionalProfileEntryArray, OptionalStepTrace,
+    SourceElement, StopReasonObject,
+};
+#[cfg(feature = "wasm")]
+use crate::util::OrderedMap;
 use crate::{
     ast::{
-        compiled::Program, Instruction, Label, LangValue, Node, RegisterRef,
-        SpanNode, StackRef, ValueSource,
+        compiled::Program, ConstantName, ExternOpId, Instruction,
+        InstructionKind, Label, LangValue, Node, RegisterRef, SpanNode,
+        StackRef, ValueSource,
+    },
+    consts::{
+        DEFAULT_MAX_DEBUG_SNAPSHOTS, MAX_CYCLE_COUNT, MAX_STACK_LENGTH_RANGE,
+        NUM_REGISTERS_RANGE, NUM_STACKS_RANGE, UNKNOWN_INPUT_LENGTH,
     },
-    consts::MAX_CYCLE_COUNT,
     debug,
-    error::{RuntimeError, SourceErrorWrapper, WithSource},
-    models::{HardwareSpec, ProgramSpec},
+    error::{
+        AllocateError, AvailableRefs, RuntimeError, RuntimeWarning,
+        SpecMismatchError, WithSource,
+    },
+    include::Includes,
+    io::{InputSource, OutputSink},
+    models::{
+        ErrorPolicy, HardwareSpec, InitialState, OverflowBehavior, TestCase,
+    },
     util::Span,
 };
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+use serde::Serialize;
 use std::{
-    cmp::Ordering, collections::HashMap, convert::TryInto, iter, num::Wrapping,
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    convert::{TryFrom, TryInto},
+    fmt::{self, Debug, Display, Formatter},
+    io::{self, Write},
+    iter,
+    ops::BitOr,
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Arc, Mutex,
+    },
 };
-#[cfg(target_arch = "wasm32")]
+#[cfg(feature = "wasm")]
 use wasm_bindgen::{prelude::*, JsCast};
 
+/// How many instructions [Machine::execute_all_interruptible] runs between
+/// checks of its [CancelToken]. Checking is a single atomic load, but doing it
+/// on every instruction slowed down a tight loop by several percent. At this
+/// interval the overhead is lost in the noise, and a cancelled machine still
+/// stops well within a millisecond.
+const CANCEL_CHECK_INTERVAL: usize = 1024;
+
+/// A host function that executes `EXT` instructions. It gets called with the
+/// ID of the extern op and the value of the register, and returns the new
+/// value for the register, or an error message. See
+/// [Machine::set_extern_handler].
+pub type ExternHandler =
+    Box<dyn FnMut(ExternOpId, LangValue) -> Result<LangValue, String> + Send>;
+
+/// One variant of a binary arithmetic operation, e.g. [LangValue::checked_add].
+/// See [Machine::arithmetic].
+type ArithmeticFn<T = LangValue> = fn(LangValue, LangValue) -> T;
+
+/// The checked, wrapping, and saturating variants of a binary arithmetic
+/// operation, in that order.
+pub(crate) type ArithmeticOps =
+    (ArithmeticFn<Option<LangValue>>, ArithmeticFn, ArithmeticFn);
+
+pub(crate) const ADD_OPS: ArithmeticOps = (
+    LangValue::checked_add,
+    LangValue::wrapping_add,
+    LangValue::saturating_add,
+);
+pub(crate) const SUB_OPS: ArithmeticOps = (
+    LangValue::checked_sub,
+    LangValue::wrapping_sub,
+    LangValue::saturating_sub,
+);
+pub(crate) const MUL_OPS: ArithmeticOps = (
+    LangValue::checked_mul,
+    LangValue::wrapping_mul,
+    LangValue::saturating_mul,
+);
+// This does flooring division. The only way for it to overflow is MIN / -1.
+// Dividing by zero has to be checked separately.
+pub(crate) const DIV_OPS: ArithmeticOps = (
+    LangValue::checked_div,
+    LangValue::wrapping_div,
+    LangValue::saturating_div,
+);
+
+/// A bitwise operation, e.g. `AND`. These can't overflow, so unlike
+/// [ArithmeticOps], there's only one variant.
+pub(crate) type BitwiseFn = ArithmeticFn;
+
+pub(crate) const AND_OP: BitwiseFn = |lhs, rhs| lhs & rhs;
+pub(crate) const OR_OP: BitwiseFn = |lhs, rhs| lhs | rhs;
+pub(crate) const XOR_OP: BitwiseFn = |lhs, rhs| lhs ^ rhs;
+// Shift amounts are masked to the width of a value, the same as Rust's
+// wrapping shifts. Casting first means a negative amount uses its low bits.
+pub(crate) const SHL_OP: BitwiseFn = |lhs, rhs| lhs.wrapping_shl(rhs as u32);
+pub(crate) const SHR_OP: BitwiseFn = |lhs, rhs| lhs.wrapping_shr(rhs as u32);
+
+/// Apply an arithmetic operation to two values, handling overflow according to
+/// the given behavior. Returns `None` if the operation overflows and the
+/// behavior is [OverflowBehavior::Error].
+pub(crate) fn apply_arithmetic(
+    overflow_behavior: OverflowBehavior,
+    lhs: LangValue,
+    rhs: LangValue,
+    (checked, wrapping, saturating): ArithmeticOps,
+) -> Option<LangValue> {
+    match overflow_behavior {
+        OverflowBehavior::Wrap => Some(wrapping(lhs, rhs)),
+        OverflowBehavior::Saturate => Some(saturating(lhs, rhs)),
+        OverflowBehavior::Error => checked(lhs, rhs),
+    }
+}
+
+/// Compare two values the way `CMP` does: -1 if the first is less, 0 if
+/// they're equal, and 1 if the first is greater.
+pub(crate) fn compare(lhs: LangValue, rhs: LangValue) -> LangValue {
+    match lhs.cmp(&rhs) {
+        Ordering::Less => -1,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+    }
+}
+
 /// A steppable program executor. Maintains the current state of the program,
 /// and execution can be progressed one instruction at a time.
 ///
-/// Created from a [HardwareSpec](HardwareSpec), [ProgramSpec](ProgramSpec), and
-/// a program. The current machine state can be obtained at any time, including
-/// execution stats (e.g. # cycles), which allows for handy visualizations of
-/// execution.
-#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+/// Created from a [HardwareSpec](HardwareSpec), a [TestCase](TestCase) from a
+/// [ProgramSpec](crate::ProgramSpec), and a program. The current machine state
+/// can be obtained at any time, including execution stats (e.g. # cycles),
+/// which allows for handy visualizations of execution.
+///
+/// With the `serde` feature enabled, a machine can be serialized at any point
+/// during execution, then deserialized later to pick up where it left off.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(try_from = "MachineDef")
+)]
 pub struct Machine {
-    // Static data - this is copied from the input and shouldn't be included in
-    // serialization. We store these ourselves instead of keeping references
-    // to the originals because it just makes life a lot easier.
+    // Static data - this is copied from the input. We store these ourselves
+    // instead of keeping references to the originals because it just makes
+    // life a lot easier.
     hardware_spec: HardwareSpec,
-    source: String,
+    // Shared with the compiler, and any other machines for the same program
+    source: Arc<str>,
+    /// Files pulled in by include directives, which spans may point into
+    includes: Includes,
     program: Program<Span>,
     expected_output: Vec<LangValue>,
+    /// The input that the machine started with. The input buffer gets
+    /// consumed during execution, so this is needed for [Self::reset].
+    initial_input: Vec<LangValue>,
+    error_policy: ErrorPolicy,
 
     // Runtime state
     /// The index of the next instruction to be executed
@@ -51,123 +180,1067 @@ pub struct Machine {
     /// The series of stacks that act as the programs RAM. The number of stacks
     /// and their capacity is determined by the initializating hardware spec.
     stacks: Vec<Vec<LangValue>>,
+    /// The most values that each stack has held at once so far. Indexed by
+    /// stack ID.
+    max_stack_usage: Vec<usize>,
+    /// Return addresses pushed by `CALL` and popped by `RET`, innermost call
+    /// last. Each one is the index of the instruction after its `CALL`. This
+    /// is hidden from the program, and its depth is bounded by the hardware
+    /// spec.
+    call_stack: Vec<usize>,
     /// The number of instructions that have been executed so far. This is not
     /// unique, so repeated instructions are counted multiple times.
     cycle_count: usize,
+    /// The number of values that have been written to `RZR` and thrown away.
+    /// Programs can read this from `RZC`, if the hardware has it.
+    null_writes: usize,
     /// Stores a runtime error, if one has occurred. Once the error occurs,
     /// this should be populated and from then on, the machine has terminated
     /// and can no longer execute.
     error: Option<WithSource<RuntimeError>>,
+    /// Runtime errors that were recovered from, rather than halting execution.
+    /// Only populated under [ErrorPolicy::Lenient].
+    warnings: Vec<RuntimeWarning>,
+    /// The trace for the instruction currently being executed. This is only
+    /// populated during a call to [Self::execute_next_traced], otherwise no
+    /// tracing is done.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    trace: Option<StepTrace>,
+    /// The events triggered by the instruction currently being executed (or
+    /// the last one, if nothing is executing). Cleared at the start of each
+    /// instruction. See [Self::execute_until_event].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    events: EventMask,
+    /// Snapshots generated by `DUMP` instructions, oldest first. This is
+    /// bounded by `max_debug_snapshots`.
+    debug_snapshots: VecDeque<DebugSnapshot>,
+    /// The maximum number of debug snapshots to hold onto. Once this is hit,
+    /// the oldest snapshot gets dropped for each new one.
+    max_debug_snapshots: usize,
+    /// Streams that replace the input and output buffers, if this machine was
+    /// created with [Self::new_with_io]. Streams can't be serialized, so a
+    /// deserialized machine always uses its buffers.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    streams: Option<Streams>,
+    /// Executes `EXT` instructions, if set with [Self::set_extern_handler].
+    /// Like streams, this is shared between clones, and can't be serialized.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    extern_handler: Option<SharedExternHandler>,
+    /// Checked by [Self::execute_all_interruptible], so it can be stopped from
+    /// another thread. Shared between clones, like the extern handler.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    cancel_token: CancelToken,
+    /// Tracks machine state to catch infinite loops early. `None` if loop
+    /// detection is disabled, which is the default. See
+    /// [Self::set_loop_detection].
+    loop_detector: Option<LoopDetector>,
+    /// The number of times each instruction has executed, indexed by
+    /// instruction index. `None` if profiling is disabled, which is the
+    /// default. See [Self::set_profiling].
+    profile: Option<Vec<u64>>,
+    /// Every value read from the input or written to the output, in order.
+    /// `None` if I/O logging is disabled, which is the default. See
+    /// [Self::set_io_logging].
+    io_log: Option<Vec<IoEvent>>,
+}
+
+/// Streaming I/O for a [Machine], which replaces its input and output
+/// buffers. A stream can't be duplicated, so clones of a machine share the
+/// same streams.
+#[derive(Clone)]
+struct Streams {
+    input: Arc<Mutex<Box<dyn InputSource>>>,
+    output: Arc<Mutex<Box<dyn OutputSink>>>,
+}
+
+impl Debug for Streams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Streams").finish_non_exhaustive()
+    }
+}
+
+/// An [ExternHandler] that can be shared between clones of a [Machine]
+#[derive(Clone)]
+struct SharedExternHandler(Arc<Mutex<ExternHandler>>);
+
+impl Debug for SharedExternHandler {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedExternHandler")
+            .finish_non_exhaustive()
+    }
+}
+
+/// A handle for cancelling [Machine::execute_all_interruptible] from another
+/// thread. Get one with [Machine::cancel_token]. Every token for a machine
+/// shares the same flag, so cancelling any of them stops the machine.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Ask the machine to stop. This returns immediately, and the machine
+    /// stops within a few instructions. If the machine isn't running, the
+    /// next interruptible execution stops before running anything.
+    pub fn cancel(&self) {
+        self.0.store(true, AtomicOrdering::Relaxed);
+    }
+
+    /// Check if cancellation has been requested, and the machine hasn't
+    /// stopped for it yet
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Clear the flag, and return whether it was set
+    fn take(&self) -> bool {
+        self.0.swap(false, AtomicOrdering::Relaxed)
+    }
+}
+
+/// Detects when a machine is stuck in an infinite loop, using Brent's cycle
+/// detection algorithm. Execution is deterministic, so once a machine returns
+/// to the exact state it was in before, it will repeat the same states
+/// forever. Rather than remembering every state, the detector saves one state
+/// and compares each new state against it. The saved state is replaced at
+/// exponentially growing intervals, so every loop is caught within a few
+/// iterations, and most steps don't allocate anything.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct LoopDetector {
+    saved: Option<LoopState>,
+    /// The number of steps since the state was saved
+    steps: usize,
+    /// The number of steps until the saved state gets replaced
+    interval: usize,
+}
+
+impl LoopDetector {
+    fn new() -> Self {
+        Self {
+            saved: None,
+            steps: 0,
+            interval: 1,
+        }
+    }
+}
+
+/// Everything that determines what a machine will do next. The output and
+/// cycle count aren't included, since they can't affect execution. Only the
+/// length of the input is needed, because values are only ever removed from
+/// the front.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct LoopState {
+    program_counter: usize,
+    input_length: usize,
+    registers: Vec<LangValue>,
+    stacks: Vec<Vec<LangValue>>,
+    call_stack: Vec<usize>,
+    null_writes: Option<usize>,
+}
+
+/// 64-bit FNV-1a, used for [Machine::state_fingerprint]. The std hasher's
+/// algorithm isn't guaranteed to stay the same between Rust releases, and
+/// its output depends on the platform's pointer width, so fingerprints
+/// wouldn't be comparable across builds.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.write(&(value as u64).to_le_bytes());
+    }
+
+    /// Hash a list of values, prefixed by its length so that adjacent lists
+    /// can't run together
+    fn write_values(&mut self, values: &[LangValue]) {
+        self.write_usize(values.len());
+        for value in values {
+            self.write(&value.to_le_bytes());
+        }
+    }
+}
+
+/// Deserialization format for [Machine]. This is identical, except that the
+/// runtime error doesn't include the source code, so it has to be reattached
+/// from the machine's copy. The trace is omitted since it's only populated
+/// mid-step.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct MachineDef {
+    hardware_spec: HardwareSpec,
+    source: Arc<str>,
+    #[serde(default)]
+    includes: Includes,
+    program: Program<Span>,
+    expected_output: Vec<LangValue>,
+    initial_input: Vec<LangValue>,
+    error_policy: ErrorPolicy,
+    program_counter: usize,
+    input: Vec<LangValue>,
+    output: Vec<LangValue>,
+    registers: Vec<LangValue>,
+    stacks: Vec<Vec<LangValue>>,
+    #[serde(default)]
+    max_stack_usage: Option<Vec<usize>>,
+    call_stack: Vec<usize>,
+    cycle_count: usize,
+    #[serde(default)]
+    null_writes: usize,
+    error: Option<WithSource<RuntimeError>>,
+    warnings: Vec<RuntimeWarning>,
+    debug_snapshots: VecDeque<DebugSnapshot>,
+    max_debug_snapshots: usize,
+    #[serde(default)]
+    loop_detector: Option<LoopDetector>,
+    #[serde(default)]
+    profile: Option<Vec<u64>>,
+    #[serde(default)]
+    io_log: Option<Vec<IoEvent>>,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<MachineDef> for Machine {
+    type Error = SpecMismatchError;
+
+    fn try_from(value: MachineDef) -> Result<Self, Self::Error> {
+        check_program_fits(&value.hardware_spec, &value.program)?;
+        let source = value.source;
+        let includes = value.includes;
+        let error = value.error.map(|error| {
+            WithSource::new(error.errors().iter().cloned(), source.clone())
+                .with_includes(includes.clone())
+        });
+        // Older machines didn't track this, so the best we can do is start
+        // from the current lengths
+        let stacks = &value.stacks;
+        let max_stack_usage = value
+            .max_stack_usage
+            .unwrap_or_else(|| stacks.iter().map(Vec::len).collect());
+        // Registers and stacks are indexed directly by ID, so a state with
+        // too few of them would panic later on
+        let hardware_spec = &value.hardware_spec;
+        for (kind, expected, actual) in [
+            (
+                "registers",
+                hardware_spec.num_registers,
+                value.registers.len(),
+            ),
+            ("stacks", hardware_spec.num_stacks, stacks.len()),
+            (
+                "stack usage entries",
+                hardware_spec.num_stacks,
+                max_stack_usage.len(),
+            ),
+        ] {
+            if expected != actual {
+                return Err(SpecMismatchError::InvalidState {
+                    kind,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        Ok(Self {
+            hardware_spec: value.hardware_spec,
+            source,
+            includes,
+            program: value.program,
+            expected_output: value.expected_output,
+            initial_input: value.initial_input,
+            error_policy: value.error_policy,
+            program_counter: value.program_counter,
+            input: value.input,
+            output: value.output,
+            registers: value.registers,
+            stacks: value.stacks,
+            max_stack_usage,
+            call_stack: value.call_stack,
+            cycle_count: value.cycle_count,
+            null_writes: value.null_writes,
+            error,
+            warnings: value.warnings,
+            trace: None,
+            events: EventMask::NONE,
+            debug_snapshots: value.debug_snapshots,
+            max_debug_snapshots: value.max_debug_snapshots,
+            streams: None,
+            extern_handler: None,
+            cancel_token: CancelToken::default(),
+            loop_detector: value.loop_detector,
+            profile: value.profile,
+            io_log: value.io_log,
+        })
+    }
+}
+
+/// Replace every named constant in a program with its value, as given by
+/// `lookup`. Constants that it doesn't know are left as they are, and their
+/// names are returned.
+fn resolve_constants(
+    program: &mut Program<Span>,
+    lookup: impl Fn(&str) -> Option<LangValue>,
+) -> BTreeSet<ConstantName> {
+    let mut undefined = BTreeSet::new();
+    for Node(instruction, _) in &mut program.instructions {
+        for Node(src, span) in instruction.value_sources_mut() {
+            if let ValueSource::Constant(Node(name, _)) = src {
+                match lookup(name) {
+                    Some(value) => {
+                        *src = ValueSource::Const(Node(value, *span))
+                    }
+                    None => {
+                        undefined.insert(name.clone());
+                    }
+                }
+            }
+        }
+    }
+    undefined
+}
+
+/// Make sure that every register and stack that a program references exists
+/// on the given hardware, since running it would panic otherwise. This walks
+/// the instructions themselves rather than trusting the program's
+/// [ProgramStats](crate::ProgramStats), since a deserialized or hand-built
+/// program can have stats that don't match. If there are multiple missing
+/// references, the one with the lowest ID is reported, registers before
+/// stacks.
+pub(crate) fn check_program_fits(
+    hardware_spec: &HardwareSpec,
+    program: &Program<Span>,
+) -> Result<(), SpecMismatchError> {
+    let num_registers = hardware_spec.num_registers;
+    let num_stacks = hardware_spec.num_stacks;
+
+    let invalid_register = program
+        .instructions
+        .iter()
+        .flat_map(|Node(instruction, _)| instruction.register_refs())
+        .filter_map(|reference| match reference {
+            RegisterRef::User(id) if id >= num_registers => Some((
+                (0, id),
+                reference,
+                AvailableRefs::UserRegisters(num_registers),
+            )),
+            RegisterRef::StackLength(id) if id >= num_stacks => Some((
+                (1, id),
+                reference,
+                AvailableRefs::StackLengthRegisters(num_stacks),
+            )),
+            RegisterRef::NullCount if !hardware_spec.null_count_enabled => {
+                Some(((2, 0), reference, AvailableRefs::NullCount))
+            }
+            _ => None,
+        })
+        .min_by_key(|(key, _, _)| *key);
+    if let Some((_, reference, available)) = invalid_register {
+        return Err(SpecMismatchError::InvalidRegisterRef {
+            reference,
+            available,
+        });
+    }
+
+    let invalid_stack = program
+        .instructions
+        .iter()
+        .filter_map(|Node(instruction, _)| instruction.stack_ref())
+        .filter(|StackRef(id)| *id >= num_stacks)
+        .min_by_key(|StackRef(id)| *id);
+    match invalid_stack {
+        Some(reference) => Err(SpecMismatchError::InvalidStackRef {
+            reference,
+            available: AvailableRefs::Stacks(num_stacks),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// A record of the state of all registers and stacks at a certain point in
+/// execution. Generated by the `DUMP` instruction, and accessible via
+/// [Machine::debug_snapshots].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct DebugSnapshot {
+    /// The source span of the `DUMP` instruction that generated this snapshot
+    pub span: Span,
+    /// The machine's cycle count when the snapshot was taken. This includes
+    /// the cycle spent on the `DUMP` itself.
+    pub cycle: usize,
+    /// All registers and their values, including stack lengths. See
+    /// [Machine::registers] for the ordering.
+    pub registers: BTreeMap<RegisterRef, LangValue>,
+    /// All stacks and their contents, ordered by ID
+    pub stacks: BTreeMap<StackRef, Vec<LangValue>>,
+}
+
+/// A single value moving through the input or output buffer. Recorded by
+/// [Machine] while I/O logging is enabled, and accessible via
+/// [Machine::io_log].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct IoEvent {
+    /// The machine's cycle count after the instruction that did the I/O. This
+    /// includes the cycle spent on the instruction itself.
+    pub cycle: usize,
+    /// Whether the value was read or written
+    pub kind: IoEventKind,
+    /// The value that was read or written
+    pub value: LangValue,
+    /// The source span of the `READ` or `WRITE` instruction
+    pub span: Span,
+}
+
+/// The direction of an [IoEvent]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub enum IoEventKind {
+    /// A value was read from the input
+    Read,
+    /// A value was written to the output
+    Write,
+}
+
+/// A record of everything that changed during the execution of a single
+/// instruction. Generated by [Machine::execute_next_traced].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct StepTrace {
+    /// The source span of the executed instruction
+    pub span: Span,
+    /// The program counter before the instruction was executed, i.e. the
+    /// index of the executed instruction
+    pub program_counter_before: usize,
+    /// The program counter after the instruction was executed, i.e. the
+    /// index of the next instruction to be executed
+    pub program_counter_after: usize,
+    /// All registers that were written to, in order of writing
+    pub register_writes: Vec<RegisterWrite>,
+    /// All stack operations that were performed, in order of execution
+    pub stack_ops: Vec<StackOp>,
+    /// The value read from the input buffer, if any
+    pub input_read: Option<LangValue>,
+    /// The value written to the output buffer, if any
+    pub output_written: Option<LangValue>,
+}
+
+impl StepTrace {
+    fn new(span: Span, program_counter: usize) -> Self {
+        Self {
+            span,
+            program_counter_before: program_counter,
+            program_counter_after: program_counter,
+            // These won't allocate until something is added
+            register_writes: Vec::new(),
+            stack_ops: Vec::new(),
+            input_read: None,
+            output_written: None,
+        }
+    }
+}
+
+/// Something notable that an instruction can do, which execution can be
+/// paused on. See [Machine::execute_until_event]. Events can be combined into
+/// an [EventMask] with `|`, e.g. `Event::InputRead | Event::OutputWritten`.
+/// In wasm, each event is a single bit, so they can be combined the same way.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum Event {
+    /// A value was written to the output
+    OutputWritten = 0b0001,
+    /// A value was read from the input. A `READ` on empty input that gets
+    /// recovered from doesn't count.
+    InputRead = 0b0010,
+    /// A value was pushed onto or popped off of a stack
+    StackChanged = 0b0100,
+    /// The instruction set the program counter itself, instead of advancing
+    /// to the next instruction. This includes conditional jumps whose
+    /// condition passed, `CALL`, and `RET`.
+    JumpTaken = 0b1000,
+}
+
+impl Event {
+    /// Every event, in order of precedence. If one instruction triggers
+    /// multiple events, the first one here is reported.
+    pub const ALL: [Self; 4] = [
+        Self::OutputWritten,
+        Self::InputRead,
+        Self::StackChanged,
+        Self::JumpTaken,
+    ];
+}
+
+impl<T: Into<EventMask>> BitOr<T> for Event {
+    type Output = EventMask;
+
+    fn bitor(self, rhs: T) -> EventMask {
+        EventMask::from(self) | rhs
+    }
+}
+
+/// A set of [Event]s. See [Machine::execute_until_event].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventMask(u32);
+
+impl EventMask {
+    /// No events at all
+    pub const NONE: Self = Self(0);
+    /// Every event
+    pub const ALL: Self = Self(0b1111);
+
+    /// Build a mask from the bits of each [Event]. Bits that don't belong to
+    /// any event are ignored.
+    pub fn from_bits_truncate(bits: u32) -> Self {
+        Self(bits & Self::ALL.0)
+    }
+
+    /// Check if the given event is in this mask
+    pub fn contains(self, event: Event) -> bool {
+        self.0 & event as u32 != 0
+    }
+
+    /// Add an event to this mask
+    pub fn insert(&mut self, event: Event) {
+        self.0 |= event as u32;
+    }
+}
+
+impl From<Event> for EventMask {
+    fn from(event: Event) -> Self {
+        Self(event as u32)
+    }
+}
+
+impl<T: Into<EventMask>> BitOr<T> for EventMask {
+    type Output = Self;
+
+    fn bitor(self, rhs: T) -> Self {
+        Self(self.0 | rhs.into().0)
+    }
+}
+
+/// Why [Machine::execute_until_event] stopped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind")]
+pub enum StopReason {
+    /// An instruction triggered one of the requested events. The instruction
+    /// has already executed, and `span` is its source span.
+    Event { event: Event, span: Span },
+    /// The program terminated without triggering any requested event
+    Terminated,
+}
+
+/// Why [Machine::execute_all_interruptible] stopped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InterruptibleStop {
+    /// The program terminated
+    Terminated,
+    /// Execution was stopped by a [CancelToken], before the program
+    /// terminated
+    Cancelled,
+}
+
+/// How a machine's output differs from its expected output. Generated by
+/// [Machine::output_diff].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct OutputDiff {
+    /// The index of the first output value that doesn't match. If one output
+    /// is a prefix of the other, this is the length of the shorter one.
+    pub index: usize,
+    /// The value that was expected at `index`, or `None` if the output has
+    /// extra values there
+    pub expected: Option<LangValue>,
+    /// The value that was output at `index`, or `None` if the output is
+    /// missing values there
+    pub actual: Option<LangValue>,
+    /// The number of expected values that are missing from the end of the
+    /// output
+    pub missing: usize,
+    /// The number of values that were output past the end of the expected
+    /// output
+    pub extra: usize,
+}
+
+impl Display for OutputDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let fmt_value = |value: Option<LangValue>| match value {
+            Some(value) => value.to_string(),
+            None => "nothing".to_owned(),
+        };
+        write!(
+            f,
+            "output differs at position {}: expected {}, got {}",
+            self.index,
+            fmt_value(self.expected),
+            fmt_value(self.actual)
+        )?;
+        match (self.missing, self.extra) {
+            (0, 0) => Ok(()),
+            (1, _) => write!(f, " (1 value missing)"),
+            (missing, 0) => write!(f, " ({} values missing)", missing),
+            (_, 1) => write!(f, " (1 extra value)"),
+            (_, extra) => write!(f, " ({} extra values)", extra),
+        }
+    }
+}
+
+/// A single write to a register, as recorded in a [StepTrace].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RegisterWrite {
+    pub register: RegisterRef,
+    pub old_value: LangValue,
+    pub new_value: LangValue,
+}
+
+/// A single stack operation, as recorded in a [StepTrace].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind")]
+pub enum StackOp {
+    /// A value was pushed onto a stack
+    Push { stack: StackRef, value: LangValue },
+    /// A value was popped off a stack
+    Pop { stack: StackRef, value: LangValue },
+}
+
+/// A copy of all the mutable runtime state of a [Machine]. This can be used to
+/// save the state of a machine at a certain point, and restore it later (e.g.
+/// for stepping backwards in a debugger). The static data of the machine
+/// (program, source, specs) is *not* included, so this is much cheaper than
+/// cloning the entire machine.
+///
+/// Created via [Machine::snapshot], and restored with [Machine::restore].
+#[derive(Clone, Debug)]
+pub struct MachineSnapshot {
+    program_counter: usize,
+    input: Vec<LangValue>,
+    output: Vec<LangValue>,
+    registers: Vec<LangValue>,
+    stacks: Vec<Vec<LangValue>>,
+    max_stack_usage: Vec<usize>,
+    call_stack: Vec<usize>,
+    cycle_count: usize,
+    null_writes: usize,
+    error: Option<WithSource<RuntimeError>>,
+    warnings: Vec<RuntimeWarning>,
+    debug_snapshots: VecDeque<DebugSnapshot>,
+    profile: Option<Vec<u64>>,
+    io_log: Option<Vec<IoEvent>>,
 }
 
 // Functions that DON'T get exported to wasm
 impl Machine {
-    /// Creates a new machine, ready to be executed.
+    /// Creates a new machine, ready to be executed. `error_policy` determines
+    /// which runtime errors will halt execution. The source can be passed as
+    /// an `Arc` to share it between machines without copying.
+    ///
+    /// The test case doesn't have to be validated (see
+    /// [ProgramSpec::validate](crate::ProgramSpec::validate)). If the input is
+    /// too long for `RLI` to hold, it reads as [LangValue::MAX].
+    ///
+    /// The program has to fit the hardware spec, i.e. it can't reference any
+    /// registers or stacks that the hardware doesn't have. A program always
+    /// fits the spec that it was compiled for, so this can only fail if the
+    /// two come from different places. To avoid that entirely, allocate
+    /// through the [Compiler](crate::Compiler) that compiled the program.
+    /// The spec doesn't have to be validated, but it can't have more
+    /// registers or stacks than the top of [NUM_REGISTERS_RANGE] and
+    /// [NUM_STACKS_RANGE].
+    ///
+    /// Named constants in the program (e.g. `$K`) are filled in from the
+    /// test case, and any that it doesn't define are an error. To also use
+    /// the constants of a whole [ProgramSpec](crate::ProgramSpec), see
+    /// [Self::new_with_constants].
     pub fn new(
         hardware_spec: HardwareSpec,
-        program_spec: &ProgramSpec,
+        test_case: &TestCase,
         program: Program<Span>,
-        source: String,
-    ) -> Self {
-        let registers =
-            iter::repeat(0).take(hardware_spec.num_registers).collect();
+        source: impl Into<Arc<str>>,
+        error_policy: ErrorPolicy,
+    ) -> Result<Self, AllocateError> {
+        Self::new_with_constants(
+            hardware_spec,
+            test_case,
+            &BTreeMap::new(),
+            program,
+            source,
+            error_policy,
+        )
+    }
+
+    /// Same as [Self::new], but named constants that the test case doesn't
+    /// define are taken from `constants`, which are usually the
+    /// [ProgramSpec::constants](crate::ProgramSpec::constants). If the program
+    /// uses any constants that neither one defines, they're all listed in
+    /// [AllocateError::UndefinedConstants].
+    pub fn new_with_constants(
+        hardware_spec: HardwareSpec,
+        test_case: &TestCase,
+        constants: &BTreeMap<String, LangValue>,
+        mut program: Program<Span>,
+        source: impl Into<Arc<str>>,
+        error_policy: ErrorPolicy,
+    ) -> Result<Self, AllocateError> {
+        // The spec may not have been validated, and allocating a huge number
+        // of registers or stacks would abort
+        for (kind, range, actual) in &[
+            (
+                "registers",
+                NUM_REGISTERS_RANGE,
+                hardware_spec.num_registers,
+            ),
+            ("stacks", NUM_STACKS_RANGE, hardware_spec.num_stacks),
+        ] {
+            if actual > range.end() {
+                return Err(SpecMismatchError::TooLarge {
+                    kind,
+                    max: *range.end(),
+                    actual: *actual,
+                }
+                .into());
+            }
+        }
+        check_program_fits(&hardware_spec, &program)?;
+        let undefined = resolve_constants(&mut program, |name| {
+            test_case
+                .constants()
+                .get(name)
+                .or_else(|| constants.get(name))
+                .copied()
+        });
+        if !undefined.is_empty() {
+            return Err(AllocateError::UndefinedConstants {
+                names: undefined.into_iter().collect(),
+            });
+        }
+        let registers = hardware_spec
+            .initial_state
+            .registers(hardware_spec.num_registers);
 
         // Initialize `num_stacks` new stacks. Set an initial capacity
-        // for each one to prevent grows during program operation
-        let stacks = iter::repeat_with(|| {
-            Vec::with_capacity(hardware_spec.max_stack_length)
-        })
-        .take(hardware_spec.num_stacks)
-        .collect();
+        // for each one to prevent grows during program operation. The spec
+        // may not have been validated, so don't trust its lengths to be sane.
+        let stacks = hardware_spec
+            .all_stack_refs()
+            .into_iter()
+            .map(|stack_ref| {
+                Vec::with_capacity(
+                    hardware_spec
+                        .stack_capacity(stack_ref)
+                        .min(*MAX_STACK_LENGTH_RANGE.end()),
+                )
+            })
+            .collect();
 
-        Self {
+        let max_stack_usage = vec![0; hardware_spec.num_stacks];
+
+        Ok(Self {
             // Static data
             hardware_spec,
             program,
-            source,
-            expected_output: program_spec.expected_output().into(),
+            source: source.into(),
+            includes: Includes::default(),
+            expected_output: test_case.expected_output().into(),
+            initial_input: test_case.input().into(),
+            error_policy,
 
             // Runtime state
             program_counter: 0,
-            input: program_spec.input().into(),
+            input: test_case.input().into(),
             output: Vec::new(),
             registers,
             stacks,
+            max_stack_usage,
+            call_stack: Vec::new(),
             error: None,
+            warnings: Vec::new(),
+            trace: None,
+            events: EventMask::NONE,
+            debug_snapshots: VecDeque::new(),
+            max_debug_snapshots: DEFAULT_MAX_DEBUG_SNAPSHOTS,
+            streams: None,
+            extern_handler: None,
+            cancel_token: CancelToken::default(),
+            loop_detector: None,
+            profile: None,
+            io_log: None,
 
             // Performance stats
             cycle_count: 0,
+            null_writes: 0,
+        })
+    }
+
+    /// Creates a new machine that reads its input from, and writes its output
+    /// to, the given streams, instead of running against a [TestCase]. This
+    /// allows programs to run interactively. Since there's no expected
+    /// output, the machine is successful as long as it terminates without an
+    /// error. The input and output buffers ([Self::input] and
+    /// [Self::output]) stay empty.
+    ///
+    /// If the input doesn't know how many values it has left (see
+    /// [InputSource::remaining]), `RLI` reads as [UNKNOWN_INPUT_LENGTH]. The
+    /// program has to fit the hardware spec, same as with [Self::new]. There
+    /// are no named constants to fill in, so the program can't use any.
+    pub fn new_with_io(
+        hardware_spec: HardwareSpec,
+        program: Program<Span>,
+        source: impl Into<Arc<str>>,
+        error_policy: ErrorPolicy,
+        input: Box<dyn InputSource>,
+        output: Box<dyn OutputSink>,
+    ) -> Result<Self, AllocateError> {
+        let mut machine = Self::new(
+            hardware_spec,
+            &TestCase::default(),
+            program,
+            source,
+            error_policy,
+        )?;
+        machine.streams = Some(Streams {
+            input: Arc::new(Mutex::new(input)),
+            output: Arc::new(Mutex::new(output)),
+        });
+        Ok(machine)
+    }
+
+    /// Get a token that can stop [Self::execute_all_interruptible] from
+    /// another thread. Clones of this machine share the same token.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel_token.clone()
+    }
+
+    /// Set the host function that executes `EXT` instructions. Without a
+    /// handler, any `EXT` triggers [RuntimeError::NoExternHandler]. The value
+    /// that the handler returns gets written to the instruction's register,
+    /// and an error from the handler triggers [RuntimeError::ExternFailed].
+    /// Clones of this machine share the same handler.
+    ///
+    /// Since the handler can return anything, a machine with a handler is
+    /// never considered to be stuck in an infinite loop (see
+    /// [Self::set_loop_detection]).
+    pub fn set_extern_handler(&mut self, handler: ExternHandler) {
+        self.extern_handler =
+            Some(SharedExternHandler(Arc::new(Mutex::new(handler))));
+    }
+
+    /// Attach the files that the program's spans may point into, so that
+    /// runtime errors in included code render with the right source
+    pub(crate) fn with_includes(mut self, includes: Includes) -> Self {
+        self.includes = includes;
+        self
+    }
+
+    /// Take the next value from the input stream, or the input buffer if
+    /// there is no stream.
+    fn read_input(&mut self) -> Option<LangValue> {
+        let value = match &self.streams {
+            Some(streams) => streams.input.lock().unwrap().read(),
+            None => self.input.read(),
+        };
+        if value.is_some() {
+            self.events.insert(Event::InputRead);
         }
+        value
+    }
+
+    /// Write a value to the output stream, or the output buffer if there is
+    /// no stream.
+    fn write_output(&mut self, value: LangValue) {
+        match &self.streams {
+            Some(streams) => streams.output.lock().unwrap().write(value),
+            None => self.output.write(value),
+        }
+        self.events.insert(Event::OutputWritten);
     }
 
     /// Gets a source value, which could either be a constant or a register.
     /// If the value is a constant, just return that. If it's a register,
-    /// return the value from that register. Panics if the register reference is
-    /// invalid (shouldn't be possible because of validation).
-    fn get_val_from_src(&self, src: &SpanNode<ValueSource<Span>>) -> LangValue {
+    /// return the value from that register, see [Self::get_reg]. Named
+    /// constants were filled in when the machine was created, so any that are
+    /// left came from a machine that was deserialized by hand.
+    fn get_val_from_src(
+        &self,
+        src: &SpanNode<ValueSource<Span>>,
+    ) -> Result<LangValue, (RuntimeError, Span)> {
         match src.value() {
-            ValueSource::Const(Node(val, _)) => *val,
-            ValueSource::Register(reg_ref) => self.get_reg(*reg_ref.value()),
+            ValueSource::Const(Node(val, _)) => Ok(*val),
+            ValueSource::Register(reg_ref) => self.get_reg(reg_ref),
+            ValueSource::Constant(_) => {
+                Err((RuntimeError::UndefinedConstant, *src.metadata()))
+            }
         }
     }
 
-    /// Gets the value from the given register. The register reference is
-    /// assumed to be valid (should be validated at build time). Will panic if
-    /// it isn't valid.
-    fn get_reg(&self, reg: RegisterRef) -> LangValue {
-        match reg {
+    /// Gets the value from the given register. Validation makes sure the
+    /// register exists, but the program may not have been validated against
+    /// this hardware (e.g. if it was deserialized), so an invalid reference
+    /// is an error rather than a panic. Every machine gets checked against
+    /// its program when it's created though (see [check_program_fits]), so
+    /// debug builds assert that the reference is valid.
+    fn get_reg(
+        &self,
+        reg: &SpanNode<RegisterRef>,
+    ) -> Result<LangValue, (RuntimeError, Span)> {
+        let value = self.read_reg(*reg.value());
+        debug_assert!(
+            value.is_some(),
+            "Register {} doesn't exist, but the program was checked against \
+            the hardware",
+            reg.value()
+        );
+        value.ok_or((RuntimeError::InvalidReference, *reg.metadata()))
+    }
+
+    /// Gets the value from the given register, or `None` if the hardware
+    /// doesn't have it
+    fn read_reg(&self, reg: RegisterRef) -> Option<LangValue> {
+        let value = match reg {
             RegisterRef::Null => 0,
-            // These conversion unwraps are safe because we know that input
-            // and stack lengths are bounded by validation rules to fit into an
-            // i32 (max length is 256 at the time of writing this)
-            RegisterRef::InputLength => self.input.len().try_into().unwrap(),
-            RegisterRef::StackLength(stack_id) => {
-                self.stacks[stack_id].len().try_into().unwrap()
+            // Validation keeps input and stack lengths well within an i32,
+            // but specs (and input streams) can skip validation, so saturate
+            // rather than panic
+            RegisterRef::InputLength => {
+                let remaining = match &self.streams {
+                    Some(streams) => streams.input.lock().unwrap().remaining(),
+                    None => self.input.remaining(),
+                };
+                match remaining {
+                    Some(remaining) => {
+                        remaining.try_into().unwrap_or(LangValue::MAX)
+                    }
+                    None => UNKNOWN_INPUT_LENGTH,
+                }
             }
-            RegisterRef::User(reg_id) => *self.registers.get(reg_id).unwrap(),
-        }
+            RegisterRef::InputHead => {
+                let head = match &self.streams {
+                    Some(streams) => streams.input.lock().unwrap().peek(),
+                    None => self.input.peek(),
+                };
+                head.unwrap_or(0)
+            }
+            RegisterRef::NullCount => {
+                if !self.hardware_spec.null_count_enabled {
+                    return None;
+                }
+                self.null_writes.try_into().unwrap_or(LangValue::MAX)
+            }
+            RegisterRef::StackLength(stack_id) => self
+                .stacks
+                .get(stack_id)?
+                .len()
+                .try_into()
+                .unwrap_or(LangValue::MAX),
+            RegisterRef::User(reg_id) => *self.registers.get(reg_id)?,
+        };
+        Some(value)
     }
 
-    /// Sets the register to the given value. The register reference is
-    /// assumed to be valid and writable (should be validated at build time).
-    /// Will panic if it isn't valid/writable.
-    fn set_reg(&mut self, reg: &SpanNode<RegisterRef>, value: LangValue) {
+    /// Sets the register to the given value. If the register can't be
+    /// written (which validation rules out at build time), or the hardware
+    /// doesn't have it, an error is returned, see [Self::get_reg].
+    fn set_reg(
+        &mut self,
+        reg: &SpanNode<RegisterRef>,
+        value: LangValue,
+    ) -> Result<(), (RuntimeError, Span)> {
         match reg.value() {
-            RegisterRef::Null => {} // /dev/null behavior - trash any input
-            RegisterRef::InputLength | RegisterRef::StackLength(_) => {
-                panic!("Unwritable register {:?}", reg)
+            // /dev/null behavior - trash any input, but keep count
+            RegisterRef::Null => self.null_writes += 1,
+            // Validation rejects these as destinations, but a hand-built
+            // program can still have them
+            RegisterRef::InputLength
+            | RegisterRef::InputHead
+            | RegisterRef::NullCount
+            | RegisterRef::StackLength(_) => {
+                return Err((RuntimeError::InvalidReference, *reg.metadata()))
             }
             RegisterRef::User(reg_id) => {
-                self.registers[*reg_id] = value;
+                debug_assert!(
+                    *reg_id < self.hardware_spec.num_registers,
+                    "Register {} doesn't exist, but the program was checked \
+                    against the hardware",
+                    reg.value()
+                );
+                let slot = self
+                    .registers
+                    .get_mut(*reg_id)
+                    .ok_or((RuntimeError::InvalidReference, *reg.metadata()))?;
+                let old_value = *slot;
+                *slot = value;
+                if let Some(trace) = &mut self.trace {
+                    trace.register_writes.push(RegisterWrite {
+                        register: *reg.value(),
+                        old_value,
+                        new_value: value,
+                    });
+                }
             }
         }
+        Ok(())
+    }
+
+    /// Get the index of the given stack. If the hardware doesn't have the
+    /// stack, an error is returned, see [Self::get_reg].
+    fn stack_index(
+        &self,
+        stack_ref: &SpanNode<StackRef>,
+    ) -> Result<usize, (RuntimeError, Span)> {
+        let index = stack_ref.value().0;
+        debug_assert!(
+            index < self.hardware_spec.num_stacks,
+            "Stack {} doesn't exist, but the program was checked against the \
+            hardware",
+            stack_ref.value()
+        );
+        if index < self.hardware_spec.num_stacks {
+            Ok(index)
+        } else {
+            Err((RuntimeError::InvalidReference, *stack_ref.metadata()))
+        }
     }
 
-    /// Pushes the given value onto the given stack. If the stack reference is
-    /// invalid or the stack is at capacity, an error is returned. If the stack
-    /// reference is invalid, will panic (should be validated at build time).
+    /// Pushes the given value onto the given stack. If the stack is at
+    /// capacity, an error is returned. The stack reference has to be valid,
+    /// see [Self::stack_index].
     fn push_stack(
         &mut self,
         stack_ref: &SpanNode<StackRef>,
         value: LangValue,
     ) -> Result<(), (RuntimeError, Span)> {
         // Have to access this first cause borrow checker
-        let max_stack_length = self.hardware_spec.max_stack_length;
-        let stack = &mut self.stacks[stack_ref.value().0];
+        let capacity = self.hardware_spec.stack_capacity(*stack_ref.value());
+        let index = stack_ref.value().0;
+        let stack = &mut self.stacks[index];
 
         // If the stack is capacity, make sure we're not over it
-        if stack.len() >= max_stack_length {
+        if stack.len() >= capacity {
             return Err((RuntimeError::StackOverflow, *stack_ref.metadata()));
         }
 
         stack.push(value);
+        let peak = &mut self.max_stack_usage[index];
+        *peak = (*peak).max(stack.len());
+        self.events.insert(Event::StackChanged);
+        if let Some(trace) = &mut self.trace {
+            trace.stack_ops.push(StackOp::Push {
+                stack: *stack_ref.value(),
+                value,
+            });
+        }
         Ok(())
     }
 
     /// Pops an element off the given stack. If the pop is successful, the
     /// popped value is returned. If the stack is empty, an error is returned.
-    /// If the stack reference is invalid, will panic (should be validated at
-    /// build time).
+    /// The stack reference has to be valid, see [Self::stack_index].
     fn pop_stack(
         &mut self,
         stack_ref: &SpanNode<StackRef>,
@@ -175,16 +1248,192 @@ impl Machine {
         let stack = &mut self.stacks[stack_ref.value().0];
 
         if let Some(val) = stack.pop() {
+            self.events.insert(Event::StackChanged);
+            if let Some(trace) = &mut self.trace {
+                trace.stack_ops.push(StackOp::Pop {
+                    stack: *stack_ref.value(),
+                    value: val,
+                });
+            }
             Ok(val)
         } else {
             Err((RuntimeError::EmptyStack, *stack_ref.metadata()))
         }
     }
 
+    /// Look up the index of the instruction that a label points to. Returns an
+    /// error if the program doesn't have the label.
+    fn label_index(
+        &self,
+        label: &SpanNode<Label>,
+    ) -> Result<usize, (RuntimeError, Span)> {
+        // The compiler never produces an unknown label, but a hand-built
+        // program can
+        self.program
+            .symbol_table
+            .get(label.value())
+            .copied()
+            .ok_or((RuntimeError::InvalidReference, *label.metadata()))
+    }
+
+    /// Handle a runtime error that the machine is able to recover from,
+    /// according to the error policy. Under the strict policy, the error is
+    /// returned so that it halts execution. Under the lenient policy, it's
+    /// recorded as a warning and execution can continue.
+    fn recover(
+        &mut self,
+        error: RuntimeError,
+        span: Span,
+    ) -> Result<(), (RuntimeError, Span)> {
+        match self.error_policy {
+            ErrorPolicy::Strict => Err((error, span)),
+            ErrorPolicy::Lenient => {
+                self.warnings.push(RuntimeWarning { error, span });
+                Ok(())
+            }
+        }
+    }
+
+    /// Apply an arithmetic operation to two values, handling overflow
+    /// according to the hardware spec. `ops` is the checked, wrapping, and
+    /// saturating version of the operation, e.g. [ADD_OPS]. Overflow errors
+    /// are recoverable, in which case the result wraps.
+    fn arithmetic(
+        &mut self,
+        lhs: LangValue,
+        rhs: LangValue,
+        ops: ArithmeticOps,
+        span: Span,
+    ) -> Result<LangValue, (RuntimeError, Span)> {
+        match apply_arithmetic(
+            self.hardware_spec.overflow_behavior,
+            lhs,
+            rhs,
+            ops,
+        ) {
+            Some(val) => Ok(val),
+            None => {
+                self.recover(RuntimeError::ArithmeticOverflow, span)?;
+                let (_, wrapping, _) = ops;
+                Ok(wrapping(lhs, rhs))
+            }
+        }
+    }
+
+    /// Apply a bitwise operation, e.g. [AND_OP], to a register and a value,
+    /// and store the result in the register
+    fn bitwise(
+        &mut self,
+        dst: &SpanNode<RegisterRef>,
+        src: &SpanNode<ValueSource<Span>>,
+        op: BitwiseFn,
+    ) -> Result<(), (RuntimeError, Span)> {
+        let val = op(self.get_reg(dst)?, self.get_val_from_src(src)?);
+        self.set_reg(dst, val)
+    }
+
+    /// Add an entry to the I/O log, if it's enabled
+    fn log_io(&mut self, kind: IoEventKind, value: LangValue, span: Span) {
+        if let Some(io_log) = &mut self.io_log {
+            io_log.push(IoEvent {
+                cycle: self.cycle_count,
+                kind,
+                value,
+                span,
+            });
+        }
+    }
+
+    /// Record the current state of all registers and stacks into the debug
+    /// snapshot buffer. If the buffer is full, the oldest snapshot is dropped.
+    fn dump(&mut self, span: Span) {
+        let snapshot = DebugSnapshot {
+            span,
+            cycle: self.cycle_count,
+            registers: self.registers(),
+            stacks: self
+                .stacks()
+                .into_iter()
+                .map(|(stack_ref, stack)| (stack_ref, stack.to_vec()))
+                .collect(),
+        };
+        self.debug_snapshots.push_back(snapshot);
+        self.truncate_debug_snapshots();
+    }
+
+    /// Drop the oldest debug snapshots until the buffer fits within the
+    /// maximum size.
+    fn truncate_debug_snapshots(&mut self) {
+        while self.debug_snapshots.len() > self.max_debug_snapshots {
+            self.debug_snapshots.pop_front();
+        }
+    }
+
+    /// Get the number of writes to `RZR`, if the program can see it through
+    /// `RZC`. Otherwise, the count doesn't affect execution, so it's left out
+    /// of loop detection and state comparisons.
+    fn visible_null_writes(&self) -> Option<usize> {
+        if self.hardware_spec.null_count_enabled {
+            Some(self.null_writes)
+        } else {
+            None
+        }
+    }
+
+    /// Check if the machine is in the exact state that was saved by the loop
+    /// detector.
+    fn is_in_state(&self, state: &LoopState) -> bool {
+        self.program_counter == state.program_counter
+            && self.input.len() == state.input_length
+            && self.registers == state.registers
+            && self.stacks == state.stacks
+            && self.call_stack == state.call_stack
+            && self.visible_null_writes() == state.null_writes
+    }
+
+    /// Check if the machine has returned to a state that it was in before,
+    /// which means it will never terminate. This should be called once before
+    /// each instruction. Always returns false if loop detection is disabled.
+    fn detect_loop(&mut self) -> bool {
+        // Streamed input and extern ops can be different every time, so
+        // repeating a state doesn't mean repeating what comes after it
+        if self.streams.is_some() || self.extern_handler.is_some() {
+            return false;
+        }
+        let mut detector = match self.loop_detector.take() {
+            Some(detector) => detector,
+            None => return false,
+        };
+
+        let looping = matches!(
+            &detector.saved,
+            Some(saved) if self.is_in_state(saved)
+        );
+        if !looping {
+            detector.steps += 1;
+            if detector.steps >= detector.interval {
+                detector.saved = Some(LoopState {
+                    program_counter: self.program_counter,
+                    input_length: self.input.len(),
+                    registers: self.registers.clone(),
+                    stacks: self.stacks.clone(),
+                    call_stack: self.call_stack.clone(),
+                    null_writes: self.visible_null_writes(),
+                });
+                detector.steps = 0;
+                detector.interval *= 2;
+            }
+        }
+        self.loop_detector = Some(detector);
+        looping
+    }
+
     /// Internal function to execute the next instruction. The return value
     /// is the same as [Self::execute_next], except the error needs to be
     /// wrapped before being handed to the user.
     fn execute_next_inner(&mut self) -> Result<bool, (RuntimeError, Span)> {
+        self.events = EventMask::NONE;
+
         // We've previously hit an error, prevent further execution
         if self.error.is_some() {
             return Ok(false);
@@ -198,8 +1447,15 @@ impl Machine {
                 None => return Ok(false),
             };
 
-        // Prevent infinite loops
-        if self.cycle_count >= MAX_CYCLE_COUNT {
+        // Prevent infinite loops, either by catching them directly or by
+        // capping the cycle count
+        if self.detect_loop() {
+            return Err((RuntimeError::InfiniteLoop, *instr_node.metadata()));
+        }
+        let cost = self.cost_of(instr_node.value().kind());
+        // Costs come from the spec, which may not have been validated, so
+        // this can't add them up first
+        if cost > MAX_CYCLE_COUNT.saturating_sub(self.cycle_count) {
             // Include the instruction that triggered the error
             return Err((RuntimeError::TooManyCycles, *instr_node.metadata()));
         }
@@ -207,136 +1463,324 @@ impl Machine {
         // If we've reached this point, we know we're going to execute the
         // instruction. Increment the cycle count now so that if we exit with
         // an error, it still counts.
-        self.cycle_count += 1;
+        self.cycle_count += cost;
+        if let Some(profile) = &mut self.profile {
+            profile[self.program_counter] += 1;
+        }
 
-        // Execute the instruction, and get a resulting optional label that we
-        // should jump to. For most instructions there will be no label, only
-        // when the instruction wants to trigger a jump.
+        // Execute the instruction, and get a resulting optional instruction
+        // index that we should jump to. For most instructions there will be no
+        // destination, only when the instruction wants to trigger a jump.
         let instruction = instr_node.value();
         let span = *instr_node.metadata();
-        let target_label: Option<&Label> = match instruction {
+        let destination: Option<usize> = match instruction {
             Instruction::Read(reg) => {
-                if self.input.is_empty() {
-                    return Err((RuntimeError::EmptyInput, span));
-                } else {
-                    // Remove the first element in the input
-                    let val = self.input.remove(0);
-                    self.set_reg(reg, val);
+                match self.read_input() {
+                    Some(val) => {
+                        if let Some(trace) = &mut self.trace {
+                            trace.input_read = Some(val);
+                        }
+                        self.log_io(IoEventKind::Read, val, span);
+                        self.set_reg(reg, val)?;
+                    }
+                    None => {
+                        self.recover(RuntimeError::EmptyInput, span)?;
+                        self.set_reg(reg, 0)?;
+                    }
+                }
+                None
+            }
+            Instruction::Rdz(reg) => {
+                // Same as READ, except empty input isn't an error
+                let val = self.read_input();
+                if let Some(val) = val {
+                    if let Some(trace) = &mut self.trace {
+                        trace.input_read = Some(val);
+                    }
+                    self.log_io(IoEventKind::Read, val, span);
                 }
+                self.set_reg(reg, val.unwrap_or(0))?;
                 None
             }
             Instruction::Write(src) => {
-                self.output.push(self.get_val_from_src(src));
+                let val = self.get_val_from_src(src)?;
+                self.write_output(val);
+                if let Some(trace) = &mut self.trace {
+                    trace.output_written = Some(val);
+                }
+                self.log_io(IoEventKind::Write, val, span);
                 None
             }
             Instruction::Set(dst, src) => {
-                self.set_reg(dst, self.get_val_from_src(src));
+                let val = self.get_val_from_src(src)?;
+                self.set_reg(dst, val)?;
+                None
+            }
+            Instruction::Swp(reg_1, reg_2) => {
+                let val_1 = self.get_reg(reg_1)?;
+                let val_2 = self.get_reg(reg_2)?;
+                self.set_reg(reg_1, val_2)?;
+                self.set_reg(reg_2, val_1)?;
                 None
             }
             Instruction::Add(dst, src) => {
-                self.set_reg(
-                    dst,
-                    (Wrapping(self.get_reg(*dst.value()))
-                        + Wrapping(self.get_val_from_src(src)))
-                    .0,
-                );
+                let val = self.arithmetic(
+                    self.get_reg(dst)?,
+                    self.get_val_from_src(src)?,
+                    ADD_OPS,
+                    span,
+                )?;
+                self.set_reg(dst, val)?;
                 None
             }
             Instruction::Sub(dst, src) => {
-                self.set_reg(
-                    dst,
-                    (Wrapping(self.get_reg(*dst.value()))
-                        - Wrapping(self.get_val_from_src(src)))
-                    .0,
-                );
+                let val = self.arithmetic(
+                    self.get_reg(dst)?,
+                    self.get_val_from_src(src)?,
+                    SUB_OPS,
+                    span,
+                )?;
+                self.set_reg(dst, val)?;
                 None
             }
             Instruction::Mul(dst, src) => {
-                self.set_reg(
-                    dst,
-                    (Wrapping(self.get_reg(*dst.value()))
-                        * Wrapping(self.get_val_from_src(src)))
-                    .0,
-                );
+                let val = self.arithmetic(
+                    self.get_reg(dst)?,
+                    self.get_val_from_src(src)?,
+                    MUL_OPS,
+                    span,
+                )?;
+                self.set_reg(dst, val)?;
                 None
             }
             Instruction::Div(dst, src) => {
-                let divisor = self.get_val_from_src(src);
-                let dividend = self.get_reg(*dst.value());
+                let divisor = self.get_val_from_src(src)?;
+                let dividend = self.get_reg(dst)?;
                 if divisor != 0 {
-                    // This does flooring division
-                    self.set_reg(dst, dividend / divisor);
+                    let val =
+                        self.arithmetic(dividend, divisor, DIV_OPS, span)?;
+                    self.set_reg(dst, val)?;
                 } else {
-                    return Err((RuntimeError::DivideByZero, span));
+                    self.recover(RuntimeError::DivideByZero, span)?;
+                    self.set_reg(dst, 0)?;
                 }
                 None
             }
+            Instruction::And(dst, src) => {
+                self.bitwise(dst, src, AND_OP)?;
+                None
+            }
+            Instruction::Or(dst, src) => {
+                self.bitwise(dst, src, OR_OP)?;
+                None
+            }
+            Instruction::Xor(dst, src) => {
+                self.bitwise(dst, src, XOR_OP)?;
+                None
+            }
+            Instruction::Shl(dst, src) => {
+                self.bitwise(dst, src, SHL_OP)?;
+                None
+            }
+            Instruction::Shr(dst, src) => {
+                self.bitwise(dst, src, SHR_OP)?;
+                None
+            }
             Instruction::Cmp(dst, src_1, src_2) => {
-                let val_1 = self.get_val_from_src(src_1);
-                let val_2 = self.get_val_from_src(src_2);
-                let cmp = match val_1.cmp(&val_2) {
-                    Ordering::Less => -1,
-                    Ordering::Equal => 0,
-                    Ordering::Greater => 1,
-                };
-                self.set_reg(dst, cmp);
+                let val_1 = self.get_val_from_src(src_1)?;
+                let val_2 = self.get_val_from_src(src_2)?;
+                self.set_reg(dst, compare(val_1, val_2))?;
                 None
             }
             Instruction::Push(src, stack_ref) => {
-                self.push_stack(stack_ref, self.get_val_from_src(src))?;
+                let val = self.get_val_from_src(src)?;
+                self.stack_index(stack_ref)?;
+                if let Err((error, span)) = self.push_stack(stack_ref, val) {
+                    // If we recover, the value just gets thrown away
+                    self.recover(error, span)?;
+                }
                 None
             }
             Instruction::Pop(stack_ref, dst) => {
-                let popped = self.pop_stack(stack_ref)?;
-                self.set_reg(dst, popped);
+                self.stack_index(stack_ref)?;
+                let popped = match self.pop_stack(stack_ref) {
+                    Ok(popped) => popped,
+                    Err((error, span)) => {
+                        self.recover(error, span)?;
+                        0
+                    }
+                };
+                self.set_reg(dst, popped)?;
+                None
+            }
+            Instruction::Peek(stack_ref, dst) => {
+                let index = self.stack_index(stack_ref)?;
+                let peeked = match self.stacks[index].last().copied() {
+                    Some(peeked) => peeked,
+                    None => {
+                        self.recover(
+                            RuntimeError::EmptyStack,
+                            *stack_ref.metadata(),
+                        )?;
+                        0
+                    }
+                };
+                self.set_reg(dst, peeked)?;
+                None
+            }
+            Instruction::Popn(stack_ref, src) => {
+                // Negative counts pop nothing
+                let count = self.get_val_from_src(src)?.max(0) as usize;
+                let index = self.stack_index(stack_ref)?;
+                // Either every value gets popped, or none of them do
+                if count > self.stacks[index].len() {
+                    self.recover(
+                        RuntimeError::EmptyStack,
+                        *stack_ref.metadata(),
+                    )?;
+                } else {
+                    for _ in 0..count {
+                        self.pop_stack(stack_ref)?;
+                    }
+                }
+                None
+            }
+            Instruction::Dup(stack_ref) => {
+                let index = self.stack_index(stack_ref)?;
+                match self.stacks[index].last().copied() {
+                    Some(top) => {
+                        if let Err((error, span)) =
+                            self.push_stack(stack_ref, top)
+                        {
+                            // If we recover, the copy just gets thrown away
+                            self.recover(error, span)?;
+                        }
+                    }
+                    None => self.recover(
+                        RuntimeError::EmptyStack,
+                        *stack_ref.metadata(),
+                    )?,
+                }
+                None
+            }
+            Instruction::Sswp(stack_ref) => {
+                let index = self.stack_index(stack_ref)?;
+                if self.stacks[index].len() < 2 {
+                    self.recover(
+                        RuntimeError::EmptyStack,
+                        *stack_ref.metadata(),
+                    )?;
+                } else {
+                    // Going through pop/push keeps the trace accurate. The
+                    // stack never grows, so the pushes can't overflow.
+                    let top = self.pop_stack(stack_ref)?;
+                    let second = self.pop_stack(stack_ref)?;
+                    self.push_stack(stack_ref, top)?;
+                    self.push_stack(stack_ref, second)?;
+                }
                 None
             }
 
             // Jumps
-            Instruction::Jmp(Node(label, _)) => Some(label),
-            Instruction::Jez(src, Node(label, _)) => {
-                if self.get_val_from_src(src) == 0 {
-                    Some(label)
+            Instruction::Jmp(label) => Some(self.label_index(label)?),
+            Instruction::Jez(src, label) => {
+                if self.get_val_from_src(src)? == 0 {
+                    Some(self.label_index(label)?)
                 } else {
                     None
                 }
             }
-            Instruction::Jnz(src, Node(label, _)) => {
-                if self.get_val_from_src(src) != 0 {
-                    Some(label)
+            Instruction::Jnz(src, label) => {
+                if self.get_val_from_src(src)? != 0 {
+                    Some(self.label_index(label)?)
                 } else {
                     None
                 }
             }
-            Instruction::Jlz(src, Node(label, _)) => {
-                if self.get_val_from_src(src) < 0 {
-                    Some(label)
+            Instruction::Jlz(src, label) => {
+                if self.get_val_from_src(src)? < 0 {
+                    Some(self.label_index(label)?)
                 } else {
                     None
                 }
             }
-            Instruction::Jgz(src, Node(label, _)) => {
-                if self.get_val_from_src(src) > 0 {
-                    Some(label)
+            Instruction::Jgz(src, label) => {
+                if self.get_val_from_src(src)? > 0 {
+                    Some(self.label_index(label)?)
                 } else {
                     None
                 }
             }
+            Instruction::Jri(src) => {
+                let target = self.get_reg(src)?;
+                // Landing right after the last instruction just terminates,
+                // same as a label at the end of the program
+                match usize::try_from(target) {
+                    Ok(index) if index <= self.program.instructions.len() => {
+                        Some(index)
+                    }
+                    _ => {
+                        return Err((
+                            RuntimeError::InvalidJumpTarget { target },
+                            span,
+                        ))
+                    }
+                }
+            }
+
+            // Subroutines
+            Instruction::Call(label) => {
+                if self.call_stack.len() >= self.hardware_spec.max_call_depth {
+                    return Err((RuntimeError::CallStackOverflow, span));
+                }
+                // Only push the return address once we know the jump is valid
+                let target = self.label_index(label)?;
+                self.call_stack.push(self.program_counter + 1);
+                Some(target)
+            }
+            Instruction::Ret => {
+                let return_address = self
+                    .call_stack
+                    .pop()
+                    .ok_or((RuntimeError::ReturnWithoutCall, span))?;
+                Some(return_address)
+            }
+
+            Instruction::Nop => None,
+
+            // Debugging
+            Instruction::Dump => {
+                self.dump(span);
+                None
+            }
+
+            // Host integration
+            Instruction::Ext(id, reg) => {
+                let handler = match &self.extern_handler {
+                    Some(handler) => handler.clone(),
+                    None => return Err((RuntimeError::NoExternHandler, span)),
+                };
+                let value = self.get_reg(reg)?;
+                let result = (handler.0.lock().unwrap())(*id.value(), value);
+                match result {
+                    Ok(value) => self.set_reg(reg, value)?,
+                    Err(message) => {
+                        return Err((
+                            RuntimeError::ExternFailed { message },
+                            span,
+                        ))
+                    }
+                }
+                None
+            }
         };
 
-        // If the instruction wants to jump to a label, look up its
-        // corresponding index and go there. Otherwise, just advance the PC one
-        // instruction
-        match target_label {
-            Some(label) => {
-                let destination = self
-                    .program
-                    .symbol_table
-                    .get(label)
-                    // If this panics, that means there's a bug in the
-                    // compiler pipeline
-                    .unwrap_or_else(|| panic!("unknown label: {}", label));
-                self.program_counter = *destination;
+        // If the instruction wants to jump, go there. Otherwise, just advance
+        // the PC one instruction
+        match destination {
+            Some(destination) => {
+                self.program_counter = destination;
+                self.events.insert(Event::JumpTaken);
             }
             None => {
                 self.program_counter += 1;
@@ -355,18 +1799,70 @@ impl Machine {
     /// - `Err(error)` if an error occurred. The error is returned, with the
     ///   source information of the offending instruction
     pub fn execute_next(&mut self) -> Result<bool, &WithSource<RuntimeError>> {
-        match self.execute_next_inner() {
+        let result = self.execute_next_inner();
+        self.wrap_result(result)
+    }
+
+    /// Executes the next instruction in the program, and records everything
+    /// that changed during execution. This is identical to
+    /// [Self::execute_next], except that the return value includes a
+    /// [StepTrace] describing the executed instruction.
+    ///
+    /// # Returns
+    /// - `Ok(Some(trace))` if the instruction executed normally
+    /// - `Ok(None)` if the instruction didn't execute because the program has
+    ///   already terminated
+    /// - `Err(error)` if an error occurred. The error is returned, with the
+    ///   source information of the offending instruction
+    pub fn execute_next_traced(
+        &mut self,
+    ) -> Result<Option<StepTrace>, &WithSource<RuntimeError>> {
+        self.trace = self.program.instructions.get(self.program_counter).map(
+            |instr_node| {
+                StepTrace::new(*instr_node.metadata(), self.program_counter)
+            },
+        );
+        let result = self.execute_next_inner();
+        // Grab the trace *before* wrapping the result, to keep the borrow
+        // checker happy
+        let trace = self.trace.take().map(|mut trace| {
+            trace.program_counter_after = self.program_counter;
+            trace
+        });
+
+        match self.wrap_result(result) {
+            Ok(true) => Ok(trace),
+            Ok(false) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Convert the result of [Self::execute_next_inner] into the format
+    /// returned to the user. If an error occurred, it will be stored in self
+    /// and a reference to it will be returned.
+    fn wrap_result(
+        &mut self,
+        result: Result<bool, (RuntimeError, Span)>,
+    ) -> Result<bool, &WithSource<RuntimeError>> {
+        match result {
             Ok(b) => Ok(b),
             Err((error, span)) => {
                 // Store the error in self, then return a ref to it
-                self.error = Some(WithSource::new(
-                    iter::once(SourceErrorWrapper::new(
-                        error,
-                        span,
-                        &self.source,
-                    )),
-                    self.source.clone(),
-                ));
+                self.error = Some(
+                    WithSource::new(
+                        iter::once(
+                            self.includes
+                                .wrap_error(error, span, &self.source)
+                                .with_contributing_spans(
+                                    self.program.contributing_spans(
+                                        self.program_counter,
+                                    ),
+                                ),
+                        ),
+                        self.source.clone(),
+                    )
+                    .with_includes(self.includes.clone()),
+                );
                 Err(self.error.as_ref().unwrap())
             }
         }
@@ -391,6 +1887,225 @@ impl Machine {
         }
     }
 
+    /// Executes this machine until termination (or error), just like
+    /// [Self::execute_all], but stops early if the machine's [CancelToken] is
+    /// cancelled (see [Self::cancel_token]). The token is checked every so
+    /// often rather than before every instruction, so a few more instructions
+    /// may run after cancelling. A cancelled machine is left as it was when it
+    /// stopped, and can be resumed by executing it again. Cancelling clears
+    /// the token once the machine stops for it.
+    ///
+    /// # Returns
+    /// - `Ok(InterruptibleStop::Terminated)` if the program terminated
+    /// - `Ok(InterruptibleStop::Cancelled)` if execution was cancelled
+    /// - `Err(error)` if an error occurred. The error is returned, with the
+    ///   source information of the offending instruction
+    pub fn execute_all_interruptible(
+        &mut self,
+    ) -> Result<InterruptibleStop, &WithSource<RuntimeError>> {
+        let mut stop_reason = InterruptibleStop::Terminated;
+        let mut steps = 0;
+        while !self.terminated() {
+            if steps % CANCEL_CHECK_INTERVAL == 0 && self.cancel_token.take() {
+                stop_reason = InterruptibleStop::Cancelled;
+                break;
+            }
+            steps += 1;
+            // Same lifetime tetris as execute_all
+            if self.execute_next().is_err() {
+                break;
+            }
+        }
+
+        match &self.error {
+            None => Ok(stop_reason),
+            Some(error) => Err(error),
+        }
+    }
+
+    /// Executes this machine until termination (or error), just like
+    /// [Self::execute_all], and writes a text trace of the execution to the
+    /// given writer. The format is stable, so traces can be saved and diffed
+    /// to catch changes in the interpreter. There is one line per executed
+    /// instruction:
+    ///
+    /// ```text
+    /// <cycle count> <instruction index> <instruction> | <registers>
+    /// ```
+    ///
+    /// - The cycle count is the total after the instruction executed
+    /// - The instruction is in canonical source form (e.g. `ADD RX0 1`), with
+    ///   labels as written in the source
+    /// - The registers are the user registers (`RX0=1`), then the stack length
+    ///   registers (`RS0=2`), after the instruction executed, separated by
+    ///   spaces. Aliases are never used.
+    ///
+    /// After execution ends, one last line gives the outcome:
+    /// - `END success`
+    /// - `END failure remaining_input` or `END failure incorrect_output`
+    /// - `END error <code> at <instruction index>`, where the code is the
+    ///   runtime error's [error code](crate::error::SourceError::error_code).
+    ///   The failing instruction doesn't get its own line.
+    ///
+    /// The outer result holds any error from the writer, in which case the
+    /// machine may be left partway through execution. Otherwise, the inner
+    /// result is the same as [Self::execute_all].
+    pub fn execute_all_traced(
+        &mut self,
+        writer: &mut impl Write,
+    ) -> io::Result<Result<bool, &WithSource<RuntimeError>>> {
+        while !self.terminated() {
+            let program_counter = self.program_counter;
+            if self.execute_next().is_err() {
+                break;
+            }
+            self.write_trace_line(writer, program_counter)?;
+        }
+
+        match &self.error {
+            None => {
+                match self.failure_reason() {
+                    None => writeln!(writer, "END success")?,
+                    Some(FailureReason::RemainingInput) => {
+                        writeln!(writer, "END failure remaining_input")?
+                    }
+                    Some(FailureReason::IncorrectOutput) => {
+                        writeln!(writer, "END failure incorrect_output")?
+                    }
+                    // Can't happen without an error
+                    Some(FailureReason::RuntimeError) => unreachable!(),
+                }
+                Ok(Ok(self.successful()))
+            }
+            Some(error) => {
+                writeln!(
+                    writer,
+                    "END error {} at {}",
+                    error.errors()[0].error_code(),
+                    self.program_counter
+                )?;
+                Ok(Err(error))
+            }
+        }
+    }
+
+    /// Write one line of the trace generated by [Self::execute_all_traced],
+    /// for the instruction that was just executed
+    fn write_trace_line(
+        &self,
+        writer: &mut impl Write,
+        program_counter: usize,
+    ) -> io::Result<()> {
+        write!(
+            writer,
+            "{} {} {} |",
+            self.cycle_count,
+            program_counter,
+            self.program.instructions[program_counter].value()
+        )?;
+        for (id, value) in self.registers.iter().enumerate() {
+            write!(writer, " {}={}", RegisterRef::User(id), value)?;
+        }
+        for (id, stack) in self.stacks.iter().enumerate() {
+            write!(
+                writer,
+                " {}={}",
+                RegisterRef::StackLength(id),
+                stack.len()
+            )?;
+        }
+        writeln!(writer)
+    }
+
+    /// Executes this machine until it reaches a breakpoint, terminates, or
+    /// hits an error. Breakpoints are instruction indices. The machine stops
+    /// *before* executing a breakpointed instruction, so
+    /// [Self::program_counter] points at it. The first instruction always
+    /// executes, even if it has a breakpoint, so that calling this again
+    /// resumes from a breakpoint rather than getting stuck on it.
+    ///
+    /// # Returns
+    /// - `Ok(true)` if execution stopped at a breakpoint
+    /// - `Ok(false)` if the program terminated
+    /// - `Err(error)` if an error occurred. The error is returned, with the
+    ///   source information of the offending instruction
+    pub fn execute_until(
+        &mut self,
+        breakpoints: &[usize],
+    ) -> Result<bool, &WithSource<RuntimeError>> {
+        let mut first = true;
+        while !self.terminated() {
+            if !first && breakpoints.contains(&self.program_counter) {
+                return Ok(true);
+            }
+            first = false;
+            // Same lifetime tetris as execute_all
+            if self.execute_next().is_err() {
+                break;
+            }
+        }
+
+        match &self.error {
+            None => Ok(false),
+            Some(error) => Err(error),
+        }
+    }
+
+    /// Same as [Self::execute_until], but breakpoints are source line numbers
+    /// (starting at 1) instead of instruction indices. A line breaks on the
+    /// instruction that starts on it, so lines without an instruction (blank
+    /// lines, comments, labels) never trigger. See
+    /// [Program::instructions_on_line].
+    pub fn execute_until_line(
+        &mut self,
+        lines: &[usize],
+    ) -> Result<bool, &WithSource<RuntimeError>> {
+        let breakpoints: Vec<usize> = lines
+            .iter()
+            .flat_map(|line| self.program.instructions_on_line(*line))
+            .collect();
+        self.execute_until(&breakpoints)
+    }
+
+    /// Executes this machine until an instruction triggers one of the given
+    /// events, the program terminates, or an error occurs. Events are
+    /// recorded as each instruction executes, so an instruction that e.g.
+    /// reads input into a register is caught the same as any other. If one
+    /// instruction triggers more than one of the events, the first in
+    /// [Event::ALL] is reported. An empty mask runs until termination.
+    ///
+    /// # Returns
+    /// - `Ok(StopReason::Event {..})` if execution stopped on an event
+    /// - `Ok(StopReason::Terminated)` if the program terminated
+    /// - `Err(error)` if an error occurred. The error is returned, with the
+    ///   source information of the offending instruction
+    pub fn execute_until_event(
+        &mut self,
+        events: EventMask,
+    ) -> Result<StopReason, &WithSource<RuntimeError>> {
+        let mut stop_reason = StopReason::Terminated;
+        while !self.terminated() {
+            let span =
+                *self.program.instructions[self.program_counter].metadata();
+            // Same lifetime tetris as execute_all
+            if self.execute_next().is_err() {
+                break;
+            }
+            let triggered = self.events;
+            if let Some(event) = Event::ALL.iter().copied().find(|event| {
+                events.contains(*event) && triggered.contains(*event)
+            }) {
+                stop_reason = StopReason::Event { event, span };
+                break;
+            }
+        }
+
+        match &self.error {
+            None => Ok(stop_reason),
+            Some(error) => Err(error),
+        }
+    }
+
     /// Get the source code that this machine is built for.
     pub fn source_code(&self) -> &str {
         &self.source
@@ -401,27 +2116,93 @@ impl Machine {
         &self.program
     }
 
-    /// Get the current input buffer.
+    /// Get the files that were included into the source code. Use this to
+    /// find the code that a span points into, via its source ID.
+    pub fn includes(&self) -> &Includes {
+        &self.includes
+    }
+
+    /// Get the hardware that this machine is running on.
+    pub fn hardware_spec(&self) -> &HardwareSpec {
+        &self.hardware_spec
+    }
+
+    /// Get the seed that the registers were randomized with, or `None` if
+    /// they started at zero. See [HardwareSpec::initial_state].
+    pub fn seed(&self) -> Option<u64> {
+        match self.hardware_spec.initial_state {
+            InitialState::Zeroed => None,
+            InitialState::Random { seed } => Some(seed),
+        }
+    }
+
+    /// Get the number of cycles that a kind of instruction costs on this
+    /// machine, according to the hardware's [CostModel](crate::CostModel).
+    pub fn cost_of(&self, kind: InstructionKind) -> usize {
+        self.hardware_spec.cost_model.cost_of(kind)
+    }
+
+    /// Get the current input buffer. This is always empty if the machine
+    /// reads from a stream (see [Self::new_with_io]).
     pub fn input(&self) -> &[LangValue] {
         self.input.as_slice()
     }
 
-    /// Get the current output buffer.
+    /// Get the current output buffer. This is always empty if the machine
+    /// writes to a stream (see [Self::new_with_io]).
     pub fn output(&self) -> &[LangValue] {
         self.output.as_slice()
     }
 
-    /// Get all registers and their current values.
-    pub fn registers(&self) -> HashMap<RegisterRef, LangValue> {
+    /// Get the output that the program is expected to produce, as defined by
+    /// the test case. This is always empty if the machine writes to a stream
+    /// (see [Self::new_with_io]).
+    pub fn expected_output(&self) -> &[LangValue] {
+        self.expected_output.as_slice()
+    }
+
+    /// Compare the output to the expected output, to see where they differ.
+    /// **Only returns a value if the program failed because of its output**,
+    /// i.e. the failure reason is [FailureReason::IncorrectOutput].
+    pub fn output_diff(&self) -> Option<OutputDiff> {
+        if !matches!(
+            self.failure_reason(),
+            Some(FailureReason::IncorrectOutput)
+        ) {
+            return None;
+        }
+
+        let expected = &self.expected_output;
+        let actual = &self.output;
+        // The outputs are known to differ, so if every shared value matches,
+        // then one is longer than the other
+        let index = expected
+            .iter()
+            .zip(actual)
+            .position(|(expected, actual)| expected != actual)
+            .unwrap_or_else(|| expected.len().min(actual.len()));
+        Some(OutputDiff {
+            index,
+            expected: expected.get(index).copied(),
+            actual: actual.get(index).copied(),
+            missing: expected.len().saturating_sub(actual.len()),
+            extra: actual.len().saturating_sub(expected.len()),
+        })
+    }
+
+    /// Get all registers and their current values. The map is ordered the
+    /// same way as [RegisterRef]: the special registers, then stack lengths,
+    /// then user registers, each by ID.
+    pub fn registers(&self) -> BTreeMap<RegisterRef, LangValue> {
         self.hardware_spec
             .all_register_refs()
             .into_iter()
-            .map(|reg_ref| (reg_ref, self.get_reg(reg_ref)))
+            .filter_map(|reg_ref| Some((reg_ref, self.read_reg(reg_ref)?)))
             .collect()
     }
 
-    /// Get all stacks and their current values.
-    pub fn stacks(&self) -> HashMap<StackRef, &[LangValue]> {
+    /// Get all stacks and their current values, ordered by ID.
+    pub fn stacks(&self) -> BTreeMap<StackRef, &[LangValue]> {
         self.hardware_spec
             .all_stack_refs()
             .into_iter()
@@ -429,41 +2210,326 @@ impl Machine {
             .collect()
     }
 
+    /// Get the most values that each stack has held at once so far. This
+    /// shows how much of each stack a program actually needs. Ordered by
+    /// stack ID.
+    pub fn max_stack_usage(&self) -> BTreeMap<StackRef, usize> {
+        self.hardware_spec
+            .all_stack_refs()
+            .into_iter()
+            .map(|stack_ref| (stack_ref, self.max_stack_usage[stack_ref.0]))
+            .collect()
+    }
+
+    /// Get a hash of the observable state of this machine. Two machines with
+    /// equal state always have equal fingerprints, even if they were compiled
+    /// from different source. The hash is stable across platforms and
+    /// builds. These fields are included:
+    /// - Program counter
+    /// - User register values (the rest are derived from other state)
+    /// - Stack contents
+    /// - Remaining input
+    /// - Output
+    /// - The number of writes to `RZR`, but only if the hardware has `RZC`,
+    ///   since programs can't see it otherwise
+    /// - Whether a runtime error has occurred (but not which one, since errors
+    ///   carry source spans)
+    ///
+    /// Everything else is ignored, including the hardware spec, the program
+    /// and its source, the cycle count, the call stack, recovered warnings,
+    /// and debug snapshots. See [Self::state_eq] to compare the same fields
+    /// directly.
+    pub fn state_fingerprint(&self) -> u64 {
+        let mut hasher = Fnv1a::new();
+        hasher.write_usize(self.program_counter);
+        hasher.write_values(&self.registers);
+        hasher.write_usize(self.stacks.len());
+        for stack in &self.stacks {
+            hasher.write_values(stack);
+        }
+        hasher.write_values(&self.input);
+        hasher.write_values(&self.output);
+        if let Some(null_writes) = self.visible_null_writes() {
+            hasher.write_usize(null_writes);
+        }
+        hasher.write(&[self.error.is_some() as u8]);
+        hasher.0
+    }
+
+    /// Check if two machines have the same observable state. This compares
+    /// exactly the fields that go into [Self::state_fingerprint], so it's
+    /// equivalent to comparing fingerprints, minus the chance of a hash
+    /// collision.
+    pub fn state_eq(&self, other: &Machine) -> bool {
+        self.program_counter == other.program_counter
+            && self.registers == other.registers
+            && self.stacks == other.stacks
+            && self.input == other.input
+            && self.output == other.output
+            && self.visible_null_writes() == other.visible_null_writes()
+            && self.error.is_some() == other.error.is_some()
+    }
+
     /// Get the runtime error that halted execution of this machine. If no error
     /// has occurred, return `None`.
     pub fn error(&self) -> Option<&WithSource<RuntimeError>> {
         self.error.as_ref()
     }
+
+    /// Get all runtime errors that were recovered from so far, in the order
+    /// they occurred. These are only recorded under [ErrorPolicy::Lenient];
+    /// under the strict policy, this is always empty.
+    pub fn warnings(&self) -> &[RuntimeWarning] {
+        &self.warnings
+    }
+
+    /// Get all snapshots generated by `DUMP` instructions so far, oldest
+    /// first. Only the most recent snapshots are kept, up to the limit set by
+    /// [Self::set_max_debug_snapshots].
+    pub fn debug_snapshots(&self) -> &VecDeque<DebugSnapshot> {
+        &self.debug_snapshots
+    }
+
+    /// Get the number of times each instruction has executed, along with the
+    /// instruction's source span, in program order. Returns `None` if
+    /// profiling is disabled (see [Self::set_profiling]). Under the default
+    /// cost model, the counts add up to the cycle count, as long as profiling
+    /// was enabled from the start.
+    pub fn profile(&self) -> Option<Vec<(Span, u64)>> {
+        let profile = self.profile.as_ref()?;
+        Some(
+            self.program
+                .instructions
+                .iter()
+                .zip(profile)
+                .map(|(instr_node, count)| (*instr_node.metadata(), *count))
+                .collect(),
+        )
+    }
+
+    /// Get every value that has been read from the input or written to the
+    /// output, in the order it happened. Returns `None` if I/O logging is
+    /// disabled (see [Self::set_io_logging]). As long as logging was enabled
+    /// from the start, the reads match the consumed input and the writes
+    /// match the output.
+    pub fn io_log(&self) -> Option<&[IoEvent]> {
+        self.io_log.as_deref()
+    }
+
+    /// Capture the current runtime state of this machine. The returned
+    /// snapshot can be passed to [Self::restore] later to return the machine
+    /// to this exact state. Streams (see [Self::new_with_io]) aren't part of
+    /// the snapshot, since anything already read from or written to them
+    /// can't be taken back.
+    pub fn snapshot(&self) -> MachineSnapshot {
+        MachineSnapshot {
+            program_counter: self.program_counter,
+            input: self.input.clone(),
+            output: self.output.clone(),
+            registers: self.registers.clone(),
+            stacks: self.stacks.clone(),
+            max_stack_usage: self.max_stack_usage.clone(),
+            call_stack: self.call_stack.clone(),
+            cycle_count: self.cycle_count,
+            null_writes: self.null_writes,
+            error: self.error.clone(),
+            warnings: self.warnings.clone(),
+            debug_snapshots: self.debug_snapshots.clone(),
+            profile: self.profile.clone(),
+            io_log: self.io_log.clone(),
+        }
+    }
+
+    /// Restore this machine to a state previously captured by
+    /// [Self::snapshot]. The snapshot should come from a machine running under
+    /// the same hardware spec. Panics if the snapshot's registers or stacks
+    /// don't fit this machine's hardware spec. Profiling counts and the I/O
+    /// log are only restored if they were enabled when the snapshot was taken,
+    /// and still are now.
+    pub fn restore(&mut self, snapshot: MachineSnapshot) {
+        assert_eq!(
+            snapshot.registers.len(),
+            self.hardware_spec.num_registers,
+            "Snapshot has {} registers, but hardware spec has {}",
+            snapshot.registers.len(),
+            self.hardware_spec.num_registers,
+        );
+        assert_eq!(
+            snapshot.stacks.len(),
+            self.hardware_spec.num_stacks,
+            "Snapshot has {} stacks, but hardware spec has {}",
+            snapshot.stacks.len(),
+            self.hardware_spec.num_stacks,
+        );
+        for (stack_id, stack) in snapshot.stacks.iter().enumerate() {
+            let capacity =
+                self.hardware_spec.stack_capacity(StackRef(stack_id));
+            assert!(
+                stack.len() <= capacity,
+                "Snapshot stack {} has {} elements, but its capacity is {}",
+                stack_id,
+                stack.len(),
+                capacity,
+            );
+        }
+
+        self.program_counter = snapshot.program_counter;
+        self.input = snapshot.input;
+        self.output = snapshot.output;
+        self.registers = snapshot.registers;
+        self.stacks = snapshot.stacks;
+        self.max_stack_usage = snapshot.max_stack_usage;
+        self.call_stack = snapshot.call_stack;
+        self.cycle_count = snapshot.cycle_count;
+        self.null_writes = snapshot.null_writes;
+        self.error = snapshot.error;
+        self.warnings = snapshot.warnings;
+        self.debug_snapshots = snapshot.debug_snapshots;
+        self.truncate_debug_snapshots();
+        if let (Some(profile), Some(saved)) =
+            (&mut self.profile, snapshot.profile)
+        {
+            *profile = saved;
+        }
+        if let (Some(io_log), Some(saved)) = (&mut self.io_log, snapshot.io_log)
+        {
+            *io_log = saved;
+        }
+        // The saved state could be from after the snapshot, so it's no longer
+        // part of this machine's history
+        if let Some(detector) = &mut self.loop_detector {
+            *detector = LoopDetector::new();
+        }
+    }
 }
 
 // Functions that get exported to wasm
-#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
 impl Machine {
     /// Get the index of the next instruction to be executed.
     #[cfg_attr(
-        target_arch = "wasm32",
+        feature = "wasm",
         wasm_bindgen(getter, js_name = "programCounter")
     )]
     pub fn program_counter(&self) -> usize {
         self.program_counter
     }
 
+    /// Get the source span of the next instruction to be executed, or `None`
+    /// if the program has run past its last instruction. After an error, this
+    /// is the span of the instruction that failed.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter, js_name = "currentSpan"))]
+    pub fn current_span(&self) -> Option<Span> {
+        self.program.span_at(self.program_counter)
+    }
+
     /// Get the number of cycles, i.e. the number of instructions that have
     /// been run, during the current program execution.
-    #[cfg_attr(
-        target_arch = "wasm32",
-        wasm_bindgen(getter, js_name = "cycleCount")
-    )]
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter, js_name = "cycleCount"))]
     pub fn cycle_count(&self) -> usize {
         self.cycle_count
     }
 
-    /// Checks if this machine has finished executing. This could be by normal
-    /// completion or by runtime error.
+    /// Get the number of values that have been written to `RZR` (and thrown
+    /// away) during the current program execution. This is tracked on all
+    /// hardware, but programs can only read it if the hardware has `RZC`.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter, js_name = "nullWrites"))]
+    pub fn null_writes(&self) -> usize {
+        self.null_writes
+    }
+
+    /// Set the maximum number of snapshots that `DUMP` instructions can store.
+    /// If more than this many snapshots are already stored, the oldest ones
+    /// are dropped. Defaults to [DEFAULT_MAX_DEBUG_SNAPSHOTS].
     #[cfg_attr(
-        target_arch = "wasm32",
-        wasm_bindgen(getter, js_name = "terminated")
+        feature = "wasm",
+        wasm_bindgen(js_name = "setMaxDebugSnapshots")
     )]
+    pub fn set_max_debug_snapshots(&mut self, max_debug_snapshots: usize) {
+        self.max_debug_snapshots = max_debug_snapshots;
+        self.truncate_debug_snapshots();
+    }
+
+    /// Enable or disable infinite loop detection. When enabled, if the machine
+    /// ever returns to the exact state (program counter, input, registers,
+    /// stacks, and call stack) that it was in before, it stops with a
+    /// [RuntimeError::InfiniteLoop], rather than running until it hits
+    /// [MAX_CYCLE_COUNT]. This is disabled by default, so the cycle limit
+    /// behaves as normal. Machines with streamed input (see
+    /// [Self::new_with_io]) are never flagged, since their input isn't known
+    /// ahead of time.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = "setLoopDetection"))]
+    pub fn set_loop_detection(&mut self, enabled: bool) {
+        self.loop_detector = if enabled {
+            Some(LoopDetector::new())
+        } else {
+            None
+        };
+    }
+
+    /// Enable or disable profiling. While enabled, the machine counts how many
+    /// times each instruction executes, which can be retrieved with
+    /// [Self::profile]. Counting starts from zero when profiling is enabled,
+    /// and disabling it throws the counts away. This is disabled by default,
+    /// in which case there's no profiling overhead.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = "setProfiling"))]
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profile = if enabled {
+            Some(vec![0; self.program.instructions.len()])
+        } else {
+            None
+        };
+    }
+
+    /// Enable or disable I/O logging. While enabled, the machine records every
+    /// value read or written, along with the cycle it happened on, which can
+    /// be retrieved with [Self::io_log]. The log starts empty when logging is
+    /// enabled, and disabling it throws the log away. This is disabled by
+    /// default, in which case there's no logging overhead.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = "setIoLogging"))]
+    pub fn set_io_logging(&mut self, enabled: bool) {
+        self.io_log = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    /// Restore this machine to its initial state, as if it was just
+    /// allocated, so the program can be run again without recompiling. The
+    /// input is reloaded, registers go back to their initial values (see
+    /// [HardwareSpec::initial_state]), and everything else (output, stacks
+    /// and their max usage, call stack, cycle count, `RZR` write count, errors,
+    /// debug snapshots, profiling counts, and the I/O log) is cleared. Streams
+    /// (see [Self::new_with_io]) can't be rewound, so they're left as-is.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(js_name = "reset"))]
+    pub fn reset(&mut self) {
+        self.program_counter = 0;
+        self.input = self.initial_input.clone();
+        self.output.clear();
+        self.registers = self
+            .hardware_spec
+            .initial_state
+            .registers(self.hardware_spec.num_registers);
+        self.stacks.iter_mut().for_each(Vec::clear);
+        self.max_stack_usage.iter_mut().for_each(|peak| *peak = 0);
+        self.call_stack.clear();
+        self.cycle_count = 0;
+        self.null_writes = 0;
+        self.error = None;
+        self.warnings.clear();
+        self.trace = None;
+        self.debug_snapshots.clear();
+        if let Some(detector) = &mut self.loop_detector {
+            *detector = LoopDetector::new();
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.iter_mut().for_each(|count| *count = 0);
+        }
+        if let Some(io_log) = &mut self.io_log {
+            io_log.clear();
+        }
+    }
+
+    /// Checks if this machine has finished executing. This could be by normal
+    /// completion or by runtime error.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter, js_name = "terminated"))]
     pub fn terminated(&self) -> bool {
         // Check for normal complete
         self.program_counter >= self.program.instructions.len()
@@ -474,10 +2540,7 @@ impl Machine {
     /// Checks if this machine has completed successfully. The criteria are:
     /// 1. Program is terminated (all instructions have been executed)
     /// 2. No failures occurred (see [FailureReason] for possible failures)
-    #[cfg_attr(
-        target_arch = "wasm32",
-        wasm_bindgen(getter, js_name = "successful")
-    )]
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter, js_name = "successful"))]
     pub fn successful(&self) -> bool {
         self.terminated() && self.failure_reason().is_none()
     }
@@ -486,7 +2549,7 @@ impl Machine {
     /// the program actually failed.** Will return `None` if the program
     /// is still running or it succeeded.
     #[cfg_attr(
-        target_arch = "wasm32",
+        feature = "wasm",
         wasm_bindgen(getter, js_name = "failureReason")
     )]
     pub fn failure_reason(&self) -> Option<FailureReason> {
@@ -495,6 +2558,9 @@ impl Machine {
             None
         } else if self.error.is_some() {
             Some(FailureReason::RuntimeError)
+        } else if self.streams.is_some() {
+            // There's no expected output for a stream to be checked against
+            None
         } else if !self.input.is_empty() {
             Some(FailureReason::RemainingInput)
         } else if self.output != self.expected_output {
@@ -507,7 +2573,7 @@ impl Machine {
 }
 
 // Wasm-ONLY functions
-#[cfg(target_arch = "wasm32")]
+#[cfg(feature = "wasm")]
 #[wasm_bindgen]
 impl Machine {
     /// A wrapper for [Self::input], to be called from wasm.
@@ -522,40 +2588,78 @@ impl Machine {
         self.output.clone()
     }
 
+    /// A wrapper for [Self::expected_output], to be called from wasm.
+    #[wasm_bindgen(getter, js_name = "expectedOutput")]
+    pub fn wasm_expected_output(&self) -> Vec<LangValue> {
+        self.expected_output.clone()
+    }
+
+    /// A wrapper for [Self::output_diff], to be called from wasm. Returns the
+    /// diff as a plain JS object, or `null` if the output isn't the problem.
+    #[wasm_bindgen(getter, js_name = "outputDiff")]
+    pub fn wasm_output_diff(&self) -> OptionalOutputDiff {
+        // Convert the diff to a js object. Be careful here!
+        JsValue::from_serde(&self.output_diff())
+            .unwrap()
+            .unchecked_into()
+    }
+
     /// A wrapper for [Self::registers], to be called from wasm. We can't send
     /// maps through wasm, so this returns a [JsValue] which is an object
-    /// mapping register names (strings) to their values (`LangValue`).
+    /// mapping register names (strings) to their values (`LangValue`). If a
+    /// register has an alias, that is used as its name.
     #[wasm_bindgen(getter, js_name = "registers")]
     pub fn wasm_registers(&self) -> LangValueMap {
         // Convert the keys of the register map to strings
-        let regs_by_name: HashMap<String, LangValue> = self
+        let regs_by_name: OrderedMap<String, LangValue> = self
             .registers()
             .into_iter()
-            .map(|(reg_ref, reg_value)| (reg_ref.to_string(), reg_value))
+            .map(|(reg_ref, reg_value)| {
+                (self.hardware_spec.register_name(reg_ref), reg_value)
+            })
             .collect();
-        // Convert the hashmap to a js object. Be careful here!
+        // Convert the map to a js object. Be careful here!
         JsValue::from_serde(&regs_by_name).unwrap().unchecked_into()
     }
 
     /// A wrapper for [Self::stacks], to be called from wasm. We can't send
     /// maps through wasm, so this returns a [JsValue] which is an object
-    /// mapping stacks names (strings) to their values (`Vec<LangValue>`).
+    /// mapping stacks names (strings) to their values (`Vec<LangValue>`). If a
+    /// stack has an alias, that is used as its name.
     #[wasm_bindgen(getter, js_name = "stacks")]
     pub fn wasm_stacks(&self) -> LangValueArrayMap {
         // Convert the keys of the stacks map to strings
-        let stacks_by_name: HashMap<String, &[LangValue]> = self
+        let stacks_by_name: OrderedMap<String, &[LangValue]> = self
             .stacks()
             .into_iter()
             .map(|(stack_ref, stack_value)| {
-                (stack_ref.to_string(), stack_value)
+                (self.hardware_spec.stack_name(stack_ref), stack_value)
             })
             .collect();
-        // Convert the hashmap to a js object. Be careful here!
+        // Convert the map to a js object. Be careful here!
         JsValue::from_serde(&stacks_by_name)
             .unwrap()
             .unchecked_into()
     }
 
+    /// A wrapper for [Self::max_stack_usage], to be called from wasm. We
+    /// can't send maps through wasm, so this returns a [JsValue] which is an
+    /// object mapping stack names (strings) to the most values that stack has
+    /// held at once.
+    #[wasm_bindgen(getter, js_name = "maxStackUsage")]
+    pub fn wasm_max_stack_usage(&self) -> LangValueMap {
+        let usage_by_name: OrderedMap<String, usize> = self
+            .max_stack_usage()
+            .into_iter()
+            .map(|(stack_ref, usage)| {
+                (self.hardware_spec.stack_name(stack_ref), usage)
+            })
+            .collect();
+        JsValue::from_serde(&usage_by_name)
+            .unwrap()
+            .unchecked_into()
+    }
+
     /// A wrapper for [Self::error], to be called from wasm. We can't send
     /// maps through wasm, so this returns a simplified error as a
     /// [SourceElement].
@@ -573,6 +2677,46 @@ impl Machine {
         })
     }
 
+    /// A wrapper for [Self::debug_snapshots], to be called from wasm. Returns
+    /// an array of plain JS objects, oldest first.
+    #[wasm_bindgen(getter, js_name = "debugSnapshots")]
+    pub fn wasm_debug_snapshots(&self) -> DebugSnapshotArray {
+        // Convert the snapshots to js objects. Be careful here!
+        JsValue::from_serde(&self.debug_snapshots)
+            .unwrap()
+            .unchecked_into()
+    }
+
+    /// A wrapper for [Self::profile], to be called from wasm. Returns an array
+    /// of plain JS objects, one per instruction, or `null` if profiling is
+    /// disabled.
+    #[wasm_bindgen(getter, js_name = "profile")]
+    pub fn wasm_profile(&self) -> OptionalProfileEntryArray {
+        #[derive(Serialize)]
+        struct ProfileEntry {
+            span: Span,
+            count: u64,
+        }
+
+        let profile: Option<Vec<ProfileEntry>> =
+            self.profile().map(|profile| {
+                profile
+                    .into_iter()
+                    .map(|(span, count)| ProfileEntry { span, count })
+                    .collect()
+            });
+        // Convert the profile to js objects. Be careful here!
+        JsValue::from_serde(&profile).unwrap().unchecked_into()
+    }
+
+    /// A wrapper for [Self::io_log], to be called from wasm. Returns an array
+    /// of plain JS objects, or `null` if I/O logging is disabled.
+    #[wasm_bindgen(getter, js_name = "ioLog")]
+    pub fn wasm_io_log(&self) -> OptionalIoEventArray {
+        // Convert the log to js objects. Be careful here!
+        JsValue::from_serde(&self.io_log).unwrap().unchecked_into()
+    }
+
     /// A wrapper for [Self::execute_next], to be called from wasm. We throw
     /// away the error because it simplifies the logic on the TS side. That
     /// error is accessible via [Self::wasm_error] anyway.
@@ -582,6 +2726,16 @@ impl Machine {
         self.execute_next().unwrap_or(true)
     }
 
+    /// A wrapper for [Self::execute_next_traced], to be called from wasm.
+    /// Returns the trace as a plain JS object, or `null` if nothing executed
+    /// or an error occurred. The error is accessible via [Self::wasm_error].
+    #[wasm_bindgen(js_name = "executeNextTraced")]
+    pub fn wasm_execute_next_traced(&mut self) -> OptionalStepTrace {
+        let trace = self.execute_next_traced().ok().flatten();
+        // Convert the trace to a js object. Be careful here!
+        JsValue::from_serde(&trace).unwrap().unchecked_into()
+    }
+
     /// A wrapper for [Self::execute_all], to be called from wasm. We throw
     /// away the error because it simplifies the logic on the TS side. That
     /// error is accessible via [Self::wasm_error] anyway.
@@ -590,13 +2744,45 @@ impl Machine {
         // If an error occurred, that means something executed, so return true
         self.execute_all().unwrap_or(true)
     }
+
+    /// A wrapper for [Self::execute_until], to be called from wasm. Returns
+    /// `true` if execution stopped at a breakpoint, or `false` if the program
+    /// terminated, including by error. The error is accessible via
+    /// [Self::wasm_error].
+    #[wasm_bindgen(js_name = "executeUntil")]
+    pub fn wasm_execute_until(&mut self, breakpoints: &[usize]) -> bool {
+        self.execute_until(breakpoints).unwrap_or(false)
+    }
+
+    /// A wrapper for [Self::execute_until_line], to be called from wasm. See
+    /// [Self::wasm_execute_until] for the return value.
+    #[wasm_bindgen(js_name = "executeUntilLine")]
+    pub fn wasm_execute_until_line(&mut self, lines: &[usize]) -> bool {
+        self.execute_until_line(lines).unwrap_or(false)
+    }
+
+    /// A wrapper for [Self::execute_until_event], to be called from wasm.
+    /// `events` is the bits of each [Event] to stop on, OR'd together.
+    /// Returns the stop reason as a plain JS object. An error counts as
+    /// termination, and is accessible via [Self::wasm_error].
+    #[wasm_bindgen(js_name = "executeUntilEvent")]
+    pub fn wasm_execute_until_event(
+        &mut self,
+        events: u32,
+    ) -> StopReasonObject {
+        let stop_reason = self
+            .execute_until_event(EventMask::from_bits_truncate(events))
+            .unwrap_or(StopReason::Terminated);
+        // Convert the stop reason to a js object. Be careful here!
+        JsValue::from_serde(&stop_reason).unwrap().unchecked_into()
+    }
 }
 
 /// The reason why a program failed. **These reasons are only applicable for
 /// terminated, unsuccessful programs**. For a program that has yet to
 /// terminate, or did so successfully, none of these cases apply.
-#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
-#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Copy, Clone, Debug, Serialize)]
 pub enum FailureReason {
     /// An error occurred while trying to execute one of the instructions
     RuntimeError,
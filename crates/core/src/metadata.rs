@@ -0,0 +1,171 @@
+//! Information about a program that isn't part of the program itself, like
+//! who wrote it and which puzzle it solves. It's declared in a header of
+//! special comments at the top of the source file:
+//!
+//! ```text
+//! ;! name: reverse-fast
+//! ;! puzzle: hw1/prog2
+//! ;! author: lucas
+//! READ RX0
+//! ```
+//!
+//! Each line is a key and a value, separated by a colon. Metadata lines only
+//! count before the first statement. Anywhere else they're ignored, with a
+//! warning. They're still comments, so they never affect execution.
+
+use crate::{
+    ast::{source::Program, Node},
+    consts::METADATA_PREFIX,
+    error::LintWarning,
+    models::HardwareSpec,
+    parse,
+    util::Span,
+};
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// The metadata header of a program. See the [module docs](self) for the
+/// format. Available from
+/// [Compiler::metadata](crate::Compiler::metadata).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct ProgramMetadata {
+    /// The name of the solution, from the `name` key
+    pub name: Option<String>,
+    /// The puzzle that the program solves, from the `puzzle` key. `gdlk test`
+    /// uses this to pair solutions with puzzles.
+    pub puzzle: Option<String>,
+    /// Who wrote the program, from the `author` key
+    pub author: Option<String>,
+    /// Every other key, with its value
+    pub other: BTreeMap<String, String>,
+}
+
+impl ProgramMetadata {
+    /// Read the metadata header of a program without compiling it, e.g. to
+    /// find out which puzzle it's for. The program doesn't need to be valid.
+    /// A line with a syntax error ends the header the same as a statement
+    /// would, so no hardware spec is needed for aliases.
+    pub fn from_source(source: &str) -> Self {
+        let (program, errors) =
+            parse::parse_lenient(source, &HardwareSpec::default());
+        let first_error = errors
+            .first()
+            .map_or(usize::MAX, |error| error.span().offset);
+        read_metadata(&program, first_error).0
+    }
+
+    /// Get the value for a key, whether it's one of the known keys or not
+    pub fn get(&self, key: &str) -> Option<&str> {
+        match key {
+            "name" => self.name.as_deref(),
+            "puzzle" => self.puzzle.as_deref(),
+            "author" => self.author.as_deref(),
+            _ => self.other.get(key).map(String::as_str),
+        }
+    }
+
+    /// Set the value for a key, unless it's already set. Returns `false` if
+    /// the key was already set.
+    fn insert(&mut self, key: &str, value: &str) -> bool {
+        let slot = match key {
+            "name" => &mut self.name,
+            "puzzle" => &mut self.puzzle,
+            "author" => &mut self.author,
+            _ if self.other.contains_key(key) => return false,
+            _ => {
+                self.other.insert(key.into(), value.into());
+                return true;
+            }
+        };
+        if slot.is_some() {
+            return false;
+        }
+        *slot = Some(value.into());
+        true
+    }
+}
+
+/// Split a metadata line into its key and value. `src` is the whole comment,
+/// including the [METADATA_PREFIX]. Returns `None` if the line isn't
+/// formatted correctly, i.e. it has no colon or no key.
+pub(crate) fn parse_entry(src: &str) -> Option<(&str, &str)> {
+    let (key, value) = src.strip_prefix(METADATA_PREFIX)?.split_once(':')?;
+    let key = key.trim();
+    if key.is_empty() {
+        None
+    } else {
+        Some((key, value.trim()))
+    }
+}
+
+/// Pull the metadata out of a parsed program. Metadata comments have to come
+/// before the first statement, and before `header_end` (a byte offset).
+/// Anything out of place, malformed, or repeated gets a warning instead. If a
+/// key is repeated, the first value wins.
+pub(crate) fn read_metadata(
+    program: &Program<Span>,
+    header_end: usize,
+) -> (ProgramMetadata, Vec<(LintWarning, Span)>) {
+    // Comments are only kept for the main source, so statements from
+    // included files don't matter
+    let header_end = program
+        .body
+        .iter()
+        .map(Node::metadata)
+        .find(|span| span.source_id.is_none())
+        .map_or(header_end, |span| span.offset.min(header_end));
+
+    let mut metadata = ProgramMetadata::default();
+    let mut warnings = Vec::new();
+    for Node(comment, span) in program.comments() {
+        if !comment.is_metadata() {
+            continue;
+        }
+        let warning = if comment.trailing || span.offset > header_end {
+            Some(LintWarning::MisplacedMetadata)
+        } else {
+            // The comment text doesn't include the leading `;`
+            match parse_entry(&format!(";{}", comment.text)) {
+                None => Some(LintWarning::InvalidMetadata),
+                Some((key, value)) if !metadata.insert(key, value) => {
+                    Some(LintWarning::DuplicateMetadata)
+                }
+                Some(_) => None,
+            }
+        };
+        warnings.extend(warning.map(|warning| (warning, *span)));
+    }
+    (metadata, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_entry() {
+        assert_eq!(parse_entry(";! name: echo"), Some(("name", "echo")));
+        assert_eq!(parse_entry(";!name:echo"), Some(("name", "echo")));
+        assert_eq!(parse_entry(";! url: a:b "), Some(("url", "a:b")));
+        assert_eq!(parse_entry(";! empty:"), Some(("empty", "")));
+        assert_eq!(parse_entry(";! name"), None);
+        assert_eq!(parse_entry(";!  : echo"), None);
+        assert_eq!(parse_entry("; name: echo"), None);
+    }
+
+    #[test]
+    fn test_from_source() {
+        // Invalid programs still have metadata
+        let metadata =
+            ProgramMetadata::from_source(";! name: a\n;! x: 1\nREAD RW0\n");
+        assert_eq!(metadata.name.as_deref(), Some("a"));
+        assert_eq!(metadata.get("x"), Some("1"));
+        // But a line with an error ends the header, like any statement
+        let metadata =
+            ProgramMetadata::from_source("READ FUEL\n;! name: a\nREAD RX0\n");
+        assert_eq!(metadata, ProgramMetadata::default());
+    }
+}
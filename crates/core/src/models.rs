@@ -2,53 +2,530 @@
 //! structs should mostly just be data containers, with little to no
 //! functionality defined on them.
 
-#[cfg(target_arch = "wasm32")]
-use crate::ast::wasm::StringArray;
-use crate::ast::{LangValue, RegisterRef, StackRef};
+#[cfg(feature = "wasm")]
+use crate::ast::wasm::{RegisterInfoArray, StringArray};
+use crate::{
+    ast::{InstructionKind, LangValue, RegisterRef, StackRef},
+    consts::{
+        CONSTANT_REF_TAG, DEFAULT_MAX_CALL_DEPTH, INPUT_HEAD_REGISTER_REF,
+        INPUT_LENGTH_REGISTER_REF, MAX_CYCLE_COUNT, MAX_STACK_LENGTH_RANGE,
+        MAX_TEST_CASE_LENGTH, NULL_COUNT_REGISTER_REF, NULL_REGISTER_REF,
+        NUM_REGISTERS_RANGE, NUM_STACKS_RANGE, STACK_LENGTH_REGISTER_REF_TAG,
+        STACK_REF_TAG, USER_REGISTER_REF_TAG,
+    },
+    error::{
+        AllocateError, CompileError, ProgramSpecError, RuntimeError, SpecError,
+        WithSource,
+    },
+    machine::FailureReason,
+    registers::RegisterSet,
+    util::seeded_values,
+};
+#[cfg(feature = "fuzz")]
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-#[cfg(target_arch = "wasm32")]
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+};
+#[cfg(feature = "fuzz")]
+use std::{iter, ops::RangeInclusive};
+#[cfg(feature = "wasm")]
 use wasm_bindgen::{prelude::*, JsCast};
 
 /// The "hardware" that a program can execute on. This defines computing
 /// constraints. This is needed both at compile time and runtime.
-#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+// cfg_eval is needed so wasm_bindgen sees the `skip` attributes on the fields
+#[cfg_attr(feature = "wasm", cfg_eval, wasm_bindgen)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "HardwareSpecDef")]
 pub struct HardwareSpec {
     // TODO make these readonly and camel case in wasm
     /// Number of registers available
     pub num_registers: usize,
     /// Maximum number of stacks permitted
     pub num_stacks: usize,
-    /// Maximum size of each stack
+    /// Maximum size of each stack. If [Self::stack_lengths] is given, this is
+    /// only an upper bound, and each stack's real capacity comes from there.
     pub max_stack_length: usize,
+    /// Optional capacity for each individual stack, e.g. `[4, 64]` for a
+    /// small `S0` and a big `S1`. If given, there must be exactly one per
+    /// stack, and none can be bigger than [Self::max_stack_length]. If empty,
+    /// every stack holds [Self::max_stack_length] values. See
+    /// [Self::stack_capacity].
+    #[cfg_attr(feature = "wasm", wasm_bindgen(skip))]
+    #[serde(default)]
+    pub stack_lengths: Vec<usize>,
+    /// Whether the `DUMP` instruction is available. This is a debugging aid,
+    /// so it's disabled unless explicitly turned on.
+    #[serde(default)]
+    pub dump_enabled: bool,
+    /// Whether the read-only `RZC` register is available. It holds the number
+    /// of writes to `RZR` so far, so programs can tell how much work they've
+    /// thrown away. Disabled by default, so `RZC` doesn't show up on existing
+    /// hardware.
+    #[serde(default)]
+    pub null_count_enabled: bool,
+    /// Optional aliases for user registers, e.g. `FUEL` for `RX0`. If given,
+    /// there must be exactly one per register. Aliases can be used in source
+    /// anywhere the register's normal name can.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(skip))]
+    #[serde(default)]
+    pub register_names: Vec<String>,
+    /// Optional aliases for stacks, e.g. `CARGO` for `S0`. If given, there
+    /// must be exactly one per stack.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(skip))]
+    #[serde(default)]
+    pub stack_names: Vec<String>,
+    /// How many cycles each instruction costs to execute
+    #[cfg_attr(feature = "wasm", wasm_bindgen(skip))]
+    #[serde(default)]
+    pub cost_model: CostModel,
+    /// What happens when arithmetic overflows [LangValue]
+    #[serde(default)]
+    pub overflow_behavior: OverflowBehavior,
+    /// The only instructions that programs may use, e.g. to make a puzzle
+    /// that has to be solved without `MUL`. If `None`, every instruction is
+    /// allowed. `DUMP` also has to be enabled with [Self::dump_enabled].
+    #[cfg_attr(feature = "wasm", wasm_bindgen(skip))]
+    #[serde(default)]
+    pub allowed_instructions: Option<BTreeSet<InstructionKind>>,
+    /// How many `CALL`s can be nested before the call stack overflows.
+    /// Setting this to zero disables `CALL` and `RET` entirely. Defaults to
+    /// [DEFAULT_MAX_CALL_DEPTH].
+    #[serde(default = "default_max_call_depth")]
+    pub max_call_depth: usize,
+    /// How many extern ops (functions provided by the host) are available to
+    /// `EXT`, with IDs starting at `0`. Setting this to zero, the default,
+    /// disables `EXT` entirely. The machine running the program needs a
+    /// handler to actually execute them, see
+    /// [Machine::set_extern_handler](crate::Machine::set_extern_handler).
+    #[serde(default)]
+    pub num_extern_ops: usize,
+    /// What the user registers hold when a machine starts. Defaults to all
+    /// zeroes.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(skip))]
+    #[serde(default)]
+    pub initial_state: InitialState,
+    /// The most instructions a program can have, not counting labels.
+    /// Programs past this fail to compile. `None`, the default, means there's
+    /// no limit.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(skip))]
+    #[serde(default)]
+    pub max_instructions: Option<usize>,
+}
+
+/// Deserialization format for [HardwareSpec]. This is identical, except that
+/// `max_stack_length` can be left out if `stack_lengths` is given. It gets
+/// validated before being converted.
+#[derive(Deserialize)]
+struct HardwareSpecDef {
+    num_registers: usize,
+    num_stacks: usize,
+    #[serde(default)]
+    max_stack_length: Option<usize>,
+    #[serde(default)]
+    stack_lengths: Vec<usize>,
+    #[serde(default)]
+    dump_enabled: bool,
+    #[serde(default)]
+    null_count_enabled: bool,
+    #[serde(default)]
+    register_names: Vec<String>,
+    #[serde(default)]
+    stack_names: Vec<String>,
+    #[serde(default)]
+    cost_model: CostModel,
+    #[serde(default)]
+    overflow_behavior: OverflowBehavior,
+    #[serde(default)]
+    allowed_instructions: Option<BTreeSet<InstructionKind>>,
+    #[serde(default = "default_max_call_depth")]
+    max_call_depth: usize,
+    #[serde(default)]
+    num_extern_ops: usize,
+    #[serde(default)]
+    initial_state: InitialState,
+    #[serde(default)]
+    max_instructions: Option<usize>,
+}
+
+fn default_max_call_depth() -> usize {
+    DEFAULT_MAX_CALL_DEPTH
+}
+
+impl TryFrom<HardwareSpecDef> for HardwareSpec {
+    type Error = SpecError;
+
+    fn try_from(value: HardwareSpecDef) -> Result<Self, Self::Error> {
+        // Per-stack lengths can stand in for the uniform length, in which
+        // case the biggest stack sets the upper bound
+        let max_stack_length = match value.max_stack_length {
+            Some(max_stack_length) => max_stack_length,
+            None if !value.stack_lengths.is_empty() => value
+                .stack_lengths
+                .iter()
+                .copied()
+                .max()
+                .unwrap_or_default(),
+            None => return Err(SpecError::MissingStackLength),
+        };
+        let spec = Self {
+            num_registers: value.num_registers,
+            num_stacks: value.num_stacks,
+            max_stack_length,
+            stack_lengths: value.stack_lengths,
+            dump_enabled: value.dump_enabled,
+            null_count_enabled: value.null_count_enabled,
+            register_names: value.register_names,
+            stack_names: value.stack_names,
+            cost_model: value.cost_model,
+            overflow_behavior: value.overflow_behavior,
+            allowed_instructions: value.allowed_instructions,
+            max_call_depth: value.max_call_depth,
+            num_extern_ops: value.num_extern_ops,
+            initial_state: value.initial_state,
+            max_instructions: value.max_instructions,
+        };
+        // Specs from before the ranges were enforced may be out of range, so
+        // those are left to the builder and the check command
+        if let Some(violation) = spec.rule_violations().into_iter().next() {
+            return Err(violation.error);
+        }
+        Ok(spec)
+    }
+}
+
+/// Check if a name would collide with one of the built-in register or stack
+/// names, e.g. `RZR` or `S0`.
+fn is_reserved_name(name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+    let has_id_suffix = |tag: &str| match name.strip_prefix(tag) {
+        Some(id) => !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()),
+        None => false,
+    };
+    name == NULL_REGISTER_REF
+        || name == INPUT_LENGTH_REGISTER_REF
+        || name == INPUT_HEAD_REGISTER_REF
+        || name == NULL_COUNT_REGISTER_REF
+        || has_id_suffix(STACK_LENGTH_REGISTER_REF_TAG)
+        || has_id_suffix(USER_REGISTER_REF_TAG)
+        || has_id_suffix(STACK_REF_TAG)
 }
 
 // Functions that DON'T get exported to wasm
 impl HardwareSpec {
-    /// Get a list of all [RegisterRef]s that exist for this hardware.
+    /// Create a new hardware spec, checking that each parameter is within
+    /// its allowed range (see [NUM_REGISTERS_RANGE], [NUM_STACKS_RANGE], and
+    /// [MAX_STACK_LENGTH_RANGE]). Unlike [Self::new], this never produces a
+    /// spec that a puzzle couldn't reasonably be built on.
+    pub fn try_new(
+        num_registers: usize,
+        num_stacks: usize,
+        max_stack_length: usize,
+    ) -> Result<Self, SpecError> {
+        let spec = Self::new(num_registers, num_stacks, max_stack_length);
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    /// Start building a spec one property at a time, e.g.
+    ///
+    /// ```
+    /// use gdlk::HardwareSpec;
+    ///
+    /// let spec = HardwareSpec::builder()
+    ///     .registers(2)
+    ///     .stacks(1)
+    ///     .stack_length(16)
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(spec, HardwareSpec::new(2, 1, 16));
+    /// ```
+    ///
+    /// Anything that isn't set keeps its value from [Self::minimal].
+    pub fn builder() -> HardwareSpecBuilder {
+        HardwareSpecBuilder {
+            spec: Self::minimal(),
+        }
+    }
+
+    /// The smallest hardware allowed: one register, and no stacks. This is
+    /// also the [Default], and what the CLI uses when no spec is given.
+    pub fn minimal() -> Self {
+        Self::new(
+            *NUM_REGISTERS_RANGE.start(),
+            *NUM_STACKS_RANGE.start(),
+            *MAX_STACK_LENGTH_RANGE.start(),
+        )
+    }
+
+    /// A middle-of-the-road spec, with enough registers and stacks to solve
+    /// most puzzles comfortably: 4 registers, and 2 stacks of length 16.
+    pub fn standard() -> Self {
+        Self::new(4, 2, 16)
+    }
+
+    /// The biggest hardware allowed, with every parameter at the top of its
+    /// range (see [NUM_REGISTERS_RANGE], [NUM_STACKS_RANGE], and
+    /// [MAX_STACK_LENGTH_RANGE]).
+    pub fn maximal() -> Self {
+        Self::new(
+            *NUM_REGISTERS_RANGE.end(),
+            *NUM_STACKS_RANGE.end(),
+            *MAX_STACK_LENGTH_RANGE.end(),
+        )
+    }
+
+    /// Make sure this spec is valid, returning the first problem found. See
+    /// [Self::violations] for the full list of rules, and which of them are
+    /// checked when a spec is deserialized.
+    pub fn validate(&self) -> Result<(), SpecError> {
+        match self.violations().into_iter().next() {
+            Some(violation) => Err(violation.error),
+            None => Ok(()),
+        }
+    }
+
+    /// Check this spec against every rule, and collect all the problems
+    /// rather than stopping at the first one. The rules are:
+    /// - The register count, stack count, and max stack length must be within
+    ///   their allowed ranges (see [NUM_REGISTERS_RANGE], [NUM_STACKS_RANGE],
+    ///   and [MAX_STACK_LENGTH_RANGE])
+    /// - The list of stack lengths must be either empty, or have exactly one
+    ///   length per stack, and none can be more than the max stack length
+    /// - Each list of names must be either empty, or have exactly one name per
+    ///   register/stack
+    /// - Every name must be a valid identifier, can't collide with a built-in
+    ///   name, and must be unique (case-insensitive) across both lists
+    /// - Every instruction must cost at least one cycle, and no more than
+    ///   [MAX_CYCLE_COUNT]
+    ///
+    /// Deserialization checks everything but the ranges, so that specs saved
+    /// before the ranges were enforced can still be loaded.
+    pub fn violations(&self) -> Vec<SpecViolation> {
+        let mut violations = self.range_violations();
+        violations.extend(self.rule_violations());
+        violations
+    }
+
+    /// Check that the register count, stack count, and max stack length are
+    /// within their allowed ranges
+    fn range_violations(&self) -> Vec<SpecViolation> {
+        let mut violations = Vec::new();
+
+        for (field, range, actual) in &[
+            ("num_registers", NUM_REGISTERS_RANGE, self.num_registers),
+            ("num_stacks", NUM_STACKS_RANGE, self.num_stacks),
+            (
+                "max_stack_length",
+                MAX_STACK_LENGTH_RANGE,
+                self.max_stack_length,
+            ),
+        ] {
+            if !range.contains(actual) {
+                violations.push(SpecViolation::new(
+                    format!("/{}", field),
+                    SpecError::OutOfBounds {
+                        field,
+                        min: *range.start(),
+                        max: *range.end(),
+                        actual: *actual,
+                    },
+                ));
+            }
+        }
+
+        violations
+    }
+
+    /// Check every rule other than the ranges. These are the rules that get
+    /// checked on deserialization.
+    fn rule_violations(&self) -> Vec<SpecViolation> {
+        let mut violations = Vec::new();
+
+        if !self.stack_lengths.is_empty()
+            && self.stack_lengths.len() != self.num_stacks
+        {
+            violations.push(SpecViolation::new(
+                "/stack_lengths".into(),
+                SpecError::StackLengthCountMismatch {
+                    expected: self.num_stacks,
+                    actual: self.stack_lengths.len(),
+                },
+            ));
+        }
+        for (index, length) in self.stack_lengths.iter().enumerate() {
+            if *length > self.max_stack_length {
+                violations.push(SpecViolation::new(
+                    format!("/stack_lengths/{}", index),
+                    SpecError::OutOfBounds {
+                        field: "stack_lengths",
+                        min: *MAX_STACK_LENGTH_RANGE.start(),
+                        max: self.max_stack_length,
+                        actual: *length,
+                    },
+                ));
+            }
+        }
+
+        let name_lists = [
+            (
+                "register",
+                "register_names",
+                &self.register_names,
+                self.num_registers,
+            ),
+            ("stack", "stack_names", &self.stack_names, self.num_stacks),
+        ];
+        for (kind, field, names, expected) in &name_lists {
+            if !names.is_empty() && names.len() != *expected {
+                violations.push(SpecViolation::new(
+                    format!("/{}", field),
+                    SpecError::NameCountMismatch {
+                        kind,
+                        expected: *expected,
+                        actual: names.len(),
+                    },
+                ));
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for (_, field, names, _) in &name_lists {
+            for (index, name) in names.iter().enumerate() {
+                let valid_start = matches!(
+                    name.chars().next(),
+                    Some(c) if c.is_ascii_alphabetic() || c == '_'
+                );
+                let error = if !valid_start
+                    || !name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+                {
+                    SpecError::InvalidName(name.clone())
+                } else if is_reserved_name(name) {
+                    SpecError::ReservedName(name.clone())
+                } else if !seen.insert(name.to_ascii_uppercase()) {
+                    SpecError::DuplicateName(name.clone())
+                } else {
+                    continue;
+                };
+                violations.push(SpecViolation::new(
+                    format!("/{}/{}", field, index),
+                    error,
+                ));
+            }
+        }
+
+        // Free instructions would let a program loop forever, and anything
+        // over the cycle limit could never run
+        for (kind, cost) in &self.cost_model.costs {
+            let error = if *cost == 0 {
+                SpecError::ZeroCost(*kind)
+            } else if *cost > MAX_CYCLE_COUNT {
+                SpecError::CostTooHigh {
+                    kind: *kind,
+                    max: MAX_CYCLE_COUNT,
+                    actual: *cost,
+                }
+            } else {
+                continue;
+            };
+            violations.push(SpecViolation::new(
+                format!("/cost_model/{}", kind),
+                error,
+            ));
+        }
+
+        violations
+    }
+
+    /// Get the name to display for a register. This is the register's alias
+    /// if it has one, otherwise its canonical name (e.g. `RX0`).
+    pub fn register_name(&self, register: RegisterRef) -> String {
+        match register {
+            RegisterRef::User(reg_id) => self.register_names.get(reg_id),
+            _ => None,
+        }
+        .cloned()
+        .unwrap_or_else(|| register.to_string())
+    }
+
+    /// Get the name to display for a stack. This is the stack's alias if it
+    /// has one, otherwise its canonical name (e.g. `S0`).
+    pub fn stack_name(&self, stack: StackRef) -> String {
+        self.stack_names
+            .get(stack.0)
+            .cloned()
+            .unwrap_or_else(|| stack.to_string())
+    }
+
+    /// Get the set of all registers that are available on this hardware,
+    /// along with their capabilities.
+    pub fn register_set(&self) -> RegisterSet {
+        RegisterSet::for_spec(self)
+    }
+
+    /// Get a list of all [RegisterRef]s that exist for this hardware, in the
+    /// order they should be displayed. This excludes `RZR`, since it always
+    /// holds zero and isn't worth showing. To check if a particular register
+    /// exists, including `RZR`, use [Self::is_valid_register].
     pub fn all_register_refs(&self) -> Vec<RegisterRef> {
-        // The order here is important. This is how it will appear in the UI!
-        // RLI first
-        let mut register_refs = vec![RegisterRef::InputLength];
-        // RSx registers
-        register_refs
-            .extend((0..self.num_stacks).map(RegisterRef::StackLength));
-        // RXx registers
-        register_refs.extend((0..self.num_registers).map(RegisterRef::User));
-        register_refs
-    }
-
-    /// Get a list of all [StackRef]s that exist for this hardware.
+        self.register_set()
+            .iter()
+            .map(|info| info.register)
+            .filter(|register| *register != RegisterRef::Null)
+            .collect()
+    }
+
+    /// Check if programs on this hardware may use the given kind of
+    /// instruction. See [Self::allowed_instructions].
+    pub fn is_instruction_allowed(&self, kind: InstructionKind) -> bool {
+        match &self.allowed_instructions {
+            Some(allowed) => allowed.contains(&kind),
+            None => true,
+        }
+    }
+
+    /// Get the number of values that the given stack can hold. This comes from
+    /// [Self::stack_lengths] if it's given, otherwise every stack gets
+    /// [Self::max_stack_length].
+    pub fn stack_capacity(&self, stack: StackRef) -> usize {
+        self.stack_lengths
+            .get(stack.0)
+            .copied()
+            .unwrap_or(self.max_stack_length)
+    }
+
+    /// Get a list of all [StackRef]s that exist for this hardware, in order of
+    /// ID.
     pub fn all_stack_refs(&self) -> Vec<StackRef> {
         (0..self.num_stacks).map(StackRef).collect()
     }
+
+    /// Check if a register exists on this hardware. `RZR` and `RLI` always
+    /// exist, while `RSx` and `RXx` depend on the number of stacks and
+    /// registers. `RZC` only exists if [Self::null_count_enabled] is set.
+    pub fn is_valid_register(&self, register: RegisterRef) -> bool {
+        self.register_set().get(register).is_some()
+    }
+
+    /// Check if a register exists on this hardware and can be written to.
+    /// `RLI` and `RSx` are read-only.
+    pub fn is_writable_register(&self, register: RegisterRef) -> bool {
+        matches!(
+            self.register_set().get(register),
+            Some(info) if info.writable
+        )
+    }
 }
 
 // Functions that get exported to wasm
-#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
 impl HardwareSpec {
-    #[cfg_attr(target_arch = "wasm32", wasm_bindgen(constructor))]
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
     pub fn new(
         num_registers: usize,
         num_stacks: usize,
@@ -58,6 +535,18 @@ impl HardwareSpec {
             num_registers,
             num_stacks,
             max_stack_length,
+            stack_lengths: Vec::new(),
+            dump_enabled: false,
+            null_count_enabled: false,
+            register_names: Vec::new(),
+            stack_names: Vec::new(),
+            cost_model: CostModel::default(),
+            overflow_behavior: OverflowBehavior::default(),
+            allowed_instructions: None,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            num_extern_ops: 0,
+            initial_state: InitialState::default(),
+            max_instructions: None,
         }
     }
 
@@ -67,31 +556,52 @@ impl HardwareSpec {
     ///
     /// TODO change to `Vec<String>` after
     /// https://github.com/rustwasm/wasm-bindgen/issues/168
-    #[cfg(target_arch = "wasm32")]
+    #[cfg(feature = "wasm")]
     #[wasm_bindgen(getter, js_name = "registers")]
     pub fn wasm_registers(&self) -> StringArray {
         let refs = self.all_register_refs();
         let reg_names: Vec<String> = refs
             .into_iter()
-            .map(|reg_ref| reg_ref.to_string())
+            .map(|reg_ref| self.register_name(reg_ref))
             .collect();
         // Convert the vec to a js array. Be careful here!
         JsValue::from_serde(&reg_names).unwrap().unchecked_into()
     }
 
+    /// A wrapper around [Self::register_set] to be called from wasm. Returns
+    /// an array of plain JS objects, one per register, in display order.
+    #[cfg(feature = "wasm")]
+    #[wasm_bindgen(getter, js_name = "registerSet")]
+    pub fn wasm_register_set(&self) -> RegisterInfoArray {
+        let infos: Vec<_> = self.register_set().iter().copied().collect();
+        // Convert the vec to a js array. Be careful here!
+        JsValue::from_serde(&infos).unwrap().unchecked_into()
+    }
+
+    /// A wrapper around [Self::stack_capacity] to be called from wasm. Returns
+    /// the capacity of every stack, in order of ID.
+    #[cfg(feature = "wasm")]
+    #[wasm_bindgen(getter, js_name = "stackCapacities")]
+    pub fn wasm_stack_capacities(&self) -> Vec<usize> {
+        self.all_stack_refs()
+            .into_iter()
+            .map(|stack_ref| self.stack_capacity(stack_ref))
+            .collect()
+    }
+
     /// A wrapper around [Self::all_stack_refs] to be called from wasm.
     /// The elements of the returned vector are strings representing the name
     /// of each stack.
     ///
     /// TODO change to `Vec<String>` after
     /// https://github.com/rustwasm/wasm-bindgen/issues/168
-    #[cfg(target_arch = "wasm32")]
+    #[cfg(feature = "wasm")]
     #[wasm_bindgen(getter, js_name = "stacks")]
     pub fn wasm_stacks(&self) -> StringArray {
         let stack_names: Vec<String> = self
             .all_stack_refs()
             .into_iter()
-            .map(|stack_ref| stack_ref.to_string())
+            .map(|stack_ref| self.stack_name(stack_ref))
             .collect();
         // Convert the vec to a js array. Be careful here!
         JsValue::from_serde(&stack_names).unwrap().unchecked_into()
@@ -101,77 +611,807 @@ impl HardwareSpec {
 // Useful for tests and prototyping
 impl Default for HardwareSpec {
     fn default() -> Self {
-        Self {
-            num_registers: 1,
-            num_stacks: 0,
-            max_stack_length: 0,
+        Self::minimal()
+    }
+}
+
+/// Builds a [HardwareSpec] one property at a time. Create one with
+/// [HardwareSpec::builder]. The spec is validated when it's built, so this
+/// can never produce an invalid spec.
+#[derive(Clone, Debug)]
+pub struct HardwareSpecBuilder {
+    spec: HardwareSpec,
+}
+
+impl HardwareSpecBuilder {
+    /// Set [HardwareSpec::num_registers]
+    pub fn registers(mut self, num_registers: usize) -> Self {
+        self.spec.num_registers = num_registers;
+        self
+    }
+
+    /// Set [HardwareSpec::num_stacks]
+    pub fn stacks(mut self, num_stacks: usize) -> Self {
+        self.spec.num_stacks = num_stacks;
+        self
+    }
+
+    /// Set [HardwareSpec::max_stack_length]
+    pub fn stack_length(mut self, max_stack_length: usize) -> Self {
+        self.spec.max_stack_length = max_stack_length;
+        self
+    }
+
+    /// Set [HardwareSpec::stack_lengths]. This also sets
+    /// [HardwareSpec::max_stack_length] to the biggest of the lengths, so it
+    /// stays a valid upper bound.
+    pub fn stack_lengths(
+        mut self,
+        stack_lengths: impl IntoIterator<Item = usize>,
+    ) -> Self {
+        self.spec.stack_lengths = stack_lengths.into_iter().collect();
+        self.spec.max_stack_length = self
+            .spec
+            .stack_lengths
+            .iter()
+            .copied()
+            .max()
+            .unwrap_or_default();
+        self
+    }
+
+    /// Set [HardwareSpec::dump_enabled]
+    pub fn dump_enabled(mut self, dump_enabled: bool) -> Self {
+        self.spec.dump_enabled = dump_enabled;
+        self
+    }
+
+    /// Set [HardwareSpec::null_count_enabled]
+    pub fn null_count_enabled(mut self, null_count_enabled: bool) -> Self {
+        self.spec.null_count_enabled = null_count_enabled;
+        self
+    }
+
+    /// Set [HardwareSpec::register_names]
+    pub fn register_names<S: Into<String>>(
+        mut self,
+        names: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.spec.register_names = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set [HardwareSpec::stack_names]
+    pub fn stack_names<S: Into<String>>(
+        mut self,
+        names: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.spec.stack_names = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set [HardwareSpec::cost_model]
+    pub fn cost_model(mut self, cost_model: CostModel) -> Self {
+        self.spec.cost_model = cost_model;
+        self
+    }
+
+    /// Set [HardwareSpec::overflow_behavior]
+    pub fn overflow_behavior(
+        mut self,
+        overflow_behavior: OverflowBehavior,
+    ) -> Self {
+        self.spec.overflow_behavior = overflow_behavior;
+        self
+    }
+
+    /// Set [HardwareSpec::allowed_instructions]
+    pub fn allowed_instructions(
+        mut self,
+        allowed_instructions: impl IntoIterator<Item = InstructionKind>,
+    ) -> Self {
+        self.spec.allowed_instructions =
+            Some(allowed_instructions.into_iter().collect());
+        self
+    }
+
+    /// Set [HardwareSpec::max_call_depth]
+    pub fn max_call_depth(mut self, max_call_depth: usize) -> Self {
+        self.spec.max_call_depth = max_call_depth;
+        self
+    }
+
+    /// Set [HardwareSpec::num_extern_ops]
+    pub fn extern_ops(mut self, num_extern_ops: usize) -> Self {
+        self.spec.num_extern_ops = num_extern_ops;
+        self
+    }
+
+    /// Set [HardwareSpec::initial_state]
+    pub fn initial_state(mut self, initial_state: InitialState) -> Self {
+        self.spec.initial_state = initial_state;
+        self
+    }
+
+    /// Set [HardwareSpec::max_instructions]
+    pub fn max_instructions(mut self, max_instructions: usize) -> Self {
+        self.spec.max_instructions = Some(max_instructions);
+        self
+    }
+
+    /// Check the spec (see [HardwareSpec::validate]) and return it, or the
+    /// first problem with it.
+    pub fn build(self) -> Result<HardwareSpec, SpecError> {
+        self.spec.validate()?;
+        Ok(self.spec)
+    }
+}
+
+/// Formats as a human-readable reference card, one property per line. The
+/// first lines are always present:
+///
+/// ```text
+/// Registers: 2
+/// Stacks: 1
+/// Max stack length: 8
+/// Valid registers: RZR, RLI, RIH, RS0, RX0 (FUEL), RX1
+/// Valid stacks: S0 (CARGO)
+/// ```
+///
+/// Aliases are shown in parentheses after the canonical name, and the stacks
+/// line is omitted if there are no stacks. After that, a line is added for
+/// each optional property that differs from its default, in this order:
+/// `Stack lengths` (e.g. `S0=4, S1=64`), `Overflow behavior`, `Max call depth`,
+/// `Extern ops`, `Initial state` (e.g. `random (seed 42)`), `Max instructions`,
+/// `Allowed instructions`, `Instruction costs` (e.g. `DIV=3, PUSH=2`), and
+/// `DUMP: enabled`. Every line, including the last, ends in a newline.
+impl Display for HardwareSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Registers: {}", self.num_registers)?;
+        writeln!(f, "Stacks: {}", self.num_stacks)?;
+        writeln!(f, "Max stack length: {}", self.max_stack_length)?;
+
+        // Show aliases alongside the canonical names, since both are valid
+        let with_alias = |canonical: String, name: String| {
+            if name == canonical {
+                name
+            } else {
+                format!("{} ({})", canonical, name)
+            }
+        };
+        let registers: Vec<String> = self
+            .register_set()
+            .iter()
+            .map(|info| {
+                with_alias(
+                    info.register.to_string(),
+                    self.register_name(info.register),
+                )
+            })
+            .collect();
+        writeln!(f, "Valid registers: {}", registers.join(", "))?;
+        if self.num_stacks > 0 {
+            let stacks: Vec<String> = self
+                .all_stack_refs()
+                .into_iter()
+                .map(|stack| {
+                    with_alias(stack.to_string(), self.stack_name(stack))
+                })
+                .collect();
+            writeln!(f, "Valid stacks: {}", stacks.join(", "))?;
+        }
+
+        if !self.stack_lengths.is_empty() {
+            let lengths: Vec<String> = self
+                .all_stack_refs()
+                .into_iter()
+                .map(|stack| {
+                    format!("{}={}", stack, self.stack_capacity(stack))
+                })
+                .collect();
+            writeln!(f, "Stack lengths: {}", lengths.join(", "))?;
         }
+        if self.overflow_behavior != OverflowBehavior::default() {
+            writeln!(f, "Overflow behavior: {:?}", self.overflow_behavior)?;
+        }
+        if self.max_call_depth != DEFAULT_MAX_CALL_DEPTH {
+            writeln!(f, "Max call depth: {}", self.max_call_depth)?;
+        }
+        if self.num_extern_ops > 0 {
+            writeln!(f, "Extern ops: {}", self.num_extern_ops)?;
+        }
+        if let InitialState::Random { seed } = self.initial_state {
+            writeln!(f, "Initial state: random (seed {})", seed)?;
+        }
+        if let Some(max_instructions) = self.max_instructions {
+            writeln!(f, "Max instructions: {}", max_instructions)?;
+        }
+        if let Some(allowed) = &self.allowed_instructions {
+            let allowed: Vec<String> =
+                allowed.iter().map(InstructionKind::to_string).collect();
+            writeln!(f, "Allowed instructions: {}", allowed.join(", "))?;
+        }
+        if !self.cost_model.costs.is_empty() {
+            let costs: Vec<String> = self
+                .cost_model
+                .costs
+                .iter()
+                .map(|(kind, cost)| format!("{}={}", kind, cost))
+                .collect();
+            writeln!(f, "Instruction costs: {}", costs.join(", "))?;
+        }
+        if self.dump_enabled {
+            writeln!(f, "DUMP: enabled")?;
+        }
+        Ok(())
+    }
+}
+
+/// A single rule that a [HardwareSpec] breaks. See
+/// [HardwareSpec::violations].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpecViolation {
+    /// JSON pointer to the offending field, e.g. `/register_names/1`
+    pub field: String,
+    /// The rule that was broken
+    pub error: SpecError,
+}
+
+impl SpecViolation {
+    fn new(field: String, error: SpecError) -> Self {
+        Self { field, error }
     }
 }
 
-/// Specification that defines a correct program. Provides the input that a
-/// program runs on, and defines the expected output, which is used to determine
-/// if the program is correct. Only needed at runtime.
-#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+impl Display for SpecViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.error)
+    }
+}
+
+/// The number of cycles that each kind of instruction costs to execute. Any
+/// instruction without an explicit cost costs one cycle, so the default model
+/// is one cycle for everything. Serializes as a map of instruction keyword to
+/// cost, e.g. `{"PUSH": 2, "DIV": 3}`.
 #[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CostModel {
+    costs: BTreeMap<InstructionKind, usize>,
+}
+
+impl CostModel {
+    /// Set the cost of a kind of instruction. Costs must be at least one,
+    /// which is checked by [HardwareSpec::validate].
+    pub fn with_cost(mut self, kind: InstructionKind, cost: usize) -> Self {
+        self.costs.insert(kind, cost);
+        self
+    }
+
+    /// Get the number of cycles that a kind of instruction costs.
+    pub fn cost_of(&self, kind: InstructionKind) -> usize {
+        self.costs.get(&kind).copied().unwrap_or(1)
+    }
+}
+
+/// Defines what happens when the result of an arithmetic instruction (`ADD`,
+/// `SUB`, `MUL`, or `DIV`) doesn't fit in a [LangValue].
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum OverflowBehavior {
+    /// The result wraps around, e.g. `MAX + 1` gives `MIN`. This is the
+    /// default.
+    #[default]
+    Wrap,
+    /// Overflow triggers a runtime error. Under [ErrorPolicy::Lenient], the
+    /// result wraps instead, and the error is recorded as a warning.
+    Error,
+    /// The result is clamped to the range of [LangValue], e.g. `MAX + 1`
+    /// gives `MAX`.
+    Saturate,
+}
+
+/// Defines what the user registers hold when a [Machine](crate::Machine)
+/// starts, or gets reset. Stacks always start empty, since the stack length
+/// registers would give away any garbage in them, and a program can't rely on
+/// a stack being empty without checking anyway.
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum InitialState {
+    /// Every register starts at zero. This is the default.
+    #[default]
+    Zeroed,
+    /// Every register starts with a pseudo-random value, to catch programs
+    /// that rely on registers starting at zero. The values are generated from
+    /// the seed, so the same seed always gives the same values.
+    Random { seed: u64 },
+}
+
+impl InitialState {
+    /// Get the starting values of the given number of registers
+    pub(crate) fn registers(self, num_registers: usize) -> Vec<LangValue> {
+        match self {
+            Self::Zeroed => vec![0; num_registers],
+            Self::Random { seed } => {
+                seeded_values(seed).take(num_registers).collect()
+            }
+        }
+    }
+}
+
+/// Defines how a [Machine](crate::Machine) handles runtime errors that it is
+/// able to recover from.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum ErrorPolicy {
+    /// Every runtime error halts execution. This is the default.
+    #[default]
+    Strict,
+    /// Recoverable runtime errors are recorded as warnings, and execution
+    /// continues. Reading from empty input or popping from an empty stack
+    /// yields `0`, dividing by zero yields `0`, and pushing onto a full stack
+    /// does nothing. Hitting the cycle limit, detecting an infinite loop,
+    /// overflowing the call stack, or returning without a call still halts
+    /// execution.
+    Lenient,
+}
+
+/// Optional settings for
+/// [Compiler::compile_with_options](crate::Compiler::compile_with_options). The
+/// defaults compile the program exactly as written.
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(default)]
+pub struct CompileOptions {
+    /// Fold arithmetic on values that are known at compile time into plain
+    /// `SET` instructions, and drop any values that get overwritten before
+    /// they're ever read. The output of the program is unchanged, but it may
+    /// take fewer instructions and cycles. If the program hits a runtime
+    /// error, the register values at that point may differ from the
+    /// unoptimized program. [ProgramStats] describe the optimized program.
+    /// Programs that use `JRI` are never folded, since removing instructions
+    /// would change the indexes that it jumps to.
+    pub constant_folding: bool,
+    /// The maximum number of errors to report if compilation fails. Any
+    /// errors past this are dropped, but still counted, see
+    /// [WithSource::truncated_count](crate::error::WithSource::truncated_count).
+    /// This applies to syntax errors as well as validation errors. `None`
+    /// reports every error.
+    pub max_errors: Option<usize>,
+}
+
+/// Specification that defines a correct program. Holds one or more
+/// [TestCase]s, each of which provides the input that a program runs on and
+/// defines the expected output. A program is only correct if it passes every
+/// test case. Only needed at runtime.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "ProgramSpecDef")]
 pub struct ProgramSpec {
-    /// The input values, where the element at position 0 is the first one that
-    /// will be popped off.
-    input: Vec<LangValue>,
-    /// The correct value to be left in the output when the program exits. The
-    /// first element will be the first one pushed, and so on.
-    expected_output: Vec<LangValue>,
+    /// All the test cases for this program. This is never empty.
+    test_cases: Vec<TestCase>,
+    /// Values for named constants (e.g. `$K`) that apply to every test case.
+    /// See [Self::constants].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    constants: BTreeMap<String, LangValue>,
+}
+
+/// All the formats that a [ProgramSpec] can be deserialized from.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProgramSpecDef {
+    TestCases {
+        test_cases: Vec<TestCase>,
+        #[serde(default)]
+        constants: BTreeMap<String, LangValue>,
+    },
+    /// The original format, from before programs could have multiple test
+    /// cases. This is just a single test case, inlined into the spec.
+    Single(TestCase),
+}
+
+impl TryFrom<ProgramSpecDef> for ProgramSpec {
+    type Error = ProgramSpecError;
+
+    fn try_from(value: ProgramSpecDef) -> Result<Self, Self::Error> {
+        let spec = match value {
+            ProgramSpecDef::TestCases {
+                test_cases,
+                constants,
+            } => Self {
+                test_cases,
+                constants,
+            },
+            ProgramSpecDef::Single(test_case) => Self {
+                test_cases: vec![test_case],
+                constants: BTreeMap::new(),
+            },
+        };
+        spec.validate()?;
+        Ok(spec)
+    }
 }
 
 // Functions that DON'T get exported to wasm
 impl ProgramSpec {
-    /// Get the program spec's defined input buffer. This is the initial value
-    /// of the input for any run of the program.
+    /// Create a program spec with multiple test cases. Panics if the list of
+    /// test cases is empty.
+    pub fn with_test_cases(test_cases: Vec<TestCase>) -> Self {
+        assert!(
+            !test_cases.is_empty(),
+            "Program spec must have at least one test case"
+        );
+        Self {
+            test_cases,
+            constants: BTreeMap::new(),
+        }
+    }
+
+    /// Set the values of named constants for every test case. A test case
+    /// can override any of them with its own (see [TestCase::with_constants]).
+    pub fn with_constants<S: Into<String>>(
+        mut self,
+        constants: impl IntoIterator<Item = (S, LangValue)>,
+    ) -> Self {
+        self.constants = constants
+            .into_iter()
+            .map(|(name, value)| (name.into(), value))
+            .collect();
+        self
+    }
+
+    /// Make sure this spec is valid, returning the first problem found. The
+    /// rules are:
+    /// - There must be at least one test case
+    /// - Each input and expected output can have at most [MAX_TEST_CASE_LENGTH]
+    ///   values
+    ///
+    /// This is called automatically when a spec is deserialized. A spec that
+    /// skips validation can still be run, but it may be slow.
+    pub fn validate(&self) -> Result<(), ProgramSpecError> {
+        if self.test_cases.is_empty() {
+            return Err(ProgramSpecError::NoTestCases);
+        }
+        for (index, test_case) in self.test_cases.iter().enumerate() {
+            for (field, values) in &[
+                ("input", &test_case.input),
+                ("expected_output", &test_case.expected_output),
+            ] {
+                if values.len() > MAX_TEST_CASE_LENGTH {
+                    return Err(ProgramSpecError::TooManyValues {
+                        test_case: index,
+                        field,
+                        max: MAX_TEST_CASE_LENGTH,
+                        actual: values.len(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get all test cases for this program spec. This is never empty.
+    pub fn test_cases(&self) -> &[TestCase] {
+        &self.test_cases
+    }
+
+    /// Get the values of the named constants that programs can reference,
+    /// e.g. `$K`, keyed by name without the `$`. These let a puzzle be
+    /// parameterized without passing the parameter through the input. The
+    /// values are filled into the program when a machine is allocated, and a
+    /// test case's own constants (see [TestCase::constants]) shadow these.
+    pub fn constants(&self) -> &BTreeMap<String, LangValue> {
+        &self.constants
+    }
+
+    /// Get the input buffer of the first test case. This is the initial value
+    /// of the input for a run of the program.
     pub fn input(&self) -> &[LangValue] {
-        &self.input
+        self.test_cases[0].input()
     }
 
-    /// Get the program spec's expected output buffer. This is the output that
-    /// a solution has to generate in order to be correct.
+    /// Get the expected output buffer of the first test case. This is the
+    /// output that a solution has to generate in order to be correct.
     pub fn expected_output(&self) -> &[LangValue] {
-        &self.expected_output
+        self.test_cases[0].expected_output()
     }
 }
 
 // Functions that get exported to wasm
-#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
 impl ProgramSpec {
-    #[cfg_attr(target_arch = "wasm32", wasm_bindgen(constructor))]
+    /// Create a program spec with a single test case.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
     pub fn new(input: Vec<LangValue>, expected_output: Vec<LangValue>) -> Self {
         ProgramSpec {
-            input,
-            expected_output,
+            test_cases: vec![TestCase::new(input, expected_output)],
+            constants: BTreeMap::new(),
         }
     }
 
     /// Version of [Self::input] to be called from wasm
-    #[cfg(target_arch = "wasm32")]
+    #[cfg(feature = "wasm")]
     #[wasm_bindgen(getter, js_name = "input")]
     pub fn wasm_input(&self) -> Vec<LangValue> {
-        self.input.clone()
+        self.input().to_vec()
     }
 
     /// Version of [Self::expected_output] to be called from wasm
-    #[cfg(target_arch = "wasm32")]
+    #[cfg(feature = "wasm")]
     #[cfg_attr(
-        target_arch = "wasm32",
+        feature = "wasm",
         wasm_bindgen(getter, js_name = "expectedOutput")
     )]
     pub fn wasm_expected_output(&self) -> Vec<LangValue> {
-        self.expected_output.clone()
+        self.expected_output().to_vec()
+    }
+}
+
+// Useful for tests and prototyping
+impl Default for ProgramSpec {
+    fn default() -> Self {
+        Self::new(Vec::new(), Vec::new())
+    }
+}
+
+/// Formats as a human-readable reference card, with a block for each test
+/// case. Every line, including the last, ends in a newline:
+///
+/// ```text
+/// Constants: $K=2
+/// Test case 1:
+///   Input: [1, 2]
+///   Expected output: [2, 4]
+/// ```
+///
+/// The constants lines (for the whole spec, and for each test case) are only
+/// there if there are any constants.
+impl Display for ProgramSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if !self.constants.is_empty() {
+            writeln!(f, "Constants: {}", format_constants(&self.constants))?;
+        }
+        for (i, test_case) in self.test_cases.iter().enumerate() {
+            writeln!(f, "Test case {}:", i + 1)?;
+            writeln!(f, "  Input: {:?}", test_case.input)?;
+            writeln!(f, "  Expected output: {:?}", test_case.expected_output)?;
+            if !test_case.constants.is_empty() {
+                writeln!(
+                    f,
+                    "  Constants: {}",
+                    format_constants(&test_case.constants)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Format constants as `$NAME=value`, separated by commas
+fn format_constants(constants: &BTreeMap<String, LangValue>) -> String {
+    constants
+        .iter()
+        .map(|(name, value)| format!("{}{}={}", CONSTANT_REF_TAG, name, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A single set of input and expected output for a program. See
+/// [ProgramSpec].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TestCase {
+    /// The input values, where the element at position 0 is the first one that
+    /// will be popped off.
+    input: Vec<LangValue>,
+    /// The correct value to be left in the output when the program exits. The
+    /// first element will be the first one pushed, and so on.
+    expected_output: Vec<LangValue>,
+    /// Values for named constants that only apply to this test case. See
+    /// [Self::constants].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    constants: BTreeMap<String, LangValue>,
+}
+
+impl TestCase {
+    pub fn new(input: Vec<LangValue>, expected_output: Vec<LangValue>) -> Self {
+        Self {
+            input,
+            expected_output,
+            constants: BTreeMap::new(),
+        }
+    }
+
+    /// Set the values of named constants for just this test case, e.g. to
+    /// vary a puzzle's parameter between test cases. These shadow the program
+    /// spec's constants with the same name.
+    pub fn with_constants<S: Into<String>>(
+        mut self,
+        constants: impl IntoIterator<Item = (S, LangValue)>,
+    ) -> Self {
+        self.constants = constants
+            .into_iter()
+            .map(|(name, value)| (name.into(), value))
+            .collect();
+        self
+    }
+
+    /// Get the values of the named constants defined by this test case. See
+    /// [ProgramSpec::constants].
+    pub fn constants(&self) -> &BTreeMap<String, LangValue> {
+        &self.constants
+    }
+
+    /// Get the test case's input buffer. This is the initial value of the
+    /// input for a run of the program.
+    pub fn input(&self) -> &[LangValue] {
+        &self.input
+    }
+
+    /// Get the test case's expected output buffer. This is the output that a
+    /// solution has to generate in order to pass.
+    pub fn expected_output(&self) -> &[LangValue] {
+        &self.expected_output
+    }
+}
+
+/// The outcome of running a program against every test case in a
+/// [ProgramSpec]. Generated by [Compiler::test_all](crate::Compiler::test_all).
+#[derive(Clone, Debug)]
+pub struct TestResults {
+    /// The result for each test case, in the same order as the spec
+    pub cases: Vec<TestCaseResult>,
+}
+
+impl TestResults {
+    /// Check if the program passed every test case.
+    pub fn successful(&self) -> bool {
+        self.cases.iter().all(TestCaseResult::successful)
     }
 }
 
+/// The outcome of running a program against a single [TestCase].
+#[derive(Clone, Debug)]
+pub struct TestCaseResult {
+    /// The number of cycles the program ran for
+    pub cycle_count: usize,
+    /// The number of values the program wrote to `RZR`
+    pub null_writes: usize,
+    /// Why the program failed this test case. `None` if it passed.
+    pub failure_reason: Option<FailureReason>,
+    /// The runtime error that halted the program, if any
+    pub error: Option<WithSource<RuntimeError>>,
+    /// The most values that each stack held at once during the run
+    pub max_stack_usage: BTreeMap<StackRef, usize>,
+}
+
+impl TestCaseResult {
+    /// Check if the program passed this test case.
+    pub fn successful(&self) -> bool {
+        self.failure_reason.is_none()
+    }
+}
+
+/// The outcome of compiling a solution and running it against every test case
+/// in a [ProgramSpec]. Generated by [run_solution](crate::run_solution).
+#[derive(Clone, Debug)]
+pub enum SolutionResult {
+    /// The solution failed to compile, so it was never run
+    CompileError(WithSource<CompileError>),
+    /// The solution compiled, but couldn't be run against the program spec,
+    /// e.g. because it uses a named constant that the spec doesn't define
+    AllocateError(AllocateError),
+    /// The solution compiled and was run against every test case
+    Executed {
+        /// The number of instructions in the compiled program
+        num_instructions: usize,
+        /// The outcome of each test case
+        results: TestResults,
+    },
+}
+
+impl SolutionResult {
+    /// Check if the solution compiled and passed every test case.
+    pub fn successful(&self) -> bool {
+        match self {
+            Self::CompileError(_) | Self::AllocateError(_) => false,
+            Self::Executed { results, .. } => results.successful(),
+        }
+    }
+}
+
+/// Generates pseudo-random [ProgramSpec]s, for fuzz testing a program against
+/// many different inputs. Generation is deterministic: the same seed always
+/// produces the same input. Used by [Compiler::fuzz](crate::Compiler::fuzz).
+#[cfg(feature = "fuzz")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProgramSpecGenerator {
+    /// The seed for the first generated spec. Each spec after that uses the
+    /// next seed in sequence.
+    seed: u64,
+    /// The range that the length of each input is picked from
+    input_length: RangeInclusive<usize>,
+    /// The range that each input value is picked from
+    values: RangeInclusive<LangValue>,
+}
+
+#[cfg(feature = "fuzz")]
+impl ProgramSpecGenerator {
+    /// Create a new generator. Panics if either range is empty.
+    pub fn new(
+        seed: u64,
+        input_length: RangeInclusive<usize>,
+        values: RangeInclusive<LangValue>,
+    ) -> Self {
+        assert!(!input_length.is_empty(), "Input length range is empty");
+        assert!(!values.is_empty(), "Value range is empty");
+        Self {
+            seed,
+            input_length,
+            values,
+        }
+    }
+
+    /// Get the seeds for the first `count` specs from this generator.
+    pub fn seeds(&self, count: usize) -> impl Iterator<Item = u64> {
+        let seed = self.seed;
+        (0..count as u64).map(move |i| seed.wrapping_add(i))
+    }
+
+    /// Generate the input for a single seed.
+    pub fn input(&self, seed: u64) -> Vec<LangValue> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let length = rng.gen_range(self.input_length.clone());
+        iter::repeat_with(|| rng.gen_range(self.values.clone()))
+            .take(length)
+            .collect()
+    }
+
+    /// Generate a program spec for a single seed. `expected_fn` computes the
+    /// expected output from the generated input.
+    pub fn generate(
+        &self,
+        seed: u64,
+        expected_fn: impl Fn(&[LangValue]) -> Vec<LangValue>,
+    ) -> ProgramSpec {
+        let input = self.input(seed);
+        let expected_output = expected_fn(&input);
+        ProgramSpec::new(input, expected_output)
+    }
+}
+
+/// The first failing case found by [Compiler::fuzz](crate::Compiler::fuzz).
+/// The seed can be passed back to [ProgramSpecGenerator::generate] to
+/// reproduce the failure.
+#[cfg(feature = "fuzz")]
+#[derive(Clone, Debug)]
+pub struct FuzzFailure {
+    /// The seed that generated the failing input
+    pub seed: u64,
+    /// The generated input
+    pub input: Vec<LangValue>,
+    /// The output that the program was expected to produce
+    pub expected_output: Vec<LangValue>,
+    /// The outcome of running the program on the input
+    pub result: TestCaseResult,
+}
+
 /// A record of **static** statistics that can be gathered about a program. We
 /// collect these statistics at compile time. They can be used to rank programs,
 /// e.g. finding the minimal number of references need to solve a problem.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
 pub struct ProgramStats {
+    /// The total number of instructions in the program. Labels aren't
+    /// instructions, so they aren't counted.
+    pub num_instructions: usize,
+    /// The number of instructions of each kind. Kinds that don't appear in the
+    /// program aren't included.
+    pub instruction_counts: BTreeMap<InstructionKind, usize>,
     /// All the registers that are referenced at least once by the program.
     /// This will include registers even if they don't get used at runtime,
     /// as we can't know at compile time which instructions will execute.
@@ -180,6 +1420,12 @@ pub struct ProgramStats {
     /// This will include stacks even if they don't get used at runtime,
     /// as we can't know at compile time which instructions will execute.
     pub referenced_stacks: HashSet<StackRef>,
+    /// How deeply jumps are nested in the program. Each jump spans the
+    /// instructions between itself and its target, and this is the most
+    /// spans that any single instruction falls under. A program without
+    /// jumps has a depth of 0, and a loop inside another loop has a depth of
+    /// at least 2.
+    pub max_jump_depth: usize,
 }
 
 #[cfg(test)]
@@ -193,9 +1439,10 @@ mod tests {
                 num_registers: 0,
                 num_stacks: 0,
                 max_stack_length: 0,
+                ..Default::default()
             }
             .all_register_refs(),
-            vec![RegisterRef::InputLength],
+            vec![RegisterRef::InputLength, RegisterRef::InputHead],
         );
 
         assert_eq!(
@@ -203,10 +1450,12 @@ mod tests {
                 num_registers: 3,
                 num_stacks: 2,
                 max_stack_length: 0,
+                ..Default::default()
             }
             .all_register_refs(),
             vec![
                 RegisterRef::InputLength,
+                RegisterRef::InputHead,
                 RegisterRef::StackLength(0),
                 RegisterRef::StackLength(1),
                 RegisterRef::User(0),
@@ -216,6 +1465,200 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_register_validity() {
+        let hardware_spec = HardwareSpec {
+            num_registers: 2,
+            num_stacks: 1,
+            max_stack_length: 4,
+            ..Default::default()
+        };
+        let check = |register| {
+            (
+                hardware_spec.is_valid_register(register),
+                hardware_spec.is_writable_register(register),
+            )
+        };
+        assert_eq!(check(RegisterRef::Null), (true, true));
+        assert_eq!(check(RegisterRef::InputLength), (true, false));
+        assert_eq!(check(RegisterRef::StackLength(0)), (true, false));
+        assert_eq!(check(RegisterRef::StackLength(1)), (false, false));
+        assert_eq!(check(RegisterRef::User(1)), (true, true));
+        assert_eq!(check(RegisterRef::User(2)), (false, false));
+        // RZC only exists if it's turned on, and is never writable
+        assert_eq!(check(RegisterRef::NullCount), (false, false));
+        let hardware_spec = HardwareSpec {
+            null_count_enabled: true,
+            ..hardware_spec.clone()
+        };
+        assert!(hardware_spec.is_valid_register(RegisterRef::NullCount));
+        assert!(!hardware_spec.is_writable_register(RegisterRef::NullCount));
+
+        // RZR and RLI exist even on the smallest hardware
+        let hardware_spec = HardwareSpec {
+            num_registers: 0,
+            num_stacks: 0,
+            max_stack_length: 0,
+            ..Default::default()
+        };
+        assert!(hardware_spec.is_valid_register(RegisterRef::Null));
+        assert!(hardware_spec.is_valid_register(RegisterRef::InputLength));
+        assert!(!hardware_spec.is_valid_register(RegisterRef::StackLength(0)));
+        assert!(!hardware_spec.is_valid_register(RegisterRef::User(0)));
+    }
+
+    #[test]
+    fn test_deserialize_program_spec() {
+        // Legacy format, with a single test case
+        assert_eq!(
+            serde_json::from_str::<ProgramSpec>(
+                r#"{"input": [1, 2], "expected_output": [3]}"#
+            )
+            .unwrap(),
+            ProgramSpec::new(vec![1, 2], vec![3])
+        );
+
+        // Multiple test cases
+        assert_eq!(
+            serde_json::from_str::<ProgramSpec>(
+                r#"{"test_cases": [
+                    {"input": [1, 2], "expected_output": [3]},
+                    {"input": [], "expected_output": []}
+                ]}"#
+            )
+            .unwrap(),
+            ProgramSpec::with_test_cases(vec![
+                TestCase::new(vec![1, 2], vec![3]),
+                TestCase::new(vec![], vec![]),
+            ])
+        );
+
+        // Constants can go on the spec or on each test case
+        assert_eq!(
+            serde_json::from_str::<ProgramSpec>(
+                r#"{
+                    "constants": {"K": 2},
+                    "test_cases": [
+                        {"input": [1], "expected_output": [2]},
+                        {
+                            "input": [1],
+                            "expected_output": [3],
+                            "constants": {"K": 3}
+                        }
+                    ]
+                }"#
+            )
+            .unwrap(),
+            ProgramSpec::with_test_cases(vec![
+                TestCase::new(vec![1], vec![2]),
+                TestCase::new(vec![1], vec![3]).with_constants(vec![("K", 3)]),
+            ])
+            .with_constants(vec![("K", 2)])
+        );
+        assert_eq!(
+            serde_json::from_str::<ProgramSpec>(
+                r#"{"input": [], "expected_output": [], "constants": {"K": 2}}"#
+            )
+            .unwrap(),
+            ProgramSpec::with_test_cases(vec![
+                TestCase::new(vec![], vec![]).with_constants(vec![("K", 2)])
+            ])
+        );
+
+        // Need at least one test case
+        assert_eq!(
+            serde_json::from_str::<ProgramSpec>(r#"{"test_cases": []}"#)
+                .unwrap_err()
+                .to_string(),
+            "Program spec must have at least one test case"
+        );
+
+        // Values have to fit in a LangValue
+        let too_big = format!(
+            r#"{{"input": [{}], "expected_output": []}}"#,
+            LangValue::MAX as i128 + 1
+        );
+        assert!(serde_json::from_str::<ProgramSpec>(&too_big).is_err());
+        // The format is the same for either width, so existing specs still
+        // load with 64-bit values
+        #[cfg(feature = "i64-values")]
+        assert_eq!(
+            serde_json::from_str::<ProgramSpec>(
+                r#"{"input": [2147483648], "expected_output": [-1]}"#
+            )
+            .unwrap(),
+            ProgramSpec::new(vec![2147483648], vec![-1])
+        );
+    }
+
+    #[test]
+    fn test_program_spec_length() {
+        let values = |len: usize| vec![0; len];
+
+        // Right at the limit is fine
+        let program_spec = ProgramSpec::new(
+            values(MAX_TEST_CASE_LENGTH),
+            values(MAX_TEST_CASE_LENGTH),
+        );
+        assert_eq!(program_spec.validate(), Ok(()));
+        let serialized = serde_json::to_string(&program_spec).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ProgramSpec>(&serialized).unwrap(),
+            program_spec
+        );
+
+        // One past it isn't
+        assert_eq!(
+            ProgramSpec::new(values(MAX_TEST_CASE_LENGTH + 1), vec![])
+                .validate(),
+            Err(ProgramSpecError::TooManyValues {
+                test_case: 0,
+                field: "input",
+                max: MAX_TEST_CASE_LENGTH,
+                actual: MAX_TEST_CASE_LENGTH + 1,
+            })
+        );
+        let program_spec = ProgramSpec::with_test_cases(vec![
+            TestCase::new(vec![], vec![]),
+            TestCase::new(vec![], values(MAX_TEST_CASE_LENGTH + 1)),
+        ]);
+        let serialized = serde_json::to_string(&program_spec).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ProgramSpec>(&serialized)
+                .unwrap_err()
+                .to_string(),
+            "Expected expected_output of test case 2 to have at most 4096 \
+                values, but got 4097"
+        );
+    }
+
+    #[test]
+    fn test_serialize_program_spec() {
+        // Round trip should give the same spec
+        let program_spec = ProgramSpec::with_test_cases(vec![
+            TestCase::new(vec![1], vec![1]),
+            TestCase::new(vec![2], vec![2]),
+        ]);
+        let serialized = serde_json::to_string(&program_spec).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ProgramSpec>(&serialized).unwrap(),
+            program_spec
+        );
+        // Constants are left out if there aren't any
+        assert!(!serialized.contains("constants"), "{}", serialized);
+
+        let program_spec =
+            ProgramSpec::with_test_cases(vec![
+                TestCase::new(vec![1], vec![1]).with_constants(vec![("N", 4)])
+            ])
+            .with_constants(vec![("K", 2)]);
+        let serialized = serde_json::to_string(&program_spec).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ProgramSpec>(&serialized).unwrap(),
+            program_spec
+        );
+    }
+
     #[test]
     fn test_all_stack_refs() {
         assert_eq!(
@@ -223,6 +1666,7 @@ mod tests {
                 num_registers: 0,
                 num_stacks: 0,
                 max_stack_length: 0,
+                ..Default::default()
             }
             .all_stack_refs(),
             vec![],
@@ -233,9 +1677,615 @@ mod tests {
                 num_registers: 3,
                 num_stacks: 2,
                 max_stack_length: 0,
+                ..Default::default()
             }
             .all_stack_refs(),
             vec![StackRef(0), StackRef(1),],
         );
     }
+
+    #[test]
+    fn test_try_new() {
+        assert_eq!(
+            HardwareSpec::try_new(1, 0, 0),
+            Ok(HardwareSpec::new(1, 0, 0))
+        );
+        assert_eq!(
+            HardwareSpec::try_new(16, 16, 256),
+            Ok(HardwareSpec::new(16, 16, 256))
+        );
+        assert_eq!(
+            HardwareSpec::try_new(0, 0, 0),
+            Err(SpecError::OutOfBounds {
+                field: "num_registers",
+                min: 1,
+                max: 16,
+                actual: 0
+            })
+        );
+        assert_eq!(
+            HardwareSpec::try_new(1, 17, 0),
+            Err(SpecError::OutOfBounds {
+                field: "num_stacks",
+                min: 0,
+                max: 16,
+                actual: 17
+            })
+        );
+        assert_eq!(
+            HardwareSpec::try_new(1, 1, 257),
+            Err(SpecError::OutOfBounds {
+                field: "max_stack_length",
+                min: 0,
+                max: 256,
+                actual: 257
+            })
+        );
+    }
+
+    #[test]
+    fn test_presets() {
+        assert_eq!(HardwareSpec::minimal(), HardwareSpec::new(1, 0, 0));
+        assert_eq!(HardwareSpec::minimal(), HardwareSpec::default());
+        assert_eq!(HardwareSpec::standard(), HardwareSpec::new(4, 2, 16));
+        assert_eq!(HardwareSpec::maximal(), HardwareSpec::new(16, 16, 256));
+        for spec in &[
+            HardwareSpec::minimal(),
+            HardwareSpec::standard(),
+            HardwareSpec::maximal(),
+        ] {
+            assert_eq!(spec.validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_builder() {
+        assert_eq!(
+            HardwareSpec::builder().build(),
+            Ok(HardwareSpec::minimal())
+        );
+        assert_eq!(
+            HardwareSpec::builder()
+                .registers(2)
+                .stacks(1)
+                .stack_length(8)
+                .register_names(vec!["FUEL", "TOTAL"])
+                .stack_names(vec!["CARGO"])
+                .cost_model(
+                    CostModel::default().with_cost(InstructionKind::Push, 2)
+                )
+                .allowed_instructions(vec![InstructionKind::Read])
+                .extern_ops(1)
+                .build(),
+            Ok(HardwareSpec {
+                num_registers: 2,
+                num_stacks: 1,
+                max_stack_length: 8,
+                register_names: vec!["FUEL".into(), "TOTAL".into()],
+                stack_names: vec!["CARGO".into()],
+                cost_model: CostModel::default()
+                    .with_cost(InstructionKind::Push, 2),
+                allowed_instructions: Some(
+                    vec![InstructionKind::Read].into_iter().collect()
+                ),
+                num_extern_ops: 1,
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_builder_invalid() {
+        assert_eq!(
+            HardwareSpec::builder().registers(0).build(),
+            Err(SpecError::OutOfBounds {
+                field: "num_registers",
+                min: 1,
+                max: 16,
+                actual: 0
+            })
+        );
+        assert_eq!(
+            HardwareSpec::builder().stacks(1).stack_length(257).build(),
+            Err(SpecError::OutOfBounds {
+                field: "max_stack_length",
+                min: 0,
+                max: 256,
+                actual: 257
+            })
+        );
+        assert_eq!(
+            HardwareSpec::builder()
+                .registers(2)
+                .register_names(vec!["FUEL"])
+                .build(),
+            Err(SpecError::NameCountMismatch {
+                kind: "register",
+                expected: 2,
+                actual: 1
+            })
+        );
+        assert_eq!(
+            HardwareSpec::builder()
+                .cost_model(
+                    CostModel::default().with_cost(InstructionKind::Add, 0)
+                )
+                .build(),
+            Err(SpecError::ZeroCost(InstructionKind::Add))
+        );
+        // The error is readable on its own
+        assert_eq!(
+            HardwareSpec::builder()
+                .stacks(1)
+                .stack_names(vec!["S0"])
+                .build()
+                .unwrap_err()
+                .to_string(),
+            "Name `S0` is reserved for a built-in register or stack"
+        );
+    }
+
+    #[test]
+    fn test_validate_names() {
+        let spec = |register_names: &[&str], stack_names: &[&str]| {
+            HardwareSpec {
+                num_registers: 2,
+                num_stacks: 1,
+                max_stack_length: 5,
+                register_names: register_names
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect(),
+                stack_names: stack_names
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect(),
+                ..Default::default()
+            }
+            .validate()
+        };
+
+        assert_eq!(spec(&[], &[]), Ok(()));
+        assert_eq!(spec(&["FUEL", "_total2"], &["CARGO"]), Ok(()));
+        assert_eq!(
+            spec(&["FUEL"], &[]),
+            Err(SpecError::NameCountMismatch {
+                kind: "register",
+                expected: 2,
+                actual: 1
+            })
+        );
+        assert_eq!(
+            spec(&[], &["A", "B"]),
+            Err(SpecError::NameCountMismatch {
+                kind: "stack",
+                expected: 1,
+                actual: 2
+            })
+        );
+        assert_eq!(
+            spec(&["FUEL", "2FAST"], &[]),
+            Err(SpecError::InvalidName("2FAST".into()))
+        );
+        assert_eq!(
+            spec(&["FUEL", "NO-DASH"], &[]),
+            Err(SpecError::InvalidName("NO-DASH".into()))
+        );
+        assert_eq!(
+            spec(&["FUEL", ""], &[]),
+            Err(SpecError::InvalidName("".into()))
+        );
+        for reserved in &["rzr", "RLI", "rih", "rzc", "RS0", "rx12", "S3"] {
+            assert_eq!(
+                spec(&["FUEL", reserved], &[]),
+                Err(SpecError::ReservedName(reserved.to_string()))
+            );
+        }
+        // These only look similar to built-in names
+        assert_eq!(spec(&["RX", "STACK"], &["RSX"]), Ok(()));
+        assert_eq!(
+            spec(&["FUEL", "TOTAL"], &["fuel"]),
+            Err(SpecError::DuplicateName("fuel".into()))
+        );
+    }
+
+    #[test]
+    fn test_violations() {
+        assert_eq!(HardwareSpec::default().violations(), vec![]);
+
+        // Every problem is reported, not just the first
+        let spec = HardwareSpec {
+            num_registers: 0,
+            num_stacks: 2,
+            max_stack_length: 300,
+            register_names: vec!["RX0".into()],
+            stack_names: vec!["CARGO".into(), "cargo".into()],
+            cost_model: CostModel::default()
+                .with_cost(InstructionKind::Push, 0),
+            ..Default::default()
+        };
+        let violations: Vec<String> = spec
+            .violations()
+            .iter()
+            .map(|violation| violation.to_string())
+            .collect();
+        assert_eq!(
+            violations,
+            vec![
+                "/num_registers: Expected num_registers to be between 1 and \
+                    16, but got 0",
+                "/max_stack_length: Expected max_stack_length to be between 0 \
+                    and 256, but got 300",
+                "/register_names: Expected 0 register names, but got 1",
+                "/register_names/0: Name `RX0` is reserved for a built-in \
+                    register or stack",
+                "/stack_names/1: Name `cargo` is used more than once",
+                "/cost_model/PUSH: Cost of `PUSH` must be at least 1 cycle",
+            ]
+        );
+        assert_eq!(
+            spec.validate(),
+            Err(SpecError::OutOfBounds {
+                field: "num_registers",
+                min: 1,
+                max: 16,
+                actual: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_deserialize_hardware_spec() {
+        // Names are optional
+        assert_eq!(
+            serde_json::from_str::<HardwareSpec>(
+                r#"{"num_registers": 1, "num_stacks": 0, "max_stack_length": 0}"#
+            )
+            .unwrap(),
+            HardwareSpec::new(1, 0, 0)
+        );
+        assert_eq!(
+            serde_json::from_str::<HardwareSpec>(
+                r#"{
+                    "num_registers": 1,
+                    "num_stacks": 1,
+                    "max_stack_length": 0,
+                    "register_names": ["FUEL"],
+                    "stack_names": ["CARGO"]
+                }"#
+            )
+            .unwrap()
+            .register_names,
+            vec!["FUEL".to_string()]
+        );
+        // Invalid names are rejected
+        assert!(serde_json::from_str::<HardwareSpec>(
+            r#"{
+                "num_registers": 1,
+                "num_stacks": 0,
+                "max_stack_length": 0,
+                "register_names": ["RX0"]
+            }"#
+        )
+        .is_err());
+        // Specs outside the allowed ranges still load, since older specs
+        // weren't held to them, but they don't pass validation
+        for (json, field) in &[
+            (
+                r#"{"num_registers": 0, "num_stacks": 0, "max_stack_length": 0}"#,
+                "num_registers",
+            ),
+            (
+                r#"{"num_registers": 1, "num_stacks": 1, "max_stack_length": 1000}"#,
+                "max_stack_length",
+            ),
+        ] {
+            let spec = serde_json::from_str::<HardwareSpec>(json).unwrap();
+            assert!(matches!(
+                spec.validate(),
+                Err(SpecError::OutOfBounds { field: f, .. }) if f == *field
+            ));
+        }
+        // Overflow behavior is optional too
+        assert_eq!(
+            serde_json::from_str::<HardwareSpec>(
+                r#"{
+                    "num_registers": 1,
+                    "num_stacks": 0,
+                    "max_stack_length": 0,
+                    "overflow_behavior": "Saturate"
+                }"#
+            )
+            .unwrap()
+            .overflow_behavior,
+            OverflowBehavior::Saturate
+        );
+        assert!(serde_json::from_str::<HardwareSpec>(
+            r#"{
+                "num_registers": 1,
+                "num_stacks": 0,
+                "max_stack_length": 0,
+                "overflow_behavior": "Explode"
+            }"#
+        )
+        .is_err());
+        // Leaving out the instruction list allows everything
+        assert!(HardwareSpec::new(1, 0, 0)
+            .is_instruction_allowed(InstructionKind::Mul));
+        let spec = serde_json::from_str::<HardwareSpec>(
+            r#"{
+                "num_registers": 1,
+                "num_stacks": 0,
+                "max_stack_length": 0,
+                "allowed_instructions": ["READ", "WRITE"]
+            }"#,
+        )
+        .unwrap();
+        assert!(spec.is_instruction_allowed(InstructionKind::Read));
+        assert!(!spec.is_instruction_allowed(InstructionKind::Mul));
+    }
+
+    #[test]
+    fn test_stack_lengths() {
+        let spec = HardwareSpec::builder()
+            .stacks(2)
+            .stack_lengths(vec![4, 64])
+            .build()
+            .unwrap();
+        assert_eq!(spec.max_stack_length, 64);
+        assert_eq!(spec.stack_capacity(StackRef(0)), 4);
+        assert_eq!(spec.stack_capacity(StackRef(1)), 64);
+        // Without per-stack lengths, every stack gets the max
+        assert_eq!(HardwareSpec::standard().stack_capacity(StackRef(1)), 16);
+
+        // Either field can be given, or both
+        let deserialize = |json: &str| {
+            serde_json::from_str::<HardwareSpec>(json)
+                .map_err(|err| err.to_string())
+        };
+        assert_eq!(
+            deserialize(
+                r#"{"num_registers": 1, "num_stacks": 2, "stack_lengths": [4, 64]}"#
+            ),
+            Ok(spec)
+        );
+        assert_eq!(
+            deserialize(
+                r#"{
+                    "num_registers": 1,
+                    "num_stacks": 2,
+                    "max_stack_length": 100,
+                    "stack_lengths": [4, 64]
+                }"#
+            )
+            .unwrap()
+            .max_stack_length,
+            100
+        );
+        assert_eq!(
+            deserialize(r#"{"num_registers": 1, "num_stacks": 0}"#),
+            Err("Expected either max_stack_length or stack_lengths".into())
+        );
+
+        let spec = HardwareSpec {
+            num_stacks: 2,
+            max_stack_length: 8,
+            stack_lengths: vec![4, 16, 2],
+            ..Default::default()
+        };
+        let violations: Vec<String> = spec
+            .violations()
+            .iter()
+            .map(|violation| violation.to_string())
+            .collect();
+        assert_eq!(
+            violations,
+            vec![
+                "/stack_lengths: Expected 2 stack lengths, but got 3",
+                "/stack_lengths/1: Expected stack_lengths to be between 0 and \
+                    8, but got 16",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_names() {
+        let spec = HardwareSpec {
+            num_registers: 1,
+            num_stacks: 1,
+            max_stack_length: 0,
+            register_names: vec!["FUEL".into()],
+            stack_names: vec!["CARGO".into()],
+            ..Default::default()
+        };
+        assert_eq!(spec.register_name(RegisterRef::User(0)), "FUEL");
+        assert_eq!(spec.register_name(RegisterRef::StackLength(0)), "RS0");
+        assert_eq!(spec.stack_name(StackRef(0)), "CARGO");
+        assert_eq!(
+            HardwareSpec::default().register_name(RegisterRef::User(0)),
+            "RX0"
+        );
+        assert_eq!(HardwareSpec::default().stack_name(StackRef(0)), "S0");
+    }
+
+    #[test]
+    fn test_cost_model() {
+        let spec: HardwareSpec = serde_json::from_str(
+            r#"{
+                "num_registers": 1,
+                "num_stacks": 0,
+                "max_stack_length": 0,
+                "cost_model": {"PUSH": 2, "DIV": 3}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(spec.cost_model.cost_of(InstructionKind::Push), 2);
+        assert_eq!(spec.cost_model.cost_of(InstructionKind::Div), 3);
+        assert_eq!(spec.cost_model.cost_of(InstructionKind::Read), 1);
+        assert_eq!(HardwareSpec::default().cost_model, CostModel::default());
+
+        // Zero-cost instructions aren't allowed
+        assert_eq!(
+            HardwareSpec {
+                cost_model: CostModel::default()
+                    .with_cost(InstructionKind::Jmp, 0),
+                ..Default::default()
+            }
+            .validate(),
+            Err(SpecError::ZeroCost(InstructionKind::Jmp))
+        );
+        // Neither are instructions that cost more than a program can take
+        assert_eq!(
+            HardwareSpec {
+                cost_model: CostModel::default()
+                    .with_cost(InstructionKind::Write, usize::MAX),
+                ..Default::default()
+            }
+            .validate(),
+            Err(SpecError::CostTooHigh {
+                kind: InstructionKind::Write,
+                max: MAX_CYCLE_COUNT,
+                actual: usize::MAX
+            })
+        );
+        assert!(serde_json::from_str::<HardwareSpec>(
+            r#"{
+                "num_registers": 1,
+                "num_stacks": 0,
+                "max_stack_length": 0,
+                "cost_model": {"NOP": 0}
+            }"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_display_hardware_spec() {
+        assert_eq!(
+            HardwareSpec::default().to_string(),
+            "Registers: 1
+Stacks: 0
+Max stack length: 0
+Valid registers: RZR, RLI, RIH, RX0
+"
+        );
+        assert_eq!(
+            HardwareSpec {
+                num_registers: 2,
+                num_stacks: 1,
+                max_stack_length: 8,
+                register_names: vec!["FUEL".into(), "X".into()],
+                stack_names: vec!["CARGO".into()],
+                ..Default::default()
+            }
+            .to_string(),
+            "Registers: 2
+Stacks: 1
+Max stack length: 8
+Valid registers: RZR, RLI, RIH, RS0, RX0 (FUEL), RX1 (X)
+Valid stacks: S0 (CARGO)
+"
+        );
+        assert_eq!(
+            HardwareSpec::builder()
+                .stacks(2)
+                .stack_lengths(vec![4, 64])
+                .build()
+                .unwrap()
+                .to_string(),
+            "Registers: 1
+Stacks: 2
+Max stack length: 64
+Valid registers: RZR, RLI, RIH, RS0, RS1, RX0
+Valid stacks: S0, S1
+Stack lengths: S0=4, S1=64
+"
+        );
+        // Optional properties only show up when they aren't the default
+        assert_eq!(
+            HardwareSpec {
+                num_registers: 1,
+                num_stacks: 2,
+                max_stack_length: 4,
+                dump_enabled: true,
+                null_count_enabled: true,
+                cost_model: CostModel::default()
+                    .with_cost(InstructionKind::Push, 2)
+                    .with_cost(InstructionKind::Div, 3),
+                overflow_behavior: OverflowBehavior::Saturate,
+                allowed_instructions: Some(
+                    vec![
+                        InstructionKind::Write,
+                        InstructionKind::Read,
+                        InstructionKind::Push,
+                    ]
+                    .into_iter()
+                    .collect()
+                ),
+                max_call_depth: 0,
+                num_extern_ops: 2,
+                initial_state: InitialState::Random { seed: 42 },
+                max_instructions: Some(10),
+                ..Default::default()
+            }
+            .to_string(),
+            "Registers: 1
+Stacks: 2
+Max stack length: 4
+Valid registers: RZR, RLI, RIH, RZC, RS0, RS1, RX0
+Valid stacks: S0, S1
+Overflow behavior: Saturate
+Max call depth: 0
+Extern ops: 2
+Initial state: random (seed 42)
+Max instructions: 10
+Allowed instructions: READ, WRITE, PUSH
+Instruction costs: DIV=3, PUSH=2
+DUMP: enabled
+"
+        );
+    }
+
+    #[test]
+    fn test_display_program_spec() {
+        assert_eq!(
+            ProgramSpec::default().to_string(),
+            "Test case 1:
+  Input: []
+  Expected output: []
+"
+        );
+        assert_eq!(
+            ProgramSpec::with_test_cases(vec![
+                TestCase::new(vec![1, 2], vec![2, 4]),
+                TestCase::new(vec![-3], vec![-6]),
+            ])
+            .to_string(),
+            "Test case 1:
+  Input: [1, 2]
+  Expected output: [2, 4]
+Test case 2:
+  Input: [-3]
+  Expected output: [-6]
+"
+        );
+        assert_eq!(
+            ProgramSpec::with_test_cases(vec![
+                TestCase::new(vec![1, 2], vec![2, 4]),
+                TestCase::new(vec![-3], vec![-9])
+                    .with_constants(vec![("K", 3)]),
+            ])
+            .with_constants(vec![("K", 2), ("B", 0)])
+            .to_string(),
+            "Constants: $B=0, $K=2
+Test case 1:
+  Input: [1, 2]
+  Expected output: [2, 4]
+Test case 2:
+  Input: [-3]
+  Expected output: [-9]
+  Constants: $K=3
+"
+        );
+    }
 }
@@ -0,0 +1,484 @@
+//! Optional passes over a compiled program, which make it cheaper to run
+//! without changing its output. These are only run if enabled by
+//! [CompileOptions](crate::CompileOptions).
+
+use crate::{
+    ast::{
+        compiled, Instruction, InstructionKind, LangValue, Node, RegisterRef,
+        SpanNode, UserRegisterId, ValueSource,
+    },
+    delabel::max_jump_depth,
+    machine::{
+        apply_arithmetic, compare, ArithmeticOps, BitwiseFn, ADD_OPS, AND_OP,
+        DIV_OPS, MUL_OPS, OR_OP, SHL_OP, SHR_OP, SUB_OPS, XOR_OP,
+    },
+    models::OverflowBehavior,
+    util::Span,
+    Compiler, HardwareSpec,
+};
+use std::collections::{HashMap, HashSet};
+
+/// A register value that's known at compile time
+#[derive(Clone, Debug)]
+struct KnownValue {
+    value: LangValue,
+    /// Spans of every instruction that the value was computed from
+    spans: Vec<Span>,
+}
+
+/// Register values that are known at a certain point in the program
+type KnownValues = HashMap<UserRegisterId, KnownValue>;
+
+/// A register that gets set to a value known at compile time, and that value
+type ConstantDef = (SpanNode<UserRegisterId>, LangValue);
+
+/// An instruction, along with the spans of everything that contributed to it
+type FoldedInstruction = (SpanNode<Instruction<Span>>, Vec<Span>);
+
+/// Get the value of a source, if it's known at compile time.
+fn known_value(
+    known: &KnownValues,
+    src: &SpanNode<ValueSource<Span>>,
+) -> Option<LangValue> {
+    match src.value() {
+        ValueSource::Const(Node(value, _)) => Some(*value),
+        // Reading the null register always gives zero
+        ValueSource::Register(Node(RegisterRef::Null, _)) => Some(0),
+        ValueSource::Register(Node(RegisterRef::User(id), _)) => {
+            known.get(id).map(|known| known.value)
+        }
+        // Input and stack lengths can only be known at runtime
+        ValueSource::Register(_) => None,
+        // Named constants come from the program spec, which isn't known yet
+        ValueSource::Constant(_) => None,
+    }
+}
+
+/// Get the user register that a destination refers to. Writes to any other
+/// register are thrown away, so there's nothing to fold.
+fn user_register(
+    dst: &SpanNode<RegisterRef>,
+) -> Option<SpanNode<UserRegisterId>> {
+    match dst.value() {
+        RegisterRef::User(id) => Some(Node(*id, *dst.metadata())),
+        _ => None,
+    }
+}
+
+/// Fold an arithmetic instruction, if both operands are known. Operations
+/// that would trigger a runtime error are never folded, so the error still
+/// happens at runtime.
+fn fold_arithmetic(
+    overflow_behavior: OverflowBehavior,
+    known: &KnownValues,
+    dst: &SpanNode<RegisterRef>,
+    src: &SpanNode<ValueSource<Span>>,
+    ops: ArithmeticOps,
+) -> Option<ConstantDef> {
+    let dst = user_register(dst)?;
+    let lhs = known.get(dst.value())?.value;
+    let rhs = known_value(known, src)?;
+    Some((dst, apply_arithmetic(overflow_behavior, lhs, rhs, ops)?))
+}
+
+/// Fold a bitwise instruction, if both operands are known. These can't fail,
+/// so there's no overflow to worry about.
+fn fold_bitwise(
+    known: &KnownValues,
+    dst: &SpanNode<RegisterRef>,
+    src: &SpanNode<ValueSource<Span>>,
+    op: BitwiseFn,
+) -> Option<ConstantDef> {
+    let dst = user_register(dst)?;
+    let lhs = known.get(dst.value())?.value;
+    Some((dst, op(lhs, known_value(known, src)?)))
+}
+
+/// If an instruction does nothing but store a value that's known at compile
+/// time, get the register and the value.
+fn constant_def(
+    hardware_spec: &HardwareSpec,
+    known: &KnownValues,
+    instruction: &Instruction<Span>,
+) -> Option<ConstantDef> {
+    let overflow_behavior = hardware_spec.overflow_behavior;
+    match instruction {
+        Instruction::Set(dst, src) => {
+            Some((user_register(dst)?, known_value(known, src)?))
+        }
+        Instruction::Add(dst, src) => {
+            fold_arithmetic(overflow_behavior, known, dst, src, ADD_OPS)
+        }
+        Instruction::Sub(dst, src) => {
+            fold_arithmetic(overflow_behavior, known, dst, src, SUB_OPS)
+        }
+        Instruction::Mul(dst, src) => {
+            fold_arithmetic(overflow_behavior, known, dst, src, MUL_OPS)
+        }
+        // Dividing by zero is a runtime error, so leave it be
+        Instruction::Div(dst, src) if known_value(known, src) != Some(0) => {
+            fold_arithmetic(overflow_behavior, known, dst, src, DIV_OPS)
+        }
+        Instruction::And(dst, src) => fold_bitwise(known, dst, src, AND_OP),
+        Instruction::Or(dst, src) => fold_bitwise(known, dst, src, OR_OP),
+        Instruction::Xor(dst, src) => fold_bitwise(known, dst, src, XOR_OP),
+        Instruction::Shl(dst, src) => fold_bitwise(known, dst, src, SHL_OP),
+        Instruction::Shr(dst, src) => fold_bitwise(known, dst, src, SHR_OP),
+        Instruction::Cmp(dst, src_1, src_2) => Some((
+            user_register(dst)?,
+            compare(known_value(known, src_1)?, known_value(known, src_2)?),
+        )),
+        _ => None,
+    }
+}
+
+/// Get every user register that an instruction reads from.
+fn read_registers(instruction: &Instruction<Span>) -> Vec<UserRegisterId> {
+    let mut registers: Vec<&RegisterRef> = Vec::new();
+    let mut sources: Vec<&SpanNode<ValueSource<Span>>> = Vec::new();
+    match instruction {
+        Instruction::Write(src)
+        | Instruction::Set(_, src)
+        | Instruction::Push(src, _)
+        | Instruction::Popn(_, src)
+        | Instruction::Jez(src, _)
+        | Instruction::Jnz(src, _)
+        | Instruction::Jlz(src, _)
+        | Instruction::Jgz(src, _) => sources.push(src),
+        Instruction::Swp(reg_1, reg_2) => {
+            registers.push(reg_1.value());
+            registers.push(reg_2.value());
+        }
+        Instruction::Jri(reg) | Instruction::Ext(_, reg) => {
+            registers.push(reg.value())
+        }
+        // Arithmetic reads its destination as the first operand
+        Instruction::Add(dst, src)
+        | Instruction::Sub(dst, src)
+        | Instruction::Mul(dst, src)
+        | Instruction::Div(dst, src)
+        | Instruction::And(dst, src)
+        | Instruction::Or(dst, src)
+        | Instruction::Xor(dst, src)
+        | Instruction::Shl(dst, src)
+        | Instruction::Shr(dst, src) => {
+            registers.push(dst.value());
+            sources.push(src);
+        }
+        Instruction::Cmp(_, src_1, src_2) => {
+            sources.push(src_1);
+            sources.push(src_2);
+        }
+        _ => {}
+    }
+    registers.extend(sources.into_iter().filter_map(|src| match src.value() {
+        ValueSource::Register(reg) => Some(reg.value()),
+        ValueSource::Const(_) | ValueSource::Constant(_) => None,
+    }));
+    registers
+        .into_iter()
+        .filter_map(|reg| match reg {
+            RegisterRef::User(id) => Some(*id),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Check if an arithmetic instruction is certain to fail at runtime, because
+/// both of its operands are known and the operation on them is an error.
+/// These never get folded, but the error should still point back to wherever
+/// the operands came from.
+fn known_failure(
+    hardware_spec: &HardwareSpec,
+    known: &KnownValues,
+    instruction: &Instruction<Span>,
+) -> bool {
+    let (dst, src, ops) = match instruction {
+        Instruction::Add(dst, src) => (dst, src, ADD_OPS),
+        Instruction::Sub(dst, src) => (dst, src, SUB_OPS),
+        Instruction::Mul(dst, src) => (dst, src, MUL_OPS),
+        Instruction::Div(dst, src) => (dst, src, DIV_OPS),
+        _ => return false,
+    };
+    let lhs = user_register(dst)
+        .and_then(|Node(id, _)| known.get(&id).map(|known| known.value));
+    match (lhs, known_value(known, src)) {
+        (Some(_), Some(0)) if matches!(instruction, Instruction::Div(_, _)) => {
+            true
+        }
+        (Some(lhs), Some(rhs)) => {
+            apply_arithmetic(hardware_spec.overflow_behavior, lhs, rhs, ops)
+                .is_none()
+        }
+        _ => false,
+    }
+}
+
+/// Check if an instruction can trigger a runtime error, depending on values
+/// that aren't known at compile time. The machine stops on an error with its
+/// registers as they are, so a stored value has to stay if one of these runs
+/// before the value gets overwritten. Errors that any instruction can trigger
+/// (e.g. running out of cycles) aren't considered. `CALL` and `RET` can fail
+/// too, but they're handled as jumps anyway.
+fn can_fail(
+    hardware_spec: &HardwareSpec,
+    instruction: &Instruction<Span>,
+) -> bool {
+    match instruction {
+        Instruction::Read(_)
+        | Instruction::Div(_, _)
+        | Instruction::Push(_, _)
+        | Instruction::Pop(_, _)
+        | Instruction::Peek(_, _)
+        | Instruction::Popn(_, _)
+        | Instruction::Dup(_)
+        | Instruction::Sswp(_)
+        | Instruction::Ext(_, _) => true,
+        Instruction::Add(_, _)
+        | Instruction::Sub(_, _)
+        | Instruction::Mul(_, _) => {
+            hardware_spec.overflow_behavior == OverflowBehavior::Error
+        }
+        _ => false,
+    }
+}
+
+/// Sort spans by their position in the source, and remove duplicates
+fn sort_spans(spans: &mut Vec<Span>) {
+    spans.sort_by_key(|span| (span.source_id, span.offset));
+    spans.dedup();
+}
+
+/// Get the spans of every instruction that the known values read by an
+/// instruction were computed from
+fn operand_spans(
+    known: &KnownValues,
+    instruction: &Instruction<Span>,
+) -> Vec<Span> {
+    read_registers(instruction)
+        .iter()
+        .filter_map(|id| known.get(id))
+        .flat_map(|known| known.spans.iter().copied())
+        .collect()
+}
+
+/// Get every user register that an instruction writes to.
+fn written_registers(instruction: &Instruction<Span>) -> Vec<UserRegisterId> {
+    let registers = match instruction {
+        Instruction::Read(dst)
+        | Instruction::Rdz(dst)
+        | Instruction::Set(dst, _)
+        | Instruction::Add(dst, _)
+        | Instruction::Sub(dst, _)
+        | Instruction::Mul(dst, _)
+        | Instruction::Div(dst, _)
+        | Instruction::And(dst, _)
+        | Instruction::Or(dst, _)
+        | Instruction::Xor(dst, _)
+        | Instruction::Shl(dst, _)
+        | Instruction::Shr(dst, _)
+        | Instruction::Cmp(dst, _, _)
+        | Instruction::Pop(_, dst)
+        | Instruction::Peek(_, dst)
+        | Instruction::Ext(_, dst) => vec![dst],
+        Instruction::Swp(reg_1, reg_2) => vec![reg_1, reg_2],
+        _ => vec![],
+    };
+    registers
+        .into_iter()
+        .filter_map(|reg| user_register(reg).map(|Node(id, _)| id))
+        .collect()
+}
+
+impl Compiler<compiled::Program<Span>> {
+    /// Propagate register values that are known at compile time through
+    /// straight-line code. Any instruction that stores a known value (e.g.
+    /// `ADD` on two known operands) is replaced with a `SET` of that value.
+    /// If that overwrites an earlier known value that was never read, the
+    /// earlier instruction is removed, unless something in between can
+    /// trigger a runtime error, since the error would observe the earlier
+    /// value.
+    ///
+    /// Each `SET` keeps the span of the instruction it replaced, and lists
+    /// the spans of every instruction its value was computed from, including
+    /// removed ones, as contributing spans (see
+    /// [compiled::Program::contributing_spans]). An arithmetic instruction
+    /// that's certain to fail does the same for its operands, so that the
+    /// error can point back to them.
+    ///
+    /// Nothing is known at the start of the program, at any jump target, or
+    /// after an unconditional jump, `CALL`, or `RET`, since execution can
+    /// arrive there with any state. Values read from the input or a stack are
+    /// never known.
+    ///
+    /// Programs with a computed jump (`JRI`) are left as-is. A computed jump
+    /// can land on any instruction, so nothing is ever known, and its targets
+    /// are instruction indexes, which would shift if anything got removed.
+    pub(crate) fn fold_constants(self) -> Self {
+        if self.ast.instructions.iter().any(|Node(instruction, _)| {
+            matches!(instruction, Instruction::Jri(_))
+        }) {
+            return self;
+        }
+
+        let hardware_spec = &self.hardware_spec;
+        let mut program = self.ast;
+        // Folding only pays off if a SET is allowed, and doesn't cost more
+        // than what it replaces
+        let set_cost = hardware_spec.cost_model.cost_of(InstructionKind::Set);
+        let can_fold = |kind| {
+            hardware_spec.is_instruction_allowed(InstructionKind::Set)
+                && set_cost <= hardware_spec.cost_model.cost_of(kind)
+        };
+        let jump_targets: HashSet<usize> =
+            program.symbol_table.values().copied().collect();
+
+        // Removed instructions are left as `None` for now, so that indexes
+        // still line up with the original program. Each one is paired with
+        // its contributing spans.
+        let mut instructions: Vec<Option<FoldedInstruction>> =
+            Vec::with_capacity(program.instructions.len());
+        let mut known = KnownValues::new();
+        // For each register, the index of the last constant def to it, if the
+        // value it stored hasn't been read yet
+        let mut unread: HashMap<UserRegisterId, usize> = HashMap::new();
+
+        for (index, Node(instruction, span)) in
+            program.instructions.into_iter().enumerate()
+        {
+            if jump_targets.contains(&index) {
+                known.clear();
+                unread.clear();
+            }
+            let mut contributing = program
+                .contributing_spans
+                .remove(&index)
+                .unwrap_or_default();
+
+            let def = if can_fold(instruction.kind()) {
+                constant_def(hardware_spec, &known, &instruction)
+            } else {
+                None
+            };
+            if let Some((dst, value)) = def {
+                contributing.extend(operand_spans(&known, &instruction));
+                // The last value stored here was never read, so drop it
+                if let Some(unread_index) = unread.insert(*dst.value(), index) {
+                    let (Node(_, unread_span), unread_contributing) =
+                        instructions[unread_index].take().unwrap();
+                    contributing.push(unread_span);
+                    contributing.extend(unread_contributing);
+                }
+                sort_spans(&mut contributing);
+                contributing
+                    .retain(|contributing_span| *contributing_span != span);
+
+                let mut spans = contributing.clone();
+                spans.push(span);
+                known.insert(*dst.value(), KnownValue { value, spans });
+                instructions.push(Some((
+                    Node(
+                        Instruction::Set(
+                            dst.map(RegisterRef::User),
+                            Node(ValueSource::Const(Node(value, span)), span),
+                        ),
+                        span,
+                    ),
+                    contributing,
+                )));
+                continue;
+            }
+
+            if known_failure(hardware_spec, &known, &instruction) {
+                contributing.extend(operand_spans(&known, &instruction));
+                sort_spans(&mut contributing);
+            }
+
+            // An error here would stop the machine with every stored value
+            // still in place
+            if can_fail(hardware_spec, &instruction) {
+                unread.clear();
+            }
+            for id in read_registers(&instruction) {
+                unread.remove(&id);
+            }
+            for id in written_registers(&instruction) {
+                known.remove(&id);
+                unread.remove(&id);
+            }
+            match instruction {
+                // Execution can continue anywhere, with any state
+                Instruction::Jmp(_)
+                | Instruction::Call(_)
+                | Instruction::Ret => {
+                    known.clear();
+                    unread.clear();
+                }
+                // These may observe any register, either here or wherever
+                // they jump to
+                Instruction::Jez(_, _)
+                | Instruction::Jnz(_, _)
+                | Instruction::Jlz(_, _)
+                | Instruction::Jgz(_, _)
+                | Instruction::Dump => unread.clear(),
+                _ => {}
+            }
+            instructions.push(Some((Node(instruction, span), contributing)));
+        }
+
+        // Map each original index to its index in the new program. Removed
+        // instructions map to whatever comes after them, and a label can
+        // point one past the last instruction, hence the extra slot.
+        let mut new_indexes = Vec::with_capacity(instructions.len() + 1);
+        let mut kept = Vec::with_capacity(instructions.len());
+        let mut contributing_spans = HashMap::new();
+        for instruction in instructions {
+            new_indexes.push(kept.len());
+            if let Some((instruction, contributing)) = instruction {
+                if !contributing.is_empty() {
+                    contributing_spans.insert(kept.len(), contributing);
+                }
+                kept.push(instruction);
+            }
+        }
+        new_indexes.push(kept.len());
+
+        let symbol_table = program
+            .symbol_table
+            .into_iter()
+            .map(|(label, index)| (label, new_indexes[index]))
+            .collect();
+        let labels = program
+            .labels
+            .into_iter()
+            .map(|(label, index)| (label, new_indexes[index]))
+            .collect();
+
+        // Keep the stats in line with the instructions that will actually run
+        let mut stats = program.stats;
+        stats.num_instructions = kept.len();
+        stats.instruction_counts.clear();
+        for Node(instruction, _) in &kept {
+            *stats
+                .instruction_counts
+                .entry(instruction.kind())
+                .or_insert(0) += 1;
+        }
+        stats.max_jump_depth = max_jump_depth(&kept, &symbol_table);
+
+        Compiler {
+            source: self.source,
+            hardware_spec: self.hardware_spec,
+            includes: self.includes,
+            ast: compiled::Program {
+                instructions: kept,
+                symbol_table,
+                stats,
+                labels,
+                contributing_spans,
+            },
+            warnings: self.warnings,
+            metadata: self.metadata,
+        }
+    }
+}
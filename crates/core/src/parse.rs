@@ -1,33 +1,52 @@
 use crate::{
     ast::{
-        source::{LabelDecl, Program, Statement},
-        Instruction, Label, LangValue, Node, RegisterRef, SpanNode, StackId,
-        StackRef, UserRegisterId, ValueSource,
+        source::{Comment, LabelDecl, Program, Statement},
+        ExternOpId, Instruction, InstructionKind, Label, LangValue, Node,
+        RegisterRef, SpanNode, StackId, StackRef, UserRegisterId, ValueSource,
     },
     consts::{
-        INPUT_LENGTH_REGISTER_REF, NULL_REGISTER_REF,
-        STACK_LENGTH_REGISTER_REF_TAG, STACK_REF_TAG, USER_REGISTER_REF_TAG,
+        CONSTANT_REF_TAG, HEX_PREFIX, INCLUDE_DIRECTIVE,
+        INPUT_HEAD_REGISTER_REF, INPUT_LENGTH_REGISTER_REF, MAX_REF_ID,
+        NULL_COUNT_REGISTER_REF, NULL_REGISTER_REF,
+        STACK_LENGTH_REGISTER_REF_TAG, STACK_REF_TAG, STATEMENT_SEPARATOR,
+        USER_REGISTER_REF_TAG,
     },
     error::{CompileError, SourceErrorWrapper, WithSource},
-    util::{RawSpan, Span},
+    include::{IncludedSource, Includes, NoIncludes, SourceResolver},
+    metadata,
+    models::HardwareSpec,
+    util::{edit_distance, ParseContext, RawSpan, Span},
     Compiler,
 };
 use nom::{
     branch::alt,
     bytes::complete::{is_not, tag, tag_no_case, take_while1},
-    character::complete::{char, digit1, line_ending, space0, space1},
-    combinator::{all_consuming, cut, map, map_res, opt, peek, recognize},
-    error::{
-        context, make_error, ErrorKind, ParseError, VerboseError,
-        VerboseErrorKind,
+    character::complete::{
+        char, digit1, hex_digit1, line_ending, none_of, space0, space1,
     },
-    multi::{many0, many1},
+    combinator::{cut, map, map_opt, map_res, opt, peek, recognize, verify},
+    error::{context, ErrorKind, ParseError, VerboseError, VerboseErrorKind},
+    multi::{many0, separated_list1},
     sequence::{delimited, preceded, terminated, tuple},
     IResult, Offset, Slice,
 };
+use serde::Serialize;
+use std::fmt::{self, Display, Formatter};
 
 type ParseResult<'a, T> = IResult<RawSpan<'a>, T, VerboseError<RawSpan<'a>>>;
 
+/// Context label for a register/stack/extern op ID that's bigger than
+/// [MAX_REF_ID].
+/// [convert_error] turns this into [CompileError::RefIdTooLarge] instead of a
+/// generic syntax error.
+const REF_ID_TOO_LARGE: &str = "ID";
+
+/// Context label for a word at the start of a statement that isn't an
+/// instruction. [convert_error] turns this into
+/// [CompileError::UnknownInstruction], with a suggestion if there's a close
+/// enough match.
+const UNKNOWN_INSTRUCTION: &str = "instruction";
+
 /// A trait for parsing into AST nodes. Any AST node that can be parsed from the
 /// source should implement this trait.
 trait Parse<'a>: Sized {
@@ -50,28 +69,73 @@ trait Parse<'a>: Sized {
     }
 }
 
-// covers StackId and UserRegisterId
+// covers StackId, UserRegisterId, and ExternOpId
 impl<'a> Parse<'a> for usize {
     fn parse(input: RawSpan<'a>) -> ParseResult<'a, Self> {
-        map_res(digit1, |s: RawSpan| {
-            let frag = s.fragment();
-
-            // If the string has unnecessary leading zeroes, reject it. Use an
-            // empty error for convenience, its value won't be used anyway.
-            if frag.len() > 1 && frag.starts_with('0') {
-                Err(())
-            } else {
-                s.fragment().parse::<usize>().map_err(|_| ())
-            }
-        })(input)
+        let (rest, digits) = digit1(input)?;
+        let frag = digits.fragment();
+
+        // If the string has unnecessary leading zeroes, reject it
+        if frag.len() > 1 && frag.starts_with('0') {
+            return Err(nom::Err::Error(VerboseError::from_error_kind(
+                input,
+                ErrorKind::Digit,
+            )));
+        }
+        // Anything too big gets its own error, instead of a confusing "expected
+        // register reference". The digits are already known to be valid, so
+        // the parse can only fail from overflow.
+        match frag.parse::<usize>() {
+            Ok(id) if id <= MAX_REF_ID => Ok((rest, id)),
+            _ => Err(nom::Err::Failure(VerboseError {
+                errors: vec![(
+                    digits,
+                    VerboseErrorKind::Context(REF_ID_TOO_LARGE),
+                )],
+            })),
+        }
     }
 }
 
 impl<'a> Parse<'a> for LangValue {
     fn parse(input: RawSpan<'a>) -> ParseResult<'a, Self> {
-        map_res(recognize(tuple((opt(char('-')), digit1))), |s: RawSpan| {
-            s.fragment().parse::<LangValue>()
-        })(input)
+        alt((
+            // "0x1F" => 31. Once we see the prefix, the rest has to be hex.
+            preceded(
+                tag_no_case(HEX_PREFIX),
+                cut(context(
+                    "hex literal",
+                    map_res(hex_digit1, |s: RawSpan| {
+                        LangValue::from_str_radix(s.fragment(), 16)
+                    }),
+                )),
+            ),
+            // "-0x1F" => error. Negative values have to be written in decimal.
+            preceded(
+                peek(preceded(char('-'), tag_no_case(HEX_PREFIX))),
+                cut(context(
+                    "hex literal without a sign",
+                    map_res(char('-'), |_| Err(())),
+                )),
+            ),
+            // "'A'" => 65. Quotes and newlines can't be used as characters.
+            preceded(
+                char('\''),
+                cut(context(
+                    "character literal",
+                    terminated(
+                        // Every char fits in a LangValue
+                        map(none_of("'\r\n"), |c| u32::from(c) as LangValue),
+                        char('\''),
+                    ),
+                )),
+            ),
+            // "-10" => -10
+            map_res(
+                recognize(tuple((opt(char('-')), digit1))),
+                |s: RawSpan| s.fragment().parse::<LangValue>(),
+            ),
+        ))(input)
     }
 }
 
@@ -92,22 +156,40 @@ impl<'a> Parse<'a> for LabelDecl {
 
 impl<'a> Parse<'a> for StackRef {
     fn parse(input: RawSpan<'a>) -> ParseResult<'a, Self> {
-        map(
-            preceded(tag_no_case(STACK_REF_TAG), StackId::parse),
-            StackRef,
-        )(input)
+        let stack_names = &input.extra.hardware_spec.stack_names;
+        alt((
+            // "CARGO" => StackRef(x), if the hardware has that alias
+            map(move |i| alias(i, stack_names), StackRef),
+            // "Sx" => StackRef(x)
+            map(
+                preceded(tag_no_case(STACK_REF_TAG), StackId::parse),
+                StackRef,
+            ),
+        ))(input)
     }
 }
 
 impl<'a> Parse<'a> for RegisterRef {
     fn parse(input: RawSpan<'a>) -> ParseResult<'a, Self> {
+        let register_names = &input.extra.hardware_spec.register_names;
         alt((
+            // "FUEL" => RegisterRef::User(x), if the hardware has that alias.
+            // This has to go first, because the other parsers can cut.
+            map(move |i| alias(i, register_names), RegisterRef::User),
             // "RZR" => RegisterRef::Null
             map(tag_no_case(NULL_REGISTER_REF), |_| RegisterRef::Null),
             // "RLI" => RegisterRef::InputLength
             map(tag_no_case(INPUT_LENGTH_REGISTER_REF), |_| {
                 RegisterRef::InputLength
             }),
+            // "RIH" => RegisterRef::InputHead
+            map(tag_no_case(INPUT_HEAD_REGISTER_REF), |_| {
+                RegisterRef::InputHead
+            }),
+            // "RZC" => RegisterRef::NullCount
+            map(tag_no_case(NULL_COUNT_REGISTER_REF), |_| {
+                RegisterRef::NullCount
+            }),
             // "RSx" => RegisterRef::StackLength(x)
             map(
                 preceded(
@@ -135,14 +217,304 @@ impl<'a> Parse<'a> for ValueSource<Span> {
             map(LangValue::parse_node, ValueSource::Const),
             // "RX1" => register
             map(RegisterRef::parse_node, ValueSource::Register),
+            // "$K" => named constant. Once we see the prefix, the rest has to
+            // be a name.
+            map(
+                preceded(
+                    tag(CONSTANT_REF_TAG),
+                    cut(context("constant name", Label::parse_node)),
+                ),
+                ValueSource::Constant,
+            ),
         ))(input)
     }
 }
 
+// ===== Instruction catalog =====
+
+/// The kinds of arguments that an instruction can take
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum ArgKind {
+    /// A register that gets written to
+    WritableRegister,
+    /// Any readable register
+    Register,
+    /// A constant or any readable register
+    Value,
+    /// A stack
+    Stack,
+    /// A label, declared elsewhere in the program
+    Label,
+    /// The ID of an extern op
+    ExternOp,
+}
+
+impl Display for ArgKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::WritableRegister => "writable register",
+            Self::Register => "register",
+            Self::Value => "value",
+            Self::Stack => "stack",
+            Self::Label => "label",
+            Self::ExternOp => "extern op",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Documentation for a single instruction. See [INSTRUCTION_CATALOG].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct InstructionInfo {
+    pub kind: InstructionKind,
+    /// The arguments that the instruction takes, in order
+    pub args: &'static [ArgKind],
+    /// A one-line description of what the instruction does
+    pub description: &'static str,
+    /// The codes of the runtime errors that the instruction can cause (see
+    /// [SourceError::error_code](crate::error::SourceError::error_code)).
+    /// Errors that any instruction can cause, like running out of cycles,
+    /// aren't included.
+    pub errors: &'static [&'static str],
+}
+
+impl InstructionInfo {
+    /// Check if programs on the given hardware can use this instruction.
+    /// Beyond [HardwareSpec::is_instruction_allowed], some instructions only
+    /// exist if the hardware has what they need.
+    pub fn is_available(&self, hardware_spec: &HardwareSpec) -> bool {
+        hardware_spec.is_instruction_allowed(self.kind)
+            && match self.kind {
+                InstructionKind::Dump => hardware_spec.dump_enabled,
+                InstructionKind::Call | InstructionKind::Ret => {
+                    hardware_spec.max_call_depth > 0
+                }
+                InstructionKind::Ext => hardware_spec.num_extern_ops > 0,
+                _ => true,
+            }
+    }
+}
+
+/// Every instruction, with the syntax that the parser accepts for it, in the
+/// same order as [InstructionKind::ALL]. This has to be kept in sync with the
+/// parser below.
+pub const INSTRUCTION_CATALOG: &[InstructionInfo] = {
+    use ArgKind::*;
+    use InstructionKind as Kind;
+    &[
+        InstructionInfo {
+            kind: Kind::Read,
+            args: &[WritableRegister],
+            description: "Read the next value from the input into a register",
+            errors: &["R002_EMPTY_INPUT"],
+        },
+        InstructionInfo {
+            kind: Kind::Rdz,
+            args: &[WritableRegister],
+            description: "Read the next value from the input into a \
+                register, or zero if the input is empty",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Write,
+            args: &[Value],
+            description: "Write a value to the output",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Set,
+            args: &[WritableRegister, Value],
+            description: "Set a register to a value",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Swp,
+            args: &[WritableRegister, WritableRegister],
+            description: "Swap the values of two registers",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Add,
+            args: &[WritableRegister, Value],
+            description: "Add a value to a register",
+            errors: &["R006_ARITHMETIC_OVERFLOW"],
+        },
+        InstructionInfo {
+            kind: Kind::Sub,
+            args: &[WritableRegister, Value],
+            description: "Subtract a value from a register",
+            errors: &["R006_ARITHMETIC_OVERFLOW"],
+        },
+        InstructionInfo {
+            kind: Kind::Mul,
+            args: &[WritableRegister, Value],
+            description: "Multiply a register by a value",
+            errors: &["R006_ARITHMETIC_OVERFLOW"],
+        },
+        InstructionInfo {
+            kind: Kind::Div,
+            args: &[WritableRegister, Value],
+            description: "Divide a register by a value, rounding toward zero",
+            errors: &["R001_DIVIDE_BY_ZERO", "R006_ARITHMETIC_OVERFLOW"],
+        },
+        InstructionInfo {
+            kind: Kind::And,
+            args: &[WritableRegister, Value],
+            description: "Bitwise AND a register with a value",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Or,
+            args: &[WritableRegister, Value],
+            description: "Bitwise OR a register with a value",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Xor,
+            args: &[WritableRegister, Value],
+            description: "Bitwise XOR a register with a value",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Shl,
+            args: &[WritableRegister, Value],
+            description: "Shift a register left by a number of bits, which \
+                wraps around at the width of a value",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Shr,
+            args: &[WritableRegister, Value],
+            description: "Shift a register right by a number of bits, keeping \
+                the sign, which wraps around at the width of a value",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Cmp,
+            args: &[WritableRegister, Value, Value],
+            description: "Set a register to -1, 0, or 1 if the first value is \
+                less than, equal to, or greater than the second",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Push,
+            args: &[Value, Stack],
+            description: "Push a value onto a stack",
+            errors: &["R003_STACK_OVERFLOW"],
+        },
+        InstructionInfo {
+            kind: Kind::Pop,
+            args: &[Stack, WritableRegister],
+            description: "Pop the top value off a stack into a register",
+            errors: &["R004_EMPTY_STACK"],
+        },
+        InstructionInfo {
+            kind: Kind::Peek,
+            args: &[Stack, WritableRegister],
+            description: "Copy the top value of a stack into a register, \
+                without popping it",
+            errors: &["R004_EMPTY_STACK"],
+        },
+        InstructionInfo {
+            kind: Kind::Popn,
+            args: &[Stack, Value],
+            description: "Pop a number of values off a stack and throw them \
+                away",
+            errors: &["R004_EMPTY_STACK"],
+        },
+        InstructionInfo {
+            kind: Kind::Dup,
+            args: &[Stack],
+            description: "Push a copy of the top value of a stack onto it",
+            errors: &["R003_STACK_OVERFLOW", "R004_EMPTY_STACK"],
+        },
+        InstructionInfo {
+            kind: Kind::Sswp,
+            args: &[Stack],
+            description: "Swap the top two values of a stack",
+            errors: &["R004_EMPTY_STACK"],
+        },
+        InstructionInfo {
+            kind: Kind::Jmp,
+            args: &[Label],
+            description: "Jump to a label",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Jez,
+            args: &[Value, Label],
+            description: "Jump to a label if a value is zero",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Jnz,
+            args: &[Value, Label],
+            description: "Jump to a label if a value is not zero",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Jlz,
+            args: &[Value, Label],
+            description: "Jump to a label if a value is less than zero",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Jgz,
+            args: &[Value, Label],
+            description: "Jump to a label if a value is greater than zero",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Jri,
+            args: &[Register],
+            description: "Jump to the instruction whose index is in a \
+                register",
+            errors: &["R010_INVALID_JUMP_TARGET"],
+        },
+        InstructionInfo {
+            kind: Kind::Call,
+            args: &[Label],
+            description: "Jump to a label, and come back here on `RET`",
+            errors: &["R007_CALL_STACK_OVERFLOW"],
+        },
+        InstructionInfo {
+            kind: Kind::Ret,
+            args: &[],
+            description: "Return to the instruction after the last `CALL`",
+            errors: &["R008_RETURN_WITHOUT_CALL"],
+        },
+        InstructionInfo {
+            kind: Kind::Nop,
+            args: &[],
+            description: "Do nothing",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Dump,
+            args: &[],
+            description: "Save a snapshot of all registers and stacks, for \
+                debugging",
+            errors: &[],
+        },
+        InstructionInfo {
+            kind: Kind::Ext,
+            args: &[ExternOp, WritableRegister],
+            description: "Pass a register's value to an extern op, and \
+                replace it with the result",
+            errors: &["R011_NO_EXTERN_HANDLER", "R012_EXTERN_FAILED"],
+        },
+    ]
+};
+
 impl<'a> Parse<'a> for Instruction<Span> {
     fn parse(input: RawSpan<'a>) -> ParseResult<'a, Self> {
         alt((
-            tag_with_args("READ", register_ref_arg, Instruction::Read),
+            // Input instructions, grouped for the same reason as the stack
+            // instructions below
+            alt((
+                tag_with_args("READ", register_ref_arg, Instruction::Read),
+                tag_with_args("RDZ", register_ref_arg, Instruction::Rdz),
+            )),
             tag_with_args("WRITE", value_source_arg, Instruction::Write),
             tag_with_args(
                 "SET",
@@ -150,40 +522,90 @@ impl<'a> Parse<'a> for Instruction<Span> {
                 |(dst, src)| Instruction::Set(dst, src),
             ),
             tag_with_args(
-                "ADD",
-                tuple((register_ref_arg, value_source_arg)),
-                |(dst, src)| Instruction::Add(dst, src),
-            ),
-            tag_with_args(
-                "SUB",
-                tuple((register_ref_arg, value_source_arg)),
-                |(dst, src)| Instruction::Sub(dst, src),
-            ),
-            tag_with_args(
-                "MUL",
-                tuple((register_ref_arg, value_source_arg)),
-                |(dst, src)| Instruction::Mul(dst, src),
-            ),
-            tag_with_args(
-                "DIV",
-                tuple((register_ref_arg, value_source_arg)),
-                |(dst, src)| Instruction::Div(dst, src),
+                "SWP",
+                tuple((register_ref_arg, register_ref_arg)),
+                |(reg_1, reg_2)| Instruction::Swp(reg_1, reg_2),
             ),
+            // Arithmetic and bitwise instructions, grouped for the same
+            // reason as the stack instructions below
+            alt((
+                tag_with_args(
+                    "ADD",
+                    tuple((register_ref_arg, value_source_arg)),
+                    |(dst, src)| Instruction::Add(dst, src),
+                ),
+                tag_with_args(
+                    "SUB",
+                    tuple((register_ref_arg, value_source_arg)),
+                    |(dst, src)| Instruction::Sub(dst, src),
+                ),
+                tag_with_args(
+                    "MUL",
+                    tuple((register_ref_arg, value_source_arg)),
+                    |(dst, src)| Instruction::Mul(dst, src),
+                ),
+                tag_with_args(
+                    "DIV",
+                    tuple((register_ref_arg, value_source_arg)),
+                    |(dst, src)| Instruction::Div(dst, src),
+                ),
+                tag_with_args(
+                    "AND",
+                    tuple((register_ref_arg, value_source_arg)),
+                    |(dst, src)| Instruction::And(dst, src),
+                ),
+                tag_with_args(
+                    "OR",
+                    tuple((register_ref_arg, value_source_arg)),
+                    |(dst, src)| Instruction::Or(dst, src),
+                ),
+                tag_with_args(
+                    "XOR",
+                    tuple((register_ref_arg, value_source_arg)),
+                    |(dst, src)| Instruction::Xor(dst, src),
+                ),
+                tag_with_args(
+                    "SHL",
+                    tuple((register_ref_arg, value_source_arg)),
+                    |(dst, src)| Instruction::Shl(dst, src),
+                ),
+                tag_with_args(
+                    "SHR",
+                    tuple((register_ref_arg, value_source_arg)),
+                    |(dst, src)| Instruction::Shr(dst, src),
+                ),
+            )),
             tag_with_args(
                 "CMP",
                 tuple((register_ref_arg, value_source_arg, value_source_arg)),
                 |(dst, src_1, src_2)| Instruction::Cmp(dst, src_1, src_2),
             ),
-            tag_with_args(
-                "PUSH",
-                tuple((value_source_arg, stack_ref_arg)),
-                |(src, stack)| Instruction::Push(src, stack),
-            ),
-            tag_with_args(
-                "POP",
-                tuple((stack_ref_arg, register_ref_arg)),
-                |(stack, dst)| Instruction::Pop(stack, dst),
-            ),
+            // Stack instructions. These are grouped because alt() can only
+            // take so many parsers at once.
+            alt((
+                tag_with_args(
+                    "PUSH",
+                    tuple((value_source_arg, stack_ref_arg)),
+                    |(src, stack)| Instruction::Push(src, stack),
+                ),
+                tag_with_args(
+                    "POPN",
+                    tuple((stack_ref_arg, value_source_arg)),
+                    |(stack, count)| Instruction::Popn(stack, count),
+                ),
+                tag_with_args(
+                    "POP",
+                    tuple((stack_ref_arg, register_ref_arg)),
+                    |(stack, dst)| Instruction::Pop(stack, dst),
+                ),
+                tag_with_args(
+                    "PEEK",
+                    tuple((stack_ref_arg, register_ref_arg)),
+                    |(stack, dst)| Instruction::Peek(stack, dst),
+                ),
+                tag_with_args("DUP", stack_ref_arg, Instruction::Dup),
+                tag_with_args("SSWP", stack_ref_arg, Instruction::Sswp),
+            )),
             tag_with_args("JMP", label_arg, Instruction::Jmp),
             tag_with_args(
                 "JEZ",
@@ -205,6 +627,16 @@ impl<'a> Parse<'a> for Instruction<Span> {
                 tuple((value_source_arg, label_arg)),
                 |(val_src, label)| Instruction::Jlz(val_src, label),
             ),
+            tag_with_args("JRI", register_ref_arg, Instruction::Jri),
+            tag_with_args("CALL", label_arg, Instruction::Call),
+            tag_no_args("RET", Instruction::Ret),
+            tag_no_args("NOP", Instruction::Nop),
+            tag_no_args("DUMP", Instruction::Dump),
+            tag_with_args(
+                "EXT",
+                tuple((extern_op_arg, register_ref_arg)),
+                |(id, reg)| Instruction::Ext(id, reg),
+            ),
         ))(input)
     }
 }
@@ -218,36 +650,6 @@ impl<'a> Parse<'a> for Statement<Span> {
     }
 }
 
-impl<'a> Parse<'a> for Program<Span> {
-    fn parse(input: RawSpan<'a>) -> ParseResult<'a, Self> {
-        context(
-            "program",
-            map_res(
-                all_consuming(many1(line)),
-                // filter out None lines
-                |lines| {
-                    let body: Vec<_> = lines.into_iter().flatten().collect();
-
-                    // If the program is empty, that's no bueno
-                    if body.is_empty() {
-                        Err(
-                            make_error::<RawSpan<'a>, VerboseError<RawSpan<'a>>>(
-                                input,
-                                // The error we pick here doesn't matter much
-                                // since the message will be determined by
-                                // the context
-                                ErrorKind::Many1,
-                            ),
-                        )
-                    } else {
-                        Ok(Program { body })
-                    }
-                },
-            ),
-        )(input)
-    }
-}
-
 // ===== Combinators =====
 
 fn arg<'a, O, F>(
@@ -297,8 +699,34 @@ where
     )
 }
 
+/// Parses one instruction keyword that takes no arguments, and returns the
+/// given value.
+fn tag_no_args<'a, O: Clone>(
+    instr_name: &'static str,
+    value: O,
+) -> impl FnMut(RawSpan<'a>) -> ParseResult<'a, O> {
+    map(
+        terminated(tag_no_case(instr_name), stmt_token_terminator),
+        move |_| value.clone(),
+    )
+}
+
 // ===== Parsers =====
 
+/// Parse an identifier that matches one of the given aliases
+/// (case-insensitive), and return the index of the alias. Fails without
+/// cutting if there is no match, so that other parsers can be tried.
+fn alias<'a>(input: RawSpan<'a>, names: &[String]) -> ParseResult<'a, usize> {
+    map_opt(
+        take_while1(|c: char| c.is_alphanumeric() || c == '_'),
+        |s: RawSpan| {
+            names
+                .iter()
+                .position(|name| name.eq_ignore_ascii_case(s.fragment()))
+        },
+    )(input)
+}
+
 /// Parse a [RegisterRef] argument to an instruction
 fn register_ref_arg(input: RawSpan) -> ParseResult<'_, SpanNode<RegisterRef>> {
     arg("register reference", RegisterRef::parse_node)(input)
@@ -316,17 +744,42 @@ fn value_source_arg(
     arg("value", ValueSource::parse_node)(input)
 }
 
+/// Parse an [ExternOpId] argument to an instruction
+fn extern_op_arg(input: RawSpan) -> ParseResult<'_, SpanNode<ExternOpId>> {
+    arg("extern op ID", ExternOpId::parse_node)(input)
+}
+
 /// Parse a [Label] argument to an instruction
 fn label_arg(input: RawSpan) -> ParseResult<'_, SpanNode<Label>> {
     arg("label", Label::parse_node)(input)
 }
 
+/// Parse any tokens after an instruction's arguments, up to the end of the
+/// line, a comment, or a statement separator. Instructions take a fixed number
+/// of arguments, so anything here is an error, but parsing it lets us point out
+/// exactly which tokens are extra. Fails if there are no extra tokens.
+fn extra_args(input: RawSpan) -> ParseResult<'_, Span> {
+    map(
+        preceded(
+            space1,
+            recognize(separated_list1(space1, is_not(" \t\r\n;|"))),
+        ),
+        |raw_span: RawSpan| Span::from_raw_span(&raw_span),
+    )(input)
+}
+
 /// The terminator that always follows a token in a statement (which is an
 /// instruction, argument, or label declaration). This does not consume the
 /// terminator, just check that it exists.
 fn stmt_token_terminator(input: RawSpan) -> ParseResult<'_, RawSpan> {
     // we don't want to eat a trailing newline, just check if it's there
-    peek(alt((space1, eol_or_eof)))(input)
+    peek(alt((space1, eol_or_eof, tag(STATEMENT_SEPARATOR))))(input)
+}
+
+/// Parse the separator between two statements on the same line, along with
+/// any whitespace around it
+fn statement_separator(input: RawSpan) -> ParseResult<'_, RawSpan> {
+    delimited(space0, tag(STATEMENT_SEPARATOR), space0)(input)
 }
 
 /// Parse a line ending, or return success if the input is empty (we've reached
@@ -340,29 +793,159 @@ fn eol_or_eof(input: RawSpan) -> ParseResult<'_, RawSpan> {
 }
 
 /// Parses a line comment, which starts with a ; and runs to the end of the
-/// line. This terminates at the line ending, but does _not_ consume it.
-fn line_comment(input: RawSpan) -> ParseResult<'_, ()> {
+/// line. This terminates at the line ending, but does _not_ consume it. The
+/// returned text doesn't include the leading ;
+fn line_comment(input: RawSpan) -> ParseResult<'_, SpanNode<String>> {
     map(
-        preceded(char(';'), many0(is_not("\r\n"))),
-        |_| (), // throw the comment away
+        recognize(preceded(char(';'), many0(is_not("\r\n")))),
+        |raw_span: RawSpan| {
+            Node(
+                raw_span.fragment()[1..].to_owned(),
+                Span::from_raw_span(&raw_span),
+            )
+        },
     )(input)
 }
 
 /// Parse everything that can go after a statement on a line: whitespace and
 /// an optional comment. Also parses the line ending, or up to the end of file.
-fn end_of_statement(input: RawSpan) -> ParseResult<'_, ()> {
+fn end_of_statement(
+    input: RawSpan,
+) -> ParseResult<'_, Option<SpanNode<String>>> {
     // Don't include the beginning whitespace in the context
     preceded(
         space0,
         context(
             "end of statement",
-            map(terminated(opt(line_comment), eol_or_eof), |_| ()),
+            terminated(opt(line_comment), eol_or_eof),
         ),
     )(input)
 }
 
+/// Parse an include directive, e.g. `%include "lib.gdlk"`. The returned path
+/// doesn't include the quotes, and neither does its span.
+fn include_directive(input: RawSpan) -> ParseResult<'_, SpanNode<String>> {
+    preceded(
+        tag_no_case(INCLUDE_DIRECTIVE),
+        cut(context(
+            "include path",
+            preceded(
+                space1,
+                delimited(
+                    char('"'),
+                    map(is_not("\"\r\n"), |raw_span: RawSpan| {
+                        Node(
+                            (*raw_span.fragment()).to_owned(),
+                            Span::from_raw_span(&raw_span),
+                        )
+                    }),
+                    char('"'),
+                ),
+            ),
+        )),
+    )(input)
+}
+
+/// A statement, along with the span of any extra arguments if it's an
+/// instruction with too many of them
+type ParsedStatement = (SpanNode<Statement<Span>>, Option<Span>);
+
+/// The meaningful part of a line, i.e. everything but the comment
+enum LineContent {
+    Empty,
+    /// One or more statements, separated by [STATEMENT_SEPARATOR]
+    Statements(Vec<ParsedStatement>),
+    /// An include directive, with the path to include
+    Include(SpanNode<String>),
+}
+
+/// Everything that can be on one line: statements or an include directive, a
+/// comment, both, or neither
+type Line = (LineContent, Option<SpanNode<Comment>>);
+
+/// Parse a single statement. For instructions, this also picks up any extra
+/// arguments, so they can be reported as such instead of as a generic syntax
+/// error.
+fn statement(input: RawSpan) -> ParseResult<'_, ParsedStatement> {
+    let (input, statement) =
+        alt((Statement::parse_node, unknown_instruction))(input)?;
+    let (input, extra_args) = match statement.value() {
+        Statement::Instruction(_) => opt(extra_args)(input)?,
+        Statement::Label(_) => (input, None),
+    };
+    Ok((input, (statement, extra_args)))
+}
+
+/// Parse every statement on a line. Statements are separated by
+/// [STATEMENT_SEPARATOR], and there has to be a statement on both sides of
+/// each separator.
+fn statements(input: RawSpan) -> ParseResult<'_, LineContent> {
+    let (input, first) = context("statement", statement)(input)?;
+    let (input, rest) = many0(preceded(
+        statement_separator,
+        // Without the cut, a missing statement would put us back before the
+        // separator, and give a less helpful error
+        cut(context("statement", statement)),
+    ))(input)?;
+    let mut statements = vec![first];
+    statements.extend(rest);
+    Ok((input, LineContent::Statements(statements)))
+}
+
+/// Tried after every valid statement fails to parse. If the statement starts
+/// with a word that isn't an instruction, e.g. `REED RX0`, fail on just that
+/// word, so the user gets a better error than "expected statement". Anything
+/// else fails without cutting, and gets the generic error.
+fn unknown_instruction(
+    input: RawSpan,
+) -> ParseResult<'_, SpanNode<Statement<Span>>> {
+    let (_, word) = terminated(
+        verify(
+            take_while1(|c: char| c.is_alphanumeric() || c == '_'),
+            |s: &RawSpan| !s.fragment().starts_with(|c: char| c.is_numeric()),
+        ),
+        stmt_token_terminator,
+    )(input)?;
+    // Known instructions report their own errors, this is just a safeguard
+    if InstructionKind::ALL
+        .iter()
+        .any(|kind| kind.name().eq_ignore_ascii_case(word.fragment()))
+    {
+        return Err(nom::Err::Error(VerboseError::from_error_kind(
+            input,
+            ErrorKind::Verify,
+        )));
+    }
+    Err(nom::Err::Failure(VerboseError {
+        errors: vec![(word, VerboseErrorKind::Context(UNKNOWN_INSTRUCTION))],
+    }))
+}
+
+/// Find the instruction that an unknown word was most likely meant to be, if
+/// any is close enough. We allow one typo for every four characters, so
+/// that short words don't match everything.
+fn suggest_instruction(found: &str) -> Option<&'static str> {
+    let found = found.to_ascii_uppercase();
+    let max_distance = 1 + found.chars().count().saturating_sub(1) / 4;
+    InstructionKind::ALL
+        .iter()
+        .map(|kind| (kind.name(), edit_distance(&found, kind.name())))
+        .filter(|(_, distance)| *distance <= max_distance)
+        // min_by_key keeps the first of any ties, so earlier instructions win
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(name, _)| name)
+}
+
+/// Attach a parsed comment's text to its position on the line
+fn comment(
+    text: Option<SpanNode<String>>,
+    trailing: bool,
+) -> Option<SpanNode<Comment>> {
+    text.map(|text| text.map(|text| Comment { text, trailing }))
+}
+
 /// Parse a single line, up to and including either end of line or end of file.
-fn line(input: RawSpan) -> ParseResult<'_, Option<SpanNode<Statement<Span>>>> {
+fn line(input: RawSpan) -> ParseResult<'_, Line> {
     if input.fragment().is_empty() {
         // many0 fails if the parser consumers nothing, so we want to fail when
         // we normally would consume nothing
@@ -374,71 +957,347 @@ fn line(input: RawSpan) -> ParseResult<'_, Option<SpanNode<Statement<Span>>>> {
         alt((
             // These contexts are for debugging only. Any error should have
             // a more precise context that can be shown to the user.
-            context("empty line [debug]", map(end_of_statement, |_| None)),
             context(
-                "line w/ statement [debug]",
+                "empty line [debug]",
+                map(end_of_statement, |text| {
+                    (LineContent::Empty, comment(text, false))
+                }),
+            ),
+            context(
+                "line w/ include [debug]",
+                map(
+                    tuple((
+                        preceded(space0, include_directive),
+                        cut(end_of_statement),
+                    )),
+                    |(path, text)| {
+                        (LineContent::Include(path), comment(text, true))
+                    },
+                ),
+            ),
+            context(
+                "line w/ statements [debug]",
                 cut(map(
-                    delimited(
-                        space0,
-                        context("statement", Statement::parse_node),
-                        end_of_statement,
-                    ),
-                    Some,
+                    tuple((preceded(space0, statements), end_of_statement)),
+                    |(statements, text)| (statements, comment(text, true)),
                 )),
             ),
         ))(input)
     }
 }
 
-/// Parse a full program
-fn parse(
+/// Convert a nom error into one of our own errors, so it can be shown to the
+/// user. `position` is where the failed line started, which is used if the
+/// error doesn't have a position of its own.
+fn convert_error(
+    error: VerboseError<RawSpan>,
+    position: RawSpan,
     input: &str,
-) -> Result<Program<Span>, Vec<SourceErrorWrapper<CompileError>>> {
-    match Program::parse(RawSpan::new(input)) {
-        Ok((_, program)) => Ok(program),
-        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
-            // Grab the first error in the chain that is a Context, which means
-            // we labelled it ourselves. Everything else is generated by nom
-            // which means it's useless.
-            let (raw_span, context) = e
-                .errors
-                .iter()
-                .filter_map(|err| match err {
-                    (span, VerboseErrorKind::Context(context)) => {
-                        Some((span, context))
+) -> SourceErrorWrapper<CompileError> {
+    // Grab the first error in the chain that is a Context, which means we
+    // labelled it ourselves. Everything else is generated by nom which means
+    // it's useless.
+    let context_error = error.errors.iter().find_map(|err| match err {
+        (span, VerboseErrorKind::Context(context)) => Some((span, *context)),
+        _ => None,
+    });
+
+    let (error, span) = match context_error {
+        // This fragment is just the ID, so we can span the whole thing
+        Some((raw_span, REF_ID_TOO_LARGE)) => (
+            CompileError::RefIdTooLarge { max: MAX_REF_ID },
+            Span::from_raw_span(raw_span),
+        ),
+        // This fragment is just the unknown word
+        Some((raw_span, UNKNOWN_INSTRUCTION)) => (
+            CompileError::UnknownInstruction {
+                found: (*raw_span.fragment()).to_owned(),
+                suggestion: suggest_instruction(raw_span.fragment()),
+            },
+            Span::from_raw_span(raw_span),
+        ),
+        // The actual fragment here is just the remaining source, so it's not
+        // useful - just use the position from it
+        Some((raw_span, context)) => (
+            CompileError::Syntax { expected: context },
+            Span::from_position(raw_span),
+        ),
+        // This indicates we're missing a context() call somewhere. That's a
+        // bug, but the user should still get an error instead of a crash.
+        None => (
+            CompileError::Syntax {
+                expected: "statement",
+            },
+            Span::from_position(
+                error.errors.first().map_or(&position, |(span, _)| span),
+            ),
+        ),
+    };
+    SourceErrorWrapper::new(error, span, input)
+}
+
+/// Skip past the rest of the current line, including the line ending. Used to
+/// recover after a line fails to parse.
+fn skip_line(input: RawSpan) -> RawSpan {
+    let fragment = input.fragment();
+    let line_len = match fragment.find('\n') {
+        Some(index) => index + 1,
+        None => fragment.len(),
+    };
+    // Slicing updates the line/column info for us
+    input.slice(line_len..)
+}
+
+/// Parses a program, along with every file that it includes. Each line is
+/// parsed independently, so if one line has a syntax error, we record it and
+/// move on to the next. This means we can report every syntax error at once,
+/// instead of just the first one.
+struct ProgramParser<'a> {
+    hardware_spec: &'a HardwareSpec,
+    resolver: &'a dyn SourceResolver,
+    /// Every file included so far. A file's source ID is its index here.
+    includes: Vec<IncludedSource>,
+    /// Names of the included files that are currently being parsed,
+    /// outermost first. Used to detect include cycles.
+    include_stack: Vec<String>,
+    body: Vec<SpanNode<Statement<Span>>>,
+    comments: Vec<SpanNode<Comment>>,
+    errors: Vec<SourceErrorWrapper<CompileError>>,
+}
+
+impl<'a> ProgramParser<'a> {
+    fn new(
+        hardware_spec: &'a HardwareSpec,
+        resolver: &'a dyn SourceResolver,
+    ) -> Self {
+        Self {
+            hardware_spec,
+            resolver,
+            includes: Vec::new(),
+            include_stack: Vec::new(),
+            body: Vec::new(),
+            comments: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Parse a single file, appending its statements to the program. Any
+    /// included files get parsed recursively, in place.
+    fn parse_file(&mut self, input: &str, source_id: Option<u32>) {
+        let mut remaining = RawSpan::new_extra(
+            input,
+            ParseContext {
+                hardware_spec: self.hardware_spec,
+                source_id,
+            },
+        );
+
+        while !remaining.fragment().is_empty() {
+            match line(remaining) {
+                Ok((rest, (content, comment))) => {
+                    match content {
+                        LineContent::Empty => {}
+                        LineContent::Statements(statements) => {
+                            for statement in statements {
+                                self.push_statement(statement, input);
+                            }
+                        }
+                        LineContent::Include(path) => self.include(path, input),
                     }
-                    _ => None,
-                })
-                .next()
-                // This indicates we're missing a context() call somewhere
-                .expect("No context errors available");
-
-            Err(vec![SourceErrorWrapper::new(
-                CompileError::Syntax { expected: context },
-                // the actual fragment here is just the remaining source, so
-                // it's not useful - just use the position from it
-                Span::from_position(raw_span),
+                    // Comments are only needed for the main source, since
+                    // they're only used to format it
+                    if source_id.is_none() {
+                        self.comments.extend(comment);
+                    }
+                    remaining = rest;
+                }
+                Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                    self.push_error(convert_error(e, remaining, input));
+                    // Throw away the rest of the line, so one error doesn't
+                    // cascade into more
+                    remaining = skip_line(remaining);
+                }
+                // only possible in streaming mode
+                Err(nom::Err::Incomplete(_needed)) => unreachable!(),
+            }
+        }
+    }
+
+    /// Add a parsed statement to the program, or record an error if it has
+    /// extra arguments
+    fn push_statement(&mut self, statement: ParsedStatement, input: &str) {
+        match statement {
+            (statement, None) => self.body.push(statement),
+            (statement, Some(extra_args_span)) => {
+                let instruction = match statement.value() {
+                    Statement::Instruction(instruction) => {
+                        instruction.value().kind()
+                    }
+                    // Extra args are only parsed after instructions
+                    Statement::Label(_) => unreachable!(),
+                };
+                self.push_error(SourceErrorWrapper::new(
+                    CompileError::TooManyArguments { instruction },
+                    extra_args_span,
+                    input,
+                ));
+            }
+        }
+    }
+
+    /// Load the file at the given path and parse it. `input` is the source
+    /// of the file that has the include directive.
+    fn include(&mut self, path: SpanNode<String>, input: &str) {
+        let Node(path, span) = path;
+        let included = match self.resolver.resolve(&path) {
+            Ok(included) => included,
+            Err(error) => {
+                self.push_error(SourceErrorWrapper::new(
+                    CompileError::Include { error },
+                    span,
+                    input,
+                ));
+                return;
+            }
+        };
+        if self.include_stack.contains(&included.name) {
+            self.push_error(SourceErrorWrapper::new(
+                CompileError::IncludeCycle,
+                span,
                 input,
-            )])
+            ));
+            return;
         }
-        // only possible in streaming mode
-        Err(nom::Err::Incomplete(_needed)) => unreachable!(),
+
+        let code = included.code.clone();
+        let source_id = self.includes.len() as u32;
+        self.include_stack.push(included.name.clone());
+        self.includes.push(included);
+        self.parse_file(&code, Some(source_id));
+        self.include_stack.pop();
+    }
+
+    /// Record an error. If it's in an included file, it gets tagged with that
+    /// file's name.
+    fn push_error(&mut self, error: SourceErrorWrapper<CompileError>) {
+        let error = match error.span().source_id {
+            Some(id) => error.in_file(&self.includes[id as usize].name),
+            None => error,
+        };
+        self.errors.push(error);
+    }
+
+    fn finish(
+        self,
+    ) -> (
+        Program<Span>,
+        Includes,
+        Vec<SourceErrorWrapper<CompileError>>,
+    ) {
+        (
+            Program {
+                body: self.body,
+                comments: self.comments,
+            },
+            Includes::new(self.includes),
+            self.errors,
+        )
     }
 }
 
+/// Parse a full program. Include directives are rejected, since there's
+/// nothing to resolve them with. See [parse_with_includes].
+pub(crate) fn parse(
+    input: &str,
+    hardware_spec: &HardwareSpec,
+) -> Result<Program<Span>, Vec<SourceErrorWrapper<CompileError>>> {
+    parse_with_includes(input, hardware_spec, &NoIncludes).0
+}
+
+/// Parse a full program, pulling in any included files with the given
+/// resolver. The included files are returned even if parsing fails, so that
+/// errors inside them can be rendered.
+pub(crate) fn parse_with_includes(
+    input: &str,
+    hardware_spec: &HardwareSpec,
+    resolver: &dyn SourceResolver,
+) -> (
+    Result<Program<Span>, Vec<SourceErrorWrapper<CompileError>>>,
+    Includes,
+) {
+    let mut parser = ProgramParser::new(hardware_spec, resolver);
+    parser.parse_file(input, None);
+    let (program, includes, errors) = parser.finish();
+
+    let result = if !errors.is_empty() {
+        Err(errors)
+    } else if program.body.is_empty() {
+        // If the program is empty, that's no bueno
+        Err(vec![SourceErrorWrapper::new(
+            CompileError::Syntax {
+                expected: "program",
+            },
+            Span::from_position(&RawSpan::new_extra(
+                input,
+                ParseContext {
+                    hardware_spec,
+                    source_id: None,
+                },
+            )),
+            input,
+        )])
+    } else {
+        Ok(program)
+    };
+    (result, includes)
+}
+
+/// Parse a full program, keeping every line that parsed successfully, even if
+/// other lines have syntax errors. Useful for tooling that needs to work on
+/// incomplete programs. Unlike [parse], an empty program isn't an error here.
+pub(crate) fn parse_lenient(
+    input: &str,
+    hardware_spec: &HardwareSpec,
+) -> (Program<Span>, Vec<SourceErrorWrapper<CompileError>>) {
+    let mut parser = ProgramParser::new(hardware_spec, &NoIncludes);
+    parser.parse_file(input, None);
+    let (program, _, errors) = parser.finish();
+    (program, errors)
+}
+
 impl Compiler<()> {
     /// Parses source code from the given input, into an abstract syntax tree.
+    /// Included files are loaded with the given resolver.
     pub(crate) fn parse(
         self,
+        resolver: &dyn SourceResolver,
     ) -> Result<Compiler<Program<Span>>, WithSource<CompileError>> {
-        match parse(&self.source) {
-            // Ok(program) => Ok(self.replace_ast(program)),
-            Ok(program) => Ok(Compiler {
-                source: self.source,
-                hardware_spec: self.hardware_spec,
-                ast: program,
-            }),
-            Err(errors) => Err(WithSource::new(errors, self.source)),
+        let (result, includes) =
+            parse_with_includes(&self.source, &self.hardware_spec, resolver);
+        match result {
+            Ok(program) => {
+                let (metadata, metadata_warnings) =
+                    metadata::read_metadata(&program, usize::MAX);
+                let source = &self.source;
+                let mut warnings = self.warnings;
+                warnings.extend(metadata_warnings.into_iter().map(
+                    |(warning, span)| {
+                        SourceErrorWrapper::new(warning, span, source)
+                    },
+                ));
+                Ok(Compiler {
+                    source: self.source,
+                    hardware_spec: self.hardware_spec,
+                    includes,
+                    ast: program,
+                    warnings,
+                    metadata,
+                })
+            }
+            Err(errors) => {
+                Err(WithSource::new(errors, self.source)
+                    .with_includes(includes))
+            }
         }
     }
 }
@@ -447,6 +1306,13 @@ impl Compiler<()> {
 mod tests {
     use super::*;
 
+    /// Parse under the default hardware spec, which has no aliases
+    fn parse(
+        input: &str,
+    ) -> Result<Program<Span>, Vec<SourceErrorWrapper<CompileError>>> {
+        super::parse(input, &HardwareSpec::default())
+    }
+
     /// Helper to make it a bit terser to create spans for tests
     fn span(
         offset: usize,
@@ -457,6 +1323,7 @@ mod tests {
         end_col: usize,
     ) -> Span {
         Span {
+            source_id: None,
             offset,
             length,
             start_line,
@@ -712,9 +1579,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_constant() {
+        assert_eq!(
+            parse("WRITE $K_2").unwrap().body,
+            vec![Node(
+                Statement::Instruction(Node(
+                    Instruction::Write(Node(
+                        ValueSource::Constant(Node(
+                            "K_2".into(),
+                            span(7, 3, 1, 8, 1, 11)
+                        )),
+                        span(6, 4, 1, 7, 1, 11)
+                    )),
+                    span(0, 10, 1, 1, 1, 11)
+                )),
+                span(0, 10, 1, 1, 1, 11)
+            )]
+        );
+        assert!(parse("WRITE $").is_err());
+        assert!(parse("WRITE $ K").is_err());
+    }
+
     #[test]
     fn test_parse_lang_val_max() {
         let source = format!("Add RX1 {}", LangValue::MAX);
+        // The literal's length depends on the width of LangValue
+        let len = LangValue::MAX.to_string().len();
         assert_eq!(
             parse(&source).unwrap().body,
             vec![Node(
@@ -724,14 +1615,14 @@ mod tests {
                         Node(
                             ValueSource::Const(Node(
                                 LangValue::MAX,
-                                span(8, 10, 1, 9, 1, 19)
+                                span(8, len, 1, 9, 1, 9 + len)
                             )),
-                            span(8, 10, 1, 9, 1, 19)
+                            span(8, len, 1, 9, 1, 9 + len)
                         )
                     ),
-                    span(0, 18, 1, 1, 1, 19)
+                    span(0, 8 + len, 1, 1, 1, 9 + len)
                 )),
-                span(0, 18, 1, 1, 1, 19)
+                span(0, 8 + len, 1, 1, 1, 9 + len)
             )]
         );
     }
@@ -739,6 +1630,7 @@ mod tests {
     #[test]
     fn test_parse_lang_val_min() {
         let source = format!("Add RX1 {}", LangValue::min_value());
+        let len = LangValue::min_value().to_string().len();
         assert_eq!(
             parse(&source).unwrap().body,
             vec![Node(
@@ -748,18 +1640,103 @@ mod tests {
                         Node(
                             ValueSource::Const(Node(
                                 LangValue::min_value(),
-                                span(8, 11, 1, 9, 1, 20)
+                                span(8, len, 1, 9, 1, 9 + len)
                             )),
-                            span(8, 11, 1, 9, 1, 20)
+                            span(8, len, 1, 9, 1, 9 + len)
                         )
                     ),
-                    span(0, 19, 1, 1, 1, 20)
+                    span(0, 8 + len, 1, 1, 1, 9 + len)
                 )),
-                span(0, 19, 1, 1, 1, 20)
+                span(0, 8 + len, 1, 1, 1, 9 + len)
             )]
         );
     }
 
+    #[cfg(not(feature = "i64-values"))]
+    #[test]
+    fn test_i32_literal_bounds() {
+        assert_eq!(parse_literal("2147483647").0, 2147483647);
+        assert_eq!(parse_literal("-2147483648").0, -2147483648);
+        assert!(parse("WRITE 2147483648").is_err());
+        assert!(parse("WRITE -2147483649").is_err());
+    }
+
+    #[cfg(feature = "i64-values")]
+    #[test]
+    fn test_i64_literal_bounds() {
+        assert_eq!(
+            parse_literal("9223372036854775807"),
+            (LangValue::MAX, span(6, 19, 1, 7, 1, 26))
+        );
+        assert_eq!(
+            parse_literal("-9223372036854775808"),
+            (LangValue::MIN, span(6, 20, 1, 7, 1, 27))
+        );
+        assert_eq!(
+            parse_literal("0x7FFFFFFFFFFFFFFF"),
+            (LangValue::MAX, span(6, 18, 1, 7, 1, 25))
+        );
+        // Past the old i32 bounds is fine now
+        assert_eq!(parse_literal("2147483648").0, 2147483648);
+        assert!(parse("WRITE 9223372036854775808").is_err());
+        assert!(parse("WRITE -9223372036854775809").is_err());
+        assert!(parse("WRITE 0x8000000000000000").is_err());
+    }
+
+    /// Parse `WRITE <literal>`, and return the written constant and its span
+    fn parse_literal(literal: &str) -> (LangValue, Span) {
+        let body = parse(&format!("WRITE {}", literal)).unwrap().body;
+        match body.as_slice() {
+            [Node(
+                Statement::Instruction(Node(
+                    Instruction::Write(Node(
+                        ValueSource::Const(Node(value, span)),
+                        _,
+                    )),
+                    _,
+                )),
+                _,
+            )] => (*value, *span),
+            _ => panic!("Unexpected parse result: {:?}", body),
+        }
+    }
+
+    #[test]
+    fn test_hex_literal() {
+        assert_eq!(parse_literal("0x1F"), (31, span(6, 4, 1, 7, 1, 11)));
+        assert_eq!(parse_literal("0X1f"), (31, span(6, 4, 1, 7, 1, 11)));
+        assert_eq!(parse_literal("0x0"), (0, span(6, 3, 1, 7, 1, 10)));
+        let max = format!("0x{:X}", LangValue::MAX);
+        assert_eq!(
+            parse_literal(&max),
+            (LangValue::MAX, span(6, max.len(), 1, 7, 1, 7 + max.len()))
+        );
+
+        // Too big, missing digits, or negative
+        assert!(parse(&format!("WRITE 0x{:X}", LangValue::MAX as u128 + 1))
+            .is_err());
+        assert!(parse("WRITE 0x").is_err());
+        assert!(parse("WRITE 0x1G").is_err());
+        assert!(parse("WRITE -0x1").is_err());
+    }
+
+    #[test]
+    fn test_char_literal() {
+        assert_eq!(parse_literal("'A'"), (65, span(6, 3, 1, 7, 1, 10)));
+        assert_eq!(parse_literal("'a'"), (97, span(6, 3, 1, 7, 1, 10)));
+        assert_eq!(parse_literal("' '"), (32, span(6, 3, 1, 7, 1, 10)));
+        assert_eq!(parse_literal("';'"), (59, span(6, 3, 1, 7, 1, 10)));
+        // Multi-byte characters use their Unicode scalar value. The length
+        // is in bytes, but columns are counted in characters.
+        assert_eq!(parse_literal("'é'"), (233, span(6, 4, 1, 7, 1, 10)));
+
+        assert!(parse("WRITE 'ab'").is_err());
+        assert!(parse("WRITE ''").is_err());
+        assert!(parse("WRITE '''").is_err());
+        assert!(parse("WRITE 'A").is_err());
+        assert!(parse("WRITE A'").is_err());
+    }
+
     #[test]
     fn test_add() {
         assert_eq!(
@@ -829,6 +1806,51 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_bitwise() {
+        for (name, instruction) in &[
+            ("And", Instruction::And as fn(_, _) -> _),
+            ("or", Instruction::Or),
+            ("XOR", Instruction::Xor),
+            ("Shl", Instruction::Shl),
+            ("SHR", Instruction::Shr),
+        ] {
+            // The arguments move over with the length of the name
+            let n = name.len();
+            assert_eq!(
+                parse(&format!("{} RX1 -3", name)).unwrap().body,
+                vec![Node(
+                    Statement::Instruction(Node(
+                        instruction(
+                            Node(
+                                RegisterRef::User(1),
+                                span(n + 1, 3, 1, n + 2, 1, n + 5)
+                            ),
+                            Node(
+                                ValueSource::Const(Node(
+                                    -3,
+                                    span(n + 5, 2, 1, n + 6, 1, n + 8)
+                                )),
+                                span(n + 5, 2, 1, n + 6, 1, n + 8)
+                            )
+                        ),
+                        span(0, n + 7, 1, 1, 1, n + 8)
+                    )),
+                    span(0, n + 7, 1, 1, 1, n + 8)
+                )],
+                "{}",
+                name
+            );
+        }
+
+        // Same arguments as arithmetic, so the destination has to be a
+        // register
+        assert!(parse("AND 1 RX0").is_err());
+        assert!(parse("SHL RX0").is_err());
+        // Longer words aren't mistaken for OR
+        assert!(parse("ORB RX0 1").is_err());
+    }
+
     #[test]
     fn test_cmp() {
         assert_eq!(
@@ -899,6 +1921,116 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_peek_popn() {
+        assert_eq!(
+            parse("PEEK S0 RX1\nPOPN S1 3").unwrap().body,
+            vec![
+                Node(
+                    Statement::Instruction(Node(
+                        Instruction::Peek(
+                            Node(StackRef(0), span(5, 2, 1, 6, 1, 8)),
+                            Node(RegisterRef::User(1), span(8, 3, 1, 9, 1, 12)),
+                        ),
+                        span(0, 11, 1, 1, 1, 12)
+                    )),
+                    span(0, 11, 1, 1, 1, 12)
+                ),
+                Node(
+                    Statement::Instruction(Node(
+                        Instruction::Popn(
+                            Node(StackRef(1), span(17, 2, 2, 6, 2, 8)),
+                            Node(
+                                ValueSource::Const(Node(
+                                    3,
+                                    span(20, 1, 2, 9, 2, 10)
+                                )),
+                                span(20, 1, 2, 9, 2, 10)
+                            ),
+                        ),
+                        span(12, 9, 2, 1, 2, 10)
+                    )),
+                    span(12, 9, 2, 1, 2, 10)
+                ),
+            ]
+        );
+        // POPN doesn't get mistaken for POP
+        assert!(parse("POPN S0 RX0 RX1").is_err());
+    }
+
+    #[test]
+    fn test_dup_sswp() {
+        assert_eq!(
+            parse("DUP S0\nSSWP S1").unwrap().body,
+            vec![
+                Node(
+                    Statement::Instruction(Node(
+                        Instruction::Dup(Node(
+                            StackRef(0),
+                            span(4, 2, 1, 5, 1, 7)
+                        )),
+                        span(0, 6, 1, 1, 1, 7)
+                    )),
+                    span(0, 6, 1, 1, 1, 7)
+                ),
+                Node(
+                    Statement::Instruction(Node(
+                        Instruction::Sswp(Node(
+                            StackRef(1),
+                            span(12, 2, 2, 6, 2, 8)
+                        )),
+                        span(7, 7, 2, 1, 2, 8)
+                    )),
+                    span(7, 7, 2, 1, 2, 8)
+                ),
+            ]
+        );
+        // These don't get mistaken for DUMP and SWP, or vice versa
+        assert!(parse("DUP").is_err());
+        assert!(parse("DUMP S0").is_err());
+        assert!(parse("SSWP RX0 RX1").is_err());
+        assert!(parse("SWP S0").is_err());
+    }
+
+    #[test]
+    fn test_nop() {
+        assert_eq!(
+            parse("NOP\nnop").unwrap().body,
+            vec![
+                Node(
+                    Statement::Instruction(Node(
+                        Instruction::Nop,
+                        span(0, 3, 1, 1, 1, 4)
+                    )),
+                    span(0, 3, 1, 1, 1, 4)
+                ),
+                Node(
+                    Statement::Instruction(Node(
+                        Instruction::Nop,
+                        span(4, 3, 2, 1, 2, 4)
+                    )),
+                    span(4, 3, 2, 1, 2, 4)
+                )
+            ]
+        );
+        // NOP doesn't take any arguments
+        assert!(parse("NOP RX0").is_err());
+    }
+
+    #[test]
+    fn test_dump() {
+        assert_eq!(
+            parse("dump").unwrap().body,
+            vec![Node(
+                Statement::Instruction(Node(
+                    Instruction::Dump,
+                    span(0, 4, 1, 1, 1, 5)
+                )),
+                span(0, 4, 1, 1, 1, 5)
+            )]
+        );
+    }
+
     #[test]
     fn test_jumps() {
         assert_eq!(
@@ -992,6 +2124,53 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_swp() {
+        assert_eq!(
+            parse("SWP RX0 RX1").unwrap().body,
+            vec![Node(
+                Statement::Instruction(Node(
+                    Instruction::Swp(
+                        Node(RegisterRef::User(0), span(4, 3, 1, 5, 1, 8)),
+                        Node(RegisterRef::User(1), span(8, 3, 1, 9, 1, 12))
+                    ),
+                    span(0, 11, 1, 1, 1, 12)
+                )),
+                span(0, 11, 1, 1, 1, 12)
+            )]
+        );
+        // Both arguments are required
+        assert!(parse("SWP RX0").is_err());
+    }
+
+    #[test]
+    fn test_call_ret() {
+        assert_eq!(
+            parse("CALL LBL\nret").unwrap().body,
+            vec![
+                Node(
+                    Statement::Instruction(Node(
+                        Instruction::Call(Node(
+                            "LBL".into(),
+                            span(5, 3, 1, 6, 1, 9)
+                        )),
+                        span(0, 8, 1, 1, 1, 9)
+                    )),
+                    span(0, 8, 1, 1, 1, 9)
+                ),
+                Node(
+                    Statement::Instruction(Node(
+                        Instruction::Ret,
+                        span(9, 3, 2, 1, 2, 4)
+                    )),
+                    span(9, 3, 2, 1, 2, 4)
+                ),
+            ]
+        );
+        // RET doesn't take any arguments
+        assert!(parse("RET LBL").is_err());
+    }
+
     #[test]
     fn test_comments() {
         assert_eq!(
@@ -1023,20 +2202,59 @@ mod tests {
     }
 
     #[test]
-    fn test_parse_simple_file() {
+    fn test_statement_separator() {
         assert_eq!(
-            parse(
-                "
+            parse("LBL: | READ RX0|WRITE 1 ; a | b").unwrap().body,
+            vec![
+                Node(
+                    Statement::Label(Node(
+                        LabelDecl("LBL".into()),
+                        span(0, 4, 1, 1, 1, 5)
+                    )),
+                    span(0, 4, 1, 1, 1, 5)
+                ),
+                Node(
+                    Statement::Instruction(Node(
+                        Instruction::Read(Node(
+                            RegisterRef::User(0),
+                            span(12, 3, 1, 13, 1, 16)
+                        )),
+                        span(7, 8, 1, 8, 1, 16)
+                    )),
+                    span(7, 8, 1, 8, 1, 16)
+                ),
+                Node(
+                    Statement::Instruction(Node(
+                        Instruction::Write(Node(
+                            ValueSource::Const(Node(
+                                1,
+                                span(22, 1, 1, 23, 1, 24)
+                            )),
+                            span(22, 1, 1, 23, 1, 24)
+                        )),
+                        span(16, 7, 1, 17, 1, 24)
+                    )),
+                    span(16, 7, 1, 17, 1, 24)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_simple_file() {
+        let program = parse(
+            "
                 ;comment start
                 Read RX0
                 ; comment poop
                 Set RX0 2 ;comment more poop
                 Write RX0
                 ; comment pog
-                "
-            )
-            .unwrap()
-            .body,
+                ",
+        )
+        .unwrap();
+        assert_eq!(
+            program.body,
             vec![
                 Node(
                     Statement::Instruction(Node(
@@ -1082,5 +2300,120 @@ mod tests {
                 )
             ]
         );
+        // Comments are kept alongside the statements
+        let comment = |text: &str, trailing: bool, span: Span| {
+            Node(
+                Comment {
+                    text: text.into(),
+                    trailing,
+                },
+                span,
+            )
+        };
+        assert_eq!(
+            program.comments(),
+            &[
+                comment("comment start", false, span(17, 14, 2, 17, 2, 31)),
+                comment(" comment poop", false, span(73, 14, 4, 17, 4, 31)),
+                comment("comment more poop", true, span(114, 18, 5, 27, 5, 45)),
+                comment(" comment pog", false, span(175, 13, 7, 17, 7, 30)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_doc_comments() {
+        let program = parse(
+            "
+; not part of the doc
+;; Doubles a value
+;;
+;;with some detail
+DOUBLE: ; not part of the doc either
+    ADD RX0 RX0 ;; trailing comments don't count
+
+    ;; this one is cut off by a blank line
+
+    RET
+",
+        )
+        .unwrap();
+        let body = &program.body;
+
+        assert_eq!(program.leading_comments(&body[0]).len(), 4);
+        assert_eq!(
+            program.doc_comment(&body[0]).as_deref(),
+            Some("Doubles a value\n\nwith some detail")
+        );
+        assert_eq!(program.leading_comments(&body[1]), &[]);
+        assert_eq!(program.doc_comment(&body[1]), None);
+        assert_eq!(program.doc_comment(&body[2]), None);
+    }
+
+    #[test]
+    fn test_instruction_catalog() {
+        // There's no wildcard here, so adding an instruction won't compile
+        // until it's added here, and to the catalog
+        let position = |kind| match kind {
+            InstructionKind::Read => 0,
+            InstructionKind::Rdz => 1,
+            InstructionKind::Write => 2,
+            InstructionKind::Set => 3,
+            InstructionKind::Swp => 4,
+            InstructionKind::Add => 5,
+            InstructionKind::Sub => 6,
+            InstructionKind::Mul => 7,
+            InstructionKind::Div => 8,
+            InstructionKind::And => 9,
+            InstructionKind::Or => 10,
+            InstructionKind::Xor => 11,
+            InstructionKind::Shl => 12,
+            InstructionKind::Shr => 13,
+            InstructionKind::Cmp => 14,
+            InstructionKind::Push => 15,
+            InstructionKind::Pop => 16,
+            InstructionKind::Peek => 17,
+            InstructionKind::Popn => 18,
+            InstructionKind::Dup => 19,
+            InstructionKind::Sswp => 20,
+            InstructionKind::Jmp => 21,
+            InstructionKind::Jez => 22,
+            InstructionKind::Jnz => 23,
+            InstructionKind::Jlz => 24,
+            InstructionKind::Jgz => 25,
+            InstructionKind::Jri => 26,
+            InstructionKind::Call => 27,
+            InstructionKind::Ret => 28,
+            InstructionKind::Nop => 29,
+            InstructionKind::Dump => 30,
+            InstructionKind::Ext => 31,
+        };
+        assert_eq!(INSTRUCTION_CATALOG.len(), InstructionKind::ALL.len());
+
+        for (i, info) in INSTRUCTION_CATALOG.iter().enumerate() {
+            assert_eq!(position(info.kind), i);
+            // The parser should accept exactly the listed arguments
+            let args: Vec<&str> = info
+                .args
+                .iter()
+                .map(|arg| match arg {
+                    ArgKind::WritableRegister => "RX0",
+                    ArgKind::Register => "RLI",
+                    ArgKind::Value => "1",
+                    ArgKind::Stack => "S0",
+                    ArgKind::Label => "LBL",
+                    ArgKind::ExternOp => "0",
+                })
+                .collect();
+            let source = format!("{} {}", info.kind, args.join(" "));
+            let program = parse(&source).unwrap();
+            match program.body[0].value() {
+                Statement::Instruction(instr) => {
+                    assert_eq!(instr.value().kind(), info.kind, "{}", source)
+                }
+                stmt => panic!("Expected instruction, got {:?}", stmt),
+            }
+            assert!(parse(&format!("{} RX0", source)).is_err(), "{}", source);
+        }
     }
 }
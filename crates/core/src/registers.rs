@@ -0,0 +1,203 @@
+//! The single source of truth for which registers exist on a given piece of
+//! hardware, and what can be done with each one. Everything that needs to know
+//! whether a register is valid or writable (validation, execution, the UI)
+//! should derive it from [RegisterSet].
+
+use crate::{
+    ast::{RegisterRef, StackId},
+    models::HardwareSpec,
+};
+use serde::Serialize;
+
+/// The reason that a register is available on a piece of hardware.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum RegisterReason {
+    /// Available on all hardware, regardless of spec
+    Base,
+    /// Available because the hardware counts writes to `RZR`, see
+    /// [HardwareSpec::null_count_enabled]
+    NullCount,
+    /// Available because the hardware has the stack with this ID
+    Stack(StackId),
+    /// Available because the hardware has enough user registers
+    UserRegister,
+}
+
+/// Everything there is to know about a single available register.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RegisterInfo {
+    pub register: RegisterRef,
+    /// Can the register be read from?
+    pub readable: bool,
+    /// Can the register be written to? Writes to a non-writable register are
+    /// rejected at compile time.
+    pub writable: bool,
+    /// Why this register is available on the hardware
+    pub reason: RegisterReason,
+}
+
+/// The set of all registers that are available on a particular piece of
+/// hardware. The registers are ordered as they should be displayed to the
+/// user.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RegisterSet {
+    registers: Vec<RegisterInfo>,
+}
+
+impl RegisterSet {
+    /// Build the set of registers available for the given hardware.
+    pub fn for_spec(hardware_spec: &HardwareSpec) -> Self {
+        // The order here is important. This is how it will appear in the UI!
+        let mut registers = vec![
+            // RZR first
+            RegisterInfo {
+                register: RegisterRef::Null,
+                readable: true,
+                writable: true,
+                reason: RegisterReason::Base,
+            },
+            // Then RLI
+            RegisterInfo {
+                register: RegisterRef::InputLength,
+                readable: true,
+                writable: false,
+                reason: RegisterReason::Base,
+            },
+            // Then RIH
+            RegisterInfo {
+                register: RegisterRef::InputHead,
+                readable: true,
+                writable: false,
+                reason: RegisterReason::Base,
+            },
+        ];
+        // Then RZC, if enabled
+        if hardware_spec.null_count_enabled {
+            registers.push(RegisterInfo {
+                register: RegisterRef::NullCount,
+                readable: true,
+                writable: false,
+                reason: RegisterReason::NullCount,
+            });
+        }
+        // RSx registers
+        registers.extend((0..hardware_spec.num_stacks).map(|stack_id| {
+            RegisterInfo {
+                register: RegisterRef::StackLength(stack_id),
+                readable: true,
+                writable: false,
+                reason: RegisterReason::Stack(stack_id),
+            }
+        }));
+        // RXx registers
+        registers.extend((0..hardware_spec.num_registers).map(|reg_id| {
+            RegisterInfo {
+                register: RegisterRef::User(reg_id),
+                readable: true,
+                writable: true,
+                reason: RegisterReason::UserRegister,
+            }
+        }));
+        Self { registers }
+    }
+
+    /// Get the info for a register. Returns `None` if the register isn't
+    /// available on this hardware.
+    pub fn get(&self, register: RegisterRef) -> Option<&RegisterInfo> {
+        self.registers.iter().find(|info| info.register == register)
+    }
+
+    /// Iterate over all registers in the set, in display order.
+    pub fn iter(&self) -> impl Iterator<Item = &RegisterInfo> {
+        self.registers.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get (register name, writable) for each register in the set, to make
+    /// assertions easier to read
+    fn summarize(hardware_spec: HardwareSpec) -> Vec<(String, bool)> {
+        RegisterSet::for_spec(&hardware_spec)
+            .iter()
+            .map(|info| (info.register.to_string(), info.writable))
+            .collect()
+    }
+
+    #[test]
+    fn test_register_set() {
+        assert_eq!(
+            summarize(HardwareSpec {
+                num_registers: 0,
+                num_stacks: 0,
+                max_stack_length: 0,
+                ..Default::default()
+            }),
+            vec![
+                ("RZR".into(), true),
+                ("RLI".into(), false),
+                ("RIH".into(), false),
+            ]
+        );
+
+        assert_eq!(
+            summarize(HardwareSpec {
+                num_registers: 2,
+                num_stacks: 1,
+                max_stack_length: 5,
+                ..Default::default()
+            }),
+            vec![
+                ("RZR".into(), true),
+                ("RLI".into(), false),
+                ("RIH".into(), false),
+                ("RS0".into(), false),
+                ("RX0".into(), true),
+                ("RX1".into(), true),
+            ]
+        );
+
+        // RZC only shows up if it's turned on
+        assert_eq!(
+            summarize(HardwareSpec {
+                num_registers: 1,
+                num_stacks: 0,
+                max_stack_length: 0,
+                null_count_enabled: true,
+                ..Default::default()
+            }),
+            vec![
+                ("RZR".into(), true),
+                ("RLI".into(), false),
+                ("RIH".into(), false),
+                ("RZC".into(), false),
+                ("RX0".into(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_register_set_get() {
+        let register_set = RegisterSet::for_spec(&HardwareSpec {
+            num_registers: 1,
+            num_stacks: 2,
+            max_stack_length: 5,
+            ..Default::default()
+        });
+        assert_eq!(
+            register_set
+                .get(RegisterRef::StackLength(1))
+                .unwrap()
+                .reason,
+            RegisterReason::Stack(1)
+        );
+        assert_eq!(
+            register_set.get(RegisterRef::User(0)).unwrap().reason,
+            RegisterReason::UserRegister
+        );
+        assert!(register_set.get(RegisterRef::StackLength(2)).is_none());
+        assert!(register_set.get(RegisterRef::User(1)).is_none());
+    }
+}
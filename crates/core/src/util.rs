@@ -1,3 +1,4 @@
+use crate::{ast::LangValue, models::HardwareSpec};
 use nom::Slice;
 use nom_locate::LocatedSpan;
 use serde::{Deserialize, Serialize};
@@ -5,29 +6,54 @@ use std::{
     fmt::{self, Formatter},
     iter,
 };
-#[cfg(target_arch = "wasm32")]
+#[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
 
-pub type RawSpan<'a> = LocatedSpan<&'a str>;
+/// Input type for the parser. The extra data is carried along with the input,
+/// see [ParseContext].
+pub type RawSpan<'a> = LocatedSpan<&'a str, ParseContext<'a>>;
+
+/// Extra data for the parser, which is attached to every [RawSpan]
+#[derive(Copy, Clone, Debug)]
+pub struct ParseContext<'a> {
+    /// Needed so that register and stack aliases can be resolved during
+    /// parsing
+    pub hardware_spec: &'a HardwareSpec,
+    /// The file being parsed, see [Span::source_id]
+    pub source_id: Option<u32>,
+}
 
 /// A definition of a span of source code. This doesn't actually hold the code
 /// itself (or any reference to it), it just defines parameters that can be used
 /// to find the source span.
-#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
 pub struct Span {
     // TODO make these readonly and camel case in wasm
-    /// Distance into the source at which this span starts. Starts at `0`.
+    /// The file that this span is in. `None` is the main source, anything
+    /// else is a file that was pulled in with an include directive. See
+    /// [Includes](crate::include::Includes). Omitted from serialization for
+    /// the main source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_id: Option<u32>,
+    /// Distance into the source at which this span starts, in bytes. Starts
+    /// at `0`.
     pub offset: usize,
-    /// Number of characters that this span includes.
+    /// Number of bytes that this span includes.
     pub length: usize,
     /// The line number that this span starts on, starting at `1`.
     pub start_line: usize,
-    /// The column that this span starts at, starting at `1`.
+    /// The column that this span starts at, starting at `1`. Columns count
+    /// characters (Unicode scalar values), not bytes, so they match what's
+    /// rendered in a terminal. Editors that count UTF-16 code units instead
+    /// need to convert, see [Self::to_utf16_cols].
     pub start_col: usize,
     /// The line number that this span ends on, starting at `1`.
     pub end_line: usize,
-    /// The column that this span ends at, starting at `1`.
+    /// The column that this span ends at, starting at `1`. Counted the same
+    /// way as [Self::start_col].
     pub end_col: usize,
 }
 
@@ -40,12 +66,13 @@ impl Span {
         let end = start.slice(len..);
 
         Self {
+            source_id: start.extra.source_id,
             offset: start.location_offset(),
             length: start.fragment().len(),
             start_line: start.location_line() as usize,
-            start_col: start.get_column(),
+            start_col: start.get_utf8_column(),
             end_line: end.location_line() as usize,
-            end_col: end.get_column(),
+            end_col: end.get_utf8_column(),
         }
     }
 
@@ -55,9 +82,10 @@ impl Span {
     /// real information (e.g. for errors).
     pub fn from_position(raw_span: &RawSpan) -> Self {
         let line = raw_span.location_line() as usize;
-        let col = raw_span.get_column();
+        let col = raw_span.get_utf8_column();
 
         Self {
+            source_id: raw_span.extra.source_id,
             offset: raw_span.location_offset(),
             length: 0,
             start_line: line,
@@ -68,15 +96,30 @@ impl Span {
         }
     }
 
+    /// Create a span that starts at the start of this span, and ends at the
+    /// end of the other span. The other span should come after this one.
+    pub fn merge(&self, other: &Span) -> Self {
+        Self {
+            source_id: self.source_id,
+            offset: self.offset,
+            length: other.offset + other.length - self.offset,
+            start_line: self.start_line,
+            start_col: self.start_col,
+            end_line: other.end_line,
+            end_col: other.end_col,
+        }
+    }
+
     /// Determine if a line number intersects with this span.
     pub fn includes_line(&self, line_num: usize) -> bool {
         self.start_line <= line_num && line_num <= self.end_line
     }
 
-    /// Get the start and end column of this span for a particular line. For the
-    /// first line in the span, the start is the span's start column. For the
-    /// last line, the end is the span's end column. For any other line, the
-    /// start is `1` and the end is the given line length.
+    /// Get the start and end column of this span for a particular line. The
+    /// end column is exclusive. For the first line in the span, the start is
+    /// the span's start column. For the last line, the end is the span's end
+    /// column. For any other line, the start is `1` and the end is just past
+    /// the end of the line.
     pub fn get_cols_for_line(
         &self,
         line_num: usize,
@@ -90,11 +133,39 @@ impl Span {
         let end_col = if line_num >= self.end_line {
             self.end_col
         } else {
-            line_len
+            line_len + 1
         };
         (start_col, end_col)
     }
 
+    /// Get the start and end column of this span in UTF-16 code units instead
+    /// of characters, still starting at `1`. This is how JavaScript strings,
+    /// and so editors like Ace and CodeMirror, count columns. The two only
+    /// differ for characters outside the Basic Multilingual Plane, e.g. emoji,
+    /// which take two code units. `source` has to be the code that this span
+    /// points into. If it isn't, and the span's offsets land in the middle of
+    /// a character, the character columns are returned unchanged.
+    pub fn to_utf16_cols(&self, source: &str) -> (usize, usize) {
+        // Count the UTF-16 width of the line, up to the given byte offset
+        let column = |offset: usize| {
+            let before = source.get(..offset.min(source.len()))?;
+            let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+            Some(before[line_start..].encode_utf16().count() + 1)
+        };
+        let start_col = column(self.offset);
+        let end_col = if self.length == 0 {
+            // Empty spans can still cover a column, see Self::from_position
+            start_col
+                .map(|col| col + self.end_col.saturating_sub(self.start_col))
+        } else {
+            column(self.offset + self.length)
+        };
+        match (start_col, end_col) {
+            (Some(start_col), Some(end_col)) => (start_col, end_col),
+            _ => (self.start_col, self.end_col),
+        }
+    }
+
     /// Find the spanned portion of source within the full source code. Returns
     /// a sub-slice of the given string that corresponds to this span.
     pub fn get_source_slice<'a>(&self, src: &'a str) -> &'a str {
@@ -102,56 +173,89 @@ impl Span {
     }
 }
 
+/// The maximum number of lines of a single span to render in full. Anything
+/// longer only gets its first and last line rendered.
+const MAX_HIGHLIGHT_LINES: usize = 3;
+
+/// Render an annotated snippet of the spanned source code, similar to rustc's
+/// error output. Each line in the span is printed with its line number, with
+/// the spanned columns underlined by `^`. The output starts with a newline,
+/// but doesn't end with one, so it can be appended to a message.
 pub fn fmt_src_highlights(
     f: &mut Formatter<'_>,
     span: &Span,
     src: &str,
 ) -> fmt::Result {
-    let margin = "   ";
+    let lines: Vec<&str> = src.lines().collect();
+    // Wide enough for the biggest line number we'll print
+    let margin = " ".repeat(span.end_line.to_string().len());
     let separator = " | ";
 
-    // Span's line numbers start at 1, so include a dummy line at the
-    // beginning here to make them line up
-    let lines: Vec<&str> = iter::once("").chain(src.lines()).collect();
-    writeln!(f)?; // Blank line
-    writeln!(f, "{}{}", margin, separator)?;
-
-    // Print the the source span, plus an extra line before and after
-    let highlight_start_line = usize::max(span.start_line - 1, 1);
-    let highlight_end_line = usize::min(span.end_line + 1, lines.len() - 1);
-    for (i, line) in lines[highlight_start_line..=highlight_end_line]
-        .iter()
-        .enumerate()
-    {
-        let line_num = highlight_start_line + i;
-        writeln!(f, "{:>3}{}{}", line_num, separator, line)?;
-
-        // If this line is actually in the span, do some underlining
-        if span.includes_line(line_num) {
-            // Underline the spanned columns with ^^^
-            let (start_col, end_col) =
-                span.get_cols_for_line(line_num, line.len());
-            writeln!(
-                f,
-                "{}{}{}{}",
-                margin,
-                separator,
-                iter::repeat(" ")
-                    .take(start_col - 1)
-                    .collect::<Vec<_>>()
-                    .join(""),
-                iter::repeat("^")
-                    .take(end_col - start_col)
-                    .collect::<Vec<_>>()
-                    .join("")
-            )?;
-        }
+    // Long spans get truncated to just their first and last line
+    let line_nums: Vec<Option<usize>> =
+        if span.end_line - span.start_line < MAX_HIGHLIGHT_LINES {
+            (span.start_line..=span.end_line).map(Some).collect()
+        } else {
+            vec![Some(span.start_line), None, Some(span.end_line)]
+        };
+
+    write!(f, "\n{}{}", margin, separator.trim_end())?;
+    for line_num in line_nums {
+        let line_num = match line_num {
+            Some(line_num) => line_num,
+            None => {
+                write!(f, "\n...")?;
+                continue;
+            }
+        };
+        // Spans can start just past the end of the source (e.g. if an
+        // argument is missing on the last line), so there may be no line
+        let line = lines.get(line_num - 1).copied().unwrap_or("");
+        write!(
+            f,
+            "\n{:>width$}{}{}",
+            line_num,
+            separator,
+            line,
+            width = margin.len()
+        )?;
+
+        // Underline the spanned columns with ^^^. Always use at least one
+        // caret, so empty lines still get marked.
+        let (start_col, end_col) =
+            span.get_cols_for_line(line_num, line.chars().count());
+        write!(
+            f,
+            "\n{}{}{}{}",
+            margin,
+            separator,
+            " ".repeat(start_col - 1),
+            "^".repeat(usize::max(end_col.saturating_sub(start_col), 1))
+        )?;
     }
-    writeln!(f, "{}{}", margin, separator)?;
 
     Ok(())
 }
 
+/// Get the Levenshtein distance between two strings, i.e. the number of
+/// single-character insertions, deletions, and substitutions it takes to get
+/// from one to the other.
+pub(crate) fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Distances from the current prefix of `a` to each prefix of `b`
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a_char != *b_char);
+            diagonal = row[j + 1];
+            row[j + 1] = (diagonal + 1).min(row[j] + 1).min(substitution);
+        }
+    }
+    row[b.len()]
+}
+
 /// Macro that can wrap any body, and only executes the body if we are running
 /// in debug mode. Debug mode is enabled by setting the environment variable
 /// `DEBUG=true`. This compiles away to nothing when --release is used.
@@ -175,3 +279,143 @@ macro_rules! debug {
         }
     };
 }
+
+/// Generate an endless stream of pseudo-random values from a seed, using
+/// SplitMix64. The same seed always gives the same values, on every platform.
+/// This is only meant for making garbage, not for anything that needs real
+/// randomness.
+pub(crate) fn seeded_values(seed: u64) -> impl Iterator<Item = LangValue> {
+    let mut state = seed;
+    iter::repeat_with(move || {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        // Truncating is fine, every bit is equally random
+        (z ^ (z >> 31)) as LangValue
+    })
+}
+
+/// A list of key-value pairs that serializes as a map, in the order of the
+/// list. Collecting into a `BTreeMap` would sort by key instead, which puts
+/// names like `RS0` before `RZR`. Used to hand ordered objects to JS.
+#[cfg(feature = "wasm")]
+pub(crate) struct OrderedMap<K, V>(Vec<(K, V)>);
+
+#[cfg(feature = "wasm")]
+impl<K, V> iter::FromIterator<(K, V)> for OrderedMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl<K: Serialize, V: Serialize> Serialize for OrderedMap<K, V> {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(key, value)| (key, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render the highlights for a span into a string
+    fn highlights(span: Span, src: &str) -> String {
+        struct Highlights<'a>(Span, &'a str);
+        impl fmt::Display for Highlights<'_> {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                fmt_src_highlights(f, &self.0, self.1)
+            }
+        }
+        Highlights(span, src).to_string()
+    }
+
+    #[test]
+    fn test_highlights_multi_line() {
+        let src = "READ RX0\nWRITE RX0\nSET RX0 1\nWRITE RX0\n";
+        // Short spans get every line rendered
+        assert_eq!(
+            highlights(
+                Span {
+                    source_id: None,
+                    offset: 5,
+                    length: 10,
+                    start_line: 1,
+                    start_col: 6,
+                    end_line: 2,
+                    end_col: 6,
+                },
+                src
+            ),
+            "
+  |
+1 | READ RX0
+  |      ^^^
+2 | WRITE RX0
+  | ^^^^^"
+        );
+
+        // Long spans only get the first and last line
+        assert_eq!(
+            highlights(
+                Span {
+                    source_id: None,
+                    offset: 0,
+                    length: 38,
+                    start_line: 1,
+                    start_col: 1,
+                    end_line: 4,
+                    end_col: 10,
+                },
+                src
+            ),
+            "
+  |
+1 | READ RX0
+  | ^^^^^^^^
+...
+4 | WRITE RX0
+  | ^^^^^^^^^"
+        );
+    }
+
+    #[test]
+    fn test_to_utf16_cols() {
+        let src = "READ RX0\nWRITE 'é' | WRITE '👋' | WRITE RX0";
+        let span = |offset, length, start_col, end_col| Span {
+            source_id: None,
+            offset,
+            length,
+            start_line: 2,
+            start_col,
+            end_line: 2,
+            end_col,
+        };
+        // ASCII and accented characters are one code unit each
+        assert_eq!(span(9, 5, 1, 6).to_utf16_cols(src), (1, 6));
+        assert_eq!(span(15, 4, 7, 10).to_utf16_cols(src), (7, 10));
+        // Emoji are two code units, which shifts everything after them
+        assert_eq!(span(28, 6, 19, 22).to_utf16_cols(src), (19, 23));
+        assert_eq!(span(43, 3, 31, 34).to_utf16_cols(src), (32, 35));
+        // Empty spans still cover one column
+        assert_eq!(span(43, 0, 31, 32).to_utf16_cols(src), (32, 33));
+        // With the wrong source, offsets can land inside a character
+        assert_eq!(span(29, 2, 19, 21).to_utf16_cols(src), (19, 21));
+        assert_eq!(span(1, 1, 4, 5).to_utf16_cols("é"), (4, 5));
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("READ", "READ"), 0);
+        assert_eq!(edit_distance("REED", "READ"), 1);
+        assert_eq!(edit_distance("PSH", "PUSH"), 1);
+        assert_eq!(edit_distance("READE", "READ"), 1);
+        assert_eq!(edit_distance("WRTIE", "WRITE"), 2);
+        assert_eq!(edit_distance("", "JMP"), 3);
+        assert_eq!(edit_distance("BANANA", "NOP"), 5);
+    }
+}
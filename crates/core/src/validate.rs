@@ -1,10 +1,10 @@
 use crate::{
     ast::{
-        source::{LabelDecl, Program, Statement},
-        Instruction, Label, Node, RegisterRef, SpanNode, StackId, StackRef,
-        ValueSource,
+        source::{LabelDecl, Program, Statement, ValidatedProgram},
+        Instruction, InstructionKind, Label, Node, RegisterRef, SpanNode,
+        StackId, StackRef, ValueSource,
     },
-    error::{CompileError, SourceErrorWrapper, WithSource},
+    error::{AvailableRefs, CompileError, LintWarning, WithSource},
     models::HardwareSpec,
     util::Span,
     Compiler, ProgramStats,
@@ -12,8 +12,10 @@ use crate::{
 use std::collections::{HashMap, HashSet};
 
 struct Context<'a> {
-    hardware_spec: HardwareSpec,
+    hardware_spec: &'a HardwareSpec,
     labels: HashMap<&'a Label, Span>,
+    /// All labels that are the target of at least one jump
+    referenced_labels: HashSet<&'a Label>,
     stats: ProgramStats,
 }
 
@@ -48,8 +50,11 @@ impl Validate for SpanNode<Label> {
         context: &mut Context,
         errors: &mut Vec<(CompileError, Span)>,
     ) {
-        if !context.labels.contains_key(&self.value()) {
-            errors.push((CompileError::InvalidLabel, *self.metadata()))
+        match context.labels.get_key_value(self.value()) {
+            Some((label, _)) => {
+                context.referenced_labels.insert(*label);
+            }
+            None => errors.push((CompileError::InvalidLabel, *self.metadata())),
         }
     }
 }
@@ -64,18 +69,24 @@ impl Validate for SpanNode<RegisterRef> {
     ) {
         // Track this reference in the stats
         context.add_register_ref(*self.value());
-        match self {
-            Node(RegisterRef::StackLength(stack_ref), span)
-                if !is_stack_id_valid(context.hardware_spec, *stack_ref) =>
-            {
-                errors.push((CompileError::InvalidRegisterRef, *span))
-            }
-            Node(RegisterRef::User(reg_ref), span) => {
-                if *reg_ref >= context.hardware_spec.num_registers {
-                    errors.push((CompileError::InvalidRegisterRef, *span))
+        if !context.hardware_spec.is_valid_register(*self.value()) {
+            // Only user and stack length registers can be out of range, and
+            // RZC can be turned off
+            let available = match self.value() {
+                RegisterRef::NullCount => AvailableRefs::NullCount,
+                RegisterRef::StackLength(_) => {
+                    AvailableRefs::StackLengthRegisters(
+                        context.hardware_spec.num_stacks,
+                    )
                 }
-            }
-            _ => {}
+                _ => AvailableRefs::UserRegisters(
+                    context.hardware_spec.num_registers,
+                ),
+            };
+            errors.push((
+                CompileError::InvalidRegisterRef { available },
+                *self.metadata(),
+            ))
         }
     }
 }
@@ -83,14 +94,15 @@ impl Validate for SpanNode<RegisterRef> {
 impl Validate for SpanNode<ValueSource<Span>> {
     /// Ensures the given ValueSource is valid. All constants are valid, but
     /// register references need to be validated to make sure they refer to real
-    /// registers.
+    /// registers. Named constants can't be checked until the program spec is
+    /// known, so they're always valid here.
     fn validate(
         &self,
         context: &mut Context,
         errors: &mut Vec<(CompileError, Span)>,
     ) {
         match self.value() {
-            ValueSource::Const(_) => {}
+            ValueSource::Const(_) | ValueSource::Constant(_) => {}
             ValueSource::Register(reg) => reg.validate(context, errors),
         }
     }
@@ -107,7 +119,14 @@ impl Validate for SpanNode<StackRef> {
         // Track this reference in the stats
         context.add_stack_ref(*self.value());
         if !is_stack_id_valid(context.hardware_spec, self.value().0) {
-            errors.push((CompileError::InvalidStackRef, *self.metadata()))
+            errors.push((
+                CompileError::InvalidStackRef {
+                    available: AvailableRefs::Stacks(
+                        context.hardware_spec.num_stacks,
+                    ),
+                },
+                *self.metadata(),
+            ))
         }
     }
 }
@@ -118,26 +137,46 @@ impl Validate for SpanNode<Instruction<Span>> {
         context: &mut Context,
         errors: &mut Vec<(CompileError, Span)>,
     ) {
+        let kind = self.value().kind();
+        let allowed = context.hardware_spec.is_instruction_allowed(kind);
+        if !allowed {
+            errors.push((
+                CompileError::ForbiddenInstruction { kind },
+                *self.metadata(),
+            ));
+        }
+
         match self.value() {
-            Instruction::Read(reg_ref) => {
+            Instruction::Read(reg_ref) | Instruction::Rdz(reg_ref) => {
                 reg_ref.validate(context, errors);
-                validate_writable(errors, reg_ref);
+                validate_writable(context, errors, reg_ref);
             }
             Instruction::Write(val_src) => val_src.validate(context, errors),
             Instruction::Set(reg_ref, val_src)
             | Instruction::Add(reg_ref, val_src)
             | Instruction::Sub(reg_ref, val_src)
             | Instruction::Mul(reg_ref, val_src)
-            | Instruction::Div(reg_ref, val_src) => {
+            | Instruction::Div(reg_ref, val_src)
+            | Instruction::And(reg_ref, val_src)
+            | Instruction::Or(reg_ref, val_src)
+            | Instruction::Xor(reg_ref, val_src)
+            | Instruction::Shl(reg_ref, val_src)
+            | Instruction::Shr(reg_ref, val_src) => {
                 // Make sure the first reg is valid and writable, and the
                 // second is a valid value source
                 reg_ref.validate(context, errors);
-                validate_writable(errors, reg_ref);
+                validate_writable(context, errors, reg_ref);
                 val_src.validate(context, errors);
             }
+            Instruction::Swp(reg_ref_1, reg_ref_2) => {
+                reg_ref_1.validate(context, errors);
+                validate_writable(context, errors, reg_ref_1);
+                reg_ref_2.validate(context, errors);
+                validate_writable(context, errors, reg_ref_2);
+            }
             Instruction::Cmp(reg_ref, val_src_1, val_src_2) => {
                 reg_ref.validate(context, errors);
-                validate_writable(errors, reg_ref);
+                validate_writable(context, errors, reg_ref);
                 val_src_1.validate(context, errors);
                 val_src_2.validate(context, errors);
             }
@@ -145,9 +184,18 @@ impl Validate for SpanNode<Instruction<Span>> {
                 val_src.validate(context, errors);
                 stack_ref.validate(context, errors);
             }
-            Instruction::Pop(stack_ref, reg_ref) => {
+            Instruction::Pop(stack_ref, reg_ref)
+            | Instruction::Peek(stack_ref, reg_ref) => {
                 stack_ref.validate(context, errors);
                 reg_ref.validate(context, errors);
+                validate_writable(context, errors, reg_ref);
+            }
+            Instruction::Popn(stack_ref, val_src) => {
+                stack_ref.validate(context, errors);
+                val_src.validate(context, errors);
+            }
+            Instruction::Dup(stack_ref) | Instruction::Sswp(stack_ref) => {
+                stack_ref.validate(context, errors);
             }
 
             // Jumps
@@ -161,6 +209,54 @@ impl Validate for SpanNode<Instruction<Span>> {
                 val_src.validate(context, errors);
                 label.validate(context, errors);
             }
+            // The target can only be checked at runtime
+            Instruction::Jri(reg_ref) => reg_ref.validate(context, errors),
+
+            // Subroutines
+            Instruction::Call(label) => {
+                label.validate(context, errors);
+                validate_call_stack(context, errors, allowed, self);
+            }
+            Instruction::Ret => {
+                validate_call_stack(context, errors, allowed, self);
+            }
+
+            // Nothing to validate
+            Instruction::Nop => {}
+
+            // Debugging
+            Instruction::Dump => {
+                // Don't pile on if it's already forbidden
+                if allowed && !context.hardware_spec.dump_enabled {
+                    errors.push((
+                        CompileError::DisabledInstruction,
+                        *self.metadata(),
+                    ))
+                }
+            }
+
+            // Host integration
+            Instruction::Ext(id, reg_ref) => {
+                let num_extern_ops = context.hardware_spec.num_extern_ops;
+                if num_extern_ops == 0 {
+                    // Don't pile on if it's already forbidden
+                    if allowed {
+                        errors.push((
+                            CompileError::DisabledInstruction,
+                            *self.metadata(),
+                        ))
+                    }
+                } else if *id.value() >= num_extern_ops {
+                    errors.push((
+                        CompileError::InvalidExternOp {
+                            available: AvailableRefs::ExternOps(num_extern_ops),
+                        },
+                        *id.metadata(),
+                    ))
+                }
+                reg_ref.validate(context, errors);
+                validate_writable(context, errors, reg_ref);
+            }
         }
     }
 }
@@ -182,26 +278,50 @@ impl Validate for SpanNode<Statement<Span>> {
 
 /// Helper method to change if a stack reference is in range. This is used for
 /// mutliple error types so the comparison logic is pulled out here.
-fn is_stack_id_valid(hardware_spec: HardwareSpec, stack_id: StackId) -> bool {
+fn is_stack_id_valid(hardware_spec: &HardwareSpec, stack_id: StackId) -> bool {
     stack_id < hardware_spec.num_stacks
 }
 
-/// Ensures the register reference refers to a writable register.
+/// Ensures the register reference refers to a writable register. If the
+/// register doesn't exist at all, that's handled by the normal register
+/// validation, so no error is added here.
 fn validate_writable(
+    context: &Context,
     errors: &mut Vec<(CompileError, Span)>,
     reg_ref_node: &SpanNode<RegisterRef>,
 ) {
-    // Only User registers are writable, all others cause an error.
-    match reg_ref_node {
-        Node(RegisterRef::Null | RegisterRef::User(_), _) => {}
-        Node(RegisterRef::InputLength | RegisterRef::StackLength(_), span) => {
-            errors.push((CompileError::UnwritableRegister, *span))
-        }
+    let register = *reg_ref_node.value();
+    if context.hardware_spec.is_valid_register(register)
+        && !context.hardware_spec.is_writable_register(register)
+    {
+        errors.push((
+            CompileError::UnwritableRegister {
+                available: AvailableRefs::UserRegisters(
+                    context.hardware_spec.num_registers,
+                ),
+            },
+            *reg_ref_node.metadata(),
+        ))
+    }
+}
+
+/// Ensures the hardware has a call stack, which `CALL` and `RET` need. If the
+/// instruction is already forbidden, no error is added here.
+fn validate_call_stack(
+    context: &Context,
+    errors: &mut Vec<(CompileError, Span)>,
+    allowed: bool,
+    instr_node: &SpanNode<Instruction<Span>>,
+) {
+    if allowed && context.hardware_spec.max_call_depth == 0 {
+        errors.push((CompileError::DisabledInstruction, *instr_node.metadata()))
     }
 }
 
 /// Collect all labels in the program into a set. Returns errors for any
-/// duplicate labels.
+/// duplicate labels, and any labels that look like an instruction. Every
+/// repeat of a label gets its own error, pointing back to the first
+/// declaration.
 fn collect_labels<'a>(
     errors: &mut Vec<(CompileError, Span)>,
     body: &'a [SpanNode<Statement<Span>>],
@@ -209,7 +329,13 @@ fn collect_labels<'a>(
     let mut labels: HashMap<&'a Label, Span> = HashMap::new();
     for stmt in body {
         if let Node(Statement::Label(Node(LabelDecl(label), span)), _) = stmt {
-            // insert returns false if the value was already present
+            // Instructions are case-insensitive, so labels have to be too
+            if InstructionKind::ALL
+                .iter()
+                .any(|kind| kind.name().eq_ignore_ascii_case(label))
+            {
+                errors.push((CompileError::ReservedLabel, *span));
+            }
             if let Some(original_span) = labels.get(&label) {
                 errors.push((
                     CompileError::DuplicateLabel {
@@ -225,59 +351,195 @@ fn collect_labels<'a>(
     labels
 }
 
+/// Ensures the program fits in the hardware's instruction limit, if it has
+/// one. Labels aren't instructions, so they don't count. The error points at
+/// the first instruction past the limit.
+fn validate_length<'a>(
+    hardware_spec: &HardwareSpec,
+    errors: &mut Vec<(CompileError, Span)>,
+    instructions: impl Iterator<Item = &'a Span>,
+) {
+    if let Some(max) = hardware_spec.max_instructions {
+        let spans: Vec<&Span> = instructions.collect();
+        if spans.len() > max {
+            errors.push((
+                CompileError::ProgramTooLong {
+                    actual: spans.len(),
+                    max,
+                },
+                *spans[max],
+            ));
+        }
+    }
+}
+
 /// Collects all the validation errors in all the instructions in the body.
+/// Any lint warnings, which don't prevent compilation, are added to the given
+/// list.
 fn validate_body(
-    hardware_spec: HardwareSpec,
+    hardware_spec: &HardwareSpec,
     body: &[SpanNode<Statement<Span>>],
+    warnings: &mut Vec<(LintWarning, Span)>,
 ) -> (ProgramStats, Vec<(CompileError, Span)>) {
     let mut errors = Vec::new();
     let labels = collect_labels(&mut errors, body);
     let mut context = Context {
         hardware_spec,
         labels,
+        referenced_labels: HashSet::new(),
         // This will be updated as we traverse the tree
-        stats: ProgramStats {
-            referenced_registers: HashSet::new(),
-            referenced_stacks: HashSet::new(),
-        },
+        stats: ProgramStats::default(),
     };
 
     // Add in errors for each statement
     for stmt in body.iter() {
         stmt.validate(&mut context, &mut errors);
     }
+    validate_length(
+        hardware_spec,
+        &mut errors,
+        body.iter().filter_map(|stmt| match stmt.value() {
+            Statement::Instruction(instr) => Some(instr.metadata()),
+            Statement::Label(_) => None,
+        }),
+    );
+
+    // Any label that never got jumped to is probably a mistake
+    let mut unused_labels: Vec<_> = context
+        .labels
+        .iter()
+        .filter(|(label, _)| !context.referenced_labels.contains(*label))
+        .map(|(_, span)| (LintWarning::UnusedLabel, *span))
+        .collect();
+    // Keep warnings in source order, since the map is unordered
+    unused_labels.sort_by_key(|(_, span)| span.offset);
+    warnings.extend(unused_labels);
 
     (context.stats, errors)
 }
 
 impl Compiler<Program<Span>> {
-    /// Performs all possible static validation on the program. The
-    /// hardware is needed to determine what values and references
-    /// are valid. If any errors occur, `Err` will be returned with all the
+    /// Performs all possible static validation on the program, under the
+    /// given hardware. The hardware is needed to determine what values and
+    /// references are valid, and the program will be compiled for it from
+    /// here on. If any errors occur, `Err` will be returned with all the
     /// errors in a collection.
     ///
     /// This step also collects static statistics on the program, such as
     /// which registers were referenced, which stats were referenced, etc. See
     /// [ProgramStats] for all the stats that are collected.
-    pub(crate) fn validate(
+    pub fn validate(
         self,
-    ) -> Result<Compiler<(Program<Span>, ProgramStats)>, WithSource<CompileError>>
+        hardware_spec: HardwareSpec,
+    ) -> Result<Compiler<ValidatedProgram<Span>>, WithSource<CompileError>>
     {
-        let (stats, errors) = validate_body(self.hardware_spec, &self.ast.body);
+        let mut warnings = Vec::new();
+        let (stats, errors) =
+            validate_body(&hardware_spec, &self.ast.body, &mut warnings);
         if errors.is_empty() {
+            // Tack our new warnings onto any from previous stages
+            let source = &self.source;
+            let includes = &self.includes;
+            let warnings = self
+                .warnings
+                .into_iter()
+                .chain(warnings.into_iter().map(|(warning, span)| {
+                    includes.wrap_error(warning, span, source)
+                }))
+                .collect();
             Ok(Compiler {
                 source: self.source,
-                hardware_spec: self.hardware_spec,
-                ast: (self.ast, stats),
+                hardware_spec,
+                includes: self.includes,
+                ast: ValidatedProgram {
+                    program: self.ast,
+                    stats,
+                },
+                warnings,
+                metadata: self.metadata,
             })
         } else {
             let errors: Vec<_> = errors
                 .into_iter()
                 .map(|(error, span)| {
-                    SourceErrorWrapper::new(error, span, &self.source)
+                    self.includes.wrap_error(error, span, &self.source)
+                })
+                .collect();
+            Err(WithSource::new(errors, self.source)
+                .with_includes(self.includes))
+        }
+    }
+}
+
+// Only needed for loading cached programs
+#[cfg(feature = "serde")]
+impl Compiler<crate::ast::compiled::Program<Span>> {
+    /// Validate an already compiled program against the hardware again. This
+    /// is needed for programs that weren't compiled by this compiler (e.g.
+    /// loaded from a cache), to make sure they don't reference anything that
+    /// the hardware doesn't have. Lints aren't checked, since they already
+    /// were during the original compilation. The references are recorded
+    /// into the program's stats again, so they can't be out of date.
+    pub(crate) fn revalidate(
+        &mut self,
+    ) -> Result<(), WithSource<CompileError>> {
+        let mut errors = Vec::new();
+        let mut context = Context {
+            hardware_spec: &self.hardware_spec,
+            // Label declarations don't exist anymore, so there's no span for
+            // them. The spans are only used for lints anyway.
+            labels: self
+                .ast
+                .symbol_table
+                .keys()
+                .map(|label| (label, Span::default()))
+                .collect(),
+            referenced_labels: HashSet::new(),
+            stats: ProgramStats::default(),
+        };
+
+        for instruction in &self.ast.instructions {
+            instruction.validate(&mut context, &mut errors);
+        }
+        validate_length(
+            &self.hardware_spec,
+            &mut errors,
+            self.ast.instructions.iter().map(Node::metadata),
+        );
+        // A label can point one past the last instruction, but no further
+        let num_instructions = self.ast.instructions.len();
+        if self
+            .ast
+            .symbol_table
+            .values()
+            .any(|index| *index > num_instructions)
+        {
+            // There's no label declaration to point to, so point at the
+            // start of the source. This still has to be a renderable span.
+            let span = Span {
+                start_line: 1,
+                start_col: 1,
+                end_line: 1,
+                end_col: 2,
+                ..Span::default()
+            };
+            errors.push((CompileError::InvalidLabel, span));
+        }
+
+        if errors.is_empty() {
+            let stats = context.stats;
+            self.ast.stats.referenced_registers = stats.referenced_registers;
+            self.ast.stats.referenced_stacks = stats.referenced_stacks;
+            Ok(())
+        } else {
+            let errors: Vec<_> = errors
+                .into_iter()
+                .map(|(error, span)| {
+                    self.includes.wrap_error(error, span, &self.source)
                 })
                 .collect();
-            Err(WithSource::new(errors, self.source))
+            Err(WithSource::new(errors, self.source.clone())
+                .with_includes(self.includes.clone()))
         }
     }
 }
@@ -0,0 +1,110 @@
+//! Integration tests for compiling arbitrary (usually garbage) input. No
+//! matter what the source is, compiling it should give either a program or
+//! compile errors, and never panic.
+
+use gdlk::{Compiler, HardwareSpec, MAX_REF_ID};
+use proptest::prelude::*;
+
+/// Compile the source, and render the result (including any errors with their
+/// source highlights), to make sure nothing along the way panics. Returns the
+/// error messages, without highlights.
+fn compile(src: &str) -> Vec<String> {
+    let hardware_spec = HardwareSpec {
+        num_stacks: 1,
+        ..Default::default()
+    };
+    match Compiler::compile(src, hardware_spec) {
+        Ok(_) => vec![],
+        Err(errors) => {
+            let _ = errors.to_string();
+            errors.errors().iter().map(|err| err.to_string()).collect()
+        }
+    }
+}
+
+#[test]
+fn test_huge_ref_ids() {
+    assert_eq!(
+        compile("READ RX99999999999999999999"),
+        &[
+            "Syntax error at 1:8: ID `99999999999999999999` is too large, the \
+            maximum is 65535"
+        ]
+    );
+    assert_eq!(
+        compile("PUSH 1 S99999999999999999999\nWRITE RS123456\nEXT 70000 RX0"),
+        &[
+            "Syntax error at 1:9: ID `99999999999999999999` is too large, \
+                the maximum is 65535",
+            "Syntax error at 2:9: ID `123456` is too large, the maximum is \
+                65535",
+            "Syntax error at 3:5: ID `70000` is too large, the maximum is \
+                65535",
+        ]
+    );
+
+    // The largest ID parses, and then fails validation like any other
+    // missing register
+    assert_eq!(
+        compile(&format!("READ RX{}", MAX_REF_ID)),
+        &["Validation error at 1:6: Invalid reference to register \
+            `RX65535` (this hardware has register RX0)"]
+    );
+}
+
+#[test]
+fn test_non_ascii() {
+    // Columns count characters, not bytes
+    assert_eq!(
+        compile("READ RX0 é"),
+        &["Syntax error at 1:10: Too many arguments for `READ`, \
+            unexpected `é`"]
+    );
+    assert_eq!(
+        compile("WRITE 'é'\n; ünïcödé\nSET RX0 'ü' ; 😀\nREAD 😀"),
+        &["Syntax error at 4:6: Expected register reference"]
+    );
+    assert_eq!(
+        Compiler::compile("WRITE 'ü' RX1", HardwareSpec::default())
+            .unwrap_err()
+            .to_string(),
+        "Syntax error at 1:11: Too many arguments for `WRITE`, unexpected \
+            `RX1`
+  |
+1 | WRITE 'ü' RX1
+  |           ^^^"
+    );
+
+    for src in &[
+        "é",
+        "😀:",
+        "\u{0}",
+        "READ\u{0}RX0",
+        "\r",
+        "\u{feff}READ RX0",
+    ] {
+        assert!(!compile(src).is_empty(), "{:?} should fail", src);
+    }
+}
+
+proptest! {
+    #[test]
+    fn test_random_bytes(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+        compile(&String::from_utf8_lossy(&bytes));
+    }
+
+    #[test]
+    fn test_random_tokens(
+        tokens in prop::collection::vec(
+            prop::sample::select(vec![
+                "READ", "WRITE", "SET", "PUSH", "POP", "JMP", "JRI", "CALL",
+                "RET", "%include", "RX", "RS", "S", "RZR", "RLI", "0", "9",
+                "99999999999999999999", "-", "0x", "'", "\"", ":", ";", "L",
+                " ", "\t", "\n", "\r", "é", "😀",
+            ]),
+            0..16,
+        )
+    ) {
+        compile(&tokens.concat());
+    }
+}
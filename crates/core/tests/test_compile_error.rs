@@ -1,7 +1,11 @@
 //! Integration tests for GDLK that expect compile errors. The programs in
 //! these tests should all fail during compilation.
 
-use gdlk::{ast::LangValue, Compiler, HardwareSpec};
+use gdlk::{
+    ast::{InstructionKind, LangValue},
+    CompileOptions, Compiler, HardwareSpec,
+};
+use std::collections::HashMap;
 
 /// Compiles the program for the given hardware, expecting compile error(s).
 /// Panics if the program compiles successfully, or if the wrong set of
@@ -9,13 +13,12 @@ use gdlk::{ast::LangValue, Compiler, HardwareSpec};
 macro_rules! assert_compile_errors {
     ($hw_spec:expr, $src:expr, $expected_errors:expr $(,)?) => {
         // Compile from hardware+src
-        let actual_errors: Vec<String> =
-            Compiler::compile($src.into(), $hw_spec)
-                .unwrap_err()
-                .errors()
-                .iter()
-                .map(|err| err.to_string())
-                .collect();
+        let actual_errors: Vec<String> = Compiler::compile($src, $hw_spec)
+            .unwrap_err()
+            .errors()
+            .iter()
+            .map(|err| err.to_string())
+            .collect();
         let strs: Vec<&str> =
             actual_errors.iter().map(String::as_str).collect();
         assert_eq!(strs.as_slice(), $expected_errors);
@@ -25,10 +28,14 @@ macro_rules! assert_compile_errors {
 /// Macro to compile a program and expect a particular compiler error.
 macro_rules! assert_parse_error {
     ($src:expr, $expected_error:expr $(,)?) => {
-        let actual_errors =
-            Compiler::compile($src.into(), HardwareSpec::default())
-                .unwrap_err();
-        assert_eq!(actual_errors.to_string(), $expected_error);
+        let actual_errors: Vec<String> =
+            Compiler::compile($src, HardwareSpec::default())
+                .unwrap_err()
+                .errors()
+                .iter()
+                .map(|err| err.to_string())
+                .collect();
+        assert_eq!(actual_errors, &[$expected_error]);
     };
 }
 
@@ -71,13 +78,160 @@ fn test_parse_errors_stacks() {
 
 #[test]
 fn test_parse_errors_simple_instructions() {
-    assert_parse_error!("RAD RX0", "Syntax error at 1:1: Expected statement");
-    assert_parse_error!("READE RX0", "Syntax error at 1:1: Expected statement");
+    assert_parse_error!(
+        "RAD RX0",
+        "Syntax error at 1:1: Unknown instruction `RAD`, did you mean `READ`?"
+    );
+    assert_parse_error!(
+        "READE RX0",
+        "Syntax error at 1:1: Unknown instruction `READE`, \
+            did you mean `READ`?"
+    );
     assert_parse_error!("PUSH STEVE S0", "Syntax error at 1:6: Expected value");
     assert_parse_error!(
         "READ RX1 WRITE RX2",
-        "Syntax error at 1:10: Expected end of statement",
+        "Syntax error at 1:10: Too many arguments for `READ`, \
+            unexpected `WRITE RX2`",
+    );
+}
+
+#[test]
+fn test_unknown_instruction() {
+    assert_parse_error!(
+        "REED RX0",
+        "Syntax error at 1:1: Unknown instruction `REED`, did you mean `READ`?"
+    );
+    assert_parse_error!(
+        "  psh RX0 S0 ; typo",
+        "Syntax error at 1:3: Unknown instruction `psh`, did you mean `PUSH`?"
+    );
+    assert_parse_error!(
+        "WRTIE 1",
+        "Syntax error at 1:1: Unknown instruction `WRTIE`, \
+            did you mean `WRITE`?"
+    );
+    assert_parse_error!(
+        "BANANA RX0",
+        "Syntax error at 1:1: Unknown instruction `BANANA`"
+    );
+
+    // The span covers just the unknown word
+    let errors = Compiler::compile("LBL:\n  REED RX0", HardwareSpec::default())
+        .unwrap_err();
+    let error = &errors.errors()[0];
+    assert_eq!(error.error_code(), "E004_UNKNOWN_INSTRUCTION");
+    let span = error.span();
+    assert_eq!((span.offset, span.length), (7, 4));
+    assert_eq!((span.start_line, span.start_col), (2, 3));
+    assert_eq!((span.end_line, span.end_col), (2, 7));
+
+    // Label declarations and references aren't mistaken for instructions
+    assert_compile_errors!(
+        HardwareSpec::default(),
+        "
+        FOO:
+        REED:
+        JMP REED
+        FOO RX0
+        ",
+        &["Syntax error at 5:9: Unknown instruction `FOO`"]
+    );
+}
+
+#[test]
+fn test_parse_errors_statement_separator() {
+    // There has to be a statement on both sides of every separator
+    assert_parse_error!(
+        "READ RX0 | | WRITE RX0",
+        "Syntax error at 1:12: Expected statement"
+    );
+    assert_parse_error!(
+        "READ RX0 |",
+        "Syntax error at 1:11: Expected statement"
+    );
+    assert_parse_error!(
+        "| READ RX0",
+        "Syntax error at 1:1: Expected statement"
+    );
+    // Once a comment starts, separators are just part of it
+    assert_parse_error!(
+        "READ RX0 ; c | WRTIE RX0\nWRTIE RX0",
+        "Syntax error at 2:1: Unknown instruction `WRTIE`, \
+            did you mean `WRITE`?"
+    );
+    assert_parse_error!(
+        "READ RX0 RX1 | WRITE RX0",
+        "Syntax error at 1:10: Too many arguments for `READ`, \
+            unexpected `RX1`"
+    );
+}
+
+#[test]
+fn test_program_too_long() {
+    let hardware_spec =
+        HardwareSpec::builder().max_instructions(3).build().unwrap();
+    // Labels don't count, so this is exactly at the limit
+    let src = "
+        START:
+        READ RX0
+        LOOP:
+        WRITE RX0
+        JMP LOOP
+        END:
+        ";
+    Compiler::compile(src, hardware_spec.clone()).unwrap();
+
+    // One more is too many. The error points at the first one past the limit.
+    assert_compile_errors!(
+        hardware_spec,
+        &format!("{}NOP\n        NOP", src),
+        &["Validation error at 8:9: Program has 5 instructions, \
+            but this hardware allows at most 3"],
+    );
+}
+
+#[test]
+fn test_too_many_arguments() {
+    assert_compile_errors!(
+        HardwareSpec {
+            num_registers: 2,
+            num_stacks: 1,
+            max_stack_length: 5,
+            ..Default::default()
+        },
+        "
+        RET RX0
+        READ RX0 RX1 ; comment
+        SET RX0 1 2\t3
+        CMP RX0 RX1 2 RX1   
+        LBL:
+        JMP LBL LBL
+        ",
+        &[
+            "Syntax error at 2:13: Too many arguments for `RET`, \
+                unexpected `RX0`",
+            "Syntax error at 3:18: Too many arguments for `READ`, \
+                unexpected `RX1`",
+            "Syntax error at 4:19: Too many arguments for `SET`, \
+                unexpected `2\t3`",
+            "Syntax error at 5:23: Too many arguments for `CMP`, \
+                unexpected `RX1`",
+            "Syntax error at 7:17: Too many arguments for `JMP`, \
+                unexpected `LBL`",
+        ],
     );
+
+    // The span covers exactly the extra arguments, without the surrounding
+    // whitespace or comment
+    let errors =
+        Compiler::compile("PUSH 1 S0 S0  RX0 ;c", HardwareSpec::default())
+            .unwrap_err();
+    let error = &errors.errors()[0];
+    assert_eq!(error.error_code(), "E002_TOO_MANY_ARGUMENTS");
+    let span = error.span();
+    assert_eq!((span.offset, span.length), (10, 7));
+    assert_eq!((span.start_line, span.start_col), (1, 11));
+    assert_eq!((span.end_line, span.end_col), (1, 18));
 }
 
 #[test]
@@ -107,15 +261,86 @@ fn test_parse_errors_constants() {
 
     // Out-of-range constants
     assert_parse_error!(
-        &format!("SET RX0 {}", LangValue::MAX as i64 + 1),
+        &format!("SET RX0 {}", LangValue::MAX as i128 + 1),
         "Syntax error at 1:9: Expected value"
     );
     assert_parse_error!(
-        &format!("SET RX0 {}", LangValue::MIN as i64 - 1),
+        &format!("SET RX0 {}", LangValue::MIN as i128 - 1),
         "Syntax error at 1:9: Expected value"
     );
 }
 
+#[test]
+fn test_parse_errors_literals() {
+    assert_parse_error!(
+        "WRITE 0x",
+        "Syntax error at 1:9: Expected hex literal"
+    );
+    assert_parse_error!(
+        &format!("WRITE 0x{:X}", LangValue::MAX as u128 + 1),
+        "Syntax error at 1:9: Expected hex literal"
+    );
+    assert_parse_error!("WRITE 0x1G", "Syntax error at 1:6: Expected value");
+    assert_parse_error!(
+        "WRITE -0x1F",
+        "Syntax error at 1:7: Expected hex literal without a sign"
+    );
+    assert_parse_error!(
+        "WRITE 'ab'",
+        "Syntax error at 1:8: Expected character literal"
+    );
+    assert_parse_error!(
+        "WRITE ''",
+        "Syntax error at 1:8: Expected character literal"
+    );
+    assert_parse_error!(
+        "WRITE 'A",
+        "Syntax error at 1:8: Expected character literal"
+    );
+    assert_parse_error!(
+        "WRITE $",
+        "Syntax error at 1:8: Expected constant name"
+    );
+    assert_parse_error!(
+        "WRITE $-1",
+        "Syntax error at 1:8: Expected constant name"
+    );
+}
+
+#[test]
+fn test_parse_multiple_errors() {
+    // Each bad line should produce exactly one error, and the good lines in
+    // between shouldn't be affected
+    assert_compile_errors!(
+        HardwareSpec::default(),
+        "
+        READ RX0 junk
+        WRITE RX0
+        READ RW0
+        SET RX0 1
+
+        FOO RX0
+        WRITE RX0
+        ",
+        &[
+            "Syntax error at 2:18: Too many arguments for `READ`, \
+                unexpected `junk`",
+            "Syntax error at 4:14: Expected register reference",
+            "Syntax error at 7:9: Unknown instruction `FOO`",
+        ]
+    );
+
+    // Errors on the first and last lines, with no trailing newline
+    assert_compile_errors!(
+        HardwareSpec::default(),
+        "READ\nWRITE 1\nJMP",
+        &[
+            "Syntax error at 1:5: Expected register reference",
+            "Syntax error at 3:4: Expected label",
+        ]
+    );
+}
+
 #[test]
 fn test_parse_empty_file() {
     assert_compile_errors!(
@@ -137,6 +362,7 @@ fn test_invalid_user_reg_ref() {
             num_registers: 1,
             num_stacks: 1,
             max_stack_length: 5,
+            ..Default::default()
         },
         "
         READ RX1
@@ -149,14 +375,22 @@ fn test_invalid_user_reg_ref() {
         POP S0 RX8
         ",
         &[
-            "Validation error at 2:14: Invalid reference to register `RX1`",
-            "Validation error at 3:15: Invalid reference to register `RX2`",
-            "Validation error at 4:13: Invalid reference to register `RX3`",
-            "Validation error at 5:13: Invalid reference to register `RX4`",
-            "Validation error at 6:13: Invalid reference to register `RX5`",
-            "Validation error at 7:13: Invalid reference to register `RX6`",
-            "Validation error at 8:14: Invalid reference to register `RX7`",
-            "Validation error at 9:16: Invalid reference to register `RX8`",
+            "Validation error at 2:14: Invalid reference to register `RX1` \
+                (this hardware has register RX0)",
+            "Validation error at 3:15: Invalid reference to register `RX2` \
+                (this hardware has register RX0)",
+            "Validation error at 4:13: Invalid reference to register `RX3` \
+                (this hardware has register RX0)",
+            "Validation error at 5:13: Invalid reference to register `RX4` \
+                (this hardware has register RX0)",
+            "Validation error at 6:13: Invalid reference to register `RX5` \
+                (this hardware has register RX0)",
+            "Validation error at 7:13: Invalid reference to register `RX6` \
+                (this hardware has register RX0)",
+            "Validation error at 8:14: Invalid reference to register `RX7` \
+                (this hardware has register RX0)",
+            "Validation error at 9:16: Invalid reference to register `RX8` \
+                (this hardware has register RX0)",
         ],
     );
 }
@@ -168,11 +402,15 @@ fn test_invalid_stack_reg_ref() {
             num_registers: 1,
             num_stacks: 1,
             max_stack_length: 5,
+            ..Default::default()
         },
         "
         SET RX0 RS1
         ",
-        &["Validation error at 2:17: Invalid reference to register `RS1`"],
+        &[
+            "Validation error at 2:17: Invalid reference to register `RS1` \
+            (this hardware has 1 stack, with length register RS0)"
+        ],
     );
 }
 
@@ -183,14 +421,42 @@ fn test_invalid_stack_ref() {
             num_registers: 1,
             num_stacks: 1,
             max_stack_length: 5,
+            ..Default::default()
         },
         "
         PUSH 5 S1
         POP S2 RX0
+        DUP S1
+        SSWP S3
         ",
         &[
-            "Validation error at 2:16: Invalid reference to stack `S1`",
-            "Validation error at 3:13: Invalid reference to stack `S2`",
+            "Validation error at 2:16: Invalid reference to stack `S1` \
+                (this hardware has stack S0)",
+            "Validation error at 3:13: Invalid reference to stack `S2` \
+                (this hardware has stack S0)",
+            "Validation error at 4:13: Invalid reference to stack `S1` \
+                (this hardware has stack S0)",
+            "Validation error at 5:14: Invalid reference to stack `S3` \
+                (this hardware has stack S0)",
+        ],
+    );
+    assert_compile_errors!(
+        HardwareSpec {
+            num_registers: 2,
+            num_stacks: 0,
+            ..Default::default()
+        },
+        "
+        PUSH RX2 S0
+        SET RX1 RS0
+        ",
+        &[
+            "Validation error at 2:14: Invalid reference to register `RX2` \
+                (this hardware has registers RX0-RX1)",
+            "Validation error at 2:18: Invalid reference to stack `S0` \
+                (this hardware has no stacks)",
+            "Validation error at 3:17: Invalid reference to register `RS0` \
+                (this hardware has no stacks)",
         ],
     );
 }
@@ -202,16 +468,473 @@ fn test_unwritable_reg() {
             num_registers: 1,
             num_stacks: 1,
             max_stack_length: 5,
+            ..Default::default()
         },
         "
         SET RLI 5
         SET RS0 5
+        RDZ RIH
+        PEEK S0 RLI
+        POP S0 RS0
         ",
         &[
             "Validation error at 2:13: \
-                Cannot write to read-only register `RLI`",
+                Cannot write to read-only register `RLI` \
+                (this hardware has register RX0)",
             "Validation error at 3:13: \
-                Cannot write to read-only register `RS0`",
+                Cannot write to read-only register `RS0` \
+                (this hardware has register RX0)",
+            "Validation error at 4:13: \
+                Cannot write to read-only register `RIH` \
+                (this hardware has register RX0)",
+            "Validation error at 5:17: \
+                Cannot write to read-only register `RLI` \
+                (this hardware has register RX0)",
+            "Validation error at 6:16: \
+                Cannot write to read-only register `RS0` \
+                (this hardware has register RX0)",
+        ],
+    );
+}
+
+#[test]
+fn test_null_count_reg() {
+    // RZC only exists if the hardware turns it on
+    assert_compile_errors!(
+        HardwareSpec::default(),
+        "WRITE RZC",
+        &[
+            "Validation error at 1:7: Invalid reference to register `RZC` \
+            (this hardware doesn't count writes to RZR)"
         ],
     );
+    // And even then, it can't be written to
+    assert_compile_errors!(
+        HardwareSpec {
+            null_count_enabled: true,
+            ..Default::default()
+        },
+        "SET RZC 1",
+        &["Validation error at 1:5: \
+            Cannot write to read-only register `RZC` \
+            (this hardware has register RX0)"],
+    );
+}
+
+#[test]
+fn test_swp_invalid() {
+    assert_compile_errors!(
+        HardwareSpec {
+            num_registers: 1,
+            num_stacks: 1,
+            max_stack_length: 5,
+            ..Default::default()
+        },
+        "
+        SWP RX0 RLI
+        SWP RS0 RX0
+        SWP RX1 RX0
+        ",
+        &[
+            "Validation error at 2:17: \
+                Cannot write to read-only register `RLI` \
+                (this hardware has register RX0)",
+            "Validation error at 3:13: \
+                Cannot write to read-only register `RS0` \
+                (this hardware has register RX0)",
+            "Validation error at 4:13: Invalid reference to register `RX1` \
+                (this hardware has register RX0)",
+        ],
+    );
+    // Constants can't be swapped
+    assert_parse_error!(
+        "SWP RX0 1",
+        "Syntax error at 1:9: Expected register reference"
+    );
+}
+
+#[test]
+fn test_dump_disabled() {
+    assert_compile_errors!(
+        HardwareSpec::default(),
+        "
+        READ RX0
+        DUMP
+        ",
+        &["Validation error at 3:9: Instruction `DUMP` is not enabled on this \
+            hardware"],
+    );
+}
+
+#[test]
+fn test_extern_ops() {
+    // Extern ops are disabled by default
+    assert_compile_errors!(
+        HardwareSpec::default(),
+        "EXT 0 RX0",
+        &[
+            "Validation error at 1:1: Instruction `EXT 0 RX0` is not enabled \
+            on this hardware"
+        ],
+    );
+    assert_compile_errors!(
+        HardwareSpec {
+            num_extern_ops: 2,
+            ..Default::default()
+        },
+        "
+        EXT 1 RX0
+        EXT 2 RX0
+        EXT 0 RLI
+        ",
+        &[
+            "Validation error at 3:13: Invalid reference to extern op `2` \
+                (this hardware has extern ops 0-1)",
+            "Validation error at 4:15: Cannot write to read-only register \
+                `RLI` (this hardware has register RX0)",
+        ],
+    );
+}
+
+#[test]
+fn test_call_stack_disabled() {
+    // A max call depth of zero disables CALL and RET, but the CALL target is
+    // still checked
+    assert_compile_errors!(
+        HardwareSpec {
+            max_call_depth: 0,
+            ..Default::default()
+        },
+        "
+        CALL FUNC
+        CALL NOWHERE
+        FUNC:
+        RET
+        ",
+        &[
+            "Validation error at 2:9: Instruction `CALL FUNC` is not enabled \
+                on this hardware",
+            "Validation error at 3:14: Invalid reference to label `NOWHERE`",
+            "Validation error at 3:9: Instruction `CALL NOWHERE` is not \
+                enabled on this hardware",
+            "Validation error at 5:9: Instruction `RET` is not enabled on this \
+                hardware",
+        ],
+    );
+}
+
+#[test]
+fn test_duplicate_labels() {
+    assert_compile_errors!(
+        HardwareSpec::default(),
+        "
+        LOOP:
+        READ RX0
+        LOOP:
+        JMP LOOP
+        ",
+        &[
+            "Validation error at 4:9: Duplicate declaration of label `LOOP`, \
+            originally defined on line 2"
+        ]
+    );
+    // Every repeat points back to the first declaration
+    assert_compile_errors!(
+        HardwareSpec::default(),
+        "
+        LOOP:
+        LOOP:
+        READ RX0
+        LOOP:
+        JMP LOOP
+        ",
+        &[
+            "Validation error at 3:9: Duplicate declaration of label `LOOP`, \
+                originally defined on line 2",
+            "Validation error at 5:9: Duplicate declaration of label `LOOP`, \
+                originally defined on line 2",
+        ]
+    );
+    // Labels are case-sensitive, so these are different
+    assert!(Compiler::compile("loop:\nLOOP:", HardwareSpec::default()).is_ok());
+}
+
+#[test]
+fn test_reserved_labels() {
+    assert_compile_errors!(
+        HardwareSpec::default(),
+        "
+        READ:
+        jmp:
+        JMP READ
+        ",
+        &[
+            "Validation error at 2:9: Label `READ` has the same name as an \
+                instruction",
+            "Validation error at 3:9: Label `jmp` has the same name as an \
+                instruction",
+        ]
+    );
+    // Only exact matches are reserved
+    assert!(
+        Compiler::compile("READS:\nJMP READS", HardwareSpec::default()).is_ok()
+    );
+}
+
+#[test]
+fn test_forbidden_instructions() {
+    // Every use of a forbidden instruction is reported
+    assert_compile_errors!(
+        HardwareSpec {
+            allowed_instructions: Some(
+                vec![
+                    InstructionKind::Read,
+                    InstructionKind::Write,
+                    InstructionKind::Add,
+                    InstructionKind::Jez,
+                ]
+                .into_iter()
+                .collect()
+            ),
+            dump_enabled: true,
+            ..Default::default()
+        },
+        "
+        LOOP:
+        JEZ RLI END
+        READ RX0
+        MUL RX0 2
+        ADD RX0 RX0
+        MUL RX0 RX0
+        WRITE RX0
+        DUMP
+        JMP LOOP
+        END:
+        ",
+        &[
+            "Validation error at 5:9: Instruction `MUL` is not allowed on \
+                this hardware",
+            "Validation error at 7:9: Instruction `MUL` is not allowed on \
+                this hardware",
+            "Validation error at 9:9: Instruction `DUMP` is not allowed on \
+                this hardware",
+            "Validation error at 10:9: Instruction `JMP` is not allowed on \
+                this hardware",
+        ],
+    );
+
+    // Operands are still validated on forbidden instructions
+    assert_compile_errors!(
+        HardwareSpec {
+            allowed_instructions: Some(Default::default()),
+            ..Default::default()
+        },
+        "SET RX1 1",
+        &[
+            "Validation error at 1:1: Instruction `SET` is not allowed on \
+                this hardware",
+            "Validation error at 1:5: Invalid reference to register `RX1` \
+                (this hardware has register RX0)",
+        ],
+    );
+}
+
+#[test]
+fn test_writability_matches_register_set() {
+    let hardware_spec = HardwareSpec {
+        num_registers: 2,
+        num_stacks: 2,
+        max_stack_length: 5,
+        ..Default::default()
+    };
+    // Validation should agree with the register set on every register
+    for info in hardware_spec.register_set().iter() {
+        let result = Compiler::compile(
+            format!("SET {} 1", info.register),
+            hardware_spec.clone(),
+        );
+        assert_eq!(result.is_ok(), info.writable, "{}", info.register);
+    }
+}
+
+#[test]
+fn test_error_columns_multi_byte() {
+    // Columns count characters, no matter how many bytes each one takes
+    let src = "; Héllo 👋\nWRITE '😀' | WRITE 'é' | REED RX0";
+    let errors = Compiler::compile(src, HardwareSpec::default()).unwrap_err();
+    let error = &errors.errors()[0];
+    assert_eq!(
+        error.to_string(),
+        "Syntax error at 2:25: Unknown instruction `REED`, did you mean `READ`?"
+    );
+    let span = error.span();
+    assert_eq!(span.get_source_slice(src), "REED");
+    assert_eq!((span.start_col, span.end_col), (25, 29));
+    // The emoji takes two UTF-16 code units, the accent only takes one
+    assert_eq!(span.to_utf16_cols(src), (26, 30));
+    assert_eq!(
+        errors.to_string(),
+        "Syntax error at 2:25: Unknown instruction `REED`, did you mean `READ`?
+  |
+2 | WRITE '😀' | WRITE 'é' | REED RX0
+  |                         ^^^^"
+    );
+}
+
+#[test]
+fn test_render_errors() {
+    let errors = Compiler::compile(
+        "READ RW0\nWRITE RX0\nJMP\n",
+        HardwareSpec::default(),
+    )
+    .unwrap_err();
+    assert_eq!(
+        errors.to_string(),
+        "Syntax error at 1:6: Expected register reference
+  |
+1 | READ RW0
+  |      ^
+
+Syntax error at 3:4: Expected label
+  |
+3 | JMP
+  |    ^"
+    );
+
+    // The margin grows to fit the line number
+    let errors = Compiler::compile(
+        format!("{}SET RX1 1", "\n".repeat(9)),
+        HardwareSpec::default(),
+    )
+    .unwrap_err();
+    assert_eq!(
+        errors.to_string(),
+        "Validation error at 10:5: Invalid reference to register `RX1` \
+            (this hardware has register RX0)
+   |
+10 | SET RX1 1
+   |     ^^^"
+    );
+}
+
+#[test]
+fn test_include_errors() {
+    // Includes are disabled unless a resolver is given
+    assert_parse_error!(
+        "%include \"lib.gdlk\"\nREAD RX0",
+        "Include error at 1:11: Cannot include `lib.gdlk`, \
+            includes are not supported here",
+    );
+    assert_parse_error!(
+        "%include lib.gdlk",
+        "Syntax error at 1:9: Expected include path"
+    );
+
+    let files: HashMap<String, String> = [
+        ("syntax.gdlk", "READ RX0\nREAD RW0\n"),
+        ("invalid.gdlk", "\nSET RX1 1"),
+        ("a.gdlk", "%include \"b.gdlk\""),
+        ("b.gdlk", "NOP\n%include \"a.gdlk\""),
+    ]
+    .iter()
+    .map(|(path, code)| ((*path).to_owned(), (*code).to_owned()))
+    .collect();
+    let compile_errors = |src: &str| -> Vec<String> {
+        Compiler::compile_with_resolver(src, HardwareSpec::default(), &files)
+            .unwrap_err()
+            .errors()
+            .iter()
+            .map(|err| err.to_string())
+            .collect()
+    };
+
+    // Errors in included files point into those files
+    assert_eq!(
+        compile_errors("%include \"missing.gdlk\"\n%include \"syntax.gdlk\""),
+        &[
+            "Include error at 1:11: Cannot include `missing.gdlk`, \
+                file not found",
+            "Syntax error at syntax.gdlk:2:6: Expected register reference",
+        ]
+    );
+    assert_eq!(
+        compile_errors("READ RX0\n%include \"invalid.gdlk\""),
+        &[
+            "Validation error at invalid.gdlk:2:5: Invalid reference to \
+            register `RX1` (this hardware has register RX0)"
+        ]
+    );
+    assert_eq!(
+        compile_errors("%include \"a.gdlk\""),
+        &[
+            "Include error at b.gdlk:2:11: Include cycle, `a.gdlk` includes \
+            itself"
+        ]
+    );
+
+    // The source of the included file gets rendered
+    let errors = Compiler::compile_with_resolver(
+        "READ RX0\n%include \"invalid.gdlk\"",
+        HardwareSpec::default(),
+        &files,
+    )
+    .unwrap_err();
+    assert_eq!(
+        errors.to_string(),
+        "Validation error at invalid.gdlk:2:5: Invalid reference to register \
+            `RX1` (this hardware has register RX0)
+  |
+2 | SET RX1 1
+  |     ^^^"
+    );
+    // Each span says which file it's in, and the source is available by ID
+    let span = errors.errors()[0].span();
+    assert_eq!(span.source_id, Some(0));
+    assert_eq!(errors.source(span.source_id), "\nSET RX1 1");
+    assert_eq!(errors.source(None), "READ RX0\n%include \"invalid.gdlk\"");
+}
+
+#[test]
+fn test_max_errors() {
+    let compile = |src: &str, max_errors: Option<usize>| {
+        Compiler::compile_with_options(
+            src,
+            HardwareSpec::default(),
+            CompileOptions {
+                max_errors,
+                ..Default::default()
+            },
+        )
+        .unwrap_err()
+    };
+
+    // Syntax errors from parse recovery get capped...
+    let errors = compile("READ\nREAD\nREAD\nREAD\nREAD", Some(2));
+    assert_eq!(errors.errors().len(), 2);
+    assert_eq!(errors.truncated_count(), 3);
+    assert!(errors.to_string().ends_with("\n\n...and 3 more errors"));
+
+    // ...and so do validation errors
+    let src = "READ RX1\nREAD RX2\nREAD RX3\nJMP NOWHERE";
+    let errors = compile(src, Some(3));
+    assert_eq!(errors.errors().len(), 3);
+    assert_eq!(errors.truncated_count(), 1);
+    assert_eq!(
+        errors.errors()[2].to_string(),
+        "Validation error at 3:6: Invalid reference to register `RX3` \
+            (this hardware has register RX0)"
+    );
+    assert!(errors.to_string().ends_with("\n\n...and 1 more error"));
+
+    // No limit by default
+    let errors = compile(src, None);
+    assert_eq!(errors.errors().len(), 4);
+    assert_eq!(errors.truncated_count(), 0);
+    assert!(!errors.to_string().contains("more error"));
+
+    // Limiting after the fact adds to what was already truncated
+    let limited = compile(src, Some(3)).errors_limited(1);
+    assert_eq!(limited.errors().len(), 1);
+    assert_eq!(limited.truncated_count(), 3);
+    assert_eq!(errors.errors_limited(10).truncated_count(), 0);
 }
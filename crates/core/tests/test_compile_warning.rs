@@ -0,0 +1,223 @@
+//! Integration tests for GDLK that expect compile warnings. The programs in
+//! these tests should compile successfully, but generate warnings along the
+//! way.
+
+use gdlk::{Compiler, HardwareSpec};
+use std::collections::HashMap;
+
+/// Compiles the program for the given hardware, expecting success with a
+/// particular set of warnings. Panics if the compile fails, or if the wrong
+/// set of warnings is returned.
+macro_rules! assert_compile_warnings {
+    ($hw_spec:expr, $src:expr, $expected_warnings:expr $(,)?) => {
+        let actual_warnings: Vec<String> = Compiler::compile($src, $hw_spec)
+            .unwrap()
+            .warnings()
+            .errors()
+            .iter()
+            .map(|warning| warning.to_string())
+            .collect();
+        let strs: Vec<&str> =
+            actual_warnings.iter().map(String::as_str).collect();
+        assert_eq!(strs.as_slice(), $expected_warnings);
+    };
+}
+
+#[test]
+fn test_no_warnings() {
+    assert_compile_warnings!(
+        HardwareSpec::default(),
+        "
+        LOOP:
+        JEZ RLI END
+        READ RX0
+        JMP LOOP
+        END:
+        ",
+        &[] as &[&str],
+    );
+}
+
+#[test]
+fn test_unused_label() {
+    assert_compile_warnings!(
+        HardwareSpec::default(),
+        "
+        START:
+        READ RX0
+        USED:
+        WRITE RX0
+        JEZ RX0 USED
+        END:
+        ",
+        &[
+            "Lint warning at 2:9: Label `START` is never referenced",
+            "Lint warning at 7:9: Label `END` is never referenced",
+        ],
+    );
+}
+
+#[test]
+fn test_warning_render() {
+    let compiler =
+        Compiler::compile("READ RX0\nFOO:\n", HardwareSpec::default()).unwrap();
+    assert_eq!(
+        compiler.warnings().to_string(),
+        "Lint warning at 2:1: Label `FOO` is never referenced
+  |
+2 | FOO:
+  | ^^^^"
+    );
+}
+
+#[test]
+fn test_unreachable_code() {
+    assert_compile_warnings!(
+        HardwareSpec::default(),
+        "
+        LOOP:
+        JEZ RLI END
+        READ RX0
+        JMP LOOP
+        WRITE RX0   ; dead
+        WRITE RX0   ; also dead
+        END:
+        JMP LOOP
+        NOP
+        ",
+        &[
+            "Lint warning at 6:9: Unreachable code",
+            "Lint warning at 10:9: Unreachable code",
+        ],
+    );
+
+    // Conditional jumps can fall through, so nothing here is dead
+    assert_compile_warnings!(
+        HardwareSpec::default(),
+        "
+        START:
+        JNZ RX0 START
+        JLZ RX0 START
+        JGZ RX0 START
+        JEZ RX0 START
+        WRITE RX0
+        ",
+        &[] as &[&str],
+    );
+}
+
+#[test]
+fn test_unreachable_render() {
+    let compiler = Compiler::compile(
+        "JMP END\nREAD RX0\nWRITE RX0\nEND:\nWRITE RZR\nUNUSED:\n",
+        HardwareSpec::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        compiler.warnings().to_string(),
+        "Lint warning at 2:1: Unreachable code
+  |
+2 | READ RX0
+  | ^^^^^^^^
+3 | WRITE RX0
+  | ^^^^^^^^^
+
+Lint warning at 6:1: Label `UNUSED` is never referenced
+  |
+6 | UNUSED:
+  | ^^^^^^^"
+    );
+}
+
+#[test]
+fn test_unreachable_include() {
+    // A run of unreachable code that crosses into and back out of an included
+    // file gets one warning per file
+    let files: HashMap<String, String> =
+        [("lib.gdlk".to_owned(), "WRITE 3\nWRITE 4\n".to_owned())]
+            .iter()
+            .cloned()
+            .collect();
+    let compiler = Compiler::compile_with_resolver(
+        "JMP END\nWRITE 2\n%include \"lib.gdlk\"\nWRITE 5\nEND:",
+        HardwareSpec::default(),
+        &files,
+    )
+    .unwrap();
+    let warnings: Vec<String> = compiler
+        .warnings()
+        .errors()
+        .iter()
+        .map(|warning| warning.to_string())
+        .collect();
+    assert_eq!(
+        warnings,
+        &[
+            "Lint warning at 2:1: Unreachable code",
+            "Lint warning at lib.gdlk:1:1: Unreachable code",
+            "Lint warning at 4:1: Unreachable code",
+        ]
+    );
+    // Each span only covers its own file, so they can all be rendered
+    assert_eq!(
+        compiler.warnings().to_string(),
+        "Lint warning at 2:1: Unreachable code
+  |
+2 | WRITE 2
+  | ^^^^^^^
+
+Lint warning at lib.gdlk:1:1: Unreachable code
+  |
+1 | WRITE 3
+  | ^^^^^^^
+2 | WRITE 4
+  | ^^^^^^^
+
+Lint warning at 4:1: Unreachable code
+  |
+4 | WRITE 5
+  | ^^^^^^^"
+    );
+}
+
+#[test]
+fn test_metadata_warnings() {
+    assert_compile_warnings!(
+        HardwareSpec::default(),
+        "
+        ;! name: first
+        ; regular comments don't end the header
+        ;! name: second
+        ;! no colon
+        ;! : no key
+        READ RX0 ;! puzzle: trailing
+        ;! puzzle: after code
+        WRITE RX0
+        ",
+        &[
+            "Lint warning at 4:9: Metadata key `name` is already set, so this \
+                line is ignored",
+            "Lint warning at 5:9: Metadata should look like `;! key: value`, \
+                so this line is ignored",
+            "Lint warning at 6:9: Metadata should look like `;! key: value`, \
+                so this line is ignored",
+            "Lint warning at 7:18: Metadata has to come before the first \
+                statement, so this line is ignored",
+            "Lint warning at 8:9: Metadata has to come before the first \
+                statement, so this line is ignored",
+        ],
+    );
+
+    // The ignored lines don't show up in the metadata
+    let compiler = Compiler::compile(
+        ";! name: first\n;! name: second\nREAD RX0\n;! puzzle: late\n",
+        HardwareSpec::default(),
+    )
+    .unwrap();
+    assert_eq!(compiler.metadata().name.as_deref(), Some("first"));
+    assert_eq!(compiler.metadata().puzzle, None);
+    assert_eq!(
+        compiler.warnings().errors()[1].error_code(),
+        "W004_MISPLACED_METADATA"
+    );
+}
@@ -0,0 +1,397 @@
+//! Integration tests for execution with extreme values, i.e.
+//! [LangValue::MIN] and [LangValue::MAX]. Arithmetic wraps by default, so
+//! none of these should ever panic, and values should never get mangled as
+//! they move through registers, stacks, and buffers. Other overflow behaviors
+//! are covered at the bottom.
+
+use gdlk::{
+    ast::LangValue, Compiler, ErrorPolicy, FailureReason, HardwareSpec,
+    Machine, OverflowBehavior, ProgramSpec,
+};
+use std::cmp::Ordering;
+
+const MIN: LangValue = LangValue::MIN;
+const MAX: LangValue = LangValue::MAX;
+
+/// Values to test with. Covers the extremes, their neighbors, and the values
+/// around zero.
+const VALUES: &[LangValue] = &[MIN, MIN + 1, -1, 0, 1, MAX - 1, MAX];
+
+/// Compiles the program and allocates a machine to run it against the given
+/// program spec. Panics if the compile fails.
+fn allocate(
+    overflow_behavior: OverflowBehavior,
+    error_policy: ErrorPolicy,
+    program_spec: ProgramSpec,
+    src: &str,
+) -> Machine {
+    Compiler::compile(
+        src,
+        HardwareSpec {
+            num_registers: 2,
+            num_stacks: 1,
+            max_stack_length: VALUES.len(),
+            overflow_behavior,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+    .allocate_with_policy(&program_spec, error_policy)
+    .unwrap()
+}
+
+/// Compiles the program and executes it against the given program spec,
+/// returning the machine. Panics if the compile fails or a runtime error
+/// occurs.
+fn execute(program_spec: ProgramSpec, src: &str) -> Machine {
+    execute_with(OverflowBehavior::Wrap, program_spec, src)
+}
+
+/// Same as [execute], but with a specific overflow behavior.
+fn execute_with(
+    overflow_behavior: OverflowBehavior,
+    program_spec: ProgramSpec,
+    src: &str,
+) -> Machine {
+    let mut machine =
+        allocate(overflow_behavior, ErrorPolicy::Strict, program_spec, src);
+    machine.execute_all().unwrap();
+    machine
+}
+
+/// Get every ordered pair of test values. If `nonzero_rhs` is set, pairs with
+/// a zero on the right are excluded.
+fn pairs(nonzero_rhs: bool) -> Vec<(LangValue, LangValue)> {
+    VALUES
+        .iter()
+        .flat_map(|a| VALUES.iter().map(move |b| (*a, *b)))
+        .filter(|(_, b)| !nonzero_rhs || *b != 0)
+        .collect()
+}
+
+/// Executes the given binary instruction on each pair of operands, and
+/// asserts that each result matches the expected value. Operands are read
+/// from the input, and the result of each instruction is written to output.
+fn assert_binary_op(
+    overflow_behavior: OverflowBehavior,
+    instr: &str,
+    pairs: &[(LangValue, LangValue)],
+    expected: impl Fn(LangValue, LangValue) -> LangValue,
+) {
+    let input: Vec<LangValue> =
+        pairs.iter().flat_map(|(a, b)| vec![*a, *b]).collect();
+    let expected_output: Vec<LangValue> =
+        pairs.iter().map(|(a, b)| expected(*a, *b)).collect();
+    let machine = execute_with(
+        overflow_behavior,
+        ProgramSpec::new(input, expected_output.clone()),
+        &format!(
+            "
+            LOOP:
+            JEZ RLI END
+            READ RX0
+            READ RX1
+            {} RX0 RX1
+            WRITE RX0
+            JMP LOOP
+            END:
+            ",
+            instr
+        ),
+    );
+    // Compare pairwise so a failure shows which operands were wrong
+    for (((a, b), actual), expected) in pairs
+        .iter()
+        .zip(machine.output())
+        .zip(expected_output.iter())
+    {
+        assert_eq!(actual, expected, "{} {} {}", instr, a, b);
+    }
+    assert!(machine.successful());
+}
+
+#[test]
+fn test_add() {
+    assert_binary_op(
+        OverflowBehavior::Wrap,
+        "ADD",
+        &pairs(false),
+        LangValue::wrapping_add,
+    );
+}
+
+#[test]
+fn test_sub() {
+    assert_binary_op(
+        OverflowBehavior::Wrap,
+        "SUB",
+        &pairs(false),
+        LangValue::wrapping_sub,
+    );
+}
+
+#[test]
+fn test_mul() {
+    assert_binary_op(
+        OverflowBehavior::Wrap,
+        "MUL",
+        &pairs(false),
+        LangValue::wrapping_mul,
+    );
+}
+
+#[test]
+fn test_bitwise() {
+    // None of these can overflow, so the overflow behavior doesn't matter
+    for (instr, expected) in &[
+        ("AND", (|a, b| a & b) as fn(LangValue, LangValue) -> _),
+        ("OR", |a, b| a | b),
+        ("XOR", |a, b| a ^ b),
+        // Every value is a valid shift amount, including negatives and ones
+        // wider than a value
+        ("SHL", |a, b| a.wrapping_shl(b as u32)),
+        ("SHR", |a, b| a.wrapping_shr(b as u32)),
+    ] {
+        for overflow_behavior in &[
+            OverflowBehavior::Wrap,
+            OverflowBehavior::Saturate,
+            OverflowBehavior::Error,
+        ] {
+            assert_binary_op(
+                *overflow_behavior,
+                instr,
+                &pairs(false),
+                expected,
+            );
+        }
+    }
+}
+
+#[test]
+fn test_div() {
+    // Includes MIN / -1, which overflows
+    assert_binary_op(
+        OverflowBehavior::Wrap,
+        "DIV",
+        &pairs(true),
+        LangValue::wrapping_div,
+    );
+}
+
+#[test]
+fn test_cmp() {
+    let pairs = pairs(false);
+    let input: Vec<LangValue> =
+        pairs.iter().flat_map(|(a, b)| vec![*a, *b]).collect();
+    let expected_output: Vec<LangValue> = pairs
+        .iter()
+        .map(|(a, b)| match a.cmp(b) {
+            Ordering::Less => -1,
+            Ordering::Equal => 0,
+            Ordering::Greater => 1,
+        })
+        .collect();
+    let machine = execute(
+        ProgramSpec::new(input, expected_output),
+        "
+        LOOP:
+        JEZ RLI END
+        READ RX0
+        READ RX1
+        CMP RX0 RX0 RX1
+        WRITE RX0
+        JMP LOOP
+        END:
+        ",
+    );
+    assert!(machine.successful());
+}
+
+#[test]
+fn test_constants() {
+    // Extremes as literals, in both operand positions
+    let machine = execute(
+        ProgramSpec::new(vec![], vec![MIN, MAX, -1, -1, 1]),
+        &format!(
+            "
+            SET RX0 {min}
+            WRITE RX0
+            SUB RX0 1
+            WRITE RX0
+            ADD RX0 {min}
+            WRITE RX0
+            CMP RX1 {min} {max}
+            WRITE RX1
+            CMP RX1 {max} {min}
+            WRITE RX1
+            ",
+            min = MIN,
+            max = MAX
+        ),
+    );
+    assert!(machine.successful());
+}
+
+#[test]
+fn test_push_pop() {
+    // Values should come out of the stack exactly as they went in
+    let mut expected_output = VALUES.to_vec();
+    expected_output.reverse();
+    let machine = execute(
+        ProgramSpec::new(VALUES.to_vec(), expected_output),
+        "
+        PUSH_LOOP:
+        JEZ RLI POP_LOOP
+        READ RX0
+        PUSH RX0 S0
+        JMP PUSH_LOOP
+        POP_LOOP:
+        JEZ RS0 END
+        POP S0 RX0
+        WRITE RX0
+        JMP POP_LOOP
+        END:
+        ",
+    );
+    assert!(machine.successful());
+}
+
+#[test]
+fn test_read_write() {
+    let machine = execute(
+        ProgramSpec::new(VALUES.to_vec(), VALUES.to_vec()),
+        "
+        LOOP:
+        JEZ RLI END
+        READ RX0
+        WRITE RX0
+        JMP LOOP
+        END:
+        ",
+    );
+    assert_eq!(machine.output(), VALUES);
+    assert!(machine.successful());
+}
+
+#[test]
+fn test_expected_output() {
+    // MIN should only match MIN
+    let machine = execute(ProgramSpec::new(vec![], vec![MIN]), "WRITE RX0");
+    assert!(matches!(
+        machine.failure_reason(),
+        Some(FailureReason::IncorrectOutput)
+    ));
+    let machine = execute(
+        ProgramSpec::new(vec![], vec![MIN]),
+        &format!("WRITE {}", MIN),
+    );
+    assert!(machine.successful());
+}
+
+#[test]
+fn test_max_plus_one() {
+    let src = &format!(
+        "
+    SET RX0 {}
+    ADD RX0 1
+    WRITE RX0
+    ",
+        MAX
+    );
+    let machine =
+        execute_with(OverflowBehavior::Wrap, ProgramSpec::default(), src);
+    assert_eq!(machine.output(), &[MIN]);
+    let machine =
+        execute_with(OverflowBehavior::Saturate, ProgramSpec::default(), src);
+    assert_eq!(machine.output(), &[MAX]);
+
+    let mut machine = allocate(
+        OverflowBehavior::Error,
+        ErrorPolicy::Strict,
+        ProgramSpec::default(),
+        src,
+    );
+    assert_eq!(
+        machine.execute_all().unwrap_err().errors()[0].to_string(),
+        "Runtime error at 3:5: Arithmetic overflow in `ADD RX0 1`"
+    );
+    assert!(machine.output().is_empty());
+
+    // Under the lenient policy, the overflow is just a warning
+    let mut machine = allocate(
+        OverflowBehavior::Error,
+        ErrorPolicy::Lenient,
+        ProgramSpec::default(),
+        src,
+    );
+    machine.execute_all().unwrap();
+    assert_eq!(machine.output(), &[MIN]);
+    assert_eq!(machine.warnings().len(), 1);
+}
+
+#[test]
+fn test_saturate() {
+    assert_binary_op(
+        OverflowBehavior::Saturate,
+        "ADD",
+        &pairs(false),
+        LangValue::saturating_add,
+    );
+    assert_binary_op(
+        OverflowBehavior::Saturate,
+        "SUB",
+        &pairs(false),
+        LangValue::saturating_sub,
+    );
+    assert_binary_op(
+        OverflowBehavior::Saturate,
+        "MUL",
+        &pairs(false),
+        LangValue::saturating_mul,
+    );
+    assert_binary_op(
+        OverflowBehavior::Saturate,
+        "DIV",
+        &pairs(true),
+        LangValue::saturating_div,
+    );
+}
+
+#[test]
+fn test_overflow_error() {
+    // Every pair that doesn't overflow should behave the same as wrapping
+    for (instr, checked) in &[
+        ("ADD", LangValue::checked_add as fn(_, _) -> _),
+        ("SUB", LangValue::checked_sub),
+        ("MUL", LangValue::checked_mul),
+        ("DIV", LangValue::checked_div),
+    ] {
+        for (a, b) in pairs(*instr == "DIV") {
+            let mut machine = allocate(
+                OverflowBehavior::Error,
+                ErrorPolicy::Strict,
+                ProgramSpec::new(vec![a, b], vec![]),
+                &format!("READ RX0\nREAD RX1\n{} RX0 RX1\nWRITE RX0", instr),
+            );
+            let result = machine.execute_all();
+            match checked(a, b) {
+                Some(expected) => {
+                    result.unwrap();
+                    assert_eq!(machine.output(), &[expected]);
+                }
+                None => assert_eq!(
+                    result.unwrap_err().errors()[0].to_string(),
+                    format!(
+                        "Runtime error at 3:1: Arithmetic overflow in \
+                        `{} RX0 RX1`",
+                        instr
+                    ),
+                    "{} {} {}",
+                    instr,
+                    a,
+                    b
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Integration tests for fuzz testing programs against generated inputs.
+//! These only run with the `fuzz` feature enabled.
+#![cfg(feature = "fuzz")]
+
+use gdlk::{
+    ast::LangValue,
+    error::{AllocateError, FuzzError},
+    Compiler, HardwareSpec, ProgramSpecGenerator,
+};
+
+/// Doubles every input value
+const DOUBLE_SRC: &str = "
+LOOP:
+JEZ RLI END
+READ RX0
+ADD RX0 RX0
+WRITE RX0
+JMP LOOP
+END:
+";
+
+#[test]
+fn test_generator_deterministic() {
+    let generator = ProgramSpecGenerator::new(1234, 0..=10, -5..=5);
+    let other = ProgramSpecGenerator::new(1234, 0..=10, -5..=5);
+    let seeds: Vec<u64> = generator.seeds(20).collect();
+    assert_eq!(seeds, other.seeds(20).collect::<Vec<_>>());
+    for seed in seeds {
+        let input = generator.input(seed);
+        assert_eq!(input, other.input(seed));
+        assert!(input.len() <= 10);
+        assert!(input.iter().all(|value| (-5..=5).contains(value)));
+    }
+
+    // Different seeds should (almost certainly) give different inputs
+    assert_ne!(
+        (0..10)
+            .map(|seed| generator.input(seed))
+            .collect::<Vec<_>>(),
+        (10..20)
+            .map(|seed| generator.input(seed))
+            .collect::<Vec<_>>(),
+    );
+}
+
+#[test]
+fn test_fuzz_success() {
+    let compiler =
+        Compiler::compile(DOUBLE_SRC, HardwareSpec::default()).unwrap();
+    let generator = ProgramSpecGenerator::new(0, 0..=20, -1000..=1000);
+    compiler
+        .fuzz(
+            &generator,
+            |input| input.iter().map(|value| value * 2).collect(),
+            100,
+        )
+        .unwrap();
+}
+
+#[test]
+fn test_fuzz_failure() {
+    let compiler =
+        Compiler::compile(DOUBLE_SRC, HardwareSpec::default()).unwrap();
+    // Only inputs with a negative value get the wrong output
+    let expected_fn = |input: &[LangValue]| -> Vec<LangValue> {
+        input.iter().map(|value| value.abs() * 2).collect()
+    };
+    let generator = ProgramSpecGenerator::new(0, 1..=5, -10..=10);
+    let failure = match compiler.fuzz(&generator, expected_fn, 100) {
+        Err(FuzzError::Failed(failure)) => failure,
+        result => panic!("Unexpected result: {:?}", result),
+    };
+    assert!(failure.input.iter().any(|value| *value < 0));
+    assert!(!failure.result.successful());
+
+    // The failure should be reproducible from its seed, and be the first one
+    let program_spec = generator.generate(failure.seed, expected_fn);
+    assert_eq!(program_spec.input(), failure.input.as_slice());
+    assert_eq!(program_spec.expected_output(), failure.expected_output);
+    for seed in generator
+        .seeds(100)
+        .take_while(|seed| *seed != failure.seed)
+    {
+        assert!(generator.input(seed).iter().all(|value| *value >= 0));
+    }
+}
+
+#[test]
+fn test_fuzz_constants() {
+    // Generated specs don't define any constants, so the program can't run
+    let compiler =
+        Compiler::compile("WRITE $K", HardwareSpec::default()).unwrap();
+    let generator = ProgramSpecGenerator::new(0, 0..=5, -10..=10);
+    match compiler.fuzz(&generator, |_| vec![], 10) {
+        Err(FuzzError::Allocate(AllocateError::UndefinedConstants {
+            names,
+        })) => assert_eq!(names, vec!["K".to_owned()]),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+}
@@ -0,0 +1,2300 @@
+//! Integration tests for the [Machine] API, beyond plain execution. These
+//! cover functionality like inspecting and manipulating machine state.
+
+use gdlk::{
+    ast::{InstructionKind, LangValue, RegisterRef, StackRef},
+    run_solution, Compiler, CostModel, Event, EventMask, FailureReason,
+    HardwareSpec, InitialState, InputSource, InterruptibleStop, IoEvent,
+    IoEventKind, Machine, OutputDiff, OutputSink, ProgramSpec, RegisterWrite,
+    SolutionResult, StackOp, StopReason, TestCase, DEFAULT_MAX_DEBUG_SNAPSHOTS,
+    MAX_CYCLE_COUNT, MAX_TEST_CASE_LENGTH, UNKNOWN_INPUT_LENGTH,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Compiles the program for the given hardware and allocates a machine to run
+/// it under the given program spec. Panics if the compile fails.
+macro_rules! allocate {
+    ($hardware_spec:expr, $program_spec:expr, $src:expr $(,)?) => {{
+        Compiler::compile($src, $hardware_spec)
+            .unwrap()
+            .allocate(&$program_spec)
+            .unwrap()
+    }};
+}
+
+/// Executes `n` instructions on the given machine.
+fn execute_n(machine: &mut Machine, n: usize) {
+    for _ in 0..n {
+        machine.execute_next().unwrap();
+    }
+}
+
+/// Asserts that the observable state of two machines is identical.
+fn assert_state_eq(m1: &Machine, m2: &Machine) {
+    assert_eq!(
+        m1.program_counter(),
+        m2.program_counter(),
+        "program counter"
+    );
+    assert_eq!(m1.cycle_count(), m2.cycle_count(), "cycle count");
+    assert_eq!(m1.null_writes(), m2.null_writes(), "null writes");
+    assert_eq!(m1.input(), m2.input(), "input");
+    assert_eq!(m1.output(), m2.output(), "output");
+    assert_eq!(m1.registers(), m2.registers(), "registers");
+    assert_eq!(m1.stacks(), m2.stacks(), "stacks");
+    assert_eq!(
+        m1.max_stack_usage(),
+        m2.max_stack_usage(),
+        "peak stack lengths"
+    );
+    assert_eq!(m1.terminated(), m2.terminated(), "terminated");
+    assert_eq!(m1.successful(), m2.successful(), "successful");
+}
+
+#[test]
+fn test_snapshot_restore() {
+    let hardware_spec = HardwareSpec {
+        num_registers: 2,
+        num_stacks: 1,
+        max_stack_length: 5,
+        ..Default::default()
+    };
+    let program_spec = ProgramSpec::new(vec![1, 2, 3], vec![3, 2, 1]);
+    let src = "
+    START:
+    JEZ RLI END
+    READ RX0
+    PUSH RX0 S0
+    JMP START
+    END:
+    JEZ RS0 DONE
+    POP S0 RX0
+    WRITE RX0
+    JMP END
+    DONE:
+    ";
+    let mut machine = allocate!(hardware_spec, program_spec, src);
+
+    // Run partway through, then take a snapshot
+    execute_n(&mut machine, 6);
+    let snapshot = machine.snapshot();
+    let checkpoint = machine.clone();
+
+    // Run to completion, then rewind and make sure we're back at the
+    // checkpoint
+    let success = machine.execute_all().unwrap();
+    assert!(success);
+    machine.restore(snapshot);
+    assert_state_eq(&machine, &checkpoint);
+
+    // Execution after the restore should be identical to the original run
+    let mut checkpoint = checkpoint;
+    while !checkpoint.terminated() {
+        machine.execute_next().unwrap();
+        checkpoint.execute_next().unwrap();
+        assert_state_eq(&machine, &checkpoint);
+    }
+    assert!(machine.successful());
+}
+
+#[test]
+fn test_state_fingerprint() {
+    let hardware_spec = HardwareSpec {
+        num_registers: 3,
+        num_stacks: 1,
+        max_stack_length: 5,
+        register_names: vec!["ACC".into()],
+        ..Default::default()
+    };
+    let program_spec = ProgramSpec::new(vec![1, 2], vec![3]);
+    let run = |src: &str| {
+        let mut machine =
+            allocate!(hardware_spec.clone(), program_spec.clone(), src);
+        machine.execute_all().unwrap();
+        machine
+    };
+
+    // Same instructions, but different formatting, comments, and names
+    let m1 = run("
+        READ RX0
+        READ RX1
+        ADD RX0 RX1
+        PUSH RX0 S0
+        WRITE RX0
+        NOP
+        ");
+    let m2 = run("
+        ; Add the inputs
+        read acc
+        read rx1
+        START:
+        add ACC rx1 ; Sum
+        push ACC s0
+        write RX0
+        nop
+        ");
+    assert!(m1.successful());
+    assert!(m1.state_eq(&m2));
+    assert_eq!(m1.state_fingerprint(), m2.state_fingerprint());
+
+    // Changing any one register changes the fingerprint
+    for register in ["RX0", "RX1", "RX2"] {
+        let m3 = run(&format!(
+            "
+            READ RX0
+            READ RX1
+            ADD RX0 RX1
+            PUSH RX0 S0
+            WRITE RX0
+            ADD {} 1
+            ",
+            register
+        ));
+        assert!(!m1.state_eq(&m3), "{}", register);
+        assert_ne!(
+            m1.state_fingerprint(),
+            m3.state_fingerprint(),
+            "{}",
+            register
+        );
+    }
+}
+
+#[test]
+fn test_max_stack_usage() {
+    let mut machine = allocate!(
+        HardwareSpec {
+            num_registers: 1,
+            num_stacks: 2,
+            max_stack_length: 5,
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "
+        PUSH 1 S0
+        PUSH 2 S0
+        PUSH 3 S0
+        PUSH 4 S1
+        POP S0 RX0
+        POP S0 RX0
+        PUSH 5 S0
+        POP S0 RX0
+        POP S0 RX0
+        POP S1 RX0
+        ",
+    );
+    assert_eq!(
+        machine.max_stack_usage(),
+        vec![(StackRef(0), 0), (StackRef(1), 0)]
+            .into_iter()
+            .collect()
+    );
+
+    // The maximums stick around after every value is popped
+    machine.execute_all().unwrap();
+    assert_eq!(machine.stacks()[&StackRef(0)], &[] as &[LangValue]);
+    assert_eq!(machine.stacks()[&StackRef(1)], &[] as &[LangValue]);
+    assert_eq!(
+        machine.max_stack_usage(),
+        vec![(StackRef(0), 3), (StackRef(1), 1)]
+            .into_iter()
+            .collect()
+    );
+
+    machine.reset();
+    assert_eq!(
+        machine.max_stack_usage(),
+        vec![(StackRef(0), 0), (StackRef(1), 0)]
+            .into_iter()
+            .collect()
+    );
+}
+
+#[test]
+fn test_snapshot_restore_error() {
+    let mut machine =
+        allocate!(HardwareSpec::default(), ProgramSpec::default(), "READ RX0");
+    let snapshot = machine.snapshot();
+
+    // Restoring should wipe out the error
+    machine.execute_all().unwrap_err();
+    assert!(machine.error().is_some());
+    machine.restore(snapshot);
+    assert!(machine.error().is_none());
+    assert!(!machine.terminated());
+}
+
+#[test]
+#[should_panic(expected = "Snapshot has 1 registers, but hardware spec has 2")]
+fn test_restore_mismatched_snapshot() {
+    let snapshot =
+        allocate!(HardwareSpec::default(), ProgramSpec::default(), "SET RX0 1")
+            .snapshot();
+    let mut machine = allocate!(
+        HardwareSpec {
+            num_registers: 2,
+            num_stacks: 0,
+            max_stack_length: 0,
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "SET RX1 1",
+    );
+    machine.restore(snapshot);
+}
+
+#[test]
+fn test_reset() {
+    let hardware_spec = HardwareSpec {
+        num_registers: 1,
+        num_stacks: 1,
+        max_stack_length: 2,
+        dump_enabled: true,
+        ..Default::default()
+    };
+    let program_spec = ProgramSpec::new(vec![1, 2, 3], vec![1, 2, 3]);
+    // Overflows the stack on the third value
+    let src = "
+    LOOP:
+    JEZ RLI END
+    READ RX0
+    WRITE RX0
+    PUSH RX0 S0
+    DUMP
+    JMP LOOP
+    END:
+    ";
+    let compiler = Compiler::compile(src, hardware_spec).unwrap();
+    let mut machine = compiler.allocate_all(&program_spec).unwrap().remove(0);
+    machine.execute_all().unwrap_err();
+    assert!(matches!(
+        machine.failure_reason(),
+        Some(FailureReason::RuntimeError)
+    ));
+    assert!(!machine.debug_snapshots().is_empty());
+
+    // After a reset, the machine should be indistinguishable from a fresh one
+    machine.reset();
+    let mut fresh = compiler.allocate(&program_spec).unwrap();
+    assert_state_eq(&machine, &fresh);
+    assert!(machine.error().is_none());
+    assert!(machine.debug_snapshots().is_empty());
+
+    // And running it again should give the exact same behavior
+    while !fresh.terminated() {
+        let expected = fresh.execute_next().map_err(|err| err.to_string());
+        let actual = machine.execute_next().map_err(|err| err.to_string());
+        assert_eq!(actual, expected);
+        assert_state_eq(&machine, &fresh);
+    }
+    assert_eq!(
+        machine.error().map(ToString::to_string),
+        fresh.error().map(ToString::to_string)
+    );
+    assert_eq!(machine.debug_snapshots(), fresh.debug_snapshots());
+}
+
+#[test]
+fn test_execute_next_traced() {
+    let mut machine = allocate!(
+        HardwareSpec {
+            num_registers: 2,
+            num_stacks: 1,
+            max_stack_length: 5,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![7], vec![7]),
+        "
+        READ RX0
+        PUSH RX0 S0
+        POP S0 RX1
+        WRITE RX1
+        JMP END
+        END:
+        ",
+    );
+
+    let trace = machine.execute_next_traced().unwrap().unwrap();
+    assert_eq!(trace.span.start_line, 2);
+    assert_eq!(trace.program_counter_before, 0);
+    assert_eq!(trace.program_counter_after, 1);
+    assert_eq!(trace.input_read, Some(7));
+    assert_eq!(
+        trace.register_writes,
+        vec![RegisterWrite {
+            register: RegisterRef::User(0),
+            old_value: 0,
+            new_value: 7,
+        }]
+    );
+    assert_eq!(trace.stack_ops, vec![]);
+
+    let trace = machine.execute_next_traced().unwrap().unwrap();
+    assert_eq!(trace.register_writes, vec![]);
+    assert_eq!(
+        trace.stack_ops,
+        vec![StackOp::Push {
+            stack: StackRef(0),
+            value: 7
+        }]
+    );
+
+    let trace = machine.execute_next_traced().unwrap().unwrap();
+    assert_eq!(
+        trace.stack_ops,
+        vec![StackOp::Pop {
+            stack: StackRef(0),
+            value: 7
+        }]
+    );
+    assert_eq!(
+        trace.register_writes,
+        vec![RegisterWrite {
+            register: RegisterRef::User(1),
+            old_value: 0,
+            new_value: 7,
+        }]
+    );
+
+    let trace = machine.execute_next_traced().unwrap().unwrap();
+    assert_eq!(trace.output_written, Some(7));
+    assert_eq!(trace.input_read, None);
+
+    // Jumps just show up as a change in program counter
+    let trace = machine.execute_next_traced().unwrap().unwrap();
+    assert_eq!(trace.program_counter_before, 4);
+    assert_eq!(trace.program_counter_after, 5);
+    assert_eq!(trace.register_writes, vec![]);
+    assert_eq!(trace.stack_ops, vec![]);
+
+    // Nothing left to execute
+    assert_eq!(machine.execute_next_traced().unwrap(), None);
+    assert!(machine.successful());
+}
+
+#[test]
+fn test_execute_next_traced_error() {
+    let mut machine =
+        allocate!(HardwareSpec::default(), ProgramSpec::default(), "READ RX0");
+    assert_eq!(
+        machine.execute_next_traced().unwrap_err().errors()[0].to_string(),
+        "Runtime error at 1:1: Read attempted on empty input"
+    );
+    // Machine is dead now
+    assert_eq!(machine.execute_next_traced().unwrap(), None);
+}
+
+#[test]
+fn test_execute_until() {
+    let program_spec = ProgramSpec::new(vec![1, 2], vec![1, 2]);
+    let src = "
+    LOOP:
+    JEZ RLI END
+    READ RX0
+    WRITE RX0
+    JMP LOOP
+    END:
+    ";
+    let mut machine = allocate!(HardwareSpec::default(), program_spec, src);
+
+    // Stops before executing the breakpointed instruction
+    assert!(machine.execute_until(&[2]).unwrap());
+    assert_eq!(machine.program_counter(), 2);
+    assert_eq!(machine.input(), &[2]);
+    assert_eq!(machine.output(), &[] as &[LangValue]);
+
+    // Resuming from the breakpoint executes it, then stops on the next pass
+    assert!(machine.execute_until(&[2]).unwrap());
+    assert_eq!(machine.program_counter(), 2);
+    assert_eq!(machine.input(), &[] as &[LangValue]);
+    assert_eq!(machine.output(), &[1]);
+
+    // No more breakpoints get hit, so the program runs to completion
+    assert!(!machine.execute_until(&[2, 1]).unwrap());
+    assert!(machine.successful());
+    assert_eq!(machine.output(), &[1, 2]);
+    assert!(!machine.execute_until(&[0]).unwrap());
+}
+
+#[test]
+fn test_execute_until_line() {
+    let program_spec = ProgramSpec::new(vec![1], vec![1]);
+    let src = "READ RX0
+
+; comment
+LBL:
+WRITE RX0";
+    let mut machine = allocate!(HardwareSpec::default(), program_spec, src);
+    // Lines without instructions never trigger
+    assert!(machine.execute_until_line(&[2, 3, 4, 5]).unwrap());
+    assert_eq!(machine.program_counter(), 1);
+    assert!(!machine.execute_until_line(&[2, 3, 4, 5]).unwrap());
+    assert!(machine.successful());
+}
+
+#[test]
+fn test_current_span() {
+    let src = "READ RX0
+LBL:
+WRITE RX0
+JMP END
+END:";
+    let mut machine = allocate!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![1], vec![1]),
+        src
+    );
+    let mut lines = Vec::new();
+    while let Some(span) = machine.current_span() {
+        lines.push(span.start_line);
+        machine.execute_next().unwrap();
+    }
+    assert_eq!(lines, vec![1, 3, 4]);
+    // The program counter is past the end now
+    assert_eq!(machine.program_counter(), 3);
+    assert!(machine.successful());
+
+    // After an error, the span points at the failed instruction
+    let mut machine = allocate!(
+        HardwareSpec::default(),
+        ProgramSpec::default(),
+        "WRITE 1\n  READ RX0"
+    );
+    assert!(machine.execute_all().is_err());
+    let span = machine.current_span().unwrap();
+    assert_eq!((span.start_line, span.start_col), (2, 3));
+}
+
+#[test]
+fn test_execute_until_error() {
+    let mut machine = allocate!(
+        HardwareSpec::default(),
+        ProgramSpec::default(),
+        "WRITE 1\nREAD RX0\nWRITE 2"
+    );
+    assert_eq!(
+        machine.execute_until(&[2]).unwrap_err().errors()[0].to_string(),
+        "Runtime error at 2:1: Read attempted on empty input"
+    );
+    assert_eq!(machine.program_counter(), 1);
+    assert!(machine.terminated());
+}
+
+/// Executes until the next event in the mask, and returns the line of the
+/// instruction that triggered it, or `None` if the program terminated
+fn next_event(
+    machine: &mut Machine,
+    events: impl Into<EventMask>,
+) -> Option<(Event, usize)> {
+    match machine.execute_until_event(events.into()).unwrap() {
+        StopReason::Event { event, span } => Some((event, span.start_line)),
+        StopReason::Terminated => None,
+    }
+}
+
+#[test]
+fn test_execute_until_event() {
+    let src = "READ RX0
+PUSH RX0 S0
+SET RX1 RX0
+JEZ RX1 END
+POP S0 RX0
+WRITE RX0
+JMP END
+WRITE 0
+END:";
+    let program_spec = ProgramSpec::new(vec![3], vec![3]);
+    let hardware_spec = HardwareSpec::builder()
+        .registers(2)
+        .stacks(1)
+        .stack_length(1)
+        .build()
+        .unwrap();
+    let allocate = || allocate!(hardware_spec.clone(), program_spec, src);
+
+    // Each kind of event on its own
+    let mut machine = allocate();
+    assert_eq!(
+        next_event(&mut machine, Event::InputRead),
+        Some((Event::InputRead, 1))
+    );
+    assert_eq!(next_event(&mut machine, Event::InputRead), None);
+
+    let mut machine = allocate();
+    assert_eq!(
+        next_event(&mut machine, Event::StackChanged),
+        Some((Event::StackChanged, 2))
+    );
+    assert_eq!(
+        next_event(&mut machine, Event::StackChanged),
+        Some((Event::StackChanged, 5))
+    );
+    assert_eq!(machine.program_counter(), 5);
+    assert_eq!(next_event(&mut machine, Event::StackChanged), None);
+
+    // Conditional jumps that aren't taken don't count
+    let mut machine = allocate();
+    assert_eq!(
+        next_event(&mut machine, Event::JumpTaken),
+        Some((Event::JumpTaken, 7))
+    );
+    assert_eq!(machine.program_counter(), 8);
+
+    let mut machine = allocate();
+    assert_eq!(
+        next_event(&mut machine, Event::OutputWritten),
+        Some((Event::OutputWritten, 6))
+    );
+    assert_eq!(machine.output(), &[3]);
+    assert_eq!(next_event(&mut machine, Event::OutputWritten), None);
+    assert!(machine.successful());
+
+    // With multiple events, whichever happens first wins
+    let mut machine = allocate();
+    let events = Event::OutputWritten | Event::JumpTaken | Event::StackChanged;
+    assert_eq!(
+        next_event(&mut machine, events),
+        Some((Event::StackChanged, 2))
+    );
+    assert_eq!(
+        next_event(&mut machine, events),
+        Some((Event::StackChanged, 5))
+    );
+    assert_eq!(
+        next_event(&mut machine, events),
+        Some((Event::OutputWritten, 6))
+    );
+    assert_eq!(
+        next_event(&mut machine, events),
+        Some((Event::JumpTaken, 7))
+    );
+    assert_eq!(next_event(&mut machine, events), None);
+
+    // No events just runs the whole thing
+    let mut machine = allocate();
+    assert_eq!(next_event(&mut machine, EventMask::NONE), None);
+    assert!(machine.successful());
+}
+
+#[test]
+fn test_execute_all_interruptible() {
+    let mut machine = allocate!(
+        HardwareSpec {
+            num_extern_ops: 1,
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "
+        LOOP:
+        EXT 0 RX0
+        JMP LOOP
+        ",
+    );
+    // Slow each iteration down, so the loop can't hit the cycle limit before
+    // we get a chance to cancel it. The first call lets us know it's running.
+    let (started_tx, started_rx) = mpsc::channel();
+    machine.set_extern_handler(Box::new(move |_, value| {
+        let _ = started_tx.send(());
+        thread::sleep(Duration::from_micros(10));
+        Ok(value)
+    }));
+    let token = machine.cancel_token();
+    let handle = thread::spawn(move || {
+        let stop_reason = machine.execute_all_interruptible().unwrap();
+        (stop_reason, machine)
+    });
+
+    started_rx.recv().unwrap();
+    let cancelled_at = Instant::now();
+    token.cancel();
+    let (stop_reason, mut machine) = handle.join().unwrap();
+    assert_eq!(stop_reason, InterruptibleStop::Cancelled);
+    assert!(cancelled_at.elapsed() < Duration::from_secs(1));
+    assert!(!machine.terminated());
+    assert!(machine.cycle_count() > 0);
+    assert!(machine.cycle_count() < MAX_CYCLE_COUNT);
+    // Stopping clears the token, so the machine can pick up where it left off
+    assert!(!token.is_cancelled());
+    let cycle_count = machine.cycle_count();
+    execute_n(&mut machine, 2);
+    assert_eq!(machine.cycle_count(), cycle_count + 2);
+
+    // A token that's cancelled ahead of time stops the next run right away
+    token.cancel();
+    assert_eq!(
+        machine.execute_all_interruptible().unwrap(),
+        InterruptibleStop::Cancelled
+    );
+    assert_eq!(machine.cycle_count(), cycle_count + 2);
+
+    // Without a cancel, it's the same as execute_all
+    let mut machine = allocate!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![1], vec![1]),
+        "READ RX0\nWRITE RX0",
+    );
+    assert_eq!(
+        machine.execute_all_interruptible().unwrap(),
+        InterruptibleStop::Terminated
+    );
+    assert!(machine.successful());
+}
+
+#[test]
+fn test_execute_until_event_error() {
+    let mut machine = allocate!(
+        HardwareSpec::default(),
+        ProgramSpec::default(),
+        "READ RX0\nWRITE 1"
+    );
+    assert_eq!(
+        machine
+            .execute_until_event(EventMask::ALL)
+            .unwrap_err()
+            .errors()[0]
+            .to_string(),
+        "Runtime error at 1:1: Read attempted on empty input"
+    );
+    assert!(machine.terminated());
+}
+
+/// Input stream that doesn't know how much input it has left, like stdin
+struct UnknownLengthInput(Vec<LangValue>);
+
+impl InputSource for UnknownLengthInput {
+    fn read(&mut self) -> Option<LangValue> {
+        self.0.read()
+    }
+
+    fn remaining(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Output stream that can still be inspected after being handed to a machine
+#[derive(Clone, Default)]
+struct SharedOutput(Arc<Mutex<Vec<LangValue>>>);
+
+impl OutputSink for SharedOutput {
+    fn write(&mut self, value: LangValue) {
+        self.0.lock().unwrap().push(value);
+    }
+}
+
+#[test]
+fn test_max_input_length() {
+    let input = vec![1; MAX_TEST_CASE_LENGTH];
+    let program_spec = ProgramSpec::new(input, vec![]);
+    assert_eq!(program_spec.validate(), Ok(()));
+    let mut machine = allocate!(
+        HardwareSpec::default(),
+        program_spec,
+        "WRITE RLI\nREAD RX0\nWRITE RLI"
+    );
+    machine.execute_all().unwrap();
+    assert_eq!(
+        machine.output(),
+        &[
+            MAX_TEST_CASE_LENGTH as LangValue,
+            MAX_TEST_CASE_LENGTH as LangValue - 1
+        ]
+    );
+}
+
+/// Input stream that claims to have more values than any register can hold
+struct EndlessInput;
+
+impl InputSource for EndlessInput {
+    fn read(&mut self) -> Option<LangValue> {
+        Some(0)
+    }
+
+    fn remaining(&self) -> Option<usize> {
+        Some(usize::MAX)
+    }
+}
+
+#[test]
+fn test_unvalidated_specs() {
+    // Neither of these specs is valid, but the machine should still run
+    let hardware_spec = HardwareSpec {
+        num_stacks: 1,
+        max_stack_length: usize::MAX,
+        ..Default::default()
+    };
+    assert!(hardware_spec.validate().is_err());
+    let output = SharedOutput::default();
+    let mut machine =
+        Compiler::compile("WRITE RLI\nPUSH 1 S0\nWRITE RS0", hardware_spec)
+            .unwrap()
+            .allocate_with_io(Box::new(EndlessInput), Box::new(output.clone()))
+            .unwrap();
+    machine.execute_all().unwrap();
+    assert_eq!(*output.0.lock().unwrap(), vec![LangValue::MAX, 1]);
+}
+
+#[test]
+fn test_oversized_specs() {
+    use gdlk::{
+        error::{AllocateError, SpecMismatchError},
+        ErrorPolicy,
+    };
+
+    // Register and stack counts too big to allocate are an error, not an
+    // abort
+    let compiler =
+        Compiler::compile("WRITE RX0", HardwareSpec::default()).unwrap();
+    let new = |hardware_spec| {
+        Machine::new(
+            hardware_spec,
+            &TestCase::new(vec![], vec![0]),
+            compiler.program().clone(),
+            "WRITE RX0",
+            ErrorPolicy::default(),
+        )
+        .map(|_| ())
+    };
+    let error = new(HardwareSpec {
+        num_registers: usize::MAX,
+        ..Default::default()
+    })
+    .unwrap_err();
+    assert_eq!(
+        error,
+        AllocateError::SpecMismatch(SpecMismatchError::TooLarge {
+            kind: "registers",
+            max: 16,
+            actual: usize::MAX,
+        })
+    );
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "Hardware has {} registers, but a machine can have at most 16",
+            usize::MAX
+        )
+    );
+    assert_eq!(
+        new(HardwareSpec {
+            num_stacks: 17,
+            ..Default::default()
+        }),
+        Err(AllocateError::SpecMismatch(SpecMismatchError::TooLarge {
+            kind: "stacks",
+            max: 16,
+            actual: 17,
+        }))
+    );
+
+    // Only the upper limits are checked. With no registers, the program just
+    // doesn't fit.
+    assert_eq!(
+        new(HardwareSpec {
+            num_registers: 0,
+            num_stacks: 16,
+            ..Default::default()
+        }),
+        Err(AllocateError::SpecMismatch(
+            SpecMismatchError::InvalidRegisterRef {
+                reference: RegisterRef::User(0),
+                available: gdlk::error::AvailableRefs::UserRegisters(0),
+            }
+        ))
+    );
+    assert_eq!(
+        new(HardwareSpec {
+            num_registers: 16,
+            num_stacks: 16,
+            ..Default::default()
+        }),
+        Ok(())
+    );
+}
+
+#[test]
+fn test_streaming_io() {
+    let src = "
+    READ RX0
+    WRITE RX0
+    WRITE RLI
+    READ RX0
+    WRITE RX0
+    ";
+    let output = SharedOutput::default();
+    let mut machine = Compiler::compile(src, HardwareSpec::default())
+        .unwrap()
+        .allocate_with_io(
+            Box::new(UnknownLengthInput(vec![3, 4, 5])),
+            Box::new(output.clone()),
+        )
+        .unwrap();
+    machine.execute_all().unwrap();
+
+    // RLI can't tell how much input is left. There's no expected output, so
+    // leftover input doesn't count as a failure either.
+    assert_eq!(*output.0.lock().unwrap(), vec![3, UNKNOWN_INPUT_LENGTH, 4]);
+    assert!(machine.successful());
+    // The buffers are bypassed entirely
+    assert_eq!(machine.input(), &[] as &[LangValue]);
+    assert_eq!(machine.output(), &[] as &[LangValue]);
+}
+
+#[test]
+fn test_streaming_io_known_length() {
+    // A plain buffer works as a stream too, and RLI sees its length
+    let output = SharedOutput::default();
+    let mut machine = Compiler::compile(
+        "LOOP:\nJEZ RLI END\nREAD RX0\nWRITE RLI\nJMP LOOP\nEND:",
+        HardwareSpec::default(),
+    )
+    .unwrap()
+    .allocate_with_io(Box::new(vec![1, 2, 3]), Box::new(output.clone()))
+    .unwrap();
+    machine.execute_all().unwrap();
+    assert_eq!(*output.0.lock().unwrap(), vec![2, 1, 0]);
+}
+
+#[test]
+fn test_streaming_io_empty() {
+    // An exhausted stream is just like an empty input buffer
+    let mut machine = Compiler::compile("READ RX0", HardwareSpec::default())
+        .unwrap()
+        .allocate_with_io(
+            Box::new(UnknownLengthInput(vec![])),
+            Box::new(SharedOutput::default()),
+        )
+        .unwrap();
+    assert_eq!(
+        machine.execute_all().unwrap_err().errors()[0].to_string(),
+        "Runtime error at 1:1: Read attempted on empty input"
+    );
+    assert!(matches!(
+        machine.failure_reason(),
+        Some(FailureReason::RuntimeError)
+    ));
+}
+
+#[test]
+fn test_loop_detection() {
+    // A loop that never changes any state gets caught right away
+    let mut machine = allocate!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![1], vec![]),
+        "
+        READ RX0
+        LOOP:
+        ADD RX0 0
+        JMP LOOP
+        ",
+    );
+    machine.set_loop_detection(true);
+    assert_eq!(
+        machine.execute_all().unwrap_err().errors()[0].to_string(),
+        "Runtime error at 5:9: Infinite loop detected, cannot execute \
+            instruction `JMP LOOP`"
+    );
+    assert!(machine.cycle_count() < 10);
+
+    // A loop that modifies state every iteration, but eventually repeats
+    let mut machine = allocate!(
+        HardwareSpec::default(),
+        ProgramSpec::default(),
+        "
+        LOOP:
+        ADD RX0 1
+        CMP RX0 RX0 100
+        JMP LOOP
+        ",
+    );
+    machine.set_loop_detection(true);
+    assert!(machine.execute_all().is_err());
+    assert!(machine.cycle_count() < 1000);
+
+    // After a reset, the loop is detected again
+    machine.reset();
+    assert!(machine.execute_all().is_err());
+    assert!(machine.cycle_count() < 1000);
+
+    // With detection disabled, the loop runs until the cycle limit
+    machine.set_loop_detection(false);
+    machine.reset();
+    assert!(machine.execute_all().is_err());
+    assert_eq!(machine.cycle_count(), MAX_CYCLE_COUNT);
+}
+
+#[test]
+fn test_loop_detection_terminating() {
+    // A long-running program never repeats a state, so it isn't flagged
+    let mut machine = allocate!(
+        HardwareSpec {
+            num_registers: 2,
+            num_stacks: 1,
+            max_stack_length: 10,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![], vec![100_000]),
+        "
+        SET RX0 100000
+        LOOP:
+        ADD RX1 1
+        PUSH RX1 S0
+        POP S0 RX1
+        SUB RX0 1
+        JGZ RX0 LOOP
+        WRITE RX1
+        ",
+    );
+    machine.set_loop_detection(true);
+    assert!(machine.execute_all().unwrap());
+    assert_eq!(machine.cycle_count(), 500_002);
+}
+
+#[test]
+fn test_profile() {
+    let mut machine = allocate!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![], vec![0]),
+        "
+        SET RX0 5
+        LOOP:
+        SUB RX0 1
+        JGZ RX0 LOOP
+        WRITE RX0
+        ",
+    );
+    // Profiling is off by default
+    assert_eq!(machine.profile(), None);
+
+    machine.set_profiling(true);
+    assert!(machine.execute_all().unwrap());
+    let profile = machine.profile().unwrap();
+    let counts: Vec<(usize, u64)> = profile
+        .iter()
+        .map(|(span, count)| (span.start_line, *count))
+        .collect();
+    assert_eq!(counts, vec![(2, 1), (4, 5), (5, 5), (6, 1)]);
+    assert_eq!(
+        profile.iter().map(|(_, count)| count).sum::<u64>(),
+        machine.cycle_count() as u64
+    );
+
+    // Stepping backwards undoes the counts
+    machine.reset();
+    execute_n(&mut machine, 3);
+    let snapshot = machine.snapshot();
+    machine.execute_all().unwrap();
+    machine.restore(snapshot);
+    assert_eq!(
+        machine
+            .profile()
+            .unwrap()
+            .iter()
+            .map(|(_, count)| *count)
+            .sum::<u64>(),
+        3
+    );
+
+    // Disabling profiling throws the counts away
+    machine.set_profiling(false);
+    assert_eq!(machine.profile(), None);
+}
+
+#[test]
+fn test_io_log() {
+    let mut machine = allocate!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![1, 2, 3], vec![2, 4, 6]),
+        "
+        LOOP:
+        JEZ RLI END
+        READ RX0
+        ADD RX0 RX0
+        WRITE RX0
+        JMP LOOP
+        END:
+        ",
+    );
+    // Logging is off by default
+    assert_eq!(machine.io_log(), None);
+
+    machine.set_io_logging(true);
+    assert!(machine.execute_all().unwrap());
+    let io_log = machine.io_log().unwrap();
+    let values = |kind| {
+        io_log
+            .iter()
+            .filter(|event| event.kind == kind)
+            .map(|event| event.value)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(values(IoEventKind::Read), &[1, 2, 3]);
+    assert_eq!(values(IoEventKind::Write), machine.output());
+    assert!(io_log
+        .windows(2)
+        .all(|events| events[0].cycle < events[1].cycle));
+    assert_eq!(
+        io_log[..2],
+        [
+            IoEvent {
+                cycle: 2,
+                kind: IoEventKind::Read,
+                value: 1,
+                span: machine.program().span_at(1).unwrap(),
+            },
+            IoEvent {
+                cycle: 4,
+                kind: IoEventKind::Write,
+                value: 2,
+                span: machine.program().span_at(3).unwrap(),
+            },
+        ]
+    );
+
+    // Stepping backwards undoes the log
+    machine.reset();
+    assert_eq!(machine.io_log(), Some(&[] as &[IoEvent]));
+    execute_n(&mut machine, 6);
+    let snapshot = machine.snapshot();
+    machine.execute_all().unwrap();
+    machine.restore(snapshot);
+    assert_eq!(machine.io_log().unwrap().len(), 2);
+
+    // Disabling logging throws the log away
+    machine.set_io_logging(false);
+    assert_eq!(machine.io_log(), None);
+}
+
+#[test]
+fn test_cost_of() {
+    let machine = allocate!(
+        HardwareSpec {
+            cost_model: CostModel::default()
+                .with_cost(InstructionKind::Push, 2),
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "NOP",
+    );
+    assert_eq!(machine.cost_of(InstructionKind::Push), 2);
+    assert_eq!(machine.cost_of(InstructionKind::Pop), 1);
+}
+
+#[test]
+fn test_nop() {
+    let mut machine = allocate!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![1], vec![1]),
+        "
+        READ RX0
+        NOP
+        WRITE RX0
+        ",
+    );
+    execute_n(&mut machine, 1);
+    let registers = machine.registers();
+
+    // NOP only advances the program counter and cycle count
+    let trace = machine.execute_next_traced().unwrap().unwrap();
+    assert_eq!(trace.program_counter_before, 1);
+    assert_eq!(trace.program_counter_after, 2);
+    assert_eq!(trace.register_writes, vec![]);
+    assert_eq!(trace.stack_ops, vec![]);
+    assert_eq!(trace.input_read, None);
+    assert_eq!(trace.output_written, None);
+    assert_eq!(machine.cycle_count(), 2);
+    assert_eq!(machine.registers(), registers);
+
+    assert!(machine.execute_all().unwrap());
+    assert_eq!(machine.cycle_count(), 3);
+}
+
+#[test]
+fn test_null_writes() {
+    let mut machine = allocate!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![1, 2], vec![0]),
+        "
+        SET RZR 5
+        ADD RZR 1
+        READ RZR
+        WRITE RZR
+        SET RX0 RZR
+        READ RZR
+        ",
+    );
+
+    // Every write counts, even though the value is thrown away
+    execute_n(&mut machine, 1);
+    assert_eq!(machine.null_writes(), 1);
+    execute_n(&mut machine, 1);
+    assert_eq!(machine.null_writes(), 2);
+    execute_n(&mut machine, 1);
+    assert_eq!(machine.null_writes(), 3);
+    // Reads don't count
+    execute_n(&mut machine, 2);
+    assert_eq!(machine.null_writes(), 3);
+    assert!(machine.execute_all().unwrap());
+    assert_eq!(machine.null_writes(), 4);
+    // RZC is off by default, so it doesn't show up
+    assert!(!machine.registers().contains_key(&RegisterRef::NullCount));
+
+    machine.reset();
+    assert_eq!(machine.null_writes(), 0);
+}
+
+#[test]
+fn test_null_count_register() {
+    let mut machine = allocate!(
+        HardwareSpec {
+            null_count_enabled: true,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![], vec![0, 2]),
+        "
+        WRITE RZC
+        SET RZR 1
+        SWP RZR RX0
+        WRITE RZC
+        ",
+    );
+    assert!(machine.execute_all().unwrap());
+    assert_eq!(machine.null_writes(), 2);
+    assert_eq!(machine.registers()[&RegisterRef::NullCount], 2);
+
+    // Once the program can see the count, it's part of the machine's state
+    let mut other = allocate!(
+        HardwareSpec {
+            null_count_enabled: true,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![], vec![0, 2]),
+        "
+        WRITE RZC
+        SET RZR 1
+        SET RZR 1
+        SET RZR 1
+        ",
+    );
+    execute_n(&mut other, 4);
+    assert!(!machine.state_eq(&other));
+}
+
+#[test]
+fn test_dump() {
+    let mut machine = allocate!(
+        HardwareSpec {
+            num_registers: 1,
+            num_stacks: 1,
+            max_stack_length: 5,
+            dump_enabled: true,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![3], vec![3]),
+        "
+        READ RX0
+        PUSH RX0 S0
+        PUSH 4 S0
+        DUMP
+        POP S0 RZR
+        POP S0 RX0
+        WRITE RX0
+        ",
+    );
+    assert!(machine.execute_all().unwrap());
+
+    // DUMP costs a cycle, but doesn't affect the output
+    assert_eq!(machine.cycle_count(), 7);
+    assert_eq!(machine.debug_snapshots().len(), 1);
+    let snapshot = &machine.debug_snapshots()[0];
+    assert_eq!(snapshot.span.start_line, 5);
+    assert_eq!(snapshot.cycle, 4);
+    assert_eq!(
+        snapshot.registers,
+        vec![
+            (RegisterRef::InputLength, 0),
+            (RegisterRef::InputHead, 0),
+            (RegisterRef::StackLength(0), 2),
+            (RegisterRef::User(0), 3),
+        ]
+        .into_iter()
+        .collect::<BTreeMap<_, _>>()
+    );
+    assert_eq!(
+        snapshot.stacks,
+        vec![(StackRef(0), vec![3, 4])]
+            .into_iter()
+            .collect::<BTreeMap<_, _>>()
+    );
+}
+
+#[test]
+fn test_state_order() {
+    let machine = allocate!(
+        HardwareSpec {
+            num_registers: 2,
+            num_stacks: 2,
+            max_stack_length: 5,
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "NOP",
+    );
+
+    // Special registers first, then stack lengths, then user registers
+    assert_eq!(
+        machine.registers().keys().copied().collect::<Vec<_>>(),
+        vec![
+            RegisterRef::InputLength,
+            RegisterRef::InputHead,
+            RegisterRef::StackLength(0),
+            RegisterRef::StackLength(1),
+            RegisterRef::User(0),
+            RegisterRef::User(1),
+        ]
+    );
+    assert_eq!(
+        machine.stacks().keys().copied().collect::<Vec<_>>(),
+        vec![StackRef(0), StackRef(1)]
+    );
+    assert_eq!(
+        machine
+            .max_stack_usage()
+            .keys()
+            .copied()
+            .collect::<Vec<_>>(),
+        vec![StackRef(0), StackRef(1)]
+    );
+}
+
+#[test]
+fn test_extern_ops() {
+    let hardware_spec = HardwareSpec {
+        num_registers: 2,
+        num_extern_ops: 2,
+        ..Default::default()
+    };
+    let src = "
+        READ RX0
+        EXT 0 RX0
+        EXT 1 RX0
+        WRITE RX0
+        ";
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let handler_calls = Arc::clone(&calls);
+    let mut machine = allocate!(
+        hardware_spec.clone(),
+        ProgramSpec::new(vec![3], vec![-7]),
+        src
+    );
+    // Op 0 doubles, op 1 negates and subtracts 1
+    machine.set_extern_handler(Box::new(move |id, value| {
+        handler_calls.lock().unwrap().push((id, value));
+        match id {
+            0 => Ok(value * 2),
+            _ => Ok(-value - 1),
+        }
+    }));
+    assert!(machine.execute_all().unwrap());
+    assert_eq!(calls.lock().unwrap().as_slice(), &[(0, 3), (1, 6)]);
+
+    // Errors from the handler halt the program
+    let mut machine = allocate!(
+        hardware_spec.clone(),
+        ProgramSpec::new(vec![3], vec![-7]),
+        src
+    );
+    machine.set_extern_handler(Box::new(|id, value| match id {
+        0 => Ok(value),
+        _ => Err(format!("op {} is out of fuel", id)),
+    }));
+    assert_eq!(
+        machine.execute_all().unwrap_err().errors()[0].to_string(),
+        "Runtime error at 4:9: Extern op failed in `EXT 1 RX0`: op 1 is out \
+            of fuel"
+    );
+    assert_eq!(machine.registers()[&RegisterRef::User(0)], 3);
+    assert_eq!(machine.program_counter(), 2);
+
+    // Without a handler, EXT always fails
+    let mut machine =
+        allocate!(hardware_spec, ProgramSpec::new(vec![3], vec![-7]), src);
+    assert_eq!(
+        machine.execute_all().unwrap_err().errors()[0].to_string(),
+        "Runtime error at 3:9: Cannot execute `EXT 0 RX0`, extern ops aren't \
+            available here"
+    );
+}
+
+#[test]
+fn test_dump_buffer_cap() {
+    let hardware_spec = HardwareSpec {
+        num_registers: 2,
+        dump_enabled: true,
+        ..Default::default()
+    };
+    let src = "
+    START:
+    ADD RX0 1
+    DUMP
+    CMP RX1 RX0 150
+    JLZ RX1 START
+    ";
+
+    // The oldest snapshots should be dropped once the buffer is full
+    let mut machine =
+        allocate!(hardware_spec.clone(), ProgramSpec::default(), src);
+    machine.execute_all().unwrap();
+    let snapshots = machine.debug_snapshots();
+    assert_eq!(snapshots.len(), DEFAULT_MAX_DEBUG_SNAPSHOTS);
+    assert_eq!(snapshots[0].registers[&RegisterRef::User(0)], 51);
+    assert_eq!(snapshots[99].registers[&RegisterRef::User(0)], 150);
+
+    // Lowering the cap should drop the oldest snapshots immediately
+    machine.set_max_debug_snapshots(3);
+    let values: Vec<_> = machine
+        .debug_snapshots()
+        .iter()
+        .map(|snapshot| snapshot.registers[&RegisterRef::User(0)])
+        .collect();
+    assert_eq!(values, vec![148, 149, 150]);
+
+    // Cap applies during execution too
+    let mut machine = allocate!(hardware_spec, ProgramSpec::default(), src);
+    machine.set_max_debug_snapshots(2);
+    machine.execute_all().unwrap();
+    assert_eq!(machine.debug_snapshots().len(), 2);
+    // 4 instructions per loop, and DUMP is the second
+    assert_eq!(machine.debug_snapshots()[0].cycle, 4 * 148 + 2);
+}
+
+#[test]
+fn test_test_all() {
+    let program_spec = ProgramSpec::with_test_cases(vec![
+        TestCase::new(vec![1, 2], vec![3]),
+        TestCase::new(vec![5, -5], vec![0]),
+        // This one will fail, since there's an extra input
+        TestCase::new(vec![1, 1, 1], vec![2]),
+        // This one will fail with an error
+        TestCase::new(vec![1], vec![1]),
+    ]);
+    let compiler = Compiler::compile(
+        "
+        READ RX0
+        READ RX1
+        ADD RX0 RX1
+        WRITE RX0
+        ",
+        HardwareSpec {
+            num_registers: 2,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    // Each machine should get its own test case
+    let machines = compiler.allocate_all(&program_spec).unwrap();
+    assert_eq!(machines.len(), 4);
+    assert_eq!(machines[1].input(), &[5, -5]);
+
+    let results = compiler.test_all(&program_spec).unwrap();
+    assert!(!results.successful());
+    let outcomes: Vec<_> = results
+        .cases
+        .iter()
+        .map(|result| (result.successful(), result.cycle_count))
+        .collect();
+    assert_eq!(outcomes, vec![(true, 4), (true, 4), (false, 4), (false, 2)]);
+    assert!(matches!(
+        results.cases[2].failure_reason,
+        Some(FailureReason::RemainingInput)
+    ));
+    assert_eq!(
+        results.cases[3].error.as_ref().unwrap().errors()[0].to_string(),
+        "Runtime error at 3:9: Read attempted on empty input"
+    );
+}
+
+#[test]
+fn test_run_solution() {
+    let hardware_spec = HardwareSpec {
+        num_registers: 1,
+        num_stacks: 1,
+        max_stack_length: 2,
+        ..Default::default()
+    };
+    let program_spec = ProgramSpec::with_test_cases(vec![
+        TestCase::new(vec![1, 2], vec![2, 1]),
+        // The stack can't hold all of these
+        TestCase::new(vec![1, 2, 3], vec![3, 2, 1]),
+    ]);
+    let src = "
+    READ_LOOP:
+    JEZ RLI WRITE_LOOP
+    READ RX0
+    PUSH RX0 S0
+    JMP READ_LOOP
+    WRITE_LOOP:
+    JEZ RS0 END
+    POP S0 RX0
+    WRITE RX0
+    JMP WRITE_LOOP
+    END:
+    ";
+
+    let result = run_solution(&hardware_spec, &program_spec, src);
+    assert!(!result.successful());
+    match result {
+        SolutionResult::Executed {
+            num_instructions,
+            results,
+        } => {
+            assert_eq!(num_instructions, 8);
+            assert!(results.cases[0].successful());
+            assert!(matches!(
+                results.cases[1].failure_reason,
+                Some(FailureReason::RuntimeError)
+            ));
+            assert_eq!(
+                results.cases[1].error.as_ref().unwrap().errors()[0]
+                    .to_string(),
+                "Runtime error at 5:14: Overflow on stack `S0`"
+            );
+        }
+        SolutionResult::CompileError(errors) => {
+            panic!("Unexpected compile error: {}", errors)
+        }
+        SolutionResult::AllocateError(error) => {
+            panic!("Unexpected allocate error: {}", error)
+        }
+    }
+
+    // A solution that doesn't compile is never run
+    let result = run_solution(&hardware_spec, &program_spec, "READ RX1");
+    assert!(!result.successful());
+    match result {
+        SolutionResult::CompileError(errors) => assert_eq!(
+            errors.errors()[0].to_string(),
+            "Validation error at 1:6: Invalid reference to register `RX1` \
+                (this hardware has register RX0)"
+        ),
+        _ => panic!("Expected compile error"),
+    }
+
+    // Neither is a solution that uses a constant the spec doesn't have
+    let result = run_solution(&hardware_spec, &program_spec, "WRITE $K");
+    assert!(!result.successful());
+    match result {
+        SolutionResult::AllocateError(error) => assert_eq!(
+            error.to_string(),
+            "Program uses constants that aren't defined by the program spec: \
+            `$K`"
+        ),
+        _ => panic!("Expected allocate error"),
+    }
+}
+
+#[test]
+fn test_run_solution_send() {
+    // Solutions can be scored in parallel
+    fn assert_send<T: Send>() {}
+    assert_send::<SolutionResult>();
+}
+
+#[test]
+fn test_output_diff() {
+    let run = |expected_output: Vec<LangValue>, src: &str| {
+        let mut machine = allocate!(
+            HardwareSpec::default(),
+            ProgramSpec::new(vec![], expected_output),
+            src
+        );
+        // Nothing to compare against until the program finishes
+        assert_eq!(machine.output_diff(), None);
+        let _ = machine.execute_all();
+        machine
+    };
+
+    // Output is too short
+    let machine = run(vec![1, 2, 3], "WRITE 1");
+    assert_eq!(machine.expected_output(), &[1, 2, 3]);
+    let diff = machine.output_diff().unwrap();
+    assert_eq!(
+        diff,
+        OutputDiff {
+            index: 1,
+            expected: Some(2),
+            actual: None,
+            missing: 2,
+            extra: 0,
+        }
+    );
+    assert_eq!(
+        diff.to_string(),
+        "output differs at position 1: expected 2, got nothing \
+            (2 values missing)"
+    );
+
+    // Output is too long
+    let diff = run(vec![1], "WRITE 1\nWRITE 2").output_diff().unwrap();
+    assert_eq!(
+        diff,
+        OutputDiff {
+            index: 1,
+            expected: None,
+            actual: Some(2),
+            missing: 0,
+            extra: 1,
+        }
+    );
+    assert_eq!(
+        diff.to_string(),
+        "output differs at position 1: expected nothing, got 2 \
+            (1 extra value)"
+    );
+
+    // One value is wrong
+    let diff = run(vec![4, 9, 5], "WRITE 4\nWRITE 6\nWRITE 5")
+        .output_diff()
+        .unwrap();
+    assert_eq!(
+        diff,
+        OutputDiff {
+            index: 1,
+            expected: Some(9),
+            actual: Some(6),
+            missing: 0,
+            extra: 0,
+        }
+    );
+    assert_eq!(
+        diff.to_string(),
+        "output differs at position 1: expected 9, got 6"
+    );
+
+    // Only incorrect output gets a diff
+    assert_eq!(run(vec![1], "WRITE 1").output_diff(), None);
+    assert_eq!(run(vec![1], "READ RX0").output_diff(), None);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_round_trip() {
+    let mut machine = allocate!(
+        HardwareSpec {
+            num_registers: 1,
+            num_stacks: 1,
+            max_stack_length: 5,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![1, 2, 3], vec![3, 2, 1]),
+        "
+        START:
+        JEZ RLI END
+        READ RX0
+        PUSH RX0 S0
+        JMP START
+        END:
+        JEZ RS0 DONE
+        POP S0 RX0
+        WRITE RX0
+        JMP END
+        DONE:
+        "
+    );
+
+    // Save the machine partway through execution
+    execute_n(&mut machine, 6);
+    let serialized = serde_json::to_string(&machine).unwrap();
+    let mut restored: Machine = serde_json::from_str(&serialized).unwrap();
+    assert_state_eq(&machine, &restored);
+
+    // Both copies should finish the same way
+    assert!(machine.execute_all().unwrap());
+    assert!(restored.execute_all().unwrap());
+    assert_state_eq(&machine, &restored);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_round_trip_error() {
+    let mut machine =
+        allocate!(HardwareSpec::default(), ProgramSpec::default(), "READ RX0");
+    assert!(machine.execute_all().is_err());
+
+    // The error should still be able to render its source
+    let serialized = serde_json::to_string(&machine).unwrap();
+    let restored: Machine = serde_json::from_str(&serialized).unwrap();
+    assert_state_eq(&machine, &restored);
+    assert_eq!(
+        restored.error().unwrap().to_string(),
+        machine.error().unwrap().to_string()
+    );
+}
+
+#[test]
+fn test_initial_state() {
+    let registers = |initial_state: InitialState| {
+        let machine = allocate!(
+            HardwareSpec {
+                num_registers: 4,
+                initial_state,
+                ..Default::default()
+            },
+            ProgramSpec::default(),
+            "WRITE RX0"
+        );
+        assert_eq!(
+            machine.seed(),
+            match initial_state {
+                InitialState::Zeroed => None,
+                InitialState::Random { seed } => Some(seed),
+            }
+        );
+        let registers = machine.registers();
+        (0..4)
+            .map(|id| registers[&RegisterRef::User(id)])
+            .collect::<Vec<_>>()
+    };
+
+    // Zeroed is the default, and unchanged
+    assert_eq!(HardwareSpec::default().initial_state, InitialState::Zeroed);
+    assert_eq!(registers(InitialState::Zeroed), vec![0; 4]);
+
+    // The same seed always gives the same registers, and different seeds
+    // give different ones
+    let random = registers(InitialState::Random { seed: 42 });
+    assert_eq!(random, registers(InitialState::Random { seed: 42 }));
+    assert_ne!(random, registers(InitialState::Random { seed: 43 }));
+    assert_ne!(random, vec![0; 4]);
+
+    // A program that assumes zeroed registers fails, and resetting gives the
+    // same garbage again
+    let mut machine = allocate!(
+        HardwareSpec {
+            num_registers: 1,
+            initial_state: InitialState::Random { seed: 42 },
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![], vec![1]),
+        "ADD RX0 1\nWRITE RX0"
+    );
+    assert!(!machine.execute_all().unwrap());
+    assert_eq!(machine.output(), &[random[0].wrapping_add(1)]);
+    machine.reset();
+    assert_eq!(machine.registers()[&RegisterRef::User(0)], random[0]);
+    assert!(machine.stacks().is_empty());
+}
+
+#[test]
+fn test_spec_mismatch() {
+    use gdlk::{
+        error::{AllocateError, SpecMismatchError},
+        ErrorPolicy, ProgramStats,
+    };
+
+    let src = "
+        READ RX3
+        PUSH RX3 S1
+        WRITE RS1
+        ";
+    let compiler = Compiler::compile(
+        src,
+        HardwareSpec {
+            num_registers: 4,
+            num_stacks: 2,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let new = |hardware_spec: HardwareSpec| {
+        Machine::new(
+            hardware_spec,
+            &TestCase::new(vec![1], vec![1]),
+            compiler.program().clone(),
+            src,
+            ErrorPolicy::default(),
+        )
+    };
+
+    // Pairing the program with smaller hardware is an error, not a panic
+    let error = new(HardwareSpec {
+        num_registers: 1,
+        num_stacks: 2,
+        ..Default::default()
+    })
+    .unwrap_err();
+    assert_eq!(
+        error,
+        AllocateError::SpecMismatch(SpecMismatchError::InvalidRegisterRef {
+            reference: RegisterRef::User(3),
+            available: gdlk::error::AvailableRefs::UserRegisters(1),
+        })
+    );
+    assert_eq!(
+        error.to_string(),
+        "Program references register `RX3`, but this hardware has register \
+        RX0"
+    );
+    assert_eq!(
+        new(HardwareSpec {
+            num_registers: 4,
+            num_stacks: 1,
+            ..Default::default()
+        })
+        .unwrap_err()
+        .to_string(),
+        "Program references register `RS1`, but this hardware has 1 stack, \
+        with length register RS0"
+    );
+    assert_eq!(
+        new(HardwareSpec {
+            num_registers: 4,
+            num_stacks: 1,
+            ..Default::default()
+        })
+        .map(|_| ()),
+        Err(AllocateError::SpecMismatch(
+            SpecMismatchError::InvalidRegisterRef {
+                reference: RegisterRef::StackLength(1),
+                available: gdlk::error::AvailableRefs::StackLengthRegisters(1),
+            }
+        ))
+    );
+
+    // The check doesn't trust the program's stats, since they could be stale
+    // or forged, e.g. in a deserialized machine
+    let mut program = compiler.program().clone();
+    program.stats = ProgramStats::default();
+    assert_eq!(
+        Machine::new(
+            HardwareSpec {
+                num_registers: 1,
+                num_stacks: 2,
+                ..Default::default()
+            },
+            &TestCase::new(vec![1], vec![1]),
+            program,
+            src,
+            ErrorPolicy::default(),
+        )
+        .map(|_| ()),
+        Err(AllocateError::SpecMismatch(
+            SpecMismatchError::InvalidRegisterRef {
+                reference: RegisterRef::User(3),
+                available: gdlk::error::AvailableRefs::UserRegisters(1),
+            }
+        ))
+    );
+
+    // Bigger hardware is fine
+    let mut machine = new(HardwareSpec {
+        num_registers: 8,
+        num_stacks: 4,
+        max_stack_length: 1,
+        ..Default::default()
+    })
+    .unwrap();
+    assert!(machine.execute_all().unwrap());
+}
+
+#[test]
+fn test_invalid_reference() {
+    use gdlk::ErrorPolicy;
+
+    // Clearing the stats doesn't get a program past the up-front hardware
+    // check, since it looks at every instruction's arguments
+    let reject = |src: &str| {
+        let mut program = Compiler::compile(
+            src,
+            HardwareSpec {
+                num_registers: 2,
+                num_stacks: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .program()
+        .clone();
+        program.stats = Default::default();
+        Machine::new(
+            HardwareSpec {
+                num_registers: 1,
+                num_stacks: 0,
+                ..Default::default()
+            },
+            &TestCase::new(vec![1], vec![]),
+            program,
+            src,
+            ErrorPolicy::Lenient,
+        )
+        .unwrap_err()
+        .to_string()
+    };
+
+    for src in &["PUSH 1 S0", "POP S0 RX0", "PEEK S0 RX0", "POPN S0 1"] {
+        assert_eq!(
+            reject(src),
+            "Program references stack `S0`, but this hardware has no stacks",
+            "{}",
+            src
+        );
+    }
+    assert_eq!(
+        reject("WRITE RS0"),
+        "Program references register `RS0`, but this hardware has no stacks"
+    );
+    assert_eq!(
+        reject("READ RX1"),
+        "Program references register `RX1`, but this hardware has register \
+        RX0"
+    );
+    assert_eq!(
+        reject("SET RX0 3\nADD RX0 RX1"),
+        "Program references register `RX1`, but this hardware has register \
+        RX0"
+    );
+}
+
+#[test]
+fn test_invalid_reference_runtime() {
+    use gdlk::{
+        ast::{compiled::Program, Instruction, Node},
+        ErrorPolicy, Span,
+    };
+
+    // A hand-built program can fit the hardware, but still do things that
+    // the compiler would never allow. The machine catches those as it runs.
+    // The source passed to the machine is what the program "should" look
+    // like, so the errors render sensibly.
+    let run = |src: &str, real_src: &str, edit: fn(&mut Program<Span>)| {
+        let mut program = Compiler::compile(src, HardwareSpec::default())
+            .unwrap()
+            .program()
+            .clone();
+        edit(&mut program);
+        let mut machine = Machine::new(
+            HardwareSpec::default(),
+            &TestCase::new(vec![1], vec![]),
+            program,
+            real_src,
+            // Invalid references can't be recovered from
+            ErrorPolicy::Lenient,
+        )
+        .unwrap();
+        machine.execute_all().unwrap_err().errors()[0].to_string()
+    };
+
+    // Writing to a read-only register
+    assert_eq!(
+        run("READ RX0", "READ RLI", |program| {
+            if let Instruction::Read(Node(reg, _)) =
+                &mut program.instructions[0].0
+            {
+                *reg = RegisterRef::InputLength;
+            }
+        }),
+        "Runtime error at 1:6: Invalid reference to `RLI`, it doesn't exist \
+        or can't be used here"
+    );
+    // Jumping to a label that the program doesn't have
+    assert_eq!(
+        run("JMP END\nEND:", "JMP END\nEND:", |program| {
+            program.symbol_table.clear()
+        }),
+        "Runtime error at 1:5: Invalid reference to `END`, it doesn't exist \
+        or can't be used here"
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_call_invalid_label() {
+    use gdlk::ErrorPolicy;
+
+    // A call to a label that doesn't exist fails before anything is pushed
+    // onto the call stack
+    let src = "CALL FUNC\nFUNC:\nRET";
+    let mut program = Compiler::compile(src, HardwareSpec::default())
+        .unwrap()
+        .program()
+        .clone();
+    program.symbol_table.clear();
+    let mut machine = Machine::new(
+        HardwareSpec::default(),
+        &TestCase::new(vec![], vec![]),
+        program,
+        src,
+        ErrorPolicy::Strict,
+    )
+    .unwrap();
+    assert_eq!(
+        machine.execute_all().unwrap_err().errors()[0].to_string(),
+        "Runtime error at 1:6: Invalid reference to `FUNC`, it doesn't exist \
+        or can't be used here"
+    );
+    assert_eq!(
+        serde_json::to_value(&machine).unwrap()["call_stack"],
+        serde_json::json!([])
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_spec_mismatch() {
+    let machine = allocate!(
+        HardwareSpec {
+            num_registers: 2,
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "SET RX1 1"
+    );
+    let mut serialized = serde_json::to_value(&machine).unwrap();
+    serialized["hardware_spec"]["num_registers"] = 1.into();
+    assert_eq!(
+        serde_json::from_value::<Machine>(serialized)
+            .unwrap_err()
+            .to_string(),
+        "Program references register `RX1`, but this hardware has register \
+        RX0"
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_truncated_state() {
+    let machine = allocate!(
+        HardwareSpec::standard(),
+        ProgramSpec::default(),
+        "PUSH 1 S0"
+    );
+    let serialized = serde_json::to_value(&machine).unwrap();
+
+    let mut truncated = serialized.clone();
+    truncated["stacks"] = serde_json::json!([[]]);
+    assert_eq!(
+        serde_json::from_value::<Machine>(truncated)
+            .unwrap_err()
+            .to_string(),
+        "Machine state has 1 stacks, but the hardware has 2"
+    );
+
+    let mut truncated = serialized.clone();
+    truncated["registers"] = serde_json::json!([]);
+    assert_eq!(
+        serde_json::from_value::<Machine>(truncated)
+            .unwrap_err()
+            .to_string(),
+        "Machine state has 0 registers, but the hardware has 4"
+    );
+
+    let mut truncated = serialized;
+    truncated["max_stack_usage"] = serde_json::json!([0, 0, 0]);
+    assert_eq!(
+        serde_json::from_value::<Machine>(truncated)
+            .unwrap_err()
+            .to_string(),
+        "Machine state has 3 stack usage entries, but the hardware has 2"
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_cache_round_trip() {
+    let hardware_spec = HardwareSpec {
+        num_registers: 1,
+        num_stacks: 1,
+        max_stack_length: 5,
+        ..Default::default()
+    };
+    let program_spec = ProgramSpec::new(vec![1, 2, 3], vec![3, 2, 1]);
+    let compiler = Compiler::compile(
+        "
+        START:
+        JEZ RLI END
+        READ RX0
+        PUSH RX0 S0
+        JMP START
+        END:
+        JEZ RS0 DONE
+        POP S0 RX0
+        WRITE RX0
+        JMP END
+        UNUSED:
+        DONE:
+        ",
+        hardware_spec.clone(),
+    )
+    .unwrap();
+
+    let bytes = compiler.to_bytes();
+    assert_eq!(&bytes[..4], b"GDLK");
+    let restored = Compiler::from_bytes(&bytes, &hardware_spec).unwrap();
+    assert_eq!(restored.program(), compiler.program());
+    // Warnings and their source survive too
+    assert_eq!(
+        restored.warnings().to_string(),
+        compiler.warnings().to_string()
+    );
+
+    let mut machine = restored.allocate(&program_spec).unwrap();
+    assert!(machine.execute_all().unwrap());
+    assert!(machine.successful());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_cache_errors() {
+    use gdlk::error::CacheError;
+
+    let hardware_spec = HardwareSpec {
+        num_registers: 2,
+        ..Default::default()
+    };
+    let bytes = Compiler::compile("READ RX1\nWRITE RX1", hardware_spec.clone())
+        .unwrap()
+        .to_bytes();
+    let load = |bytes: &[u8], hardware_spec: &HardwareSpec| {
+        Compiler::from_bytes(bytes, hardware_spec).unwrap_err()
+    };
+
+    assert!(matches!(
+        load(&bytes[..6], &hardware_spec),
+        CacheError::Truncated
+    ));
+    assert!(matches!(
+        load(b"not a program", &hardware_spec),
+        CacheError::InvalidHeader
+    ));
+    let mut old_version = bytes.clone();
+    old_version[4..8].copy_from_slice(&0u32.to_le_bytes());
+    assert!(matches!(
+        load(&old_version, &hardware_spec),
+        CacheError::VersionMismatch {
+            expected: 3,
+            actual: 0
+        }
+    ));
+    assert!(matches!(
+        load(&bytes[..bytes.len() - 10], &hardware_spec),
+        CacheError::Corrupt(_)
+    ));
+
+    // Different hardware is rejected outright
+    let small_hardware = HardwareSpec::default();
+    assert!(matches!(
+        load(&bytes, &small_hardware),
+        CacheError::HardwareMismatch
+    ));
+
+    // If the stored hardware is tampered with to match, the program still
+    // gets caught by validation
+    let mut payload: serde_json::Value =
+        serde_json::from_slice(&bytes[8..]).unwrap();
+    payload["hardware_spec"]["num_registers"] = 1.into();
+    let mut tampered = bytes[..8].to_vec();
+    tampered.extend(serde_json::to_vec(&payload).unwrap());
+    match load(&tampered, &small_hardware) {
+        CacheError::InvalidProgram(errors) => assert_eq!(
+            errors.errors()[0].to_string(),
+            "Validation error at 1:6: Invalid reference to register `RX1` \
+                (this hardware has register RX0)"
+        ),
+        error => panic!("Unexpected error: {:?}", error),
+    }
+
+    // Spans are checked against the stored source before anything gets
+    // rendered with them
+    let tamper = |edit: fn(&mut serde_json::Value)| {
+        let json = String::from_utf8(bytes[8..].to_vec())
+            .unwrap()
+            .replace("RX1", "RX7");
+        let mut payload: serde_json::Value =
+            serde_json::from_str(&json).unwrap();
+        edit(&mut payload);
+        let mut tampered = bytes[..8].to_vec();
+        tampered.extend(serde_json::to_vec(&payload).unwrap());
+        load(&tampered, &hardware_spec).to_string()
+    };
+    assert_eq!(
+        tamper(|payload| {
+            payload["program"]["instructions"][0][1]["offset"] = 400.into()
+        }),
+        "Cached program is corrupt: Span at offset 400 doesn't fit the source"
+    );
+    assert_eq!(
+        tamper(|payload| {
+            payload["program"]["instructions"][1][1]["start_line"] = 5.into()
+        }),
+        "Cached program is corrupt: Span at offset 9 doesn't fit the source"
+    );
+    assert_eq!(
+        tamper(|payload| {
+            payload["program"]["instructions"][0][1]["source_id"] = 3.into()
+        }),
+        "Cached program is corrupt: Span points into unknown file 3"
+    );
+    // With the spans intact, the bad register is caught by validation, and
+    // the error can be rendered
+    assert!(tamper(|_| {}).starts_with(
+        "Cached program is invalid for this hardware:\n\
+        Validation error at 1:6: Invalid reference to register `RX7`"
+    ));
+
+    // Stored stats aren't trusted, they're recomputed from the program
+    let mut payload: serde_json::Value =
+        serde_json::from_slice(&bytes[8..]).unwrap();
+    payload["program"]["stats"]["referenced_registers"] =
+        serde_json::json!(["RX5"]);
+    let mut tampered = bytes[..8].to_vec();
+    tampered.extend(serde_json::to_vec(&payload).unwrap());
+    let restored = Compiler::from_bytes(&tampered, &hardware_spec).unwrap();
+    assert_eq!(
+        restored.program().stats.referenced_registers,
+        vec![RegisterRef::User(1)].into_iter().collect()
+    );
+}
+
+#[test]
+fn test_execute_all_traced() {
+    // The trace format is stable, so compare against a saved copy
+    let mut machine = allocate!(
+        HardwareSpec {
+            num_registers: 4,
+            num_stacks: 0,
+            max_stack_length: 0,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![4], vec![0, 1, 1, 2]),
+        "
+        READ RX0
+        SET RX1 0
+        SET RX2 1
+        LOOP:
+            JEZ RX0 END
+            WRITE RX1
+            SET RX3 RX2
+            ADD RX2 RX1
+            SET RX1 RX3
+            SUB RX0 1
+            JMP LOOP
+        END:
+        "
+    );
+    let mut trace = Vec::new();
+    assert!(machine.execute_all_traced(&mut trace).unwrap().unwrap());
+    assert_eq!(
+        String::from_utf8(trace).unwrap(),
+        include_str!("golden/fibonacci.trace")
+    );
+
+    // Stack lengths are included, and errors end the trace
+    let mut machine = allocate!(
+        HardwareSpec {
+            num_registers: 1,
+            num_stacks: 2,
+            max_stack_length: 1,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![], vec![]),
+        "
+        PUSH 3 S1
+        PUSH 3 S1
+        "
+    );
+    let mut trace = Vec::new();
+    let error = machine.execute_all_traced(&mut trace).unwrap().unwrap_err();
+    assert_eq!(error.errors()[0].error_code(), "R003_STACK_OVERFLOW");
+    assert_eq!(
+        String::from_utf8(trace).unwrap(),
+        "1 0 PUSH 3 S1 | RX0=0 RS0=0 RS1=1\nEND error R003_STACK_OVERFLOW at 1\n"
+    );
+
+    // A failed program gets its failure reason
+    let mut machine = allocate!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![1], vec![2]),
+        "READ RX0\nWRITE RX0"
+    );
+    let mut trace = Vec::new();
+    assert!(!machine.execute_all_traced(&mut trace).unwrap().unwrap());
+    assert_eq!(
+        String::from_utf8(trace).unwrap(),
+        "1 0 READ RX0 | RX0=1\n2 1 WRITE RX0 | RX0=1\nEND failure incorrect_output\n"
+    );
+}
+
+#[test]
+fn test_include() {
+    let files: HashMap<String, String> = [
+        ("double.gdlk", "ADD RX0 RX0\nJMP DONE\n"),
+        ("underflow.gdlk", "; Nothing to pop\nPOP S0 RX0\n"),
+    ]
+    .iter()
+    .map(|(path, code)| ((*path).to_owned(), (*code).to_owned()))
+    .collect();
+    let program_spec = ProgramSpec::new(vec![3], vec![6]);
+
+    // Labels work across files
+    let compiler = Compiler::compile_with_resolver(
+        "READ RX0\n%include \"double.gdlk\"\nWRITE RX0\nDONE:\nWRITE RX0",
+        HardwareSpec::default(),
+        &files,
+    )
+    .unwrap();
+    assert_eq!(compiler.includes().files().len(), 1);
+    assert_eq!(compiler.includes().files()[0].name, "double.gdlk");
+    // Line numbers only refer to the main source
+    let program = compiler.program();
+    assert_eq!(program.instructions_on_line(1), vec![0]);
+    assert_eq!(program.instructions_on_line(2), Vec::<usize>::new());
+    assert_eq!(program.instructions_on_line(3), vec![3]);
+    assert_eq!(program.span_at(1).unwrap().source_id, Some(0));
+    let mut machine = compiler.allocate(&program_spec).unwrap();
+    assert!(machine.execute_all().unwrap());
+    assert!(machine.successful());
+
+    // The compiler's stages can load them too, and validate for whatever
+    // hardware they're given
+    let src = "READ RX0\n%include \"double.gdlk\"\nWRITE RX0\nDONE:\nWRITE RX0";
+    assert!(Compiler::parse_only(src).is_err());
+    let compiler = Compiler::parse_only_with_resolver(src, &files).unwrap();
+    assert_eq!(compiler.source_ast().body.len(), 6);
+    let compiler = compiler
+        .validate(HardwareSpec {
+            num_registers: 2,
+            ..Default::default()
+        })
+        .unwrap()
+        .delabel();
+    assert_eq!(compiler.compiled_ast().instructions.len(), 5);
+    let machine = compiler.allocate(&program_spec).unwrap();
+    assert_eq!(machine.hardware_spec().num_registers, 2);
+
+    // Runtime errors in included code point into the included file
+    let mut machine = Compiler::compile_with_resolver(
+        "READ RX0\n%include \"underflow.gdlk\"",
+        HardwareSpec {
+            num_stacks: 1,
+            ..Default::default()
+        },
+        &files,
+    )
+    .unwrap()
+    .allocate(&program_spec)
+    .unwrap();
+    let error = machine.execute_all().unwrap_err();
+    assert_eq!(error.errors()[0].source_name(), Some("underflow.gdlk"));
+    assert_eq!(
+        error.to_string(),
+        "Runtime error at underflow.gdlk:2:5: Cannot pop from empty stack `S0`
+  |
+2 | POP S0 RX0
+  |     ^^"
+    );
+}
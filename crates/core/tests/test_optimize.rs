@@ -0,0 +1,568 @@
+//! Integration tests for the optional optimization passes, enabled via
+//! [CompileOptions]. Optimized programs must behave exactly the same as the
+//! unoptimized version, other than taking fewer instructions and cycles.
+
+use gdlk::{
+    ast::{compiled, InstructionKind, LangValue},
+    CompileOptions, Compiler, CostModel, HardwareSpec, OverflowBehavior,
+    ProgramSpec, Span, TestCase,
+};
+
+/// Compiles the program for the given hardware both with and without constant
+/// folding, and runs both versions against every test case in the program
+/// spec. Asserts that the output and the outcome of each test case are the
+/// same, and that the optimized version never takes more cycles. Returns the
+/// optimized compiler, for further checks.
+macro_rules! assert_equivalent {
+    ($hardware_spec:expr, $program_spec:expr, $src:expr $(,)?) => {{
+        let hardware_spec: HardwareSpec = $hardware_spec;
+        let program_spec: ProgramSpec = $program_spec;
+        let unoptimized =
+            Compiler::compile($src, hardware_spec.clone()).unwrap();
+        let optimized = Compiler::compile_with_options(
+            $src,
+            hardware_spec,
+            CompileOptions {
+                constant_folding: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let machines = unoptimized
+            .allocate_all(&program_spec)
+            .unwrap()
+            .into_iter()
+            .zip(optimized.allocate_all(&program_spec).unwrap());
+        for (i, (mut expected, mut actual)) in machines.enumerate() {
+            let _ = expected.execute_all();
+            let _ = actual.execute_all();
+            assert_eq!(actual.output(), expected.output(), "case {} output", i);
+            assert_eq!(
+                actual.successful(),
+                expected.successful(),
+                "case {} success",
+                i
+            );
+            assert_eq!(
+                actual.error().is_some(),
+                expected.error().is_some(),
+                "case {} error",
+                i
+            );
+            assert!(
+                actual.cycle_count() <= expected.cycle_count(),
+                "case {} cycles",
+                i
+            );
+        }
+        optimized
+    }};
+}
+
+/// Get the text of each instruction in a compiled program
+fn instructions(compiler: &Compiler<compiled::Program<Span>>) -> Vec<String> {
+    compiler
+        .instructions_pretty()
+        .into_iter()
+        .map(|(instruction, _)| instruction)
+        .collect()
+}
+
+#[test]
+fn test_fold_straight_line() {
+    let compiler = assert_equivalent!(
+        HardwareSpec {
+            num_registers: 2,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![], vec![9, 1]),
+        "
+        SET RX0 2
+        ADD RX0 3
+        MUL RX0 4
+        SUB RX0 1
+        DIV RX0 2
+        WRITE RX0
+        CMP RX1 RX0 RZR
+        WRITE RX1
+        ",
+    );
+    assert_eq!(
+        instructions(&compiler),
+        vec!["SET RX0 9", "WRITE RX0", "SET RX1 1", "WRITE RX1"]
+    );
+
+    // The surviving SET keeps the span of the DIV it replaced, and lists
+    // everything else that was folded into it
+    let (_, span) = compiler.instructions_pretty()[0];
+    assert_eq!(span.start_line, 6);
+    assert_eq!(span.end_line, 6);
+    assert_eq!(
+        compiler
+            .program()
+            .contributing_spans(0)
+            .iter()
+            .map(|span| span.start_line)
+            .collect::<Vec<_>>(),
+        vec![2, 3, 4, 5]
+    );
+    assert!(compiler.program().contributing_spans(1).is_empty());
+
+    let stats = compiler.stats();
+    assert_eq!(stats.num_instructions, 4);
+    assert_eq!(
+        stats.instruction_counts,
+        vec![(InstructionKind::Set, 2), (InstructionKind::Write, 2)]
+            .into_iter()
+            .collect()
+    );
+}
+
+#[test]
+fn test_fold_bitwise() {
+    let compiler = assert_equivalent!(
+        HardwareSpec {
+            num_registers: 2,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![5], vec![-3, 1]),
+        "
+        SET RX0 -1
+        SHL RX0 2
+        AND RX0 -3
+        XOR RX0 6
+        OR RX0 1
+        SHR RX0 1
+        WRITE RX0
+        READ RX1
+        AND RX1 1
+        WRITE RX1
+        ",
+    );
+    // The parity check depends on input, so it can't be folded
+    assert_eq!(
+        instructions(&compiler),
+        vec![
+            "SET RX0 -3",
+            "WRITE RX0",
+            "READ RX1",
+            "AND RX1 1",
+            "WRITE RX1"
+        ]
+    );
+}
+
+#[test]
+fn test_fold_reindex_labels() {
+    let compiler = assert_equivalent!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![], vec![3, 3, 3]),
+        "
+        SET RX0 1
+        ADD RX0 2
+        LOOP:
+        WRITE RX0
+        JLZ RLI LOOP
+        WRITE RX0
+        WRITE RX0
+        END:
+        ",
+    );
+    assert_eq!(
+        instructions(&compiler),
+        vec![
+            "SET RX0 3",
+            "WRITE RX0",
+            "JLZ RLI LOOP",
+            "WRITE RX0",
+            "WRITE RX0"
+        ]
+    );
+    let program = compiler.program();
+    assert_eq!(program.symbol_table["LOOP"], 1);
+    assert_eq!(program.symbol_table["END"], 5);
+    assert_eq!(
+        program
+            .labels()
+            .map(|(label, index)| (label.as_str(), index))
+            .collect::<Vec<_>>(),
+        vec![("LOOP", 1), ("END", 5)]
+    );
+}
+
+#[test]
+fn test_fold_unknown_values() {
+    // Values from the input, stacks, and jump targets are never folded
+    let compiler = assert_equivalent!(
+        HardwareSpec {
+            num_registers: 2,
+            num_stacks: 1,
+            max_stack_length: 4,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![5], vec![15, 6]),
+        "
+        READ RX0
+        SET RX1 10
+        ADD RX1 RX0
+        WRITE RX1
+        PUSH 6 S0
+        POP S0 RX0
+        SET RX1 RX0
+        WRITE RX1
+        ",
+    );
+    assert_eq!(
+        instructions(&compiler),
+        vec![
+            "READ RX0",
+            "SET RX1 10",
+            "ADD RX1 RX0",
+            "WRITE RX1",
+            "PUSH 6 S0",
+            "POP S0 RX0",
+            "SET RX1 RX0",
+            "WRITE RX1"
+        ]
+    );
+}
+
+#[test]
+fn test_fold_named_constants() {
+    // Named constants aren't known until the program spec is, so they can't
+    // be folded
+    let compiler = assert_equivalent!(
+        HardwareSpec::default(),
+        ProgramSpec::with_test_cases(vec![
+            TestCase::new(vec![], vec![5]),
+            TestCase::new(vec![], vec![7]).with_constants(vec![("K", 5)]),
+        ])
+        .with_constants(vec![("K", 3)]),
+        "
+        SET RX0 $K
+        ADD RX0 2
+        WRITE RX0
+        ",
+    );
+    assert_eq!(
+        instructions(&compiler),
+        vec!["SET RX0 $K", "ADD RX0 2", "WRITE RX0"]
+    );
+}
+
+#[test]
+fn test_fold_corpus() {
+    // Loops, where the value at the top of the loop isn't known
+    assert_equivalent!(
+        HardwareSpec {
+            num_registers: 2,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![], vec![1, 2, 3]),
+        "
+        SET RX0 0
+        LOOP:
+        ADD RX0 1
+        WRITE RX0
+        CMP RX1 RX0 3
+        JLZ RX1 LOOP
+        ",
+    );
+
+    // A value that's only read after a conditional jump
+    assert_equivalent!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![], vec![1]),
+        "
+        SET RX0 1
+        JEZ RZR END
+        SET RX0 2
+        END:
+        WRITE RX0
+        ",
+    );
+
+    // Subroutines
+    assert_equivalent!(
+        HardwareSpec {
+            num_registers: 2,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![1, 2, 3], vec![3, 5, 7]),
+        "
+        JMP MAIN
+        DOUBLE:
+        SET RX1 2
+        MUL RX0 RX1
+        RET
+        MAIN:
+        READ RX0
+        SET RX1 5
+        CALL DOUBLE
+        ADD RX0 1
+        WRITE RX0
+        JGZ RLI MAIN
+        ",
+    );
+
+    // Multiple test cases, with stacks
+    assert_equivalent!(
+        HardwareSpec {
+            num_registers: 2,
+            num_stacks: 1,
+            max_stack_length: 8,
+            ..Default::default()
+        },
+        ProgramSpec::with_test_cases(vec![
+            TestCase::new(vec![1, 2, 3], vec![3, 2, 1]),
+            TestCase::new(vec![4], vec![4]),
+        ]),
+        "
+        SET RX1 0
+        PUSH_LOOP:
+        READ RX0
+        PUSH RX0 S0
+        JGZ RLI PUSH_LOOP
+        POP_LOOP:
+        POP S0 RX0
+        WRITE RX0
+        JGZ RS0 POP_LOOP
+        ",
+    );
+
+    // Overflow, under each behavior
+    for overflow_behavior in [
+        OverflowBehavior::Wrap,
+        OverflowBehavior::Saturate,
+        OverflowBehavior::Error,
+    ] {
+        assert_equivalent!(
+            HardwareSpec {
+                overflow_behavior,
+                ..Default::default()
+            },
+            ProgramSpec::new(vec![], vec![LangValue::MIN]),
+            "
+            SET RX0 2147483647
+            ADD RX0 1
+            WRITE RX0
+            ",
+        );
+        assert_equivalent!(
+            HardwareSpec {
+                overflow_behavior,
+                ..Default::default()
+            },
+            ProgramSpec::new(vec![], vec![LangValue::MIN]),
+            "
+            SET RX0 -2147483648
+            DIV RX0 -1
+            WRITE RX0
+            ",
+        );
+    }
+
+    // Dividing by zero still fails at runtime
+    assert_equivalent!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![], vec![0]),
+        "
+        SET RX0 5
+        DIV RX0 0
+        WRITE RX0
+        ",
+    );
+}
+
+#[test]
+fn test_fold_error_provenance() {
+    // SET+ADD get folded, then the next ADD overflows. The error should point
+    // at the ADD, but also back to the folded lines that computed RX0.
+    let src = format!(
+        "
+        SET RX0 {}
+        ADD RX0 1
+        ADD RX0 1
+        WRITE RX0
+        ",
+        LangValue::MAX - 1
+    );
+    let compiler = assert_equivalent!(
+        HardwareSpec {
+            overflow_behavior: OverflowBehavior::Error,
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        &src,
+    );
+    assert_eq!(
+        instructions(&compiler),
+        vec![
+            format!("SET RX0 {}", LangValue::MAX),
+            "ADD RX0 1".into(),
+            "WRITE RX0".into()
+        ]
+    );
+
+    let mut machine = compiler.allocate(&ProgramSpec::default()).unwrap();
+    let error = machine.execute_all().unwrap_err();
+    assert_eq!(
+        error.errors()[0].to_string(),
+        "Runtime error at 4:9: Arithmetic overflow in `ADD RX0 1`\n\
+        note: folded from line 2\n\
+        note: folded from line 3"
+    );
+
+    // Without folding, there's nothing to note
+    let mut machine = Compiler::compile(
+        &src,
+        HardwareSpec {
+            overflow_behavior: OverflowBehavior::Error,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+    .allocate(&ProgramSpec::default())
+    .unwrap();
+    let error = machine.execute_all().unwrap_err();
+    assert_eq!(
+        error.errors()[0].to_string(),
+        "Runtime error at 4:9: Arithmetic overflow in `ADD RX0 1`"
+    );
+    assert!(error.errors()[0].contributing_spans().is_empty());
+}
+
+#[test]
+fn test_fold_keeps_sets_before_errors() {
+    // The first SET looks dead, but each instruction in between can fail,
+    // which stops the machine while RX0 still holds 1
+    let hardware_spec = HardwareSpec {
+        num_registers: 2,
+        num_stacks: 1,
+        max_stack_length: 4,
+        ..Default::default()
+    };
+    for (between, input) in &[
+        ("POP S0 RX1", vec![]),
+        ("PEEK S0 RX1", vec![]),
+        ("READ RX1", vec![]),
+        ("READ RX1\nDIV RX1 RX1", vec![0]),
+    ] {
+        let src = format!("SET RX0 1\n{}\nSET RX0 2\nWRITE RX0", between);
+        let program_spec = ProgramSpec::new(input.clone(), vec![2]);
+        let compiler = assert_equivalent!(
+            hardware_spec.clone(),
+            program_spec.clone(),
+            &src,
+        );
+        assert_eq!(instructions(&compiler)[0], "SET RX0 1", "{}", between);
+
+        let mut expected = Compiler::compile(&src, hardware_spec.clone())
+            .unwrap()
+            .allocate(&program_spec)
+            .unwrap();
+        let mut actual = compiler.allocate(&program_spec).unwrap();
+        assert!(expected.execute_all().is_err());
+        assert!(actual.execute_all().is_err());
+        assert_eq!(actual.registers(), expected.registers(), "{}", between);
+    }
+
+    // Overflow is only an error if the hardware says so. The label hides the
+    // value of RX1, so the ADD can't be folded.
+    let src = format!(
+        "SET RX1 {}\nLOOP:\nSET RX0 1\nADD RX1 RX1\nSET RX0 2\nWRITE RX0",
+        LangValue::MAX
+    );
+    let src = src.as_str();
+    let program_spec = ProgramSpec::new(vec![], vec![2]);
+    let compiler = assert_equivalent!(
+        HardwareSpec {
+            overflow_behavior: OverflowBehavior::Error,
+            ..hardware_spec.clone()
+        },
+        program_spec.clone(),
+        src,
+    );
+    assert_eq!(instructions(&compiler)[1], "SET RX0 1");
+    let compiler = assert_equivalent!(hardware_spec, program_spec, src);
+    assert_eq!(instructions(&compiler)[1], "ADD RX1 RX1");
+}
+
+#[test]
+fn test_fold_hardware_constraints() {
+    let src = "
+        SET RX0 2
+        ADD RX0 3
+        WRITE RX0
+        ";
+
+    // Nothing gets folded if SET isn't allowed...
+    let compiler = assert_equivalent!(
+        HardwareSpec {
+            allowed_instructions: Some(
+                vec![InstructionKind::Add, InstructionKind::Write]
+                    .into_iter()
+                    .collect()
+            ),
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![], vec![3]),
+        "
+        ADD RX0 3
+        ADD RX0 -1
+        WRITE RX0
+        ",
+    );
+    assert_eq!(
+        instructions(&compiler),
+        vec!["ADD RX0 3", "ADD RX0 -1", "WRITE RX0"]
+    );
+
+    // ...or if it costs more than what it would replace
+    let compiler = assert_equivalent!(
+        HardwareSpec {
+            cost_model: CostModel::default().with_cost(InstructionKind::Set, 3),
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![], vec![5]),
+        src,
+    );
+    assert_eq!(
+        instructions(&compiler),
+        vec!["SET RX0 2", "ADD RX0 3", "WRITE RX0"]
+    );
+}
+
+#[test]
+fn test_fold_computed_jump() {
+    // Folding would shift the indexes that JRI jumps to, so it's skipped
+    let compiler = assert_equivalent!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![], vec![5]),
+        "
+        SET RX0 2
+        ADD RX0 2
+        JRI RX0
+        WRITE 3
+        WRITE 5
+        ",
+    );
+    assert_eq!(
+        instructions(&compiler),
+        vec!["SET RX0 2", "ADD RX0 2", "JRI RX0", "WRITE 3", "WRITE 5"]
+    );
+}
+
+#[test]
+fn test_fold_disabled_by_default() {
+    let compiler = Compiler::compile(
+        "
+        SET RX0 2
+        ADD RX0 3
+        ",
+        HardwareSpec::default(),
+    )
+    .unwrap();
+    assert_eq!(instructions(&compiler), vec!["SET RX0 2", "ADD RX0 3"]);
+}
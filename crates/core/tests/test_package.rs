@@ -0,0 +1,54 @@
+//! Tests for the packaging of this crate, i.e. what gets published to
+//! crates.io. These shell out to cargo, using the same cargo binary that is
+//! running the tests.
+
+use std::{env, path::PathBuf, process::Command};
+
+/// Path to the manifest for this crate
+fn manifest_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml")
+}
+
+/// Build a cargo command with the given args, pointed at this crate
+fn cargo(args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO"));
+    command
+        .args(args)
+        .arg("--manifest-path")
+        .arg(manifest_path());
+    command
+}
+
+/// The wasm bindings should only be pulled in by the `wasm` feature, so that
+/// consumers on other targets don't pay for them.
+#[test]
+fn test_no_wasm_bindgen_by_default() {
+    let output = cargo(&["tree", "-p", "gdlk-lang", "-e", "normal"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "cargo tree failed: {:?}", output);
+    let tree = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        !tree.contains("wasm-bindgen"),
+        "wasm-bindgen found in default dependency tree:\n{}",
+        tree
+    );
+}
+
+/// Package the crate, then build the packaged version outside of the
+/// workspace. This catches files or path dependencies that are missing from
+/// the package. Requires network access to fetch dependencies, so it's ignored
+/// by default.
+#[test]
+#[ignore]
+fn test_package_builds_standalone() {
+    // Use a separate target dir so we don't contend for the lock on the one
+    // that's running these tests
+    let target_dir = env::temp_dir().join("gdlk-lang-package");
+    let status = cargo(&["package", "-p", "gdlk-lang", "--allow-dirty"])
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .status()
+        .unwrap();
+    assert!(status.success(), "cargo package failed");
+}
@@ -1,7 +1,12 @@
 //! Integration tests for GDLK that expect compile errors. The programs in
 //! these tests should all fail during execution.
 
-use gdlk::{Compiler, HardwareSpec, ProgramSpec};
+use gdlk::{
+    ast::{InstructionKind, RegisterRef, StackRef},
+    error::{AllocateError, RuntimeError},
+    Compiler, CostModel, ErrorPolicy, HardwareSpec, ProgramSpec,
+    MAX_CYCLE_COUNT,
+};
 
 /// Compiles the program for the given hardware, executes it under the given
 /// program spec, and expects a runtime error. Panics if the program executes
@@ -9,13 +14,20 @@ use gdlk::{Compiler, HardwareSpec, ProgramSpec};
 macro_rules! assert_runtime_error {
     ($hw_spec:expr,$program_spec:expr, $src:expr, $expected_error:expr $(,)?) => {{
         // Compile from hardware+src
-        let mut machine = Compiler::compile($src.into(), $hw_spec)
+        let mut machine = Compiler::compile($src, $hw_spec)
             .unwrap()
-            .allocate(&($program_spec));
+            .allocate(&($program_spec))
+            .unwrap();
 
         // Execute to completion
-        let actual_error = machine.execute_all().unwrap_err();
-        assert_eq!(actual_error.to_string(), $expected_error);
+        let actual_errors: Vec<String> = machine
+            .execute_all()
+            .unwrap_err()
+            .errors()
+            .iter()
+            .map(|err| err.to_string())
+            .collect();
+        assert_eq!(actual_errors, &[$expected_error]);
         machine
     }};
 }
@@ -40,6 +52,7 @@ fn test_stack_overflow() {
             num_registers: 1,
             num_stacks: 1,
             max_stack_length: 3,
+            ..Default::default()
         },
         ProgramSpec::default(),
         "
@@ -53,6 +66,71 @@ fn test_stack_overflow() {
     );
 }
 
+#[test]
+fn test_stack_overflow_per_stack() {
+    // S1 holds more than S0 can, but only S0 overflows
+    let machine = assert_runtime_error!(
+        HardwareSpec {
+            num_registers: 1,
+            num_stacks: 2,
+            max_stack_length: 8,
+            stack_lengths: vec![2, 8],
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "
+        SET RX0 5
+        BIG:
+        PUSH RX0 S1
+        SUB RX0 1
+        JGZ RX0 BIG
+        SET RX0 3
+        SMALL:
+        PUSH RX0 S0
+        SUB RX0 1
+        JGZ RX0 SMALL
+        ",
+        "Runtime error at 9:18: Overflow on stack `S0`",
+    );
+    let stacks = machine.stacks();
+    assert_eq!(stacks[&StackRef(0)], &[3, 2]);
+    assert_eq!(stacks[&StackRef(1)], &[5, 4, 3, 2, 1]);
+}
+
+#[test]
+fn test_undefined_constant() {
+    // Undefined constants are an error as soon as the machine is allocated,
+    // even if they're never read. Defined constants aren't listed.
+    let program_spec =
+        ProgramSpec::new(vec![], vec![]).with_constants(vec![("K", 2)]);
+    let compiler = Compiler::compile(
+        "
+        SET RX0 $K
+        JGZ RX0 END
+        WRITE $n
+        WRITE $N
+        END:
+        WRITE $n
+        ",
+        HardwareSpec::default(),
+    )
+    .unwrap();
+    let error = compiler.allocate_all(&program_spec).unwrap_err();
+    assert_eq!(
+        error,
+        AllocateError::UndefinedConstants {
+            names: vec!["N".into(), "n".into()]
+        }
+    );
+    assert_eq!(
+        error.to_string(),
+        "Program uses constants that aren't defined by the program spec: \
+        `$N`, `$n`"
+    );
+    assert_eq!(compiler.test_all(&program_spec).unwrap_err(), error);
+    assert_eq!(compiler.allocate(&program_spec).unwrap_err(), error);
+}
+
 #[test]
 fn test_empty_input() {
     assert_runtime_error!(
@@ -70,6 +148,7 @@ fn test_empty_stack() {
             num_registers: 1,
             num_stacks: 1,
             max_stack_length: 3,
+            ..Default::default()
         },
         ProgramSpec::default(),
         "POP S0 RX0",
@@ -77,6 +156,172 @@ fn test_empty_stack() {
     );
 }
 
+#[test]
+fn test_peek_empty_stack() {
+    assert_runtime_error!(
+        HardwareSpec {
+            num_registers: 1,
+            num_stacks: 1,
+            max_stack_length: 3,
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "PEEK S0 RX0",
+        "Runtime error at 1:6: Cannot pop from empty stack `S0`",
+    );
+}
+
+#[test]
+fn test_dup_empty_stack() {
+    assert_runtime_error!(
+        HardwareSpec {
+            num_registers: 1,
+            num_stacks: 1,
+            max_stack_length: 3,
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "DUP S0",
+        "Runtime error at 1:5: Cannot pop from empty stack `S0`",
+    );
+}
+
+#[test]
+fn test_dup_stack_overflow() {
+    // Duplicating onto a full stack overflows, even though it only reads the
+    // top value
+    let machine = assert_runtime_error!(
+        HardwareSpec {
+            num_registers: 1,
+            num_stacks: 1,
+            max_stack_length: 2,
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "
+        PUSH 1 S0
+        DUP S0
+        DUP S0
+        ",
+        "Runtime error at 4:13: Overflow on stack `S0`",
+    );
+    assert_eq!(machine.stacks()[&StackRef(0)], &[1, 1]);
+}
+
+#[test]
+fn test_sswp_too_few() {
+    let machine = assert_runtime_error!(
+        HardwareSpec {
+            num_registers: 1,
+            num_stacks: 1,
+            max_stack_length: 3,
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "
+        PUSH 1 S0
+        SSWP S0
+        ",
+        "Runtime error at 3:14: Cannot pop from empty stack `S0`",
+    );
+    // Nothing was swapped or popped
+    assert_eq!(machine.stacks()[&StackRef(0)], &[1]);
+}
+
+#[test]
+fn test_popn_too_many() {
+    let machine = assert_runtime_error!(
+        HardwareSpec {
+            num_registers: 1,
+            num_stacks: 1,
+            max_stack_length: 3,
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "
+        PUSH 1 S0
+        PUSH 2 S0
+        SET RX0 3
+        POPN S0 RX0
+        ",
+        "Runtime error at 5:14: Cannot pop from empty stack `S0`",
+    );
+    // Nothing was popped
+    assert_eq!(machine.stacks()[&StackRef(0)], &[1, 2]);
+
+    // Popping from an empty stack fails too, unless the count is zero
+    assert_runtime_error!(
+        HardwareSpec {
+            num_registers: 1,
+            num_stacks: 1,
+            max_stack_length: 3,
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "
+        POPN S0 0
+        POPN S0 1
+        ",
+        "Runtime error at 3:14: Cannot pop from empty stack `S0`",
+    );
+}
+
+#[test]
+fn test_call_stack_overflow() {
+    // Unbounded recursion runs out of call stack
+    let machine = assert_runtime_error!(
+        HardwareSpec {
+            max_call_depth: 4,
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "
+        RECURSE:
+        ADD RX0 1
+        CALL RECURSE
+        ",
+        "Runtime error at 4:9: Call stack overflow in `CALL RECURSE`",
+    );
+    // The overflowing call doesn't count
+    assert_eq!(machine.registers()[&RegisterRef::User(0)], 5);
+}
+
+#[test]
+fn test_return_without_call() {
+    assert_runtime_error!(
+        HardwareSpec::default(),
+        ProgramSpec::default(),
+        "
+        CALL FUNC
+        FUNC:
+        RET
+        ",
+        "Runtime error at 4:9: Cannot return without a matching `CALL`",
+    );
+}
+
+#[test]
+fn test_invalid_jump_target() {
+    assert_runtime_error!(
+        HardwareSpec::default(),
+        ProgramSpec::default(),
+        "
+        SET RX0 3
+        JRI RX0
+        ",
+        "Runtime error at 3:9: Jump target 3 is out of range in `JRI RX0`",
+    );
+    assert_runtime_error!(
+        HardwareSpec::default(),
+        ProgramSpec::default(),
+        "
+        SET RX0 -1
+        JRI RX0
+        ",
+        "Runtime error at 3:9: Jump target -1 is out of range in `JRI RX0`",
+    );
+}
+
 #[test]
 fn test_exceed_max_cycle_count() {
     assert_runtime_error!(
@@ -91,6 +336,43 @@ fn test_exceed_max_cycle_count() {
     );
 }
 
+#[test]
+fn test_exceed_max_cycle_count_weighted() {
+    // The instruction that would go over the limit shouldn't execute
+    let machine = assert_runtime_error!(
+        HardwareSpec {
+            cost_model: CostModel::default().with_cost(InstructionKind::Jmp, 3),
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "
+        START:
+        JMP START
+        ",
+        "Runtime error at 3:9: Maximum number of cycles reached, \
+            cannot execute instruction `JMP START`",
+    );
+    assert_eq!(machine.cycle_count(), MAX_CYCLE_COUNT / 3 * 3);
+}
+
+#[test]
+fn test_exceed_max_cycle_count_huge_cost() {
+    // The spec isn't validated, so a cost can be big enough to overflow the
+    // cycle count
+    let machine = assert_runtime_error!(
+        HardwareSpec {
+            cost_model: CostModel::default()
+                .with_cost(InstructionKind::Write, usize::MAX),
+            ..Default::default()
+        },
+        ProgramSpec::default(),
+        "NOP\nWRITE 1",
+        "Runtime error at 2:1: Maximum number of cycles reached, \
+            cannot execute instruction `WRITE 1`",
+    );
+    assert_eq!(machine.cycle_count(), 1);
+}
+
 #[test]
 fn test_execute_after_error() {
     // Excuting after an error returns false
@@ -122,3 +404,58 @@ fn test_no_success_on_error() {
 
     assert!(!machine.successful());
 }
+
+#[test]
+fn test_lenient_error_policy() {
+    let hardware_spec = HardwareSpec {
+        num_registers: 2,
+        num_stacks: 1,
+        max_stack_length: 1,
+        ..Default::default()
+    };
+    let program_spec = ProgramSpec::new(vec![], vec![0, 0, 5]);
+    let src = "
+        READ RX0
+        WRITE RX0
+        POP S0 RX1
+        WRITE RX1
+        PUSH 5 S0
+        PUSH 6 S0
+        POP S0 RX0
+        SET RX1 3
+        DIV RX1 0
+        ADD RX0 RX1
+        WRITE RX0
+        ";
+
+    // Under the strict policy, the first error is fatal
+    let machine = assert_runtime_error!(
+        hardware_spec.clone(),
+        program_spec,
+        src,
+        "Runtime error at 2:9: Read attempted on empty input",
+    );
+    assert_eq!(machine.warnings(), &[]);
+
+    // Under the lenient policy, every error is recovered from
+    let mut machine = Compiler::compile(src, hardware_spec)
+        .unwrap()
+        .allocate_with_policy(&program_spec, ErrorPolicy::Lenient)
+        .unwrap();
+    assert!(machine.execute_all().unwrap());
+    assert!(machine.error().is_none());
+    let warnings: Vec<_> = machine
+        .warnings()
+        .iter()
+        .map(|warning| (warning.error.clone(), warning.span.start_line))
+        .collect();
+    assert_eq!(
+        warnings,
+        vec![
+            (RuntimeError::EmptyInput, 2),
+            (RuntimeError::EmptyStack, 4),
+            (RuntimeError::StackOverflow, 7),
+            (RuntimeError::DivideByZero, 10),
+        ]
+    );
+}
@@ -3,8 +3,9 @@
 //! outcome.
 
 use gdlk::{
-    ast::{LangValue, RegisterRef, StackRef},
-    Compiler, HardwareSpec, ProgramSpec, ProgramStats,
+    ast::{InstructionKind, LangValue, RegisterRef, StackRef},
+    Compiler, CostModel, HardwareSpec, ProgramMetadata, ProgramSpec,
+    ProgramStats, TestCase,
 };
 
 /// Compiles the program for the given hardware, and executes it against the
@@ -14,9 +15,10 @@ macro_rules! assert_success {
     ($hardware_spec:expr, $program_spec:expr, $src:expr $(,)?) => {{
         let program_spec_val = &$program_spec;
         // Compile from hardware+src
-        let mut machine = Compiler::compile($src.into(), $hardware_spec)
+        let mut machine = Compiler::compile($src, $hardware_spec)
             .unwrap()
-            .allocate(program_spec_val);
+            .allocate(program_spec_val)
+            .unwrap();
 
         // Execute to completion
         let success = machine.execute_all().unwrap();
@@ -53,6 +55,7 @@ fn test_read_write() {
             num_registers: 1,
             num_stacks: 0,
             max_stack_length: 0,
+            ..Default::default()
         },
         ProgramSpec::new(vec![1, 2], vec![1, 2]),
         "
@@ -64,6 +67,33 @@ fn test_read_write() {
     );
 }
 
+#[test]
+fn test_read_zero() {
+    // RIH shows the next input without consuming it, and RDZ reads zeroes
+    // once the input is drained
+    assert_success!(
+        HardwareSpec {
+            num_registers: 2,
+            num_stacks: 0,
+            max_stack_length: 0,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![3, 4], vec![3, 3, 4, 7, 0, 0]),
+        "
+        WRITE RIH
+        RDZ RX0
+        WRITE RX0
+        WRITE RIH
+        RDZ RX1
+        ADD RX0 RX1
+        WRITE RX0
+        WRITE RIH
+        RDZ RX0
+        WRITE RX0
+        ",
+    );
+}
+
 #[test]
 fn test_set_push_pop() {
     assert_success!(
@@ -71,6 +101,7 @@ fn test_set_push_pop() {
             num_registers: 2,
             num_stacks: 1,
             max_stack_length: 5,
+            ..Default::default()
         },
         ProgramSpec::new(vec![], vec![10, 5]),
         "
@@ -86,6 +117,102 @@ fn test_set_push_pop() {
     );
 }
 
+#[test]
+fn test_swp() {
+    assert_success!(
+        HardwareSpec {
+            num_registers: 2,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![1, 2], vec![2, 1]),
+        "
+        READ RX0
+        READ RX1
+        SWP RX0 RX1
+        WRITE RX0
+        WRITE RX1
+        ",
+    );
+
+    // Works on a single register, with itself or with RZR
+    assert_success!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![3], vec![3, 0]),
+        "
+        READ RX0
+        SWP RX0 RX0
+        WRITE RX0
+        SWP RX0 RZR
+        WRITE RX0
+        ",
+    );
+}
+
+#[test]
+fn test_peek_popn() {
+    assert_success!(
+        HardwareSpec {
+            num_registers: 2,
+            num_stacks: 1,
+            max_stack_length: 5,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![1, 2, 3, 4], vec![4, 4, 1, 0]),
+        "
+        PUSH_LOOP:
+        READ RX0
+        PUSH RX0 S0
+        JGZ RLI PUSH_LOOP
+        ; PEEK leaves the value on the stack
+        PEEK S0 RX0
+        WRITE RX0
+        PEEK S0 RX1
+        WRITE RX1
+        ; Pop all but the bottom value
+        SET RX0 3
+        POPN S0 RX0
+        PEEK S0 RX0
+        WRITE RX0
+        ; Popping zero or fewer does nothing, and popping exactly as many
+        ; values as there are empties the stack
+        POPN S0 0
+        POPN S0 -1
+        POPN S0 RS0
+        WRITE RS0
+        ",
+    );
+}
+
+#[test]
+fn test_dup_sswp() {
+    assert_success!(
+        HardwareSpec {
+            num_registers: 1,
+            num_stacks: 1,
+            max_stack_length: 3,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![1, 2], vec![3, 1, 1, 2]),
+        "
+        READ RX0
+        PUSH RX0 S0
+        READ RX0
+        PUSH RX0 S0
+        ; Swapping exactly two values works
+        SSWP S0
+        ; Duplicating fills the stack up to capacity
+        DUP S0
+        WRITE RS0
+        POP S0 RX0
+        WRITE RX0
+        POP S0 RX0
+        WRITE RX0
+        POP S0 RX0
+        WRITE RX0
+        ",
+    );
+}
+
 #[test]
 fn test_add_sub_mul() {
     assert_success!(
@@ -93,6 +220,7 @@ fn test_add_sub_mul() {
             num_registers: 2,
             num_stacks: 0,
             max_stack_length: 0,
+            ..Default::default()
         },
         ProgramSpec::new(vec![], vec![-3, 140]),
         "
@@ -146,6 +274,112 @@ fn test_div() {
     );
 }
 
+#[test]
+fn test_bitwise() {
+    assert_success!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![], vec![8, 14, 6, 1, 255, 7, -13]),
+        "
+        SET RX0 12 | AND RX0 10
+        WRITE RX0 ; 0b1000
+        SET RX0 12 | OR RX0 10
+        WRITE RX0 ; 0b1110
+        SET RX0 12 | XOR RX0 10
+        WRITE RX0 ; 0b0110
+
+        ; Negative values are two's complement
+        SET RX0 -3 | AND RX0 1
+        WRITE RX0 ; odd
+        SET RX0 -1 | AND RX0 0xFF
+        WRITE RX0 ; 255
+        SET RX0 -8 | XOR RX0 -1
+        WRITE RX0 ; 7
+        SET RX0 -16 | OR RX0 3
+        WRITE RX0 ; -13
+        ",
+    );
+}
+
+#[test]
+fn test_shifts() {
+    let bits = LangValue::BITS;
+    assert_success!(
+        HardwareSpec::default(),
+        ProgramSpec::new(
+            vec![],
+            vec![12, -3, -1, LangValue::MIN, -2, 5, 10, LangValue::MIN, 32]
+        ),
+        &format!(
+            "
+            SET RX0 3 | SHL RX0 2
+            WRITE RX0 ; 12
+            SET RX0 -12 | SHR RX0 2
+            WRITE RX0 ; -3, the sign is kept
+            SET RX0 -1 | SHR RX0 {max_shift}
+            WRITE RX0 ; -1
+            SET RX0 1 | SHL RX0 {max_shift}
+            WRITE RX0 ; MIN
+            SET RX0 {max} | SHL RX0 1
+            WRITE RX0 ; -2, bits shifted out are lost
+
+            ; The shift amount wraps around at the width of a value
+            SET RX0 5 | SHL RX0 {bits}
+            WRITE RX0 ; 5
+            SET RX0 5 | SHL RX0 {bits_plus_one}
+            WRITE RX0 ; 10
+            ; Negative amounts only use their low bits
+            SET RX0 1 | SHL RX0 -1
+            WRITE RX0 ; MIN
+            SET RX0 64 | SHR RX0 -{max_shift}
+            WRITE RX0 ; 32
+            ",
+            max = LangValue::MAX,
+            max_shift = bits - 1,
+            bits = bits,
+            bits_plus_one = bits + 1,
+        ),
+    );
+}
+
+#[test]
+fn test_metadata() {
+    let source = "
+    ;! name: echo-fast
+    ;! puzzle: hw1/prog2
+    ; Regular comments can go in the header too
+    ;!author:lucas
+    ;! notes: reads: then writes
+
+    READ RX0
+    WRITE RX0
+    ";
+    let machine = assert_success!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![3], vec![3]),
+        source,
+    );
+    // Metadata doesn't cost anything
+    assert_eq!(machine.cycle_count(), 2);
+
+    let compiler = Compiler::compile(source, HardwareSpec::default()).unwrap();
+    let metadata = compiler.metadata();
+    assert_eq!(metadata.name.as_deref(), Some("echo-fast"));
+    assert_eq!(metadata.puzzle.as_deref(), Some("hw1/prog2"));
+    assert_eq!(metadata.author.as_deref(), Some("lucas"));
+    // Only the first colon splits the key from the value
+    assert_eq!(metadata.get("notes"), Some("reads: then writes"));
+    assert_eq!(metadata.get("author"), Some("lucas"));
+    assert_eq!(metadata.get("version"), None);
+    assert!(compiler.warnings().errors().is_empty());
+    assert_eq!(ProgramMetadata::from_source(source), *metadata);
+
+    // No header, no metadata
+    let compiler =
+        Compiler::compile("; hello\nREAD RX0", HardwareSpec::default())
+            .unwrap();
+    assert_eq!(compiler.metadata(), &ProgramMetadata::default());
+}
+
 #[test]
 fn test_cmp() {
     assert_success!(
@@ -153,6 +387,7 @@ fn test_cmp() {
             num_registers: 2,
             num_stacks: 0,
             max_stack_length: 0,
+            ..Default::default()
         },
         ProgramSpec::new(vec![], vec![-1, 0, 1, 1]),
         "
@@ -171,6 +406,34 @@ fn test_cmp() {
     );
 }
 
+#[test]
+fn test_constants() {
+    // Multiply every input by K, where K changes between test cases
+    let src = "
+    LOOP:
+        JEZ RLI END
+        READ RX0
+        MUL RX0 $K
+        ADD RX0 $ZERO
+        WRITE RX0
+        JMP LOOP
+    END:
+    ";
+    let program_spec = ProgramSpec::with_test_cases(vec![
+        TestCase::new(vec![1, 2], vec![3, 6]),
+        // The test case's K shadows the spec's, but ZERO still comes through
+        TestCase::new(vec![1, 2], vec![-1, -2]).with_constants(vec![("K", -1)]),
+    ])
+    .with_constants(vec![("K", 3), ("ZERO", 0)]);
+    assert_success!(HardwareSpec::default(), program_spec, src);
+
+    let results = Compiler::compile(src, HardwareSpec::default())
+        .unwrap()
+        .test_all(&program_spec)
+        .unwrap();
+    assert!(results.successful(), "{:?}", results);
+}
+
 #[test]
 fn test_jumps() {
     let program_spec = ProgramSpec::new(vec![], vec![1]);
@@ -235,6 +498,88 @@ fn test_jumps() {
     );
 }
 
+#[test]
+fn test_computed_jump() {
+    // 3-way jump table. Labels don't count towards instruction indexes.
+    assert_success!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![2, 0, 1], vec![30, 10, 20]),
+        "
+        START:
+        READ RX0    ; 0
+        MUL RX0 2   ; 1
+        ADD RX0 4   ; 2
+        JRI RX0     ; 3
+        WRITE 10    ; 4
+        JMP NEXT    ; 5
+        WRITE 20    ; 6
+        JMP NEXT    ; 7
+        WRITE 30    ; 8
+        NEXT:
+        JGZ RLI START
+        ",
+    );
+
+    // Jumping just past the last instruction terminates
+    assert_success!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![], vec![]),
+        "
+        SET RX0 3
+        JRI RX0
+        WRITE 1
+        ",
+    );
+}
+
+#[test]
+fn test_call_ret() {
+    // Nested calls return to the right places
+    assert_success!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![1, 2, 3], vec![3, 5, 7]),
+        "
+        JMP MAIN
+        DOUBLE:
+        ADD RX0 RX0
+        RET
+        DOUBLE_PLUS_ONE:
+        CALL DOUBLE
+        ADD RX0 1
+        RET
+        MAIN:
+        READ RX0
+        CALL DOUBLE_PLUS_ONE
+        WRITE RX0
+        JGZ RLI MAIN
+        ",
+    );
+
+    // Recursion that exactly fits in the call stack
+    assert_success!(
+        HardwareSpec {
+            num_registers: 2,
+            max_call_depth: 5,
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![4], vec![10]),
+        "
+        READ RX0
+        CALL SUM
+        WRITE RX1
+        JMP END
+        SUM:
+        JEZ RX0 BASE
+        ADD RX1 RX0
+        SUB RX0 1
+        CALL SUM
+        BASE:
+        RET
+        END:
+        ",
+    );
+}
+
 #[test]
 fn test_square_all() {
     assert_success!(
@@ -242,6 +587,7 @@ fn test_square_all() {
             num_registers: 1,
             num_stacks: 0,
             max_stack_length: 0,
+            ..Default::default()
         },
         ProgramSpec::new(
             vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
@@ -266,6 +612,7 @@ fn test_fibonacci() {
             num_registers: 4,
             num_stacks: 0,
             max_stack_length: 0,
+            ..Default::default()
         },
         ProgramSpec::new(vec![10], vec![0, 1, 1, 2, 3, 5, 8, 13, 21, 34]),
         "
@@ -292,6 +639,7 @@ fn test_insertion_sort() {
             num_registers: 3,
             num_stacks: 2,
             max_stack_length: 16,
+            ..Default::default()
         },
         ProgramSpec::new(
             vec![9, 3, 8, 4, 5, 1, 3, 8, 9, 5, 2, 10, 4, 1, 8],
@@ -383,6 +731,31 @@ fn test_cycle_count_jump() {
     assert_eq!(m1.cycle_count(), 13);
 }
 
+#[test]
+fn test_cycle_count_cost_model() {
+    let machine = assert_success!(
+        HardwareSpec {
+            num_registers: 1,
+            num_stacks: 1,
+            max_stack_length: 1,
+            cost_model: CostModel::default()
+                .with_cost(InstructionKind::Push, 2)
+                .with_cost(InstructionKind::Pop, 2)
+                .with_cost(InstructionKind::Div, 3),
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![4], vec![2]),
+        "
+        READ RX0        ; 1
+        PUSH RX0 S0     ; 3
+        POP S0 RX0      ; 5
+        DIV RX0 2       ; 8
+        WRITE RX0       ; 9
+        ",
+    );
+    assert_eq!(machine.cycle_count(), 9);
+}
+
 #[test]
 fn test_execute_after_termination() {
     // Excuting after a normal termination returns false
@@ -402,6 +775,7 @@ fn test_stats() {
         num_registers: 3,
         num_stacks: 3,
         max_stack_length: 10,
+        ..Default::default()
     };
     let src = "
     READ RX0
@@ -412,8 +786,17 @@ fn test_stats() {
     PUSH RS1 S0
     PUSH RX1 S2
     ";
-    let compiler = Compiler::compile(src.into(), hw_spec).unwrap();
+    let compiler = Compiler::compile(src, hw_spec).unwrap();
     let expected_stats = ProgramStats {
+        num_instructions: 7,
+        instruction_counts: vec![
+            (InstructionKind::Read, 1),
+            (InstructionKind::Write, 1),
+            (InstructionKind::Set, 1),
+            (InstructionKind::Push, 4),
+        ]
+        .into_iter()
+        .collect(),
         referenced_registers: vec![
             RegisterRef::User(0),
             RegisterRef::Null,
@@ -424,6 +807,282 @@ fn test_stats() {
         .into_iter()
         .collect(),
         referenced_stacks: vec![StackRef(0), StackRef(2)].into_iter().collect(),
+        max_jump_depth: 0,
     };
     assert_eq!(&compiler.program().stats, &expected_stats);
+    assert_eq!(compiler.stats(), &expected_stats);
+}
+
+#[test]
+fn test_stats_jumps() {
+    let hw_spec = HardwareSpec {
+        num_registers: 2,
+        ..Default::default()
+    };
+    let stats = |src: &str| {
+        let compiler = Compiler::compile(src, hw_spec.clone()).unwrap();
+        let stats = compiler.stats();
+        (
+            stats.num_instructions,
+            stats.instruction_counts.get(&InstructionKind::Jmp).copied(),
+            compiler.program().num_user_registers_referenced(),
+            stats.max_jump_depth,
+        )
+    };
+
+    // Labels aren't instructions
+    assert_eq!(stats("START:\nNOP\nEND:"), (1, None, 0, 0));
+    // A jump to itself still covers one instruction
+    assert_eq!(stats("LOOP:\nJMP LOOP"), (1, Some(1), 0, 1));
+    // Two jumps over the same instructions
+    assert_eq!(
+        stats(
+            "
+            LOOP:
+            JEZ RLI END
+            READ RX0
+            JMP LOOP
+            END:
+            "
+        ),
+        (3, Some(1), 1, 2)
+    );
+    // Jumps that follow one another don't nest
+    assert_eq!(
+        stats(
+            "
+            JMP A
+            A:
+            JMP B
+            B:
+            JMP C
+            C:
+            "
+        ),
+        (3, Some(3), 0, 1)
+    );
+    // A loop within a loop
+    assert_eq!(
+        stats(
+            "
+            OUTER:
+            READ RX0
+            INNER:
+            SUB RX0 1
+            JGZ RX0 INNER
+            WRITE RX1
+            JNZ RLI OUTER
+            "
+        ),
+        (5, None, 2, 2)
+    );
+}
+
+#[test]
+fn test_labels() {
+    let compiler = Compiler::compile(
+        "
+        START:
+        LOOP:
+        JEZ RLI END
+        READ RX0
+        BODY:
+        WRITE RX0
+        JMP LOOP
+        END:
+        ",
+        HardwareSpec::default(),
+    )
+    .unwrap();
+    let program = compiler.program();
+
+    // Declaration order, which is also index order. END points one past the
+    // last instruction.
+    assert_eq!(
+        program
+            .labels()
+            .map(|(label, index)| (label.as_str(), index))
+            .collect::<Vec<_>>(),
+        vec![("START", 0), ("LOOP", 0), ("BODY", 2), ("END", 4)]
+    );
+    // When multiple labels share an instruction, the first one wins
+    let labels_at: Vec<_> =
+        (0..=5).map(|index| program.label_at(index)).collect();
+    assert_eq!(
+        labels_at,
+        vec![
+            Some(&"START".to_owned()),
+            None,
+            Some(&"BODY".to_owned()),
+            None,
+            Some(&"END".to_owned()),
+            None,
+        ]
+    );
+}
+
+#[test]
+fn test_source_lines() {
+    let compiler = Compiler::compile(
+        "READ RX0
+LOOP:
+  WRITE RX0 ; comment
+
+JEZ RX0 LOOP
+END:",
+        HardwareSpec::default(),
+    )
+    .unwrap();
+    let program = compiler.program();
+
+    let span = program.span_at(1).unwrap();
+    assert_eq!((span.start_line, span.start_col), (3, 3));
+    assert_eq!((span.end_line, span.end_col), (3, 12));
+    // One past the end has no instruction, even though END points there
+    assert_eq!(program.span_at(3), None);
+
+    // Labels, comments, and blank lines have no instructions
+    let instructions_on_lines: Vec<Vec<usize>> = (1..=7)
+        .map(|line| program.instructions_on_line(line))
+        .collect();
+    assert_eq!(
+        instructions_on_lines,
+        vec![vec![0], vec![], vec![1], vec![], vec![2], vec![], vec![]]
+    );
+}
+
+#[test]
+fn test_instructions_pretty() {
+    let hw_spec = HardwareSpec {
+        num_registers: 2,
+        num_stacks: 1,
+        max_stack_length: 5,
+        dump_enabled: true,
+        ..Default::default()
+    };
+    // Weird casing and spacing, to make sure we output the canonical form
+    let src = "
+    read rx0
+    WRITE   -3
+    SET RX0 RLI
+    ADD RX0 RX1 ; comment
+    SUB RX0 1
+    MUL RX0 RS0
+    DIV RX0 -1
+    CMP RX0 RZR 4
+    PUSH 3 s0
+    POP S0 RX1
+    JMP END
+    JEZ RX0 END
+    JNZ 1 END
+    JLZ RX1 END
+    JGZ RS0 END
+    nop
+    dump
+    END:
+    ";
+    let compiler = Compiler::compile(src, hw_spec.clone()).unwrap();
+    let instructions = compiler.instructions_pretty();
+    let texts: Vec<&str> =
+        instructions.iter().map(|(text, _)| text.as_str()).collect();
+    assert_eq!(
+        texts.as_slice(),
+        &[
+            "READ RX0",
+            "WRITE -3",
+            "SET RX0 RLI",
+            "ADD RX0 RX1",
+            "SUB RX0 1",
+            "MUL RX0 RS0",
+            "DIV RX0 -1",
+            "CMP RX0 RZR 4",
+            "PUSH 3 S0",
+            "POP S0 RX1",
+            "JMP END",
+            "JEZ RX0 END",
+            "JNZ 1 END",
+            "JLZ RX1 END",
+            "JGZ RS0 END",
+            "NOP",
+            "DUMP",
+        ]
+    );
+    // Each span should point back to the original instruction
+    assert_eq!(instructions[3].1.start_line, 5);
+
+    // Compiling the output should give back the same program
+    let round_trip_src = format!("{}\nEND:", texts.join("\n"));
+    let round_trip = Compiler::compile(round_trip_src, hw_spec).unwrap();
+    let round_trip_texts: Vec<String> = round_trip
+        .instructions_pretty()
+        .into_iter()
+        .map(|(text, _)| text)
+        .collect();
+    assert_eq!(round_trip_texts, texts);
+    assert_eq!(round_trip.program().stats, compiler.program().stats);
+}
+
+#[test]
+fn test_nop() {
+    let machine = assert_success!(
+        HardwareSpec::default(),
+        ProgramSpec::new(vec![1], vec![1]),
+        "
+        NOP
+        READ RX0
+        JMP OUTPUT
+        NOP ; skipped
+        OUTPUT:
+        NOP
+        WRITE RX0
+        ",
+    );
+    assert_eq!(machine.cycle_count(), 5);
+}
+
+#[test]
+fn test_only_nops_and_labels() {
+    let machine = assert_success!(
+        HardwareSpec::default(),
+        ProgramSpec::default(),
+        "
+        START:
+        NOP
+        MIDDLE:
+        NOP
+        LAST:
+        NOP
+        END:
+        ",
+    );
+    assert_eq!(machine.cycle_count(), 3);
+}
+
+#[test]
+fn test_aliases() {
+    let machine = assert_success!(
+        HardwareSpec {
+            num_registers: 2,
+            num_stacks: 1,
+            max_stack_length: 5,
+            register_names: vec!["fuel".into(), "Total".into()],
+            stack_names: vec!["CARGO".into()],
+            ..Default::default()
+        },
+        ProgramSpec::new(vec![3, 4], vec![7]),
+        "
+        READ FUEL       ; aliases are case-insensitive
+        PUSH fuel cargo
+        READ RX0        ; canonical names still work
+        POP S0 TOTAL
+        ADD total RX0
+        WRITE RX1
+        ",
+    );
+    assert_eq!(
+        machine.registers()[&RegisterRef::User(1)],
+        7,
+        "alias should refer to the same register as RX1"
+    );
+    assert_eq!(machine.stacks()[&StackRef(0)], &[] as &[LangValue]);
 }
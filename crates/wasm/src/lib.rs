@@ -1,21 +1,145 @@
 #![deny(clippy::all)]
 // https://prestonrichey.com/blog/react-rust-wasm/
-use gdlk::ast::wasm::SourceElementArray;
 pub use gdlk::{
     ast::{compiled::Program, wasm::SourceElement, LangValue},
-    Compiler, HardwareSpec, Machine, ProgramSpec, Span,
+    CompileOptions, Compiler, HardwareSpec, Machine, ProgramSpec, Span,
 };
+use gdlk::{
+    ast::{
+        wasm::{InstructionInfoArray, ProgramStatsObject, SourceElementArray},
+        Node,
+    },
+    error::{AllocateError, ProgramSpecError},
+    INSTRUCTION_CATALOG,
+};
+use serde_derive::{Deserialize, Serialize};
 use wasm_bindgen::{prelude::*, JsCast};
 
+/// The most compile errors that get sent back to JS. A program written for
+/// the wrong hardware can have an error on every line, and there's no use
+/// serializing hundreds of them just to show them in the editor.
+const MAX_ERRORS: usize = 100;
+
+// TS definitions for the errors that get thrown to JS
+#[wasm_bindgen(typescript_custom_section)]
+const TS_COMPILE_FAILURE: &'static str = r#"
+export interface FieldError {
+  field: string;
+  message: string;
+}
+
+export type CompileFailure =
+  | { kind: "InvalidSpec"; spec: "hardware" | "program"; errors: FieldError[] }
+  | { kind: "InvalidProgram"; errors: SourceElement[] };
+"#;
+
+/// A problem with one field of a spec, e.g. a register count that's out of
+/// range
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldError {
+    /// JSON pointer to the offending field, e.g. `/num_registers`
+    pub field: String,
+    pub message: String,
+}
+
+/// The spec that a [CompileFailure::InvalidSpec] is about
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpecKind {
+    Hardware,
+    Program,
+}
+
+/// The reason that [compile] or [compile_and_allocate] failed. This gets
+/// thrown to JS as a plain object, tagged with a `kind` field.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum CompileFailure {
+    /// One of the specs that was passed in is invalid. The program isn't
+    /// compiled at all in this case, since errors against a broken spec
+    /// wouldn't mean anything.
+    InvalidSpec {
+        spec: SpecKind,
+        errors: Vec<FieldError>,
+    },
+    /// The specs are fine, but the program failed to compile. There are at
+    /// most [MAX_ERRORS] errors.
+    InvalidProgram { errors: Vec<SourceElement> },
+}
+
+impl CompileFailure {
+    /// Check a hardware spec, and get every problem with it, if any
+    fn check_hardware_spec(hardware_spec: &HardwareSpec) -> Result<(), Self> {
+        let errors: Vec<FieldError> = hardware_spec
+            .violations()
+            .into_iter()
+            .map(|violation| FieldError {
+                field: violation.field,
+                message: violation.error.to_string(),
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Self::InvalidSpec {
+                spec: SpecKind::Hardware,
+                errors,
+            })
+        }
+    }
+
+    /// Check a program spec. Program specs stop at the first problem, so
+    /// there's at most one error.
+    fn check_program_spec(program_spec: &ProgramSpec) -> Result<(), Self> {
+        program_spec.validate().map_err(|error| {
+            let field = match &error {
+                ProgramSpecError::NoTestCases => "/test_cases".into(),
+                ProgramSpecError::TooManyValues {
+                    test_case, field, ..
+                } => format!("/test_cases/{}/{}", test_case, field),
+            };
+            Self::InvalidSpec {
+                spec: SpecKind::Program,
+                errors: vec![FieldError {
+                    field,
+                    message: error.to_string(),
+                }],
+            }
+        })
+    }
+
+    /// Convert a failed allocation into a program spec error. The program
+    /// was just compiled for the hardware, so the only thing that can be
+    /// wrong is a named constant that the spec doesn't define.
+    fn from_allocate_error(error: AllocateError) -> Self {
+        Self::InvalidSpec {
+            spec: SpecKind::Program,
+            errors: vec![FieldError {
+                field: "/constants".into(),
+                message: error.to_string(),
+            }],
+        }
+    }
+}
+
+impl From<CompileFailure> for JsValue {
+    fn from(failure: CompileFailure) -> Self {
+        JsValue::from_serde(&failure).unwrap()
+    }
+}
+
+/// A successfully compiled program. This is enough to show instructions and
+/// diagnostics in the editor. To actually run the program, allocate a machine
+/// with [Self::allocate].
 #[wasm_bindgen]
 #[derive(Debug)]
-pub struct CompileSuccess {
-    program: Program<Span>,
-    machine: Machine,
+pub struct CompileResult {
+    compiler: Compiler<Program<Span>>,
+    warnings: Vec<SourceElement>,
 }
 
 #[wasm_bindgen]
-impl CompileSuccess {
+impl CompileResult {
     /// Get the compiled instructions that make up the program. The instructions
     /// can be mapped back to their source.
     ///
@@ -24,46 +148,145 @@ impl CompileSuccess {
     #[wasm_bindgen(getter)]
     pub fn instructions(&self) -> SourceElementArray {
         // Convert the AST into a JS array of instruction descriptors
-        let instructions: Vec<SourceElement> = self
-            .program
+        let program = self.compiler.program();
+        let instructions: Vec<SourceElement> = program
             .instructions
             .iter()
-            .map(|instr| {
-                let span = instr.1;
-                SourceElement {
-                    // TODO use doc string here or something
-                    text: "TODO".into(),
-                    span,
-                }
+            .enumerate()
+            .map(|(index, Node(instr, span))| SourceElement {
+                text: instr.to_string(),
+                span: *span,
+                label: program.label_at(index).cloned(),
+                code: None,
             })
             .collect();
         JsValue::from_serde(&instructions).unwrap().unchecked_into()
     }
 
+    /// Get static statistics about the compiled program, e.g. the number of
+    /// instructions and which registers it references.
+    #[wasm_bindgen(getter)]
+    pub fn stats(&self) -> ProgramStatsObject {
+        JsValue::from_serde(self.compiler.stats())
+            .unwrap()
+            .unchecked_into()
+    }
+
+    /// Get all warnings generated during compilation. These don't prevent
+    /// the program from running, but probably indicate a mistake.
+    #[wasm_bindgen(getter)]
+    pub fn warnings(&self) -> SourceElementArray {
+        JsValue::from_serde(&self.warnings)
+            .unwrap()
+            .unchecked_into()
+    }
+
+    /// Allocate a new machine to run the program against the given spec. This
+    /// can be called any number of times, and each machine is independent.
+    /// If the spec doesn't define every constant that the program uses, a
+    /// [CompileFailure] is thrown.
+    pub fn allocate(
+        &self,
+        program_spec: &ProgramSpec,
+    ) -> Result<Machine, JsValue> {
+        self.compiler
+            .clone()
+            .allocate(program_spec)
+            .map_err(|error| CompileFailure::from_allocate_error(error).into())
+    }
+}
+
+/// A compiled program, along with a machine that's ready to run it. Returned
+/// by [compile_and_allocate].
+#[wasm_bindgen]
+#[derive(Debug)]
+pub struct CompileSuccess {
+    result: CompileResult,
+    machine: Machine,
+}
+
+#[wasm_bindgen]
+impl CompileSuccess {
+    /// See [CompileResult::instructions]
+    #[wasm_bindgen(getter)]
+    pub fn instructions(&self) -> SourceElementArray {
+        self.result.instructions()
+    }
+
+    /// See [CompileResult::stats]
+    #[wasm_bindgen(getter)]
+    pub fn stats(&self) -> ProgramStatsObject {
+        self.result.stats()
+    }
+
     #[wasm_bindgen(getter)]
     pub fn machine(&self) -> Machine {
         self.machine.clone()
     }
+
+    /// See [CompileResult::warnings]
+    #[wasm_bindgen(getter)]
+    pub fn warnings(&self) -> SourceElementArray {
+        self.result.warnings()
+    }
 }
 
-/// Compile a program under the given specifications. This takes in references
-/// so we don't have to move the values out of JS memory.
+/// Compile a program under the given hardware spec. This doesn't allocate a
+/// machine, so it's cheap enough to call on every edit. This takes in
+/// references so we don't have to move the values out of JS memory. On
+/// failure, the error is a [CompileFailure]. The spec is checked before the
+/// program, since specs built in JS skip validation.
 #[wasm_bindgen]
 pub fn compile(
     hardware_spec: &HardwareSpec,
-    program_spec: &ProgramSpec,
     source: &str,
-) -> Result<CompileSuccess, JsValue> {
-    match Compiler::compile(source.to_string(), *hardware_spec) {
+) -> Result<CompileResult, JsValue> {
+    CompileFailure::check_hardware_spec(hardware_spec)?;
+    let options = CompileOptions {
+        max_errors: Some(MAX_ERRORS),
+        ..Default::default()
+    };
+    match Compiler::compile_with_options(source, hardware_spec.clone(), options)
+    {
         Ok(compiler) => {
-            let program = compiler.program().clone();
-            let machine = compiler.allocate(program_spec);
-            Ok(CompileSuccess { program, machine })
+            let warnings = compiler
+                .warnings()
+                .errors()
+                .iter()
+                .map(SourceElement::from)
+                .collect();
+            Ok(CompileResult { compiler, warnings })
         }
-        Err(err) => {
-            let errors: Vec<SourceElement> =
-                err.errors().iter().map(SourceElement::from).collect();
-            Err(JsValue::from_serde(&errors).unwrap())
+        Err(err) => Err(CompileFailure::InvalidProgram {
+            errors: err.errors().iter().map(SourceElement::from).collect(),
         }
+        .into()),
     }
 }
+
+/// Compile a program, then allocate a machine to run it under the given
+/// program spec. This is the same as calling [compile] then
+/// [CompileResult::allocate], and is kept for backwards compatibility. Both
+/// specs are checked before the program, hardware first.
+#[wasm_bindgen(js_name = "compileAndAllocate")]
+pub fn compile_and_allocate(
+    hardware_spec: &HardwareSpec,
+    program_spec: &ProgramSpec,
+    source: &str,
+) -> Result<CompileSuccess, JsValue> {
+    CompileFailure::check_hardware_spec(hardware_spec)?;
+    CompileFailure::check_program_spec(program_spec)?;
+    let result = compile(hardware_spec, source)?;
+    let machine = result.allocate(program_spec)?;
+    Ok(CompileSuccess { result, machine })
+}
+
+/// Get the documentation for every instruction, as an array of plain JS
+/// objects. This matches what the parser accepts, so the reference panel can
+/// be generated from it. See [INSTRUCTION_CATALOG].
+#[wasm_bindgen(js_name = "instructionCatalog")]
+pub fn instruction_catalog() -> InstructionInfoArray {
+    JsValue::from_serde(INSTRUCTION_CATALOG)
+        .unwrap()
+        .unchecked_into()
+}
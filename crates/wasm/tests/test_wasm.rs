@@ -5,9 +5,12 @@
 #![allow(clippy::bool_assert_comparison)]
 
 use gdlk_wasm::{
-    compile, HardwareSpec, LangValue, ProgramSpec, SourceElement, Span,
+    compile, compile_and_allocate, instruction_catalog, CompileFailure,
+    FieldError, HardwareSpec, LangValue, ProgramSpec, SourceElement, Span,
+    SpecKind,
 };
 use maplit::hashmap;
+use serde_derive::Deserialize;
 use std::collections::HashMap;
 use wasm_bindgen_test::wasm_bindgen_test;
 
@@ -54,11 +57,12 @@ macro_rules! assert_machine_state {
 
 #[wasm_bindgen_test]
 fn test_compile_success() {
-    let result = compile(
+    let result = compile_and_allocate(
         &HardwareSpec {
             num_registers: 1,
             num_stacks: 2,
             max_stack_length: 10,
+            ..Default::default()
         },
         &ProgramSpec::new(vec![1], vec![1]),
         "
@@ -75,24 +79,30 @@ fn test_compile_success() {
             SourceElement {
                 text: "TODO".into(),
                 span: Span {
+                    source_id: None,
                     offset: 9,
                     length: 8,
                     start_line: 2,
                     start_col: 9,
                     end_line: 2,
                     end_col: 17
-                }
+                },
+                label: None,
+                code: None,
             },
             SourceElement {
                 text: "TODO".into(),
                 span: Span {
+                    source_id: None,
                     offset: 26,
                     length: 9,
                     start_line: 3,
                     start_col: 9,
                     end_line: 3,
                     end_col: 18
-                }
+                },
+                label: None,
+                code: None,
             }
         ]
     );
@@ -122,58 +132,214 @@ fn test_compile_success() {
     );
 }
 
+#[wasm_bindgen_test]
+fn test_compile_then_allocate() {
+    let hardware_spec = HardwareSpec {
+        num_registers: 1,
+        num_stacks: 1,
+        max_stack_length: 10,
+        ..Default::default()
+    };
+    // No program spec is needed to compile
+    let result = compile(&hardware_spec, "READ RX0\nWRITE RX0").unwrap();
+    assert_eq!(
+        result
+            .instructions()
+            .into_serde::<Vec<SourceElement>>()
+            .unwrap()
+            .len(),
+        2
+    );
+    assert_eq!(
+        result
+            .warnings()
+            .into_serde::<Vec<SourceElement>>()
+            .unwrap(),
+        vec![]
+    );
+
+    // Each allocated machine is independent
+    let mut machine_1 = result
+        .allocate(&ProgramSpec::new(vec![1], vec![1]))
+        .unwrap();
+    let machine_2 = result
+        .allocate(&ProgramSpec::new(vec![2], vec![2]))
+        .unwrap();
+    while !machine_1.terminated() {
+        assert!(machine_1.wasm_execute_next());
+    }
+    assert!(machine_1.successful());
+    assert_machine_state!(
+        machine_2,
+        program_counter = 0,
+        cycle_count = 0,
+        terminated = false,
+        successful = false,
+        input = &[2],
+        output = &[],
+        registers = hashmap! {
+            "RLI".into() => 1,
+            "RS0".into() => 0,
+            "RX0".into() => 0,
+        },
+        stacks = hashmap! {
+            "S0".into() => vec![],
+        },
+        error = None
+    );
+}
+
 #[wasm_bindgen_test]
 fn test_compile_errors() {
-    let result = compile(
-        &HardwareSpec::default(),
-        &ProgramSpec::default(),
-        "
+    let source = "
         READ RX1
         PUSH 3 S0
-        ",
+        ";
+    let errors = compile(&HardwareSpec::default(), source).unwrap_err();
+    // The combined function gives the same errors
+    let combined_errors = compile_and_allocate(
+        &HardwareSpec::default(),
+        &ProgramSpec::default(),
+        source,
+    )
+    .unwrap_err();
+    assert_eq!(
+        combined_errors.into_serde::<CompileFailure>().unwrap(),
+        errors.into_serde::<CompileFailure>().unwrap()
     );
 
-    let errors = result.unwrap_err();
+    let errors = compile(&HardwareSpec::default(), source).unwrap_err();
     assert_eq!(
-        errors.into_serde::<Vec<SourceElement>>().unwrap(),
-        vec![
-            SourceElement {
-                text:
-                    "Validation error at 2:14: Invalid reference to register `RX1`"
+        errors.into_serde::<CompileFailure>().unwrap(),
+        CompileFailure::InvalidProgram {
+            errors: vec![
+                SourceElement {
+                    text: "Validation error at 2:14: Invalid reference to \
+                        register `RX1` (this hardware has register RX0)"
                         .into(),
-                span: Span {
-                    offset: 14,
-                    length: 3,
-                    start_line: 2,
-                    start_col: 14,
-                    end_line: 2,
-                    end_col: 17,
+                    span: Span {
+                        source_id: None,
+                        offset: 14,
+                        length: 3,
+                        start_line: 2,
+                        start_col: 14,
+                        end_line: 2,
+                        end_col: 17,
+                    },
+                    label: None,
+                    code: Some("E010_INVALID_REGISTER".into()),
+                },
+                SourceElement {
+                    text: "Validation error at 3:16: Invalid reference to \
+                        stack `S0` (this hardware has no stacks)"
+                        .into(),
+                    span: Span {
+                        source_id: None,
+                        offset: 33,
+                        length: 2,
+                        start_line: 3,
+                        start_col: 16,
+                        end_line: 3,
+                        end_col: 18,
+                    },
+                    label: None,
+                    code: Some("E011_INVALID_STACK".into()),
                 }
+            ]
+        }
+    );
+}
+
+#[wasm_bindgen_test]
+fn test_invalid_spec() {
+    // Every problem with the hardware is reported, and the program isn't
+    // compiled at all
+    let hardware_spec = HardwareSpec::new(0, 20, 10);
+    let expected = CompileFailure::InvalidSpec {
+        spec: SpecKind::Hardware,
+        errors: vec![
+            FieldError {
+                field: "/num_registers".into(),
+                message: "Expected num_registers to be between 1 and 16, but \
+                    got 0"
+                    .into(),
             },
-            SourceElement {
-                text: "Validation error at 3:16: Invalid reference to stack `S0`"
+            FieldError {
+                field: "/num_stacks".into(),
+                message: "Expected num_stacks to be between 0 and 16, but \
+                    got 20"
                     .into(),
-                span: Span {
-                    offset: 33,
-                    length: 2,
-                    start_line: 3,
-                    start_col: 16,
-                    end_line: 3,
-                    end_col: 18,
-                }
-            }
-        ]
+            },
+        ],
+    };
+    let failure = compile(&hardware_spec, "READ RX9").unwrap_err();
+    assert_eq!(failure.into_serde::<CompileFailure>().unwrap(), expected);
+    let failure = compile_and_allocate(
+        &hardware_spec,
+        &ProgramSpec::default(),
+        "READ RX9",
+    )
+    .unwrap_err();
+    assert_eq!(failure.into_serde::<CompileFailure>().unwrap(), expected);
+
+    // The program spec is checked too, but only when allocating
+    let program_spec = ProgramSpec::new(vec![0; 4097], vec![]);
+    assert!(compile(&HardwareSpec::default(), "READ RX0").is_ok());
+    let failure = compile_and_allocate(
+        &HardwareSpec::default(),
+        &program_spec,
+        "READ RX0",
+    )
+    .unwrap_err();
+    assert_eq!(
+        failure.into_serde::<CompileFailure>().unwrap(),
+        CompileFailure::InvalidSpec {
+            spec: SpecKind::Program,
+            errors: vec![FieldError {
+                field: "/test_cases/0/input".into(),
+                message: "Expected input of test case 1 to have at most 4096 \
+                    values, but got 4097"
+                    .into(),
+            }],
+        }
+    );
+}
+
+#[wasm_bindgen_test]
+fn test_instruction_catalog() {
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct InstructionInfo {
+        kind: String,
+        args: Vec<String>,
+        description: String,
+        errors: Vec<String>,
+    }
+
+    let catalog = instruction_catalog()
+        .into_serde::<Vec<InstructionInfo>>()
+        .unwrap();
+    assert_eq!(catalog.len(), 27);
+    assert_eq!(
+        catalog[0],
+        InstructionInfo {
+            kind: "READ".into(),
+            args: vec!["WritableRegister".into()],
+            description: "Read the next value from the input into a register"
+                .into(),
+            errors: vec!["R002_EMPTY_INPUT".into()],
+        }
     );
 }
 
 #[allow(clippy::cognitive_complexity)]
 #[wasm_bindgen_test]
 fn test_execute() {
-    let result = compile(
+    let result = compile_and_allocate(
         &HardwareSpec {
             num_registers: 1,
             num_stacks: 1,
             max_stack_length: 10,
+            ..Default::default()
         },
         &ProgramSpec::new(vec![1, 2, 3], vec![1, 2, 3]),
         "
@@ -364,7 +530,7 @@ fn test_execute() {
 
 #[wasm_bindgen_test]
 fn test_runtime_error() {
-    let result = compile(
+    let result = compile_and_allocate(
         &HardwareSpec::default(),
         &ProgramSpec::default(),
         "READ RX0",
@@ -388,13 +554,16 @@ fn test_runtime_error() {
         error = Some(SourceElement {
             text: "Runtime error at 1:1: Read attempted on empty input".into(),
             span: Span {
+                source_id: None,
                 offset: 0,
                 length: 8,
                 start_line: 1,
                 start_col: 1,
                 end_line: 1,
                 end_col: 9
-            }
+            },
+            label: None,
+            code: Some("R002_EMPTY_INPUT".into()),
         })
     );
 }
@@ -60,6 +60,8 @@ const DocsPage: React.FC = () => {
   // We use this to selectively hide irrelevant docs, based on the hardware
   const context = {
     showStacks: hardware.numStacks > 0,
+    // DUMP is a debugging aid that isn't available in the game hardware
+    showDump: false,
   };
 
   // This content is written from within the GDLK canon, i.e. from the
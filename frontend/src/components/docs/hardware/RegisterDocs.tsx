@@ -51,6 +51,16 @@ const REGISTERS: Register[] = [
       </>
     ),
   },
+  {
+    name: "RIH",
+    writable: false,
+    summary: (
+      <>
+        Register Input Head: Holds the next value in the <code>INPUT</code>{" "}
+        buffer, without removing it. If <code>INPUT</code> is empty, this is 0.
+      </>
+    ),
+  },
   {
     name: "RZR",
     writable: true,
@@ -61,6 +71,16 @@ const REGISTERS: Register[] = [
       </>
     ),
   },
+  {
+    name: "RZC",
+    writable: false,
+    summary: (
+      <>
+        Register Zero Count: Holds the number of values that have been written
+        to <code>RZR</code> so far. Only some GDLKx PCs have this register.
+      </>
+    ),
+  },
 ];
 
 /**
@@ -41,6 +41,23 @@ const INSTRUCTIONS: InstructionReference[] = [
     ],
     examples: ["READ RX0 ; Move the first value in INPUT into RX0"],
   },
+  {
+    name: "RDZ",
+    args: ["REG"],
+    summary: (
+      <>
+        Read the next value from <code>INPUT</code> and store it in a register,
+        or store 0 if <code>INPUT</code> is empty.
+      </>
+    ),
+    moreInfo: (
+      <>
+        This behaves like <code>READ</code>, except that reading while{" "}
+        <code>INPUT</code> is empty is not an error.
+      </>
+    ),
+    examples: ["RDZ RX0 ; Move the first value in INPUT into RX0, or 0"],
+  },
   {
     name: "WRITE",
     args: ["VAL"],
@@ -69,6 +86,18 @@ const INSTRUCTIONS: InstructionReference[] = [
       "SET RX0 RX1 ; Set RX0 to the value of RX1",
     ],
   },
+  {
+    name: "SWP",
+    summary: "Exchange the values in two registers.",
+    args: ["REG", "REG"],
+    moreInfo: (
+      <>
+        Both registers must be writable. Swapping with <code>RZR</code> sets
+        the other register to zero.
+      </>
+    ),
+    examples: ["SWP RX0 RX1 ; RX0 now holds RX1's old value, and vice versa"],
+  },
   {
     name: "ADD",
     summary: "Add a value to a register.",
@@ -139,6 +168,70 @@ const INSTRUCTIONS: InstructionReference[] = [
       "DIV RX0 RX1 ; Divide the value in RX0 by the value in RX1",
     ],
   },
+  {
+    name: "AND",
+    summary: "Bitwise AND a register with a value.",
+    moreInfo: <>The result is stored in the register.</>,
+    args: ["REG", "VAL"],
+    examples: [
+      "AND RX0 1   ; Set RX0 to 1 if it's odd, or 0 if it's even",
+      "AND RX0 RX1 ; Keep only the bits of RX0 that are also set in RX1",
+    ],
+  },
+  {
+    name: "OR",
+    summary: "Bitwise OR a register with a value.",
+    moreInfo: <>The result is stored in the register.</>,
+    args: ["REG", "VAL"],
+    examples: [
+      "OR RX0 1   ; Set the lowest bit of RX0",
+      "OR RX0 RX1 ; Set every bit in RX0 that's set in RX1",
+    ],
+  },
+  {
+    name: "XOR",
+    summary: "Bitwise XOR a register with a value.",
+    moreInfo: <>The result is stored in the register.</>,
+    args: ["REG", "VAL"],
+    examples: [
+      "XOR RX0 -1  ; Flip every bit of RX0",
+      "XOR RX0 RX1 ; Flip every bit in RX0 that's set in RX1",
+    ],
+  },
+  {
+    name: "SHL",
+    summary: "Shift a register left by a number of bits.",
+    moreInfo: (
+      <>
+        Bits shifted off the left end are thrown away, and zeroes are shifted in
+        on the right. Only the lowest 5 bits of the shift amount are used, so
+        shifting by 32 is the same as shifting by 0, and shifting by -1 is the
+        same as shifting by 31. The result is stored in the register.
+      </>
+    ),
+    args: ["REG", "VAL"],
+    examples: [
+      "SHL RX0 3   ; Multiply RX0 by 8",
+      "SHL RX0 RX1 ; Shift RX0 left by the value in RX1",
+    ],
+  },
+  {
+    name: "SHR",
+    summary: "Shift a register right by a number of bits.",
+    moreInfo: (
+      <>
+        This is an arithmetic shift, so the sign of the value is kept, e.g.{" "}
+        <code>-8</code> shifted right by 1 is <code>-4</code>. The shift amount
+        works the same as <code>SHL</code>. The result is stored in the
+        register.
+      </>
+    ),
+    args: ["REG", "VAL"],
+    examples: [
+      "SHR RX0 1   ; Divide RX0 by 2, rounding down",
+      "SHR RX0 RX1 ; Shift RX0 right by the value in RX1",
+    ],
+  },
   {
     name: "CMP",
     summary: "Compare two values, and put the output into a register.",
@@ -181,6 +274,56 @@ const INSTRUCTIONS: InstructionReference[] = [
     examples: ["POP S0 RX0 ; Move the top value of S0 into RX0"],
     isVisible: (context) => context.showStacks,
   },
+  {
+    name: "PEEK",
+    summary:
+      "Copy the value at the top of a stack into a register, without popping it.",
+    args: ["STACK", "REG"],
+    errorCases: [<>Peeking at an empty stack causes a runtime error.</>],
+    examples: ["PEEK S0 RX0 ; Copy the top value of S0 into RX0"],
+    isVisible: (context) => context.showStacks,
+  },
+  {
+    name: "POPN",
+    summary:
+      "Pop some number of values off the top of a stack, and throw them away. A count of zero or less pops nothing.",
+    args: ["STACK", "VAL"],
+    errorCases: [
+      <>
+        Popping more values than the stack holds causes a runtime error, and
+        nothing gets popped.
+      </>,
+    ],
+    examples: [
+      "POPN S0 2   ; Throw away the top two values of S0",
+      "POPN S0 RS0 ; Empty S0",
+    ],
+    isVisible: (context) => context.showStacks,
+  },
+  {
+    name: "DUP",
+    summary: "Push a copy of the value at the top of a stack onto that stack.",
+    args: ["STACK"],
+    errorCases: [
+      <>Duplicating the top of an empty stack causes a runtime error.</>,
+      <>
+        Duplicating onto a stack that is already full causes a runtime error.{" "}
+        <Link to="#stacks--capacity">More information on stack capacity</Link>.
+      </>,
+    ],
+    examples: ["DUP S0 ; Copy the top value of S0 onto the top of S0"],
+    isVisible: (context) => context.showStacks,
+  },
+  {
+    name: "SSWP",
+    summary: "Swap the top two values of a stack.",
+    args: ["STACK"],
+    errorCases: [
+      <>Swapping a stack with fewer than two values causes a runtime error.</>,
+    ],
+    examples: ["SSWP S0 ; The top of S0 is now the value that was below it"],
+    isVisible: (context) => context.showStacks,
+  },
   {
     name: "JMP",
     summary: "Jump to a label, unconditionally.",
@@ -242,6 +385,86 @@ const INSTRUCTIONS: InstructionReference[] = [
       "JLZ 1 END\nREAD RX0 ; This instruction will be executed\nEND:",
     ],
   },
+  {
+    name: "JRI",
+    summary: "Jump to the instruction at the index held in a register.",
+    args: ["REG"],
+    moreInfo: (
+      <>
+        Instructions are numbered from <code>0</code>, and labels don&apos;t
+        count. Jumping to the index right after the last instruction ends the
+        program. This is useful for building jump tables. Programs that use{" "}
+        <code>JRI</code> are never optimized, since that would change the
+        instruction indexes.
+      </>
+    ),
+    errorCases: [
+      <>
+        Jumping to a negative index, or past the end of the program, causes a
+        runtime error.
+      </>,
+    ],
+    examples: ["SET RX0 2\nJRI RX0\nREAD RX0 ; This instruction will be skipped"],
+  },
+  {
+    name: "CALL",
+    summary: "Jump to a label, and remember where to return to.",
+    args: ["LABEL"],
+    moreInfo: (
+      <>
+        The position of the next instruction is pushed onto a hidden call
+        stack, and <code>RET</code> jumps back to it. Calls can be nested, up to
+        the maximum call depth of the hardware.
+      </>
+    ),
+    errorCases: [
+      <>Calling when the call stack is full causes a runtime error.</>,
+    ],
+    examples: [
+      "CALL DOUBLE\nWRITE RX0\nJMP END\nDOUBLE:\n  ADD RX0 RX0\n  RET\nEND:",
+    ],
+  },
+  {
+    name: "RET",
+    summary: (
+      <>
+        Return from the most recent <code>CALL</code>.
+      </>
+    ),
+    args: [],
+    errorCases: [
+      <>
+        Returning when there is no <code>CALL</code> to return from causes a
+        runtime error.
+      </>,
+    ],
+    examples: ["RET ; Jump back to the instruction after the last CALL"],
+  },
+  {
+    name: "NOP",
+    summary: "Do nothing.",
+    args: [],
+    moreInfo: (
+      <>
+        This does not modify any state, but still takes one cycle. It can be
+        useful as a placeholder, or as an explicit target for a jump.
+      </>
+    ),
+    examples: ["NOP ; Wait one cycle"],
+  },
+  {
+    name: "DUMP",
+    summary: "Record the value of all registers and stacks, for debugging.",
+    args: [],
+    moreInfo: (
+      <>
+        This does not modify any state, but still takes one cycle. Only
+        available on hardware with debugging enabled.
+      </>
+    ),
+    examples: ["DUMP ; Take a snapshot of the current state"],
+    isVisible: (context) => context.showDump,
+  },
 ];
 
 /**
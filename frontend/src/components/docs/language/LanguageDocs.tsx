@@ -23,6 +23,27 @@ const LanguageDocs: React.FC = () => (
         operations are performed on these integers.
       </Typography>
 
+      <Typography id="values--literals" variant="h4">
+        Literals
+      </Typography>
+      <Typography>
+        Values can be written in decimal (<code>-10</code>), in hexadecimal (
+        <code>0x1F</code>), or as a character in single quotes (<code>'A'</code>
+        ). A character literal is equal to the character's Unicode value, so{" "}
+        <code>'A'</code> is the same as <code>65</code>. Negative values must be
+        written in decimal.
+      </Typography>
+
+      <Typography id="values--constants" variant="h4">
+        Constants
+      </Typography>
+      <Typography>
+        Some puzzles define named constants, which can be used anywhere a value
+        can, e.g. <code>MUL RX0 $K</code>. The value comes from the puzzle, and
+        can change between test cases. Using a constant that the puzzle
+        doesn't define is an error, even if it's never read.
+      </Typography>
+
       <Typography id="values--overflow-and-underflow" variant="h4">
         Overflow & Underflow
       </Typography>
@@ -36,7 +57,8 @@ const LanguageDocs: React.FC = () => (
         <code>
           {MIN_LANG_VALUE} - 1 = {MAX_LANG_VALUE}
         </code>
-        .
+        . Some hardware instead stops the value at the max or min, or treats
+        overflow as a runtime error.
       </Typography>
     </DocsSection>
 
@@ -55,6 +77,14 @@ LOOP:
       but this is simply a convention and <em>not required</em>.
     </DocsSection>
 
+    <DocsSection id="statements" level={3} title="Statements">
+      Each line normally holds one statement: a label or an instruction.
+      Multiple statements can share a line if they're separated by{" "}
+      <code>|</code>, e.g. <code>READ RX0 | WRITE RX0</code>. A comment, which
+      starts with <code>;</code>, can only come after the last statement on a
+      line.
+    </DocsSection>
+
     <InstructionDocs />
   </DocsSection>
 );
@@ -45,14 +45,14 @@ const CodeEditor: React.FC<{ className?: string }> = ({ className }) => {
 
         if (nextInstruction) {
           markers.push({
-            ...gdlkSpanToAce(nextInstruction.span),
+            ...gdlkSpanToAce(nextInstruction.span, sourceCode),
             className: localClasses.activeInstruction,
             type: "fullLine",
           });
         }
 
         if (runtimeError) {
-          const aceSpan = gdlkSpanToAce(runtimeError.span);
+          const aceSpan = gdlkSpanToAce(runtimeError.span, sourceCode);
           markers.push({
             ...aceSpan,
             className: localClasses.activeInstruction,
@@ -70,7 +70,7 @@ const CodeEditor: React.FC<{ className?: string }> = ({ className }) => {
 
     case "error":
       compiledState.errors.forEach((error) => {
-        const aceSpan = gdlkSpanToAce(error.span);
+        const aceSpan = gdlkSpanToAce(error.span, sourceCode);
         markers.push({
           ...aceSpan,
           className: localClasses.errorSpan,
@@ -12,14 +12,14 @@ const StackInfo: React.FC<{
 
   return (
     <Stack className={className} direction="row" padding={1} spacing={1}>
-      {wasmHardwareSpec.stacks.map((name) => (
+      {wasmHardwareSpec.stacks.map((name, i) => (
         <BufferDisplay
           key={name}
           // Reverse the column so values are inserted bottom-up
           direction="column-reverse"
           label={name}
           values={machineState?.stacks[name] ?? []}
-          maxLength={wasmHardwareSpec.max_stack_length}
+          maxLength={wasmHardwareSpec.stackCapacities[i]}
         />
       ))}
     </Stack>
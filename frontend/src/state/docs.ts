@@ -8,8 +8,10 @@ import React from "react";
  */
 export interface DocsContextType {
   showStacks: boolean;
+  showDump: boolean;
 }
 
 export const DocsContext = React.createContext<DocsContextType>({
   showStacks: false,
+  showDump: false,
 });
@@ -15,12 +15,30 @@ export interface AceSpan {
   endCol: number;
 }
 
-export function gdlkSpanToAce(span: Span): AceSpan {
+/**
+ * Convert a 1-based column from gdlk, which counts characters, to one that
+ * counts UTF-16 code units, like Ace does. The two only differ for characters
+ * that take two code units, e.g. emoji.
+ */
+function toUtf16Col(lines: string[], line: number, col: number): number {
+  const chars = Array.from(lines[line - 1] ?? "").slice(0, col - 1);
+  // Columns past the end of the line (e.g. an error at the end of the code)
+  // are left as is
+  return chars.join("").length + (col - 1 - chars.length) + 1;
+}
+
+/**
+ * Convert a span from gdlk into Ace's format. The source code is needed to
+ * convert columns to UTF-16 code units, so it must be the same code that the
+ * span came from.
+ */
+export function gdlkSpanToAce(span: Span, sourceCode: string): AceSpan {
+  const lines = sourceCode.split("\n");
   return {
     startRow: span.start_line - 1,
-    startCol: span.start_col - 1,
+    startCol: toUtf16Col(lines, span.start_line, span.start_col) - 1,
     endRow: span.end_line - 1,
-    endCol: span.end_col,
+    endCol: toUtf16Col(lines, span.end_line, span.end_col),
   };
 }
 
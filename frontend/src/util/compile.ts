@@ -1,12 +1,12 @@
 // Can only use these imports as types, the actual import needs to be async
 import type {
+  CompileFailure,
   HardwareSpec,
   SourceElement,
   ProgramSpec,
   Machine,
 } from "gdlk_wasm";
 import { LangValue } from "@root/state/ide";
-import { isTypedArray } from "./guards";
 const gdlk = await import("gdlk_wasm");
 
 export interface CompileErrors {
@@ -14,16 +14,18 @@ export interface CompileErrors {
 }
 
 /**
- * Type guard for the SourceElement type. Checks if the given value fits the
- * criteria of a SourceElement.
+ * Type guard for the CompileFailure type, which is what wasm throws when a
+ * compile fails.
  * @param value The value to check
- * @returns true if the value is a SourceElement, false if not
+ * @returns true if the value is a CompileFailure, false if not
  */
-// eslint-disable-next-line @typescript-eslint/ban-types
-function isSourceElement(value: object): value is SourceElement {
-  // TODO make this more robust after
-  // https://github.com/Microsoft/TypeScript/issues/21732
-  return "text" in value && "span" in value;
+function isCompileFailure(value: unknown): value is CompileFailure {
+  return (
+    typeof value === "object" &&
+    value !== null &&
+    "kind" in value &&
+    "errors" in value
+  );
 }
 
 export interface MachineState {
@@ -110,7 +112,11 @@ export class Compiler {
     source: string
   ): CompileResult {
     try {
-      const result = gdlk.compile(hardwareSpec, programSpec, source);
+      const result = gdlk.compileAndAllocate(
+        hardwareSpec,
+        programSpec,
+        source
+      );
 
       return {
         type: "compiled",
@@ -119,8 +125,18 @@ export class Compiler {
       };
     } catch (e) {
       // Check that the error value matches the expected compile error format
-      if (isTypedArray(isSourceElement, e)) {
-        return { type: "error", errors: e };
+      if (isCompileFailure(e)) {
+        switch (e.kind) {
+          case "InvalidProgram":
+            return { type: "error", errors: e.errors };
+          case "InvalidSpec":
+            // Specs are defined by us, not the user, so this is a bug
+            throw new Error(
+              `Invalid ${e.spec} spec: ${e.errors
+                .map(({ field, message }) => `${field}: ${message}`)
+                .join(", ")}`
+            );
+        }
       }
       // Unknown error, blow up!
       throw e;